@@ -0,0 +1,275 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use anyhow::{bail, Result};
+use hif::*;
+use humility::core::{Core, CORE_MAX_READSIZE};
+use humility::hubris::*;
+use humility_cmd::hiffy::*;
+use humility_cmd::{Archive, Args, Attach, Command, Validate};
+use indicatif::HumanBytes;
+use std::thread;
+use std::time::{Duration, Instant};
+use structopt::clap::App;
+use structopt::StructOpt;
+
+#[derive(StructOpt, Debug)]
+#[structopt(
+    name = "bench",
+    about = "measure HIF and memory access performance"
+)]
+struct BenchArgs {
+    /// sets timeout
+    #[structopt(
+        long, short = "T", default_value = "5000", value_name = "timeout_ms",
+        parse(try_from_str = parse_int::parse)
+    )]
+    timeout: u32,
+
+    /// number of iterations of each measurement
+    #[structopt(long, short, default_value = "20", value_name = "count",
+        parse(try_from_str = parse_int::parse),
+    )]
+    iterations: u32,
+
+    /// interval at which to poll for HIF completion
+    #[structopt(long, short, default_value = "0", value_name = "poll_ms",
+        parse(try_from_str = parse_int::parse),
+    )]
+    poll: u64,
+
+    /// number of bytes to read when measuring bulk read bandwidth
+    #[structopt(long, short, value_name = "nbytes",
+        parse(try_from_str = parse_int::parse),
+    )]
+    nbytes: Option<u32>,
+}
+
+struct Measurement {
+    test: &'static str,
+    param: String,
+    samples: Vec<Duration>,
+    bytes: usize,
+}
+
+impl Measurement {
+    fn new(test: &'static str, param: String, bytes: usize) -> Self {
+        Self { test, param, samples: vec![], bytes }
+    }
+
+    fn measure(
+        &mut self,
+        iterations: u32,
+        mut f: impl FnMut() -> Result<()>,
+    ) -> Result<()> {
+        for _ in 0..iterations {
+            let started = Instant::now();
+            f()?;
+            self.samples.push(started.elapsed());
+        }
+
+        Ok(())
+    }
+
+    fn print(&self) {
+        let ms = |d: &Duration| d.as_secs_f64() * 1000.0;
+        let n = self.samples.len();
+
+        if n == 0 {
+            return;
+        }
+
+        let min = self.samples.iter().min().unwrap();
+        let max = self.samples.iter().max().unwrap();
+        let total: Duration = self.samples.iter().sum();
+        let avg = total / n as u32;
+
+        let rate = if self.bytes != 0 {
+            let rate = (self.bytes * n) as f64 / total.as_secs_f64();
+            format!("{}/s", HumanBytes(rate as u64))
+        } else {
+            "-".to_string()
+        };
+
+        println!(
+            "{:14} {:>10} {:>5} {:>10.3} {:>10.3} {:>10.3} {:>12}",
+            self.test,
+            self.param,
+            n,
+            ms(min),
+            ms(&avg),
+            ms(max),
+            rate
+        );
+    }
+}
+
+fn hiffy_roundtrip(
+    context: &mut HiffyContext,
+    core: &mut dyn Core,
+    ops: &[Op],
+    data: Option<&[u8]>,
+    poll: u64,
+) -> Result<()> {
+    context.start(core, ops, data)?;
+
+    while !context.done(core)? {
+        if poll != 0 {
+            thread::sleep(Duration::from_millis(poll));
+        }
+    }
+
+    context.results(core)?;
+    Ok(())
+}
+
+#[rustfmt::skip::macros(println, bail)]
+fn bench(
+    hubris: &mut HubrisArchive,
+    core: &mut dyn Core,
+    _args: &Args,
+    subargs: &[String],
+) -> Result<()> {
+    let subargs = BenchArgs::from_iter_safe(subargs)?;
+    let iterations = subargs.iterations;
+    let poll = subargs.poll;
+
+    if iterations == 0 {
+        bail!("iterations must be non-zero");
+    }
+
+    let mut context = HiffyContext::new(hubris, core, subargs.timeout)?;
+    let scratch = hubris.lookup_variable("HIFFY_DATA")?;
+    let rstack = hubris.lookup_variable("HIFFY_RSTACK")?;
+    let data = vec![0u8; context.data_size()];
+    let ops = [Op::Done];
+
+    let (probe, _) = core.info();
+    println!("humility: benchmarking via {}, polling every {}ms",
+        probe, poll);
+
+    println!("{:14} {:>10} {:>5} {:>10} {:>10} {:>10} {:>12}",
+        "TEST", "PARAM", "ITER", "MIN(ms)", "AVG(ms)", "MAX(ms)", "RATE");
+
+    //
+    // First, the latency of an empty HIF program -- which is the floor for
+    // any HIF-based command.
+    //
+    let mut m = Measurement::new("hiffy", "empty".to_string(), 0);
+    m.measure(iterations, || {
+        hiffy_roundtrip(&mut context, core, &ops, None, poll)
+    })?;
+    m.print();
+
+    //
+    // Now an empty program accompanied by a full data area, as (for
+    // example) the I2C and QSPI writes do.
+    //
+    let mut m = Measurement::new("hiffy", "data".to_string(), data.len());
+    m.measure(iterations, || {
+        hiffy_roundtrip(&mut context, core, &ops, Some(&data), poll)
+    })?;
+    m.print();
+
+    //
+    // Next, the raw bandwidth to and from the HIF data area and the HIF
+    // return stack, which bound the bandwidth of any HIF operation.
+    //
+    let mut m =
+        Measurement::new("scratch write", "data".to_string(), data.len());
+    m.measure(iterations, || {
+        core.halt()?;
+        let rval = core.write_8(scratch.addr, &data);
+        core.run()?;
+        rval
+    })?;
+    m.print();
+
+    let mut buf = vec![0u8; scratch.size];
+    let mut m =
+        Measurement::new("scratch read", "data".to_string(), scratch.size);
+    m.measure(iterations, || {
+        core.halt()?;
+        let rval = core.read_8(scratch.addr, &mut buf);
+        core.run()?;
+        rval
+    })?;
+    m.print();
+
+    let mut buf = vec![0u8; rstack.size];
+    let mut m =
+        Measurement::new("scratch read", "rstack".to_string(), rstack.size);
+    m.measure(iterations, || {
+        core.halt()?;
+        let rval = core.read_8(rstack.addr, &mut buf);
+        core.run()?;
+        rval
+    })?;
+    m.print();
+
+    //
+    // Finally, bulk reads from the largest readable memory region, at
+    // varying chunk sizes.
+    //
+    let regions = hubris.regions(core)?;
+
+    let region = regions
+        .values()
+        .filter(|r| r.attr.read && !r.attr.device)
+        .max_by_key(|r| r.size);
+
+    let region = match region {
+        Some(region) => region,
+        None => {
+            bail!("no readable memory region found");
+        }
+    };
+
+    let nbytes = match subargs.nbytes {
+        Some(nbytes) if nbytes > region.size => {
+            bail!("{} bytes exceeds size of largest region ({} bytes)",
+                nbytes, region.size);
+        }
+        Some(nbytes) => nbytes as usize,
+        None => region.size as usize,
+    };
+
+    let mut buf = vec![0u8; nbytes];
+    let mut chunk = 256;
+
+    while chunk <= CORE_MAX_READSIZE {
+        let mut m = Measurement::new("bulk read", format!("{}", chunk), nbytes);
+
+        m.measure(iterations, || {
+            for (i, c) in buf.chunks_mut(chunk).enumerate() {
+                core.read_8(region.base + (i * chunk) as u32, c)?;
+            }
+
+            Ok(())
+        })?;
+
+        m.print();
+
+        if chunk >= nbytes {
+            break;
+        }
+
+        chunk *= 4;
+    }
+
+    Ok(())
+}
+
+pub fn init<'a, 'b>() -> (Command, App<'a, 'b>) {
+    (
+        Command::Attached {
+            name: "bench",
+            archive: Archive::Required,
+            attach: Attach::LiveOnly,
+            validate: Validate::Booted,
+            run: bench,
+        },
+        BenchArgs::clap(),
+    )
+}
@@ -0,0 +1,346 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+#[macro_use]
+extern crate log;
+
+use anyhow::{anyhow, bail, Context, Result};
+use humility::arch::ARMRegister;
+use humility::core::{Core, CoreOps, CoreWatch};
+use humility::hubris::*;
+use humility_cmd::halted::*;
+use humility_cmd::{coalesced, Archive, Args, Attach, Command, Validate};
+use humility_cortex::debug::*;
+use num_traits::FromPrimitive;
+use std::collections::HashMap;
+use std::thread;
+use std::time::{Duration, Instant};
+use structopt::clap::App;
+use structopt::StructOpt;
+
+#[derive(StructOpt, Debug)]
+#[structopt(
+    name = "break",
+    about = "set a breakpoint or watchpoint and report on the hit"
+)]
+struct BreakArgs {
+    /// interval at which to poll the target, in milliseconds
+    #[structopt(long, short, default_value = "100", value_name = "ms")]
+    interval: u64,
+
+    /// set a watchpoint on writes to the location
+    #[structopt(long, short, conflicts_with_all = &["read", "access"])]
+    write: bool,
+
+    /// set a watchpoint on reads of the location
+    #[structopt(long, short, conflicts_with = "access")]
+    read: bool,
+
+    /// set a watchpoint on any access to the location
+    #[structopt(long, short)]
+    access: bool,
+
+    /// give up if not hit within the specified number of seconds
+    #[structopt(long, short, value_name = "seconds")]
+    timeout: Option<u64>,
+
+    /// resume the target after each hit and continue waiting
+    #[structopt(long = "continue", short)]
+    resume: bool,
+
+    /// show line number information with stack backtrace
+    #[structopt(long, short)]
+    line: bool,
+
+    /// function (for a breakpoint) or variable (for a watchpoint) in the
+    /// archive, or an address
+    location: String,
+}
+
+#[derive(Copy, Clone, Debug)]
+enum BreakKind {
+    Breakpoint,
+    Watchpoint(CoreWatch),
+}
+
+impl BreakKind {
+    fn set(&self, core: &mut dyn Core, addr: u32) -> Result<()> {
+        match self {
+            BreakKind::Breakpoint => core.set_breakpoint(addr),
+            BreakKind::Watchpoint(watch) => core.set_watchpoint(addr, *watch),
+        }
+    }
+
+    fn clear(&self, core: &mut dyn Core, addr: u32) -> Result<()> {
+        match self {
+            BreakKind::Breakpoint => core.clear_breakpoint(addr),
+            BreakKind::Watchpoint(_) => core.clear_watchpoint(addr),
+        }
+    }
+}
+
+impl std::fmt::Display for BreakKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BreakKind::Breakpoint => write!(f, "breakpoint"),
+            BreakKind::Watchpoint(watch) => write!(f, "{} watchpoint", watch),
+        }
+    }
+}
+
+fn symbolize(hubris: &HubrisArchive, addr: u32) -> String {
+    match hubris.instr_sym(addr) {
+        Some((name, base)) => format!(" ({}+0x{:x})", name, addr - base),
+        None => "".to_string(),
+    }
+}
+
+//
+// Resolves the location to an address:  for a breakpoint, a function; for
+// a watchpoint, a variable (or, failing that, any symbol).  Watchpoints
+// watch a word, so a variable that is larger than a word has only its
+// first word watched.
+//
+fn break_location(
+    hubris: &HubrisArchive,
+    kind: BreakKind,
+    location: &str,
+) -> Result<u32> {
+    if let Ok(addr) = parse_int::parse::<u32>(location) {
+        return Ok(addr);
+    }
+
+    let err = || format!("\"{}\" is neither an address nor a symbol", location);
+
+    match kind {
+        BreakKind::Breakpoint => {
+            let (addr, _) = hubris.lookup_symbol(location).with_context(err)?;
+            Ok(addr & !1)
+        }
+        BreakKind::Watchpoint(_) => match hubris.lookup_variable(location) {
+            Ok(var) => {
+                if var.size > 4 {
+                    warn!(
+                        "{} is {} bytes; watching its first word",
+                        location, var.size
+                    );
+                }
+
+                Ok(var.addr)
+            }
+            Err(_) => {
+                let (addr, _) =
+                    hubris.lookup_symbol(location).with_context(err)?;
+                Ok(addr)
+            }
+        },
+    }
+}
+
+fn break_report(
+    hubris: &HubrisArchive,
+    core: &mut dyn Core,
+    subargs: &BreakArgs,
+    kind: BreakKind,
+    addr: u32,
+    target: &str,
+) -> Result<()> {
+    //
+    // Unlike at a fault, the core halts with all of its registers live.
+    //
+    let mut regs = HashMap::new();
+
+    for r in 0..16 {
+        let reg = ARMRegister::from_u16(r).unwrap();
+        regs.insert(reg, core.read_reg(reg)?);
+    }
+
+    let xpsr = core.read_reg(ARMRegister::xPSR)?;
+    regs.insert(ARMRegister::xPSR, xpsr);
+
+    let pc = regs[&ARMRegister::PC];
+
+    warn!("{} hit on {}", kind, target);
+
+    //
+    // If we're in thread mode, we're in the current task; otherwise, we're
+    // in the kernel.
+    //
+    let (task, limit) = if xpsr & 0x1ff == 0 {
+        let (task, name, limit) = current_task(hubris, core)?;
+        println!("{:>8}: {} ({})", "task", name, task.id());
+        (task, limit)
+    } else {
+        println!("{:>8}: kernel", "task");
+        (HubrisTask::Kernel, kernel_stack_limit(core)?)
+    };
+
+    println!("{:>8}: 0x{:08x}{}", "pc", pc, symbolize(hubris, pc));
+
+    //
+    // A watchpoint halts the core after the accessing instruction has
+    // executed (and possibly a few instructions beyond it), so the PC is
+    // only near the access; we also show the value of the watched word.
+    //
+    if let BreakKind::Watchpoint(_) = kind {
+        let word = addr & !0b11;
+        let value = core.read_word_32(word)?;
+        println!("{:>8}: 0x{:08x} = 0x{:08x}", "value", word, value);
+    }
+
+    println!("\n   registers:");
+    print_regs(&regs);
+
+    println!("\n   backtrace:");
+
+    match hubris.stack(core, task, limit, &regs) {
+        Ok(stack) => print_stack(hubris, &stack, subargs.line),
+        Err(err) => println!("      <failed to unwind: {}>", err),
+    }
+
+    println!();
+
+    Ok(())
+}
+
+fn break_wait(
+    hubris: &HubrisArchive,
+    core: &mut dyn Core,
+    subargs: &BreakArgs,
+    kind: BreakKind,
+    addr: u32,
+    target: &str,
+) -> Result<()> {
+    let interval = Duration::from_millis(subargs.interval);
+    let mut start = Instant::now();
+
+    loop {
+        if !DHCSR::read(core)?.halted() {
+            if let Some(timeout) = subargs.timeout {
+                if start.elapsed().as_secs() >= timeout {
+                    bail!("{} not hit after {} seconds", kind, timeout);
+                }
+            }
+
+            thread::sleep(interval);
+            continue;
+        }
+
+        let dfsr = DFSR::read(core)?;
+
+        let hit = match kind {
+            BreakKind::Breakpoint => dfsr.breakpoint(),
+            BreakKind::Watchpoint(_) => dfsr.watchpoint(),
+        };
+
+        if !hit {
+            let pc = core.read_reg(ARMRegister::PC)?;
+            bail!(
+                "target halted at 0x{:x} on something other than the {}",
+                pc,
+                kind
+            );
+        }
+
+        //
+        // Clear the debug event status (which is write-one-to-clear) so
+        // that we can distinguish the next one.
+        //
+        DFSR::from(u32::from(dfsr)).write(core)?;
+
+        //
+        // Unwinding the stack results in many small reads, so we coalesce
+        // them.  (The core is halted, but the coalescing core must be told
+        // as much.)
+        //
+        coalesced(hubris, core, |core| {
+            core.halt()?;
+            break_report(hubris, core, subargs, kind, addr, target)
+        })?;
+
+        if !subargs.resume {
+            info!("core left halted");
+            return Ok(());
+        }
+
+        //
+        // To resume from a breakpoint, we must step over the breakpointed
+        // instruction with the breakpoint cleared.
+        //
+        if let BreakKind::Breakpoint = kind {
+            core.clear_breakpoint(addr)?;
+            core.step()?;
+            core.set_breakpoint(addr)?;
+        }
+
+        core.run()?;
+        start = Instant::now();
+        info!("core resumed; waiting for {}", kind);
+    }
+}
+
+fn breakcmd(
+    hubris: &mut HubrisArchive,
+    core: &mut dyn Core,
+    _args: &Args,
+    subargs: &[String],
+) -> Result<()> {
+    let subargs = BreakArgs::from_iter_safe(subargs)?;
+
+    let kind = if subargs.write {
+        BreakKind::Watchpoint(CoreWatch::Write)
+    } else if subargs.read {
+        BreakKind::Watchpoint(CoreWatch::Read)
+    } else if subargs.access {
+        BreakKind::Watchpoint(CoreWatch::ReadWrite)
+    } else {
+        BreakKind::Breakpoint
+    };
+
+    let addr = break_location(hubris, kind, &subargs.location)?;
+
+    let target = match parse_int::parse::<u32>(&subargs.location) {
+        Ok(_) => format!("0x{:x}{}", addr, symbolize(hubris, addr)),
+        Err(_) => format!("{} (0x{:x})", subargs.location, addr),
+    };
+
+    kind.set(core, addr)?;
+    info!("{} set on {}; waiting for hit", kind, target);
+
+    let rval = break_wait(hubris, core, &subargs, kind, addr, &target);
+
+    //
+    // A failure to clear must not hide why we stopped waiting, so if both
+    // fail, we report the error from waiting first.
+    //
+    match (rval, kind.clear(core, addr)) {
+        (Err(err), Err(clear)) => Err(anyhow!(
+            "{:#} (additionally, failed to clear {}: {:#})",
+            err,
+            kind,
+            clear
+        )),
+        (Err(err), Ok(())) => Err(err),
+        (Ok(()), cleared) => cleared,
+    }
+}
+
+pub fn init<'a, 'b>() -> (Command, App<'a, 'b>) {
+    (
+        Command::Attached {
+            name: "break",
+            archive: Archive::Required,
+            attach: Attach::Requires(
+                CoreOps::READ
+                    | CoreOps::REGISTERS
+                    | CoreOps::WRITE
+                    | CoreOps::CONTROL
+                    | CoreOps::BREAKPOINTS,
+            ),
+            validate: Validate::Booted,
+            run: breakcmd,
+        },
+        BreakArgs::clap(),
+    )
+}
@@ -0,0 +1,247 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+#[macro_use]
+extern crate log;
+
+use anyhow::{bail, Result};
+use humility::core::Core;
+use humility::hubris::*;
+use humility_cmd::{Archive, Args, Attach, Command, Validate};
+use humility_cortex::chip::chip_identify;
+use structopt::clap::App;
+use structopt::StructOpt;
+
+#[derive(StructOpt, Debug)]
+#[structopt(
+    name = "calibration",
+    about = "read and decode factory calibration data"
+)]
+struct CalibrationArgs {
+    /// raw temperature sensor ADC reading to convert
+    #[structopt(long, value_name = "raw",
+        parse(try_from_str = parse_int::parse),
+    )]
+    ts_data: Option<u32>,
+
+    /// raw internal reference ADC reading from which to derive VDDA
+    #[structopt(long, value_name = "raw",
+        parse(try_from_str = parse_int::parse),
+    )]
+    vrefint_data: Option<u32>,
+}
+
+//
+// An RC oscillator's factory calibration and user trim, as found in its
+// RCC configuration register.
+//
+struct Trim {
+    oscillator: &'static str,
+    register: &'static str,
+    addr: u32,
+    cal: (u32, u32),
+    trim: (u32, u32),
+    default: u32,
+}
+
+//
+// The factory calibration of a part:  its temperature sensor is calibrated
+// at two temperatures, and its internal reference at one, all with the ADC
+// at the specified resolution and VDDA at the specified voltage.
+//
+struct Calibration {
+    part: &'static str,
+    vdda_mv: u32,
+    bits: u32,
+    ts_cal1: (u32, i32),
+    ts_cal2: (u32, i32),
+    vrefint_cal: u32,
+    trims: &'static [Trim],
+}
+
+const CALIBRATIONS: &[Calibration] = &[
+    Calibration {
+        part: "STM32F4",
+        vdda_mv: 3300,
+        bits: 12,
+        ts_cal1: (0x1fff_7a2c, 30),
+        ts_cal2: (0x1fff_7a2e, 110),
+        vrefint_cal: 0x1fff_7a2a,
+        trims: &[Trim {
+            oscillator: "HSI",
+            register: "RCC_CR",
+            addr: 0x4002_3800,
+            cal: (8, 8),
+            trim: (3, 5),
+            default: 0x10,
+        }],
+    },
+    Calibration {
+        part: "STM32H742",
+        vdda_mv: 3300,
+        bits: 16,
+        ts_cal1: (0x1ff1_e820, 30),
+        ts_cal2: (0x1ff1_e840, 110),
+        vrefint_cal: 0x1ff1_e860,
+        trims: &[
+            Trim {
+                oscillator: "HSI",
+                register: "RCC_HSICFGR",
+                addr: 0x5802_4404,
+                cal: (0, 12),
+                trim: (24, 7),
+                default: 0x40,
+            },
+            Trim {
+                oscillator: "CSI",
+                register: "RCC_CSICFGR",
+                addr: 0x5802_4408,
+                cal: (0, 8),
+                trim: (24, 6),
+                default: 0x20,
+            },
+        ],
+    },
+];
+
+fn field(val: u32, (shift, width): (u32, u32)) -> u32 {
+    (val >> shift) & ((1 << width) - 1)
+}
+
+fn read_cal(core: &mut dyn Core, addr: u32) -> Result<u32> {
+    let mut buf = [0u8; 2];
+    core.read_8(addr, &mut buf)?;
+    Ok(u16::from_le_bytes(buf) as u32)
+}
+
+fn calibration(
+    _hubris: &mut HubrisArchive,
+    core: &mut dyn Core,
+    _args: &Args,
+    subargs: &[String],
+) -> Result<()> {
+    let subargs = CalibrationArgs::from_iter_safe(subargs)?;
+
+    let chip = match chip_identify(core, None)? {
+        Some(chip) => chip,
+        None => bail!("attached chip could not be identified"),
+    };
+
+    let cal = match CALIBRATIONS.iter().find(|c| chip.name.starts_with(c.part))
+    {
+        Some(cal) => cal,
+        None => bail!("calibration data not known for {}", chip.name),
+    };
+
+    info!("attached chip is {}", chip.name);
+
+    let full = ((1u64 << cal.bits) - 1) as f64;
+    let vdda = cal.vdda_mv as f64 / 1000.0;
+    let mv = |raw: u32| raw as f64 * cal.vdda_mv as f64 / full;
+
+    let (t1, t2) = (cal.ts_cal1.1, cal.ts_cal2.1);
+    let ts_cal1 = read_cal(core, cal.ts_cal1.0)?;
+    let ts_cal2 = read_cal(core, cal.ts_cal2.0)?;
+    let vrefint_cal = read_cal(core, cal.vrefint_cal)?;
+
+    println!(
+        "Temperature sensor (calibrated with VDDA = {:.3} V, {}-bit ADC):",
+        vdda, cal.bits
+    );
+
+    for (name, (addr, temp), raw) in
+        [("TS_CAL1", cal.ts_cal1, ts_cal1), ("TS_CAL2", cal.ts_cal2, ts_cal2)]
+    {
+        println!(
+            "  {:<11} 0x{:08x} = {:>5} ({:.1} mV at {}°C)",
+            name,
+            addr,
+            raw,
+            mv(raw),
+            temp
+        );
+    }
+
+    if ts_cal2 <= ts_cal1 {
+        warn!("TS_CAL2 is not greater than TS_CAL1; calibration invalid");
+    } else {
+        let slope = (t2 - t1) as f64 / (ts_cal2 - ts_cal1) as f64;
+        let mv_per_c = (mv(ts_cal2) - mv(ts_cal1)) / (t2 - t1) as f64;
+
+        println!(
+            "  slope       = ({} - {}) / (TS_CAL2 - TS_CAL1) \
+            = {:.5} °C/LSB ({:.3} mV/°C)",
+            t2, t1, slope, mv_per_c
+        );
+        println!("  temperature = {} + (TS_DATA - TS_CAL1) * slope", t1);
+
+        if let Some(data) = subargs.ts_data {
+            println!(
+                "  TS_DATA     = {:>5} => {:.2}°C",
+                data,
+                t1 as f64 + (data as f64 - ts_cal1 as f64) * slope
+            );
+        }
+    }
+
+    println!("\nInternal reference:");
+    println!(
+        "  {:<11} 0x{:08x} = {:>5} ({:.1} mV)",
+        "VREFINT_CAL",
+        cal.vrefint_cal,
+        vrefint_cal,
+        mv(vrefint_cal)
+    );
+    println!("  VDDA        = {:.3} V * VREFINT_CAL / VREFINT_DATA", vdda);
+
+    if let Some(data) = subargs.vrefint_data {
+        if data == 0 {
+            bail!("VREFINT_DATA cannot be zero");
+        }
+
+        println!(
+            "  VREFINT_DATA = {:>5} => VDDA = {:.3} V",
+            data,
+            vdda * vrefint_cal as f64 / data as f64
+        );
+    }
+
+    println!("\nRC oscillators:");
+
+    for trim in cal.trims {
+        let val = core.read_word_32(trim.addr)?;
+        let t = field(val, trim.trim);
+
+        println!(
+            "  {:<4} {:<12} = 0x{:08x}: {}CAL = 0x{:x}, {}TRIM = 0x{:x}{}",
+            trim.oscillator,
+            trim.register,
+            val,
+            trim.oscillator,
+            field(val, trim.cal),
+            trim.oscillator,
+            t,
+            if t == trim.default {
+                " (default)".to_string()
+            } else {
+                format!(" (default is 0x{:x})", trim.default)
+            }
+        );
+    }
+
+    Ok(())
+}
+
+pub fn init<'a, 'b>() -> (Command, App<'a, 'b>) {
+    (
+        Command::Attached {
+            name: "calibration",
+            archive: Archive::Optional,
+            attach: Attach::LiveOnly,
+            validate: Validate::None,
+            run: calibration,
+        },
+        CalibrationArgs::clap(),
+    )
+}
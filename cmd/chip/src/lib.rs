@@ -0,0 +1,75 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+#[macro_use]
+extern crate log;
+
+use anyhow::{bail, Result};
+use humility::core::Core;
+use humility::hubris::*;
+use humility_cmd::{Archive, Args, Attach, Command, Validate};
+use humility_cortex::chip::{chip_archive, chip_identify};
+use structopt::clap::App;
+use structopt::StructOpt;
+
+#[derive(StructOpt, Debug)]
+#[structopt(name = "chip", about = "identify the attached chip")]
+struct ChipArgs {
+    /// check the chip against the archive, distinguishing among parts that
+    /// share a device ID (which may briefly halt the target)
+    #[structopt(long, short)]
+    check: bool,
+}
+
+fn chip(
+    hubris: &mut HubrisArchive,
+    core: &mut dyn Core,
+    _args: &Args,
+    subargs: &[String],
+) -> Result<()> {
+    let subargs = ChipArgs::from_iter_safe(subargs)?;
+    let expected = if hubris.loaded() { chip_archive(hubris) } else { None };
+
+    if subargs.check && expected.is_none() {
+        bail!("chip for archive could not be determined");
+    }
+
+    let chip = match chip_identify(core, expected.filter(|_| subargs.check))? {
+        Some(chip) => chip,
+        None => bail!("attached chip could not be identified"),
+    };
+
+    println!("{:>8}: {}", "attached", chip.name);
+
+    if let Some(expected) = expected {
+        println!("{:>8}: {}", "archive", expected);
+
+        if subargs.check {
+            if !chip.matches(expected) {
+                bail!(
+                    "archive is for {}, but attached chip is {}",
+                    expected,
+                    chip.name
+                );
+            }
+
+            info!("attached chip matches archive");
+        }
+    }
+
+    Ok(())
+}
+
+pub fn init<'a, 'b>() -> (Command, App<'a, 'b>) {
+    (
+        Command::Attached {
+            name: "chip",
+            archive: Archive::Optional,
+            attach: Attach::LiveOnly,
+            validate: Validate::None,
+            run: chip,
+        },
+        ChipArgs::clap(),
+    )
+}
@@ -0,0 +1,443 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+#[macro_use]
+extern crate log;
+
+use anyhow::{bail, Result};
+use humility::core::Core;
+use humility::hubris::*;
+use humility_cmd::{Archive, Args, Attach, Command, Validate};
+use humility_cortex::chip::chip_identify;
+use humility_cortex::itm::itm_traceclock_measure;
+use humility_cortex::scs::CoreInfo;
+use structopt::clap::App;
+use structopt::StructOpt;
+
+#[derive(StructOpt, Debug)]
+#[structopt(name = "clocks", about = "display the active clock tree")]
+struct ClocksArgs {
+    /// frequency of the HSE oscillator or clock, in Hz
+    #[structopt(long, value_name = "hz",
+        parse(try_from_str = parse_int::parse),
+    )]
+    hse: Option<u32>,
+
+    /// don't measure the core clock
+    #[structopt(long, short = "M")]
+    nomeasure: bool,
+}
+
+/// Tolerance (in percent) before the core clock is flagged as a mismatch
+const CLOCKS_TOLERANCE: f64 = 1.0;
+
+const STM32F4_RCC: u32 = 0x4002_3800;
+const STM32F4_HSI: f64 = 16_000_000.0;
+
+const STM32H7_RCC: u32 = 0x5802_4400;
+const STM32H7_HSI: f64 = 64_000_000.0;
+const STM32H7_CSI: f64 = 4_000_000.0;
+
+fn field(val: u32, shift: u32, width: u32) -> u32 {
+    (val >> shift) & ((1 << width) - 1)
+}
+
+fn bit(val: u32, bit: u32) -> bool {
+    val & (1 << bit) != 0
+}
+
+//
+// The AHB (and CPU) prescalers are four bits; the APB prescalers are three.
+// For both, a clear high bit denotes no division.
+//
+fn ahb_div(val: u32) -> u32 {
+    match val {
+        0b1000..=0b1011 => 1 << (val - 0b0111),
+        0b1100..=0b1111 => 1 << (val - 0b0110),
+        _ => 1,
+    }
+}
+
+fn apb_div(val: u32) -> u32 {
+    match val {
+        0b100..=0b111 => 1 << (val - 0b011),
+        _ => 1,
+    }
+}
+
+fn freq(hz: Option<f64>) -> String {
+    match hz {
+        Some(hz) => format!("{:.3} MHz", hz / 1_000_000.0),
+        None => "unknown".to_string(),
+    }
+}
+
+fn div(hz: Option<f64>, div: u32) -> Option<f64> {
+    hz.map(|hz| hz / div as f64)
+}
+
+fn state(on: bool, ready: bool) -> &'static str {
+    match (on, ready) {
+        (true, true) => "on",
+        (true, false) => "not ready",
+        (false, _) => "off",
+    }
+}
+
+struct Clocks {
+    rows: Vec<(String, String, Option<f64>)>,
+    cpu: Option<f64>,
+}
+
+impl Clocks {
+    fn new() -> Self {
+        Self { rows: vec![], cpu: None }
+    }
+
+    fn add(&mut self, name: &str, detail: String, hz: Option<f64>) {
+        self.rows.push((name.to_string(), detail, hz));
+    }
+
+    fn section(&mut self, name: &str) {
+        self.rows.push((name.to_string(), "".to_string(), None));
+    }
+
+    fn print(&self) {
+        for (name, detail, hz) in &self.rows {
+            if detail.is_empty() {
+                println!("{}:", name);
+            } else {
+                println!("  {:<10} {:<44} {:>15}", name, detail, freq(*hz));
+            }
+        }
+    }
+}
+
+fn clocks_stm32f4(core: &mut dyn Core, hse: Option<f64>) -> Result<Clocks> {
+    let mut clocks = Clocks::new();
+
+    let cr = core.read_word_32(STM32F4_RCC)?;
+    let pllcfgr = core.read_word_32(STM32F4_RCC + 0x04)?;
+    let cfgr = core.read_word_32(STM32F4_RCC + 0x08)?;
+
+    clocks.section("Oscillators");
+    clocks.add(
+        "HSI",
+        state(bit(cr, 0), bit(cr, 1)).to_string(),
+        Some(STM32F4_HSI),
+    );
+    clocks.add(
+        "HSE",
+        format!(
+            "{}{}",
+            state(bit(cr, 16), bit(cr, 17)),
+            if bit(cr, 18) { ", bypassed" } else { "" }
+        ),
+        hse,
+    );
+
+    let (src, input) = if bit(pllcfgr, 22) {
+        ("HSE", hse)
+    } else {
+        ("HSI", Some(STM32F4_HSI))
+    };
+
+    let m = field(pllcfgr, 0, 6);
+    let n = field(pllcfgr, 6, 9);
+    let p = (field(pllcfgr, 16, 2) + 1) * 2;
+    let q = field(pllcfgr, 24, 4);
+
+    if m == 0 {
+        bail!("PLLM is invalid (RCC_PLLCFGR is 0x{:x})", pllcfgr);
+    }
+
+    let vco = input.map(|hz| hz / m as f64 * n as f64);
+
+    clocks.section("PLL");
+    clocks.add(
+        "VCO",
+        format!(
+            "{}, {} / M={} * N={}",
+            state(bit(cr, 24), bit(cr, 25)),
+            src,
+            m,
+            n
+        ),
+        vco,
+    );
+    clocks.add("PLLCLK", format!("VCO / P={}", p), div(vco, p));
+    clocks.add(
+        "PLL48CK",
+        format!("VCO / Q={}", q),
+        if q >= 2 { div(vco, q) } else { None },
+    );
+
+    let (sws, sysclk) = match field(cfgr, 2, 2) {
+        0 => ("HSI", Some(STM32F4_HSI)),
+        1 => ("HSE", hse),
+        2 => ("PLL", div(vco, p)),
+        _ => ("invalid", None),
+    };
+
+    let hpre = ahb_div(field(cfgr, 4, 4));
+    let ppre1 = apb_div(field(cfgr, 10, 3));
+    let ppre2 = apb_div(field(cfgr, 13, 3));
+    let hclk = div(sysclk, hpre);
+
+    clocks.section("Buses");
+    clocks.add("SYSCLK", format!("from {}", sws), sysclk);
+    clocks.add("HCLK", format!("SYSCLK / HPRE={}", hpre), hclk);
+    clocks.add("PCLK1", format!("HCLK / PPRE1={}", ppre1), div(hclk, ppre1));
+    clocks.add("PCLK2", format!("HCLK / PPRE2={}", ppre2), div(hclk, ppre2));
+
+    clocks.cpu = hclk;
+
+    Ok(clocks)
+}
+
+fn clocks_stm32h7(core: &mut dyn Core, hse: Option<f64>) -> Result<Clocks> {
+    let mut clocks = Clocks::new();
+
+    let cr = core.read_word_32(STM32H7_RCC)?;
+    let cfgr = core.read_word_32(STM32H7_RCC + 0x10)?;
+    let d1cfgr = core.read_word_32(STM32H7_RCC + 0x18)?;
+    let d2cfgr = core.read_word_32(STM32H7_RCC + 0x1c)?;
+    let d3cfgr = core.read_word_32(STM32H7_RCC + 0x20)?;
+    let pllckselr = core.read_word_32(STM32H7_RCC + 0x28)?;
+    let pllcfgr = core.read_word_32(STM32H7_RCC + 0x2c)?;
+
+    let hsi = STM32H7_HSI / (1 << field(cr, 3, 2)) as f64;
+
+    clocks.section("Oscillators");
+    clocks.add(
+        "HSI",
+        format!("{}, /{}", state(bit(cr, 0), bit(cr, 2)), 1 << field(cr, 3, 2)),
+        Some(hsi),
+    );
+    clocks.add(
+        "CSI",
+        state(bit(cr, 7), bit(cr, 8)).to_string(),
+        Some(STM32H7_CSI),
+    );
+    clocks.add(
+        "HSE",
+        format!(
+            "{}{}",
+            state(bit(cr, 16), bit(cr, 17)),
+            if bit(cr, 18) { ", bypassed" } else { "" }
+        ),
+        hse,
+    );
+
+    let (src, input) = match field(pllckselr, 0, 2) {
+        0 => ("HSI", Some(hsi)),
+        1 => ("CSI", Some(STM32H7_CSI)),
+        2 => ("HSE", hse),
+        _ => ("none", None),
+    };
+
+    clocks.section(&format!("PLLs (from {})", src));
+
+    let mut pll1p = None;
+
+    for pll in 0..3 {
+        let on = bit(cr, 24 + pll * 2);
+        let ready = bit(cr, 25 + pll * 2);
+        let m = field(pllckselr, 4 + pll * 8, 6);
+        let divr = core.read_word_32(STM32H7_RCC + 0x30 + pll * 8)?;
+        let fracr = core.read_word_32(STM32H7_RCC + 0x34 + pll * 8)?;
+
+        let name = format!("PLL{}", pll + 1);
+
+        if !on || m == 0 {
+            clocks.add(&name, state(on, ready).to_string(), None);
+            continue;
+        }
+
+        let n = field(divr, 0, 9) + 1;
+
+        let frac = if bit(pllcfgr, pll * 4) { field(fracr, 3, 13) } else { 0 };
+
+        let vco =
+            input.map(|hz| hz / m as f64 * (n as f64 + frac as f64 / 8192.0));
+
+        clocks.add(
+            &name,
+            format!(
+                "{}, VCO = {} / M={} * (N={} + {}/8192)",
+                state(on, ready),
+                src,
+                m,
+                n,
+                frac
+            ),
+            vco,
+        );
+
+        for (i, output) in ["P", "Q", "R"].iter().enumerate() {
+            let i = i as u32;
+            let enabled = bit(pllcfgr, 16 + pll * 3 + i);
+            let d = field(divr, 9 + i * 7, 7) + 1;
+            let hz = if enabled { div(vco, d) } else { None };
+
+            if pll == 0 && i == 0 {
+                pll1p = hz;
+            }
+
+            clocks.add(
+                &format!("{}{}", name, output),
+                if enabled {
+                    format!("VCO / DIV{}={}", output, d)
+                } else {
+                    "disabled".to_string()
+                },
+                hz,
+            );
+        }
+    }
+
+    let (sws, sys) = match field(cfgr, 3, 3) {
+        0 => ("HSI", Some(hsi)),
+        1 => ("CSI", Some(STM32H7_CSI)),
+        2 => ("HSE", hse),
+        3 => ("PLL1P", pll1p),
+        _ => ("invalid", None),
+    };
+
+    let d1cpre = ahb_div(field(d1cfgr, 8, 4));
+    let hpre = ahb_div(field(d1cfgr, 0, 4));
+    let d1ppre = apb_div(field(d1cfgr, 4, 3));
+    let d2ppre1 = apb_div(field(d2cfgr, 4, 3));
+    let d2ppre2 = apb_div(field(d2cfgr, 8, 3));
+    let d3ppre = apb_div(field(d3cfgr, 4, 3));
+
+    let cpu = div(sys, d1cpre);
+    let hclk = div(cpu, hpre);
+
+    clocks.section("Buses");
+    clocks.add("sys_ck", format!("from {}", sws), sys);
+    clocks.add("CPU", format!("sys_ck / D1CPRE={}", d1cpre), cpu);
+    clocks.add("HCLK", format!("CPU / HPRE={}", hpre), hclk);
+    clocks.add("PCLK3", format!("HCLK / D1PPRE={}", d1ppre), div(hclk, d1ppre));
+    clocks.add(
+        "PCLK1",
+        format!("HCLK / D2PPRE1={}", d2ppre1),
+        div(hclk, d2ppre1),
+    );
+    clocks.add(
+        "PCLK2",
+        format!("HCLK / D2PPRE2={}", d2ppre2),
+        div(hclk, d2ppre2),
+    );
+    clocks.add("PCLK4", format!("HCLK / D3PPRE={}", d3ppre), div(hclk, d3ppre));
+
+    clocks.cpu = cpu;
+
+    Ok(clocks)
+}
+
+//
+// Checks the computed CPU clock against the specified frequency (in kHz),
+// returning true if it matches.
+//
+fn clocks_check(cpu: Option<f64>, what: &str, khz: u32) -> bool {
+    let expected = khz as f64 * 1000.0;
+
+    let (matches, detail) = match cpu {
+        Some(cpu) => {
+            let err = (cpu - expected).abs() / expected * 100.0;
+            (err <= CLOCKS_TOLERANCE, format!("{:.1}% from CPU clock", err))
+        }
+        None => (true, "CPU clock unknown".to_string()),
+    };
+
+    println!(
+        "  {:<10} {:<44} {:>15}{}",
+        what,
+        detail,
+        freq(Some(expected)),
+        if matches { "" } else { " <- MISMATCH" }
+    );
+
+    matches
+}
+
+fn clocks(
+    hubris: &mut HubrisArchive,
+    core: &mut dyn Core,
+    _args: &Args,
+    subargs: &[String],
+) -> Result<()> {
+    let subargs = ClocksArgs::from_iter_safe(subargs)?;
+    let hse = subargs.hse.map(|hz| hz as f64);
+
+    let chip = match chip_identify(core, None)? {
+        Some(chip) => chip,
+        None => bail!("attached chip could not be identified"),
+    };
+
+    info!("attached chip is {}", chip.name);
+
+    let clocks = if chip.name.starts_with("STM32F4") {
+        clocks_stm32f4(core, hse)?
+    } else if chip.name.starts_with("STM32H742") {
+        clocks_stm32h7(core, hse)?
+    } else {
+        bail!("clock tree not known for {}", chip.name);
+    };
+
+    if hse.is_none() && clocks.cpu.is_none() {
+        warn!("CPU clock depends on HSE; specify its frequency with --hse");
+    }
+
+    clocks.print();
+
+    //
+    // Now check our CPU clock against the archive's notion of it (if any)
+    // and against the clock as measured.
+    //
+    let expected = if hubris.loaded() { hubris.clock(core)? } else { None };
+
+    let measured = if subargs.nomeasure {
+        None
+    } else {
+        let coreinfo = CoreInfo::read(core)?;
+        Some(itm_traceclock_measure(core, &coreinfo)?)
+    };
+
+    if expected.is_none() && measured.is_none() {
+        return Ok(());
+    }
+
+    println!("Checks:");
+
+    let mut mismatch = false;
+
+    if let Some(khz) = expected {
+        mismatch |= !clocks_check(clocks.cpu, "archive", khz);
+    }
+
+    if let Some(khz) = measured {
+        mismatch |= !clocks_check(clocks.cpu, "measured", khz);
+    }
+
+    if mismatch {
+        warn!("CPU clock disagrees with expected frequency");
+    }
+
+    Ok(())
+}
+
+pub fn init<'a, 'b>() -> (Command, App<'a, 'b>) {
+    (
+        Command::Attached {
+            name: "clocks",
+            archive: Archive::Optional,
+            attach: Attach::LiveOnly,
+            validate: Validate::None,
+            run: clocks,
+        },
+        ClocksArgs::clap(),
+    )
+}
@@ -0,0 +1,375 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use anyhow::{anyhow, bail, Context, Result};
+use colored::Colorize;
+use humility::core::Core;
+use humility::hubris::*;
+use humility_cmd::doppel::{Task, TaskDesc, TaskState};
+use humility_cmd::reflect::{self, Load};
+use humility_cmd::{Archive, Args, Command};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+use structopt::clap::App;
+use structopt::StructOpt;
+
+#[derive(StructOpt, Debug)]
+#[structopt(
+    name = "compare",
+    about = "compare test artifacts and report regressions"
+)]
+struct CompareArgs {
+    /// kind of artifact (default is to infer it from the file)
+    #[structopt(long, short,
+        possible_values = &["dump", "csv", "counters"],
+    )]
+    kind: Option<String>,
+
+    /// threshold for a metric, as metric=value (absolute) or metric=value%
+    /// (relative); a trailing '*' in the metric name matches any suffix
+    #[structopt(long = "threshold", short = "t", value_name = "metric=limit")]
+    thresholds: Vec<String>,
+
+    /// threshold for metrics without an explicit threshold
+    #[structopt(long, short, default_value = "10%", value_name = "limit")]
+    default: Limit,
+
+    /// show all metrics, not just those that changed
+    #[structopt(long, short)]
+    all: bool,
+
+    /// baseline artifact
+    baseline: String,
+
+    /// artifact to compare against the baseline
+    current: String,
+}
+
+#[derive(Copy, Clone, Debug)]
+enum Limit {
+    Absolute(f64),
+    Relative(f64),
+}
+
+impl FromStr for Limit {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (val, relative) = match s.strip_suffix('%') {
+            Some(val) => (val, true),
+            None => (s, false),
+        };
+
+        let val = val
+            .trim()
+            .parse::<f64>()
+            .map_err(|_| anyhow!("invalid threshold \"{}\"", s))?;
+
+        if val < 0.0 {
+            bail!("threshold \"{}\" must not be negative", s);
+        }
+
+        Ok(if relative { Limit::Relative(val) } else { Limit::Absolute(val) })
+    }
+}
+
+impl std::fmt::Display for Limit {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Limit::Absolute(val) => write!(f, "{}", val),
+            Limit::Relative(val) => write!(f, "{}%", val),
+        }
+    }
+}
+
+impl Limit {
+    fn exceeded(&self, baseline: f64, current: f64) -> bool {
+        let delta = (current - baseline).abs();
+
+        match self {
+            Limit::Absolute(val) => delta > *val,
+            Limit::Relative(_) if delta == 0.0 => false,
+            Limit::Relative(_) if baseline == 0.0 => true,
+            Limit::Relative(val) => delta * 100.0 / baseline.abs() > *val,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Kind {
+    Dump,
+    Csv,
+    Counters,
+}
+
+type Metrics = BTreeMap<String, f64>;
+
+fn kind(filename: &str, contents: &[u8]) -> Kind {
+    let ext = Path::new(filename).extension().and_then(|e| e.to_str());
+
+    if contents.starts_with(b"\x7fELF") {
+        Kind::Dump
+    } else if ext.map_or(false, |e| e.eq_ignore_ascii_case("csv")) {
+        Kind::Csv
+    } else {
+        Kind::Counters
+    }
+}
+
+//
+// For a dump, our metrics are the number of times each task has been
+// restarted, and whether or not each task is faulted.
+//
+fn dump_metrics(filename: &str) -> Result<Metrics> {
+    let mut hubris = HubrisArchive::new()?;
+    hubris.load_dump(filename)?;
+
+    let mut c = humility::core::attach_dump(filename, &hubris)?;
+    let core = c.as_mut();
+
+    let base = core.read_word_32(hubris.lookup_symword("TASK_TABLE_BASE")?)?;
+    let task_count =
+        core.read_word_32(hubris.lookup_symword("TASK_TABLE_SIZE")?)?;
+    let task_t = hubris.lookup_struct_byname("Task")?;
+
+    let mut taskblock = vec![0; task_t.size * task_count as usize];
+    core.read_8(base, &mut taskblock)?;
+
+    let mut metrics = Metrics::new();
+
+    for i in 0..task_count {
+        let offs = i as usize * task_t.size;
+        let task_value: reflect::Value =
+            reflect::load(&hubris, &taskblock, task_t, offs)?;
+        let task: Task = Task::from_value(&task_value)?;
+        let desc: TaskDesc = task.descriptor.load_from(&hubris, core)?;
+        let module = hubris.instr_mod(desc.entry_point).unwrap_or("<unknown>");
+
+        let faulted = matches!(task.state, TaskState::Faulted { .. });
+
+        metrics.insert(
+            format!("{}.restarts", module),
+            u32::from(task.generation) as f64,
+        );
+        metrics.insert(format!("{}.faulted", module), faulted as u32 as f64);
+    }
+
+    Ok(metrics)
+}
+
+//
+// For a CSV (e.g., of sensor readings), every column that is entirely
+// numeric yields its minimum, maximum and mean.  Columns that denote time
+// are skipped, as they will differ between any two runs.
+//
+fn csv_metrics(contents: &[u8]) -> Result<Metrics> {
+    let mut rdr = csv::Reader::from_reader(contents);
+    let headers = rdr.headers()?.clone();
+
+    let mut columns: Vec<Option<(f64, f64, f64, usize)>> = headers
+        .iter()
+        .map(|h| {
+            if h.to_lowercase().contains("time") {
+                None
+            } else {
+                Some((f64::MAX, f64::MIN, 0.0, 0))
+            }
+        })
+        .collect();
+
+    for record in rdr.records() {
+        let record = record?;
+
+        for (field, column) in record.iter().zip(columns.iter_mut()) {
+            let field = field.trim();
+
+            if field.is_empty() {
+                continue;
+            }
+
+            if let Some((min, max, sum, n)) = column {
+                match field.parse::<f64>() {
+                    Ok(val) => {
+                        *min = min.min(val);
+                        *max = max.max(val);
+                        *sum += val;
+                        *n += 1;
+                    }
+                    Err(_) => *column = None,
+                }
+            }
+        }
+    }
+
+    let mut metrics = Metrics::new();
+
+    for (header, column) in headers.iter().zip(columns.iter()) {
+        if let Some((min, max, sum, n)) = column {
+            if *n == 0 {
+                continue;
+            }
+
+            metrics.insert(format!("{}.min", header), *min);
+            metrics.insert(format!("{}.max", header), *max);
+            metrics.insert(format!("{}.mean", header), sum / *n as f64);
+        }
+    }
+
+    Ok(metrics)
+}
+
+//
+// Counters are expected one per line, with the value separated from the
+// name by whitespace, '=' or ','; lines that don't end in a number (e.g.,
+// headers) and lines that start with '#' are ignored.
+//
+fn counter_metrics(contents: &[u8]) -> Result<Metrics> {
+    let contents = std::str::from_utf8(contents)?;
+    let mut metrics = Metrics::new();
+
+    for line in contents.lines() {
+        let line = line.trim();
+
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let mut fields = line
+            .rsplitn(2, |c: char| c.is_whitespace() || c == '=' || c == ',');
+
+        if let (Some(val), Some(name)) = (fields.next(), fields.next()) {
+            if let Ok(val) = val.parse::<f64>() {
+                metrics.insert(name.trim().to_string(), val);
+            }
+        }
+    }
+
+    Ok(metrics)
+}
+
+fn metrics(filename: &str, kind: Option<Kind>) -> Result<(Kind, Metrics)> {
+    let contents = fs::read(filename)
+        .with_context(|| format!("failed to read {}", filename))?;
+    let kind = kind.unwrap_or_else(|| self::kind(filename, &contents));
+
+    let metrics = match kind {
+        Kind::Dump => dump_metrics(filename),
+        Kind::Csv => csv_metrics(&contents),
+        Kind::Counters => counter_metrics(&contents),
+    }
+    .with_context(|| format!("failed to process {}", filename))?;
+
+    Ok((kind, metrics))
+}
+
+fn threshold(thresholds: &[(String, Limit)], default: Limit, m: &str) -> Limit {
+    for (pattern, limit) in thresholds {
+        let matched = match pattern.strip_suffix('*') {
+            Some(prefix) => m.starts_with(prefix),
+            None => m == pattern,
+        };
+
+        if matched {
+            return *limit;
+        }
+    }
+
+    default
+}
+
+#[rustfmt::skip::macros(println, bail)]
+fn compare(
+    _hubris: &mut HubrisArchive,
+    _args: &Args,
+    subargs: &[String],
+) -> Result<()> {
+    let subargs = CompareArgs::from_iter_safe(subargs)?;
+
+    let thresholds = subargs
+        .thresholds
+        .iter()
+        .map(|t| match t.split_once('=') {
+            Some((name, limit)) => Ok((name.to_string(), limit.parse()?)),
+            None => Err(anyhow!("threshold \"{}\" must be metric=limit", t)),
+        })
+        .collect::<Result<Vec<(String, Limit)>>>()?;
+
+    let kind = subargs.kind.as_deref().map(|k| match k {
+        "dump" => Kind::Dump,
+        "csv" => Kind::Csv,
+        _ => Kind::Counters,
+    });
+
+    let (bkind, baseline) = metrics(&subargs.baseline, kind)?;
+    let (ckind, current) = metrics(&subargs.current, kind)?;
+
+    if bkind != ckind {
+        bail!("{} is a {:?} artifact, but {} is a {:?} artifact",
+            subargs.baseline, bkind, subargs.current, ckind);
+    }
+
+    if baseline.is_empty() {
+        bail!("no metrics found in {}", subargs.baseline);
+    }
+
+    println!("{:30} {:>12} {:>12} {:>12} {:>9} RESULT",
+        "METRIC", "BASELINE", "CURRENT", "DELTA", "LIMIT");
+
+    let mut regressions = 0;
+
+    for (name, b) in &baseline {
+        let limit = threshold(&thresholds, subargs.default, name);
+
+        let c = match current.get(name) {
+            Some(c) => *c,
+            None => {
+                regressions += 1;
+                println!("{:30} {:>12} {:>12} {:>12} {:>9} {}",
+                    name, b, "-", "-", limit.to_string(),
+                    "MISSING".red());
+                continue;
+            }
+        };
+
+        let result = if limit.exceeded(*b, c) {
+            regressions += 1;
+            "REGRESSED".red()
+        } else if c != *b {
+            "ok".yellow()
+        } else if subargs.all {
+            "ok".green()
+        } else {
+            continue;
+        };
+
+        println!("{:30} {:>12} {:>12} {:>12} {:>9} {}",
+            name, b, c, format!("{:+}", c - b), limit.to_string(), result);
+    }
+
+    for (name, c) in current.iter().filter(|(n, _)| !baseline.contains_key(*n))
+    {
+        println!("{:30} {:>12} {:>12} {:>12} {:>9} {}",
+            name, "-", c, "-", "-", "new");
+    }
+
+    if regressions > 0 {
+        bail!("{} metric(s) regressed relative to {}", regressions,
+            subargs.baseline);
+    }
+
+    Ok(())
+}
+
+pub fn init<'a, 'b>() -> (Command, App<'a, 'b>) {
+    (
+        Command::Unattached {
+            name: "compare",
+            archive: Archive::Ignored,
+            run: compare,
+        },
+        CompareArgs::clap(),
+    )
+}
@@ -0,0 +1,348 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use anyhow::{bail, Context, Result};
+use humility::core::Core;
+use humility::hubris::*;
+use humility_cmd::{attach_live, Archive, Args, Command};
+use humility_cortex::debug::{Register, DEMCR};
+use humility_cortex::dwt::{DWT_CTRL, DWT_PCSR};
+use humility_cortex::etm::*;
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io::Write;
+use std::time::{Duration, Instant};
+use structopt::clap::App;
+use structopt::StructOpt;
+
+#[macro_use]
+extern crate log;
+
+#[derive(StructOpt, Debug)]
+#[structopt(
+    name = "coverage",
+    about = "collect code coverage via PC sampling or ETM trace"
+)]
+struct CoverageArgs {
+    /// duration to sample the program counter
+    #[structopt(
+        long, short, default_value = "10000", value_name = "duration_ms",
+        parse(try_from_str = parse_int::parse),
+    )]
+    duration: u64,
+
+    /// ingest ETM data as CSV rather than sampling the program counter
+    #[structopt(long, short, value_name = "filename")]
+    ingest: Option<String>,
+
+    /// sets ETM trace identifier
+    #[structopt(
+        long, short, value_name = "identifier",
+        default_value = "0x54", parse(try_from_str = parse_int::parse),
+    )]
+    traceid: u8,
+
+    /// name of the test (emitted as the lcov test name)
+    #[structopt(long, short = "n", value_name = "name")]
+    testname: Option<String>,
+
+    /// file to which lcov output should be written (default is stdout)
+    #[structopt(long, short, value_name = "filename")]
+    output: Option<String>,
+}
+
+/*
+ * The value read from DWT_PCSR when the core is halted or in a state in
+ * which the PC cannot be sampled.
+ */
+const PCSR_INVALID: u32 = 0xffff_ffff;
+
+fn sample_pcs(
+    core: &mut dyn Core,
+    duration: Duration,
+) -> Result<(HashMap<u32, u64>, u64)> {
+    let ctrl = DWT_CTRL::read(core)?;
+
+    if ctrl.no_trace_sampling() {
+        bail!("PC sampling is not supported on this part");
+    }
+
+    let mut hits = HashMap::new();
+    let mut invalid = 0;
+    let started = Instant::now();
+
+    while started.elapsed() < duration {
+        let pc = DWT_PCSR::read(core)?.pc();
+
+        if pc == PCSR_INVALID {
+            invalid += 1;
+            continue;
+        }
+
+        *hits.entry(pc).or_insert(0) += 1;
+    }
+
+    Ok((hits, invalid))
+}
+
+fn sample(
+    core: &mut dyn Core,
+    duration: Duration,
+) -> Result<(HashMap<u32, u64>, u64)> {
+    //
+    // The DWT isn't accessible unless trace is enabled, so we enable it,
+    // remembering the original state of DEMCR to restore it when we're
+    // done.
+    //
+    let orig = DEMCR::read(core)?;
+    let mut demcr = orig;
+    demcr.set_trcena(true);
+    demcr.write(core)?;
+
+    let rval = sample_pcs(core, duration);
+    orig.write(core)?;
+
+    rval
+}
+
+#[rustfmt::skip::macros(bail)]
+fn ingest(
+    hubris: &HubrisArchive,
+    filename: &str,
+    traceid: u8,
+) -> Result<HashMap<u32, u64>> {
+    let file = File::open(filename)?;
+    let mut rdr = csv::Reader::from_reader(file);
+    let mut curaddr: Option<u32> = None;
+    let mut lastaddr: Option<u32> = None;
+    let mut broken = false;
+    let mut hits = HashMap::new();
+
+    let config = &ETM3Config {
+        alternative_encoding: true,
+        context_id: 0,
+        data_access: false,
+        traceid,
+    };
+
+    type SaleaeTraceRecord = (f64, u8, Option<String>, Option<String>);
+
+    let mut iter = rdr.deserialize();
+
+    etm_ingest(
+        config,
+        || {
+            if let Some(line) = iter.next() {
+                let record: SaleaeTraceRecord = line?;
+                Ok(Some((record.1, record.0)))
+            } else {
+                Ok(None)
+            }
+        },
+        |packet| {
+            if lastaddr.is_none() {
+                match packet.header {
+                    ETM3Header::ISync => {}
+                    _ => return Ok(()),
+                }
+            }
+
+            //
+            // We only count an instruction as covered if it actually executed
+            // (that is, if it wasn't a conditional instruction that failed
+            // its condition code check).
+            //
+            let mut instr = |executed| {
+                if broken {
+                    return;
+                }
+
+                let addr = curaddr.unwrap();
+
+                if executed {
+                    *hits.entry(addr).or_insert(0) += 1;
+                }
+
+                curaddr = match hubris.instr_len(addr) {
+                    Some(len) => Some(addr + len),
+                    None => {
+                        warn!("unknown instruction length at {:x}!", addr);
+                        broken = true;
+                        None
+                    }
+                };
+            };
+
+            match packet.header {
+                ETM3Header::PHeaderFormat1 { e, n } => {
+                    for _i in 0..e {
+                        instr(true);
+                    }
+
+                    for _i in 0..n {
+                        instr(false);
+                    }
+                }
+                ETM3Header::PHeaderFormat2 { e0, e1 } => {
+                    instr(!e0);
+                    instr(!e1);
+                }
+                _ => {}
+            }
+
+            match packet.payload {
+                ETM3Payload::ISync { address, .. } => {
+                    if broken {
+                        warn!("re-railing at offset {}", packet.offset);
+                        broken = false;
+                    }
+
+                    curaddr = Some(address);
+                    lastaddr = curaddr;
+                }
+                ETM3Payload::BranchAddress { addr, mask, .. } => {
+                    curaddr = Some((lastaddr.unwrap() & mask) | addr);
+                    lastaddr = curaddr;
+                }
+                ETM3Payload::None => {}
+            }
+
+            Ok(())
+        },
+    )?;
+
+    if hits.is_empty() {
+        bail!("no executed instructions found in {}", filename);
+    }
+
+    Ok(hits)
+}
+
+fn lcov(
+    hubris: &HubrisArchive,
+    hits: &HashMap<u32, u64>,
+    testname: &str,
+    out: &mut dyn Write,
+) -> Result<(usize, usize)> {
+    //
+    // Every line that appears in the line tables is instrumented; its hit
+    // count is the sum of the hits of all addresses that map to it.
+    //
+    let mut files: BTreeMap<String, BTreeMap<u64, u64>> = BTreeMap::new();
+
+    for (_, src) in hubris.instr_lines() {
+        if src.line == 0 {
+            continue;
+        }
+
+        files.entry(src.fullpath()).or_default().entry(src.line).or_insert(0);
+    }
+
+    for (addr, count) in hits {
+        if let Some(src) = hubris.instr_src(*addr) {
+            if src.line == 0 {
+                continue;
+            }
+
+            *files
+                .entry(src.fullpath())
+                .or_default()
+                .entry(src.line)
+                .or_insert(0) += count;
+        }
+    }
+
+    let mut found = 0;
+    let mut covered = 0;
+
+    for (file, lines) in &files {
+        let hit = lines.values().filter(|&&c| c != 0).count();
+
+        writeln!(out, "TN:{}", testname)?;
+        writeln!(out, "SF:{}", file)?;
+
+        for (line, count) in lines {
+            writeln!(out, "DA:{},{}", line, count)?;
+        }
+
+        writeln!(out, "LF:{}", lines.len())?;
+        writeln!(out, "LH:{}", hit)?;
+        writeln!(out, "end_of_record")?;
+
+        found += lines.len();
+        covered += hit;
+    }
+
+    Ok((found, covered))
+}
+
+#[rustfmt::skip::macros(bail)]
+fn coverage(
+    hubris: &mut HubrisArchive,
+    args: &Args,
+    subargs: &[String],
+) -> Result<()> {
+    let subargs = CoverageArgs::from_iter_safe(subargs)?;
+
+    let hits = match &subargs.ingest {
+        Some(filename) => ingest(hubris, filename, subargs.traceid)
+            .with_context(|| format!("failed to ingest {}", filename))?,
+        None => {
+            if args.dump.is_some() {
+                bail!("PC sampling requires a live target");
+            }
+
+            let mut core = attach_live(args)?;
+            let duration = Duration::from_millis(subargs.duration);
+
+            info!("sampling PC for {}ms", subargs.duration);
+
+            let (hits, invalid) = sample(core.as_mut(), duration)?;
+            let total: u64 = hits.values().sum();
+
+            if total == 0 {
+                bail!("no valid PC samples ({} invalid); is the core halted?",
+                    invalid);
+            }
+
+            info!("collected {} samples ({} invalid)", total, invalid);
+
+            hits
+        }
+    };
+
+    let testname = match &subargs.testname {
+        Some(name) => name.clone(),
+        None => "humility".to_string(),
+    };
+
+    let (found, covered) = match &subargs.output {
+        Some(filename) => {
+            let mut file = File::create(filename)
+                .with_context(|| format!("failed to create {}", filename))?;
+            lcov(hubris, &hits, &testname, &mut file)?
+        }
+        None => lcov(hubris, &hits, &testname, &mut std::io::stdout())?,
+    };
+
+    info!(
+        "{} of {} lines covered ({:.1}%)",
+        covered,
+        found,
+        if found != 0 { covered as f64 * 100.0 / found as f64 } else { 0.0 }
+    );
+
+    Ok(())
+}
+
+pub fn init<'a, 'b>() -> (Command, App<'a, 'b>) {
+    (
+        Command::Unattached {
+            name: "coverage",
+            archive: Archive::Required,
+            run: coverage,
+        },
+        CoverageArgs::clap(),
+    )
+}
@@ -0,0 +1,325 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+#[macro_use]
+extern crate log;
+
+use anyhow::{bail, Result};
+use hif::*;
+use humility::arch::ARMRegister;
+use humility::core::{Core, CoreOps};
+use humility::hubris::*;
+use humility_cmd::hiffy::*;
+use humility_cmd::{Archive, Args, Attach, Command, Validate};
+use humility_cortex::debug::{DEMCR, DFSR, DHCSR};
+use humility_cortex::dwt::{DWT_CTRL, DWT_CYCCNT};
+use std::thread;
+use std::time::{Duration, Instant};
+use structopt::clap::App;
+use structopt::StructOpt;
+
+//
+// The interval at which we poll the core for having hit a breakpoint.
+//
+const CYCLES_POLL: Duration = Duration::from_millis(5);
+
+#[derive(StructOpt, Debug)]
+#[structopt(
+    name = "cycles",
+    about = "measure cycles between addresses via the DWT cycle counter"
+)]
+struct CyclesArgs {
+    /// address (or symbol) at which to start counting
+    #[structopt(long, short, value_name = "address")]
+    from: String,
+
+    /// address (or symbol) at which to stop counting (by default, the
+    /// return address at the time the start address is hit)
+    #[structopt(long, short, value_name = "address")]
+    to: Option<String>,
+
+    /// number of measurements to make
+    #[structopt(long, short = "n", default_value = "1",
+        parse(try_from_str = parse_int::parse)
+    )]
+    iterations: u32,
+
+    /// call the specified HIF function to exercise the measured code
+    #[structopt(long, short, value_name = "function")]
+    call: Option<String>,
+
+    /// argument to the HIF function
+    #[structopt(long, short, value_name = "value", number_of_values = 1,
+        requires = "call", parse(try_from_str = parse_int::parse)
+    )]
+    arg: Vec<u32>,
+
+    /// core clock in MHz (by default, determined from the archive)
+    #[structopt(long, value_name = "MHz")]
+    clock: Option<u32>,
+
+    /// sets timeout for each measurement
+    #[structopt(
+        long, short = "T", default_value = "5000", value_name = "timeout_ms",
+        parse(try_from_str = parse_int::parse)
+    )]
+    timeout: u32,
+}
+
+fn cycles_addr(hubris: &HubrisArchive, addr: &str) -> Result<u32> {
+    match parse_int::parse::<u32>(addr) {
+        Ok(addr) => Ok(addr & !1),
+        Err(_) => Ok(hubris.lookup_symbol(addr)?.0),
+    }
+}
+
+//
+// Waits for the core to halt on a breakpoint at the specified address.
+//
+fn cycles_wait(
+    core: &mut dyn Core,
+    addr: u32,
+    timeout: Duration,
+) -> Result<()> {
+    let started = Instant::now();
+
+    loop {
+        if DHCSR::read(core)?.halted() {
+            let pc = core.read_reg(ARMRegister::PC)?;
+
+            if DFSR::read(core)?.breakpoint() && pc == addr {
+                DFSR::from(0x1f).write(core)?;
+                return Ok(());
+            }
+
+            bail!("core halted unexpectedly at 0x{:x}", pc);
+        }
+
+        if started.elapsed() > timeout {
+            bail!("timed out waiting for 0x{:x}", addr);
+        }
+
+        thread::sleep(CYCLES_POLL);
+    }
+}
+
+//
+// Makes our measurements, returning the cycle counts.  The core is halted
+// on entry and on return; the breakpoint that is set (if any) is recorded
+// in `armed`, allowing our caller to clear it should we fail.
+//
+fn cycles_measure(
+    core: &mut dyn Core,
+    armed: &mut Option<u32>,
+    subargs: &CyclesArgs,
+    from: u32,
+    to: Option<u32>,
+    mut call: Option<(&mut HiffyContext<'_>, &[Op])>,
+) -> Result<Vec<u32>> {
+    let timeout = Duration::from_millis(subargs.timeout.into());
+    let mut rval = vec![];
+
+    for _ in 0..subargs.iterations {
+        core.set_breakpoint(from)?;
+        *armed = Some(from);
+        core.run()?;
+
+        if let Some((ref mut context, ops)) = call {
+            context.start(core, ops, None)?;
+        }
+
+        cycles_wait(core, from, timeout)?;
+
+        let to = match to {
+            Some(to) => to,
+            None => core.read_reg(ARMRegister::LR)? & !1,
+        };
+
+        //
+        // With the breakpoint on our start address cleared, we can resume
+        // without stepping; zero the counter and run to our end address.
+        //
+        core.clear_breakpoint(from)?;
+        *armed = None;
+        core.set_breakpoint(to)?;
+        *armed = Some(to);
+        DWT_CYCCNT::from(0).write(core)?;
+        core.run()?;
+
+        cycles_wait(core, to, timeout)?;
+        rval.push(DWT_CYCCNT::read(core)?.count());
+        core.clear_breakpoint(to)?;
+        *armed = None;
+
+        if let Some((ref mut context, _)) = call {
+            core.run()?;
+
+            while !context.done(core)? {
+                thread::sleep(Duration::from_millis(100));
+            }
+
+            context.results(core)?;
+            core.halt()?;
+        }
+    }
+
+    Ok(rval)
+}
+
+fn cycles_time(cycles: f64, khz: Option<u32>) -> String {
+    match khz {
+        Some(khz) if khz != 0 => {
+            let us = cycles / khz as f64 * 1000.0;
+
+            if us >= 1000.0 {
+                format!("{:.3}ms", us / 1000.0)
+            } else {
+                format!("{:.3}us", us)
+            }
+        }
+        _ => "-".to_string(),
+    }
+}
+
+fn cycles(
+    hubris: &mut HubrisArchive,
+    core: &mut dyn Core,
+    _args: &Args,
+    subargs: &[String],
+) -> Result<()> {
+    let subargs = CyclesArgs::from_iter_safe(subargs)?;
+
+    if subargs.iterations == 0 {
+        bail!("number of iterations must be non-zero");
+    }
+
+    let from = cycles_addr(hubris, &subargs.from)?;
+    let to = match subargs.to {
+        Some(ref to) => Some(cycles_addr(hubris, to)?),
+        None => None,
+    };
+
+    let khz = match subargs.clock {
+        Some(mhz) => Some(mhz * 1000),
+        None => hubris.clock(core)?,
+    };
+
+    let mut context = match subargs.call {
+        Some(_) => Some(HiffyContext::new(hubris, core, subargs.timeout)?),
+        None => None,
+    };
+
+    let ops = match (&mut context, &subargs.call) {
+        (Some(context), Some(name)) => {
+            let funcs = context.functions()?;
+            let func = funcs.get(name, subargs.arg.len())?;
+            let mut ops: Vec<Op> =
+                subargs.arg.iter().map(|&a| Op::Push32(a)).collect();
+
+            ops.push(Op::Call(func.id));
+
+            if !subargs.arg.is_empty() {
+                ops.push(Op::DropN(subargs.arg.len() as u8));
+            }
+
+            ops.push(Op::Done);
+            ops
+        }
+        _ => vec![],
+    };
+
+    core.halt()?;
+
+    let mut demcr = DEMCR::read(core)?;
+    demcr.set_trcena(true);
+    demcr.write(core)?;
+
+    let mut ctrl = DWT_CTRL::read(core)?;
+
+    if ctrl.no_cycle_counter() {
+        core.run()?;
+        bail!("DWT has no cycle counter");
+    }
+
+    ctrl.set_cyccnt_enabled(true);
+    ctrl.write(core)?;
+
+    info!(
+        "measuring from 0x{:x} to {}",
+        from,
+        match to {
+            Some(to) => format!("0x{:x}", to),
+            None => "return".to_string(),
+        }
+    );
+
+    let call = context.as_mut().map(|c| (c, ops.as_slice()));
+    let mut armed = None;
+    let rval = cycles_measure(core, &mut armed, &subargs, from, to, call);
+
+    //
+    // Whether or not we succeeded, remove our breakpoint and resume.
+    //
+    if !DHCSR::read(core)?.halted() {
+        core.halt()?;
+    }
+
+    if let Some(addr) = armed {
+        core.clear_breakpoint(addr)?;
+    }
+
+    core.run()?;
+
+    let samples = rval?;
+
+    println!("{:>4} {:>12} {:>12}", "ITER", "CYCLES", "TIME");
+
+    for (i, cycles) in samples.iter().enumerate() {
+        println!(
+            "{:>4} {:>12} {:>12}",
+            i,
+            cycles,
+            cycles_time(*cycles as f64, khz)
+        );
+    }
+
+    if samples.len() > 1 {
+        let min = *samples.iter().min().unwrap();
+        let max = *samples.iter().max().unwrap();
+        let mean = samples.iter().map(|&c| c as f64).sum::<f64>()
+            / samples.len() as f64;
+
+        for (what, cycles) in
+            [("min", min as f64), ("mean", mean), ("max", max as f64)]
+        {
+            println!(
+                "{:>4} {:>12.1} {:>12}",
+                what,
+                cycles,
+                cycles_time(cycles, khz)
+            );
+        }
+    }
+
+    Ok(())
+}
+
+pub fn init<'a, 'b>() -> (Command, App<'a, 'b>) {
+    (
+        Command::Attached {
+            name: "cycles",
+            archive: Archive::Required,
+            attach: Attach::Requires(
+                CoreOps::READ
+                    | CoreOps::REGISTERS
+                    | CoreOps::WRITE
+                    | CoreOps::CONTROL
+                    | CoreOps::BREAKPOINTS,
+            ),
+            validate: Validate::Booted,
+            run: cycles,
+        },
+        CyclesArgs::clap(),
+    )
+}
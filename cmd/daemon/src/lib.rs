@@ -0,0 +1,61 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+#[macro_use]
+extern crate log;
+
+use anyhow::{Context, Result};
+use humility::core::Core;
+use humility::hubris::*;
+use humility::net;
+use humility_cmd::{Archive, Args, Attach, Command, Validate};
+use std::net::TcpListener;
+use structopt::clap::App;
+use structopt::StructOpt;
+
+#[derive(StructOpt, Debug)]
+#[structopt(
+    name = "daemon",
+    about = "share the attached probe with remote clients over TCP"
+)]
+struct DaemonArgs {
+    /// address on which to listen for clients
+    #[structopt(
+        long,
+        short,
+        default_value = "127.0.0.1:9437",
+        value_name = "address"
+    )]
+    listen: String,
+}
+
+fn daemon(
+    _hubris: &mut HubrisArchive,
+    core: &mut dyn Core,
+    _args: &Args,
+    subargs: &[String],
+) -> Result<()> {
+    let subargs = DaemonArgs::from_iter_safe(subargs)?;
+
+    let listener = TcpListener::bind(&subargs.listen)
+        .with_context(|| format!("failed to listen on {}", subargs.listen))?;
+
+    info!("serving {} on {}", core.info().0, subargs.listen);
+    info!("clients may perform: {}", core.ops());
+
+    net::serve(core, &listener)
+}
+
+pub fn init<'a, 'b>() -> (Command, App<'a, 'b>) {
+    (
+        Command::Attached {
+            name: "daemon",
+            archive: Archive::Ignored,
+            attach: Attach::LiveOnly,
+            validate: Validate::None,
+            run: daemon,
+        },
+        DaemonArgs::clap(),
+    )
+}
@@ -0,0 +1,258 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use anyhow::{bail, Result};
+use humility::core::Core;
+use humility::hubris::*;
+use humility_cmd::{Archive, Args, Attach, Command, Validate};
+use structopt::clap::App;
+use structopt::StructOpt;
+
+//
+// The registers common to every access port, and those specific to a MEM-AP.
+//
+const AP_IDR: u8 = 0xfc;
+const AP_BASE: u8 = 0xf8;
+const MEMAP_CSW: u8 = 0x00;
+const MEMAP_TAR: u8 = 0x04;
+const MEMAP_DRW: u8 = 0x0c;
+
+//
+// The fields of the MEM-AP's Control/Status Word that we concern ourselves
+// with.
+//
+const CSW_SIZE_MASK: u32 = 0b111;
+const CSW_SIZE_WORD: u32 = 0b010;
+const CSW_ADDRINC_MASK: u32 = 0b11 << 4;
+const CSW_DEVICEEN: u32 = 1 << 6;
+const CSW_SPIDEN: u32 = 1 << 23;
+
+/// The AP class that denotes a MEM-AP
+const AP_CLASS_MEMAP: u32 = 0b1000;
+
+#[derive(StructOpt, Debug)]
+#[structopt(name = "dap", about = "raw access to debug and access ports")]
+struct DapArgs {
+    /// list the access ports
+    #[structopt(long, short, conflicts_with_all = &["dp", "ap"])]
+    list: bool,
+
+    /// debug port register to access
+    #[structopt(long, value_name = "register", conflicts_with = "ap",
+        parse(try_from_str = parse_int::parse),
+    )]
+    dp: Option<u8>,
+
+    /// access port to select
+    #[structopt(long, value_name = "ap",
+        parse(try_from_str = parse_int::parse),
+    )]
+    ap: Option<u8>,
+
+    /// access port register to access
+    #[structopt(long, short, value_name = "register", requires = "ap",
+        parse(try_from_str = parse_int::parse),
+    )]
+    reg: Option<u8>,
+
+    /// memory address to access via the selected MEM-AP
+    #[structopt(long, short, value_name = "address", requires = "ap",
+        conflicts_with = "reg", parse(try_from_str = parse_int::parse),
+    )]
+    mem: Option<u32>,
+
+    /// value to write (rather than reading)
+    #[structopt(long, short, value_name = "value",
+        parse(try_from_str = parse_int::parse),
+    )]
+    write: Option<u32>,
+}
+
+fn dap_memap(idr: u32) -> bool {
+    (idr >> 13) & 0b1111 == AP_CLASS_MEMAP
+}
+
+fn dap_aptype(idr: u32) -> &'static str {
+    if !dap_memap(idr) {
+        return match ((idr >> 13) & 0b1111, idr & 0b1111) {
+            (0, 0) => "JTAG-AP",
+            (0, _) => "COM-AP",
+            _ => "<unknown>",
+        };
+    }
+
+    match idr & 0b1111 {
+        0x1 => "AHB3-AP",
+        0x2 => "APB2/APB3-AP",
+        0x4 => "AXI3/AXI4-AP",
+        0x5 => "AHB5-AP",
+        0x6 => "APB4-AP",
+        0x7 => "AXI5-AP",
+        0x8 => "AHB5-AP (enhanced HPROT)",
+        _ => "MEM-AP",
+    }
+}
+
+fn dap_list(core: &mut dyn Core) -> Result<()> {
+    println!(
+        "{:>3} {:>10} {:24} {:>10} {:>10} FLAGS",
+        "AP", "IDR", "TYPE", "BASE", "CSW"
+    );
+
+    //
+    // Access ports are numbered contiguously from zero; the first one that
+    // has a zero IDR denotes the end of them.
+    //
+    for ap in 0..=u8::MAX {
+        let idr = core.read_ap(ap, AP_IDR)?;
+
+        if idr == 0 {
+            break;
+        }
+
+        if !dap_memap(idr) {
+            println!("{:>3} 0x{:08x} {:24}", ap, idr, dap_aptype(idr));
+            continue;
+        }
+
+        let base = core.read_ap(ap, AP_BASE)?;
+        let csw = core.read_ap(ap, MEMAP_CSW)?;
+
+        let mut flags = vec![];
+
+        if csw & CSW_DEVICEEN == 0 {
+            flags.push("disabled");
+        }
+
+        if csw & CSW_SPIDEN != 0 {
+            flags.push("secure-debug");
+        }
+
+        println!(
+            "{:>3} 0x{:08x} {:24} 0x{:08x} 0x{:08x} {}",
+            ap,
+            idr,
+            dap_aptype(idr),
+            base,
+            csw,
+            flags.join(",")
+        );
+    }
+
+    Ok(())
+}
+
+//
+// Accesses a word of memory via the specified MEM-AP, restoring its
+// Control/Status Word when done.
+//
+fn dap_mem(
+    core: &mut dyn Core,
+    ap: u8,
+    addr: u32,
+    write: Option<u32>,
+) -> Result<Option<u32>> {
+    let idr = core.read_ap(ap, AP_IDR)?;
+
+    if !dap_memap(idr) {
+        bail!("AP {} is not a MEM-AP (IDR is 0x{:08x})", ap, idr);
+    }
+
+    if addr & 0b11 != 0 {
+        bail!("address 0x{:x} is not word-aligned", addr);
+    }
+
+    let csw = core.read_ap(ap, MEMAP_CSW)?;
+
+    if csw & CSW_DEVICEEN == 0 {
+        bail!("AP {} is not enabled (CSW is 0x{:08x})", ap, csw);
+    }
+
+    let access = (csw & !(CSW_SIZE_MASK | CSW_ADDRINC_MASK)) | CSW_SIZE_WORD;
+    core.write_ap(ap, MEMAP_CSW, access)?;
+
+    let rval: Result<Option<u32>> = (|| {
+        core.write_ap(ap, MEMAP_TAR, addr)?;
+
+        match write {
+            Some(value) => {
+                core.write_ap(ap, MEMAP_DRW, value)?;
+                Ok(None)
+            }
+            None => Ok(Some(core.read_ap(ap, MEMAP_DRW)?)),
+        }
+    })();
+
+    core.write_ap(ap, MEMAP_CSW, csw)?;
+
+    rval
+}
+
+fn dap(
+    _hubris: &mut HubrisArchive,
+    core: &mut dyn Core,
+    _args: &Args,
+    subargs: &[String],
+) -> Result<()> {
+    let subargs = DapArgs::from_iter_safe(subargs)?;
+
+    if subargs.list {
+        return dap_list(core);
+    }
+
+    let (what, rval) = match (subargs.dp, subargs.ap) {
+        (Some(reg), _) => (
+            format!("DP register 0x{:x}", reg),
+            match subargs.write {
+                Some(value) => {
+                    core.write_dp(reg, value)?;
+                    None
+                }
+                None => Some(core.read_dp(reg)?),
+            },
+        ),
+
+        (None, Some(ap)) => match (subargs.reg, subargs.mem) {
+            (Some(reg), _) => (
+                format!("AP {} register 0x{:x}", ap, reg),
+                match subargs.write {
+                    Some(value) => {
+                        core.write_ap(ap, reg, value)?;
+                        None
+                    }
+                    None => Some(core.read_ap(ap, reg)?),
+                },
+            ),
+            (None, Some(addr)) => (
+                format!("AP {} address 0x{:08x}", ap, addr),
+                dap_mem(core, ap, addr, subargs.write)?,
+            ),
+            (None, None) => bail!("must specify a register or an address"),
+        },
+
+        (None, None) => {
+            bail!("must specify a DP register or an AP (or list the APs)")
+        }
+    };
+
+    match rval {
+        Some(value) => println!("{} = 0x{:08x}", what, value),
+        None => println!("{} written", what),
+    }
+
+    Ok(())
+}
+
+pub fn init<'a, 'b>() -> (Command, App<'a, 'b>) {
+    (
+        Command::Attached {
+            name: "dap",
+            archive: Archive::Optional,
+            attach: Attach::LiveOnly,
+            validate: Validate::None,
+            run: dap,
+        },
+        DapArgs::clap(),
+    )
+}
@@ -0,0 +1,316 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+#[macro_use]
+extern crate log;
+
+use anyhow::{bail, Result};
+use humility::arch::ARMRegister;
+use humility::core::Core;
+use humility::hubris::*;
+use humility_cmd::halted::*;
+use humility_cmd::{coalesced, Archive, Args, Attach, Command, Validate};
+use humility_cortex::debug::*;
+use num_traits::FromPrimitive;
+use std::collections::HashMap;
+use std::thread;
+use std::time::Duration;
+use structopt::clap::App;
+use structopt::StructOpt;
+
+#[derive(StructOpt, Debug)]
+#[structopt(
+    name = "faultmon",
+    about = "halt on faults and display fault state and backtrace"
+)]
+struct FaultmonArgs {
+    /// interval at which to poll the target, in milliseconds
+    #[structopt(long, short, default_value = "100", value_name = "ms")]
+    interval: u64,
+
+    /// resume the target after each fault and continue monitoring
+    #[structopt(long = "continue", short)]
+    resume: bool,
+
+    /// write a dump upon each fault
+    #[structopt(long, short)]
+    dump: bool,
+
+    /// show line number information with stack backtrace
+    #[structopt(long, short)]
+    line: bool,
+}
+
+//
+// The sizes of the basic and extended (that is, with floating point state)
+// exception frames.
+//
+const FRAME_BASIC: u32 = 8 * 4;
+const FRAME_EXTENDED: u32 = 26 * 4;
+
+fn fault_name(exception: u32) -> &'static str {
+    match exception {
+        3 => "HardFault",
+        4 => "MemManage",
+        5 => "BusFault",
+        6 => "UsageFault",
+        7 => "SecureFault",
+        _ => "<unknown>",
+    }
+}
+
+fn fault_causes(core: &mut dyn Core) -> Result<Vec<String>> {
+    let cfsr = CFSR::read(core)?;
+    let hfsr = HFSR::read(core)?;
+
+    let mut causes = vec![];
+
+    let mut cause = |set: bool, what: &str| {
+        if set {
+            causes.push(what.to_string());
+        }
+    };
+
+    cause(hfsr.forced_fault(), "escalated to HardFault");
+    cause(hfsr.vector_fault(), "vector table read error");
+    cause(hfsr.debug_fault(), "debug event");
+
+    cause(cfsr.mem_instr_access(), "instruction access violation");
+    cause(cfsr.mem_data_access(), "data access violation");
+    cause(cfsr.mem_exception_return(), "MemManage fault on exception return");
+    cause(cfsr.mem_exception_entry(), "MemManage fault on exception entry");
+    cause(cfsr.mem_lazy_fp(), "MemManage fault on lazy FP preservation");
+
+    cause(cfsr.bus_instr_prefetch(), "instruction prefetch bus error");
+    cause(cfsr.bus_precise_data(), "precise data bus error");
+    cause(cfsr.bus_imprecise_data(), "imprecise data bus error");
+    cause(cfsr.bus_exception_return(), "bus fault on exception return");
+    cause(cfsr.bus_exception_entry(), "bus fault on exception entry");
+    cause(cfsr.bus_lazy_fp(), "bus fault on lazy FP preservation");
+
+    cause(cfsr.usage_undefined_instr(), "undefined instruction");
+    cause(cfsr.usage_invalid_state(), "invalid state");
+    cause(cfsr.usage_invalid_pc(), "invalid PC on exception return");
+    cause(cfsr.usage_no_coprocessor(), "no coprocessor");
+    cause(cfsr.usage_unaligned(), "unaligned access");
+    cause(cfsr.usage_divide_by_zero(), "divide by zero");
+
+    if cfsr.mem_addr_valid() {
+        causes.push(format!("MMFAR = 0x{:08x}", MMFAR::read(core)?.address()));
+    }
+
+    if cfsr.bus_addr_valid() {
+        causes.push(format!("BFAR = 0x{:08x}", BFAR::read(core)?.address()));
+    }
+
+    Ok(causes)
+}
+
+//
+// Reconstructs the registers at the time of the fault.  At the vector
+// catch, the exception frame has been pushed to either the process stack
+// (if the fault occurred in a task) or the main stack (if it occurred in
+// the kernel), and EXC_RETURN in LR tells us which -- and if the frame has
+// been extended with floating point state.  Registers not in the frame
+// (R4-R11) are still live.
+//
+fn fault_registers(
+    core: &mut dyn Core,
+) -> Result<(bool, HashMap<ARMRegister, u32>)> {
+    let exc_return = core.read_reg(ARMRegister::LR)?;
+    let process = exc_return & (1 << 2) != 0;
+
+    let sp = core.read_reg(if process {
+        ARMRegister::PSP
+    } else {
+        ARMRegister::MSP
+    })?;
+
+    let mut regs = HashMap::new();
+
+    for r in 4..=11 {
+        let reg = ARMRegister::from_u16(r).unwrap();
+        regs.insert(reg, core.read_reg(reg)?);
+    }
+
+    let mut frame = [0u8; FRAME_BASIC as usize];
+    core.read_8(sp, &mut frame)?;
+
+    let stacked = [
+        ARMRegister::R0,
+        ARMRegister::R1,
+        ARMRegister::R2,
+        ARMRegister::R3,
+        ARMRegister::R12,
+        ARMRegister::LR,
+        ARMRegister::PC,
+        ARMRegister::xPSR,
+    ];
+
+    for (i, reg) in stacked.iter().enumerate() {
+        let o = i * 4;
+        let val = u32::from_le_bytes(frame[o..o + 4].try_into().unwrap());
+        regs.insert(*reg, val);
+    }
+
+    //
+    // The stack pointer at the time of the fault is above the frame, and
+    // above any alignment padding (as denoted by bit 9 of the stacked PSR).
+    //
+    let size =
+        if exc_return & (1 << 4) == 0 { FRAME_EXTENDED } else { FRAME_BASIC };
+
+    let pad = if regs[&ARMRegister::xPSR] & (1 << 9) != 0 { 4 } else { 0 };
+
+    regs.insert(ARMRegister::SP, sp + size + pad);
+
+    Ok((process, regs))
+}
+
+fn faultmon_report(
+    hubris: &HubrisArchive,
+    core: &mut dyn Core,
+    subargs: &FaultmonArgs,
+) -> Result<()> {
+    let exception = core.read_reg(ARMRegister::xPSR)? & 0x1ff;
+    let causes = fault_causes(core)?;
+    let (process, regs) = fault_registers(core)?;
+
+    warn!("core halted on {} fault", fault_name(exception));
+
+    println!("{:>8}: {}", "fault", fault_name(exception));
+
+    for cause in &causes {
+        println!("{:>8}  {}", "", cause);
+    }
+
+    //
+    // If the fault occurred on the process stack, it belongs to the current
+    // task; otherwise, it's the kernel's.
+    //
+    let (task, limit) = if process {
+        let (task, name, limit) = current_task(hubris, core)?;
+        println!("{:>8}: {} ({})", "task", name, task.id());
+        (task, limit)
+    } else {
+        println!("{:>8}: kernel", "task");
+        (HubrisTask::Kernel, kernel_stack_limit(core)?)
+    };
+
+    let pc = regs[&ARMRegister::PC];
+
+    println!(
+        "{:>8}: 0x{:08x}{}",
+        "pc",
+        pc,
+        match hubris.instr_sym(pc) {
+            Some((name, base)) => format!(" ({}+0x{:x})", name, pc - base),
+            None => "".to_string(),
+        }
+    );
+
+    println!("\n   registers at fault:");
+    print_regs(&regs);
+
+    println!("\n   backtrace:");
+
+    match hubris.stack(core, task, limit, &regs) {
+        Ok(stack) => print_stack(hubris, &stack, subargs.line),
+        Err(err) => println!("      <failed to unwind: {}>", err),
+    }
+
+    println!();
+
+    Ok(())
+}
+
+fn faultmon(
+    hubris: &mut HubrisArchive,
+    core: &mut dyn Core,
+    _args: &Args,
+    subargs: &[String],
+) -> Result<()> {
+    let subargs = FaultmonArgs::from_iter_safe(subargs)?;
+    let interval = Duration::from_millis(subargs.interval);
+
+    //
+    // Enable vector catch on every fault, remembering the original state
+    // of DEMCR to restore it when we're done.
+    //
+    let orig = DEMCR::read(core)?;
+    let mut demcr = orig;
+
+    demcr.set_vc_harderr(true);
+    demcr.set_vc_interr(true);
+    demcr.set_vc_buserr(true);
+    demcr.set_vc_staterr(true);
+    demcr.set_vc_chkerr(true);
+    demcr.set_vc_nocperr(true);
+    demcr.set_vc_mmerr(true);
+    demcr.write(core)?;
+
+    info!("monitoring for faults; ^C to stop");
+
+    let rval: Result<()> = (|| loop {
+        if !DHCSR::read(core)?.halted() {
+            thread::sleep(interval);
+            continue;
+        }
+
+        let dfsr = DFSR::read(core)?;
+
+        if !dfsr.vector_catch() {
+            let pc = core.read_reg(ARMRegister::PC)?;
+            bail!(
+                "target halted at 0x{:x} on something other than a fault",
+                pc
+            );
+        }
+
+        //
+        // Clear the vector catch status (which is write-one-to-clear) so
+        // that we can distinguish the next one.
+        //
+        DFSR::from(u32::from(dfsr)).write(core)?;
+
+        //
+        // Unwinding the stack results in many small reads, so we coalesce
+        // them -- but not those of the dump, which are already large.  (The
+        // core is halted, but the coalescing core must be told as much.)
+        //
+        coalesced(hubris, core, |core| {
+            core.halt()?;
+            faultmon_report(hubris, core, &subargs)
+        })?;
+
+        if subargs.dump {
+            hubris.dump(core, None)?;
+        }
+
+        if !subargs.resume {
+            info!("core left halted at fault");
+            return Ok(());
+        }
+
+        core.run()?;
+        info!("core resumed; monitoring for faults");
+    })();
+
+    orig.write(core)?;
+
+    rval
+}
+
+pub fn init<'a, 'b>() -> (Command, App<'a, 'b>) {
+    (
+        Command::Attached {
+            name: "faultmon",
+            archive: Archive::Required,
+            attach: Attach::LiveOnly,
+            validate: Validate::Match,
+            run: faultmon,
+        },
+        FaultmonArgs::clap(),
+    )
+}
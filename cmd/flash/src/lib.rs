@@ -0,0 +1,142 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+#[macro_use]
+extern crate log;
+
+use anyhow::{bail, Context, Result};
+use humility::core::{Core, CoreOps};
+use humility::hubris::*;
+use humility_cmd::{Archive, Args, Attach, Command, Validate};
+use indicatif::{HumanBytes, HumanDuration};
+use std::io::Write;
+use std::time::Instant;
+use structopt::clap::App;
+use structopt::StructOpt;
+
+#[derive(StructOpt, Debug)]
+#[structopt(name = "flash", about = "flash the archive's image to the target")]
+struct FlashArgs {
+    /// flash even if the target appears to already be running the archive
+    #[structopt(long, short = "F")]
+    force: bool,
+
+    /// do not verify the image after flashing it
+    #[structopt(long)]
+    noverify: bool,
+
+    /// leave the target halted rather than resetting it after flashing
+    #[structopt(long)]
+    noreset: bool,
+}
+
+fn flash(
+    hubris: &mut HubrisArchive,
+    core: &mut dyn Core,
+    _args: &Args,
+    subargs: &[String],
+) -> Result<()> {
+    let subargs = FlashArgs::from_iter_safe(subargs)?;
+
+    if !subargs.force
+        && hubris.validate(core, HubrisValidate::ArchiveMatch).is_ok()
+    {
+        info!("target already matches archive; use -F to flash anyway");
+        return Ok(());
+    }
+
+    let segments = hubris.image_segments()?;
+    let total: u64 = segments.iter().map(|(_, data)| data.len() as u64).sum();
+
+    for (addr, data) in &segments {
+        info!(
+            "segment at 0x{:08x}: {} (0x{:x} bytes)",
+            addr,
+            HumanBytes(data.len() as u64),
+            data.len()
+        );
+    }
+
+    //
+    // Our core programs an ELF file, so we write the image out to a
+    // temporary file (which is removed when we're done with it).
+    //
+    let mut elf = tempfile::Builder::new()
+        .prefix("humility-flash-")
+        .suffix(".elf")
+        .tempfile()
+        .context("failed to create temporary file")?;
+
+    elf.write_all(&hubris.extract_file("img/final.elf")?)
+        .and_then(|_| elf.flush())
+        .with_context(|| format!("failed to write {}", elf.path().display()))?;
+
+    let started = Instant::now();
+
+    info!("flashing {}...", HumanBytes(total));
+
+    let rval = core.load(elf.path());
+
+    //
+    // A failure to remove the temporary file must not hide the result of
+    // flashing, so we merely warn about it.
+    //
+    let path = elf.path().to_path_buf();
+
+    if let Err(err) = elf.close() {
+        warn!("failed to remove {}: {}", path.display(), err);
+    }
+
+    rval?;
+
+    info!(
+        "flashed {} in {}",
+        HumanBytes(total),
+        HumanDuration(started.elapsed())
+    );
+
+    if !subargs.noverify {
+        let mut mismatches = vec![];
+
+        for (base, data) in &segments {
+            let name = format!("segment at 0x{:08x}", base);
+            let region = verify_region(core, &name, *base, data, None)?;
+
+            if let Some((addr, n)) = region.differs {
+                error!("{} differs at 0x{:08x} ({} bytes)", name, addr, n);
+                mismatches.push(region);
+            }
+        }
+
+        if !mismatches.is_empty() {
+            bail!(
+                "image failed verification in {} of {} segments",
+                mismatches.len(),
+                segments.len()
+            );
+        }
+
+        info!("image verified");
+    }
+
+    if !subargs.noreset {
+        core.reset()?;
+        info!("target reset");
+    }
+
+    Ok(())
+}
+
+pub fn init<'a, 'b>() -> (Command, App<'a, 'b>) {
+    (
+        Command::Attached {
+            name: "flash",
+            archive: Archive::Required,
+            attach: Attach::Requires(CoreOps::FLASH | CoreOps::READ),
+            validate: Validate::None,
+            run: flash,
+        },
+        FlashArgs::clap(),
+    )
+}
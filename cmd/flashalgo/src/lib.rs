@@ -0,0 +1,165 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+#[macro_use]
+extern crate log;
+
+use anyhow::{bail, Result};
+use humility::core::Core;
+use humility::hubris::*;
+use humility_cmd::flashalgo::{FlashAlgorithm, FlashStub};
+use humility_cmd::{Archive, Args, Attach, Command, Validate};
+use indicatif::{HumanBytes, HumanDuration};
+use indicatif::{ProgressBar, ProgressStyle};
+use std::time::Instant;
+use structopt::clap::{App, ArgGroup};
+use structopt::StructOpt;
+
+#[derive(StructOpt, Debug)]
+#[structopt(
+    name = "flashalgo",
+    about = "erase and program memories via a flash algorithm",
+    group = ArgGroup::with_name("command").multiple(false).required(true)
+)]
+struct FlashalgoArgs {
+    /// flash algorithm (as descriptor or packaged ZIP archive)
+    #[structopt(
+        long,
+        short = "A",
+        value_name = "filename",
+        env = "HUMILITY_FLASH_ALGORITHM"
+    )]
+    algorithm: String,
+
+    /// erase the sectors containing the specified range
+    #[structopt(
+        long, short,
+        group = "command",
+        requires_all = &["addr", "nbytes"]
+    )]
+    erase: bool,
+
+    /// erase the entire memory
+    #[structopt(long, short = "E", group = "command")]
+    bulkerase: bool,
+
+    /// erase and program the specified file
+    #[structopt(long, short = "W", value_name = "filename", group = "command")]
+    writefile: Option<String>,
+
+    /// address (by default, the base of the memory)
+    #[structopt(long, short, value_name = "address",
+        parse(try_from_str = parse_int::parse),
+    )]
+    addr: Option<u32>,
+
+    /// size in bytes
+    #[structopt(long, short, value_name = "nbytes",
+        parse(try_from_str = parse_int::parse),
+    )]
+    nbytes: Option<u32>,
+}
+
+fn flashalgo_run(
+    core: &mut dyn Core,
+    stub: &FlashStub,
+    algorithm: &FlashAlgorithm,
+    subargs: &FlashalgoArgs,
+) -> Result<()> {
+    let d = &algorithm.descriptor;
+    let addr = subargs.addr.unwrap_or(d.base);
+
+    if subargs.bulkerase {
+        info!("erasing {}...", d.name);
+        stub.erase_all(core)?;
+        info!("... done");
+        return Ok(());
+    }
+
+    if subargs.erase {
+        let nbytes = subargs.nbytes.unwrap();
+        info!("erasing 0x{:x} bytes at 0x{:x}...", nbytes, addr);
+        stub.erase(core, addr, nbytes, |_| {})?;
+        info!("... done");
+        return Ok(());
+    }
+
+    let filename = subargs.writefile.as_ref().unwrap();
+    let data = std::fs::read(filename)?;
+
+    if data.is_empty() {
+        bail!("{} is empty", filename);
+    }
+
+    let started = Instant::now();
+    let len = data.len() as u32;
+
+    let bar = ProgressBar::new(len as u64);
+    bar.set_style(
+        ProgressStyle::default_bar()
+            .template("humility: erasing [{bar:30}] {bytes}/{total_bytes}"),
+    );
+
+    stub.erase(core, addr, len, |n| bar.set_position(n.min(len) as u64))?;
+    bar.finish_and_clear();
+
+    let bar = ProgressBar::new(len as u64);
+    bar.set_style(
+        ProgressStyle::default_bar()
+            .template("humility: flashing [{bar:30}] {bytes}/{total_bytes}"),
+    );
+
+    stub.program(core, addr, &data, |n| bar.set_position(n as u64))?;
+    bar.finish_and_clear();
+
+    info!(
+        "flashed {} in {}",
+        HumanBytes(len as u64),
+        HumanDuration(started.elapsed())
+    );
+
+    Ok(())
+}
+
+fn flashalgo(
+    _hubris: &mut HubrisArchive,
+    core: &mut dyn Core,
+    _args: &Args,
+    subargs: &[String],
+) -> Result<()> {
+    let subargs = FlashalgoArgs::from_iter_safe(subargs)?;
+    let algorithm = FlashAlgorithm::load(&subargs.algorithm)?;
+
+    info!(
+        "using {}{}",
+        algorithm.descriptor.name,
+        match &algorithm.descriptor.description {
+            Some(description) => format!(" ({})", description),
+            None => "".to_string(),
+        }
+    );
+
+    let stub = FlashStub::load(&algorithm, core)?;
+    let rval = flashalgo_run(core, &stub, &algorithm, &subargs);
+
+    //
+    // Whether or not we succeeded, restore the target.
+    //
+    stub.unload(core)?;
+
+    rval
+}
+
+pub fn init<'a, 'b>() -> (Command, App<'a, 'b>) {
+    (
+        Command::Attached {
+            name: "flashalgo",
+            archive: Archive::Optional,
+            attach: Attach::LiveOnly,
+            validate: Validate::None,
+            run: flashalgo,
+        },
+        FlashalgoArgs::clap(),
+    )
+}
@@ -0,0 +1,932 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//!
+//! A GDB remote serial protocol server, allowing GDB (or anything that
+//! speaks its remote protocol, like VS Code's cortex-debug) to debug a
+//! Hubris target through Humility.  Once `humility gdb` is listening,
+//! connect to it from GDB with `target extended-remote`.
+//!
+//! Hubris tasks are presented as threads:  thread N is task N - 1, and the
+//! registers of a thread are the saved registers of its task (or, for the
+//! task that is running, those of the CPU).  Memory can be read and
+//! written, and the target halted, resumed and single-stepped.  Breakpoints
+//! and watchpoints are set via the core's hardware breakpoints and
+//! watchpoints; as the code of a Hubris image is in flash, software
+//! breakpoints are implemented as hardware breakpoints.
+//!
+
+use anyhow::{anyhow, bail, Result};
+use humility::arch::ARMRegister;
+use humility::core::{Core, CoreWatch};
+use humility::hubris::*;
+use humility_cmd::doppel::{SchedState, Task, TaskState};
+use humility_cmd::reflect::{self, Load};
+use humility_cmd::{Archive, Args, Attach, Command, Validate};
+use humility_cortex::debug::*;
+use humility_cortex::dwt::DWTWatchpoints;
+use std::collections::HashMap;
+use std::io::{ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::str;
+use std::time::Duration;
+use structopt::clap::App;
+use structopt::StructOpt;
+
+#[macro_use]
+extern crate log;
+
+#[derive(StructOpt, Debug)]
+#[structopt(name = "gdb", about = "act as a GDB remote serial protocol server")]
+struct GdbArgs {
+    /// address on which to listen for GDB
+    #[structopt(
+        long,
+        short,
+        default_value = "127.0.0.1:3333",
+        value_name = "address"
+    )]
+    listen: String,
+}
+
+//
+// The registers that we present, in the order in which we number them.
+//
+const REGISTERS: &[(&str, ARMRegister)] = &[
+    ("r0", ARMRegister::R0),
+    ("r1", ARMRegister::R1),
+    ("r2", ARMRegister::R2),
+    ("r3", ARMRegister::R3),
+    ("r4", ARMRegister::R4),
+    ("r5", ARMRegister::R5),
+    ("r6", ARMRegister::R6),
+    ("r7", ARMRegister::R7),
+    ("r8", ARMRegister::R8),
+    ("r9", ARMRegister::R9),
+    ("r10", ARMRegister::R10),
+    ("r11", ARMRegister::R11),
+    ("r12", ARMRegister::R12),
+    ("sp", ARMRegister::SP),
+    ("lr", ARMRegister::LR),
+    ("pc", ARMRegister::PC),
+    ("xpsr", ARMRegister::xPSR),
+];
+
+//
+// The largest packet that we accept (and advertise), in bytes.
+//
+const GDB_PACKET_SIZE: usize = 0x4000;
+
+//
+// The interval at which we poll a running target (and our connection).
+//
+const GDB_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+const GDB_PACKET_START: u8 = b'$';
+const GDB_PACKET_END: u8 = b'#';
+const GDB_PACKET_ACK: u8 = b'+';
+const GDB_PACKET_NAK: u8 = b'-';
+const GDB_PACKET_ESCAPE: u8 = b'}';
+const GDB_PACKET_HALT: u8 = 3;
+
+//
+// The signals with which we report stops:  a trap for anything we induced,
+// and an interrupt for a halt requested by GDB.
+//
+const GDB_SIGTRAP: u8 = 5;
+const GDB_SIGINT: u8 = 2;
+
+enum Incoming {
+    Packet(Vec<u8>),
+    Interrupt,
+    Closed,
+}
+
+struct Connection {
+    stream: TcpStream,
+    buf: Vec<u8>,
+    noack: bool,
+}
+
+impl Connection {
+    fn new(stream: TcpStream) -> Result<Self> {
+        stream.set_read_timeout(Some(GDB_POLL_INTERVAL))?;
+        stream.set_nodelay(true)?;
+
+        Ok(Self { stream, buf: vec![], noack: false })
+    }
+
+    //
+    // Attempts to take a packet (or an interrupt) from what we have
+    // received, acknowledging it if required.
+    //
+    fn take(&mut self) -> Result<Option<Incoming>> {
+        loop {
+            match self.buf.first() {
+                None => return Ok(None),
+                Some(&GDB_PACKET_ACK) | Some(&GDB_PACKET_NAK) => {
+                    self.buf.remove(0);
+                }
+                Some(&GDB_PACKET_HALT) => {
+                    self.buf.remove(0);
+                    return Ok(Some(Incoming::Interrupt));
+                }
+                Some(&GDB_PACKET_START) => break,
+                Some(c) => {
+                    trace!("discarding unexpected byte 0x{:x}", c);
+                    self.buf.remove(0);
+                }
+            }
+        }
+
+        let end = match self.buf.iter().position(|&c| c == GDB_PACKET_END) {
+            Some(end) if end + 2 < self.buf.len() => end,
+            _ => return Ok(None),
+        };
+
+        let packet: Vec<u8> = self.buf.drain(..end + 3).collect();
+        let payload = &packet[1..end];
+
+        let cksum = str::from_utf8(&packet[end + 1..])
+            .ok()
+            .and_then(|c| u8::from_str_radix(c, 16).ok());
+
+        let computed = payload.iter().fold(0u8, |sum, c| sum.wrapping_add(*c));
+
+        if !self.noack {
+            if cksum != Some(computed) {
+                warn!("bad checksum on packet; requesting retransmission");
+                self.stream.write_all(&[GDB_PACKET_NAK])?;
+                return Ok(None);
+            }
+
+            self.stream.write_all(&[GDB_PACKET_ACK])?;
+        }
+
+        //
+        // Binary data (as found in packets like X) has the characters of
+        // our framing escaped; we unescape them here.
+        //
+        let mut rval = Vec::with_capacity(payload.len());
+        let mut bytes = payload.iter();
+
+        while let Some(&c) = bytes.next() {
+            if c == GDB_PACKET_ESCAPE {
+                if let Some(&c) = bytes.next() {
+                    rval.push(c ^ 0x20);
+                }
+            } else {
+                rval.push(c);
+            }
+        }
+
+        trace!("received {}", String::from_utf8_lossy(&rval));
+
+        Ok(Some(Incoming::Packet(rval)))
+    }
+
+    //
+    // Receives a packet or an interrupt.  If wait is false, this returns
+    // None if nothing has been received within our polling interval.
+    //
+    fn recv(&mut self, wait: bool) -> Result<Option<Incoming>> {
+        let mut rbuf = [0u8; 4096];
+
+        loop {
+            if let Some(incoming) = self.take()? {
+                return Ok(Some(incoming));
+            }
+
+            match self.stream.read(&mut rbuf) {
+                Ok(0) => return Ok(Some(Incoming::Closed)),
+                Ok(n) => self.buf.extend_from_slice(&rbuf[..n]),
+                Err(err)
+                    if err.kind() == ErrorKind::WouldBlock
+                        || err.kind() == ErrorKind::TimedOut =>
+                {
+                    if !wait {
+                        return Ok(None);
+                    }
+                }
+                Err(err) => return Err(err.into()),
+            }
+        }
+    }
+
+    fn send(&mut self, payload: &str) -> Result<()> {
+        trace!("sending {}", payload);
+
+        let cksum = payload.bytes().fold(0u8, |sum, c| sum.wrapping_add(c));
+        let packet = format!("${}#{:02x}", payload, cksum);
+
+        self.stream.write_all(packet.as_bytes())?;
+        Ok(())
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn unhex(s: &str) -> Result<Vec<u8>> {
+    if !s.is_ascii() || s.len() % 2 != 0 {
+        bail!("malformed hex string \"{}\"", s);
+    }
+
+    (0..s.len())
+        .step_by(2)
+        .map(|i| {
+            u8::from_str_radix(&s[i..i + 2], 16)
+                .map_err(|_| anyhow!("bad hex string \"{}\"", s))
+        })
+        .collect()
+}
+
+fn parse_hex(s: &str) -> Result<u32> {
+    u32::from_str_radix(s, 16).map_err(|_| anyhow!("bad hex value \"{}\"", s))
+}
+
+//
+// Parses an address and a length (or kind), separated by a comma.
+//
+fn parse_range(s: &str) -> Result<(u32, u32)> {
+    match s.split_once(',') {
+        Some((addr, len)) => Ok((parse_hex(addr)?, parse_hex(len)?)),
+        None => bail!("malformed range \"{}\"", s),
+    }
+}
+
+//
+// Parses a thread ID.  We denote "any thread" (0) and "all threads" (-1)
+// as None:  these operate on the thread that is running.
+//
+fn parse_thread(s: &str) -> Result<Option<u32>> {
+    match s {
+        "0" | "-1" => Ok(None),
+        _ => Ok(Some(parse_hex(s)?)),
+    }
+}
+
+fn target_xml() -> String {
+    let regs: String = REGISTERS
+        .iter()
+        .map(|(name, reg)| {
+            let kind = match reg {
+                ARMRegister::SP => " type=\"data_ptr\"",
+                ARMRegister::PC => " type=\"code_ptr\"",
+                _ => "",
+            };
+
+            format!("<reg name=\"{}\" bitsize=\"32\"{}/>", name, kind)
+        })
+        .collect();
+
+    format!(
+        "<?xml version=\"1.0\"?>\
+        <!DOCTYPE target SYSTEM \"gdb-target.dtd\">\
+        <target version=\"1.0\">\
+        <architecture>arm</architecture>\
+        <feature name=\"org.gnu.gdb.arm.m-profile\">{}</feature>\
+        </target>",
+        regs
+    )
+}
+
+struct GdbTask {
+    name: String,
+    state: &'static str,
+}
+
+#[derive(Copy, Clone, Debug)]
+struct GdbWatchpoint {
+    addr: u32,
+    watch: CoreWatch,
+}
+
+enum Resume {
+    Continue,
+    Step,
+}
+
+struct Session<'a> {
+    hubris: &'a HubrisArchive,
+    core: &'a mut dyn Core,
+    conn: Connection,
+    tasks: Vec<GdbTask>,
+    current: u32,
+    thread: Option<u32>,
+    breakpoints: Vec<u32>,
+    watchpoints: Vec<GdbWatchpoint>,
+}
+
+impl<'a> Session<'a> {
+    //
+    // Loads the task table; this is done whenever the target stops.
+    //
+    fn load(&mut self) -> Result<()> {
+        let hubris = self.hubris;
+        let core = &mut *self.core;
+
+        let base =
+            core.read_word_32(hubris.lookup_symword("TASK_TABLE_BASE")?)?;
+        let task_count =
+            core.read_word_32(hubris.lookup_symword("TASK_TABLE_SIZE")?)?;
+        let cur =
+            core.read_word_32(hubris.lookup_symword("CURRENT_TASK_PTR")?)?;
+        let task_t = hubris.lookup_struct_byname("Task")?;
+
+        let mut taskblock = vec![0; task_t.size * task_count as usize];
+        core.read_8(base, &mut taskblock)?;
+
+        self.tasks.clear();
+
+        for i in 0..task_count {
+            let offs = i as usize * task_t.size;
+            let task_value: reflect::Value =
+                reflect::load(hubris, &taskblock, task_t, offs)?;
+            let task: Task = Task::from_value(&task_value)?;
+
+            if base + offs as u32 == cur {
+                self.current = i;
+            }
+
+            self.tasks.push(GdbTask {
+                name: hubris.task_name(i as usize).unwrap_or("?").to_string(),
+                state: match task.state {
+                    TaskState::Faulted { .. } => "faulted",
+                    TaskState::Healthy(SchedState::Stopped) => "not started",
+                    TaskState::Healthy(SchedState::InSend(_)) => "sending",
+                    TaskState::Healthy(SchedState::InReply(_)) => "replying",
+                    TaskState::Healthy(SchedState::InRecv(_)) => "receiving",
+                    TaskState::Healthy(SchedState::Runnable) => "runnable",
+                },
+            });
+        }
+
+        Ok(())
+    }
+
+    //
+    // Returns the task of the selected thread.
+    //
+    fn selected(&self) -> Result<u32> {
+        match self.thread {
+            Some(id) if id == 0 || id as usize > self.tasks.len() => {
+                bail!("invalid thread id: {}", id)
+            }
+            Some(id) => Ok(id - 1),
+            None => Ok(self.current),
+        }
+    }
+
+    fn registers(&mut self) -> Result<HashMap<ARMRegister, u32>> {
+        let task = HubrisTask::Task(self.selected()?);
+        self.hubris.registers(self.core, task)
+    }
+
+    //
+    // Writes a register.  Only the registers of the running task -- that
+    // is, those of the CPU -- can be written.
+    //
+    fn write_reg(&mut self, ndx: usize, val: u32) -> Result<()> {
+        if self.selected()? != self.current {
+            bail!("can only write registers of the running task");
+        }
+
+        match REGISTERS.get(ndx) {
+            Some((_, reg)) => self.core.write_reg(*reg, val),
+            None => bail!("invalid register {}", ndx),
+        }
+    }
+
+    fn insert_breakpoint(&mut self, addr: u32) -> Result<()> {
+        if !self.breakpoints.contains(&addr) {
+            self.core.set_breakpoint(addr)?;
+            self.breakpoints.push(addr);
+        }
+
+        Ok(())
+    }
+
+    fn remove_breakpoint(&mut self, addr: u32) -> Result<()> {
+        if let Some(ndx) = self.breakpoints.iter().position(|&b| b == addr) {
+            self.core.clear_breakpoint(addr)?;
+            self.breakpoints.remove(ndx);
+        }
+
+        Ok(())
+    }
+
+    fn insert_watchpoint(&mut self, addr: u32, watch: CoreWatch) -> Result<()> {
+        //
+        // Watchpoints are on words; a watchpoint on a smaller (or
+        // unaligned) location watches the word that contains it.
+        //
+        self.core.set_watchpoint(addr, watch)?;
+        self.watchpoints.push(GdbWatchpoint { addr, watch });
+        Ok(())
+    }
+
+    fn remove_watchpoint(&mut self, addr: u32, watch: CoreWatch) -> Result<()> {
+        let found = self
+            .watchpoints
+            .iter()
+            .position(|w| w.addr == addr && w.watch == watch);
+
+        if let Some(ndx) = found {
+            self.core.clear_watchpoint(addr)?;
+            self.watchpoints.remove(ndx);
+        }
+
+        Ok(())
+    }
+
+    //
+    // Removes all breakpoints and watchpoints, as we do when GDB goes away.
+    //
+    fn clear(&mut self) -> Result<()> {
+        for addr in std::mem::take(&mut self.breakpoints) {
+            self.core.clear_breakpoint(addr)?;
+        }
+
+        for w in std::mem::take(&mut self.watchpoints) {
+            self.core.clear_watchpoint(w.addr)?;
+        }
+
+        Ok(())
+    }
+
+    //
+    // Determines why the target stopped, and constructs the stop reply that
+    // tells GDB.
+    //
+    fn stopped(&mut self, signal: u8) -> Result<String> {
+        let mut reason = String::new();
+
+        if !self.core.is_dump() {
+            let dfsr = DFSR::read(self.core)?;
+
+            if dfsr.watchpoint() && !self.watchpoints.is_empty() {
+                let dwt = DWTWatchpoints::read(self.core)?;
+
+                if let Some(addr) = dwt.matched_watchpoint(self.core)? {
+                    let found = self
+                        .watchpoints
+                        .iter()
+                        .find(|w| w.addr & !0b11 == addr);
+
+                    if let Some(w) = found {
+                        let kind = match w.watch {
+                            CoreWatch::Write => "watch",
+                            CoreWatch::Read => "rwatch",
+                            CoreWatch::ReadWrite => "awatch",
+                        };
+
+                        reason = format!("{}:{:x};", kind, w.addr);
+                    }
+                }
+            } else if dfsr.breakpoint() {
+                reason = "hwbreak:;".to_string();
+            }
+
+            //
+            // The status bits are write-one-to-clear; we clear them to be
+            // able to distinguish the reason for the next stop.
+            //
+            DFSR::from(u32::from(dfsr)).write(self.core)?;
+        }
+
+        self.load()?;
+        self.thread = None;
+
+        Ok(format!("T{:02x}{}thread:{:x};", signal, reason, self.current + 1))
+    }
+
+    //
+    // Resumes the target, waiting for it to stop (or for GDB to interrupt
+    // it), and returns the stop reply.
+    //
+    fn resume(&mut self, how: Resume) -> Result<String> {
+        if self.core.is_dump() {
+            bail!("cannot resume a dump");
+        }
+
+        if let Resume::Step = how {
+            self.core.step()?;
+            return self.stopped(GDB_SIGTRAP);
+        }
+
+        self.core.run()?;
+
+        loop {
+            match self.conn.recv(false)? {
+                Some(Incoming::Interrupt) => {
+                    self.core.halt()?;
+                    return self.stopped(GDB_SIGINT);
+                }
+                Some(Incoming::Closed) => {
+                    self.core.halt()?;
+                    bail!("connection closed while target running");
+                }
+                Some(Incoming::Packet(packet)) => {
+                    warn!(
+                        "ignoring packet while running: {}",
+                        String::from_utf8_lossy(&packet)
+                    );
+                }
+                None => {}
+            }
+
+            if DHCSR::read(self.core)?.halted() {
+                return self.stopped(GDB_SIGTRAP);
+            }
+        }
+    }
+
+    fn read_memory(&mut self, addr: u32, len: u32) -> Result<String> {
+        //
+        // We can return fewer bytes than were asked for; GDB will ask for
+        // the remainder.
+        //
+        let len = std::cmp::min(len as usize, (GDB_PACKET_SIZE - 16) / 2);
+        let mut buf = vec![0u8; len];
+        self.core.read_8(addr, &mut buf)?;
+        Ok(hex(&buf))
+    }
+
+    fn query(&mut self, query: &str) -> Result<String> {
+        if let Some(whence) = query.strip_prefix("Xfer:features:read:") {
+            let (annex, range) = whence
+                .split_once(':')
+                .ok_or_else(|| anyhow!("malformed qXfer: {}", query))?;
+
+            if annex != "target.xml" {
+                bail!("unknown annex \"{}\"", annex);
+            }
+
+            let (offset, len) = parse_range(range)?;
+            let xml = target_xml();
+            let offset = std::cmp::min(offset as usize, xml.len());
+            let end = std::cmp::min(offset + len as usize, xml.len());
+
+            return Ok(format!(
+                "{}{}",
+                if end < xml.len() { "m" } else { "l" },
+                &xml[offset..end]
+            ));
+        }
+
+        if let Some(id) = query.strip_prefix("ThreadExtraInfo,") {
+            let id = parse_hex(id)?;
+
+            let task = match self.tasks.get((id as usize).wrapping_sub(1)) {
+                Some(task) => task,
+                None => bail!("invalid thread id: {}", id),
+            };
+
+            let info = format!(
+                "{} ({}{})",
+                task.name,
+                task.state,
+                if id - 1 == self.current { ", running" } else { "" }
+            );
+
+            return Ok(hex(info.as_bytes()));
+        }
+
+        Ok(match query.split(':').next().unwrap_or("") {
+            "Supported" => format!(
+                "PacketSize={:x};QStartNoAckMode+;qXfer:features:read+;\
+                hwbreak+;vContSupported+",
+                GDB_PACKET_SIZE
+            ),
+            "Attached" => "1".to_string(),
+            "C" => format!("QC{:x}", self.current + 1),
+            "fThreadInfo" => {
+                let ids: Vec<String> = (1..=self.tasks.len())
+                    .map(|id| format!("{:x}", id))
+                    .collect();
+                format!("m{}", ids.join(","))
+            }
+            "sThreadInfo" => "l".to_string(),
+            "Symbol" => "OK".to_string(),
+            _ => "".to_string(),
+        })
+    }
+
+    fn watch(kind: char) -> Option<CoreWatch> {
+        match kind {
+            '2' => Some(CoreWatch::Write),
+            '3' => Some(CoreWatch::Read),
+            '4' => Some(CoreWatch::ReadWrite),
+            _ => None,
+        }
+    }
+
+    //
+    // Handles a packet, returning the reply -- or None if the session is
+    // over.
+    //
+    fn execute(&mut self, packet: &[u8]) -> Result<Option<String>> {
+        let cmd = String::from_utf8_lossy(packet);
+        let cmd = cmd.as_ref();
+        let (first, rest) = match cmd.char_indices().nth(1) {
+            Some((ndx, _)) => cmd.split_at(ndx),
+            None => (cmd, ""),
+        };
+
+        let rval = match first {
+            "?" => self.stopped(GDB_SIGINT)?,
+
+            "g" => {
+                let regs = self.registers()?;
+
+                REGISTERS
+                    .iter()
+                    .map(|(_, reg)| match regs.get(reg) {
+                        Some(val) => hex(&val.to_le_bytes()),
+                        None => "xxxxxxxx".to_string(),
+                    })
+                    .collect()
+            }
+
+            "G" => {
+                let bytes = unhex(rest)?;
+
+                for (ndx, val) in bytes.chunks_exact(4).enumerate() {
+                    let val = u32::from_le_bytes(val.try_into().unwrap());
+                    self.write_reg(ndx, val)?;
+                }
+
+                "OK".to_string()
+            }
+
+            "p" => {
+                let ndx = parse_hex(rest)? as usize;
+                let regs = self.registers()?;
+
+                match REGISTERS.get(ndx).and_then(|(_, r)| regs.get(r)) {
+                    Some(val) => hex(&val.to_le_bytes()),
+                    None => "xxxxxxxx".to_string(),
+                }
+            }
+
+            "P" => {
+                let (ndx, val) = rest
+                    .split_once('=')
+                    .ok_or_else(|| anyhow!("malformed register write"))?;
+                let bytes = unhex(val)?;
+
+                if bytes.len() != 4 {
+                    bail!("bad register value \"{}\"", val);
+                }
+
+                let val = u32::from_le_bytes(bytes[..].try_into().unwrap());
+                self.write_reg(parse_hex(ndx)? as usize, val)?;
+                "OK".to_string()
+            }
+
+            "m" => {
+                let (addr, len) = parse_range(rest)?;
+                self.read_memory(addr, len)?
+            }
+
+            "M" => {
+                let (range, data) = rest
+                    .split_once(':')
+                    .ok_or_else(|| anyhow!("malformed memory write"))?;
+                let (addr, len) = parse_range(range)?;
+                let data = unhex(data)?;
+
+                if data.len() != len as usize {
+                    bail!("memory write length mismatch");
+                }
+
+                self.core.write_8(addr, &data)?;
+                "OK".to_string()
+            }
+
+            "X" => {
+                //
+                // The binary data follows the colon; we find it in the raw
+                // packet, as it need not be valid UTF-8.
+                //
+                let colon = packet
+                    .iter()
+                    .position(|&c| c == b':')
+                    .ok_or_else(|| anyhow!("malformed memory write"))?;
+                let (addr, len) = parse_range(&rest[..colon - 1])?;
+                let data = &packet[colon + 1..];
+
+                if data.len() != len as usize {
+                    bail!("memory write length mismatch");
+                }
+
+                if !data.is_empty() {
+                    self.core.write_8(addr, data)?;
+                }
+
+                "OK".to_string()
+            }
+
+            "c" => self.resume(Resume::Continue)?,
+            "s" => self.resume(Resume::Step)?,
+
+            "H" => {
+                //
+                // We only operate on one thread at a time, so we don't
+                // distinguish the thread for registers from that for
+                // execution.
+                //
+                if rest.len() > 1 {
+                    self.thread = parse_thread(&rest[1..])?;
+                }
+
+                "OK".to_string()
+            }
+
+            "T" => match parse_hex(rest)? {
+                id if id >= 1 && id as usize <= self.tasks.len() => {
+                    "OK".to_string()
+                }
+                _ => "E01".to_string(),
+            },
+
+            "Z" | "z" => {
+                if self.core.is_dump() {
+                    bail!("cannot set breakpoints on a dump");
+                }
+
+                let (kind, range) = rest
+                    .split_once(',')
+                    .ok_or_else(|| anyhow!("malformed breakpoint"))?;
+                let kind = kind.chars().next();
+                let (addr, _) = parse_range(range)?;
+                let insert = first == "Z";
+
+                match kind {
+                    Some('0') | Some('1') if insert => {
+                        self.insert_breakpoint(addr)?
+                    }
+                    Some('0') | Some('1') => self.remove_breakpoint(addr)?,
+                    Some(k) => match Self::watch(k) {
+                        Some(watch) if insert => {
+                            self.insert_watchpoint(addr, watch)?
+                        }
+                        Some(watch) => self.remove_watchpoint(addr, watch)?,
+                        None => return Ok(Some("".to_string())),
+                    },
+                    None => bail!("malformed breakpoint"),
+                }
+
+                "OK".to_string()
+            }
+
+            "q" => self.query(rest)?,
+
+            "Q" if rest == "StartNoAckMode" => "OK".to_string(),
+
+            "v" => {
+                if rest == "Cont?" {
+                    "vCont;c;C;s;S;t".to_string()
+                } else if let Some(actions) = rest.strip_prefix("Cont;") {
+                    //
+                    // We can only resume all tasks together, so we step if
+                    // any thread is to be stepped, and otherwise continue.
+                    //
+                    let step = actions.split(';').any(|action| {
+                        action.starts_with('s') || action.starts_with('S')
+                    });
+
+                    if actions.starts_with('t') {
+                        self.core.halt()?;
+                        self.stopped(GDB_SIGINT)?
+                    } else if step {
+                        self.resume(Resume::Step)?
+                    } else {
+                        self.resume(Resume::Continue)?
+                    }
+                } else if rest.starts_with("Kill") {
+                    return Ok(None);
+                } else {
+                    "".to_string()
+                }
+            }
+
+            "D" => {
+                self.conn.send("OK")?;
+                return Ok(None);
+            }
+
+            "k" => return Ok(None),
+
+            _ => "".to_string(),
+        };
+
+        Ok(Some(rval))
+    }
+
+    fn serve(&mut self) -> Result<()> {
+        loop {
+            let packet = match self.conn.recv(true)? {
+                Some(Incoming::Packet(packet)) => packet,
+                Some(Incoming::Interrupt) => continue,
+                Some(Incoming::Closed) | None => return Ok(()),
+            };
+
+            match self.execute(&packet) {
+                Ok(Some(reply)) => {
+                    self.conn.send(&reply)?;
+
+                    //
+                    // The reply to a request to stop acknowledging packets
+                    // must itself be acknowledged.
+                    //
+                    if packet == b"QStartNoAckMode" {
+                        self.conn.noack = true;
+                    }
+                }
+                Ok(None) => return Ok(()),
+                Err(err) => {
+                    warn!(
+                        "{} failed: {}",
+                        String::from_utf8_lossy(&packet),
+                        err
+                    );
+                    self.conn.send("E01")?;
+                }
+            }
+        }
+    }
+}
+
+fn gdb(
+    hubris: &mut HubrisArchive,
+    core: &mut dyn Core,
+    _args: &Args,
+    subargs: &[String],
+) -> Result<()> {
+    let subargs = GdbArgs::from_iter_safe(subargs)?;
+    let listener = TcpListener::bind(&subargs.listen)?;
+
+    info!(
+        "listening on {}; connect with \"target extended-remote {}\"",
+        subargs.listen, subargs.listen
+    );
+
+    for stream in listener.incoming() {
+        let stream = stream?;
+        info!("connection from {}", stream.peer_addr()?);
+
+        //
+        // As other GDB servers do, we stop the target when GDB connects.
+        //
+        if !core.is_dump() {
+            core.halt()?;
+        }
+
+        let mut session = Session {
+            hubris: &*hubris,
+            core: &mut *core,
+            conn: Connection::new(stream)?,
+            tasks: vec![],
+            current: 0,
+            thread: None,
+            breakpoints: vec![],
+            watchpoints: vec![],
+        };
+
+        let rval = session.load().and_then(|_| session.serve());
+
+        //
+        // Whether or not the session ended cleanly, we remove any
+        // breakpoints and watchpoints and -- as GDB does when detaching --
+        // leave the target running.
+        //
+        if !session.core.is_dump() {
+            session.clear()?;
+            session.core.run()?;
+        }
+
+        match rval {
+            Ok(_) => info!("GDB detached"),
+            Err(err) => warn!("GDB session failed: {}", err),
+        }
+    }
+
+    Ok(())
+}
+
+pub fn init<'a, 'b>() -> (Command, App<'a, 'b>) {
+    (
+        Command::Attached {
+            name: "gdb",
+            archive: Archive::Required,
+            attach: Attach::Any,
+            validate: Validate::Booted,
+            run: gdb,
+        },
+        GdbArgs::clap(),
+    )
+}
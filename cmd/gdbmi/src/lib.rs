@@ -0,0 +1,1214 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//!
+//! A GDB/MI front end, allowing IDEs that drive GDB via its machine
+//! interface (e.g., Eclipse and CLion) to use Humility as their debugger.
+//! MI commands are read from stdin and responses written to stdout; to use
+//! it, configure the IDE's debugger to be a script that runs `humility -a
+//! <archive> gdbmi "$@"` (the arguments that IDEs pass to GDB, like
+//! `--interpreter=mi2`, are accepted and ignored).
+//!
+//! Hubris tasks are presented as threads:  thread N is task N - 1, the
+//! registers of a thread are the saved registers of its task, and its stack
+//! is the unwound stack of the task.  Memory can be read, the target
+//! halted, resumed and single-stepped, and hardware breakpoints set on
+//! functions, source lines (as `file:line`) or addresses (as `*addr`).
+//!
+
+use anyhow::{anyhow, bail, Result};
+use humility::arch::ARMRegister;
+use humility::core::Core;
+use humility::hubris::*;
+use humility_cmd::doppel::{SchedState, Task, TaskDesc, TaskState};
+use humility_cmd::reflect::{self, Load};
+use humility_cmd::{Archive, Args, Attach, Command, Validate};
+use humility_cortex::debug::{DFSR, DHCSR};
+use std::collections::HashMap;
+use std::io::{self, BufRead, Write};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::thread;
+use std::time::Duration;
+use structopt::clap::App;
+use structopt::StructOpt;
+
+#[macro_use]
+extern crate log;
+
+#[derive(StructOpt, Debug)]
+#[structopt(name = "gdbmi", about = "act as a GDB/MI debugger back end")]
+struct GdbmiArgs {
+    /// interpreter (accepted for compatibility with GDB)
+    #[structopt(long, default_value = "mi2", value_name = "interpreter")]
+    interpreter: String,
+
+    /// do not print version information (accepted for compatibility)
+    #[structopt(long, short, alias = "silent")]
+    quiet: bool,
+
+    /// program to debug (ignored; symbols are taken from the archive)
+    program: Vec<String>,
+}
+
+//
+// The registers that we present, in the order in which we number them.
+//
+const REGISTERS: &[(&str, ARMRegister)] = &[
+    ("r0", ARMRegister::R0),
+    ("r1", ARMRegister::R1),
+    ("r2", ARMRegister::R2),
+    ("r3", ARMRegister::R3),
+    ("r4", ARMRegister::R4),
+    ("r5", ARMRegister::R5),
+    ("r6", ARMRegister::R6),
+    ("r7", ARMRegister::R7),
+    ("r8", ARMRegister::R8),
+    ("r9", ARMRegister::R9),
+    ("r10", ARMRegister::R10),
+    ("r11", ARMRegister::R11),
+    ("r12", ARMRegister::R12),
+    ("sp", ARMRegister::SP),
+    ("lr", ARMRegister::LR),
+    ("pc", ARMRegister::PC),
+    ("xpsr", ARMRegister::xPSR),
+];
+
+const BANNER: &str = "humility GDB/MI back end\n";
+
+//
+// How often we check if the target has stopped while it's running.
+//
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+//
+// Options that GDB accepts but that have no meaning for us.  IDEs pass
+// these, so we accept and discard them.
+//
+const IGNORED_OPTIONS: &[&str] = &["--nx", "-nx", "-n", "--nh"];
+
+struct MiTask {
+    name: String,
+    state: &'static str,
+    initial_stack: u32,
+}
+
+struct MiFrame {
+    pc: u32,
+    func: Option<String>,
+    src: Option<HubrisSrc>,
+    registers: HashMap<ARMRegister, u32>,
+}
+
+struct MiBreakpoint {
+    number: u32,
+    addr: u32,
+    location: String,
+    temporary: bool,
+    hits: u32,
+}
+
+enum MiStop {
+    Interrupted,
+    Stepped,
+    Breakpoint { number: u32, temporary: bool },
+    Halted,
+}
+
+struct MiCommand {
+    token: String,
+    name: String,
+    thread: Option<u32>,
+    frame: Option<usize>,
+    args: Vec<String>,
+}
+
+fn quote(s: &str) -> String {
+    let mut rval = String::from("\"");
+
+    for c in s.chars() {
+        match c {
+            '"' => rval.push_str("\\\""),
+            '\\' => rval.push_str("\\\\"),
+            '\n' => rval.push_str("\\n"),
+            '\t' => rval.push_str("\\t"),
+            c => rval.push(c),
+        }
+    }
+
+    rval.push('"');
+    rval
+}
+
+//
+// Splits an MI command line into its words, honoring C-style quoting.
+//
+fn words(line: &str) -> Vec<String> {
+    let mut rval = vec![];
+    let mut word = None;
+    let mut chars = line.chars();
+    let mut quoted = false;
+
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => {
+                quoted = !quoted;
+                word.get_or_insert_with(String::new);
+            }
+            '\\' if quoted => {
+                if let Some(c) = chars.next() {
+                    word.get_or_insert_with(String::new).push(match c {
+                        'n' => '\n',
+                        't' => '\t',
+                        c => c,
+                    });
+                }
+            }
+            c if c.is_whitespace() && !quoted => {
+                if let Some(w) = word.take() {
+                    rval.push(w);
+                }
+            }
+            c => word.get_or_insert_with(String::new).push(c),
+        }
+    }
+
+    if let Some(w) = word {
+        rval.push(w);
+    }
+
+    rval
+}
+
+fn parse(line: &str) -> Result<MiCommand> {
+    let ndx = line.find(|c: char| !c.is_ascii_digit()).unwrap_or(line.len());
+    let (token, rest) = line.split_at(ndx);
+    let mut words = words(rest).into_iter();
+
+    let mut cmd = MiCommand {
+        token: token.to_string(),
+        name: words.next().unwrap_or_default(),
+        thread: None,
+        frame: None,
+        args: vec![],
+    };
+
+    while let Some(word) = words.next() {
+        match word.as_str() {
+            "--thread" | "--frame" => {
+                let val = words
+                    .next()
+                    .ok_or_else(|| anyhow!("{} requires an argument", word))?;
+                let val = val
+                    .parse::<usize>()
+                    .map_err(|_| anyhow!("invalid {} \"{}\"", word, val))?;
+
+                if word == "--thread" {
+                    cmd.thread = Some(val as u32);
+                } else {
+                    cmd.frame = Some(val);
+                }
+            }
+            "--" => cmd.args.extend(words.by_ref()),
+            _ => cmd.args.push(word),
+        }
+    }
+
+    Ok(cmd)
+}
+
+struct Session<'a> {
+    hubris: &'a HubrisArchive,
+    core: &'a mut dyn Core,
+    tasks: Vec<MiTask>,
+    current: u32,
+    thread: u32,
+    frame: usize,
+    halted: bool,
+    stacks: HashMap<u32, Vec<MiFrame>>,
+    breakpoints: Vec<MiBreakpoint>,
+    next: u32,
+}
+
+impl<'a> Session<'a> {
+    //
+    // Loads the task table; this is done whenever the target stops.
+    //
+    fn load(&mut self) -> Result<()> {
+        let hubris = self.hubris;
+        let core = &mut *self.core;
+
+        let base =
+            core.read_word_32(hubris.lookup_symword("TASK_TABLE_BASE")?)?;
+        let task_count =
+            core.read_word_32(hubris.lookup_symword("TASK_TABLE_SIZE")?)?;
+        let cur =
+            core.read_word_32(hubris.lookup_symword("CURRENT_TASK_PTR")?)?;
+        let task_t = hubris.lookup_struct_byname("Task")?;
+
+        let mut taskblock = vec![0; task_t.size * task_count as usize];
+        core.read_8(base, &mut taskblock)?;
+
+        let first = self.tasks.is_empty();
+        self.tasks.clear();
+        self.stacks.clear();
+
+        for i in 0..task_count {
+            let offs = i as usize * task_t.size;
+            let task_value: reflect::Value =
+                reflect::load(hubris, &taskblock, task_t, offs)?;
+            let task: Task = Task::from_value(&task_value)?;
+            let desc: TaskDesc = task.descriptor.load_from(hubris, core)?;
+
+            let state = match task.state {
+                TaskState::Faulted { .. } => "faulted",
+                TaskState::Healthy(SchedState::Stopped) => "not started",
+                TaskState::Healthy(_) => "healthy",
+            };
+
+            if base + offs as u32 == cur {
+                self.current = i;
+            }
+
+            self.tasks.push(MiTask {
+                name: hubris.task_name(i as usize).unwrap_or("?").to_string(),
+                state,
+                initial_stack: desc.initial_stack,
+            });
+
+            if first {
+                println!("=thread-created,id=\"{}\",group-id=\"i1\"", i + 1);
+            }
+        }
+
+        self.thread = self.current;
+        self.frame = 0;
+
+        Ok(())
+    }
+
+    fn stack(&mut self, task: u32) -> Result<&[MiFrame]> {
+        if !self.stacks.contains_key(&task) {
+            let hubris = self.hubris;
+            let t = HubrisTask::Task(task);
+            let limit = self.tasks[task as usize].initial_stack;
+            let regs = hubris.registers(self.core, t)?;
+
+            let mut frames = vec![];
+
+            match hubris.stack(self.core, t, limit, &regs) {
+                Ok(stack) => {
+                    for frame in &stack {
+                        let pc = frame.registers[&ARMRegister::PC];
+
+                        for inline in frame.inlined.iter().flatten() {
+                            frames.push(MiFrame {
+                                pc: inline.addr,
+                                func: Some(inline.name.to_string()),
+                                src: hubris.lookup_src(inline.origin).cloned(),
+                                registers: frame.registers.clone(),
+                            });
+                        }
+
+                        frames.push(MiFrame {
+                            pc,
+                            func: frame.sym.map(|s| s.demangled_name.clone()),
+                            src: hubris.instr_src(pc),
+                            registers: frame.registers.clone(),
+                        });
+                    }
+                }
+                Err(err) => {
+                    //
+                    // If we can't unwind the stack, we still want to
+                    // present the task's registers.
+                    //
+                    warn!("failed to unwind stack of task {}: {}", task, err);
+
+                    let pc = regs[&ARMRegister::PC];
+
+                    frames.push(MiFrame {
+                        pc,
+                        func: hubris.instr_sym(pc).map(|s| s.0.to_string()),
+                        src: hubris.instr_src(pc),
+                        registers: regs,
+                    });
+                }
+            }
+
+            self.stacks.insert(task, frames);
+        }
+
+        Ok(&self.stacks[&task])
+    }
+
+    fn halted(&self) -> Result<()> {
+        if !self.halted {
+            bail!("target is running");
+        }
+
+        Ok(())
+    }
+
+    fn selected(&self, cmd: &MiCommand) -> Result<u32> {
+        match cmd.thread {
+            Some(id) if id == 0 || id as usize > self.tasks.len() => {
+                bail!("invalid thread id: {}", id)
+            }
+            Some(id) => Ok(id - 1),
+            None => Ok(self.thread),
+        }
+    }
+
+    fn mi_frame(frame: &MiFrame, level: Option<usize>) -> String {
+        let mut rval = String::from("{");
+
+        if let Some(level) = level {
+            rval.push_str(&format!("level=\"{}\",", level));
+        }
+
+        rval.push_str(&format!("addr=\"0x{:08x}\"", frame.pc));
+        rval.push_str(&format!(
+            ",func={}",
+            quote(frame.func.as_deref().unwrap_or("??"))
+        ));
+
+        if let Some(src) = &frame.src {
+            rval.push_str(&format!(
+                ",file={},fullname={},line=\"{}\"",
+                quote(&src.file),
+                quote(&src.fullpath()),
+                src.line
+            ));
+        }
+
+        rval.push('}');
+        rval
+    }
+
+    fn mi_thread(&mut self, task: u32) -> Result<String> {
+        let (name, state) = {
+            let t = &self.tasks[task as usize];
+            (t.name.clone(), t.state)
+        };
+
+        let frame = self
+            .stack(task)
+            .ok()
+            .and_then(|s| s.first())
+            .map(|f| Self::mi_frame(f, Some(0)));
+
+        Ok(format!(
+            "{{id=\"{}\",target-id={},name={},details={},state=\"{}\"{}}}",
+            task + 1,
+            quote(&format!("Task {} ({})", task, name)),
+            quote(&name),
+            quote(state),
+            if self.halted { "stopped" } else { "running" },
+            match frame {
+                Some(frame) => format!(",frame={}", frame),
+                None => "".to_string(),
+            }
+        ))
+    }
+
+    fn stopped(&mut self, stop: MiStop) -> Result<()> {
+        let thread = self.current;
+        let frame = self
+            .stack(thread)
+            .ok()
+            .and_then(|s| s.first())
+            .map(|f| Self::mi_frame(f, None));
+
+        let reason = match stop {
+            MiStop::Interrupted => "reason=\"signal-received\",\
+                signal-name=\"SIGINT\",signal-meaning=\"Interrupt\""
+                .to_string(),
+            MiStop::Stepped => "reason=\"end-stepping-range\"".to_string(),
+            MiStop::Breakpoint { number, temporary } => format!(
+                "reason=\"breakpoint-hit\",disp=\"{}\",bkptno=\"{}\"",
+                if temporary { "del" } else { "keep" },
+                number
+            ),
+            MiStop::Halted => "reason=\"signal-received\",\
+                signal-name=\"SIGTRAP\",\
+                signal-meaning=\"Trace/breakpoint trap\""
+                .to_string(),
+        };
+
+        println!(
+            "*stopped,{},{}thread-id=\"{}\",stopped-threads=\"all\"",
+            reason,
+            match frame {
+                Some(frame) => format!("frame={},", frame),
+                None => "".to_string(),
+            },
+            thread + 1
+        );
+
+        Ok(())
+    }
+
+    //
+    // Called periodically while the target is running, to determine if it
+    // has stopped (e.g., on a breakpoint) and to tell the IDE if so.
+    //
+    fn poll(&mut self) -> Result<()> {
+        if self.halted || self.core.is_dump() {
+            return Ok(());
+        }
+
+        if !DHCSR::read(self.core)?.halted() {
+            return Ok(());
+        }
+
+        self.halted = true;
+
+        //
+        // The status bits are write-one-to-clear; we clear them to be able
+        // to distinguish the reason for the next stop.
+        //
+        let dfsr = DFSR::read(self.core)?;
+        DFSR::from(u32::from(dfsr)).write(self.core)?;
+
+        let pc = self.core.read_reg(ARMRegister::PC)?;
+        let hit = self.breakpoints.iter().position(|b| b.addr == pc & !1);
+
+        let stop = match hit {
+            Some(ndx) if dfsr.breakpoint() => {
+                self.breakpoints[ndx].hits += 1;
+
+                let (number, temporary) = {
+                    let b = &self.breakpoints[ndx];
+                    (b.number, b.temporary)
+                };
+
+                if temporary {
+                    self.core.clear_breakpoint(pc & !1)?;
+                    self.breakpoints.remove(ndx);
+                }
+
+                MiStop::Breakpoint { number, temporary }
+            }
+            _ => MiStop::Halted,
+        };
+
+        self.load()?;
+        self.stopped(stop)?;
+        io::stdout().flush()?;
+
+        Ok(())
+    }
+
+    //
+    // Returns the address of the breakpoint on which the target is stopped,
+    // if any.
+    //
+    fn on_breakpoint(&mut self) -> Result<Option<u32>> {
+        let pc = self.core.read_reg(ARMRegister::PC)? & !1;
+        Ok(self.breakpoints.iter().find(|b| b.addr == pc).map(|b| b.addr))
+    }
+
+    //
+    // Steps the target, first clearing any breakpoint on the current
+    // instruction (which would otherwise prevent us from getting past it).
+    //
+    fn step(&mut self) -> Result<()> {
+        if let Some(pc) = self.on_breakpoint()? {
+            self.core.clear_breakpoint(pc)?;
+            let rval = self.core.step();
+            self.core.set_breakpoint(pc)?;
+            rval
+        } else {
+            self.core.step()
+        }
+    }
+
+    //
+    // Resolves a breakpoint location:  an address (as "*addr"), a source
+    // line (as "file:line") or a function.
+    //
+    fn location(&mut self, location: &str) -> Result<u32> {
+        if let Some(addr) = location.strip_prefix('*') {
+            return Ok(self.evaluate(addr)? as u32);
+        }
+
+        if let Some((file, line)) = location.rsplit_once(':') {
+            if let Ok(line) = line.parse::<u64>() {
+                let addr = self
+                    .hubris
+                    .instr_lines()
+                    .filter(|(_, src)| src.line == line)
+                    .filter(|(_, src)| {
+                        src.file == file || src.fullpath().ends_with(file)
+                    })
+                    .map(|(addr, _)| addr)
+                    .min();
+
+                return match addr {
+                    Some(addr) => Ok(addr),
+                    None => bail!("no code at line {} of {}", line, file),
+                };
+            }
+        }
+
+        match self.hubris.lookup_symbol(location) {
+            Ok((addr, _)) => Ok(addr & !1),
+            Err(_) => bail!("function \"{}\" not defined", location),
+        }
+    }
+
+    fn mi_breakpoint(&self, b: &MiBreakpoint) -> String {
+        let mut rval = format!(
+            "{{number=\"{}\",type=\"breakpoint\",disp=\"{}\",\
+            enabled=\"y\",addr=\"0x{:08x}\"",
+            b.number,
+            if b.temporary { "del" } else { "keep" },
+            b.addr
+        );
+
+        if let Some((func, _)) = self.hubris.instr_sym(b.addr) {
+            rval.push_str(&format!(",func={}", quote(func)));
+        }
+
+        if let Some(src) = self.hubris.instr_src(b.addr) {
+            rval.push_str(&format!(
+                ",file={},fullname={},line=\"{}\"",
+                quote(&src.file),
+                quote(&src.fullpath()),
+                src.line
+            ));
+        }
+
+        rval.push_str(&format!(
+            ",thread-groups=[\"i1\"],times=\"{}\",original-location={}}}",
+            b.hits,
+            quote(&b.location)
+        ));
+
+        rval
+    }
+
+    fn break_insert(&mut self, args: &[String]) -> Result<String> {
+        if self.core.is_dump() {
+            bail!("cannot set breakpoints in a dump");
+        }
+
+        let mut temporary = false;
+        let mut location = None;
+
+        for arg in args {
+            match arg.as_str() {
+                "-t" => temporary = true,
+
+                //
+                // All of our breakpoints are hardware breakpoints, and we
+                // have no shared libraries to wait upon for a pending one.
+                //
+                "-h" | "-f" => {}
+
+                opt if opt.starts_with('-') && location.is_none() => {
+                    bail!("breakpoint option {} is not supported", opt);
+                }
+
+                _ if location.is_none() => location = Some(arg.clone()),
+                _ => bail!("unexpected argument \"{}\"", arg),
+            }
+        }
+
+        let location =
+            location.ok_or_else(|| anyhow!("missing breakpoint location"))?;
+        let addr = self.location(&location)?;
+
+        if self.breakpoints.iter().any(|b| b.addr == addr) {
+            bail!("breakpoint already set at 0x{:x}", addr);
+        }
+
+        self.core.set_breakpoint(addr)?;
+        self.next += 1;
+
+        let b = MiBreakpoint {
+            number: self.next,
+            addr,
+            location,
+            temporary,
+            hits: 0,
+        };
+
+        let rval = format!("bkpt={}", self.mi_breakpoint(&b));
+        self.breakpoints.push(b);
+
+        Ok(rval)
+    }
+
+    fn break_delete(&mut self, args: &[String]) -> Result<()> {
+        let numbers = if args.is_empty() {
+            self.breakpoints.iter().map(|b| b.number).collect()
+        } else {
+            args.iter()
+                .map(|n| {
+                    n.parse::<u32>()
+                        .map_err(|_| anyhow!("invalid breakpoint \"{}\"", n))
+                })
+                .collect::<Result<Vec<_>>>()?
+        };
+
+        for number in numbers {
+            let ndx = self
+                .breakpoints
+                .iter()
+                .position(|b| b.number == number)
+                .ok_or_else(|| anyhow!("no breakpoint number {}", number))?;
+
+            self.core.clear_breakpoint(self.breakpoints[ndx].addr)?;
+            self.breakpoints.remove(ndx);
+        }
+
+        Ok(())
+    }
+
+    fn break_list(&self) -> String {
+        let hdr = [
+            ("7", "-1", "number", "Num"),
+            ("14", "-1", "type", "Type"),
+            ("4", "-1", "disp", "Disp"),
+            ("3", "-1", "enabled", "Enb"),
+            ("10", "-1", "addr", "Address"),
+            ("40", "2", "what", "What"),
+        ];
+
+        let hdr: Vec<String> = hdr
+            .iter()
+            .map(|(width, alignment, name, colhdr)| {
+                format!(
+                    "{{width=\"{}\",alignment=\"{}\",col_name=\"{}\",\
+                    colhdr=\"{}\"}}",
+                    width, alignment, name, colhdr
+                )
+            })
+            .collect();
+
+        let body: Vec<String> = self
+            .breakpoints
+            .iter()
+            .map(|b| format!("bkpt={}", self.mi_breakpoint(b)))
+            .collect();
+
+        format!(
+            "BreakpointTable={{nr_rows=\"{}\",nr_cols=\"{}\",\
+            hdr=[{}],body=[{}]}}",
+            body.len(),
+            hdr.len(),
+            hdr.join(","),
+            body.join(",")
+        )
+    }
+
+    //
+    // Evaluates a (very) limited set of expressions:  integers, registers
+    // of the selected frame, and the addresses of variables.
+    //
+    fn evaluate(&mut self, expr: &str) -> Result<u64> {
+        let expr = expr.trim();
+
+        if let Ok(val) = parse_int::parse::<u64>(expr) {
+            return Ok(val);
+        }
+
+        if let Some(reg) = expr.strip_prefix('$') {
+            self.halted()?;
+            let (thread, frame) = (self.thread, self.frame);
+            let frames = self.stack(thread)?;
+
+            let reg = REGISTERS
+                .iter()
+                .find(|(name, _)| *name == reg)
+                .ok_or_else(|| anyhow!("unknown register ${}", reg))?
+                .1;
+
+            return match frames.get(frame).and_then(|f| f.registers.get(&reg)) {
+                Some(val) => Ok(*val as u64),
+                None => bail!("value of {} is not available", expr),
+            };
+        }
+
+        if let Some(name) = expr.strip_prefix('&') {
+            return Ok(self.hubris.lookup_variable(name.trim())?.addr as u64);
+        }
+
+        bail!("cannot evaluate \"{}\"", expr);
+    }
+
+    fn execute(&mut self, cmd: &MiCommand) -> Result<Option<String>> {
+        let args = &cmd.args;
+
+        let rval = match cmd.name.as_str() {
+            "-gdb-set"
+            | "-gdb-show"
+            | "-environment-cd"
+            | "-file-exec-and-symbols"
+            | "-file-exec-file"
+            | "-file-symbol-file"
+            | "-inferior-tty-set"
+            | "-enable-pretty-printing"
+            | "-target-select"
+            | "-target-attach"
+            | "-target-detach"
+            | "-interpreter-exec" => String::new(),
+
+            "-gdb-version" => {
+                println!("~{}", quote(BANNER));
+                String::new()
+            }
+
+            "-list-features" => {
+                "features=[\"thread-info\",\"data-read-memory-bytes\"]"
+                    .to_string()
+            }
+
+            "-list-thread-groups" => "groups=[{id=\"i1\",type=\"process\",\
+                pid=\"1\",executable=\"hubris\"}]"
+                .to_string(),
+
+            "-exec-continue" => {
+                if self.core.is_dump() {
+                    bail!("cannot resume a dump");
+                }
+
+                self.halted()?;
+
+                //
+                // If we're stopped on a breakpoint, we must step over it
+                // before we can continue.
+                //
+                if self.on_breakpoint()?.is_some() {
+                    self.step()?;
+                }
+
+                self.core.run()?;
+                self.halted = false;
+
+                println!("{}^running", cmd.token);
+                println!("*running,thread-id=\"all\"");
+                return Ok(None);
+            }
+
+            "-exec-interrupt" => {
+                if !self.halted {
+                    self.core.halt()?;
+                    self.halted = true;
+                    self.load()?;
+                }
+
+                println!("{}^done", cmd.token);
+                self.stopped(MiStop::Interrupted)?;
+                return Ok(None);
+            }
+
+            "-exec-step-instruction" | "-exec-next-instruction" => {
+                if self.core.is_dump() {
+                    bail!("cannot step a dump");
+                }
+
+                self.halted()?;
+                self.step()?;
+
+                println!("{}^running", cmd.token);
+                println!("*running,thread-id=\"all\"");
+                self.load()?;
+                self.stopped(MiStop::Stepped)?;
+                return Ok(None);
+            }
+
+            "-thread-info" => {
+                self.halted()?;
+
+                let tasks: Vec<u32> = match args.first() {
+                    Some(id) => {
+                        let id = id
+                            .parse::<u32>()
+                            .ok()
+                            .filter(|&id| id > 0)
+                            .filter(|&id| id as usize <= self.tasks.len())
+                            .ok_or_else(|| anyhow!("invalid thread {}", id))?;
+                        vec![id - 1]
+                    }
+                    None => (0..self.tasks.len() as u32).collect(),
+                };
+
+                let threads = tasks
+                    .iter()
+                    .map(|&t| self.mi_thread(t))
+                    .collect::<Result<Vec<_>>>()?;
+
+                format!(
+                    "threads=[{}],current-thread-id=\"{}\"",
+                    threads.join(","),
+                    self.thread + 1
+                )
+            }
+
+            "-thread-list-ids" => {
+                let ids: Vec<String> = (1..=self.tasks.len())
+                    .map(|id| format!("thread-id=\"{}\"", id))
+                    .collect();
+
+                format!(
+                    "thread-ids={{{}}},current-thread-id=\"{}\",\
+                    number-of-threads=\"{}\"",
+                    ids.join(","),
+                    self.thread + 1,
+                    self.tasks.len()
+                )
+            }
+
+            "-thread-select" => {
+                self.halted()?;
+
+                let id = args
+                    .first()
+                    .and_then(|id| id.parse::<u32>().ok())
+                    .filter(|&id| id > 0 && id as usize <= self.tasks.len())
+                    .ok_or_else(|| anyhow!("invalid thread id"))?;
+
+                self.thread = id - 1;
+                self.frame = 0;
+
+                let frame = self
+                    .stack(id - 1)?
+                    .first()
+                    .map(|f| Self::mi_frame(f, Some(0)))
+                    .unwrap_or_else(|| "{}".to_string());
+
+                format!("new-thread-id=\"{}\",frame={}", id, frame)
+            }
+
+            "-stack-list-frames" => {
+                self.halted()?;
+
+                let thread = self.selected(cmd)?;
+                let frames = self.stack(thread)?;
+
+                let (low, high) = match (args.get(0), args.get(1)) {
+                    (Some(low), Some(high)) => {
+                        (low.parse::<usize>()?, high.parse::<usize>()?)
+                    }
+                    _ => (0, usize::MAX),
+                };
+
+                let frames: Vec<String> = frames
+                    .iter()
+                    .enumerate()
+                    .filter(|(level, _)| *level >= low && *level <= high)
+                    .map(|(level, f)| {
+                        format!("frame={}", Self::mi_frame(f, Some(level)))
+                    })
+                    .collect();
+
+                format!("stack=[{}]", frames.join(","))
+            }
+
+            "-stack-info-depth" => {
+                self.halted()?;
+                let thread = self.selected(cmd)?;
+                format!("depth=\"{}\"", self.stack(thread)?.len())
+            }
+
+            "-stack-select-frame" => {
+                self.halted()?;
+
+                let level = args
+                    .first()
+                    .and_then(|l| l.parse::<usize>().ok())
+                    .ok_or_else(|| anyhow!("invalid frame"))?;
+                let thread = self.thread;
+
+                if level >= self.stack(thread)?.len() {
+                    bail!("no frame at level {}", level);
+                }
+
+                self.frame = level;
+                String::new()
+            }
+
+            "-stack-info-frame" => {
+                self.halted()?;
+                let (thread, level) = (self.selected(cmd)?, self.frame);
+
+                match self.stack(thread)?.get(level) {
+                    Some(f) => format!("frame={}", Self::mi_frame(f, Some(0))),
+                    None => bail!("no frame at level {}", level),
+                }
+            }
+
+            "-data-list-register-names" => {
+                let names: Vec<String> =
+                    REGISTERS.iter().map(|(n, _)| quote(n)).collect();
+                format!("register-names=[{}]", names.join(","))
+            }
+
+            "-data-list-changed-registers" => {
+                let all: Vec<String> = (0..REGISTERS.len())
+                    .map(|n| format!("\"{}\"", n))
+                    .collect();
+                format!("changed-registers=[{}]", all.join(","))
+            }
+
+            "-data-list-register-values" => {
+                self.halted()?;
+
+                let thread = self.selected(cmd)?;
+                let level = cmd.frame.unwrap_or(self.frame);
+                let decimal = matches!(
+                    args.first().map(String::as_str),
+                    Some("d") | Some("N") | Some("r")
+                );
+
+                let wanted: Vec<usize> = args
+                    .iter()
+                    .skip(1)
+                    .filter_map(|n| n.parse::<usize>().ok())
+                    .collect();
+
+                let frames = self.stack(thread)?;
+                let regs = &frames
+                    .get(level)
+                    .ok_or_else(|| anyhow!("no frame at level {}", level))?
+                    .registers;
+
+                let values: Vec<String> = REGISTERS
+                    .iter()
+                    .enumerate()
+                    .filter(|(n, _)| wanted.is_empty() || wanted.contains(n))
+                    .filter_map(|(n, (_, reg))| {
+                        let val = regs.get(reg)?;
+
+                        Some(if decimal {
+                            format!("{{number=\"{}\",value=\"{}\"}}", n, val)
+                        } else {
+                            format!(
+                                "{{number=\"{}\",value=\"0x{:x}\"}}",
+                                n, val
+                            )
+                        })
+                    })
+                    .collect();
+
+                format!("register-values=[{}]", values.join(","))
+            }
+
+            "-data-read-memory-bytes" => {
+                let mut args = args.iter();
+                let mut offset = 0u64;
+                let mut addr = None;
+                let mut count = None;
+
+                while let Some(arg) = args.next() {
+                    match arg.as_str() {
+                        "-o" => {
+                            let o = args
+                                .next()
+                                .ok_or_else(|| anyhow!("missing offset"))?;
+                            offset = self.evaluate(o)?;
+                        }
+                        _ if addr.is_none() => addr = Some(self.evaluate(arg)?),
+                        _ => count = Some(self.evaluate(arg)?),
+                    }
+                }
+
+                let (addr, count) = match (addr, count) {
+                    (Some(addr), Some(count)) => (addr + offset, count),
+                    _ => bail!("usage: -data-read-memory-bytes addr count"),
+                };
+
+                if count as usize > humility::core::CORE_MAX_READSIZE {
+                    bail!(
+                        "cannot read more than {} bytes",
+                        humility::core::CORE_MAX_READSIZE
+                    );
+                }
+
+                let mut buf = vec![0u8; count as usize];
+                self.core.read_8(addr as u32, &mut buf)?;
+
+                let contents: String =
+                    buf.iter().map(|b| format!("{:02x}", b)).collect();
+
+                format!(
+                    "memory=[{{begin=\"0x{:x}\",offset=\"0x0\",\
+                    end=\"0x{:x}\",contents=\"{}\"}}]",
+                    addr,
+                    addr + count,
+                    contents
+                )
+            }
+
+            "-data-evaluate-expression" => {
+                let expr = args.join(" ");
+                format!("value=\"{}\"", self.evaluate(&expr)?)
+            }
+
+            "-gdb-exit" => {
+                println!("{}^exit", cmd.token);
+                return Ok(Some("exit".to_string()));
+            }
+
+            "-break-insert" => self.break_insert(args)?,
+
+            "-break-delete" => {
+                self.break_delete(args)?;
+                String::new()
+            }
+
+            "-break-list" => self.break_list(),
+
+            name if name.starts_with("-break-") => {
+                bail!("{} is not supported", name);
+            }
+
+            "quit" | "q" => return Ok(Some("exit".to_string())),
+
+            name => bail!("Undefined MI command: {}", name),
+        };
+
+        if rval.is_empty() {
+            println!("{}^done", cmd.token);
+        } else {
+            println!("{}^done,{}", cmd.token, rval);
+        }
+
+        Ok(None)
+    }
+}
+
+#[rustfmt::skip::macros(println, bail)]
+fn gdbmi(
+    hubris: &mut HubrisArchive,
+    core: &mut dyn Core,
+    _args: &Args,
+    subargs: &[String],
+) -> Result<()> {
+    let subargs = GdbmiArgs::from_iter_safe(
+        subargs.iter().filter(|arg| !IGNORED_OPTIONS.contains(&arg.as_str())),
+    )?;
+
+    if !subargs.interpreter.starts_with("mi") {
+        bail!("unsupported interpreter \"{}\"", subargs.interpreter);
+    }
+
+    if !subargs.program.is_empty() {
+        info!(
+            "ignoring {}; symbols are taken from the archive",
+            subargs.program.join(" ")
+        );
+    }
+
+    let mut session = Session {
+        hubris,
+        core,
+        tasks: vec![],
+        current: 0,
+        thread: 0,
+        frame: 0,
+        halted: false,
+        stacks: HashMap::new(),
+        breakpoints: vec![],
+        next: 0,
+    };
+
+    if !subargs.quiet {
+        println!("~{}", quote(BANNER));
+    }
+
+    println!("=thread-group-added,id=\"i1\"");
+
+    session.core.halt()?;
+    session.halted = true;
+    session.load()?;
+    session.stopped(MiStop::Interrupted)?;
+
+    println!("(gdb) ");
+    io::stdout().flush()?;
+
+    //
+    // We read commands on a separate thread, so that we can notice the
+    // target stopping (e.g., on a breakpoint) while waiting for them.
+    //
+    let (tx, rx) = mpsc::channel();
+
+    thread::spawn(move || {
+        for line in io::stdin().lock().lines() {
+            if tx.send(line).is_err() {
+                break;
+            }
+        }
+    });
+
+    loop {
+        let line = match rx.recv_timeout(POLL_INTERVAL) {
+            Ok(line) => line?,
+            Err(RecvTimeoutError::Timeout) => {
+                session.poll()?;
+                continue;
+            }
+            Err(RecvTimeoutError::Disconnected) => break,
+        };
+
+        let line = line.trim();
+
+        if !line.is_empty() {
+            let done = match parse(line) {
+                Ok(cmd) => match session.execute(&cmd) {
+                    Ok(rval) => rval.is_some(),
+                    Err(err) => {
+                        println!("{}^error,msg={}", cmd.token,
+                            quote(&err.to_string()));
+                        false
+                    }
+                },
+                Err(err) => {
+                    println!("^error,msg={}", quote(&err.to_string()));
+                    false
+                }
+            };
+
+            if done {
+                break;
+            }
+        }
+
+        println!("(gdb) ");
+        io::stdout().flush()?;
+    }
+
+    //
+    // As GDB does when detaching, we leave the target running -- and
+    // without any breakpoints that we set.
+    //
+    for b in &session.breakpoints {
+        if let Err(err) = session.core.clear_breakpoint(b.addr) {
+            warn!("failed to clear breakpoint at 0x{:x}: {}", b.addr, err);
+        }
+    }
+
+    if session.halted && !session.core.is_dump() {
+        session.core.run()?;
+    }
+
+    io::stdout().flush()?;
+
+    Ok(())
+}
+
+pub fn init<'a, 'b>() -> (Command, App<'a, 'b>) {
+    (
+        Command::Attached {
+            name: "gdbmi",
+            archive: Archive::Required,
+            attach: Attach::Any,
+            validate: Validate::Booted,
+            run: gdbmi,
+        },
+        GdbmiArgs::clap(),
+    )
+}
@@ -5,7 +5,9 @@
 use humility::core::Core;
 use humility::hubris::*;
 use humility_cmd::hiffy::*;
+use humility_cmd::trigger::Triggers;
 use humility_cmd::{Archive, Args, Attach, Command, Validate};
+use humility_cortex::chip::chip_archive;
 use std::str;
 
 use anyhow::{bail, Result};
@@ -32,6 +34,13 @@ struct GpioArgs {
     )]
     input: bool,
 
+    /// list the configuration and state of all pins
+    #[structopt(
+        long, short = "A",
+        conflicts_with_all = &["input", "toggle", "set", "reset", "configure"]
+    )]
+    all: bool,
+
     /// toggle specified pins
     #[structopt(
         long, short, requires = "pins",
@@ -61,16 +70,119 @@ struct GpioArgs {
     pins: Option<Vec<String>>,
 }
 
+//
+// The registers of an STM32 GPIO port, and the width (in bits) of each
+// pin's field within them.
+//
+const STM32_GPIO_MODER: (u32, u32) = (0x00, 2);
+const STM32_GPIO_OTYPER: (u32, u32) = (0x04, 1);
+const STM32_GPIO_OSPEEDR: (u32, u32) = (0x08, 2);
+const STM32_GPIO_PUPDR: (u32, u32) = (0x0c, 2);
+const STM32_GPIO_IDR: (u32, u32) = (0x10, 1);
+const STM32_GPIO_ODR: (u32, u32) = (0x14, 1);
+const STM32_GPIO_AFRL: u32 = 0x20;
+
+//
+// The base address of GPIO port A and the stride between ports, by part.
+//
+const STM32_GPIO_PORTS: &[(&str, u32, u32)] =
+    &[("STM32F4", 0x4002_0000, 0x400), ("STM32H7", 0x5802_0000, 0x400)];
+
+//
+// Lists the configuration and state of every pin by reading the GPIO
+// registers directly, which (unlike reading pins via hiffy) allows pins to
+// be inspected without knowing how they've been configured.  This is only
+// possible on parts whose GPIO register layout we know; the ports are
+// those that the GPIO API on the target knows about.
+//
+fn gpio_all(
+    hubris: &HubrisArchive,
+    core: &mut dyn Core,
+    gpio_input: &HiffyFunction,
+) -> Result<()> {
+    let (base, stride) = match chip_archive(hubris) {
+        Some(chip) => match STM32_GPIO_PORTS
+            .iter()
+            .find(|(part, _, _)| chip.starts_with(part))
+        {
+            Some((_, base, stride)) => (*base, *stride),
+            None => bail!("listing all pins is not supported on {}", chip),
+        },
+        None => bail!("listing all pins requires the chip to be known"),
+    };
+
+    let mut ports = vec![];
+
+    for (name, _) in gpio_input.argument_variants(hubris, 0)? {
+        match name.as_bytes() {
+            [port @ b'A'..=b'K'] => {
+                ports.push((name.clone(), base + (port - b'A') as u32 * stride))
+            }
+            _ => bail!("unrecognized GPIO port \"{}\"", name),
+        }
+    }
+
+    println!(
+        "{:6} {:9} {:10} {:9} {:9} {:>2} {:>2} {:>3}",
+        "PIN", "MODE", "TYPE", "SPEED", "PULL", "AF", "IN", "OUT"
+    );
+
+    for (port, base) in &ports {
+        let mut buf = [0u8; 40];
+        core.read_8(*base, &mut buf)?;
+
+        let regs = buf
+            .chunks_exact(4)
+            .map(|w| u32::from_le_bytes(w.try_into().unwrap()))
+            .collect::<Vec<_>>();
+
+        let field = |(offs, width): (u32, u32), pin: u32| {
+            let reg = regs[(offs / 4) as usize];
+            (reg >> (pin * width)) & ((1 << width) - 1)
+        };
+
+        for pin in 0..16 {
+            let af = (regs[(STM32_GPIO_AFRL / 4 + pin / 8) as usize]
+                >> ((pin % 8) * 4))
+                & 0xf;
+
+            let mode = field(STM32_GPIO_MODER, pin);
+
+            println!(
+                "{:6} {:9} {:10} {:9} {:9} {:>2} {:>2} {:>3}",
+                format!("{}:{}", port, pin),
+                ["input", "output", "alternate", "analog"][mode as usize],
+                ["push-pull", "open-drain"]
+                    [field(STM32_GPIO_OTYPER, pin) as usize],
+                ["low", "medium", "high", "very-high"]
+                    [field(STM32_GPIO_OSPEEDR, pin) as usize],
+                ["none", "pull-up", "pull-down", "reserved"]
+                    [field(STM32_GPIO_PUPDR, pin) as usize],
+                if mode == 2 { af.to_string() } else { "-".to_string() },
+                field(STM32_GPIO_IDR, pin),
+                field(STM32_GPIO_ODR, pin),
+            );
+        }
+    }
+
+    Ok(())
+}
+
 fn gpio(
     hubris: &mut HubrisArchive,
     core: &mut dyn Core,
-    _args: &Args,
+    args: &Args,
     subargs: &[String],
 ) -> Result<()> {
     let subargs = GpioArgs::from_iter_safe(subargs)?;
     let mut context = HiffyContext::new(hubris, core, subargs.timeout)?;
+    context.set_triggers(Triggers::new(&args.triggers())?);
     let funcs = context.functions()?;
 
+    if subargs.all {
+        return gpio_all(hubris, core, funcs.get("GpioInput", 1)?);
+    }
+
     let gpio_toggle = funcs.get("GpioToggle", 2)?;
     let gpio_set = funcs.get("GpioSet", 2)?;
     let gpio_reset = funcs.get("GpioReset", 2)?;
@@ -0,0 +1,294 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use anyhow::Result;
+use humility::core::{Core, CoreOps};
+use humility::hubris::*;
+use humility_cmd::doppel::{SchedState, Task, TaskDesc, TaskId, TaskState};
+use humility_cmd::reflect::{self, Load};
+use humility_cmd::{coalesced, Archive, Args, Attach, Command, Validate};
+use structopt::clap::App;
+use structopt::StructOpt;
+
+#[derive(StructOpt, Debug)]
+#[structopt(name = "graph", about = "graph IPC relationships between tasks")]
+struct GraphArgs {
+    /// output format
+    #[structopt(
+        long, short, default_value = "dot",
+        possible_values = &["dot", "mermaid"],
+    )]
+    format: String,
+
+    /// do not annotate the graph with the current state of the tasks
+    #[structopt(long = "static", short = "s")]
+    nolive: bool,
+}
+
+//
+// An edge in our graph:  either a task slot (that is, a task that a task
+// has been configured to be able to send to), an observed blocking
+// relationship, or both.
+//
+#[derive(Debug)]
+struct Edge {
+    from: String,
+    to: String,
+    slot: Option<String>,
+    blocked: Option<&'static str>,
+}
+
+impl Edge {
+    fn label(&self) -> Option<String> {
+        match (&self.slot, self.blocked) {
+            (Some(slot), Some(blocked)) => {
+                Some(format!("{}: {}", slot, blocked))
+            }
+            (Some(slot), None) => Some(slot.clone()),
+            (None, Some(blocked)) => Some(blocked.to_string()),
+            (None, None) => None,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Node {
+    name: String,
+    faulted: bool,
+}
+
+fn graph_dot(nodes: &[Node], edges: &[Edge]) {
+    println!("digraph hubris {{");
+    println!("    rankdir=LR;");
+    println!("    node [shape=box];");
+
+    for node in nodes {
+        if node.faulted {
+            println!(
+                "    \"{}\" [label=\"{}\\n(faulted)\", color=red];",
+                node.name, node.name
+            );
+        } else {
+            println!("    \"{}\";", node.name);
+        }
+    }
+
+    for edge in edges {
+        let mut attrs = vec![];
+
+        if let Some(label) = edge.label() {
+            attrs.push(format!("label=\"{}\"", label));
+        }
+
+        if edge.blocked.is_some() {
+            attrs.push("color=red".to_string());
+            attrs.push("style=bold".to_string());
+
+            if edge.slot.is_none() {
+                attrs.push("constraint=false".to_string());
+            }
+        }
+
+        if attrs.is_empty() {
+            println!("    \"{}\" -> \"{}\";", edge.from, edge.to);
+        } else {
+            println!(
+                "    \"{}\" -> \"{}\" [{}];",
+                edge.from,
+                edge.to,
+                attrs.join(", ")
+            );
+        }
+    }
+
+    println!("}}");
+}
+
+fn graph_mermaid(nodes: &[Node], edges: &[Edge]) {
+    println!("graph LR");
+
+    for node in nodes {
+        if node.faulted {
+            println!("    {}[\"{} (faulted)\"]", node.name, node.name);
+            println!("    style {} stroke:red", node.name);
+        } else {
+            println!("    {}", node.name);
+        }
+    }
+
+    //
+    // Mermaid styles links by their index in the order of their definition,
+    // so we track which of our edges denote blocking relationships.
+    //
+    let mut blocked = vec![];
+
+    for (ndx, edge) in edges.iter().enumerate() {
+        let arrow = if edge.blocked.is_some() {
+            blocked.push(ndx.to_string());
+            "==>"
+        } else {
+            "-->"
+        };
+
+        match edge.label() {
+            Some(label) => {
+                println!(
+                    "    {} {}|\"{}\"| {}",
+                    edge.from, arrow, label, edge.to
+                );
+            }
+            None => println!("    {} {} {}", edge.from, arrow, edge.to),
+        }
+    }
+
+    if !blocked.is_empty() {
+        println!("    linkStyle {} stroke:red", blocked.join(","));
+    }
+}
+
+fn graph(
+    hubris: &mut HubrisArchive,
+    core: &mut dyn Core,
+    _args: &Args,
+    subargs: &[String],
+) -> Result<()> {
+    let subargs = GraphArgs::from_iter_safe(subargs)?;
+
+    let base = core.read_word_32(hubris.lookup_symword("TASK_TABLE_BASE")?)?;
+    let task_count =
+        core.read_word_32(hubris.lookup_symword("TASK_TABLE_SIZE")?)?;
+    let task_t = hubris.lookup_struct_byname("Task")?;
+
+    //
+    // As with tasks, we read the entire task table at once (and with the
+    // core halted) to get a consistent snapshot of blocking relationships.
+    //
+    let mut taskblock = vec![0; task_t.size * task_count as usize];
+
+    core.halt()?;
+    let rval = core.read_8(base, &mut taskblock);
+    core.run()?;
+    rval?;
+
+    //
+    // Each task's descriptor is read separately, so we coalesce the reads.
+    //
+    let (nodes, states) = coalesced(hubris, core, |core| {
+        let mut nodes = vec![];
+        let mut states = vec![];
+
+        for i in 0..task_count {
+            let offs = i as usize * task_t.size;
+            let task_value: reflect::Value =
+                reflect::load(hubris, &taskblock, task_t, offs)?;
+            let task: Task = Task::from_value(&task_value)?;
+            let desc: TaskDesc = task.descriptor.load_from(hubris, core)?;
+
+            let name = match hubris.instr_mod(desc.entry_point) {
+                Some(module) => module.to_string(),
+                None => format!("task{}", i),
+            };
+
+            nodes.push(Node {
+                name,
+                faulted: matches!(task.state, TaskState::Faulted { .. }),
+            });
+
+            states.push(task.state);
+        }
+
+        Ok((nodes, states))
+    })?;
+
+    let mut edges = vec![];
+
+    for node in &nodes {
+        if let Some(slots) = hubris.manifest.task_slots.get(&node.name) {
+            for slot in slots {
+                edges.push(Edge {
+                    from: node.name.clone(),
+                    to: slot.task.clone(),
+                    slot: if slot.name != slot.task {
+                        Some(slot.name.clone())
+                    } else {
+                        None
+                    },
+                    blocked: None,
+                });
+            }
+        }
+    }
+
+    if !subargs.nolive {
+        let mut kernel = false;
+
+        for (i, state) in states.iter().enumerate() {
+            let (tid, blocked) = match state {
+                TaskState::Healthy(SchedState::InSend(tid)) => {
+                    (*tid, "sending")
+                }
+                TaskState::Healthy(SchedState::InReply(tid)) => {
+                    (*tid, "awaiting reply")
+                }
+                TaskState::Healthy(SchedState::InRecv(Some(tid))) => {
+                    (*tid, "receiving")
+                }
+                _ => continue,
+            };
+
+            let from = &nodes[i].name;
+
+            let to = if tid == TaskId::KERNEL {
+                kernel = true;
+                "kernel".to_string()
+            } else {
+                match nodes.get(tid.index()) {
+                    Some(node) => node.name.clone(),
+                    None => format!("task{}", tid.index()),
+                }
+            };
+
+            //
+            // If this blocking relationship corresponds to a task slot, we
+            // annotate that edge rather than adding another.
+            //
+            match edges
+                .iter_mut()
+                .find(|e| &e.from == from && e.to == to && e.blocked.is_none())
+            {
+                Some(edge) => edge.blocked = Some(blocked),
+                None => edges.push(Edge {
+                    from: from.clone(),
+                    to,
+                    slot: None,
+                    blocked: Some(blocked),
+                }),
+            }
+        }
+
+        if kernel {
+            nodes.push(Node { name: "kernel".to_string(), faulted: false });
+        }
+    }
+
+    match subargs.format.as_str() {
+        "mermaid" => graph_mermaid(&nodes, &edges),
+        _ => graph_dot(&nodes, &edges),
+    }
+
+    Ok(())
+}
+
+pub fn init<'a, 'b>() -> (Command, App<'a, 'b>) {
+    (
+        Command::Attached {
+            name: "graph",
+            archive: Archive::Required,
+            attach: Attach::Requires(CoreOps::READ),
+            validate: Validate::Booted,
+            run: graph,
+        },
+        GraphArgs::clap(),
+    )
+}
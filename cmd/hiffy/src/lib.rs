@@ -2,11 +2,16 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
-use anyhow::{bail, Result};
+use anyhow::{anyhow, bail, Context, Result};
+use hif::*;
 use humility::core::Core;
 use humility::hubris::*;
 use humility_cmd::hiffy::*;
+use humility_cmd::json::Json;
 use humility_cmd::{Archive, Args, Attach, Command, Validate};
+use serde::Deserialize;
+use std::fs;
+use std::path::Path;
 use structopt::clap::App;
 use structopt::StructOpt;
 
@@ -21,24 +26,47 @@ struct HiffyArgs {
     timeout: u32,
 
     /// list HIF functions
-    #[structopt(long, short)]
+    #[structopt(long, short, conflicts_with_all = &["script", "call"])]
     list: bool,
+
+    /// run a script of HIF function calls
+    #[structopt(long, short, value_name = "file", conflicts_with = "call")]
+    script: Option<String>,
+
+    /// call a HIF function
+    #[structopt(long, short, value_name = "function")]
+    call: Option<String>,
+
+    /// arguments to the function, as values or as name=value
+    #[structopt(requires = "call")]
+    arguments: Vec<String>,
 }
 
-fn hiffy(
-    hubris: &mut HubrisArchive,
-    core: &mut dyn Core,
-    _args: &Args,
-    subargs: &[String],
-) -> Result<()> {
-    let subargs = HiffyArgs::from_iter_safe(subargs)?;
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum HiffyScriptArg {
+    Int(u32),
+    Name(String),
+}
 
-    if !subargs.list {
-        bail!("expected -l");
-    }
+#[derive(Debug, Deserialize)]
+struct HiffyScriptCall {
+    function: String,
+    #[serde(default)]
+    args: Vec<HiffyScriptArg>,
+}
 
-    let mut context = HiffyContext::new(hubris, core, subargs.timeout)?;
+#[derive(Debug, Deserialize)]
+struct HiffyScript {
+    data: Option<String>,
+    #[serde(default)]
+    call: Vec<HiffyScriptCall>,
+}
 
+fn hiffy_list(
+    hubris: &HubrisArchive,
+    context: &mut HiffyContext,
+) -> Result<()> {
     let funcs = context.functions()?;
     let mut byid: Vec<Option<(&String, &HiffyFunction)>> = vec![];
 
@@ -58,11 +86,11 @@ fn hiffy(
         byid[ndx] = Some((name, func));
     }
 
-    println!("{:>3} {:30} #ARGS", "ID", "FUNCTION");
+    println!("{:>3} {:30} ARGUMENTS", "ID", "FUNCTION");
 
     for (i, id) in byid.iter().enumerate() {
         if let Some((name, func)) = id {
-            println!("{:3} {:30} {}", i, name, func.args.len());
+            println!("{:3} {:30} {}", i, name, hiffy_signature(hubris, func));
         } else {
             bail!("missing function for ID {}", i);
         }
@@ -71,6 +99,210 @@ fn hiffy(
     Ok(())
 }
 
+fn hiffy_signature(hubris: &HubrisArchive, func: &HiffyFunction) -> String {
+    match func.parameters(hubris) {
+        Ok(params) if params.is_empty() => "-".to_string(),
+        Ok(params) => params
+            .iter()
+            .map(|p| format!("{}: {}", p.name, p.typename))
+            .collect::<Vec<_>>()
+            .join(", "),
+        Err(_) => format!("({} arguments)", func.args.len()),
+    }
+}
+
+//
+// Compiles the calls in a script into a single program, returning it along
+// with the function called by each call.
+//
+fn hiffy_compile<'a>(
+    hubris: &HubrisArchive,
+    funcs: &'a HiffyFunctions,
+    script: &HiffyScript,
+) -> Result<(Vec<Op>, Vec<&'a HiffyFunction>)> {
+    let mut ops = vec![];
+    let mut called = vec![];
+
+    for (ndx, call) in script.call.iter().enumerate() {
+        let func = funcs
+            .get(&call.function, call.args.len())
+            .with_context(|| format!("call {}", ndx))?;
+
+        let params =
+            func.parameters(hubris).with_context(|| format!("call {}", ndx))?;
+        let mut values = vec![];
+
+        for (arg, param) in call.args.iter().zip(params.iter()) {
+            values.push(match arg {
+                HiffyScriptArg::Int(val) => HiffyValue::from(*val),
+                HiffyScriptArg::Name(name) => param
+                    .parse(name)
+                    .with_context(|| format!("call {}", ndx))?,
+            });
+        }
+
+        ops.extend(
+            func.call_ops(hubris, &values)
+                .with_context(|| format!("call {}", ndx))?,
+        );
+
+        called.push(func);
+    }
+
+    ops.push(Op::Done);
+
+    Ok((ops, called))
+}
+
+fn hiffy_script(
+    hubris: &HubrisArchive,
+    core: &mut dyn Core,
+    context: &mut HiffyContext,
+    args: &Args,
+    filename: &str,
+) -> Result<()> {
+    let contents = fs::read_to_string(filename)
+        .with_context(|| format!("failed to read {}", filename))?;
+    let script: HiffyScript = toml::from_str(&contents)
+        .with_context(|| format!("failed to parse {}", filename))?;
+
+    if script.call.is_empty() {
+        bail!("{}: script contains no calls", filename);
+    }
+
+    let data = match script.data {
+        Some(ref data) => {
+            let path = Path::new(filename).with_file_name(data);
+
+            Some(fs::read(&path).with_context(|| {
+                format!("failed to read {}", path.display())
+            })?)
+        }
+        None => None,
+    };
+
+    let funcs = context.functions()?;
+    let (ops, called) = hiffy_compile(hubris, &funcs, &script)?;
+
+    hiffy_run(core, context, args, &ops, &called, data.as_deref())
+}
+
+//
+// Calls a single function, with arguments as specified on the command line.
+//
+fn hiffy_call(
+    hubris: &HubrisArchive,
+    core: &mut dyn Core,
+    context: &mut HiffyContext,
+    args: &Args,
+    function: &str,
+    arguments: &[String],
+) -> Result<()> {
+    let funcs = context.functions()?;
+
+    let func = funcs
+        .0
+        .get(function)
+        .ok_or_else(|| anyhow!("no function \"{}\" (-l to list)", function))?;
+
+    let values = func.parse_arguments(hubris, arguments)?;
+    let mut ops = func.call_ops(hubris, &values)?;
+    ops.push(Op::Done);
+
+    hiffy_run(core, context, args, &ops, &[func], None)
+}
+
+fn hiffy_run(
+    core: &mut dyn Core,
+    context: &mut HiffyContext,
+    args: &Args,
+    ops: &[Op],
+    called: &[&HiffyFunction],
+    data: Option<&[u8]>,
+) -> Result<()> {
+    let results = context.run(core, ops, data)?;
+
+    if results.len() != called.len() {
+        bail!("expected {} results, found {}", called.len(), results.len());
+    }
+
+    if args.json() {
+        Json::Array(
+            called
+                .iter()
+                .zip(results.iter())
+                .enumerate()
+                .map(|(ndx, (func, result))| {
+                    Json::object(vec![
+                        ("call", Json::from(ndx)),
+                        ("function", Json::from(&func.name)),
+                        match result {
+                            Ok(val) => ("ok", Json::from(val.as_slice())),
+                            Err(code) => {
+                                ("err", Json::from(func.strerror(*code)))
+                            }
+                        },
+                    ])
+                })
+                .collect(),
+        )
+        .print();
+
+        return Ok(());
+    }
+
+    println!("{:>4} {:24} RESULT", "CALL", "FUNCTION");
+
+    for (ndx, (func, result)) in called.iter().zip(results.iter()).enumerate() {
+        match result {
+            Ok(val) => println!("{:4} {:24} Ok({:x?})", ndx, func.name, val),
+            Err(code) => {
+                println!(
+                    "{:4} {:24} Err({})",
+                    ndx,
+                    func.name,
+                    func.strerror(*code)
+                );
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn hiffy(
+    hubris: &mut HubrisArchive,
+    core: &mut dyn Core,
+    args: &Args,
+    subargs: &[String],
+) -> Result<()> {
+    let subargs = HiffyArgs::from_iter_safe(subargs)?;
+
+    if !subargs.list && subargs.script.is_none() && subargs.call.is_none() {
+        bail!("expected -l, --script or --call");
+    }
+
+    let mut context = HiffyContext::new(hubris, core, subargs.timeout)?;
+
+    if let Some(ref function) = subargs.call {
+        return hiffy_call(
+            hubris,
+            core,
+            &mut context,
+            args,
+            function,
+            &subargs.arguments,
+        );
+    }
+
+    match subargs.script {
+        Some(ref script) => {
+            hiffy_script(hubris, core, &mut context, args, script)
+        }
+        None => hiffy_list(hubris, &mut context),
+    }
+}
+
 pub fn init<'a, 'b>() -> (Command, App<'a, 'b>) {
     (
         Command::Attached {
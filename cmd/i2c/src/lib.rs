@@ -2,21 +2,24 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
-use anyhow::{bail, Result};
+use anyhow::{anyhow, bail, Result};
 use hif::*;
 use humility::core::Core;
 use humility::hubris::*;
 use humility_cmd::hiffy::*;
+use humility_cmd::json::{self, Json};
 use humility_cmd::printmem;
+use humility_cmd::regmap::{I2cRegister, I2cRegisterMaps};
+use humility_cmd::trigger::Triggers;
 use humility_cmd::{Archive, Args, Attach, Command, Validate};
 use structopt::clap::App;
 use structopt::StructOpt;
 
-use std::collections::HashMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::fs;
 use std::fs::File;
 use std::io::Read;
-use std::time::Instant;
+use std::time::{Duration, Instant};
 
 use indicatif::{HumanBytes, HumanDuration};
 use indicatif::{ProgressBar, ProgressStyle};
@@ -113,6 +116,700 @@ pub struct I2cArgs {
         requires = "device",
     )]
     flash: Option<String>,
+
+    /// repeatedly read the specified devices (or all devices on the
+    /// specified bus, or all devices) for the specified number of seconds,
+    /// tracking errors by device
+    #[structopt(long, value_name = "seconds",
+        conflicts_with_all = &[
+            "scan", "scanreg", "raw", "write", "writeraw", "flash"
+        ],
+        parse(try_from_str = parse_int::parse),
+    )]
+    stress: Option<u64>,
+
+    /// decode registers using the register map for the device (or the
+    /// specified register map), reading all of its registers if no register
+    /// has been specified
+    #[structopt(long, value_name = "map",
+        conflicts_with_all = &[
+            "scan", "scanreg", "raw", "write", "writeraw", "flash", "stress",
+            "block", "nbytes"
+        ],
+    )]
+    decode: Option<Option<String>>,
+
+    /// file of register maps, in addition to those that are built in
+    #[structopt(long, value_name = "filename", requires = "decode")]
+    regmap: Option<String>,
+
+    /// scan every controller, port and multiplexer segment for devices,
+    /// displaying them as a tree along with the devices in the manifest
+    #[structopt(long,
+        conflicts_with_all = &[
+            "scan", "scanreg", "bus", "controller", "port", "mux", "device",
+            "register", "raw", "write", "writeraw", "flash", "stress",
+            "decode"
+        ],
+    )]
+    scan_all: bool,
+}
+
+#[derive(Default)]
+struct I2cStressStats {
+    reads: u64,
+    naks: u64,
+    timeouts: u64,
+    resets: u64,
+    other: u64,
+}
+
+impl I2cStressStats {
+    fn record(
+        &mut self,
+        result: Option<&Result<Vec<u8>, u32>>,
+        func: &HiffyFunction,
+    ) {
+        self.reads += 1;
+
+        let err = match result {
+            Some(Ok(_)) => return,
+            Some(Err(err)) => err,
+            None => {
+                self.timeouts += 1;
+                return;
+            }
+        };
+
+        //
+        // We classify errors by their names:  a missing device or register
+        // denotes a NAK, a locked bus denotes a timeout (the controller gave
+        // up waiting for the bus), and the driver reports when it has had
+        // to reset the controller.
+        //
+        match func.errmap.get(err).map(String::as_str) {
+            Some("NoDevice") | Some("NoRegister") => self.naks += 1,
+            Some(name) if name.contains("Locked") => self.timeouts += 1,
+            Some(name) if name.contains("Reset") => self.resets += 1,
+            _ => self.other += 1,
+        }
+    }
+}
+
+fn i2c_stress(
+    hubris: &HubrisArchive,
+    core: &mut dyn Core,
+    context: &mut HiffyContext,
+    func: &HiffyFunction,
+    subargs: &I2cArgs,
+    seconds: u64,
+) -> Result<()> {
+    //
+    // If we have been given a device, we stress just that device; if we
+    // have been given a bus (or controller), we stress all of the devices
+    // in the manifest on that bus (and segment, if specified); otherwise we
+    // stress every device in the manifest.
+    //
+    let devices = if subargs.bus.is_some()
+        || subargs.controller.is_some()
+        || subargs.device.is_some()
+    {
+        let hargs = humility_cmd::i2c::I2cArgs::parse(
+            hubris,
+            &subargs.bus,
+            subargs.controller,
+            &subargs.port,
+            &subargs.mux,
+            &subargs.device,
+        )?;
+
+        if hargs.address.is_some() {
+            vec![hargs]
+        } else {
+            hubris
+                .manifest
+                .i2c_devices
+                .iter()
+                .filter(|d| d.controller == hargs.controller)
+                .filter(|d| d.port.index == hargs.port.index)
+                .map(humility_cmd::i2c::I2cArgs::from_device)
+                .filter(|d| hargs.mux.is_none() || d.mux == hargs.mux)
+                .collect::<Vec<_>>()
+        }
+    } else {
+        hubris
+            .manifest
+            .i2c_devices
+            .iter()
+            .map(humility_cmd::i2c::I2cArgs::from_device)
+            .collect::<Vec<_>>()
+    };
+
+    if devices.is_empty() {
+        bail!("no devices found to stress");
+    }
+
+    //
+    // Each pass is a single HIF program that reads each device once; as
+    // devices are visited in manifest order, passes will switch among mux
+    // segments.
+    //
+    let mut ops = vec![];
+
+    for device in &devices {
+        ops.extend(func.call_ops(
+            hubris,
+            &[
+                device.controller.into(),
+                device.port.index.into(),
+                device.mux.map(|(mux, _)| mux).into(),
+                device.mux.map(|(_, segment)| segment).into(),
+                device.address.unwrap().into(),
+                subargs.register.into(),
+                subargs.nbytes.unwrap_or(1).into(),
+            ],
+        )?);
+    }
+
+    ops.push(Op::Done);
+
+    info!("stressing {} device(s) for {} seconds", devices.len(), seconds);
+
+    let mut stats =
+        devices.iter().map(|_| I2cStressStats::default()).collect::<Vec<_>>();
+
+    let started = Instant::now();
+    let duration = Duration::from_secs(seconds);
+    let mut passes = 0;
+
+    let bar = ProgressBar::new(seconds);
+    bar.set_style(
+        ProgressStyle::default_bar()
+            .template("humility: stressing [{bar:30}] {pos}/{len}s"),
+    );
+
+    let rval = loop {
+        if started.elapsed() >= duration {
+            break Ok(());
+        }
+
+        let results = match context.run(core, ops.as_slice(), None) {
+            Ok(results) => results,
+            Err(err) => break Err(err),
+        };
+
+        for (i, stat) in stats.iter_mut().enumerate() {
+            stat.record(results.get(i), func);
+        }
+
+        passes += 1;
+        bar.set_position(started.elapsed().as_secs().min(seconds));
+    };
+
+    bar.finish_and_clear();
+
+    info!(
+        "completed {} passes in {}",
+        passes,
+        HumanDuration(started.elapsed())
+    );
+
+    println!(
+        "{:16} {:32} {:>8} {:>6} {:>8} {:>6} {:>6}",
+        "DEVICE", "LOCATION", "READS", "NAKS", "TIMEOUTS", "RESETS", "OTHER"
+    );
+
+    for (device, stat) in devices.iter().zip(stats.iter()) {
+        println!(
+            "{:16} {:32} {:>8} {:>6} {:>8} {:>6} {:>6}",
+            device.device.as_deref().unwrap_or("-"),
+            device.to_string(),
+            stat.reads,
+            stat.naks,
+            stat.timeouts,
+            stat.resets,
+            stat.other
+        );
+    }
+
+    rval
+}
+
+fn mux_json(mux: Option<(u8, u8)>) -> Json {
+    match mux {
+        Some((mux, segment)) => Json::object(vec![
+            ("mux", Json::from(mux)),
+            ("segment", Json::from(segment)),
+        ]),
+        None => Json::Null,
+    }
+}
+
+fn register_json(
+    register: &I2cRegister,
+    result: Option<&Result<Vec<u8>, u32>>,
+    func: &HiffyFunction,
+) -> Json {
+    let mut members = vec![
+        ("name", Json::from(&register.name)),
+        ("address", Json::from(register.address)),
+    ];
+
+    let raw = match result {
+        Some(Ok(val)) => register.raw(val),
+        Some(Err(err)) => Err(anyhow!("{}", func.strerror(*err))),
+        None => Err(anyhow!("timed out")),
+    };
+
+    match raw {
+        Ok(raw) => {
+            members.push(("raw", Json::from(raw)));
+
+            if register.scaled() {
+                members.push(("value", Json::from(register.value(raw))));
+                members.push(("units", Json::from(register.units.as_ref())));
+            }
+
+            if !register.fields.is_empty() {
+                let fields: Vec<_> = register
+                    .fields
+                    .iter()
+                    .map(|field| (&field.name, Json::from(field.extract(raw))))
+                    .collect();
+
+                members.push(("fields", Json::object(fields)));
+            }
+        }
+        Err(err) => members.push(("error", Json::from(err.to_string()))),
+    }
+
+    Json::object(members)
+}
+
+//
+// Reads the registers of a device as described by its register map,
+// decoding them into their values and fields.  All registers are read in a
+// single HIF program.
+//
+fn i2c_decode(
+    hubris: &HubrisArchive,
+    core: &mut dyn Core,
+    context: &mut HiffyContext,
+    func: &HiffyFunction,
+    subargs: &I2cArgs,
+    hargs: &humility_cmd::i2c::I2cArgs,
+    json: bool,
+) -> Result<()> {
+    let address = match hargs.address {
+        Some(address) => address,
+        None => bail!("expected device"),
+    };
+
+    //
+    // If we haven't been given a register map explicitly, we use the map
+    // for the device as it is named in the manifest.
+    //
+    let name = match (&subargs.decode, &hargs.device) {
+        (Some(Some(map)), _) => map.clone(),
+        (_, Some(device)) => device.clone(),
+        _ => match hubris.manifest.i2c_devices.iter().find(|d| {
+            d.controller == hargs.controller
+                && d.port.index == hargs.port.index
+                && d.mux.zip(d.segment) == hargs.mux
+                && d.address == address
+        }) {
+            Some(d) => d.device.clone(),
+            None => bail!(
+                "device 0x{:x} is not in the manifest; specify its \
+                register map via --decode",
+                address
+            ),
+        },
+    };
+
+    let maps = I2cRegisterMaps::new(subargs.regmap.as_deref())?;
+
+    let map = match maps.lookup(&name) {
+        Some(map) => map,
+        None => bail!("no register map for {}", name),
+    };
+
+    let registers = match subargs.register {
+        Some(register) => match map.lookup(&register.to_string()) {
+            Some(r) => vec![r],
+            None => bail!("{} has no register 0x{:x}", map.name, register),
+        },
+        None => map.register.iter().collect::<Vec<_>>(),
+    };
+
+    let mut ops = vec![];
+
+    for register in &registers {
+        ops.extend(func.call_ops(
+            hubris,
+            &[
+                hargs.controller.into(),
+                hargs.port.index.into(),
+                hargs.mux.map(|(mux, _)| mux).into(),
+                hargs.mux.map(|(_, segment)| segment).into(),
+                address.into(),
+                register.address.into(),
+                register.size().into(),
+            ],
+        )?);
+    }
+
+    ops.push(Op::Done);
+
+    let results = context.run(core, ops.as_slice(), None)?;
+
+    if json {
+        Json::object(vec![
+            ("controller", Json::from(hargs.controller)),
+            ("port", Json::from(&hargs.port.name)),
+            ("mux", mux_json(hargs.mux)),
+            ("address", Json::from(address)),
+            ("device", Json::from(&map.name)),
+            (
+                "registers",
+                Json::Array(
+                    registers
+                        .iter()
+                        .enumerate()
+                        .map(|(i, r)| register_json(r, results.get(i), func))
+                        .collect(),
+                ),
+            ),
+        ])
+        .print();
+
+        return Ok(());
+    }
+
+    println!("{}: {}\n", hargs, map.name);
+    println!("{:4} {:20} VALUE", "REG", "NAME");
+
+    for (i, register) in registers.iter().enumerate() {
+        print!("0x{:02x} {:20} ", register.address, register.name);
+
+        match results.get(i) {
+            Some(Ok(val)) => match register.raw(val) {
+                Ok(raw) => println!("{}", register.describe(raw)),
+                Err(err) => println!("{}", err),
+            },
+            Some(Err(err)) => println!("Err({})", func.strerror(*err)),
+            None => println!("Timed out"),
+        }
+    }
+
+    Ok(())
+}
+
+//
+// A segment of a bus as found by --scan-all:  the addresses that responded
+// (or, if the segment could not be scanned at all, the error).
+//
+struct I2cSegment {
+    mux: Option<(u8, u8)>,
+    found: BTreeSet<u8>,
+    error: Option<String>,
+}
+
+fn i2c_scan_segment(
+    core: &mut dyn Core,
+    context: &mut HiffyContext,
+    func: &HiffyFunction,
+    bus: &HubrisI2cBus,
+    mux: Option<(u8, u8)>,
+) -> Result<I2cSegment> {
+    let mut ops = vec![Op::Push(bus.controller), Op::Push(bus.port.index)];
+
+    if let Some((mux, segment)) = mux {
+        ops.push(Op::Push(mux));
+        ops.push(Op::Push(segment));
+    } else {
+        ops.push(Op::PushNone);
+        ops.push(Op::PushNone);
+    }
+
+    //
+    // This is the same loop as a scan of a single controller:  a raw read
+    // of one byte from each address.
+    //
+    ops.push(Op::PushNone);
+    ops.push(Op::Push(0));
+    ops.push(Op::PushNone);
+    ops.push(Op::Label(Target(0)));
+    ops.push(Op::Drop);
+    ops.push(Op::Swap);
+    ops.push(Op::Push(1));
+    ops.push(Op::Call(func.id));
+    ops.push(Op::Drop);
+    ops.push(Op::Swap);
+    ops.push(Op::Push(1));
+    ops.push(Op::Add);
+    ops.push(Op::Push(128));
+    ops.push(Op::BranchGreaterThanOrEqualTo(Target(0)));
+    ops.push(Op::Done);
+
+    let results = context.run(core, ops.as_slice(), None)?;
+    let mut found = BTreeSet::new();
+    let mut errors = BTreeSet::new();
+
+    for (addr, result) in results.iter().enumerate() {
+        match result {
+            Ok(_) => {
+                found.insert(addr as u8);
+            }
+            Err(err) => match func.errmap.get(err).map(String::as_str) {
+                Some("NoDevice") | Some("ReservedAddress") => {}
+                _ => {
+                    errors.insert(func.strerror(*err));
+                }
+            },
+        }
+    }
+
+    //
+    // If nothing responded and every address failed in the same way, we
+    // take the segment itself to be in error (e.g., a bad mux segment).
+    //
+    let error = if results.len() < 128 {
+        Some("timed out".to_string())
+    } else if found.is_empty() && errors.len() == 1 {
+        errors.into_iter().next()
+    } else {
+        if !errors.is_empty() {
+            let errors = errors.into_iter().collect::<Vec<_>>();
+            warn!(
+                "I2C{}: errors during scan: {}",
+                bus.controller,
+                errors.join(", ")
+            );
+        }
+
+        None
+    };
+
+    Ok(I2cSegment { mux, found, error })
+}
+
+//
+// Scans every bus in the manifest (and every multiplexer segment on which
+// the manifest has a device), displaying what was found as a tree, along
+// with the manifest's devices that weren't found.  A device on the root
+// of a bus will respond regardless of the mux segment selected, so we
+// only display devices on the segment on which they are found.
+//
+fn i2c_scan_all(
+    hubris: &HubrisArchive,
+    core: &mut dyn Core,
+    context: &mut HiffyContext,
+    func: &HiffyFunction,
+    json: bool,
+) -> Result<()> {
+    let mut output = vec![];
+
+    for bus in hubris.manifest.i2c_buses.iter().filter(|b| !b.target) {
+        let devices = hubris
+            .manifest
+            .i2c_devices
+            .iter()
+            .filter(|d| d.controller == bus.controller)
+            .filter(|d| d.port.index == bus.port.index)
+            .collect::<Vec<_>>();
+
+        let muxes = devices
+            .iter()
+            .filter_map(|d| d.mux.zip(d.segment))
+            .collect::<BTreeSet<_>>();
+
+        let mut segments = vec![];
+        let root = i2c_scan_segment(core, context, func, bus, None)?;
+
+        for mux in muxes {
+            let mut segment =
+                i2c_scan_segment(core, context, func, bus, Some(mux))?;
+
+            segment.found.retain(|addr| !root.found.contains(addr));
+            segments.push(segment);
+        }
+
+        segments.insert(0, root);
+
+        //
+        // For each segment, we merge what we found with the manifest's
+        // devices, by address.
+        //
+        let segments = segments
+            .into_iter()
+            .map(|segment| {
+                let mut entries = BTreeMap::new();
+
+                for addr in &segment.found {
+                    entries.insert(*addr, (true, None));
+                }
+
+                for d in &devices {
+                    if d.mux.zip(d.segment) == segment.mux {
+                        entries.entry(d.address).or_insert((false, None)).1 =
+                            Some(*d);
+                    }
+                }
+
+                (segment, entries)
+            })
+            .collect::<Vec<_>>();
+
+        output.push((bus, segments));
+    }
+
+    if json {
+        let mut buses = vec![];
+
+        for (bus, segments) in &output {
+            let mut segs = vec![];
+
+            for (segment, entries) in segments {
+                let devices = entries
+                    .iter()
+                    .map(|(addr, (found, device))| {
+                        Json::object(vec![
+                            ("address", Json::from(*addr)),
+                            ("found", Json::from(*found)),
+                            ("device", Json::from(device.map(|d| &d.device))),
+                            (
+                                "description",
+                                Json::from(device.map(|d| &d.description)),
+                            ),
+                        ])
+                    })
+                    .collect();
+
+                segs.push(Json::object(vec![
+                    ("mux", mux_json(segment.mux)),
+                    ("error", Json::from(segment.error.as_ref())),
+                    ("devices", Json::Array(devices)),
+                ]));
+            }
+
+            buses.push(Json::object(vec![
+                ("controller", Json::from(bus.controller)),
+                ("port", Json::from(&bus.port.name)),
+                ("name", Json::from(bus.name.as_ref())),
+                ("segments", Json::Array(segs)),
+            ]));
+        }
+
+        Json::Array(buses).print();
+        return Ok(());
+    }
+
+    let entry = |addr: &u8, found: bool, device: Option<&HubrisI2cDevice>| {
+        let mut rval = format!("0x{:02x}", addr);
+
+        match device {
+            Some(d) => {
+                rval.push_str(&format!(" {} ({})", d.device, d.description))
+            }
+            None => rval.push_str(" unknown device"),
+        }
+
+        if !found {
+            rval.push_str(": not found");
+        }
+
+        rval
+    };
+
+    for (bus, segments) in &output {
+        match bus.name {
+            Some(ref name) => println!(
+                "I2C{}, port {} ({})",
+                bus.controller, bus.port.name, name
+            ),
+            None => println!("I2C{}, port {}", bus.controller, bus.port.name),
+        }
+
+        //
+        // The root segment's devices are children of the bus, as are the
+        // other segments; each segment's devices are children of it.
+        //
+        let mut lines = vec![];
+
+        for (segment, entries) in segments {
+            let children = entries
+                .iter()
+                .map(|(addr, (found, device))| entry(addr, *found, *device))
+                .collect::<Vec<_>>();
+
+            match segment.mux {
+                None => {
+                    if let Some(ref err) = segment.error {
+                        lines.push((format!("scan failed: {}", err), vec![]));
+                    }
+
+                    lines.extend(children.into_iter().map(|c| (c, vec![])));
+                }
+                Some((mux, seg)) => {
+                    let mut label = format!("mux {}, segment {}", mux, seg);
+
+                    if let Some(ref err) = segment.error {
+                        label.push_str(&format!(": scan failed: {}", err));
+                    }
+
+                    lines.push((label, children));
+                }
+            }
+        }
+
+        for (i, (line, children)) in lines.iter().enumerate() {
+            let last = i == lines.len() - 1;
+            let branch = if last { "└── " } else { "├── " };
+            println!("{}{}", branch, line);
+
+            for (j, child) in children.iter().enumerate() {
+                println!(
+                    "{}{}{}",
+                    if last { "    " } else { "│   " },
+                    if j == children.len() - 1 {
+                        "└── "
+                    } else {
+                        "├── "
+                    },
+                    child
+                );
+            }
+        }
+
+        println!();
+    }
+
+    Ok(())
+}
+
+//
+// Emits the results as JSON.  For a scan of a controller, each result
+// corresponds to the address of the same index; for a scan of a device, each
+// result corresponds to the register of the same index.
+//
+fn i2c_json(
+    subargs: &I2cArgs,
+    hargs: &humility_cmd::i2c::I2cArgs,
+    results: &[Result<Vec<u8>, u32>],
+    func: &HiffyFunction,
+) -> Json {
+    let scan = subargs.scan || subargs.scanreg.is_some();
+
+    Json::object(vec![
+        ("controller", Json::from(hargs.controller)),
+        ("port", Json::from(&hargs.port.name)),
+        ("mux", mux_json(hargs.mux)),
+        ("address", Json::from(hargs.address)),
+        ("register", Json::from(subargs.register.or(subargs.scanreg))),
+        ("scan", Json::from(scan)),
+        ("results", json::hiffy_results(func, results)),
+    ])
 }
 
 fn i2c_done(
@@ -347,7 +1044,7 @@ fn i2c_done(
 fn i2c(
     hubris: &mut HubrisArchive,
     core: &mut dyn Core,
-    _args: &Args,
+    args: &Args,
     subargs: &[String],
 ) -> Result<()> {
     let subargs = I2cArgs::from_iter_safe(subargs)?;
@@ -357,16 +1054,21 @@ fn i2c(
         && subargs.register.is_none()
         && !subargs.raw
         && subargs.flash.is_none()
+        && subargs.stress.is_none()
+        && subargs.decode.is_none()
+        && !subargs.scan_all
     {
         bail!(
-            "must indicate a scan (-s/-S), specify a register (-r), \
-            indicate raw (-R) or flash (-f)"
+            "must indicate a scan (-s/-S/--scan-all), specify a register \
+            (-r), indicate raw (-R), flash (-f), stress (--stress) or \
+            decode (--decode)"
         );
     }
 
     let mut context = HiffyContext::new(hubris, core, subargs.timeout)?;
+    context.set_triggers(Triggers::new(&args.triggers())?);
 
-    let (fname, args) = if subargs.flash.is_some() {
+    let (fname, nargs) = if subargs.flash.is_some() {
         ("I2cBulkWrite", 8)
     } else {
         match (subargs.write.is_some(), subargs.writeraw) {
@@ -376,7 +1078,15 @@ fn i2c(
     };
 
     let funcs = context.functions()?;
-    let func = funcs.get(fname, args)?;
+    let func = funcs.get(fname, nargs)?;
+
+    if let Some(seconds) = subargs.stress {
+        return i2c_stress(hubris, core, &mut context, func, &subargs, seconds);
+    }
+
+    if subargs.scan_all {
+        return i2c_scan_all(hubris, core, &mut context, func, args.json());
+    }
 
     let hargs = humility_cmd::i2c::I2cArgs::parse(
         hubris,
@@ -387,6 +1097,18 @@ fn i2c(
         &subargs.device,
     )?;
 
+    if subargs.decode.is_some() {
+        return i2c_decode(
+            hubris,
+            core,
+            &mut context,
+            func,
+            &subargs,
+            &hargs,
+            args.json(),
+        );
+    }
+
     let mut ops = vec![Op::Push(hargs.controller)];
 
     ops.push(Op::Push(hargs.port.index));
@@ -629,7 +1351,11 @@ fn i2c(
 
     let results = context.run(core, ops.as_slice(), None)?;
 
-    i2c_done(&subargs, &hargs, &results, func)?;
+    if args.json() {
+        i2c_json(&subargs, &hargs, &results, func).print();
+    } else {
+        i2c_done(&subargs, &hargs, &results, func)?;
+    }
 
     Ok(())
 }
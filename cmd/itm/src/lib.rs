@@ -6,12 +6,16 @@ use anyhow::{bail, Result};
 use humility::core::Core;
 use humility::hubris::*;
 use humility_cmd::attach_live;
+use humility_cmd::defmt::{DefmtDecoder, DefmtTable};
+use humility_cmd::forward::{ForwardSeverity, LogForwarder};
 use humility_cmd::{Archive, Args, Command};
 use humility_cortex::debug::*;
 use humility_cortex::dwt::*;
+use humility_cortex::export::{TraceExporter, TraceFormat};
 use humility_cortex::itm::*;
 use humility_cortex::scs::*;
 use humility_cortex::tpiu::*;
+use std::collections::HashMap;
 use std::fs::File;
 use std::io::Read;
 use std::time::Instant;
@@ -60,6 +64,200 @@ struct ItmArgs {
         parse(try_from_str = parse_int::parse),
     )]
     clockscaler: Option<u16>,
+    /// sets the SWO baud rate (by default, 2000000)
+    #[structopt(long, value_name = "baud", requires = "enable",
+        parse(try_from_str = parse_int::parse),
+    )]
+    baud: Option<u32>,
+    /// decode port 0 as defmt, using the table of the specified task (by
+    /// default, the only task that uses defmt)
+    #[structopt(long, value_name = "task")]
+    defmt: Option<Option<String>>,
+    /// enable exception trace via the DWT
+    #[structopt(long, requires = "enable")]
+    exceptions: bool,
+    /// enable PC sampling via the DWT every specified number of cycles
+    #[structopt(long, value_name = "cycles", requires = "enable",
+        parse(try_from_str = parse_int::parse),
+    )]
+    pcsample: Option<u32>,
+    /// export ingested trace to the specified file (or directory, for CTF)
+    #[structopt(long, short = "x", value_name = "path")]
+    export: Option<String>,
+    /// format of exported trace
+    #[structopt(long, short = "F", default_value = "perfetto",
+        possible_values = &["perfetto", "ctf"], requires = "export"
+    )]
+    format: TraceFormat,
+    /// file mapping stimulus ports to named streams (by default, the
+    /// mapping in the archive, if any)
+    #[structopt(long, value_name = "filename")]
+    ports: Option<String>,
+    /// enable the specified stimulus port (by name or number)
+    #[structopt(
+        long,
+        value_name = "port",
+        number_of_values = 1,
+        requires = "enable",
+        conflicts_with = "mask"
+    )]
+    port: Vec<String>,
+    /// enable the stimulus ports in the specified mask
+    #[structopt(long, value_name = "mask", requires = "enable",
+        parse(try_from_str = parse_int::parse),
+    )]
+    mask: Option<u32>,
+}
+
+//
+// Demultiplexes output on named stimulus ports, displaying (and forwarding)
+// each line with the name of its port.
+//
+struct ItmDemux {
+    ports: HashMap<u32, HubrisItmPort>,
+    partial: HashMap<u32, Vec<u8>>,
+}
+
+impl ItmDemux {
+    fn new(ports: &[HubrisItmPort]) -> Self {
+        Self {
+            ports: ports.iter().map(|p| (p.port, p.clone())).collect(),
+            partial: HashMap::new(),
+        }
+    }
+
+    fn lookup(&self, port: &str) -> Result<u32> {
+        if let Ok(port) = parse_int::parse::<u32>(port) {
+            if port >= 32 {
+                bail!("stimulus port {} exceeds 31", port);
+            }
+
+            return Ok(port);
+        }
+
+        match self.ports.values().find(|p| p.name == port) {
+            Some(p) => Ok(p.port),
+            None => bail!("unknown stimulus port \"{}\"", port),
+        }
+    }
+
+    //
+    // Returns the stimulus enable mask:  the specified mask or ports if
+    // any, the named ports if there are any, or (by default) ports 0-3.
+    //
+    fn mask(&self, subargs: &ItmArgs) -> Result<u32> {
+        if let Some(mask) = subargs.mask {
+            return Ok(mask);
+        }
+
+        if !subargs.port.is_empty() {
+            let mut mask = 0;
+
+            for port in &subargs.port {
+                mask |= 1 << self.lookup(port)?;
+            }
+
+            return Ok(mask);
+        }
+
+        if !self.ports.is_empty() {
+            return Ok(self.ports.keys().fold(0, |mask, p| mask | (1 << p)));
+        }
+
+        Ok(0x0000_000f)
+    }
+
+    //
+    // Consumes the payload if it is on a named port; returns true if the
+    // payload was consumed.
+    //
+    fn received(
+        &mut self,
+        port: u32,
+        payload: &[u8],
+        forward: &mut Option<LogForwarder>,
+    ) -> Result<bool> {
+        let p = match self.ports.get(&port) {
+            Some(p) => p,
+            None => return Ok(false),
+        };
+
+        let partial = self.partial.entry(port).or_default();
+
+        for b in payload {
+            match *b {
+                b'\n' => {
+                    let line = String::from_utf8_lossy(partial).into_owned();
+                    partial.clear();
+
+                    println!("{:>10}: {}", p.name, line);
+
+                    if let Some(forward) = forward {
+                        let task = p.task.as_deref().unwrap_or(&p.name);
+                        forward.line(
+                            ForwardSeverity::Info,
+                            Some(task),
+                            &line,
+                        )?;
+                    }
+                }
+                b'\r' => {}
+                b => partial.push(b),
+            }
+        }
+
+        Ok(true)
+    }
+}
+
+//
+// If we have a defmt decoder, data on port 0 is fed to it (and the decoded
+// frames displayed and forwarded); returns true if the payload was consumed.
+//
+fn itm_defmt(
+    port: u32,
+    payload: &[u8],
+    defmt: &mut Option<(String, DefmtDecoder)>,
+    forward: &mut Option<LogForwarder>,
+) -> Result<bool> {
+    let (task, decoder) = match (port, defmt) {
+        (0, Some((task, decoder))) => (task, decoder),
+        _ => return Ok(false),
+    };
+
+    for frame in decoder.received(payload) {
+        match frame {
+            Ok(frame) => {
+                println!("{}", frame);
+
+                if let Some(forward) = forward {
+                    let severity = ForwardSeverity::from_level(frame.level);
+                    forward.line(
+                        severity,
+                        Some(task.as_str()),
+                        &frame.message,
+                    )?;
+                }
+            }
+            Err(err) => warn!("failed to decode defmt frame: {}", err),
+        }
+    }
+
+    Ok(true)
+}
+
+//
+// Forwards text received on a stimulus port, if we are forwarding.
+//
+fn itm_forward(
+    port: u32,
+    payload: &[u8],
+    forward: &mut Option<LogForwarder>,
+) -> Result<()> {
+    match forward {
+        Some(forward) => forward.received(port, payload),
+        None => Ok(()),
+    }
 }
 
 fn itmcmd_probe(core: &mut dyn Core, coreinfo: &CoreInfo) -> Result<()> {
@@ -114,15 +312,36 @@ fn itmcmd_disable(core: &mut dyn Core) -> Result<()> {
     Ok(())
 }
 
-fn itmcmd_ingest(subargs: &ItmArgs, filename: &str) -> Result<()> {
+fn itmcmd_ingest(
+    subargs: &ItmArgs,
+    filename: &str,
+    demux: &mut ItmDemux,
+    mut defmt: Option<(String, DefmtDecoder)>,
+    mut export: Option<TraceExporter>,
+    mut forward: Option<LogForwarder>,
+) -> Result<()> {
     let file = File::open(filename)?;
     let traceid = if subargs.bypass { None } else { Some(subargs.traceid) };
 
     let process = |packet: &ITMPacket| -> Result<()> {
-        if let ITMPayload::Instrumentation { payload, .. } = &packet.payload {
+        if let Some(export) = &mut export {
+            export.packet(packet)?;
+        }
+
+        if let ITMPayload::Instrumentation { payload, port } = &packet.payload {
+            if itm_defmt(*port, payload, &mut defmt, &mut forward)? {
+                return Ok(());
+            }
+
+            if demux.received(*port, payload, &mut forward)? {
+                return Ok(());
+            }
+
             for p in payload {
                 print!("{}", *p as char);
             }
+
+            itm_forward(*port, payload, &mut forward)?;
         }
 
         Ok(())
@@ -177,6 +396,10 @@ fn itmcmd_ingest_attached(
     core: &mut dyn Core,
     coreinfo: &CoreInfo,
     subargs: &ItmArgs,
+    demux: &mut ItmDemux,
+    mut defmt: Option<(String, DefmtDecoder)>,
+    mut export: Option<TraceExporter>,
+    mut forward: Option<LogForwarder>,
 ) -> Result<()> {
     let mut bytes: Vec<u8> = vec![];
     let mut ndx = 0;
@@ -200,9 +423,21 @@ fn itmcmd_ingest_attached(
             Ok(Some((bytes[ndx - 1], start.elapsed().as_secs_f64())))
         },
         |packet| {
+            if let Some(export) = &mut export {
+                export.packet(packet)?;
+            }
+
             if let ITMPayload::Instrumentation { payload, port } =
                 &packet.payload
             {
+                if itm_defmt(*port, payload, &mut defmt, &mut forward)? {
+                    return Ok(());
+                }
+
+                if demux.received(*port, payload, &mut forward)? {
+                    return Ok(());
+                }
+
                 if *port > 1 {
                     println!("{:x?}", payload);
                     return Ok(());
@@ -211,6 +446,8 @@ fn itmcmd_ingest_attached(
                 for p in payload {
                     print!("{}", *p as char);
                 }
+
+                itm_forward(*port, payload, &mut forward)?;
             }
 
             Ok(())
@@ -232,8 +469,33 @@ fn itmcmd(
         bail!("traceid has a maximum value of {:x}", ITM_TRACEID_MAX);
     }
 
+    let table = match &subargs.defmt {
+        Some(task) => Some(DefmtTable::lookup(hubris, task.as_deref())?),
+        None => None,
+    };
+
+    let defmt = table
+        .as_ref()
+        .map(|(task, table)| (task.clone(), DefmtDecoder::new(table)));
+
+    let forward = LogForwarder::new(args, hubris)?;
+
+    let mut demux = match &subargs.ports {
+        Some(filename) => {
+            let contents = std::fs::read_to_string(filename)?;
+            ItmDemux::new(&HubrisItmPort::from_toml(&contents)?)
+        }
+        None => ItmDemux::new(&hubris.manifest.itm_ports),
+    };
+
+    let export = match &subargs.export {
+        Some(path) => Some(TraceExporter::new(hubris, subargs.format, path)?),
+        None => None,
+    };
+
     if let Some(ingest) = &subargs.ingest {
-        match itmcmd_ingest(subargs, ingest) {
+        match itmcmd_ingest(subargs, ingest, &mut demux, defmt, export, forward)
+        {
             Err(e) => {
                 bail!("failed to ingest {}: {}", ingest, e);
             }
@@ -262,27 +524,48 @@ fn itmcmd(
     }
 
     if subargs.enable {
+        let baud = subargs.baud.unwrap_or(SWO_DEFAULT_BAUD);
+
+        //
+        // Unless we have been given an explicit clock scaler, derive it
+        // from the trace clock -- in which case we capture at the baud rate
+        // that results rather than the one requested.  If we have been
+        // given a clock scaler, the trace clock is implied by it.
+        //
+        let (traceclk, clockscaler, baud) = match subargs.clockscaler {
+            Some(value) => match baud.checked_mul(value as u32 + 1) {
+                Some(traceclk) => (traceclk, value, baud),
+                None => bail!("clock scaler {} is too large", value),
+            },
+            None => {
+                let khz = itm_traceclock(core, &coreinfo, hubris)?;
+                let (clockscaler, baud) = swoconfig(khz, baud)?;
+                (khz * 1000, clockscaler, baud)
+            }
+        };
+
+        info!("SWO scaler is {}, baud rate is {}", clockscaler, baud);
+
         if subargs.attach {
-            core.init_swv()?;
+            core.init_swv(traceclk, baud)?;
         }
 
-        /*
-         * By default, we enable all logging (ports 0-7).
-         */
-        let stim = 0x0000_000f;
-        let clockscaler = match subargs.clockscaler {
-            Some(value) => value,
-            None => swoscaler(hubris, core)?,
-        };
+        let stim = demux.mask(subargs)?;
 
         rval = itm_enable_explicit(core, &coreinfo, clockscaler, traceid, stim);
+
+        if rval.is_ok() && (subargs.exceptions || subargs.pcsample.is_some()) {
+            rval = itm_enable_dwt(core, subargs.exceptions, subargs.pcsample);
+        }
     }
 
     core.run()?;
     info!("core resumed");
 
     if rval.is_ok() && subargs.attach {
-        match itmcmd_ingest_attached(core, &coreinfo, subargs) {
+        match itmcmd_ingest_attached(
+            core, &coreinfo, subargs, &mut demux, defmt, export, forward,
+        ) {
             Err(e) => {
                 bail!("failed to ingest from attached device: {}", e);
             }
@@ -5,9 +5,13 @@
 use anyhow::{anyhow, bail, Result};
 use humility::core::Core;
 use humility::hubris::*;
+use humility_cmd::doppel::Task;
 use humility_cmd::jefe::{send_request, JefeRequest};
+use humility_cmd::reflect;
 use humility_cmd::{Archive, Args, Attach, Command, Validate};
 use std::num::NonZeroU32;
+use std::thread;
+use std::time::{Duration, Instant};
 use structopt::clap::App;
 use structopt::StructOpt;
 
@@ -40,9 +44,74 @@ struct JefeArgs {
     #[structopt(long, short)]
     release: bool,
 
+    /// restart the specified task
+    #[structopt(
+        long, short = "R",
+        conflicts_with_all = &["fault", "start", "hold", "release"]
+    )]
+    restart: bool,
+
     task: String,
 }
 
+//
+// Reads the generation of the specified task, which is incremented by the
+// kernel each time that the task is restarted.
+//
+fn jefe_generation(
+    hubris: &HubrisArchive,
+    core: &mut dyn Core,
+    id: NonZeroU32,
+) -> Result<u32> {
+    let base = core.read_word_32(hubris.lookup_symword("TASK_TABLE_BASE")?)?;
+    let task_t = hubris.lookup_struct_byname("Task")?;
+    let mut buf = vec![0; task_t.size];
+
+    core.halt()?;
+    let rval = core.read_8(base + u32::from(id) * task_t.size as u32, &mut buf);
+    core.run()?;
+    rval?;
+
+    let task: Task = reflect::load(hubris, &buf, task_t, 0)?;
+
+    Ok(task.generation.into())
+}
+
+//
+// Jefe's external interface has no request to restart a task, but we can
+// get the same effect by injecting a fault (which holds the task in the
+// faulted state) and then releasing the task (which sets its disposition to
+// restart, prompting jefe to restart it).  To be sure that this worked, we
+// then wait for the task's generation to change.
+//
+fn jefe_restart(
+    hubris: &mut HubrisArchive,
+    core: &mut dyn Core,
+    id: NonZeroU32,
+    timeout: u32,
+) -> Result<(u32, u32)> {
+    let before = jefe_generation(hubris, core, id)?;
+
+    send_request(hubris, core, JefeRequest::Fault, id, timeout)?;
+    send_request(hubris, core, JefeRequest::Release, id, timeout)?;
+
+    let started = Instant::now();
+
+    loop {
+        let after = jefe_generation(hubris, core, id)?;
+
+        if after != before {
+            return Ok((before, after));
+        }
+
+        if started.elapsed().as_millis() > timeout.into() {
+            bail!("task was not restarted");
+        }
+
+        thread::sleep(Duration::from_millis(100));
+    }
+}
+
 fn jefe(
     hubris: &mut HubrisArchive,
     core: &mut dyn Core,
@@ -51,16 +120,20 @@ fn jefe(
 ) -> Result<()> {
     let subargs = JefeArgs::from_iter_safe(subargs)?;
 
-    let request = if subargs.fault {
-        JefeRequest::Fault
+    let request = if subargs.restart {
+        None
+    } else if subargs.fault {
+        Some(JefeRequest::Fault)
     } else if subargs.start {
-        JefeRequest::Start
+        Some(JefeRequest::Start)
     } else if subargs.hold {
-        JefeRequest::Hold
+        Some(JefeRequest::Hold)
     } else if subargs.release {
-        JefeRequest::Release
+        Some(JefeRequest::Release)
     } else {
-        bail!("one of fault, start, hold, or release must be specified");
+        bail!(
+            "one of fault, start, hold, release, or restart must be specified"
+        );
     };
 
     let task = hubris
@@ -80,9 +153,21 @@ fn jefe(
         }
     };
 
-    send_request(hubris, core, request, id, subargs.timeout)?;
-
-    info!("successfully changed disposition for {}", subargs.task);
+    match request {
+        Some(request) => {
+            send_request(hubris, core, request, id, subargs.timeout)?;
+            info!("successfully changed disposition for {}", subargs.task);
+        }
+        None => {
+            let (before, after) =
+                jefe_restart(hubris, core, id, subargs.timeout)?;
+
+            info!(
+                "restarted {} (generation {} -> {})",
+                subargs.task, before, after
+            );
+        }
+    }
 
     Ok(())
 }
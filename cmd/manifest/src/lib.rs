@@ -14,10 +14,15 @@ struct ManifestArgs {}
 
 fn manifestcmd(
     hubris: &mut HubrisArchive,
-    _args: &Args,
+    args: &Args,
     _subargs: &[String],
 ) -> Result<()> {
-    hubris.manifest()?;
+    if args.json() {
+        hubris.manifest_json()?.print();
+    } else {
+        hubris.manifest()?;
+    }
+
     Ok(())
 }
 
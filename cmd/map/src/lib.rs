@@ -3,7 +3,7 @@
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
 use anyhow::Result;
-use humility::core::Core;
+use humility::core::{Core, CoreOps};
 use humility::hubris::*;
 use humility_cmd::{Archive, Args, Attach, Command, Validate};
 use structopt::clap::App;
@@ -61,7 +61,7 @@ pub fn init<'a, 'b>() -> (Command, App<'a, 'b>) {
         Command::Attached {
             name: "map",
             archive: Archive::Required,
-            attach: Attach::Any,
+            attach: Attach::Requires(CoreOps::READ),
             validate: Validate::Booted,
             run: mapcmd,
         },
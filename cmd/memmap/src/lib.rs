@@ -0,0 +1,251 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use anyhow::{Context, Result};
+use humility::hubris::*;
+use humility::json::Json;
+use humility_cmd::{Archive, Args, Command};
+use std::fs::File;
+use std::io::Write;
+use structopt::clap::App;
+use structopt::StructOpt;
+
+#[derive(StructOpt, Debug)]
+#[structopt(
+    name = "memmap",
+    about = "export the archive's memory map for use by other tools"
+)]
+struct MemmapArgs {
+    /// format of the memory map
+    #[structopt(long, short, default_value = "json",
+        possible_values = &["json", "linker", "gdb"],
+    )]
+    format: String,
+
+    /// file to which the memory map should be written (default is stdout)
+    #[structopt(long, short, value_name = "filename")]
+    output: Option<String>,
+}
+
+struct Region {
+    name: String,
+    owner: String,
+    base: u32,
+    size: Option<u32>,
+    attr: HubrisRegionAttr,
+}
+
+impl Region {
+    fn attr(&self) -> String {
+        format!(
+            "{}{}{}",
+            if self.attr.read { "r" } else { "" },
+            if self.attr.write { "w" } else { "" },
+            if self.attr.execute { "x" } else { "" },
+        )
+    }
+}
+
+//
+// Gathers our regions:  the loaded regions of the kernel and each task
+// (named by their owner, and their index within it), followed by the
+// peripherals that the archive knows about.
+//
+fn regions(hubris: &HubrisArchive) -> Result<Vec<Region>> {
+    let mut rval: Vec<Region> = vec![];
+
+    for region in hubris.loaded_regions().values() {
+        let owner = hubris.lookup_module(region.task)?.name.clone();
+        let ndx = rval.iter().filter(|r| r.owner == owner).count();
+
+        rval.push(Region {
+            name: format!("{}_{}", owner, ndx),
+            owner,
+            base: region.base,
+            size: Some(region.size),
+            attr: region.attr,
+        });
+    }
+
+    for (name, p) in &hubris.manifest.peripherals {
+        rval.push(Region {
+            name: name.clone(),
+            owner: "peripheral".to_string(),
+            base: p.address,
+            size: p.size,
+            attr: HubrisRegionAttr {
+                read: true,
+                write: true,
+                execute: false,
+                device: true,
+                dma: false,
+            },
+        });
+    }
+
+    rval.sort_by_key(|r| r.base);
+
+    Ok(rval)
+}
+
+//
+// Emits an array of regions, one region per line.
+//
+fn json(regions: &[Region], out: &mut dyn Write) -> Result<()> {
+    writeln!(out, "[")?;
+
+    for (i, r) in regions.iter().enumerate() {
+        let region = Json::object(vec![
+            ("name", Json::from(&r.name)),
+            ("owner", Json::from(&r.owner)),
+            ("base", Json::from(r.base)),
+            ("size", Json::from(r.size)),
+            ("read", Json::from(r.attr.read)),
+            ("write", Json::from(r.attr.write)),
+            ("execute", Json::from(r.attr.execute)),
+            ("device", Json::from(r.attr.device)),
+        ]);
+
+        let comma = if i + 1 < regions.len() { "," } else { "" };
+        writeln!(out, "  {}{}", region, comma)?;
+    }
+
+    writeln!(out, "]")?;
+
+    Ok(())
+}
+
+//
+// Emits a MEMORY command suitable for inclusion in a linker script.  Region
+// names must be valid identifiers, so anything else is replaced with an
+// underscore.
+//
+fn linker(regions: &[Region], out: &mut dyn Write) -> Result<()> {
+    writeln!(out, "/* Generated by humility memmap */")?;
+    writeln!(out, "MEMORY")?;
+    writeln!(out, "{{")?;
+
+    for r in regions {
+        let size = match r.size {
+            Some(size) => size,
+            None => continue,
+        };
+
+        let name: String = r
+            .name
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+            .collect();
+
+        writeln!(
+            out,
+            "    {:<24} ({}) : ORIGIN = 0x{:08x}, LENGTH = 0x{:x}",
+            name.to_uppercase(),
+            r.attr(),
+            r.base,
+            size
+        )?;
+    }
+
+    writeln!(out, "}}")?;
+
+    Ok(())
+}
+
+//
+// Emits a GDB script that defines a memory region for each of our regions.
+// GDB does not allow memory regions to overlap, so any regions that do are
+// coalesced into one.
+//
+fn gdb(regions: &[Region], out: &mut dyn Write) -> Result<()> {
+    let mut merged: Vec<(u64, u64, HubrisRegionAttr, Vec<&str>)> = vec![];
+
+    for r in regions {
+        let size = match r.size {
+            Some(size) => size,
+            None => continue,
+        };
+
+        let (base, end) = (r.base as u64, r.base as u64 + size as u64);
+
+        match merged.last_mut() {
+            Some((_, pend, attr, names)) if base < *pend => {
+                *pend = u64::max(*pend, end);
+                attr.read |= r.attr.read;
+                attr.write |= r.attr.write;
+                attr.device |= r.attr.device;
+                names.push(&r.name);
+            }
+            _ => merged.push((base, end, r.attr, vec![&r.name])),
+        }
+    }
+
+    writeln!(out, "# Generated by humility memmap")?;
+    writeln!(out, "delete mem")?;
+
+    for (base, end, attr, names) in merged {
+        let mode = match (attr.read, attr.write) {
+            (true, true) => "rw",
+            (false, true) => "wo",
+            _ => "ro",
+        };
+
+        writeln!(out, "# {}", names.join(", "))?;
+
+        if attr.device {
+            writeln!(
+                out,
+                "mem 0x{:08x} 0x{:08x} {} 32 nocache",
+                base, end, mode
+            )?;
+        } else {
+            writeln!(out, "mem 0x{:08x} 0x{:08x} {}", base, end, mode)?;
+        }
+    }
+
+    Ok(())
+}
+
+fn memmap(
+    hubris: &mut HubrisArchive,
+    _args: &Args,
+    subargs: &[String],
+) -> Result<()> {
+    let subargs = MemmapArgs::from_iter_safe(subargs)?;
+
+    hubris
+        .load_archive_svd()
+        .context("failed to load SVD file from archive")?;
+
+    let regions = regions(hubris)?;
+
+    let mut out: Box<dyn Write> = match &subargs.output {
+        Some(filename) => Box::new(
+            File::create(filename)
+                .with_context(|| format!("failed to create {}", filename))?,
+        ),
+        None => Box::new(std::io::stdout()),
+    };
+
+    match subargs.format.as_str() {
+        "linker" => linker(&regions, &mut out)?,
+        "gdb" => gdb(&regions, &mut out)?,
+        _ => json(&regions, &mut out)?,
+    }
+
+    out.flush()?;
+
+    Ok(())
+}
+
+pub fn init<'a, 'b>() -> (Command, App<'a, 'b>) {
+    (
+        Command::Unattached {
+            name: "memmap",
+            archive: Archive::Required,
+            run: memmap,
+        },
+        MemmapArgs::clap(),
+    )
+}
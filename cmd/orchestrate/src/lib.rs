@@ -0,0 +1,282 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use anyhow::{anyhow, bail, Context, Result};
+use humility::hubris::*;
+use humility_cmd::{Archive, Args, Command};
+use serde::Deserialize;
+use std::fs;
+use std::path::Path;
+use std::process;
+use std::thread;
+use std::time::{Duration, Instant};
+use structopt::clap::App;
+use structopt::StructOpt;
+
+#[derive(StructOpt, Debug)]
+#[structopt(
+    name = "orchestrate",
+    about = "run commands across multiple boards in parallel"
+)]
+struct OrchestrateArgs {
+    /// TOML file describing the boards to run against
+    #[structopt(long, short, value_name = "filename")]
+    lab: String,
+
+    /// command to run on each board (may be repeated; defaults to "test")
+    #[structopt(long = "run", short = "r", value_name = "command")]
+    commands: Vec<String>,
+
+    /// restrict to the specified board (may be repeated)
+    #[structopt(long, short, value_name = "board")]
+    board: Vec<String>,
+
+    /// continue running commands on a board after a failure
+    #[structopt(long, short)]
+    keep_going: bool,
+
+    /// directory in which to deposit per-board output
+    #[structopt(long, short, value_name = "directory")]
+    output: Option<String>,
+}
+
+/*
+ * A board in the lab file.  The probe, chip and archive all default to
+ * those specified to Humility itself (either as options or via their
+ * respective environment variables).
+ */
+#[derive(Debug, Deserialize)]
+struct Board {
+    name: String,
+    probe: Option<String>,
+    chip: Option<String>,
+    archive: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Lab {
+    board: Vec<Board>,
+}
+
+#[derive(Debug)]
+struct Outcome {
+    command: String,
+    success: bool,
+    status: String,
+    duration: Duration,
+}
+
+#[derive(Debug)]
+struct BoardResult {
+    name: String,
+    outcomes: Vec<Outcome>,
+    output: Vec<u8>,
+}
+
+impl BoardResult {
+    fn passed(&self) -> bool {
+        self.outcomes.iter().all(|o| o.success)
+    }
+}
+
+fn run_board(
+    exe: &Path,
+    board: &Board,
+    commands: &[String],
+    keep_going: bool,
+) -> BoardResult {
+    let mut rval = BoardResult {
+        name: board.name.clone(),
+        outcomes: vec![],
+        output: vec![],
+    };
+
+    for command in commands {
+        let mut cmd = process::Command::new(exe);
+
+        //
+        // We don't want a dump specified in our environment to be inherited
+        // by our children:  every board is expected to be live.
+        //
+        cmd.env_remove("HUMILITY_DUMP");
+
+        if let Some(ref chip) = board.chip {
+            cmd.arg("-c").arg(chip);
+        }
+
+        if let Some(ref probe) = board.probe {
+            cmd.arg("-p").arg(probe);
+        }
+
+        if let Some(ref archive) = board.archive {
+            cmd.arg("-a").arg(archive);
+        }
+
+        cmd.args(command.split_whitespace());
+
+        let start = Instant::now();
+        let result = cmd.output();
+        let duration = start.elapsed();
+
+        rval.output.extend(format!("$ humility {}\n", command).as_bytes());
+
+        let outcome = match result {
+            Ok(output) => {
+                rval.output.extend(&output.stdout);
+                rval.output.extend(&output.stderr);
+
+                Outcome {
+                    command: command.clone(),
+                    success: output.status.success(),
+                    status: match output.status.code() {
+                        Some(0) => "ok".to_string(),
+                        Some(code) => format!("exit {}", code),
+                        None => "killed".to_string(),
+                    },
+                    duration,
+                }
+            }
+            Err(err) => {
+                rval.output.extend(format!("{}\n", err).as_bytes());
+
+                Outcome {
+                    command: command.clone(),
+                    success: false,
+                    status: "spawn failed".to_string(),
+                    duration,
+                }
+            }
+        };
+
+        let failed = !outcome.success;
+        rval.outcomes.push(outcome);
+
+        if failed && !keep_going {
+            break;
+        }
+    }
+
+    rval
+}
+
+#[rustfmt::skip::macros(println, bail)]
+fn orchestrate(
+    _hubris: &mut HubrisArchive,
+    args: &Args,
+    subargs: &[String],
+) -> Result<()> {
+    let subargs = OrchestrateArgs::from_iter_safe(subargs)?;
+
+    let contents = fs::read_to_string(&subargs.lab)
+        .with_context(|| format!("failed to read {}", subargs.lab))?;
+    let lab: Lab = toml::from_str(&contents)
+        .with_context(|| format!("failed to parse {}", subargs.lab))?;
+
+    for name in &subargs.board {
+        if !lab.board.iter().any(|b| &b.name == name) {
+            bail!("board \"{}\" not found in {}", name, subargs.lab);
+        }
+    }
+
+    //
+    // Resolve each board's chip, probe and archive against our own options
+    // now, so that each thread has everything it needs.
+    //
+    let boards: Vec<Board> = lab
+        .board
+        .into_iter()
+        .filter(|b| subargs.board.is_empty() || subargs.board.contains(&b.name))
+        .map(|b| Board {
+            name: b.name,
+            chip: b.chip.or_else(|| Some(args.chip.clone())),
+            probe: b.probe.or_else(|| args.probe.clone()),
+            archive: b.archive.or_else(|| args.archive.clone()),
+        })
+        .collect();
+
+    if boards.is_empty() {
+        bail!("no boards found in {}", subargs.lab);
+    }
+
+    let commands = if subargs.commands.is_empty() {
+        vec!["test".to_string()]
+    } else {
+        subargs.commands.clone()
+    };
+
+    let exe = std::env::current_exe()
+        .context("failed to determine path to humility")?;
+
+    let handles: Vec<_> = boards
+        .into_iter()
+        .map(|board| {
+            let exe = exe.clone();
+            let commands = commands.clone();
+            let keep_going = subargs.keep_going;
+
+            thread::spawn(move || {
+                run_board(&exe, &board, &commands, keep_going)
+            })
+        })
+        .collect();
+
+    let mut results = handles
+        .into_iter()
+        .map(|h| h.join().map_err(|_| anyhow!("board thread panicked")))
+        .collect::<Result<Vec<_>>>()?;
+
+    results.sort_by(|a, b| a.name.cmp(&b.name));
+
+    if let Some(ref dir) = subargs.output {
+        fs::create_dir_all(dir)
+            .with_context(|| format!("failed to create {}", dir))?;
+
+        for result in &results {
+            let path = Path::new(dir).join(format!("{}.txt", result.name));
+            fs::write(&path, &result.output)
+                .with_context(|| format!("failed to write {:?}", path))?;
+        }
+    }
+
+    println!("{:20} {:30} {:>12} {:>10}", "BOARD", "COMMAND", "RESULT", "TIME");
+
+    for result in &results {
+        for outcome in &result.outcomes {
+            println!("{:20} {:30} {:>12} {:>9.2}s",
+                result.name, outcome.command, outcome.status,
+                outcome.duration.as_secs_f64());
+        }
+    }
+
+    let failed: Vec<_> =
+        results.iter().filter(|r| !r.passed()).map(|r| &r.name).collect();
+
+    println!();
+    println!("{} of {} boards passed", results.len() - failed.len(),
+        results.len());
+
+    if !failed.is_empty() {
+        if subargs.output.is_none() {
+            for result in results.iter().filter(|r| !r.passed()) {
+                println!("\n==== {} ====", result.name);
+                print!("{}", String::from_utf8_lossy(&result.output));
+            }
+        }
+
+        bail!("failed on {} board(s): {:?}", failed.len(), failed);
+    }
+
+    Ok(())
+}
+
+pub fn init<'a, 'b>() -> (Command, App<'a, 'b>) {
+    (
+        Command::Unattached {
+            name: "orchestrate",
+            archive: Archive::Ignored,
+            run: orchestrate,
+        },
+        OrchestrateArgs::clap(),
+    )
+}
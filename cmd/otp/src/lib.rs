@@ -0,0 +1,502 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+#[macro_use]
+extern crate log;
+
+use anyhow::{bail, Result};
+use humility::core::Core;
+use humility::hubris::*;
+use humility_cmd::{Archive, Args, Attach, Command, Validate};
+use humility_cortex::chip::chip_identify;
+use humility_cortex::debug::*;
+use std::time::{Duration, Instant};
+use structopt::clap::App;
+use structopt::StructOpt;
+
+//
+// The STM32F4 has 512 bytes of OTP in 16 blocks of 32 bytes, followed by a
+// lock byte for each block (a block is locked when its lock byte is 0x00).
+// The factory-programmed unique ID and flash size follow in system memory.
+//
+const STM32F4_OTP_BASE: u32 = 0x1fff_7800;
+const STM32F4_OTP_NBLOCKS: usize = 16;
+const STM32F4_OTP_BLOCK_SIZE: usize = 32;
+const STM32F4_OTP_LOCK: u32 = 0x1fff_7a00;
+const STM32F4_UID: u32 = 0x1fff_7a10;
+const STM32F4_FLASH_SIZE: u32 = 0x1fff_7a22;
+
+//
+// OTP is programmed via the flash interface, a byte at a time.
+//
+const STM32F4_FLASH_KEYR: u32 = 0x4002_3c04;
+const STM32F4_FLASH_SR: u32 = 0x4002_3c0c;
+const STM32F4_FLASH_CR: u32 = 0x4002_3c10;
+
+const FLASH_KEY1: u32 = 0x4567_0123;
+const FLASH_KEY2: u32 = 0xcdef_89ab;
+
+const FLASH_SR_EOP: u32 = 1 << 0;
+const FLASH_SR_BSY: u32 = 1 << 16;
+const FLASH_SR_ERRORS: &[(u32, &str)] = &[
+    (1 << 1, "operation error"),
+    (1 << 4, "write protection error"),
+    (1 << 5, "programming alignment error"),
+    (1 << 6, "programming parallelism error"),
+    (1 << 7, "programming sequence error"),
+];
+
+const FLASH_CR_PG: u32 = 1 << 0;
+const FLASH_CR_PSIZE_MASK: u32 = 0b11 << 8;
+const FLASH_CR_LOCK: u32 = 1 << 31;
+
+/// How long we are willing to wait for a byte to be programmed
+const FLASH_TIMEOUT: Duration = Duration::from_secs(1);
+
+#[derive(StructOpt, Debug)]
+#[structopt(
+    name = "otp",
+    about = "read and program one-time programmable memory"
+)]
+struct OtpArgs {
+    #[structopt(subcommand)]
+    cmd: OtpCommand,
+}
+
+#[derive(StructOpt, Debug)]
+enum OtpCommand {
+    /// read and decode OTP memory and factory-programmed fields
+    Read,
+
+    /// program bytes within an OTP block
+    Program {
+        /// block to program
+        #[structopt(parse(try_from_str = parse_int::parse))]
+        block: usize,
+
+        /// comma-separated bytes to program
+        data: String,
+
+        /// offset within the block
+        #[structopt(long, short, default_value = "0", value_name = "offset",
+            parse(try_from_str = parse_int::parse),
+        )]
+        offset: usize,
+
+        /// actually program the block (rather than showing what would be done)
+        #[structopt(long, requires = "confirm")]
+        doit: bool,
+
+        /// confirmation token, as shown when run without --doit
+        #[structopt(long, value_name = "token")]
+        confirm: Option<String>,
+    },
+
+    /// lock an OTP block, preventing any further programming of it
+    Lock {
+        /// block to lock
+        #[structopt(parse(try_from_str = parse_int::parse))]
+        block: usize,
+
+        /// actually lock the block (rather than showing what would be done)
+        #[structopt(long, requires = "confirm")]
+        doit: bool,
+
+        /// confirmation token, as shown when run without --doit
+        #[structopt(long, value_name = "token")]
+        confirm: Option<String>,
+    },
+}
+
+struct Otp {
+    blocks: Vec<Vec<u8>>,
+    locked: Vec<bool>,
+    uid: [u8; 12],
+}
+
+impl Otp {
+    fn read(core: &mut dyn Core) -> Result<Self> {
+        let mut otp = vec![0u8; STM32F4_OTP_NBLOCKS * STM32F4_OTP_BLOCK_SIZE];
+        core.read_8(STM32F4_OTP_BASE, &mut otp)?;
+
+        let mut lock = [0u8; STM32F4_OTP_NBLOCKS];
+        core.read_8(STM32F4_OTP_LOCK, &mut lock)?;
+
+        let mut uid = [0u8; 12];
+        core.read_8(STM32F4_UID, &mut uid)?;
+
+        Ok(Self {
+            blocks: otp
+                .chunks(STM32F4_OTP_BLOCK_SIZE)
+                .map(|c| c.to_vec())
+                .collect(),
+            locked: lock.iter().map(|&l| l == 0).collect(),
+            uid,
+        })
+    }
+
+    fn check_block(&self, block: usize) -> Result<()> {
+        if block >= STM32F4_OTP_NBLOCKS {
+            bail!("block must be less than {}", STM32F4_OTP_NBLOCKS);
+        }
+
+        if self.locked[block] {
+            bail!("block {} is locked", block);
+        }
+
+        Ok(())
+    }
+
+    //
+    // Returns the token that must be provided to confirm an operation.  As
+    // it is derived from both the operation and the unique ID of the part,
+    // a token can't be reused to (mistakenly) perform a different operation
+    // or to perform the same operation on a different part.
+    //
+    fn token(
+        &self,
+        op: &str,
+        block: usize,
+        offset: usize,
+        data: &[u8],
+    ) -> String {
+        let mut hash: u32 = 0x811c_9dc5;
+
+        let bytes = self
+            .uid
+            .iter()
+            .chain(op.as_bytes())
+            .chain(&(block as u32).to_le_bytes())
+            .chain(&(offset as u32).to_le_bytes())
+            .chain(data);
+
+        for b in bytes {
+            hash ^= *b as u32;
+            hash = hash.wrapping_mul(0x0100_0193);
+        }
+
+        format!("{:08x}", hash)
+    }
+}
+
+//
+// Verifies that the attached part is one whose OTP we know.
+//
+fn otp_check_chip(core: &mut dyn Core) -> Result<()> {
+    match chip_identify(core, None)? {
+        Some(chip) if chip.name.starts_with("STM32F4") => {
+            info!("attached chip is {}", chip.name);
+            Ok(())
+        }
+        Some(chip) => bail!("OTP is not supported on {}", chip.name),
+        None => bail!("attached chip could not be identified"),
+    }
+}
+
+fn otp_read(core: &mut dyn Core) -> Result<()> {
+    let otp = Otp::read(core)?;
+    let flash = core.read_word_32(STM32F4_FLASH_SIZE & !0b11)? >> 16;
+    let uid = &otp.uid;
+
+    println!("{:>16} => {} KiB", "flash size", flash);
+    println!(
+        "{:>16} => {}",
+        "unique ID",
+        uid.iter().map(|b| format!("{:02x}", b)).collect::<String>()
+    );
+    println!("{:>16} => {}", "lot", String::from_utf8_lossy(&uid[5..12]));
+    println!("{:>16} => {}", "wafer", uid[4]);
+    println!(
+        "{:>16} => ({}, {})",
+        "coordinates",
+        u16::from_le_bytes([uid[0], uid[1]]),
+        u16::from_le_bytes([uid[2], uid[3]])
+    );
+
+    println!("\n{:>5} {:8} DATA", "BLOCK", "STATE");
+
+    for (ndx, block) in otp.blocks.iter().enumerate() {
+        let state = if otp.locked[ndx] { "locked" } else { "unlocked" };
+
+        if block.iter().all(|&b| b == 0xff) {
+            println!("{:>5} {:8} <blank>", ndx, state);
+            continue;
+        }
+
+        for (i, line) in block.chunks(16).enumerate() {
+            println!(
+                "{:>5} {:8} {}",
+                if i == 0 { ndx.to_string() } else { "".to_string() },
+                if i == 0 { state } else { "" },
+                line.iter()
+                    .map(|b| format!("{:02x}", b))
+                    .collect::<Vec<_>>()
+                    .join(" ")
+            );
+        }
+    }
+
+    Ok(())
+}
+
+fn otp_wait(core: &mut dyn Core) -> Result<()> {
+    let started = Instant::now();
+
+    loop {
+        let sr = core.read_word_32(STM32F4_FLASH_SR)?;
+
+        if sr & FLASH_SR_BSY == 0 {
+            for (bit, err) in FLASH_SR_ERRORS {
+                if sr & bit != 0 {
+                    core.write_word_32(STM32F4_FLASH_SR, sr)?;
+                    bail!("{} (FLASH_SR is 0x{:x})", err, sr);
+                }
+            }
+
+            core.write_word_32(STM32F4_FLASH_SR, sr & FLASH_SR_EOP)?;
+            return Ok(());
+        }
+
+        if started.elapsed() > FLASH_TIMEOUT {
+            bail!("timed out waiting for flash (FLASH_SR is 0x{:x})", sr);
+        }
+    }
+}
+
+//
+// Programs the specified bytes.  We do this with the core halted to not
+// race with the target's own use of the flash interface, leaving the flash
+// interface locked when done.
+//
+fn otp_write(core: &mut dyn Core, addr: u32, data: &[u8]) -> Result<()> {
+    let halted = DHCSR::read(core)?.halted();
+
+    if !halted {
+        core.halt()?;
+    }
+
+    let rval: Result<()> = (|| {
+        otp_wait(core)?;
+
+        if core.read_word_32(STM32F4_FLASH_CR)? & FLASH_CR_LOCK != 0 {
+            core.write_word_32(STM32F4_FLASH_KEYR, FLASH_KEY1)?;
+            core.write_word_32(STM32F4_FLASH_KEYR, FLASH_KEY2)?;
+        }
+
+        let cr = core.read_word_32(STM32F4_FLASH_CR)?;
+
+        if cr & FLASH_CR_LOCK != 0 {
+            bail!("failed to unlock flash (FLASH_CR is 0x{:x})", cr);
+        }
+
+        //
+        // Program with a parallelism of x8, which is valid at any voltage.
+        //
+        core.write_word_32(
+            STM32F4_FLASH_CR,
+            (cr & !FLASH_CR_PSIZE_MASK) | FLASH_CR_PG,
+        )?;
+
+        let rval: Result<()> = (|| {
+            for (i, b) in data.iter().enumerate() {
+                if *b == 0xff {
+                    continue;
+                }
+
+                core.write_8(addr + i as u32, &[*b])?;
+                otp_wait(core)?;
+            }
+
+            Ok(())
+        })();
+
+        core.write_word_32(STM32F4_FLASH_CR, FLASH_CR_LOCK)?;
+
+        rval
+    })();
+
+    if !halted {
+        core.run()?;
+    }
+
+    rval?;
+
+    //
+    // Now read it back to be sure that it took.
+    //
+    let mut readback = vec![0u8; data.len()];
+    core.read_8(addr, &mut readback)?;
+
+    for (i, (expected, actual)) in data.iter().zip(readback.iter()).enumerate()
+    {
+        if actual != expected {
+            bail!(
+                "verify failed at 0x{:x}: expected 0x{:02x}, found 0x{:02x}",
+                addr + i as u32,
+                expected,
+                actual
+            );
+        }
+    }
+
+    Ok(())
+}
+
+fn otp_confirm(
+    token: &str,
+    doit: bool,
+    confirm: &Option<String>,
+    what: &str,
+) -> Result<bool> {
+    if !doit {
+        println!(
+            "{} is permanent and cannot be undone; to proceed, \
+            rerun with --doit --confirm {}",
+            what, token
+        );
+        return Ok(false);
+    }
+
+    match confirm {
+        Some(confirm) if confirm == token => Ok(true),
+        _ => bail!(
+            "confirmation token does not match; rerun without --doit to \
+            review the operation"
+        ),
+    }
+}
+
+fn otp_program(
+    core: &mut dyn Core,
+    block: usize,
+    offset: usize,
+    data: &str,
+    doit: bool,
+    confirm: &Option<String>,
+) -> Result<()> {
+    let otp = Otp::read(core)?;
+    otp.check_block(block)?;
+
+    let mut bytes = vec![];
+
+    for byte in data.split(',') {
+        if let Ok(val) = parse_int::parse::<u8>(byte) {
+            bytes.push(val);
+        } else {
+            bail!("invalid byte {}", byte)
+        }
+    }
+
+    if offset + bytes.len() > STM32F4_OTP_BLOCK_SIZE {
+        bail!(
+            "{} byte(s) at offset {} exceeds block size of {} bytes",
+            bytes.len(),
+            offset,
+            STM32F4_OTP_BLOCK_SIZE
+        );
+    }
+
+    let current = &otp.blocks[block][offset..offset + bytes.len()];
+
+    //
+    // Bits can only be cleared, never set -- so any byte that would require
+    // a set bit can't be programmed.
+    //
+    for (i, (cur, new)) in current.iter().zip(bytes.iter()).enumerate() {
+        if cur & new != *new {
+            bail!(
+                "byte {} of block {} is 0x{:02x}; cannot program 0x{:02x}",
+                offset + i,
+                block,
+                cur,
+                new
+            );
+        }
+    }
+
+    if current == bytes.as_slice() {
+        info!("block {} already contains specified bytes", block);
+        return Ok(());
+    }
+
+    let fmt = |b: &[u8]| {
+        b.iter().map(|b| format!("{:02x}", b)).collect::<Vec<_>>().join(" ")
+    };
+
+    println!("block {}, offset {}:", block, offset);
+    println!("  current: {}", fmt(current));
+    println!("      new: {}", fmt(&bytes));
+
+    let token = otp.token("program", block, offset, &bytes);
+    let what = format!("programming block {}", block);
+
+    if !otp_confirm(&token, doit, confirm, &what)? {
+        return Ok(());
+    }
+
+    let addr = STM32F4_OTP_BASE
+        + (block * STM32F4_OTP_BLOCK_SIZE) as u32
+        + offset as u32;
+
+    otp_write(core, addr, &bytes)?;
+    info!("programmed {} byte(s) in block {}", bytes.len(), block);
+
+    Ok(())
+}
+
+fn otp_lock(
+    core: &mut dyn Core,
+    block: usize,
+    doit: bool,
+    confirm: &Option<String>,
+) -> Result<()> {
+    let otp = Otp::read(core)?;
+    otp.check_block(block)?;
+
+    let token = otp.token("lock", block, 0, &[]);
+    let what = format!("locking block {}", block);
+
+    if !otp_confirm(&token, doit, confirm, &what)? {
+        return Ok(());
+    }
+
+    otp_write(core, STM32F4_OTP_LOCK + block as u32, &[0])?;
+    info!("locked block {}", block);
+
+    Ok(())
+}
+
+fn otp(
+    _hubris: &mut HubrisArchive,
+    core: &mut dyn Core,
+    _args: &Args,
+    subargs: &[String],
+) -> Result<()> {
+    let subargs = OtpArgs::from_iter_safe(subargs)?;
+
+    otp_check_chip(core)?;
+
+    match subargs.cmd {
+        OtpCommand::Read => otp_read(core),
+        OtpCommand::Program { block, data, offset, doit, confirm } => {
+            otp_program(core, block, offset, &data, doit, &confirm)
+        }
+        OtpCommand::Lock { block, doit, confirm } => {
+            otp_lock(core, block, doit, &confirm)
+        }
+    }
+}
+
+pub fn init<'a, 'b>() -> (Command, App<'a, 'b>) {
+    (
+        Command::Attached {
+            name: "otp",
+            archive: Archive::Optional,
+            attach: Attach::LiveOnly,
+            validate: Validate::None,
+            run: otp,
+        },
+        OtpArgs::clap(),
+    )
+}
@@ -4,16 +4,22 @@
 
 use humility::core::Core;
 use humility::hubris::*;
+use humility_cmd::flash::{FlashDevice, FlashDevices};
+use humility_cmd::hexfile::{self, HexFormat};
 use humility_cmd::hiffy::*;
+use humility_cmd::json::{self, Json};
 use humility_cmd::printmem;
+use humility_cmd::trigger::Triggers;
 use humility_cmd::{Archive, Args, Attach, Command, Validate};
 use std::fs;
 use std::fs::File;
 use std::io::Read;
-use std::time::Instant;
+use std::thread;
+use std::time::{Duration, Instant};
 
-use anyhow::{bail, Result};
+use anyhow::{bail, Context, Result};
 use hif::*;
+use sha2::{Digest, Sha256};
 use structopt::{clap::App, clap::ArgGroup, StructOpt};
 
 use indicatif::{HumanBytes, HumanDuration};
@@ -73,6 +79,12 @@ struct QspiArgs {
     )]
     nbytes: Option<usize>,
 
+    /// emit a read in the specified format (Intel HEX or S-records)
+    #[structopt(long, short, value_name = "format",
+        possible_values = &["ihex", "srec"], requires = "read"
+    )]
+    output: Option<HexFormat>,
+
     /// comma-separated bytes to write
     #[structopt(
         long,
@@ -83,53 +95,499 @@ struct QspiArgs {
     )]
     write: Option<String>,
 
-    /// file to write or verify
+    /// file to write or verify (at the specified address, if any)
     #[structopt(long, short = "W", value_name = "filename", group = "command")]
     writefile: Option<String>,
 
+    /// file into which to read (by default, the rest of the device)
+    #[structopt(long, short = "R", value_name = "filename", group = "command")]
+    readfile: Option<String>,
+
     /// verify instead of writing
     #[structopt(long, short = "V", requires = "writefile")]
     verify: bool,
+
+    /// check the integrity of every transfer to and from the target
+    #[structopt(long, short = "c")]
+    check: bool,
+
+    /// flash device (by default, the device is identified by its JEDEC ID)
+    #[structopt(long, value_name = "device")]
+    device: Option<String>,
+
+    /// file of additional flash device definitions
+    #[structopt(long, value_name = "filename", env = "HUMILITY_FLASH_DEVICES")]
+    devices: Option<String>,
+}
+
+//
+// The interval at which we poll for completion when pipelining HIF
+// programs.  This is much tighter than the interval used by
+// [HiffyContext::run], as we expect each program to complete quickly
+// -- but we don't want to poll so aggressively as to starve the target.
+//
+const QSPI_POLL_MS: u64 = 10;
+
+//
+// The size of the chunks of flash over which we have the target compute a
+// digest when verifying.
+//
+const QSPI_DIGEST_CHUNK: usize = 256 * 1024;
+
+//
+// Returns the throughput (in bytes per second) of a transfer of the
+// specified number of bytes that started at the specified time.
+//
+fn throughput(nbytes: usize, started: Instant) -> u64 {
+    let elapsed = started.elapsed().as_secs_f64();
+
+    if elapsed > 0.0 {
+        (nbytes as f64 / elapsed) as u64
+    } else {
+        0
+    }
+}
+
+fn wait(context: &mut HiffyContext, core: &mut dyn Core) -> Result<()> {
+    while !context.done(core)? {
+        thread::sleep(Duration::from_millis(QSPI_POLL_MS));
+    }
+
+    Ok(())
+}
+
+//
+// Reads the specified range of flash, in chunks that fit in the return
+// stack.  Each chunk is read by its own HIF program, and these are
+// pipelined:  each program is started as the results of its predecessor
+// are retrieved, so the target reads the next chunk from flash while we
+// process the previous one.  (The retrieval itself can't overlap with the
+// target's execution, as both use the single return stack.)
+//
+fn qspi_read(
+    context: &mut HiffyContext,
+    core: &mut dyn Core,
+    func: &HiffyFunction,
+    addr: usize,
+    nbytes: usize,
+    bar: &ProgressBar,
+) -> Result<Vec<u8>> {
+    let block_size = 256;
+    let size = context.limits().max_payload(1);
+    let chunk = size - (size % block_size);
+
+    if chunk == 0 {
+        bail!("return stack is too small to read flash");
+    }
+
+    let ops = |offset: usize| {
+        vec![
+            Op::Push32((addr + offset) as u32),
+            Op::Push32(usize::min(chunk, nbytes - offset) as u32),
+            Op::Call(func.id),
+            Op::Done,
+        ]
+    };
+
+    let mut rval = Vec::with_capacity(nbytes);
+    let mut offset = 0;
+
+    context.start(core, &ops(offset), None)?;
+
+    loop {
+        wait(context, core)?;
+
+        let next = offset + chunk;
+
+        let results = if next < nbytes {
+            context.results_and_start(core, &ops(next), None)?
+        } else {
+            context.results(core)?
+        };
+
+        match results.first() {
+            Some(Ok(bytes)) => {
+                rval.extend_from_slice(bytes);
+                bar.set_position(rval.len() as u64);
+            }
+            Some(Err(err)) => {
+                bail!(
+                    "failed to read 0x{:x}: {}",
+                    addr + offset,
+                    func.strerror(err)
+                );
+            }
+            None => {
+                bail!("missing result for read of 0x{:x}", addr + offset);
+            }
+        }
+
+        if next >= nbytes {
+            break;
+        }
+
+        offset = next;
+    }
+
+    Ok(rval)
+}
+
+//
+// A digest of flash contents that the target may be able to compute:  either
+// a SHA-256 hash (via QspiHash) or a CRC-32 checksum (via QspiChecksum).
+//
+#[derive(Copy, Clone, Debug)]
+enum QspiDigest {
+    Sha256,
+    Crc32,
+}
+
+impl QspiDigest {
+    fn lookup(funcs: &HiffyFunctions) -> Option<Self> {
+        [QspiDigest::Sha256, QspiDigest::Crc32]
+            .into_iter()
+            .find(|digest| funcs.get(digest.function(), 2).is_ok())
+    }
+
+    fn function(&self) -> &'static str {
+        match self {
+            QspiDigest::Sha256 => "QspiHash",
+            QspiDigest::Crc32 => "QspiChecksum",
+        }
+    }
+
+    fn compute(&self, data: &[u8]) -> Vec<u8> {
+        match self {
+            QspiDigest::Sha256 => Sha256::digest(data).to_vec(),
+            QspiDigest::Crc32 => crc32fast::hash(data).to_le_bytes().to_vec(),
+        }
+    }
+}
+
+fn qspi_verified(
+    what: &str,
+    nbytes: usize,
+    base: usize,
+    started: Instant,
+    failed: usize,
+) -> Result<()> {
+    if failed != 0 {
+        bail!("{} of {} failed to verify", HumanBytes(failed as u64), what);
+    }
+
+    info!(
+        "verified {} at 0x{:x} via {} in {} ({}/s)",
+        HumanBytes(nbytes as u64),
+        base,
+        what,
+        HumanDuration(started.elapsed()),
+        HumanBytes(throughput(nbytes, started))
+    );
+
+    Ok(())
+}
+
+//
+// Verifies flash by having the target compute a digest of each chunk of the
+// range, comparing it to our own digest of the corresponding chunk of data.
+// Each chunk is small enough that its digest can be computed well within
+// our timeout.
+//
+fn qspi_verify_digest(
+    context: &mut HiffyContext,
+    core: &mut dyn Core,
+    func: &HiffyFunction,
+    digest: QspiDigest,
+    data: &[u8],
+    base: usize,
+) -> Result<()> {
+    let started = Instant::now();
+    let bar = ProgressBar::new(data.len() as u64);
+    bar.set_style(
+        ProgressStyle::default_bar()
+            .template("humility: verifying [{bar:30}] {bytes}/{total_bytes}"),
+    );
+
+    let mut failed = 0;
+
+    for (i, chunk) in data.chunks(QSPI_DIGEST_CHUNK).enumerate() {
+        let addr = base + i * QSPI_DIGEST_CHUNK;
+
+        let ops = [
+            Op::Push32(addr as u32),
+            Op::Push32(chunk.len() as u32),
+            Op::Call(func.id),
+            Op::Done,
+        ];
+
+        let results = context.run(core, &ops, None)?;
+
+        match results.first() {
+            Some(Ok(rval)) => {
+                if *rval != digest.compute(chunk) {
+                    info!(
+                        "0x{:x}-0x{:x} failed to verify",
+                        addr,
+                        addr + chunk.len() - 1
+                    );
+                    failed += chunk.len();
+                }
+            }
+            Some(Err(err)) => {
+                bail!("failed to digest 0x{:x}: {}", addr, func.strerror(*err))
+            }
+            None => bail!("missing result for digest of 0x{:x}", addr),
+        }
+
+        bar.set_position((addr - base + chunk.len()) as u64);
+    }
+
+    bar.finish_and_clear();
+
+    qspi_verified(func.name.as_str(), data.len(), base, started, failed)
+}
+
+//
+// Verifies flash by reading it back and comparing it to our data.
+//
+fn qspi_verify_readback(
+    context: &mut HiffyContext,
+    core: &mut dyn Core,
+    func: &HiffyFunction,
+    data: &[u8],
+    base: usize,
+) -> Result<()> {
+    let started = Instant::now();
+    let bar = ProgressBar::new(data.len() as u64);
+    bar.set_style(
+        ProgressStyle::default_bar().template(
+            "humility: reading back [{bar:30}] {bytes}/{total_bytes}",
+        ),
+    );
+
+    let contents = qspi_read(context, core, func, base, data.len(), &bar)?;
+    bar.finish_and_clear();
+
+    let mut failed = 0;
+
+    for (offset, (ours, theirs)) in data.iter().zip(contents.iter()).enumerate()
+    {
+        if ours != theirs {
+            if failed == 0 {
+                info!(
+                    "mismatch at 0x{:x}: expected 0x{:02x}, found 0x{:02x}",
+                    base + offset,
+                    ours,
+                    theirs
+                );
+            }
+
+            failed += 1;
+        }
+    }
+
+    qspi_verified("readback", data.len(), base, started, failed)
+}
+
+//
+// Determines our flash device, either as specified or by its JEDEC ID.  If
+// the device can't be identified, we return None, and our caller assumes
+// the geometry that we have historically assumed.
+//
+fn qspi_device<'d>(
+    context: &mut HiffyContext,
+    core: &mut dyn Core,
+    funcs: &HiffyFunctions,
+    devices: &'d FlashDevices,
+    subargs: &QspiArgs,
+) -> Result<Option<&'d dyn FlashDevice>> {
+    if let Some(ref name) = subargs.device {
+        return match devices.lookup(name) {
+            Some(device) => Ok(Some(device)),
+            None => bail!("unknown flash device \"{}\"", name),
+        };
+    }
+
+    let qspi_read_id = funcs.get("QspiReadId", 0)?;
+    let id: Vec<u8> =
+        context.call(core, qspi_read_id, &[]).context("failed to read ID")?;
+
+    match devices.identify(&id) {
+        Some(device) => {
+            info!("flash device is {}", device.name());
+            Ok(Some(device))
+        }
+        None => {
+            warn!(
+                "unrecognized flash device (ID {:02x?}); assuming 64 KiB \
+                sectors and 256-byte pages",
+                id
+            );
+            Ok(None)
+        }
+    }
+}
+
+//
+// Checks that our flash device isn't protected from being erased or written.
+//
+fn qspi_protected(
+    context: &mut HiffyContext,
+    core: &mut dyn Core,
+    funcs: &HiffyFunctions,
+    device: &dyn FlashDevice,
+) -> Result<()> {
+    let qspi_read_status = funcs.get("QspiReadStatus", 0)?;
+    let status: u8 = context
+        .call(core, qspi_read_status, &[])
+        .context("failed to read status")?;
+
+    if device.protected(status) {
+        bail!(
+            "{} is write protected (status register is 0x{:02x})",
+            device.name(),
+            status
+        );
+    }
+
+    Ok(())
 }
 
 fn qspi(
     hubris: &mut HubrisArchive,
     core: &mut dyn Core,
-    _args: &Args,
+    args: &Args,
     subargs: &[String],
 ) -> Result<()> {
     let subargs = QspiArgs::from_iter_safe(subargs)?;
     let mut context = HiffyContext::new(hubris, core, subargs.timeout)?;
+    context.set_triggers(Triggers::new(&args.triggers())?);
     let funcs = context.functions()?;
 
-    let sector_size = 64 * 1024;
-    let block_size = 256;
+    if subargs.check {
+        context.set_integrity(&funcs);
+    }
+
+    //
+    // For any operation that depends on the geometry of the device (or that
+    // modifies it), we need to know what the device is -- and for the
+    // latter, that it isn't protected.
+    //
+    let modifying = subargs.erase
+        || subargs.bulkerase
+        || subargs.write.is_some()
+        || (subargs.writefile.is_some() && !subargs.verify);
+
+    let devices = FlashDevices::new(subargs.devices.as_deref())?;
+
+    let device = if modifying
+        || subargs.writefile.is_some()
+        || subargs.readfile.is_some()
+    {
+        qspi_device(&mut context, core, &funcs, &devices, &subargs)?
+    } else {
+        None
+    };
+
+    if let (Some(device), true) = (device, modifying) {
+        qspi_protected(&mut context, core, &funcs, device)?;
+    }
+
+    let (sector_size, block_size) = match device {
+        Some(device) => (device.sector_size(), device.page_size()),
+        None => (64 * 1024, 256),
+    };
 
     let mut ops = vec![];
 
-    let data = if subargs.status {
+    let (func, data) = if subargs.status {
         let qspi_read_status = funcs.get("QspiReadStatus", 0)?;
-        ops.push(Op::Call(qspi_read_status.id));
-        None
+        ops.extend(qspi_read_status.call_ops(hubris, &[])?);
+        (qspi_read_status, None)
     } else if subargs.id {
         let qspi_read_id = funcs.get("QspiReadId", 0)?;
-        ops.push(Op::Call(qspi_read_id.id));
-        None
+        ops.extend(qspi_read_id.call_ops(hubris, &[])?);
+        (qspi_read_id, None)
     } else if subargs.erase {
         let qspi_sector_erase = funcs.get("QspiSectorErase", 1)?;
-        ops.push(Op::Push32(subargs.addr.unwrap() as u32));
-        ops.push(Op::Call(qspi_sector_erase.id));
-        None
+        let addr = subargs.addr.unwrap() as u32;
+
+        if let Some(device) = device {
+            device.check_range(addr, 1)?;
+        }
+
+        ops.extend(qspi_sector_erase.call_ops(hubris, &[addr.into()])?);
+        (qspi_sector_erase, None)
     } else if subargs.bulkerase {
         let qspi_bulk_erase = funcs.get("QspiBulkErase", 0)?;
-        ops.push(Op::Call(qspi_bulk_erase.id));
-        None
+        ops.extend(qspi_bulk_erase.call_ops(hubris, &[])?);
+        (qspi_bulk_erase, None)
     } else if subargs.read {
         let qspi_read = funcs.get("QspiRead", 2)?;
-        ops.push(Op::Push32(subargs.addr.unwrap() as u32));
-        ops.push(Op::Push32(subargs.nbytes.unwrap() as u32));
-        ops.push(Op::Call(qspi_read.id));
-        None
+        let addr = subargs.addr.unwrap();
+        let nbytes = subargs.nbytes.unwrap();
+
+        let bar = ProgressBar::hidden();
+        let bytes =
+            qspi_read(&mut context, core, qspi_read, addr, nbytes, &bar)?;
+
+        match subargs.output {
+            Some(format) => {
+                let mut out = std::io::stdout();
+                hexfile::writemem(&mut out, format, &bytes, addr as u32)?;
+            }
+            None if args.json() => {
+                Json::object(vec![
+                    ("addr", Json::from(addr)),
+                    ("data", Json::from(bytes.as_slice())),
+                ])
+                .print();
+            }
+            None => printmem(&bytes, 0, 1, 16),
+        }
+
+        return Ok(());
+    } else if let Some(ref filename) = subargs.readfile {
+        let qspi_read = funcs.get("QspiRead", 2)?;
+        let addr = subargs.addr.unwrap_or(0);
+
+        let nbytes = match (subargs.nbytes, device) {
+            (Some(nbytes), _) => nbytes,
+            (None, Some(device)) => {
+                (device.capacity() as usize).saturating_sub(addr)
+            }
+            (None, None) => {
+                bail!("must specify number of bytes for unrecognized device")
+            }
+        };
+
+        if let Some(device) = device {
+            device.check_range(addr as u32, nbytes as u32)?;
+        }
+
+        let started = Instant::now();
+        let bar = ProgressBar::new(nbytes as u64);
+        bar.set_style(
+            ProgressStyle::default_bar()
+                .template("humility: reading [{bar:30}] {bytes}/{total_bytes}"),
+        );
+
+        let bytes =
+            qspi_read(&mut context, core, qspi_read, addr, nbytes, &bar)?;
+
+        bar.finish_and_clear();
+        fs::write(filename, &bytes)?;
+
+        info!(
+            "read {} from 0x{:x} to {} in {} ({}/s)",
+            HumanBytes(nbytes as u64),
+            addr,
+            filename,
+            HumanDuration(started.elapsed()),
+            HumanBytes(throughput(nbytes, started))
+        );
+
+        return Ok(());
     } else if let Some(ref write) = subargs.write {
         let qspi_page_program = funcs.get("QspiPageProgram", 3)?;
         let bytes: Vec<&str> = write.split(',').collect();
@@ -143,12 +601,58 @@ fn qspi(
             }
         }
 
-        ops.push(Op::Push32(subargs.addr.unwrap() as u32));
-        ops.push(Op::Push(0));
-        ops.push(Op::Push32(arr.len() as u32));
-        ops.push(Op::Call(qspi_page_program.id));
-        Some(arr)
+        let addr = subargs.addr.unwrap() as u32;
+
+        if let Some(device) = device {
+            device.check_range(addr, arr.len() as u32)?;
+        }
+
+        let len = arr.len() as u32;
+        ops.extend(
+            qspi_page_program
+                .call_ops(hubris, &[addr.into(), 0u32.into(), len.into()])?,
+        );
+        (qspi_page_program, Some(arr))
     } else if let Some(filename) = subargs.writefile {
+        //
+        // If we are verifying, we prefer to have the target compute a digest
+        // of flash rather than sending (or reading back) its contents; if it
+        // can't, we fall back to having the target compare the contents we
+        // send it -- and failing that, to reading it back.
+        //
+        if subargs.verify {
+            let base = subargs.addr.unwrap_or(0);
+            let data = fs::read(&filename)?;
+
+            if let Some(device) = device {
+                device.check_range(base as u32, data.len() as u32)?;
+            }
+
+            if let Some(digest) = QspiDigest::lookup(&funcs) {
+                let func = funcs.get(digest.function(), 2)?;
+                return qspi_verify_digest(
+                    &mut context,
+                    core,
+                    func,
+                    digest,
+                    &data,
+                    base,
+                );
+            }
+
+            if funcs.get("QspiVerify", 3).is_err() {
+                info!("target cannot verify flash; reading it back");
+                let func = funcs.get("QspiRead", 2)?;
+                return qspi_verify_readback(
+                    &mut context,
+                    core,
+                    func,
+                    &data,
+                    base,
+                );
+            }
+        }
+
         let qspi_sector_erase = funcs.get("QspiSectorErase", 1)?;
         let qspi_page_program = if subargs.verify {
             funcs.get("QspiVerify", 3)
@@ -157,13 +661,32 @@ fn qspi(
         }?;
 
         let filelen = fs::metadata(filename.clone())?.len() as u32;
+        let base = subargs.addr.unwrap_or(0) as u32;
+
+        if let Some(device) = device {
+            device.check_range(base, filelen)?;
+        }
+
+        //
+        // Because we erase the sectors that we write, we insist that a write
+        // begin on a sector boundary (which is necessarily a page boundary);
+        // we don't want to silently erase the contents of flash before the
+        // specified address.
+        //
+        if base % sector_size != 0 && !subargs.verify {
+            bail!(
+                "address 0x{:x} is not aligned to the {}-byte sector size",
+                base,
+                sector_size
+            );
+        }
 
         if !subargs.verify {
             //
             // First, we need to erase the sectors
             //
-            ops.push(Op::Push32(filelen));
-            ops.push(Op::Push32(0));
+            ops.push(Op::Push32(base + filelen));
+            ops.push(Op::Push32(base));
             ops.push(Op::Label(Target(0)));
             ops.push(Op::Call(qspi_sector_erase.id));
             ops.push(Op::Push32(sector_size));
@@ -178,7 +701,11 @@ fn qspi(
 
             for (i, block_result) in results.iter().enumerate() {
                 if let Err(err) = *block_result {
-                    bail!("failed to erase sector {}: {}", i, f.strerror(err));
+                    bail!(
+                        "failed to erase sector at 0x{:x}: {}",
+                        base + i as u32 * sector_size,
+                        f.strerror(err)
+                    );
                 }
             }
 
@@ -210,14 +737,48 @@ fn qspi(
             ));
         }
 
+        let verify = subargs.verify;
+
+        let check = |results: HiffyResults, offset: u32| {
+            for (i, block_result) in results.iter().enumerate() {
+                match block_result {
+                    Err(err) => {
+                        bail!(
+                            "failed on block {} at offset {}: {}",
+                            i,
+                            offset,
+                            qspi_page_program.strerror(err),
+                        );
+                    }
+                    Ok(r) if verify => {
+                        if r.len() != 1 {
+                            bail!("expected single byte return value");
+                        }
+
+                        if r[0] != 0 {
+                            let a = base + offset + (i as u32 * block_size);
+                            info!("block at 0x{:x} failed to verify", a);
+                        }
+                    }
+                    _ => {}
+                }
+            }
+
+            Ok(())
+        };
+
+        let mut pending = None;
+
         loop {
             let len = if offset + chunk > filelen {
                 //
-                // Zero the end of the buffer so we don't have to deal with
-                // sub-block size writes inside of HIF
+                // Fill the end of the buffer with the erased value so we
+                // don't have to deal with sub-block size writes inside of
+                // HIF:  programming it leaves the rest of the final page
+                // as it was erased.
                 //
                 for i in filelen - offset..chunk {
-                    buf[i as usize] = 0;
+                    buf[i as usize] = 0xff;
                 }
 
                 filelen - offset
@@ -229,10 +790,13 @@ fn qspi(
 
             //
             // We have our chunk; now a HIF loop to write/verify our chunk
-            // in block_size nibbles.
+            // in block_size nibbles -- rounding a final partial chunk up to
+            // the next block rather than writing the entire chunk.
             //
+            let nbytes = ((len + block_size - 1) / block_size) * block_size;
+
             let ops = vec![
-                Op::Push32(offset),
+                Op::Push32(base + offset),
                 Op::Push32(0),
                 Op::PushNone,
                 Op::Label(Target(0)),
@@ -244,39 +808,33 @@ fn qspi(
                 Op::Push32(block_size),
                 Op::Add,
                 Op::Swap,
-                Op::Push32(chunk),
+                Op::Push32(nbytes),
                 Op::BranchGreaterThan(Target(0)),
                 Op::Done,
             ];
 
-            let results = context.run(core, ops.as_slice(), Some(&buf))?;
-
-            bar.set_position((offset + len).into());
-
-            for (i, block_result) in results.iter().enumerate() {
-                match block_result {
-                    Err(err) => {
-                        bail!(
-                            "failed on block {} at offset {}: {}",
-                            i,
-                            offset,
-                            qspi_page_program.strerror(*err),
-                        );
-                    }
-                    Ok(r) if subargs.verify => {
-                        if r.len() != 1 {
-                            bail!("expected single byte return value");
-                        }
-
-                        if r[0] != 0 {
-                            let a = offset + (i as u32 * block_size);
-                            info!("block at 0x{:x} failed to verify", a);
-                        }
-                    }
-                    _ => {}
+            //
+            // We pipeline our writes:  while the target is busy with this
+            // chunk, we will read the next chunk from the file -- and then
+            // check the results of this chunk as we kick off the next.
+            //
+            match pending {
+                None => context.start(core, ops.as_slice(), Some(&buf))?,
+                Some(prev) => {
+                    wait(&mut context, core)?;
+
+                    let results = context.results_and_start(
+                        core,
+                        ops.as_slice(),
+                        Some(&buf),
+                    )?;
+
+                    check(results, prev)?;
+                    bar.set_position((prev + chunk).into());
                 }
             }
 
+            pending = Some(offset);
             offset += chunk;
 
             if offset >= filelen {
@@ -284,21 +842,22 @@ fn qspi(
             }
         }
 
+        if let Some(prev) = pending {
+            wait(&mut context, core)?;
+            let results = context.results(core)?;
+            check(results, prev)?;
+        }
+
         bar.finish_and_clear();
 
-        if subargs.verify {
-            info!(
-                "verified {} in {}",
-                HumanBytes(filelen as u64),
-                HumanDuration(started.elapsed())
-            );
-        } else {
-            info!(
-                "flashed {} in {}",
-                HumanBytes(filelen as u64),
-                HumanDuration(started.elapsed())
-            );
-        }
+        info!(
+            "{} {} at 0x{:x} in {} ({}/s)",
+            if subargs.verify { "verified" } else { "flashed" },
+            HumanBytes(filelen as u64),
+            base,
+            HumanDuration(started.elapsed()),
+            HumanBytes(throughput(filelen as usize, started))
+        );
 
         return Ok(());
     } else {
@@ -316,15 +875,12 @@ fn qspi(
         },
     )?;
 
-    if subargs.read {
-        if let Ok(results) = &results[0] {
-            printmem(results, 0, 1, 16);
-            return Ok(());
-        }
+    if args.json() {
+        json::hiffy_results(func, &results).print();
+    } else {
+        println!("{:x?}", results);
     }
 
-    println!("{:x?}", results);
-
     Ok(())
 }
 
@@ -340,3 +896,108 @@ pub fn init<'a, 'b>() -> (Command, App<'a, 'b>) {
         QspiArgs::clap(),
     )
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use humility::mock::MockCore;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    type Calls = Rc<RefCell<Vec<(u32, u32)>>>;
+
+    //
+    // Sets up a mock target with the specified flash contents that provides
+    // QspiRead and QspiChecksum, recording the address and length of each
+    // call made of either.
+    //
+    fn setup(
+        flash: Vec<u8>,
+    ) -> (HubrisArchive, MockCore, HiffyFunctions, Calls) {
+        let hubris = mock_archive(0x2000_0000).unwrap();
+        let funcs = mock_functions(&[("QspiRead", 2), ("QspiChecksum", 2)]);
+        let mut core = MockCore::new();
+        let calls = Rc::new(RefCell::new(vec![]));
+        let c = calls.clone();
+
+        mock(&hubris, &mut core, move |ops, _| {
+            let (addr, len) = match ops {
+                [Op::Push32(addr), Op::Push32(len), Op::Call(_), Op::Done] => {
+                    (*addr, *len)
+                }
+                _ => panic!("unexpected program"),
+            };
+
+            c.borrow_mut().push((addr, len));
+
+            let contents = &flash[addr as usize..(addr + len) as usize];
+
+            vec![Ok(match ops[2] {
+                Op::Call(TargetFunction(0)) => contents.to_vec(),
+                _ => crc32fast::hash(contents).to_le_bytes().to_vec(),
+            })]
+        })
+        .unwrap();
+
+        (hubris, core, funcs, calls)
+    }
+
+    fn flash(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 7 + i / 256) as u8).collect()
+    }
+
+    #[test]
+    fn read_chunked() {
+        let contents = flash(5000);
+        let (hubris, mut core, funcs, calls) = setup(contents.clone());
+        let mut context = HiffyContext::new(&hubris, &mut core, 1000).unwrap();
+        let func = funcs.get("QspiRead", 2).unwrap();
+
+        let chunk = context.limits().max_payload(1) / 256 * 256;
+        assert!(chunk * 2 < 4000);
+
+        let bar = ProgressBar::hidden();
+        let rval =
+            qspi_read(&mut context, &mut core, func, 100, 4000, &bar).unwrap();
+
+        assert_eq!(rval, contents[100..4100]);
+
+        let expected: Vec<(u32, u32)> = (0..4000)
+            .step_by(chunk)
+            .map(|o| ((100 + o) as u32, usize::min(chunk, 4000 - o) as u32))
+            .collect();
+
+        assert_eq!(*calls.borrow(), expected);
+    }
+
+    #[test]
+    fn verify_digest() {
+        let contents = flash(2 * QSPI_DIGEST_CHUNK + 1000);
+        let (hubris, mut core, funcs, calls) = setup(contents.clone());
+        let mut context = HiffyContext::new(&hubris, &mut core, 1000).unwrap();
+
+        let digest = QspiDigest::lookup(&funcs).unwrap();
+        let func = funcs.get(digest.function(), 2).unwrap();
+
+        qspi_verify_digest(&mut context, &mut core, func, digest, &contents, 0)
+            .unwrap();
+
+        assert_eq!(calls.borrow().len(), 3);
+
+        //
+        // A difference in any chunk must fail verification.
+        //
+        let mut data = contents;
+        data[QSPI_DIGEST_CHUNK + 17] ^= 1;
+
+        assert!(qspi_verify_digest(
+            &mut context,
+            &mut core,
+            func,
+            digest,
+            &data,
+            0
+        )
+        .is_err());
+    }
+}
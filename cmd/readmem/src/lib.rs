@@ -2,9 +2,11 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
-use anyhow::{bail, Result};
-use humility::core::Core;
+use anyhow::{bail, Context, Result};
+use humility::core::{Core, CoreOps};
 use humility::hubris::*;
+use humility_cmd::hexfile::{self, HexFormat};
+use humility_cmd::json::Json;
 use humility_cmd::printmem;
 use humility_cmd::{Archive, Args, Attach, Command, Validate};
 use std::convert::TryInto;
@@ -23,9 +25,20 @@ struct ReadmemArgs {
     word: bool,
 
     /// print out as symbols
-    #[structopt(long, short)]
+    #[structopt(long, short, conflicts_with_all = &["registers"])]
     symbol: bool,
 
+    /// print out as registers (and their fields), as described by SVD
+    #[structopt(long, short, conflicts_with_all = &["halfword", "word"])]
+    registers: bool,
+
+    /// emit memory in the specified format (Intel HEX or S-records)
+    #[structopt(long, short, value_name = "format",
+        possible_values = &["ihex", "srec"],
+        conflicts_with_all = &["halfword", "word", "symbol", "registers"]
+    )]
+    output: Option<HexFormat>,
+
     /// address to read
     address: String,
 
@@ -34,15 +47,64 @@ struct ReadmemArgs {
     length: Option<usize>,
 }
 
+//
+// Displays memory as the registers described by any loaded SVD files (and
+// the fields within them); words that don't correspond to a described
+// register are displayed as-is.
+//
+fn readmem_registers(hubris: &HubrisArchive, bytes: &[u8], addr: u32) {
+    let mut offs = 0;
+
+    while offs + 4 <= bytes.len() {
+        let a = addr + offs as u32;
+
+        match hubris.lookup_register(a) {
+            Some((p, r))
+                if r.nbytes() <= 8 && offs + r.nbytes() <= bytes.len() =>
+            {
+                let nbytes = r.nbytes();
+                let mut buf = [0u8; 8];
+                buf[..nbytes].copy_from_slice(&bytes[offs..offs + nbytes]);
+                let val = u64::from_le_bytes(buf);
+
+                println!(
+                    "0x{:08x} | 0x{:0width$x} {}.{}",
+                    a,
+                    val,
+                    p.name,
+                    r.name,
+                    width = nbytes * 2
+                );
+
+                for f in &r.fields {
+                    println!("{:13}{:<24} 0x{:x}", "", f.name, f.extract(val));
+                }
+
+                //
+                // Registers smaller than a word are still word aligned; we
+                // always advance by at least a word.
+                //
+                offs += usize::max(nbytes, 4);
+            }
+            _ => {
+                let slice = &bytes[offs..offs + 4];
+                let val = u32::from_le_bytes(slice.try_into().unwrap());
+                println!("0x{:08x} | 0x{:08x}", a, val);
+                offs += 4;
+            }
+        }
+    }
+}
+
 fn readmem(
     hubris: &mut HubrisArchive,
     core: &mut dyn Core,
-    _args: &Args,
+    args: &Args,
     subargs: &[String],
 ) -> Result<()> {
     let subargs = ReadmemArgs::from_iter_safe(subargs)?;
     let max = humility::core::CORE_MAX_READSIZE;
-    let size = if subargs.word || subargs.symbol {
+    let size = if subargs.word || subargs.symbol || subargs.registers {
         4
     } else if subargs.halfword {
         2
@@ -60,6 +122,20 @@ fn readmem(
         hubris.validate(core, HubrisValidate::ArchiveMatch)?;
     }
 
+    //
+    // Any SVD files bundled in the archive are needed to display registers
+    // (and may describe the peripheral that we have been asked for).
+    //
+    if subargs.registers || parse_int::parse::<u32>(&subargs.address).is_err() {
+        hubris
+            .load_archive_svd()
+            .context("failed to load SVD file from archive")?;
+    }
+
+    if subargs.registers && !hubris.svd_loaded() {
+        bail!("displaying registers requires an SVD file (see --svd)");
+    }
+
     let addr = match parse_int::parse::<u32>(&subargs.address) {
         Ok(addr) => addr,
         _ => {
@@ -116,6 +192,35 @@ fn readmem(
         return Ok(());
     }
 
+    if subargs.registers {
+        readmem_registers(hubris, &bytes, addr);
+        return Ok(());
+    }
+
+    if let Some(format) = subargs.output {
+        return hexfile::writemem(&mut std::io::stdout(), format, &bytes, addr);
+    }
+
+    if args.json() {
+        //
+        // We emit the memory as an array of values of the specified size,
+        // each decoded as little-endian.
+        //
+        let data = bytes
+            .chunks_exact(size)
+            .map(|c| c.iter().rev().fold(0u32, |v, b| (v << 8) | *b as u32))
+            .collect::<Vec<_>>();
+
+        Json::object(vec![
+            ("addr", Json::from(addr)),
+            ("size", Json::from(size)),
+            ("data", Json::from(data)),
+        ])
+        .print();
+
+        return Ok(());
+    }
+
     printmem(&bytes, addr, size, 16);
 
     Ok(())
@@ -126,7 +231,7 @@ pub fn init<'a, 'b>() -> (Command, App<'a, 'b>) {
         Command::Attached {
             name: "readmem",
             archive: Archive::Optional,
-            attach: Attach::Any,
+            attach: Attach::Requires(CoreOps::READ),
             validate: Validate::None,
             run: readmem,
         },
@@ -0,0 +1,444 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+#[macro_use]
+extern crate log;
+
+use anyhow::{anyhow, bail, Context, Result};
+use humility::core::{Core, CoreOps};
+use humility::hubris::*;
+use humility::svd::{SvdAccess, SvdField, SvdPeripheral, SvdRegister};
+use humility_cmd::{Archive, Args, Attach, Command, Validate};
+use humility_cortex::debug::DHCSR;
+use structopt::clap::App;
+use structopt::StructOpt;
+
+#[derive(StructOpt, Debug)]
+#[structopt(
+    name = "registers",
+    about = "display and modify peripheral registers described by SVD"
+)]
+struct RegistersArgs {
+    /// list peripherals (or, if a peripheral is specified, its registers)
+    #[structopt(long, short)]
+    list: bool,
+
+    /// display descriptions of registers and fields
+    #[structopt(long, short)]
+    verbose: bool,
+
+    /// write a field of the specified register via read-modify-write
+    #[structopt(
+        long,
+        short,
+        value_name = "field=value",
+        number_of_values = 1,
+        requires = "register"
+    )]
+    write: Vec<String>,
+
+    /// peripheral, or register within a peripheral (peripheral.register)
+    register: Option<String>,
+}
+
+fn access(access: Option<SvdAccess>) -> &'static str {
+    match access {
+        Some(SvdAccess::ReadOnly) => "r",
+        Some(SvdAccess::WriteOnly) => "w",
+        Some(SvdAccess::ReadWrite) => "rw",
+        Some(SvdAccess::WriteOnce) => "w1",
+        Some(SvdAccess::ReadWriteOnce) => "rw1",
+        None => "-",
+    }
+}
+
+fn bits(field: &SvdField) -> String {
+    if field.width == 1 {
+        format!("[{}]", field.offset)
+    } else {
+        format!("[{}:{}]", field.offset + field.width - 1, field.offset)
+    }
+}
+
+fn register_read(
+    core: &mut dyn Core,
+    addr: u32,
+    register: &SvdRegister,
+) -> Result<u64> {
+    //
+    // Peripherals are often sensitive to the width of an access, so we
+    // read word-sized registers as words.
+    //
+    match register.nbytes() {
+        4 => Ok(core.read_word_32(addr)? as u64),
+        n if n <= 8 => {
+            let mut buf = [0u8; 8];
+            core.read_8(addr, &mut buf[..n])?;
+            Ok(u64::from_le_bytes(buf))
+        }
+        n => bail!("{}: {}-byte registers not supported", register.name, n),
+    }
+}
+
+fn register_write(
+    core: &mut dyn Core,
+    addr: u32,
+    register: &SvdRegister,
+    val: u64,
+) -> Result<()> {
+    match register.nbytes() {
+        4 => core.write_word_32(addr, val as u32),
+        n if n <= 8 => core.write_8(addr, &val.to_le_bytes()[..n]),
+        n => bail!("{}: {}-byte registers not supported", register.name, n),
+    }
+}
+
+fn register_print(
+    peripheral: &SvdPeripheral,
+    register: &SvdRegister,
+    val: u64,
+    verbose: bool,
+) {
+    let addr = peripheral.base.wrapping_add(register.offset);
+
+    println!(
+        "0x{:08x} | 0x{:0width$x} {}.{}",
+        addr,
+        val,
+        peripheral.name,
+        register.name,
+        width = register.nbytes() * 2
+    );
+
+    if verbose {
+        if let Some(ref description) = register.description {
+            println!("{:13}{}", "", description);
+        }
+    }
+
+    for f in &register.fields {
+        let fval = f.extract(val);
+
+        match f.lookup_value(fval) {
+            Some(v) => {
+                println!(
+                    "{:13}{:<24} {:>7} 0x{:<4x} {}",
+                    "",
+                    f.name,
+                    bits(f),
+                    fval,
+                    v.name
+                );
+            }
+            None => {
+                println!("{:13}{:<24} {:>7} 0x{:x}", "", f.name, bits(f), fval);
+            }
+        }
+
+        if verbose {
+            if let Some(ref description) = f.description {
+                println!("{:15}{}", "", description);
+            }
+        }
+    }
+}
+
+fn registers_list(hubris: &HubrisArchive, peripheral: Option<&SvdPeripheral>) {
+    match peripheral {
+        None => {
+            println!(
+                "{:20} {:>10} {:>5} DESCRIPTION",
+                "PERIPHERAL", "BASE", "REGS"
+            );
+
+            for p in hubris.svd_peripherals() {
+                println!(
+                    "{:20} 0x{:08x} {:>5} {}",
+                    p.name,
+                    p.base,
+                    p.registers.len(),
+                    p.description.as_deref().unwrap_or("-")
+                );
+            }
+        }
+        Some(p) => {
+            println!(
+                "{:24} {:>10} {:>4} {:>6} DESCRIPTION",
+                "REGISTER", "ADDR", "SIZE", "ACCESS"
+            );
+
+            for r in &p.registers {
+                println!(
+                    "{:24} 0x{:08x} {:>4} {:>6} {}",
+                    r.name,
+                    p.base.wrapping_add(r.offset),
+                    r.size,
+                    access(r.access),
+                    r.description.as_deref().unwrap_or("-")
+                );
+            }
+        }
+    }
+}
+
+//
+// Modifies the specified fields of a register via read-modify-write.  We
+// refuse to do this for anything that SVD tells us doesn't make sense:
+// read-only registers or fields, write-only registers (which we can't read
+// back), and registers that have side-effects when read.
+//
+fn registers_write(
+    core: &mut dyn Core,
+    peripheral: &SvdPeripheral,
+    register: &SvdRegister,
+    writes: &[String],
+) -> Result<(u64, u64)> {
+    let name = format!("{}.{}", peripheral.name, register.name);
+    let addr = peripheral.base.wrapping_add(register.offset);
+
+    if core.ops().missing(CoreOps::WRITE) != CoreOps::NONE {
+        bail!("{} does not support writing memory", core.info().0);
+    }
+
+    if let Some(access) = register.access {
+        if !access.writable() {
+            bail!("{} is read-only", name);
+        }
+
+        if !access.readable() {
+            bail!("{} is write-only; cannot read-modify-write it", name);
+        }
+    }
+
+    if register.read_action {
+        bail!("reading {} has side-effects; cannot read-modify-write it", name);
+    }
+
+    let mut fields = vec![];
+
+    for write in writes {
+        let (field, value) = write.split_once('=').ok_or_else(|| {
+            anyhow!("expected field=value, found \"{}\"", write)
+        })?;
+
+        let f = register
+            .lookup_field(field)
+            .ok_or_else(|| anyhow!("{} has no field \"{}\"", name, field))?;
+
+        if let Some(access) = f.access {
+            if !access.writable() {
+                bail!("{}.{} is read-only", name, f.name);
+            }
+        }
+
+        let val = match parse_int::parse::<u64>(value) {
+            Ok(val) => val,
+            Err(_) => match f.lookup_value_byname(value) {
+                Some(v) => v.value,
+                None if f.values.is_empty() => {
+                    bail!("invalid value \"{}\" for {}", value, f.name);
+                }
+                None => {
+                    let values: Vec<_> =
+                        f.values.iter().map(|v| v.name.as_str()).collect();
+
+                    bail!(
+                        "invalid value \"{}\" for {} (must be one of: {})",
+                        value,
+                        f.name,
+                        values.join(", ")
+                    );
+                }
+            },
+        };
+
+        //
+        // Check that the value fits before we touch the target.
+        //
+        f.insert(0, val)?;
+        fields.push((f, val));
+    }
+
+    //
+    // We halt the target across the read-modify-write to prevent it from
+    // modifying the register out from under us -- and leave it as we found
+    // it.
+    //
+    let halted = DHCSR::read(core)?.halted();
+
+    if !halted {
+        core.halt()?;
+    }
+
+    let rval = registers_rmw(core, addr, register, &fields);
+
+    if !halted {
+        core.run()?;
+    }
+
+    rval
+}
+
+fn registers_rmw(
+    core: &mut dyn Core,
+    addr: u32,
+    register: &SvdRegister,
+    fields: &[(&SvdField, u64)],
+) -> Result<(u64, u64)> {
+    let before = register_read(core, addr, register)?;
+    let mut val = before;
+
+    for (f, fval) in fields {
+        val = f.insert(val, *fval)?;
+    }
+
+    register_write(core, addr, register, val)?;
+
+    Ok((before, register_read(core, addr, register)?))
+}
+
+fn registers(
+    hubris: &mut HubrisArchive,
+    core: &mut dyn Core,
+    _args: &Args,
+    subargs: &[String],
+) -> Result<()> {
+    let subargs = RegistersArgs::from_iter_safe(subargs)?;
+
+    hubris
+        .load_archive_svd()
+        .context("failed to load SVD file from archive")?;
+
+    if !hubris.svd_loaded() {
+        bail!("no SVD file loaded (see --svd)");
+    }
+
+    let (peripheral, register) = match subargs.register {
+        Some(ref register) => {
+            let (pname, rname) = match register.split_once('.') {
+                Some((pname, rname)) => (pname, Some(rname)),
+                None => (register.as_str(), None),
+            };
+
+            let p = hubris.lookup_svd_peripheral(pname).ok_or_else(|| {
+                anyhow!("unknown peripheral \"{}\" (-l to list)", pname)
+            })?;
+
+            let r = match rname {
+                Some(rname) => {
+                    Some(p.lookup_register(rname).ok_or_else(|| {
+                        anyhow!(
+                            "{} has no register \"{}\" (-l to list)",
+                            p.name,
+                            rname
+                        )
+                    })?)
+                }
+                None => None,
+            };
+
+            (Some(p), r)
+        }
+        None => (None, None),
+    };
+
+    if subargs.list {
+        registers_list(hubris, peripheral);
+        return Ok(());
+    }
+
+    let peripheral = match peripheral {
+        Some(p) => p,
+        None => bail!("must specify a peripheral or register (-l to list)"),
+    };
+
+    if !subargs.write.is_empty() {
+        let register = match register {
+            Some(r) => r,
+            None => bail!("must specify a register to write"),
+        };
+
+        //
+        // We can read registers from a dump, but writing them requires a
+        // live target that we can halt.
+        //
+        let missing = core.ops().missing(CoreOps::WRITE | CoreOps::CONTROL);
+
+        if missing != CoreOps::NONE {
+            bail!(
+                "{} does not support operations required to write ({})",
+                core.info().0,
+                missing
+            );
+        }
+
+        let (before, after) =
+            registers_write(core, peripheral, register, &subargs.write)?;
+
+        info!(
+            "{}.{}: 0x{:x} -> 0x{:x}",
+            peripheral.name, register.name, before, after
+        );
+
+        register_print(peripheral, register, after, subargs.verbose);
+        return Ok(());
+    }
+
+    let registers: Vec<&SvdRegister> = match register {
+        Some(r) => vec![r],
+        None => peripheral.registers.iter().collect(),
+    };
+
+    for r in registers {
+        let addr = peripheral.base.wrapping_add(r.offset);
+
+        //
+        // When displaying an entire peripheral, we don't read registers
+        // that can't be read or that have side-effects when read; such a
+        // register must be named explicitly to be read.
+        //
+        if register.is_none() {
+            if let Some(false) = r.access.map(|a| a.readable()) {
+                println!(
+                    "0x{:08x} | (write-only) {}.{}",
+                    addr, peripheral.name, r.name
+                );
+                continue;
+            }
+
+            if r.read_action {
+                println!(
+                    "0x{:08x} | (not read: side-effects) {}.{}",
+                    addr, peripheral.name, r.name
+                );
+                continue;
+            }
+        }
+
+        match register_read(core, addr, r) {
+            Ok(val) => register_print(peripheral, r, val, subargs.verbose),
+            Err(err) => {
+                println!(
+                    "0x{:08x} | (failed: {}) {}.{}",
+                    addr, err, peripheral.name, r.name
+                );
+            }
+        }
+    }
+
+    Ok(())
+}
+
+pub fn init<'a, 'b>() -> (Command, App<'a, 'b>) {
+    (
+        Command::Attached {
+            name: "registers",
+            archive: Archive::Optional,
+            attach: Attach::Requires(CoreOps::READ),
+            validate: Validate::None,
+            run: registers,
+        },
+        RegistersArgs::clap(),
+    )
+}
@@ -2,9 +2,10 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
-use anyhow::{bail, Result};
-use humility::core::Core;
+use anyhow::{anyhow, bail, Result};
+use humility::core::{Core, CoreOps};
 use humility::hubris::*;
+use humility_cmd::defmt::{DefmtDecoder, DefmtTable};
 use humility_cmd::doppel::{Ringbuf, StaticCell};
 use humility_cmd::reflect::{self, Format, Load, Value};
 use humility_cmd::{Archive, Args, Attach, Command, Validate};
@@ -23,16 +24,71 @@ struct RingbufArgs {
     /// list variables
     #[structopt(long, short)]
     list: bool,
-    /// print only a single ringbuffer by name
-    #[structopt(conflicts_with = "list")]
+    /// print only ring buffers in the specified task
+    #[structopt(long, short, value_name = "task")]
+    task: Option<String>,
+    /// decode byte payloads as defmt frames, using the table of the task
+    /// that contains the ring buffer
+    #[structopt(long, conflicts_with = "list")]
+    defmt: bool,
+    /// print only ring buffers whose name contains the specified string
     variable: Option<String>,
 }
 
+//
+// Extracts the bytes from a payload that is either a single byte or an
+// array of them.
+//
+fn ringbuf_bytes(payload: &Value) -> Result<Vec<u8>> {
+    match payload {
+        Value::Array(_) => Vec::<u8>::from_value(payload),
+        _ => Ok(vec![u8::from_value(payload)?]),
+    }
+}
+
+//
+// Feeds the payloads of the entries (oldest first) to a defmt decoder,
+// displaying each frame along with the entry that completed it.  An entry
+// that has been written more than once in succession is fed as many times.
+// The oldest entries may well begin in the middle of a frame, so failures
+// to decode are merely warned about.
+//
+fn ringbuf_defmt(
+    table: &DefmtTable,
+    ringbuf: &Ringbuf,
+    slots: &[usize],
+) -> Result<()> {
+    let mut decoder = DefmtDecoder::new(table);
+
+    println!("{:>4} {:>4} {:>8} {:>8} FRAME", "NDX", "LINE", "GEN", "COUNT");
+
+    for &slot in slots {
+        let entry = &ringbuf.buffer[slot];
+        let bytes = ringbuf_bytes(&entry.payload)
+            .map_err(|_| anyhow!("payload is not bytes; can't decode defmt"))?;
+
+        for _ in 0..entry.count {
+            for frame in decoder.received(&bytes) {
+                match frame {
+                    Ok(frame) => println!(
+                        "{:4} {:4} {:8} {:8} {}",
+                        slot, entry.line, entry.generation, entry.count, frame
+                    ),
+                    Err(err) => warn!("failed to decode defmt frame: {}", err),
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
 fn ringbuf_dump(
     hubris: &HubrisArchive,
     core: &mut dyn Core,
     definition: &HubrisStruct,
     ringbuf_var: &HubrisVariable,
+    defmt: Option<&DefmtTable>,
 ) -> Result<()> {
     let mut buf: Vec<u8> = vec![];
     buf.resize_with(ringbuf_var.size, Default::default);
@@ -55,24 +111,42 @@ fn ringbuf_dump(
         Ringbuf::from_value(&cell.cell.value)
     })?;
 
-    let ndx = if let Some(x) = ringbuf.last {
-        x as usize
-    } else {
-        return Ok(());
+    let newest = match ringbuf.last {
+        Some(ndx) => match ringbuf.buffer.get(ndx as usize) {
+            Some(entry) => entry.generation,
+            None => bail!("last index {} exceeds ring buffer size", ndx),
+        },
+        None => return Ok(()),
     };
 
+    //
+    // Each slot's generation is incremented each time that it is written,
+    // so we order the entries by their age relative to the newest entry --
+    // oldest first -- and then by slot.
+    //
+    let mut slots: Vec<_> = ringbuf
+        .buffer
+        .iter()
+        .enumerate()
+        .filter(|(_, entry)| entry.generation != 0)
+        .map(|(slot, entry)| (newest.wrapping_sub(entry.generation), slot))
+        .collect();
+
+    slots.sort_by(|l, r| r.0.cmp(&l.0).then(l.1.cmp(&r.1)));
+
+    let slots: Vec<usize> = slots.iter().map(|(_, slot)| *slot).collect();
+
+    if let Some(table) = defmt {
+        return ringbuf_defmt(table, &ringbuf, &slots);
+    }
+
     let fmt = HubrisPrintFormat { hex: true, ..HubrisPrintFormat::default() };
 
     println!("{:>4} {:>4} {:>8} {:>8} PAYLOAD", "NDX", "LINE", "GEN", "COUNT",);
 
-    for i in 0..ringbuf.buffer.len() {
-        let slot = (ndx + i + 1) % ringbuf.buffer.len();
+    for slot in slots {
         let entry = &ringbuf.buffer[slot];
 
-        if entry.generation == 0 {
-            continue;
-        }
-
         let mut dumped = vec![];
         entry.payload.format(hubris, fmt, &mut dumped)?;
         let dumped = String::from_utf8(dumped)?;
@@ -101,21 +175,46 @@ fn ringbuf(
 ) -> Result<()> {
     let subargs = RingbufArgs::from_iter_safe(subargs)?;
 
+    let task = match subargs.task {
+        Some(ref task) => Some(
+            *hubris
+                .lookup_task(task)
+                .ok_or_else(|| anyhow!("couldn't find task {}", task))?,
+        ),
+        None => None,
+    };
+
     let mut ringbufs = vec![];
 
     for v in hubris.qualified_variables() {
-        if let Some(ref variable) = subargs.variable {
-            if v.0.eq(variable) {
-                ringbufs.push(v);
+        //
+        // A variable that is named exactly is always displayed; otherwise,
+        // we look for ring buffers whose names contain the specified name.
+        //
+        let matched = match subargs.variable {
+            Some(ref variable) => {
+                v.0 == variable.as_str()
+                    || (v.0.ends_with("RINGBUF") && v.0.contains(variable))
+            }
+            None => v.0.ends_with("RINGBUF"),
+        };
+
+        if !matched {
+            continue;
+        }
+
+        if let Some(task) = task {
+            if HubrisTask::from(v.1.goff) != task {
+                continue;
             }
-        } else if v.0.ends_with("RINGBUF") {
-            ringbufs.push(v);
         }
+
+        ringbufs.push(v);
     }
 
     if ringbufs.is_empty() {
-        if let Some(variable) = subargs.variable {
-            bail!("ring buffer \"{}\" not found (-l to list)", variable);
+        if subargs.variable.is_some() || subargs.task.is_some() {
+            bail!("no matching ring buffers found (-l to list)");
         } else {
             bail!("no ring buffers found");
         }
@@ -142,8 +241,29 @@ fn ringbuf(
             v.0,
             taskname(hubris, v.1).unwrap_or("???")
         );
+
+        let table = if subargs.defmt {
+            let task = HubrisTask::from(v.1.goff);
+
+            match hubris.lookup_defmt(task).map(DefmtTable::new) {
+                Some(Ok(table)) => Some(table),
+                Some(Err(e)) => {
+                    info!("failed to load defmt table: {}", e);
+                    continue;
+                }
+                None => {
+                    info!("task does not use defmt");
+                    continue;
+                }
+            }
+        } else {
+            None
+        };
+
         if let Ok(def) = hubris.lookup_struct(v.1.goff) {
-            if let Err(e) = ringbuf_dump(hubris, core, def, v.1) {
+            let table = table.as_ref();
+
+            if let Err(e) = ringbuf_dump(hubris, core, def, v.1, table) {
                 info!("ringbuf dump failed: {}", e);
             }
         } else {
@@ -159,7 +279,7 @@ pub fn init<'a, 'b>() -> (Command, App<'a, 'b>) {
         Command::Attached {
             name: "ringbuf",
             archive: Archive::Required,
-            attach: Attach::Any,
+            attach: Attach::Requires(CoreOps::READ),
             validate: Validate::Match,
             run: ringbuf,
         },
@@ -0,0 +1,322 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+#[macro_use]
+extern crate log;
+
+use anyhow::{anyhow, bail, Result};
+use humility::core::Core;
+use humility::hubris::*;
+use humility_cmd::defmt::{DefmtDecoder, DefmtTable};
+use humility_cmd::forward::{ForwardSeverity, LogForwarder};
+use humility_cmd::rtt::{RttChannel, RttControlBlock};
+use humility_cmd::{Archive, Args, Attach, Command, Validate};
+use std::collections::HashMap;
+use std::io::{BufRead, Write};
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+use structopt::clap::App;
+use structopt::StructOpt;
+
+#[derive(StructOpt, Debug)]
+#[structopt(name = "rtt", about = "stream RTT channels from the target")]
+struct RttArgs {
+    /// address of the RTT control block
+    #[structopt(long, short, value_name = "address",
+        parse(try_from_str = parse_int::parse),
+    )]
+    address: Option<u32>,
+
+    /// memory to search for the RTT control block, as base:size
+    #[structopt(long, short, value_name = "base:size",
+        number_of_values = 1, parse(try_from_str = parse_range),
+        conflicts_with = "address",
+    )]
+    search: Vec<(u32, u32)>,
+
+    /// list the channels in the control block
+    #[structopt(long, short)]
+    list: bool,
+
+    /// up channel to stream (and down channel to which input is sent)
+    #[structopt(long, short, default_value = "0", value_name = "channel")]
+    channel: u32,
+
+    /// stream all up channels, displaying each line with the name of its
+    /// channel
+    #[structopt(long, short = "A")]
+    all: bool,
+
+    /// decode the channel as defmt, using the table of the specified task
+    /// (by default, the only task that uses defmt)
+    #[structopt(long, value_name = "task")]
+    defmt: Option<Option<String>>,
+
+    /// send lines from standard input to the down channel
+    #[structopt(long, short)]
+    input: bool,
+
+    /// interval at which to poll the target, in milliseconds
+    #[structopt(long, default_value = "10", value_name = "ms")]
+    interval: u64,
+}
+
+fn parse_range(s: &str) -> Result<(u32, u32)> {
+    let (base, size) = s
+        .split_once(':')
+        .ok_or_else(|| anyhow!("expected base:size, found \"{}\"", s))?;
+
+    Ok((parse_int::parse(base)?, parse_int::parse(size)?))
+}
+
+fn rtt_list(cb: &RttControlBlock) {
+    println!("{:>4} {:4} {:16} {:>8}", "CHAN", "DIR", "NAME", "SIZE");
+
+    for (dir, channels) in [("up", &cb.up), ("down", &cb.down)] {
+        for channel in channels.iter() {
+            println!(
+                "{:>4} {:4} {:16} {:>8}",
+                channel.index,
+                dir,
+                channel.name.as_deref().unwrap_or("-"),
+                channel.size
+            );
+        }
+    }
+}
+
+//
+// Demultiplexes output on multiple up channels, displaying (and forwarding)
+// each line with the name of its channel -- much as `humility itm` does for
+// named stimulus ports.
+//
+#[derive(Default)]
+struct RttDemux {
+    partial: HashMap<u32, Vec<u8>>,
+}
+
+impl RttDemux {
+    fn received(
+        &mut self,
+        channel: &RttChannel,
+        data: &[u8],
+        forward: &mut Option<LogForwarder>,
+    ) -> Result<()> {
+        let name = match channel.name {
+            Some(ref name) => name.clone(),
+            None => format!("rtt{}", channel.index),
+        };
+
+        let partial = self.partial.entry(channel.index).or_default();
+
+        for b in data {
+            match *b {
+                b'\n' => {
+                    let line = String::from_utf8_lossy(partial).into_owned();
+                    partial.clear();
+
+                    println!("{:>10}: {}", name, line);
+
+                    if let Some(forward) = forward {
+                        forward.line(
+                            ForwardSeverity::Info,
+                            Some(&name),
+                            &line,
+                        )?;
+                    }
+                }
+                b'\r' => {}
+                b => partial.push(b),
+            }
+        }
+
+        Ok(())
+    }
+}
+
+//
+// Spawns a thread to read lines from standard input, returning the channel
+// on which they will be sent.
+//
+fn rtt_input() -> mpsc::Receiver<Vec<u8>> {
+    let (tx, rx) = mpsc::channel();
+
+    thread::spawn(move || {
+        let stdin = std::io::stdin();
+
+        for line in stdin.lock().lines().flatten() {
+            if tx.send(format!("{}\n", line).into_bytes()).is_err() {
+                break;
+            }
+        }
+    });
+
+    rx
+}
+
+fn rtt_all(
+    hubris: &HubrisArchive,
+    core: &mut dyn Core,
+    args: &Args,
+    cb: &RttControlBlock,
+    subargs: &RttArgs,
+) -> Result<()> {
+    if subargs.defmt.is_some() || subargs.input {
+        bail!("--defmt and --input require a single channel");
+    }
+
+    if cb.up.is_empty() {
+        bail!("control block has no up channels");
+    }
+
+    let mut forward = LogForwarder::new(args, hubris)?;
+    let mut demux = RttDemux::default();
+    let interval = Duration::from_millis(subargs.interval);
+
+    info!("streaming {} up channels; ^C to stop", cb.up.len());
+
+    loop {
+        let mut idle = true;
+
+        for up in &cb.up {
+            let data = up.read(core)?;
+
+            if !data.is_empty() {
+                demux.received(up, &data, &mut forward)?;
+                idle = false;
+            }
+        }
+
+        if idle {
+            thread::sleep(interval);
+        }
+    }
+}
+
+fn rtt(
+    hubris: &mut HubrisArchive,
+    core: &mut dyn Core,
+    args: &Args,
+    subargs: &[String],
+) -> Result<()> {
+    let subargs = RttArgs::from_iter_safe(subargs)?;
+    let cb = RttControlBlock::locate(
+        hubris,
+        core,
+        subargs.address,
+        &subargs.search,
+    )?;
+
+    if subargs.list {
+        rtt_list(&cb);
+        return Ok(());
+    }
+
+    if subargs.all {
+        return rtt_all(hubris, core, args, &cb, &subargs);
+    }
+
+    let up: &RttChannel = cb
+        .up
+        .get(subargs.channel as usize)
+        .ok_or_else(|| anyhow!("no up channel {}", subargs.channel))?;
+
+    let down = if subargs.input {
+        match cb.down.get(subargs.channel as usize) {
+            Some(down) => Some(down),
+            None => bail!("no down channel {}", subargs.channel),
+        }
+    } else {
+        None
+    };
+
+    let table = match &subargs.defmt {
+        Some(task) => Some(DefmtTable::lookup(hubris, task.as_deref())?),
+        None => None,
+    };
+
+    let mut defmt = table
+        .as_ref()
+        .map(|(task, table)| (task.as_str(), DefmtDecoder::new(table)));
+
+    let mut forward = LogForwarder::new(args, hubris)?;
+    let input = down.map(|_| rtt_input());
+    let mut pending: Vec<u8> = vec![];
+    let interval = Duration::from_millis(subargs.interval);
+
+    info!(
+        "streaming channel {} ({}); ^C to stop",
+        up.index,
+        up.name.as_deref().unwrap_or("unnamed")
+    );
+
+    loop {
+        let data = up.read(core)?;
+
+        if !data.is_empty() {
+            match defmt {
+                Some((task, ref mut decoder)) => {
+                    for frame in decoder.received(&data) {
+                        match frame {
+                            Ok(frame) => {
+                                println!("{}", frame);
+
+                                if let Some(ref mut forward) = forward {
+                                    forward.line(
+                                        ForwardSeverity::from_level(
+                                            frame.level,
+                                        ),
+                                        Some(task),
+                                        &frame.message,
+                                    )?;
+                                }
+                            }
+                            Err(err) => {
+                                warn!("failed to decode defmt frame: {}", err)
+                            }
+                        }
+                    }
+                }
+                None => {
+                    let mut stdout = std::io::stdout();
+                    stdout.write_all(&data)?;
+                    stdout.flush()?;
+
+                    if let Some(ref mut forward) = forward {
+                        forward.received(up.index, &data)?;
+                    }
+                }
+            }
+        }
+
+        if let (Some(down), Some(input)) = (down, &input) {
+            while let Ok(line) = input.try_recv() {
+                pending.extend_from_slice(&line);
+            }
+
+            if !pending.is_empty() {
+                let written = down.write(core, &pending)?;
+                pending.drain(..written);
+            }
+        }
+
+        if data.is_empty() {
+            thread::sleep(interval);
+        }
+    }
+}
+
+pub fn init<'a, 'b>() -> (Command, App<'a, 'b>) {
+    (
+        Command::Attached {
+            name: "rtt",
+            archive: Archive::Optional,
+            attach: Attach::LiveOnly,
+            validate: Validate::None,
+            run: rtt,
+        },
+        RttArgs::clap(),
+    )
+}
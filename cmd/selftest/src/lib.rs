@@ -0,0 +1,489 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use anyhow::{bail, Result};
+use colored::Colorize;
+use humility::core::{Core, CoreOps};
+use humility::hubris::*;
+use humility_cmd::{attach_live, Archive, Args, Command};
+use humility_cortex::debug::{corename, ARMCore, Register, CPUID, DHCSR};
+use humility_cortex::scs::{CoreSightClass, CoreSightPage};
+use std::time::Instant;
+use structopt::clap::App;
+use structopt::StructOpt;
+
+#[derive(StructOpt, Debug)]
+#[structopt(
+    name = "selftest",
+    about = "test probe and connectivity to the target"
+)]
+struct SelftestArgs {
+    /// address of a RAM word that may be overwritten (and then restored)
+    #[structopt(long, short, value_name = "address",
+        parse(try_from_str = parse_int::parse),
+    )]
+    scratch: Option<u32>,
+
+    /// number of reads to perform when checking link integrity
+    #[structopt(long, short, default_value = "256", value_name = "count",
+        parse(try_from_str = parse_int::parse),
+    )]
+    count: u32,
+
+    /// sweep the debug link speed (available only when attaching via USB)
+    #[structopt(long, short = "S")]
+    sweep: bool,
+
+    /// speeds to sweep, in kHz
+    #[structopt(
+        long,
+        value_name = "khz",
+        use_delimiter = true,
+        default_value = "100,480,1000,1800,4000,8000"
+    )]
+    speeds: Vec<u32>,
+}
+
+/*
+ * The CoreSight component preamble, as assembled by CoreSightPage from
+ * CIDR0 through CIDR3:  every component (and every ROM table) must have it.
+ */
+const CORESIGHT_PREAMBLE: u32 = 0x0b10_500d;
+
+//
+// The debug port registers that we check, and the bits of CTRL/STAT that
+// indicate the power state of the debug and system domains and any sticky
+// errors.
+//
+const DP_DPIDR: u8 = 0x0;
+const DP_CTRL_STAT: u8 = 0x4;
+const CTRL_STAT_CSYSPWRUPACK: u32 = 1 << 31;
+const CTRL_STAT_CDBGPWRUPACK: u32 = 1 << 29;
+const CTRL_STAT_WDATAERR: u32 = 1 << 7;
+const CTRL_STAT_STICKYERR: u32 = 1 << 5;
+const CTRL_STAT_STICKYORUN: u32 = 1 << 1;
+
+//
+// The access port registers that we check, and the class and enable bit
+// of a MEM-AP.  The core is behind access port 0.
+//
+const AP_CORE: u8 = 0;
+const AP_IDR: u8 = 0xfc;
+const MEMAP_CSW: u8 = 0x00;
+const AP_CLASS_MEMAP: u32 = 0b1000;
+const CSW_DEVICEEN: u32 = 1 << 6;
+
+//
+// The patterns that we will write to our scratch word.
+//
+const PATTERNS: [u32; 4] = [0x0000_0000, 0xffff_ffff, 0xaaaa_aaaa, 0x5555_5555];
+
+fn rom_base(cpuid: &CPUID) -> u32 {
+    use num_traits::FromPrimitive;
+
+    match ARMCore::from_u32(cpuid.partno()) {
+        Some(ARMCore::CortexM7) | Some(ARMCore::CortexM33) => 0xe00f_e000,
+        _ => 0xe00f_f000,
+    }
+}
+
+fn check_cpuid(core: &mut dyn Core) -> Result<String> {
+    use num_traits::FromPrimitive;
+
+    let cpuid = CPUID::read(core)?;
+
+    if cpuid.implementer() != 0x41 {
+        bail!("CPUID ({:x?}) does not indicate an ARM core", cpuid);
+    }
+
+    match ARMCore::from_u32(cpuid.partno()) {
+        Some(part) => Ok(format!(
+            "{} r{}p{}",
+            corename(part),
+            cpuid.variant(),
+            cpuid.revision()
+        )),
+        None => bail!("unknown core in CPUID {:x?}", cpuid),
+    }
+}
+
+#[rustfmt::skip::macros(bail)]
+fn check_dp(core: &mut dyn Core) -> Result<String> {
+    let dpidr = core.read_dp(DP_DPIDR)?;
+
+    //
+    // The low bit of DPIDR always reads as one; if it doesn't, we aren't
+    // actually talking to a debug port.
+    //
+    if dpidr & 1 == 0 {
+        bail!("DPIDR (0x{:08x}) is invalid", dpidr);
+    }
+
+    let ctrl = core.read_dp(DP_CTRL_STAT)?;
+    let pwrup = CTRL_STAT_CSYSPWRUPACK | CTRL_STAT_CDBGPWRUPACK;
+
+    if ctrl & pwrup != pwrup {
+        bail!("debug and system domains not powered up: CTRL/STAT = 0x{:08x}",
+            ctrl);
+    }
+
+    let sticky =
+        CTRL_STAT_WDATAERR | CTRL_STAT_STICKYERR | CTRL_STAT_STICKYORUN;
+
+    if ctrl & sticky != 0 {
+        bail!("sticky errors are set: CTRL/STAT = 0x{:08x}", ctrl);
+    }
+
+    Ok(format!("DPIDR = 0x{:08x}, CTRL/STAT = 0x{:08x}", dpidr, ctrl))
+}
+
+#[rustfmt::skip::macros(bail)]
+fn check_ap(core: &mut dyn Core) -> Result<String> {
+    let idr = core.read_ap(AP_CORE, AP_IDR)?;
+
+    if idr == 0 {
+        bail!("AP {} is not present", AP_CORE);
+    }
+
+    if (idr >> 13) & 0b1111 != AP_CLASS_MEMAP {
+        bail!("AP {} is not a MEM-AP (IDR is 0x{:08x})", AP_CORE, idr);
+    }
+
+    let csw = core.read_ap(AP_CORE, MEMAP_CSW)?;
+
+    if csw & CSW_DEVICEEN == 0 {
+        bail!("AP {} is not enabled (CSW is 0x{:08x})", AP_CORE, csw);
+    }
+
+    Ok(format!("AP {}: IDR = 0x{:08x}, CSW = 0x{:08x}", AP_CORE, idr, csw))
+}
+
+#[rustfmt::skip::macros(bail)]
+fn check_rom(core: &mut dyn Core, count: u32) -> Result<String> {
+    let rom = rom_base(&CPUID::read(core)?);
+    let page = CoreSightPage::new(core, rom)?;
+
+    if page.preamble != CORESIGHT_PREAMBLE {
+        bail!("bad preamble at 0x{:x}: expected 0x{:x}, found 0x{:x}",
+            rom, CORESIGHT_PREAMBLE, page.preamble);
+    }
+
+    if page.class != CoreSightClass::ROM {
+        bail!("expected ROM table at 0x{:x}, found {:?}", rom, page.class);
+    }
+
+    //
+    // Now hammer on the component identification registers, which must
+    // read back the same every time.
+    //
+    let mut expected = [0u8; 16];
+    let mut buf = [0u8; 16];
+    core.read_8(rom + 0xff0, &mut expected)?;
+
+    for i in 0..count {
+        core.read_8(rom + 0xff0, &mut buf)?;
+
+        if buf != expected {
+            bail!("read {} of {} at 0x{:x}: expected {:x?}, found {:x?}",
+                i + 1, count, rom + 0xff0, expected, buf);
+        }
+    }
+
+    Ok(format!("{} consistent reads of ROM table at 0x{:x}", count, rom))
+}
+
+#[rustfmt::skip::macros(bail)]
+fn check_patterns(core: &mut dyn Core, addr: u32, count: u32) -> Result<()> {
+    for i in 0..count {
+        let pattern = PATTERNS[i as usize % PATTERNS.len()];
+        core.write_word_32(addr, pattern)?;
+
+        let val = core.read_word_32(addr)?;
+
+        if val != pattern {
+            bail!("write {} of {} at 0x{:x}: wrote 0x{:08x}, read 0x{:08x}",
+                i + 1, count, addr, pattern, val);
+        }
+    }
+
+    Ok(())
+}
+
+fn check_ram(core: &mut dyn Core, addr: u32, count: u32) -> Result<String> {
+    //
+    // We halt the core while we are scribbling on its memory, taking care
+    // to restore the original contents before resuming it.
+    //
+    let halted = DHCSR::read(core)?.halted();
+
+    if !halted {
+        core.halt()?;
+    }
+
+    let orig = core.read_word_32(addr);
+
+    let rval = match orig {
+        Ok(orig) => {
+            let rval = check_patterns(core, addr, count);
+            core.write_word_32(addr, orig).and(rval)
+        }
+        Err(err) => Err(err),
+    };
+
+    if !halted {
+        core.run()?;
+    }
+
+    rval.map(|_| format!("{} writes at 0x{:x}", count, addr))
+}
+
+fn sweep(
+    args: &Args,
+    subargs: &SelftestArgs,
+    expected: u32,
+) -> Vec<(u32, Result<String>)> {
+    let probe = match &args.probe {
+        Some(p) => p.as_str(),
+        None => "auto",
+    };
+
+    subargs
+        .speeds
+        .iter()
+        .map(|&speed| {
+            let rval = humility::core::attach_with_speed(
+                probe,
+                &args.chip,
+                Some(speed),
+            )
+            .and_then(|mut core| {
+                let started = Instant::now();
+
+                for i in 0..subargs.count {
+                    let val = core.read_word_32(CPUID::ADDRESS)?;
+
+                    if val != expected {
+                        bail!(
+                            "read {} of {}: expected 0x{:x}, found 0x{:x}",
+                            i + 1,
+                            subargs.count,
+                            expected,
+                            val
+                        );
+                    }
+                }
+
+                let elapsed = started.elapsed().as_secs_f64();
+
+                Ok(format!(
+                    "{} reads at {:.0} reads/sec",
+                    subargs.count,
+                    subargs.count as f64 / elapsed
+                ))
+            });
+
+            (speed, rval)
+        })
+        .collect()
+}
+
+fn report(layer: &str, result: &Result<String>) {
+    match result {
+        Ok(detail) => {
+            println!(
+                "{:12} {} {}",
+                layer,
+                format!("{:6}", "ok").green(),
+                detail
+            );
+        }
+        Err(err) => {
+            println!("{:12} {} {}", layer, format!("{:6}", "FAIL").red(), err);
+        }
+    }
+}
+
+#[rustfmt::skip::macros(println, bail)]
+fn selftest(
+    hubris: &mut HubrisArchive,
+    args: &Args,
+    subargs: &[String],
+) -> Result<()> {
+    let subargs = SelftestArgs::from_iter_safe(subargs)?;
+
+    //
+    // If we weren't given an explicit scratch word but we have an archive,
+    // we use the first word of the HIFFY_DATA area, which is only used by
+    // the target when processing a HIF request.
+    //
+    let scratch = match subargs.scratch {
+        Some(addr) => Some(addr),
+        None if hubris.loaded() => {
+            hubris.lookup_variable("HIFFY_DATA").ok().map(|v| v.addr)
+        }
+        None => None,
+    };
+
+    println!("{:12} {:6} DETAIL", "LAYER", "RESULT");
+
+    //
+    // Each layer depends on the layer before it, so we stop at the first
+    // failure -- and we indicate what a failure at that layer likely means.
+    //
+    let fail = |layer: &str, hint: &str| -> Result<()> {
+        bail!("failed at {} layer: {}", layer, hint);
+    };
+
+    let attached = attach_live(args).map(|core| {
+        let (name, serial) = core.info();
+        (core, name, serial)
+    });
+
+    let mut core = match attached {
+        Ok((core, name, serial)) => {
+            report(
+                "probe",
+                &Ok(match serial {
+                    Some(serial) => format!("{}, serial {}", name, serial),
+                    None => name,
+                }),
+            );
+            core
+        }
+        Err(err) => {
+            report("probe", &Err(err));
+            return fail(
+                "probe",
+                "check that the probe is connected to \
+                the host and not in use by another debugger",
+            );
+        }
+    };
+
+    //
+    // If we can access the debug port directly, we check its health and
+    // that of the access port in front of the core before going through
+    // them.
+    //
+    if core.ops().contains(CoreOps::DEBUG_PORT) {
+        let dp = check_dp(core.as_mut());
+        report("debug port", &dp);
+
+        if dp.is_err() {
+            return fail(
+                "debug port",
+                "check target power and the cable \
+                between the probe and the target",
+            );
+        }
+
+        let ap = check_ap(core.as_mut());
+        report("access port", &ap);
+
+        if ap.is_err() {
+            return fail(
+                "access port",
+                "check that the target is not in a low-power \
+                state and that debug access has not been disabled",
+            );
+        }
+    } else {
+        for layer in &["debug port", "access port"] {
+            println!("{:12} {:6} {}", layer, "-",
+                "no raw debug port access via this probe");
+        }
+    }
+
+    let link = DHCSR::read(core.as_mut())
+        .map(|dhcsr| format!("DHCSR = 0x{:08x}", u32::from(dhcsr)));
+    report("debug link", &link);
+
+    if link.is_err() {
+        return fail(
+            "debug link",
+            "check target power and the cable \
+            between the probe and the target",
+        );
+    }
+
+    let cpuid = check_cpuid(core.as_mut());
+    report("cpuid", &cpuid);
+
+    if cpuid.is_err() {
+        return fail("cpuid", "check that the correct chip is specified");
+    }
+
+    let rom = check_rom(core.as_mut(), subargs.count);
+    report("rom table", &rom);
+
+    if rom.is_err() {
+        return fail(
+            "rom table",
+            "link is unreliable; check the cable \
+            or try a lower link speed",
+        );
+    }
+
+    match scratch {
+        Some(addr) => {
+            let ram = check_ram(core.as_mut(), addr, subargs.count);
+            report("ram", &ram);
+
+            if ram.is_err() {
+                return fail(
+                    "ram",
+                    "memory writes are failing; if reads \
+                    are succeeding, check that the address is writable",
+                );
+            }
+        }
+        None => {
+            println!("{:12} {:6} {}", "ram", "-",
+                "no scratch word available; specify one with --scratch");
+        }
+    }
+
+    if !subargs.sweep {
+        return Ok(());
+    }
+
+    let expected = core.read_word_32(CPUID::ADDRESS)?;
+
+    //
+    // To sweep the link speed, we need to reattach at each speed, so drop
+    // our current attachment.
+    //
+    drop(core);
+
+    let results = sweep(args, &subargs, expected);
+    let mut fastest = None;
+
+    for (speed, result) in &results {
+        report(&format!("{} kHz", speed), result);
+
+        if result.is_ok() {
+            fastest = fastest.max(Some(*speed));
+        }
+    }
+
+    match fastest {
+        Some(speed) => {
+            println!("{:12} {:6} fastest reliable link speed is {} kHz",
+                "speed", "-", speed);
+            Ok(())
+        }
+        None => fail("speed", "link failed at all speeds"),
+    }
+}
+
+pub fn init<'a, 'b>() -> (Command, App<'a, 'b>) {
+    (
+        Command::Unattached {
+            name: "selftest",
+            archive: Archive::Optional,
+            run: selftest,
+        },
+        SelftestArgs::clap(),
+    )
+}
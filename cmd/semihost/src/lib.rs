@@ -0,0 +1,95 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+#[macro_use]
+extern crate log;
+
+use anyhow::{bail, Result};
+use humility::arch::ARMRegister;
+use humility::core::Core;
+use humility::hubris::*;
+use humility_cmd::{Archive, Args, Attach, Command, Validate};
+use humility_cortex::debug::DHCSR;
+use humility_cortex::semihosting::*;
+use std::io::Read;
+use std::thread;
+use std::time::Duration;
+use structopt::clap::App;
+use structopt::StructOpt;
+
+#[derive(StructOpt, Debug)]
+#[structopt(
+    name = "semihost",
+    about = "service semihosting requests from the target"
+)]
+struct SemihostArgs {
+    /// interval at which to poll the target, in milliseconds
+    #[structopt(long, short, default_value = "10", value_name = "ms")]
+    interval: u64,
+
+    /// feed standard input to the target's console reads
+    #[structopt(long)]
+    input: bool,
+}
+
+fn semihost(
+    _hubris: &mut HubrisArchive,
+    core: &mut dyn Core,
+    _args: &Args,
+    subargs: &[String],
+) -> Result<()> {
+    let subargs = SemihostArgs::from_iter_safe(subargs)?;
+    let interval = Duration::from_millis(subargs.interval);
+
+    let input: Option<Box<dyn Read>> =
+        if subargs.input { Some(Box::new(std::io::stdin())) } else { None };
+
+    let mut semihost = Semihost::new(input);
+
+    info!("servicing semihosting requests; ^C to stop");
+
+    loop {
+        if semihosting_pending(core)? {
+            match semihost.handle(core)? {
+                None => {
+                    core.run()?;
+                }
+                Some(SemihostExit::Exited(0)) => {
+                    info!("target exited");
+                    break;
+                }
+                Some(SemihostExit::Exited(code)) => {
+                    bail!("target exited with code {}", code);
+                }
+                Some(SemihostExit::Stopped(reason)) => {
+                    bail!("target stopped with reason 0x{:x}", reason);
+                }
+            }
+
+            continue;
+        }
+
+        if DHCSR::read(core)?.halted() {
+            let pc = core.read_reg(ARMRegister::PC)?;
+            bail!("target halted at 0x{:x} outside of semihosting", pc);
+        }
+
+        thread::sleep(interval);
+    }
+
+    Ok(())
+}
+
+pub fn init<'a, 'b>() -> (Command, App<'a, 'b>) {
+    (
+        Command::Attached {
+            name: "semihost",
+            archive: Archive::Optional,
+            attach: Attach::LiveOnly,
+            validate: Validate::None,
+            run: semihost,
+        },
+        SemihostArgs::clap(),
+    )
+}
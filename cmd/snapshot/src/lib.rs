@@ -0,0 +1,185 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+#[macro_use]
+extern crate log;
+
+use anyhow::{bail, Context, Result};
+use humility::arch::ARMRegister;
+use humility::core::Core;
+use humility::hubris::*;
+use humility_cmd::{Archive, Args, Attach, Command, Validate};
+use indicatif::{HumanBytes, HumanDuration};
+use indicatif::{ProgressBar, ProgressStyle};
+use num_traits::FromPrimitive;
+use std::time::Instant;
+use structopt::clap::App;
+use structopt::StructOpt;
+
+/// The size of the chunks in which we restore memory
+const SNAPSHOT_CHUNK: usize = 1024;
+
+#[derive(StructOpt, Debug)]
+#[structopt(
+    name = "snapshot",
+    about = "save and restore target RAM and registers"
+)]
+struct SnapshotArgs {
+    #[structopt(subcommand)]
+    cmd: SnapshotCommand,
+}
+
+#[derive(StructOpt, Debug)]
+enum SnapshotCommand {
+    /// save RAM and registers to a snapshot
+    Save { snapshot: Option<String> },
+
+    /// restore RAM and registers from a snapshot
+    Restore {
+        snapshot: String,
+
+        /// resume the target after restoring it
+        #[structopt(long, short)]
+        run: bool,
+    },
+}
+
+//
+// A snapshot is a dump:  it contains the contents of all memory (along with
+// the registers), and the archive from which the image was built.
+//
+fn snapshot_save(
+    hubris: &HubrisArchive,
+    core: &mut dyn Core,
+    snapshot: Option<&str>,
+) -> Result<()> {
+    core.halt()?;
+    info!("core halted");
+
+    let rval = hubris.dump(core, snapshot);
+
+    core.run()?;
+    info!("core resumed");
+
+    rval
+}
+
+fn snapshot_restore(
+    hubris: &HubrisArchive,
+    core: &mut dyn Core,
+    snapshot: &str,
+    run: bool,
+) -> Result<()> {
+    let mut snap = HubrisArchive::new()?;
+    snap.load_dump(snapshot)
+        .with_context(|| format!("failed to load snapshot {}", snapshot))?;
+
+    if snap.image_id() != hubris.image_id() {
+        bail!("{} is a snapshot of a different image", snapshot);
+    }
+
+    let mut dump = humility::core::attach_dump(snapshot, &snap)?;
+    let dump = dump.as_mut();
+
+    //
+    // We only restore memory that is writable -- and not device memory.
+    //
+    let regions = snap
+        .regions(dump)?
+        .into_values()
+        .filter(|r| r.attr.write && !r.attr.device)
+        .collect::<Vec<_>>();
+
+    let total = regions.iter().fold(0, |ttl, r| ttl + r.size as u64);
+
+    core.halt()?;
+    info!("core halted");
+
+    let started = Instant::now();
+    let bar = ProgressBar::new(total);
+    bar.set_style(
+        ProgressStyle::default_bar()
+            .template("humility: restoring [{bar:30}] {bytes}/{total_bytes}"),
+    );
+
+    let mut buf = vec![0u8; SNAPSHOT_CHUNK];
+    let mut restored = 0;
+
+    for region in &regions {
+        let mut offs = 0;
+
+        while offs < region.size {
+            let n = std::cmp::min(SNAPSHOT_CHUNK as u32, region.size - offs);
+            let buf = &mut buf[..n as usize];
+
+            dump.read_8(region.base + offs, buf)?;
+            core.write_8(region.base + offs, buf)?;
+
+            offs += n;
+            restored += n as u64;
+            bar.set_position(restored);
+        }
+    }
+
+    bar.finish_and_clear();
+
+    //
+    // With memory restored, restore the registers in the order in which
+    // they were saved.  (The stack pointer is written before MSP and PSP,
+    // so the banked pointers prevail.)
+    //
+    for i in 0..31 {
+        if let Some(reg) = ARMRegister::from_u16(i) {
+            let val = dump.read_reg(reg)?;
+            core.write_reg(reg, val)?;
+        }
+    }
+
+    info!(
+        "restored {} in {} from {}",
+        HumanBytes(total),
+        HumanDuration(started.elapsed()),
+        snapshot
+    );
+
+    if run {
+        core.run()?;
+        info!("core resumed");
+    } else {
+        info!("core left halted");
+    }
+
+    Ok(())
+}
+
+fn snapshot(
+    hubris: &mut HubrisArchive,
+    core: &mut dyn Core,
+    _args: &Args,
+    subargs: &[String],
+) -> Result<()> {
+    let subargs = SnapshotArgs::from_iter_safe(subargs)?;
+
+    match subargs.cmd {
+        SnapshotCommand::Save { snapshot } => {
+            snapshot_save(hubris, core, snapshot.as_deref())
+        }
+        SnapshotCommand::Restore { snapshot, run } => {
+            snapshot_restore(hubris, core, &snapshot, run)
+        }
+    }
+}
+
+pub fn init<'a, 'b>() -> (Command, App<'a, 'b>) {
+    (
+        Command::Attached {
+            name: "snapshot",
+            archive: Archive::Required,
+            attach: Attach::LiveOnly,
+            validate: Validate::Match,
+            run: snapshot,
+        },
+        SnapshotArgs::clap(),
+    )
+}
@@ -2,7 +2,7 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
-use humility::core::Core;
+use humility::core::{Core, CoreOps};
 use humility::hubris::*;
 use humility_cmd::hiffy::*;
 use humility_cmd::i2c::I2cArgs;
@@ -358,7 +358,7 @@ pub fn init<'a, 'b>() -> (Command, App<'a, 'b>) {
         Command::Attached {
             name: "spd",
             archive: Archive::Required,
-            attach: Attach::Any,
+            attach: Attach::Requires(CoreOps::READ),
             validate: Validate::Booted,
             run: spd,
         },
@@ -4,23 +4,28 @@
 
 use humility::core::Core;
 use humility::hubris::*;
+use humility_cmd::hexfile::{self, HexFormat};
 use humility_cmd::hiffy::*;
 use humility_cmd::printmem;
+use humility_cmd::trigger::Triggers;
 use humility_cmd::{Archive, Args, Attach, Command, Validate};
 
 use std::convert::TryInto;
+use std::fs;
 use std::str;
 
-use anyhow::{bail, Result};
+use anyhow::{bail, Context, Result};
 use hif::*;
-use structopt::clap::App;
-use structopt::StructOpt;
+use structopt::{clap::App, clap::ArgGroup, StructOpt};
 
 #[macro_use]
 extern crate log;
 
 #[derive(StructOpt, Debug)]
-#[structopt(name = "spi", about = "SPI reading and writing")]
+#[structopt(
+    name = "spi", about = "SPI reading and writing",
+    group = ArgGroup::with_name("payload").multiple(false)
+)]
 struct SpiArgs {
     /// sets timeout
     #[structopt(
@@ -33,10 +38,22 @@ struct SpiArgs {
     #[structopt(long, short, value_name = "peripheral")]
     peripheral: Option<u8>,
 
+    /// index of the device (that is, the chip select) on which to operate
+    #[structopt(long, short = "D", value_name = "device")]
+    device: Option<u8>,
+
     /// comma-separated bytes to write
-    #[structopt(long, short, value_name = "bytes")]
+    #[structopt(long, short, value_name = "bytes", group = "payload")]
     write: Option<String>,
 
+    /// hex string of bytes to write
+    #[structopt(long, short = "x", value_name = "hex", group = "payload")]
+    hex: Option<String>,
+
+    /// file containing bytes to write
+    #[structopt(long, short, value_name = "filename", group = "payload")]
+    file: Option<String>,
+
     /// perform a read
     #[structopt(long, short, requires = "nbytes")]
     read: bool,
@@ -51,17 +68,24 @@ struct SpiArgs {
     #[structopt(long, short = "W", requires = "read")]
     word: bool,
 
+    /// emit a read in the specified format (Intel HEX or S-records)
+    #[structopt(long, short, value_name = "format",
+        possible_values = &["ihex", "srec"], requires = "read",
+        conflicts_with = "word"
+    )]
+    output: Option<HexFormat>,
+
     /// interpret the specified number of trailing bytes on a write as a
     /// bigendian address
     #[structopt(
-        long, short = "A", requires_all = &["read", "write", "discard"]
+        long, short = "A", requires_all = &["read", "payload", "discard"]
     )]
     bigendian_address: Option<usize>,
 
     /// interpret the specified number of trailing bytes on a write as a
     /// bigendian address
     #[structopt(
-        long, short = "a", requires_all = &["read", "write", "discard"],
+        long, short = "a", requires_all = &["read", "payload", "discard"],
         conflicts_with = "bigendian_address"
     )]
     littleendian_address: Option<usize>,
@@ -69,6 +93,388 @@ struct SpiArgs {
     /// number of bytes to discard when printing read result
     #[structopt(long, short, value_name = "nbytes", requires = "read")]
     discard: Option<usize>,
+
+    /// run loopback transfers (MOSI jumpered to MISO, or internally
+    /// looped back with --internal) and report errors
+    #[structopt(long, short = "L", conflicts_with_all = &["payload", "read"])]
+    loopback: bool,
+
+    /// loop back within the SPI controller rather than via a jumper
+    #[structopt(long, requires = "loopback")]
+    internal: bool,
+
+    /// comma-separated clock dividers at which to run loopback transfers
+    #[structopt(long, value_name = "dividers", requires = "loopback")]
+    dividers: Option<String>,
+
+    /// number of loopback transfers at each clock divider
+    #[structopt(
+        long, short, default_value = "100", value_name = "count",
+        requires = "loopback", parse(try_from_str = parse_int::parse)
+    )]
+    iterations: u32,
+}
+
+/// The default size of a loopback transfer
+const SPI_LOOPBACK_NBYTES: usize = 64;
+
+//
+// The SPI controllers whose clock dividers and loopback we know how to
+// configure.  On the STM32H7, the baud rate is set by the MBR field of
+// SPI_CFG1 (which can only be written with SPE clear in SPI_CR1); there is
+// no internal loopback.  On the LPC55, the Flexcomm SPI has a LOOP bit in
+// its CFG register (which can only be written with the SPI disabled), and a
+// divider in its DIV register.
+//
+const STM32H7_SPI_CR1: u32 = 0x00;
+const STM32H7_SPI_CR1_SPE: u32 = 1 << 0;
+const STM32H7_SPI_CFG1: u32 = 0x08;
+const STM32H7_SPI_CFG1_MBR_SHIFT: u32 = 28;
+const STM32H7_SPI_CFG1_MBR_MASK: u32 = 0b111 << STM32H7_SPI_CFG1_MBR_SHIFT;
+
+const LPC55_SPI_CFG: u32 = 0x400;
+const LPC55_SPI_CFG_ENABLE: u32 = 1 << 0;
+const LPC55_SPI_CFG_LOOP: u32 = 1 << 7;
+const LPC55_SPI_DIV: u32 = 0x424;
+
+#[derive(Copy, Clone, Debug)]
+enum SpiController {
+    Stm32H7(u32),
+    Lpc55(u32),
+    Unknown,
+}
+
+impl SpiController {
+    fn lookup(hubris: &HubrisArchive, peripheral: u8) -> Result<Self> {
+        let chip = match humility_cortex::chip::chip_archive(hubris) {
+            Some(chip) => chip,
+            None => return Ok(SpiController::Unknown),
+        };
+
+        Ok(if chip.starts_with("STM32H7") {
+            let spi = format!("spi{}", peripheral);
+            SpiController::Stm32H7(hubris.lookup_peripheral(&spi)?)
+        } else if chip.starts_with("LPC55") {
+            let flexcomm = format!("flexcomm{}", peripheral);
+            SpiController::Lpc55(hubris.lookup_peripheral(&flexcomm)?)
+        } else {
+            SpiController::Unknown
+        })
+    }
+
+    fn check_divider(&self, divider: u32) -> Result<()> {
+        match self {
+            SpiController::Stm32H7(_) => {
+                if !divider.is_power_of_two() || !(2..=256).contains(&divider) {
+                    bail!("divider must be a power of two between 2 and 256");
+                }
+            }
+            SpiController::Lpc55(_) => {
+                if !(1..=65536).contains(&divider) {
+                    bail!("divider must be between 1 and 65536");
+                }
+            }
+            SpiController::Unknown => {
+                bail!("clock divider can't be set on this SPI controller");
+            }
+        }
+
+        Ok(())
+    }
+
+    //
+    // Configures the controller for the specified divider (if any) and
+    // loopback, returning the original values of any registers modified.
+    // We do this with the core halted to not race with the SPI driver.
+    //
+    fn configure(
+        &self,
+        core: &mut dyn Core,
+        divider: Option<u32>,
+        internal: bool,
+    ) -> Result<Vec<(u32, u32)>> {
+        let mut saved = vec![];
+
+        if divider.is_none() && !internal {
+            return Ok(saved);
+        }
+
+        core.halt()?;
+
+        let rval: Result<()> = (|| {
+            match *self {
+                SpiController::Stm32H7(base) => {
+                    if internal {
+                        bail!("STM32H7 SPI has no internal loopback");
+                    }
+
+                    let cr1 = core.read_word_32(base + STM32H7_SPI_CR1)?;
+
+                    if cr1 & STM32H7_SPI_CR1_SPE != 0 {
+                        bail!(
+                            "SPI controller is busy (SPI_CR1 is 0x{:x})",
+                            cr1
+                        );
+                    }
+
+                    let cfg1 = core.read_word_32(base + STM32H7_SPI_CFG1)?;
+                    let mbr = divider.unwrap().trailing_zeros() - 1;
+                    let val = (cfg1 & !STM32H7_SPI_CFG1_MBR_MASK)
+                        | (mbr << STM32H7_SPI_CFG1_MBR_SHIFT);
+
+                    saved.push((base + STM32H7_SPI_CFG1, cfg1));
+                    core.write_word_32(base + STM32H7_SPI_CFG1, val)?;
+                }
+
+                SpiController::Lpc55(base) => {
+                    if let Some(divider) = divider {
+                        let div = core.read_word_32(base + LPC55_SPI_DIV)?;
+                        saved.push((base + LPC55_SPI_DIV, div));
+                        core.write_word_32(base + LPC55_SPI_DIV, divider - 1)?;
+                    }
+
+                    if internal {
+                        let cfg = core.read_word_32(base + LPC55_SPI_CFG)?;
+                        let disabled = cfg & !LPC55_SPI_CFG_ENABLE;
+                        saved.push((base + LPC55_SPI_CFG, cfg));
+
+                        core.write_word_32(base + LPC55_SPI_CFG, disabled)?;
+                        core.write_word_32(
+                            base + LPC55_SPI_CFG,
+                            disabled | LPC55_SPI_CFG_LOOP,
+                        )?;
+                        core.write_word_32(
+                            base + LPC55_SPI_CFG,
+                            cfg | LPC55_SPI_CFG_LOOP,
+                        )?;
+                    }
+                }
+
+                SpiController::Unknown => {
+                    bail!(
+                        "loopback can't be configured on this SPI controller"
+                    );
+                }
+            }
+
+            Ok(())
+        })();
+
+        if rval.is_err() {
+            SpiController::restore(core, &saved)?;
+        }
+
+        core.run()?;
+        rval?;
+
+        Ok(saved)
+    }
+
+    fn restore(core: &mut dyn Core, saved: &[(u32, u32)]) -> Result<()> {
+        for (addr, val) in saved.iter().rev() {
+            core.write_word_32(*addr, *val)?;
+        }
+
+        Ok(())
+    }
+
+    fn unconfigure(core: &mut dyn Core, saved: &[(u32, u32)]) -> Result<()> {
+        if saved.is_empty() {
+            return Ok(());
+        }
+
+        core.halt()?;
+        let rval = SpiController::restore(core, saved);
+        core.run()?;
+
+        rval
+    }
+}
+
+#[derive(Default)]
+struct SpiLoopbackStats {
+    transfers: u32,
+    failed: u32,
+    bytes: u64,
+    errors: u64,
+}
+
+//
+// Generates the pattern for a batch of loopback transfers; we use a simple
+// xorshift to assure that the pattern changes from batch to batch.
+//
+fn spi_loopback_pattern(seed: u32, nbytes: usize) -> Vec<u8> {
+    let mut x = seed.wrapping_mul(0x9e37_79b9) | 1;
+
+    (0..nbytes)
+        .map(|_| {
+            x ^= x << 13;
+            x ^= x >> 17;
+            x ^= x << 5;
+            x as u8
+        })
+        .collect()
+}
+
+fn spi_loopback_run(
+    core: &mut dyn Core,
+    context: &mut HiffyContext,
+    spi_read: &HiffyFunction,
+    target: &[Op],
+    nbytes: usize,
+    iterations: u32,
+) -> Result<SpiLoopbackStats> {
+    let mut stats = SpiLoopbackStats::default();
+
+    //
+    // We batch as many transfers as will fit in the return stack, using the
+    // same pattern for each transfer in a batch.
+    //
+    let batch = std::cmp::max(context.limits().max_results(nbytes), 1);
+    let mut seed = 0;
+
+    let transfer = HiffyCall::new(
+        vec![
+            Op::Push32(nbytes as u32),
+            Op::Push32(nbytes as u32),
+            Op::Call(spi_read.id),
+            Op::DropN(2),
+        ],
+        nbytes,
+    );
+
+    while stats.transfers < iterations {
+        let n = std::cmp::min(batch as u32, iterations - stats.transfers);
+        let pattern = spi_loopback_pattern(seed, nbytes);
+        let calls = vec![transfer.clone(); n as usize];
+
+        let results = context.run_sequence(
+            core,
+            target,
+            &calls,
+            Some(pattern.as_slice()),
+        )?;
+
+        for result in &results {
+            stats.transfers += 1;
+
+            match result {
+                Ok(data) => {
+                    //
+                    // Any byte that we didn't get back counts as an error
+                    // in each of its bits.
+                    //
+                    stats.bytes += nbytes as u64;
+                    stats.errors += (0..nbytes)
+                        .map(|i| match data.get(i) {
+                            Some(b) => (b ^ pattern[i]).count_ones() as u64,
+                            None => 8,
+                        })
+                        .sum::<u64>();
+                }
+                Err(err) => {
+                    debug!("transfer failed: {}", spi_read.strerror(*err));
+                    stats.failed += 1;
+                }
+            }
+        }
+
+        seed += 1;
+    }
+
+    Ok(stats)
+}
+
+fn spi_loopback(
+    hubris: &HubrisArchive,
+    core: &mut dyn Core,
+    context: &mut HiffyContext,
+    spi_read: &HiffyFunction,
+    peripheral: u8,
+    target: &[Op],
+    subargs: &SpiArgs,
+) -> Result<()> {
+    let nbytes = subargs.nbytes.unwrap_or(SPI_LOOPBACK_NBYTES);
+
+    if nbytes == 0 {
+        bail!("loopback transfers must be at least one byte");
+    }
+
+    let limits = context.limits();
+    let max = std::cmp::min(limits.data, limits.max_payload(1));
+
+    if nbytes > max {
+        bail!("loopback transfers cannot exceed {} bytes", max);
+    }
+
+    let controller = SpiController::lookup(hubris, peripheral)?;
+
+    let dividers = match subargs.dividers {
+        Some(ref dividers) => {
+            let mut rval = vec![];
+
+            for divider in dividers.split(',') {
+                if let Ok(val) = parse_int::parse::<u32>(divider) {
+                    controller.check_divider(val)?;
+                    rval.push(Some(val));
+                } else {
+                    bail!("invalid divider {}", divider);
+                }
+            }
+
+            rval
+        }
+        None => vec![None],
+    };
+
+    info!(
+        "running {} {}-byte transfers at each divider via {} loopback",
+        subargs.iterations,
+        nbytes,
+        if subargs.internal { "internal" } else { "external" }
+    );
+
+    println!(
+        "{:>8} {:>8} {:>8} {:>10} {:>10} {:>10}",
+        "DIVIDER", "XFERS", "FAILED", "BYTES", "BITERRS", "BER"
+    );
+
+    for divider in dividers {
+        let saved = controller.configure(core, divider, subargs.internal)?;
+
+        let stats = spi_loopback_run(
+            core,
+            context,
+            spi_read,
+            target,
+            nbytes,
+            subargs.iterations,
+        );
+
+        SpiController::unconfigure(core, &saved)?;
+        let stats = stats?;
+
+        let ber = if stats.bytes == 0 {
+            "-".to_string()
+        } else {
+            format!("{:.2e}", stats.errors as f64 / (stats.bytes * 8) as f64)
+        };
+
+        println!(
+            "{:>8} {:>8} {:>8} {:>10} {:>10} {:>10}",
+            match divider {
+                Some(divider) => divider.to_string(),
+                None => "-".to_string(),
+            },
+            stats.transfers,
+            stats.failed,
+            stats.bytes,
+            stats.errors,
+            ber
+        );
+    }
+
+    Ok(())
 }
 
 /// Looks up which Hubris task is associated with SPI (accepting a peripheral
@@ -77,6 +483,14 @@ pub fn spi_task(
     hubris: &HubrisArchive,
     peripheral: Option<u8>,
 ) -> Result<HubrisTask> {
+    Ok(spi_lookup(hubris, peripheral)?.1)
+}
+
+/// Looks up the SPI peripheral and its associated Hubris task.
+fn spi_lookup(
+    hubris: &HubrisArchive,
+    peripheral: Option<u8>,
+) -> Result<(u8, HubrisTask)> {
     let lookup = |peripheral| {
         let spi = format!("spi{}", peripheral);
         let tasks = hubris.lookup_feature(&spi)?;
@@ -90,9 +504,9 @@ pub fn spi_task(
         }
     };
 
-    let task = if let Some(peripheral) = peripheral {
+    let (peripheral, task) = if let Some(peripheral) = peripheral {
         match lookup(peripheral)? {
-            Some(task) => task,
+            Some(task) => (peripheral, task),
             None => {
                 bail!("SPI peripheral {} not found", peripheral);
             }
@@ -121,50 +535,136 @@ pub fn spi_task(
             );
         }
 
-        found[0].1
+        found[0]
     };
     if task == HubrisTask::Kernel {
         bail!("SPI task cannot be the kernel");
     }
-    Ok(task)
+    Ok((peripheral, task))
+}
+
+//
+// Returns the bytes to write (if any), as specified either as comma-separated
+// bytes, as a hex string, or as a file.
+//
+fn spi_payload(subargs: &SpiArgs) -> Result<Option<Vec<u8>>> {
+    if let Some(ref write) = subargs.write {
+        let mut arr = vec![];
+
+        for byte in write.split(',') {
+            if let Ok(val) = parse_int::parse::<u8>(byte) {
+                arr.push(val);
+            } else {
+                bail!("invalid byte {}", byte)
+            }
+        }
+
+        Ok(Some(arr))
+    } else if let Some(ref hex) = subargs.hex {
+        let hex = hex.strip_prefix("0x").unwrap_or(hex);
+        let digits = hex
+            .chars()
+            .filter(|c| !c.is_whitespace() && *c != ':')
+            .collect::<Vec<_>>();
+
+        if digits.len() % 2 != 0 {
+            bail!("hex string must have an even number of digits");
+        }
+
+        let mut arr = vec![];
+
+        for pair in digits.chunks(2) {
+            let pair = pair.iter().collect::<String>();
+
+            match u8::from_str_radix(&pair, 16) {
+                Ok(val) => arr.push(val),
+                Err(_) => bail!("invalid hex byte {}", pair),
+            }
+        }
+
+        Ok(Some(arr))
+    } else if let Some(ref filename) = subargs.file {
+        Ok(Some(
+            fs::read(filename)
+                .with_context(|| format!("failed to read {}", filename))?,
+        ))
+    } else {
+        Ok(None)
+    }
 }
 
 fn spi(
     hubris: &mut HubrisArchive,
     core: &mut dyn Core,
-    _args: &Args,
+    args: &Args,
     subargs: &[String],
 ) -> Result<()> {
     let subargs = SpiArgs::from_iter_safe(subargs)?;
     let mut context = HiffyContext::new(hubris, core, subargs.timeout)?;
+    context.set_triggers(Triggers::new(&args.triggers())?);
     let funcs = context.functions()?;
 
-    let spi_read = funcs.get("SpiRead", 3)?;
-    let spi_write = funcs.get("SpiWrite", 2)?;
+    //
+    // Newer SPI APIs take the index of the device (that is, the chip select)
+    // on which to operate; older ones can only operate on the first.
+    //
+    let spi_read =
+        funcs.get("SpiRead", 4).or_else(|_| funcs.get("SpiRead", 3))?;
+    let spi_write =
+        funcs.get("SpiWrite", 3).or_else(|_| funcs.get("SpiWrite", 2))?;
+
+    let device = match (subargs.device, spi_read.args.len() == 4) {
+        (device, true) => Some(device.unwrap_or(0)),
+        (Some(_), false) => bail!("SPI API does not support selecting devices"),
+        (None, false) => None,
+    };
+
+    if (spi_write.args.len() == 3) != device.is_some() {
+        bail!("mismatched signatures on SpiRead and SpiWrite");
+    }
 
-    let task = spi_task(hubris, subargs.peripheral)?;
-    let mut ops = vec![];
+    let (peripheral, task) = spi_lookup(hubris, subargs.peripheral)?;
 
-    if let HubrisTask::Task(task) = task {
-        ops.push(Op::Push32(task));
+    let id = if let HubrisTask::Task(id) = task {
+        id
     } else {
         bail!("SPI task cannot be the kernel");
-    }
+    };
 
     info!("SPI master is {}", hubris.lookup_module(task)?.name);
 
-    let mut addr = 0;
+    //
+    // Every operation is prefixed by the task (and, if supported, the
+    // device) on which to operate.
+    //
+    let mut target = vec![Op::Push32(id)];
 
-    let data = if let Some(ref write) = subargs.write {
-        let bytes: Vec<&str> = write.split(',').collect();
-        let mut arr = vec![];
+    if let Some(device) = device {
+        target.push(Op::Push(device));
+    }
 
-        for byte in &bytes {
-            if let Ok(val) = parse_int::parse::<u8>(byte) {
-                arr.push(val);
-            } else {
-                bail!("invalid byte {}", byte)
-            }
+    if subargs.loopback {
+        return spi_loopback(
+            hubris,
+            core,
+            &mut context,
+            spi_read,
+            peripheral,
+            &target,
+            &subargs,
+        );
+    }
+
+    let mut ops = target.clone();
+    let mut addr = 0;
+
+    let data = if let Some(arr) = spi_payload(&subargs)? {
+        if arr.len() > context.data_size() {
+            bail!(
+                "{} bytes to write exceeds maximum of {} bytes",
+                arr.len(),
+                context.data_size()
+            );
         }
 
         if let Some(size) = subargs.littleendian_address {
@@ -249,6 +749,12 @@ fn spi(
                 bail!("short read: {:x?}", results);
             }
 
+            if let Some(format) = subargs.output {
+                let mut out = std::io::stdout();
+                hexfile::writemem(&mut out, format, &results[discard..], addr)?;
+                return Ok(());
+            }
+
             let size = if subargs.word { 4 } else { 1 };
             printmem(&results[discard..], addr, size, 16);
             return Ok(());
@@ -2,11 +2,17 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
-use anyhow::{bail, Result};
-use humility::core::Core;
+#[macro_use]
+extern crate log;
+
+use anyhow::{anyhow, bail, Result};
+use humility::coalesce::CoalescingCore;
+use humility::core::{Core, CoreOps};
 use humility::hubris::*;
+use humility_cmd::json::Json;
 use humility_cmd::{Archive, Args, Attach, Command, Validate};
 use std::convert::TryInto;
+use std::fmt;
 use structopt::clap::App;
 use structopt::StructOpt;
 
@@ -15,17 +21,78 @@ use structopt::StructOpt;
     name = "stackmargin",
     about = "calculate and print stack margins by task"
 )]
-struct StackmarginArgs {}
+struct StackmarginArgs {
+    /// flag tasks with less than the specified margin, in bytes or as a
+    /// percentage of stack size (e.g., "10%")
+    #[structopt(
+        long, short, value_name = "margin",
+        parse(try_from_str = parse_threshold)
+    )]
+    threshold: Option<StackmarginThreshold>,
+
+    /// fail if any task is below the threshold
+    #[structopt(long, short, requires = "threshold")]
+    fail: bool,
+}
+
+#[derive(Copy, Clone, Debug)]
+enum StackmarginThreshold {
+    Bytes(usize),
+    Percent(f64),
+}
+
+impl StackmarginThreshold {
+    fn below(&self, size: usize, margin: usize) -> bool {
+        match self {
+            StackmarginThreshold::Bytes(bytes) => margin < *bytes,
+            StackmarginThreshold::Percent(pct) => {
+                (margin as f64) < (size as f64) * pct / 100.0
+            }
+        }
+    }
+}
+
+impl fmt::Display for StackmarginThreshold {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StackmarginThreshold::Bytes(bytes) => write!(f, "{} bytes", bytes),
+            StackmarginThreshold::Percent(pct) => write!(f, "{}%", pct),
+        }
+    }
+}
+
+fn parse_threshold(s: &str) -> Result<StackmarginThreshold> {
+    match s.strip_suffix('%') {
+        Some(pct) => match pct.parse::<f64>() {
+            Ok(pct) if (0.0..=100.0).contains(&pct) => {
+                Ok(StackmarginThreshold::Percent(pct))
+            }
+            _ => bail!("invalid percentage \"{}\"", s),
+        },
+        None => Ok(StackmarginThreshold::Bytes(
+            parse_int::parse::<usize>(s)
+                .map_err(|_| anyhow!("invalid threshold \"{}\"", s))?,
+        )),
+    }
+}
 
 #[rustfmt::skip::macros(println, bail)]
 fn stackmargin(
     hubris: &mut HubrisArchive,
     core: &mut dyn Core,
-    _args: &Args,
-    _subargs: &[String],
+    args: &Args,
+    subargs: &[String],
 ) -> Result<()> {
+    let subargs = StackmarginArgs::from_iter_safe(subargs)?;
     let regions = hubris.regions(core)?;
 
+    //
+    // Each task's initial stack is read from its descriptor, so we coalesce
+    // the reads.
+    //
+    let mut coalesced = CoalescingCore::new(core, &regions);
+    let core = &mut coalesced as &mut dyn Core;
+
     let base = core.read_word_32(hubris.lookup_symword("TASK_TABLE_BASE")?)?;
     let size = core.read_word_32(hubris.lookup_symword("TASK_TABLE_SIZE")?)?;
 
@@ -39,8 +106,13 @@ fn stackmargin(
     let descriptor = task.lookup_member("descriptor")?.offset as u32;
     let initial_stack = taskdesc.lookup_member("initial_stack")?.offset as u32;
 
-    println!("{:2} {:18} {:>10} {:>10} {:>10} {:>10}",
-        "ID", "TASK", "STACKBASE", "STACKSIZE", "MAXDEPTH", "MARGIN");
+    if !args.json() {
+        println!("{:2} {:18} {:>10} {:>10} {:>10} {:>10}",
+            "ID", "TASK", "STACKBASE", "STACKSIZE", "MAXDEPTH", "MARGIN");
+    }
+
+    let mut json = vec![];
+    let mut below = vec![];
 
     let taskblock32 =
         |o| u32::from_le_bytes(taskblock[o..o + 4].try_into().unwrap());
@@ -87,9 +159,59 @@ fn stackmargin(
             o += 4;
         };
 
-        println!("{:2} {:18} 0x{:<8x} {:10} {:10} {:10}",
+        let margin = size - depth;
+        let flagged = match subargs.threshold {
+            Some(threshold) => threshold.below(size, margin),
+            None => false,
+        };
+
+        if flagged {
+            below.push(module.name.as_str());
+        }
+
+        if args.json() {
+            let mut members = vec![
+                ("id", Json::from(i)),
+                ("task", Json::from(&module.name)),
+                ("stackbase", Json::from(region.base)),
+                ("stacksize", Json::from(size)),
+                ("maxdepth", Json::from(depth)),
+                ("margin", Json::from(margin)),
+            ];
+
+            if subargs.threshold.is_some() {
+                members.push(("below_threshold", Json::from(flagged)));
+            }
+
+            json.push(Json::object(members));
+            continue;
+        }
+
+        println!("{:2} {:18} 0x{:<8x} {:10} {:10} {:10}{}",
             i, module.name, region.base,
-            size, depth, size - depth);
+            size, depth, margin, if flagged { " <- low" } else { "" });
+    }
+
+    if args.json() {
+        Json::Array(json).print();
+    }
+
+    if let Some(threshold) = subargs.threshold {
+        if !below.is_empty() {
+            let msg = format!(
+                "{} {} less than {} of stack margin: {}",
+                below.len(),
+                if below.len() == 1 { "task has" } else { "tasks have" },
+                threshold,
+                below.join(", ")
+            );
+
+            if subargs.fail {
+                bail!("{}", msg);
+            }
+
+            warn!("{}", msg);
+        }
     }
 
     Ok(())
@@ -100,7 +222,7 @@ pub fn init<'a, 'b>() -> (Command, App<'a, 'b>) {
         Command::Attached {
             name: "stackmargin",
             archive: Archive::Required,
-            attach: Attach::Any,
+            attach: Attach::Requires(CoreOps::READ),
             validate: Validate::Booted,
             run: stackmargin,
         },
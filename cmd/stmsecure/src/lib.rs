@@ -4,7 +4,7 @@
 
 use anyhow::{anyhow, Result};
 use humility::arch::ARMRegister;
-use humility::core::Core;
+use humility::core::{Core, CoreOps};
 use humility::hubris::*;
 use humility_cmd::{Archive, Args, Attach, Command, Validate};
 use structopt::clap::App;
@@ -300,7 +300,7 @@ pub fn init<'a, 'b>() -> (Command, App<'a, 'b>) {
         Command::Attached {
             name: "stmsecure",
             archive: Archive::Optional,
-            attach: Attach::Any,
+            attach: Attach::Requires(CoreOps::READ | CoreOps::WRITE),
             validate: Validate::None,
             run: stmsecure,
         },
@@ -4,13 +4,18 @@
 
 use anyhow::{bail, Result};
 use humility::arch::ARMRegister;
-use humility::core::Core;
+use humility::coalesce::CoalescingCore;
+use humility::core::{Core, CoreOps};
 use humility::hubris::*;
 use humility_cmd::doppel::{self, Task, TaskDesc, TaskId, TaskState};
+use humility_cmd::json::Json;
+use humility_cmd::otlp::{OtlpExporter, OtlpSeverity};
 use humility_cmd::reflect::{self, Format, Load};
 use humility_cmd::{Archive, Args, Attach, Command, Validate};
 use num_traits::FromPrimitive;
 use std::collections::HashMap;
+use std::thread;
+use std::time::Duration;
 use structopt::clap::App;
 use structopt::StructOpt;
 
@@ -37,6 +42,22 @@ struct TasksArgs {
     #[structopt(long, short)]
     verbose: bool,
 
+    /// continuously display tasks, updating in place
+    #[structopt(
+        long, short,
+        conflicts_with_all = &["registers", "stack", "spin", "verbose"]
+    )]
+    watch: bool,
+
+    /// with --watch, interval between updates, in milliseconds
+    #[structopt(long, short, default_value = "1000", value_name = "ms")]
+    interval: u64,
+
+    /// with --watch, number of samples of the current task to take between
+    /// updates to estimate CPU utilization
+    #[structopt(long, default_value = "100", value_name = "samples")]
+    samples: u32,
+
     /// single task to display
     task: Option<String>,
 }
@@ -120,25 +141,321 @@ fn print_regs(regs: &HashMap<ARMRegister, u32>, additional: bool) {
     }
 }
 
+//
+// Exports a task's generation as a gauge, along with any restart or fault
+// since it was last sampled as an event.
+//
+fn export_task(
+    otlp: &mut OtlpExporter,
+    last: &mut HashMap<u32, (u32, bool)>,
+    ndx: u32,
+    module: &str,
+    task: &Task,
+) {
+    let gen = u32::from(task.generation);
+    let fault = match task.state {
+        TaskState::Faulted { fault, .. } => Some(fault),
+        _ => None,
+    };
+
+    let attrs = [("task", module)];
+
+    otlp.gauge("hubris.task.generation", gen as f64, &attrs);
+
+    if let Some((lgen, lfaulted)) = last.get(&ndx) {
+        if gen != *lgen {
+            otlp.event(
+                OtlpSeverity::Warn,
+                &format!("task {} restarted (generation {})", module, gen),
+                &attrs,
+            );
+        }
+
+        if let (Some(fault), false) = (fault, *lfaulted) {
+            otlp.event(
+                OtlpSeverity::Error,
+                &format!("task {} faulted: {:?}", module, fault),
+                &attrs,
+            );
+        }
+    }
+
+    last.insert(ndx, (gen, fault.is_some()));
+}
+
+fn task_id_json(hubris: &HubrisArchive, task_id: TaskId) -> Json {
+    if task_id == TaskId::KERNEL {
+        return Json::object(vec![("task", Json::from("kernel"))]);
+    }
+
+    Json::object(vec![
+        ("task", Json::from(hubris.task_name(task_id.index()))),
+        ("index", Json::from(task_id.index())),
+        ("generation", Json::from(task_id.generation())),
+    ])
+}
+
+fn sched_state_json(
+    hubris: &HubrisArchive,
+    ss: doppel::SchedState,
+    current: bool,
+) -> Json {
+    use doppel::SchedState;
+
+    let (state, peer) = match ss {
+        SchedState::Stopped => ("stopped", None),
+        SchedState::Runnable if current => ("running", None),
+        SchedState::Runnable => ("ready", None),
+        SchedState::InSend(tid) => ("send", Some(tid)),
+        SchedState::InReply(tid) => ("reply", Some(tid)),
+        SchedState::InRecv(tid) => ("recv", tid),
+    };
+
+    Json::object(vec![
+        ("state", Json::from(state)),
+        ("peer", Json::from(peer.map(|tid| task_id_json(hubris, tid)))),
+    ])
+}
+
+//
+// Describes a task as JSON.  The fault, if any, is described as it is
+// represented in the kernel rather than in prose.
+//
+fn task_json(
+    hubris: &HubrisArchive,
+    core: &mut dyn Core,
+    ndx: u32,
+    module: &str,
+    task: &Task,
+    current: bool,
+    subargs: &TasksArgs,
+) -> Result<Json> {
+    let mut members = vec![
+        ("id", Json::from(ndx)),
+        ("name", Json::from(module)),
+        ("generation", Json::from(u32::from(task.generation))),
+        ("priority", Json::from(task.priority.0)),
+    ];
+
+    match task.state {
+        TaskState::Healthy(ss) => {
+            members.push(("healthy", Json::from(true)));
+            members.push(("state", sched_state_json(hubris, ss, current)));
+        }
+        TaskState::Faulted { fault, original_state } => {
+            let state = sched_state_json(hubris, original_state, false);
+
+            members.push(("healthy", Json::from(false)));
+            members.push(("fault", Json::from(format!("{:?}", fault))));
+            members.push(("state", state));
+        }
+    }
+
+    if subargs.registers {
+        let regs = hubris.registers(core, HubrisTask::Task(ndx))?;
+        let mut registers = vec![];
+
+        for r in 0..16 {
+            let reg = ARMRegister::from_usize(r).unwrap();
+
+            if let Some(val) = regs.get(&reg) {
+                registers.push((reg.to_string(), Json::from(*val)));
+            }
+        }
+
+        members.push(("registers", Json::object(registers)));
+    }
+
+    Ok(Json::object(members))
+}
+
+//
+// Displays the tasks continuously, redrawing the display in place.  Between
+// updates, we sample the current task pointer (without halting the core) to
+// estimate the share of the CPU that each task is getting, and we highlight
+// any task whose generation has changed since the last update -- that is,
+// any task that has been restarted.
+//
+#[rustfmt::skip::macros(println)]
+fn tasks_watch(
+    hubris: &mut HubrisArchive,
+    core: &mut dyn Core,
+    base: u32,
+    task_count: u32,
+    task_t: &HubrisStruct,
+    subargs: &TasksArgs,
+) -> Result<()> {
+    let cur_ptr = hubris.lookup_symword("CURRENT_TASK_PTR")?;
+    let samples = std::cmp::max(subargs.samples, 1);
+    let pause = Duration::from_millis(subargs.interval) / samples;
+
+    let mut last: HashMap<u32, u32> = HashMap::new();
+    let mut restarts: HashMap<u32, u32> = HashMap::new();
+
+    loop {
+        let mut counts = vec![0u32; task_count as usize];
+
+        for _ in 0..samples {
+            let cur = core.read_word_32(cur_ptr)?;
+
+            if cur >= base {
+                let ndx = ((cur - base) as usize) / task_t.size;
+
+                if let Some(count) = counts.get_mut(ndx) {
+                    *count += 1;
+                }
+            }
+
+            thread::sleep(pause);
+        }
+
+        let ticks = hubris.sample_ticks(core)?;
+
+        core.halt()?;
+
+        let mut taskblock = vec![0; task_t.size * task_count as usize];
+        let rval = core.read_8(base, &mut taskblock);
+        let cur = core.read_word_32(cur_ptr);
+
+        core.run()?;
+        rval?;
+        let cur = cur?;
+
+        //
+        // Move the cursor home and clear the screen before redrawing.
+        //
+        print!("\x1b[H\x1b[2J");
+
+        println!("system time = {}, updating every {}ms; ^C to stop",
+            hubris.timebase().display(ticks), subargs.interval);
+
+        println!("{:2} {:15} {:>8} {:>8} {:3} {:>6} {:9}",
+            "ID", "TASK", "GEN", "RESTARTS", "PRI", "CPU", "STATE");
+
+        for i in 0..task_count {
+            let addr = base + i * task_t.size as u32;
+            let offs = i as usize * task_t.size;
+
+            let task_value: reflect::Value =
+                reflect::load(hubris, &taskblock, task_t, offs)?;
+            let task: Task = Task::from_value(&task_value)?;
+            let desc: TaskDesc = task.descriptor.load_from(hubris, core)?;
+            let module =
+                hubris.instr_mod(desc.entry_point).unwrap_or("<unknown>");
+
+            if let Some(ref task) = subargs.task {
+                if task != module {
+                    continue;
+                }
+            }
+
+            let gen = u32::from(task.generation);
+
+            let changed = match last.insert(i, gen) {
+                Some(lgen) if lgen != gen => {
+                    *restarts.entry(i).or_insert(0) += 1;
+                    true
+                }
+                _ => false,
+            };
+
+            let irqs = hubris.manifest.task_irqs.get(module);
+
+            let timer = task.timer.deadline.map(|deadline| {
+                (deadline.0 as i64 - ticks as i64, task.timer.to_post.0)
+            });
+
+            let mut modname = module.to_string();
+
+            if modname.len() > 14 {
+                modname.truncate(14);
+                modname.push('…');
+            }
+
+            if changed {
+                print!("\x1b[1;7m");
+            }
+
+            print!(
+                "{:2} {:15} {:>8} {:>8} {:3} {:>5.1}% ",
+                i,
+                modname,
+                gen,
+                restarts.get(&i).unwrap_or(&0),
+                task.priority.0,
+                counts[i as usize] as f64 * 100.0 / samples as f64,
+            );
+
+            explain_state(
+                hubris,
+                core,
+                i,
+                task.state,
+                addr == cur,
+                irqs,
+                timer,
+            )?;
+
+            if changed {
+                print!("\x1b[0m");
+            }
+
+            println!();
+        }
+    }
+}
+
 #[rustfmt::skip::macros(println)]
 fn tasks(
     hubris: &mut HubrisArchive,
     core: &mut dyn Core,
-    _args: &Args,
+    args: &Args,
     subargs: &[String],
 ) -> Result<()> {
     let subargs = TasksArgs::from_iter_safe(subargs)?;
+    let mut otlp = OtlpExporter::new(args, hubris)?;
 
     let base = core.read_word_32(hubris.lookup_symword("TASK_TABLE_BASE")?)?;
     let task_count =
         core.read_word_32(hubris.lookup_symword("TASK_TABLE_SIZE")?)?;
-    let ticks = core.read_word_64(hubris.lookup_variable("TICKS")?.addr)?;
 
-    let task_t = hubris.lookup_struct_byname("Task")?;
+    //
+    // We sample the tick counter on each pass (which requires the archive
+    // to be mutable), so we take our own copy of the task structure.
+    //
+    let task_t = hubris.lookup_struct_byname("Task")?.clone();
+
+    //
+    // Decoding tasks (and especially unwinding their stacks) results in
+    // many small reads, so we coalesce them.
+    //
+    let regions = hubris.regions(core)?;
+    let mut coalesced = CoalescingCore::new(core, &regions);
+    let core = &mut coalesced as &mut dyn Core;
+
+    if subargs.watch {
+        if core.is_dump() {
+            bail!("cannot watch tasks on a dump");
+        }
+
+        if args.json() {
+            bail!("--watch is only supported for text output");
+        }
+
+        return tasks_watch(hubris, core, base, task_count, &task_t, &subargs);
+    }
 
     let mut found = false;
 
+    //
+    // If we are exporting, we track the generation and fault state of each
+    // task to be able to report restarts and faults as events.
+    //
+    let mut last: HashMap<u32, (u32, bool)> = HashMap::new();
+
     loop {
+        let ticks = hubris.sample_ticks(core)?;
+
         core.halt()?;
 
         let cur =
@@ -155,10 +472,59 @@ fn tasks(
             core.run()?;
         }
 
-        println!("system time = {}", ticks);
+        //
+        // If we are displaying stacks, we unwind them all before displaying
+        // anything, which allows them to be unwound in parallel.
+        //
+        let mut work = vec![];
+        let mut ndx = vec![];
 
-        println!("{:2} {:15} {:>8} {:3} {:9}",
-            "ID", "TASK", "GEN", "PRI", "STATE");
+        if subargs.stack {
+            for i in 0..task_count {
+                let offs = i as usize * task_t.size;
+                let task_value: reflect::Value =
+                    reflect::load(hubris, &taskblock, &task_t, offs)?;
+                let task: Task = Task::from_value(&task_value)?;
+                let desc: TaskDesc = task.descriptor.load_from(hubris, core)?;
+
+                let module = hubris.instr_mod(desc.entry_point);
+
+                if let Some(ref task) = subargs.task {
+                    if module != Some(task.as_str()) {
+                        continue;
+                    }
+                }
+
+                let t = HubrisTask::Task(i);
+                work.push((t, desc.initial_stack, hubris.registers(core, t)?));
+                ndx.push(i);
+            }
+        }
+
+        let stacks: HashMap<_, _> =
+            ndx.iter().copied().zip(hubris.stacks(core, &work)?).collect();
+
+        //
+        // Hang on to the registers that we read to unwind the stacks, lest
+        // we read them again to display them.
+        //
+        let mut registers: HashMap<_, _> = ndx
+            .into_iter()
+            .zip(work.into_iter().map(|(_, _, regs)| regs))
+            .collect();
+
+        //
+        // For machine-readable output, we emit a single object for each pass
+        // over the task table.
+        //
+        let mut json = vec![];
+
+        if !args.json() {
+            println!("system time = {}", hubris.timebase().display(ticks));
+
+            println!("{:2} {:15} {:>8} {:3} {:9}",
+                "ID", "TASK", "GEN", "PRI", "STATE");
+        }
 
         let mut any_names_truncated = false;
         for i in 0..task_count {
@@ -166,7 +532,7 @@ fn tasks(
             let offs = i as usize * task_t.size;
 
             let task_value: reflect::Value =
-                reflect::load(hubris, &taskblock, task_t, offs)?;
+                reflect::load(hubris, &taskblock, &task_t, offs)?;
             let task: Task = Task::from_value(&task_value)?;
             let desc: TaskDesc = task.descriptor.load_from(hubris, core)?;
             let module =
@@ -182,6 +548,24 @@ fn tasks(
                 found = true;
             }
 
+            if args.json() {
+                json.push(task_json(
+                    hubris,
+                    core,
+                    i,
+                    module,
+                    &task,
+                    addr == cur,
+                    &subargs,
+                )?);
+
+                if let Some(ref mut otlp) = otlp {
+                    export_task(otlp, &mut last, i, module, &task);
+                }
+
+                continue;
+            }
+
             let timer = task.timer.deadline.map(|deadline| {
                 (deadline.0 as i64 - ticks as i64, task.timer.to_post.0)
             });
@@ -212,17 +596,22 @@ fn tasks(
             )?;
             println!();
 
+            if let Some(ref mut otlp) = otlp {
+                export_task(otlp, &mut last, i, module, &task);
+            }
+
             if subargs.stack || subargs.registers {
-                let t = HubrisTask::Task(i);
-                let regs = hubris.registers(core, t)?;
-
-                if subargs.stack {
-                    match hubris.stack(core, t, desc.initial_stack, &regs) {
-                        Ok(stack) => print_stack(hubris, &stack, &subargs),
-                        Err(e) => {
-                            println!("   stack unwind failed: {:?} ", e);
-                        }
+                let regs = match registers.remove(&i) {
+                    Some(regs) => regs,
+                    None => hubris.registers(core, HubrisTask::Task(i))?,
+                };
+
+                match stacks.get(&i) {
+                    Some(Ok(stack)) => print_stack(hubris, stack, &subargs),
+                    Some(Err(e)) => {
+                        println!("   stack unwind failed: {:?} ", e);
                     }
+                    None => {}
                 }
 
                 if subargs.registers {
@@ -248,6 +637,14 @@ fn tasks(
             }
         }
 
+        if args.json() {
+            Json::object(vec![
+                ("ticks", Json::from(ticks)),
+                ("tasks", Json::Array(json)),
+            ])
+            .print();
+        }
+
         if any_names_truncated {
             println!("Note: task names were truncated to fit. Use \
                 humility manifest to see them.");
@@ -261,6 +658,10 @@ fn tasks(
             bail!("\"{}\" is not a valid task", subargs.task.unwrap());
         }
 
+        if let Some(ref mut otlp) = otlp {
+            otlp.tick();
+        }
+
         if !subargs.spin {
             break;
         }
@@ -535,7 +936,7 @@ pub fn init<'a, 'b>() -> (Command, App<'a, 'b>) {
         Command::Attached {
             name: "tasks",
             archive: Archive::Required,
-            attach: Attach::Any,
+            attach: Attach::Requires(CoreOps::READ | CoreOps::REGISTERS),
             validate: Validate::Booted,
             run: tasks,
         },
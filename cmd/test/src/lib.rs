@@ -23,6 +23,32 @@ struct TestArgs {
     /// sets the output file
     #[structopt(long, short, value_name = "filename")]
     output: Option<String>,
+    /// write a JUnit-style XML report of the results
+    #[structopt(long, short, value_name = "filename")]
+    junit: Option<String>,
+}
+
+//
+// Reports on a run that has ended (whether or not it completed), writing
+// the full report if needed and the JUnit report if asked.
+//
+fn test_report(
+    testrun: &mut TestRun,
+    subargs: &TestArgs,
+    wire: &[(u8, f64, f64)],
+    err: Option<&anyhow::Error>,
+) -> Result<()> {
+    if err.is_some() || testrun.failed() || subargs.dumpalways {
+        testrun.report(subargs.output.as_ref(), wire, err)?;
+    }
+
+    testrun.summary();
+
+    if let Some(ref junit) = subargs.junit {
+        testrun.junit(junit, err)?;
+    }
+
+    Ok(())
 }
 
 fn test_ingest(
@@ -53,7 +79,6 @@ fn test_ingest(
     let wirebuf = vec![];
     let wire = RefCell::new(wirebuf);
 
-    let output = subargs.output.as_ref();
     let timeout = 30;
 
     let rval = itm_ingest(
@@ -119,8 +144,9 @@ fn test_ingest(
                     match testrun.consume(source, *p as char) {
                         Ok(_) => {}
                         Err(err) => {
-                            testrun.report(
-                                output,
+                            test_report(
+                                &mut testrun,
+                                subargs,
                                 &wire.borrow(),
                                 Some(&err),
                             )?;
@@ -129,16 +155,18 @@ fn test_ingest(
                     }
 
                     if testrun.completed() {
-                        if testrun.failed() {
-                            testrun.report(output, &wire.borrow(), None)?;
-                            std::process::exit(1);
-                        }
-
-                        if subargs.dumpalways {
-                            testrun.report(output, &wire.borrow(), None)?;
-                        }
-
-                        std::process::exit(0);
+                        test_report(
+                            &mut testrun,
+                            subargs,
+                            &wire.borrow(),
+                            None,
+                        )?;
+
+                        std::process::exit(if testrun.failed() {
+                            1
+                        } else {
+                            0
+                        });
                     }
                 }
 
@@ -171,7 +199,7 @@ fn test_ingest(
     match rval {
         Ok(_) => rval,
         Err(err) => {
-            testrun.report(output, &wire.borrow(), Some(&err))?;
+            test_report(&mut testrun, subargs, &wire.borrow(), Some(&err))?;
             Err(err)
         }
     }
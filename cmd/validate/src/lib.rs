@@ -0,0 +1,245 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use anyhow::{bail, Result};
+use colored::Colorize;
+use hif::*;
+use humility::core::Core;
+use humility::hubris::*;
+use humility_cmd::hiffy::*;
+use humility_cmd::{Archive, Args, Attach, Command, Validate};
+use structopt::clap::App;
+use structopt::StructOpt;
+
+#[derive(StructOpt, Debug)]
+#[structopt(
+    name = "validate",
+    about = "validate presence of devices in the archive manifest"
+)]
+struct ValidateArgs {
+    /// sets timeout
+    #[structopt(
+        long, short = "T", default_value = "5000", value_name = "timeout_ms",
+        parse(try_from_str = parse_int::parse)
+    )]
+    timeout: u32,
+}
+
+/*
+ * PMBus MFR_ID; we use this to identify PMBus devices, which should all
+ * respond to it with a block read.
+ */
+const PMBUS_MFR_ID: u8 = 0x99;
+
+//
+// The MFR_ID that we expect of each PMBus device that we know.  Devices not
+// in this table are presumed to be correct if they return any MFR_ID.
+//
+const PMBUS_MFR_IDS: &[(&str, &[u8])] =
+    &[("adm1272", b"ADI"), ("bmr480", b"Flex"), ("bmr491", b"Flex")];
+
+fn printable(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .map(|&b| match b {
+            b' '..=b'~' => b as char,
+            _ => '.',
+        })
+        .collect()
+}
+
+//
+// Checks the result of reading a device, returning an error message if it
+// fails validation, or any detail to report if it passes.
+//
+fn identify(device: &HubrisI2cDevice, bytes: &[u8]) -> Result<String, String> {
+    if let HubrisI2cDeviceClass::Pmbus { .. } = device.class {
+        //
+        // Some devices pad their MFR_ID with NULs or spaces.
+        //
+        let len = bytes
+            .iter()
+            .rposition(|&b| b != 0 && b != b' ')
+            .map_or(0, |pos| pos + 1);
+        let id = &bytes[..len];
+
+        let expected = PMBUS_MFR_IDS
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(&device.device))
+            .map(|(_, expected)| *expected);
+
+        return match expected {
+            Some(expected) if id != expected => Err(format!(
+                "MFR_ID \"{}\", expected \"{}\"",
+                printable(id),
+                printable(expected)
+            )),
+            Some(_) => Ok(format!(" (MFR_ID \"{}\")", printable(id))),
+            None if id.is_empty() => Err("empty MFR_ID".to_string()),
+            None => Ok(format!(" (MFR_ID \"{}\", unverified)", printable(id))),
+        };
+    }
+
+    Ok("".to_string())
+}
+
+#[rustfmt::skip::macros(println, bail)]
+fn validate(
+    hubris: &mut HubrisArchive,
+    core: &mut dyn Core,
+    _args: &Args,
+    subargs: &[String],
+) -> Result<()> {
+    let subargs = ValidateArgs::from_iter_safe(subargs)?;
+    let devices = &hubris.manifest.i2c_devices;
+
+    if devices.is_empty() {
+        bail!("no I2C devices found in archive manifest");
+    }
+
+    println!("{:2} {:>2} {:2} {:3} {:4} {:13} {:8} DESCRIPTION",
+        "ID", "C", "P", "MUX", "ADDR", "DEVICE", "RESULT");
+
+    let mux = |device: &HubrisI2cDevice| match (device.mux, device.segment) {
+        (Some(m), Some(s)) => format!("{}:{}", m, s),
+        (Some(m), None) => format!("{}:?", m),
+        (None, Some(s)) => format!("?:{}", s),
+        (None, None) => "-".to_string(),
+    };
+
+    let mut context = HiffyContext::new(hubris, core, subargs.timeout)?;
+    let funcs = context.functions()?;
+    let func = funcs.get("I2cRead", 7)?;
+
+    let mut ops = vec![];
+
+    //
+    // For each device, we perform a single read:  PMBus devices are asked
+    // for their MFR_ID (which also serves to identify them); all other
+    // devices are merely checked for presence with a raw single-byte read.
+    // A device behind a mux must have both its mux and its segment
+    // specified; if it doesn't, it fails without being read.  We record
+    // the index of the result that corresponds to each device that we read.
+    //
+    let mut calls = vec![];
+    let mut nread = 0;
+
+    for device in devices {
+        match (device.mux, device.segment) {
+            (Some(mux), Some(segment)) => {
+                ops.push(Op::Push(device.controller));
+                ops.push(Op::Push(device.port.index));
+                ops.push(Op::Push(mux));
+                ops.push(Op::Push(segment));
+            }
+            (None, None) => {
+                ops.push(Op::Push(device.controller));
+                ops.push(Op::Push(device.port.index));
+                ops.push(Op::PushNone);
+                ops.push(Op::PushNone);
+            }
+            _ => {
+                calls.push(None);
+                continue;
+            }
+        }
+
+        ops.push(Op::Push(device.address));
+
+        match device.class {
+            HubrisI2cDeviceClass::Pmbus { .. } => {
+                ops.push(Op::Push(PMBUS_MFR_ID));
+                ops.push(Op::PushNone);
+            }
+            _ => {
+                ops.push(Op::PushNone);
+                ops.push(Op::Push(1));
+            }
+        }
+
+        ops.push(Op::Call(func.id));
+        ops.push(Op::DropN(7));
+        calls.push(Some(nread));
+        nread += 1;
+    }
+
+    //
+    // If the image has QSPI support, also identify the flash part.
+    //
+    let qspi = funcs.0.get("QspiReadId").filter(|f| f.args.is_empty());
+
+    if let Some(qspi) = qspi {
+        ops.push(Op::Call(qspi.id));
+    }
+
+    ops.push(Op::Done);
+
+    let results = context.run(core, ops.as_slice(), None)?;
+    let mut failed = 0;
+
+    for (ndx, device) in devices.iter().enumerate() {
+        let checked = match calls[ndx] {
+            None => Err("mux and segment must both be specified".to_string()),
+            Some(call) => match &results[call] {
+                Ok(bytes) => identify(device, bytes),
+                Err(code) => Err(func.strerror(*code)),
+            },
+        };
+
+        let (result, detail) = match checked {
+            Ok(detail) => (format!("{:8}", "ok").green(), detail),
+            Err(err) => {
+                failed += 1;
+                (format!("{:8}", "FAIL").red(), format!(" ({})", err))
+            }
+        };
+
+        println!("{:2} {:>2} {:2} {:3} 0x{:02x} {:13} {} {}{}",
+            ndx, device.controller, device.port.name, mux(device),
+            device.address, device.device, result, device.description,
+            detail);
+    }
+
+    if let Some(qspi) = qspi {
+        let (result, detail) = match &results[nread] {
+            Ok(bytes) if bytes.iter().all(|&b| b == 0 || b == 0xff) => {
+                failed += 1;
+                (
+                    format!("{:8}", "FAIL").red(),
+                    format!("invalid ID {:x?}", bytes),
+                )
+            }
+            Ok(bytes) => (
+                format!("{:8}", "ok").green(),
+                format!("flash ID {:x?}", bytes),
+            ),
+            Err(code) => {
+                failed += 1;
+                (format!("{:8}", "FAIL").red(), qspi.strerror(*code))
+            }
+        };
+
+        println!("{:2} {:>2} {:2} {:3} {:4} {:13} {} {}",
+            "-", "-", "-", "-", "-", "qspi", result, detail);
+    }
+
+    if failed > 0 {
+        bail!("{} device(s) failed validation", failed);
+    }
+
+    Ok(())
+}
+
+pub fn init<'a, 'b>() -> (Command, App<'a, 'b>) {
+    (
+        Command::Attached {
+            name: "validate",
+            archive: Archive::Required,
+            attach: Attach::LiveOnly,
+            validate: Validate::Booted,
+            run: validate,
+        },
+        ValidateArgs::clap(),
+    )
+}
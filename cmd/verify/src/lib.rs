@@ -0,0 +1,110 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use anyhow::{bail, Result};
+use humility::core::Core;
+use humility::hubris::*;
+use humility_cmd::json::Json;
+use humility_cmd::{Archive, Args, Attach, Command, Validate};
+use structopt::clap::App;
+use structopt::StructOpt;
+
+#[derive(StructOpt, Debug)]
+#[structopt(
+    name = "verify",
+    about = "verify that the image on the target matches the archive"
+)]
+struct VerifyArgs {
+    /// compare all of each object's text rather than a sample of it
+    #[structopt(long, short)]
+    full: bool,
+
+    /// number of chunks of each object's text to compare
+    #[structopt(long, short = "n", default_value = "32", value_name = "n",
+        parse(try_from_str = parse_int::parse),
+    )]
+    samples: usize,
+}
+
+fn verify_json(regions: &[HubrisVerifyRegion]) -> Json {
+    Json::Array(
+        regions
+            .iter()
+            .map(|r| {
+                let mut members = vec![
+                    ("name", Json::from(&r.name)),
+                    ("base", Json::from(r.base)),
+                    ("size", Json::from(r.size)),
+                    ("compared", Json::from(r.compared)),
+                    ("match", Json::from(r.differs.is_none())),
+                ];
+
+                if let Some((addr, ndiffs)) = r.differs {
+                    members.push(("differs_at", Json::from(addr)));
+                    members.push(("differing_bytes", Json::from(ndiffs)));
+                }
+
+                Json::object(members)
+            })
+            .collect(),
+    )
+}
+
+#[rustfmt::skip::macros(println)]
+fn verify(
+    hubris: &mut HubrisArchive,
+    core: &mut dyn Core,
+    args: &Args,
+    subargs: &[String],
+) -> Result<()> {
+    let subargs = VerifyArgs::from_iter_safe(subargs)?;
+    let samples = if subargs.full { None } else { Some(subargs.samples) };
+
+    let regions = hubris.verify(core, samples)?;
+    let ndiffs = regions.iter().filter(|r| r.differs.is_some()).count();
+
+    if args.json() {
+        verify_json(&regions).print();
+    } else {
+        println!("{:16} {:>10} {:>8} {:>8} RESULT",
+            "REGION", "BASE", "SIZE", "COMPARED");
+
+        for r in &regions {
+            print!(
+                "{:16} 0x{:08x} {:>8x} {:>8x} ",
+                r.name, r.base, r.size, r.compared
+            );
+
+            match r.differs {
+                Some((addr, n)) => {
+                    println!("DIFFERS at 0x{:08x} ({} bytes)", addr, n);
+                }
+                None => println!("match"),
+            }
+        }
+    }
+
+    if ndiffs != 0 {
+        bail!(
+            "image on target does not match archive: {} of {} regions differ",
+            ndiffs,
+            regions.len()
+        );
+    }
+
+    Ok(())
+}
+
+pub fn init<'a, 'b>() -> (Command, App<'a, 'b>) {
+    (
+        Command::Attached {
+            name: "verify",
+            archive: Archive::Required,
+            attach: Attach::LiveOnly,
+            validate: Validate::None,
+            run: verify,
+        },
+        VerifyArgs::clap(),
+    )
+}
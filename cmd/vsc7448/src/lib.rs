@@ -3,6 +3,7 @@
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 use std::collections::HashMap;
 use std::convert::TryInto;
+use std::rc::Rc;
 
 use humility::core::Core;
 use humility::hubris::*;
@@ -114,7 +115,7 @@ struct Vsc7448<'a> {
     context: HiffyContext<'a>,
 
     task: HubrisTask,
-    funcs: HiffyFunctions,
+    funcs: Rc<HiffyFunctions>,
 }
 
 impl<'a> Vsc7448<'a> {
@@ -0,0 +1,406 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+#[macro_use]
+extern crate log;
+
+use anyhow::{bail, Context, Result};
+use humility::arch::ARMRegister;
+use humility::core::{Core, CoreWatch};
+use humility::hubris::*;
+use humility_cmd::{Archive, Args, Attach, Command, Validate};
+use humility_cortex::debug::*;
+use humility_cortex::dwt::DWTWatchpoints;
+use num_traits::FromPrimitive;
+use std::thread;
+use std::time::Duration;
+use structopt::clap::App;
+use structopt::StructOpt;
+
+#[derive(StructOpt, Debug)]
+#[structopt(
+    name = "watch",
+    about = "watch a register or memory location and act on a condition"
+)]
+struct WatchArgs {
+    /// interval at which to poll the target, in milliseconds
+    #[structopt(long, short, default_value = "100", value_name = "ms")]
+    interval: u64,
+
+    /// address (or name of variable) to watch
+    #[structopt(
+        long,
+        short,
+        value_name = "address",
+        required_unless = "register",
+        conflicts_with = "register"
+    )]
+    addr: Option<String>,
+
+    /// register to watch (halts the core to sample it)
+    #[structopt(long, short, value_name = "register")]
+    register: Option<String>,
+
+    /// mask to apply to the watched value before comparing it
+    #[structopt(long, short, default_value = "0xffffffff",
+        parse(try_from_str = parse_int::parse),
+    )]
+    mask: u32,
+
+    /// value that the masked value must equal (if not specified, any
+    /// change in the masked value matches)
+    #[structopt(long, short, value_name = "value",
+        parse(try_from_str = parse_int::parse),
+    )]
+    equals: Option<u32>,
+
+    /// action to take upon a match: halt, dump, reset, or "run <command>"
+    #[structopt(long, short, default_value = "halt", value_name = "action")]
+    then: WatchAction,
+
+    /// use a DWT watchpoint to halt on writes rather than polling
+    #[structopt(long, short, conflicts_with = "register")]
+    dwt: bool,
+
+    /// continue watching after taking the action
+    #[structopt(long, short = "R")]
+    repeat: bool,
+}
+
+#[derive(Clone, Debug)]
+enum WatchAction {
+    Halt,
+    Dump,
+    Reset,
+    Run(String),
+}
+
+impl std::str::FromStr for WatchAction {
+    type Err = anyhow::Error;
+
+    fn from_str(action: &str) -> Result<Self> {
+        match action.trim().split_once(char::is_whitespace) {
+            Some(("run", cmd)) => Ok(WatchAction::Run(cmd.trim().to_string())),
+            Some(_) => bail!("unknown action \"{}\"", action),
+            None => match action.trim() {
+                "halt" => Ok(WatchAction::Halt),
+                "dump" => Ok(WatchAction::Dump),
+                "reset" => Ok(WatchAction::Reset),
+                "run" => bail!("run action must specify a command"),
+                _ => bail!("unknown action \"{}\"", action),
+            },
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+enum WatchTarget {
+    Memory(u32),
+    Register(ARMRegister),
+}
+
+impl std::fmt::Display for WatchTarget {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            WatchTarget::Memory(addr) => write!(f, "0x{:08x}", addr),
+            WatchTarget::Register(reg) => write!(f, "{}", reg),
+        }
+    }
+}
+
+fn watch_target(
+    hubris: &HubrisArchive,
+    subargs: &WatchArgs,
+) -> Result<WatchTarget> {
+    if let Some(ref name) = subargs.register {
+        for i in 0..=ARMRegister::FPSCR as u16 {
+            if let Some(reg) = ARMRegister::from_u16(i) {
+                if format!("{:?}", reg).eq_ignore_ascii_case(name) {
+                    return Ok(WatchTarget::Register(reg));
+                }
+            }
+        }
+
+        bail!("unknown register \"{}\"", name);
+    }
+
+    let addr = subargs.addr.as_ref().unwrap();
+
+    let addr = match parse_int::parse::<u32>(addr) {
+        Ok(addr) => addr,
+        Err(_) => {
+            let var = hubris.lookup_variable(addr).with_context(|| {
+                format!("\"{}\" is neither an address nor a variable", addr)
+            })?;
+
+            if var.size != 4 {
+                warn!(
+                    "{} is {} bytes; watching its first word",
+                    addr, var.size
+                );
+            }
+
+            var.addr
+        }
+    };
+
+    if addr & 0b11 != 0 {
+        bail!("address 0x{:x} is not word-aligned", addr);
+    }
+
+    Ok(WatchTarget::Memory(addr))
+}
+
+fn watch_read(core: &mut dyn Core, target: WatchTarget) -> Result<u32> {
+    match target {
+        WatchTarget::Memory(addr) => core.read_word_32(addr),
+        WatchTarget::Register(reg) => {
+            core.halt()?;
+            let rval = core.read_reg(reg);
+            core.run()?;
+            rval
+        }
+    }
+}
+
+//
+// Takes the specified action.  The core may or may not be halted; it is
+// left in the state in which it was found unless the action is to halt or
+// reset it.
+//
+fn watch_act(
+    hubris: &HubrisArchive,
+    core: &mut dyn Core,
+    action: &WatchAction,
+    target: WatchTarget,
+    value: u32,
+    halted: bool,
+) -> Result<()> {
+    match action {
+        WatchAction::Halt => {
+            if !halted {
+                core.halt()?;
+            }
+
+            info!("core halted");
+        }
+
+        WatchAction::Dump => {
+            if !halted {
+                core.halt()?;
+            }
+
+            let rval = hubris.dump(core, None);
+
+            if !halted {
+                core.run()?;
+            }
+
+            rval?;
+        }
+
+        WatchAction::Reset => {
+            core.reset()?;
+            info!("core reset");
+        }
+
+        WatchAction::Run(cmd) => {
+            let status = std::process::Command::new("sh")
+                .arg("-c")
+                .arg(cmd)
+                .env("HUMILITY_WATCH_TARGET", target.to_string())
+                .env("HUMILITY_WATCH_VALUE", format!("0x{:x}", value))
+                .status()
+                .with_context(|| format!("failed to run \"{}\"", cmd))?;
+
+            if !status.success() {
+                warn!("\"{}\" failed: {}", cmd, status);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn watch(
+    hubris: &mut HubrisArchive,
+    core: &mut dyn Core,
+    _args: &Args,
+    subargs: &[String],
+) -> Result<()> {
+    let subargs = WatchArgs::from_iter_safe(subargs)?;
+    let interval = Duration::from_millis(subargs.interval);
+    let target = watch_target(hubris, &subargs)?;
+
+    if let WatchAction::Dump = subargs.then {
+        if !hubris.loaded() {
+            bail!("must specify a Hubris archive to dump");
+        }
+    }
+
+    if subargs.repeat {
+        if let WatchAction::Halt = subargs.then {
+            bail!("cannot continue watching after halting");
+        }
+    }
+
+    if let WatchTarget::Register(_) = target {
+        warn!("watching a register halts the core on each poll");
+    }
+
+    let mask = subargs.mask;
+
+    let matches = |last: Option<u32>, value: u32| match subargs.equals {
+        Some(equals) => value & mask == equals & mask,
+        None => last.map_or(false, |last| last & mask != value & mask),
+    };
+
+    let report = |value: u32| {
+        println!(
+            "{} = 0x{:08x} (masked 0x{:08x}): taking action {:?}",
+            target,
+            value,
+            value & mask,
+            subargs.then
+        );
+    };
+
+    if !subargs.dwt {
+        info!("watching {}; ^C to stop", target);
+
+        let mut last = None;
+        let mut matched = false;
+
+        loop {
+            let value = watch_read(core, target)?;
+
+            //
+            // We act on the transition to a match, not on every poll that
+            // matches.
+            //
+            if matches(last, value) {
+                if !matched {
+                    report(value);
+                    watch_act(
+                        hubris,
+                        core,
+                        &subargs.then,
+                        target,
+                        value,
+                        false,
+                    )?;
+
+                    if !subargs.repeat {
+                        return Ok(());
+                    }
+                }
+
+                matched = true;
+            } else {
+                matched = false;
+            }
+
+            last = Some(value);
+            thread::sleep(interval);
+        }
+    }
+
+    let addr = match target {
+        WatchTarget::Memory(addr) => addr,
+        WatchTarget::Register(_) => unreachable!(),
+    };
+
+    //
+    // Setting a watchpoint enables the DWT; we remember the original state
+    // of DEMCR to restore it when we're done.
+    //
+    let orig = DEMCR::read(core)?;
+    core.set_watchpoint(addr, CoreWatch::Write)?;
+
+    info!("watching writes to {} via DWT; ^C to stop", target);
+
+    let mut last = Some(core.read_word_32(addr)?);
+
+    let rval: Result<()> = (|| loop {
+        if !DHCSR::read(core)?.halted() {
+            thread::sleep(interval);
+            continue;
+        }
+
+        let dfsr = DFSR::read(core)?;
+
+        let matched = if dfsr.watchpoint() {
+            DWTWatchpoints::read(core)?.matched_watchpoint(core)?
+        } else {
+            None
+        };
+
+        if matched != Some(addr & !0b11) {
+            let pc = core.read_reg(ARMRegister::PC)?;
+            bail!(
+                "target halted at 0x{:x} on something other than a write",
+                pc
+            );
+        }
+
+        //
+        // Clear the watchpoint status (which is write-one-to-clear) so that
+        // we can distinguish the next one.
+        //
+        DFSR::from(u32::from(dfsr)).write(core)?;
+
+        let value = core.read_word_32(addr)?;
+        let pc = core.read_reg(ARMRegister::PC)?;
+
+        trace!("write to {} at pc 0x{:x}: 0x{:x}", target, pc, value);
+
+        if matches(last, value) {
+            report(value);
+
+            if let Some((name, base)) = hubris.instr_sym(pc) {
+                println!(
+                    "written near 0x{:08x} ({}+0x{:x})",
+                    pc,
+                    name,
+                    pc - base
+                );
+            }
+
+            watch_act(hubris, core, &subargs.then, target, value, true)?;
+
+            if !subargs.repeat {
+                return match subargs.then {
+                    WatchAction::Halt | WatchAction::Reset => Ok(()),
+                    _ => core.run(),
+                };
+            }
+
+            if let WatchAction::Reset = subargs.then {
+                last = None;
+                continue;
+            }
+        }
+
+        last = Some(value);
+        core.run()?;
+    })();
+
+    core.clear_watchpoint(addr)?;
+    orig.write(core)?;
+
+    rval
+}
+
+pub fn init<'a, 'b>() -> (Command, App<'a, 'b>) {
+    (
+        Command::Attached {
+            name: "watch",
+            archive: Archive::Optional,
+            attach: Attach::LiveOnly,
+            validate: Validate::None,
+            run: watch,
+        },
+        WatchArgs::clap(),
+    )
+}
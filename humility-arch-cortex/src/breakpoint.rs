@@ -0,0 +1,150 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//!
+//! Hardware breakpoints and watchpoints for cores that can access the FPB
+//! and DWT but have no notion of breakpoints themselves.  A
+//! [`BreakpointCore`] wraps such a core, implementing the breakpoint and
+//! watchpoint operations of the [`Core`] trait by programming the FPB and
+//! DWT directly; cores that do provide these operations (e.g., because a
+//! debugger that owns the comparators sits between us and the target) have
+//! them passed through.  All other operations are passed through to the
+//! wrapped core.
+//!
+
+use crate::dwt::DWTWatchpoints;
+use crate::fpb::FPB;
+use anyhow::Result;
+use humility::arch::ARMRegister;
+use humility::core::{Core, CoreOps, CoreWatch};
+use std::path::Path;
+
+pub struct BreakpointCore {
+    core: Box<dyn Core>,
+}
+
+impl BreakpointCore {
+    /// The operations that we need of the wrapped core
+    const NEEDS: CoreOps = CoreOps::READ.union(CoreOps::WRITE);
+
+    ///
+    /// Wraps the specified core, if it can program the FPB and DWT (that
+    /// is, if it can write memory) but can't set breakpoints itself.
+    ///
+    pub fn wrap(core: Box<dyn Core>) -> Box<dyn Core> {
+        let ops = core.ops();
+
+        if ops.contains(CoreOps::BREAKPOINTS) || !ops.contains(Self::NEEDS) {
+            core
+        } else {
+            Box::new(Self { core })
+        }
+    }
+}
+
+impl Core for BreakpointCore {
+    fn info(&self) -> (String, Option<String>) {
+        self.core.info()
+    }
+
+    fn read_word_32(&mut self, addr: u32) -> Result<u32> {
+        self.core.read_word_32(addr)
+    }
+
+    fn read_word_64(&mut self, addr: u32) -> Result<u64> {
+        self.core.read_word_64(addr)
+    }
+
+    fn read_8(&mut self, addr: u32, data: &mut [u8]) -> Result<()> {
+        self.core.read_8(addr, data)
+    }
+
+    fn read_reg(&mut self, reg: ARMRegister) -> Result<u32> {
+        self.core.read_reg(reg)
+    }
+
+    fn write_reg(&mut self, reg: ARMRegister, value: u32) -> Result<()> {
+        self.core.write_reg(reg, value)
+    }
+
+    fn init_swv(&mut self, traceclk: u32, baud: u32) -> Result<()> {
+        self.core.init_swv(traceclk, baud)
+    }
+
+    fn read_swv(&mut self) -> Result<Vec<u8>> {
+        self.core.read_swv()
+    }
+
+    fn write_word_32(&mut self, addr: u32, data: u32) -> Result<()> {
+        self.core.write_word_32(addr, data)
+    }
+
+    fn write_8(&mut self, addr: u32, data: &[u8]) -> Result<()> {
+        self.core.write_8(addr, data)
+    }
+
+    fn halt(&mut self) -> Result<()> {
+        self.core.halt()
+    }
+
+    fn run(&mut self) -> Result<()> {
+        self.core.run()
+    }
+
+    fn step(&mut self) -> Result<()> {
+        self.core.step()
+    }
+
+    fn is_dump(&self) -> bool {
+        self.core.is_dump()
+    }
+
+    fn ops(&self) -> CoreOps {
+        self.core.ops() | CoreOps::BREAKPOINTS
+    }
+
+    fn read_dp(&mut self, addr: u8) -> Result<u32> {
+        self.core.read_dp(addr)
+    }
+
+    fn write_dp(&mut self, addr: u8, value: u32) -> Result<()> {
+        self.core.write_dp(addr, value)
+    }
+
+    fn read_ap(&mut self, ap: u8, addr: u8) -> Result<u32> {
+        self.core.read_ap(ap, addr)
+    }
+
+    fn write_ap(&mut self, ap: u8, addr: u8, value: u32) -> Result<()> {
+        self.core.write_ap(ap, addr, value)
+    }
+
+    fn load(&mut self, path: &Path) -> Result<()> {
+        self.core.load(path)
+    }
+
+    fn reset(&mut self) -> Result<()> {
+        self.core.reset()
+    }
+
+    fn set_breakpoint(&mut self, addr: u32) -> Result<()> {
+        let core = self.core.as_mut();
+        FPB::read(core)?.set_breakpoint(core, addr)
+    }
+
+    fn clear_breakpoint(&mut self, addr: u32) -> Result<()> {
+        let core = self.core.as_mut();
+        FPB::read(core)?.clear_breakpoint(core, addr)
+    }
+
+    fn set_watchpoint(&mut self, addr: u32, watch: CoreWatch) -> Result<()> {
+        let core = self.core.as_mut();
+        DWTWatchpoints::read(core)?.set_watchpoint(core, addr, watch)
+    }
+
+    fn clear_watchpoint(&mut self, addr: u32) -> Result<()> {
+        let core = self.core.as_mut();
+        DWTWatchpoints::read(core)?.clear_watchpoint(core, addr)
+    }
+}
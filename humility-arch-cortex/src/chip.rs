@@ -0,0 +1,221 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//!
+//! Identification of the attached chip, allowing it to be checked against
+//! the chip for which a Hubris archive was built.  Chips are identified by
+//! their vendor-specific ID registers; where those registers don't
+//! distinguish among the parts of a line (as with the STM32H743 and the
+//! STM32H753, which share a device ID), we look for the features that tell
+//! them apart.
+//!
+
+use crate::debug::*;
+use crate::scs::*;
+use anyhow::{bail, Result};
+use humility::core::Core;
+use humility::hubris::HubrisArchive;
+
+//
+// The kernel features that denote a chip, and the boards for which the
+// chip is known.
+//
+const CHIP_FEATURES: &[(&str, &str)] =
+    &[("h743", "STM32H743"), ("h753", "STM32H753"), ("h7b3", "STM32H7B3")];
+
+const CHIP_BOARDS: &[(&str, &str)] = &[
+    ("stm32f4-discovery", "STM32F407"),
+    ("nucleo-h743zi2", "STM32H743"),
+    ("nucleo-h753zi", "STM32H753"),
+    ("stm32h7b3i-dk", "STM32H7B3"),
+    ("lpcxpresso55s69", "LPC55S69"),
+];
+
+//
+// The STM32 device IDs that we know, and the parts that each denotes.
+//
+const CHIP_STM32: &[(u32, &[&str])] = &[
+    (0x413, &["STM32F405", "STM32F407", "STM32F415", "STM32F417"]),
+    (0x419, &["STM32F427", "STM32F429", "STM32F437", "STM32F439"]),
+    (0x450, &["STM32H742", "STM32H743", "STM32H750", "STM32H753"]),
+    (0x480, &["STM32H7A3", "STM32H7B3", "STM32H7B0"]),
+];
+
+//
+// On the STM32H7, the parts with the cryptographic accelerator (the
+// STM32H75x) have an enable bit for it in RCC_AHB2ENR; on those without it
+// (the STM32H74x), the bit is reserved and reads as zero.
+//
+const STM32H7_RCC_AHB2ENR: u32 = 0x5802_44dc;
+const STM32H7_RCC_AHB2ENR_CRYPEN: u32 = 1 << 4;
+
+#[derive(Clone, Debug)]
+pub struct Chip {
+    pub name: String,
+    parts: Vec<&'static str>,
+}
+
+impl Chip {
+    /// Determines if the specified chip could be this one.
+    pub fn matches(&self, chip: &str) -> bool {
+        self.parts.iter().any(|p| chip.starts_with(p))
+    }
+}
+
+//
+// Determines if an STM32H7 has the cryptographic accelerator by setting its
+// enable bit and seeing if it sticks.  To not race with the target
+// modifying RCC_AHB2ENR itself, we do this with the core halted.  Because
+// this modifies target state (however briefly), it is only done when
+// explicitly requested.
+//
+fn stm32h7_crypto(core: &mut dyn Core) -> Result<bool> {
+    let halted = DHCSR::read(core)?.halted();
+
+    if !halted {
+        core.halt()?;
+    }
+
+    let rval: Result<bool> = (|| {
+        let orig = core.read_word_32(STM32H7_RCC_AHB2ENR)?;
+
+        if orig & STM32H7_RCC_AHB2ENR_CRYPEN != 0 {
+            return Ok(true);
+        }
+
+        let val = orig | STM32H7_RCC_AHB2ENR_CRYPEN;
+        core.write_word_32(STM32H7_RCC_AHB2ENR, val)?;
+        let readback = core.read_word_32(STM32H7_RCC_AHB2ENR)?;
+        core.write_word_32(STM32H7_RCC_AHB2ENR, orig)?;
+
+        Ok(readback & STM32H7_RCC_AHB2ENR_CRYPEN != 0)
+    })();
+
+    if !halted {
+        core.run()?;
+    }
+
+    rval
+}
+
+///
+/// Identifies the attached chip, returning `None` if it can't be identified.
+/// If a chip is specified, we will go to the additional effort (if any)
+/// required to determine if the attached chip is that part specifically --
+/// which may involve briefly halting the target and modifying its state.
+///
+pub fn chip_identify(
+    core: &mut dyn Core,
+    chip: Option<&str>,
+) -> Result<Option<Chip>> {
+    let (part, vendor, _) = CoreInfo::identify(core)?;
+
+    let devid = match (vendor, part) {
+        (Vendor::ST, ARMCore::CortexM4) => {
+            STM32F4_DBGMCU_IDCODE::read(core)?.dev_id()
+        }
+        (Vendor::ST, ARMCore::CortexM7) => {
+            STM32H7_DBGMCU_IDC::read(core)?.dev_id()
+        }
+        (Vendor::NXP, ARMCore::CortexM33) => {
+            //
+            // The LPC55 parts don't identify themselves beyond their line.
+            //
+            return Ok(Some(Chip {
+                name: "LPC55".to_string(),
+                parts: vec!["LPC55"],
+            }));
+        }
+        _ => return Ok(None),
+    };
+
+    let parts = match CHIP_STM32.iter().find(|(id, _)| *id == devid) {
+        Some((_, parts)) => parts.to_vec(),
+        None => return Ok(None),
+    };
+
+    //
+    // If we have been asked about a part that can't be distinguished by
+    // its device ID alone, look for its distinguishing features.
+    //
+    let specific = match chip {
+        Some(chip) if devid == 0x450 && chip.starts_with("STM32H7") => {
+            if stm32h7_crypto(core)? {
+                Some(vec!["STM32H750", "STM32H753"])
+            } else {
+                Some(vec!["STM32H742", "STM32H743"])
+            }
+        }
+        _ => None,
+    };
+
+    let parts = specific.unwrap_or(parts);
+
+    Ok(Some(Chip { name: parts.join("/"), parts }))
+}
+
+///
+/// Returns the chip for which the archive was built, if it can be
+/// determined from its kernel features or its board.
+///
+pub fn chip_archive(hubris: &HubrisArchive) -> Option<&'static str> {
+    for feature in hubris.features() {
+        if let Some((_, chip)) =
+            CHIP_FEATURES.iter().find(|(f, _)| *f == feature.as_str())
+        {
+            return Some(chip);
+        }
+    }
+
+    let board = hubris.board()?;
+
+    CHIP_BOARDS.iter().find(|(b, _)| *b == board).map(|(_, chip)| *chip)
+}
+
+///
+/// Checks the attached chip against the chip for which the archive was
+/// built, failing if they don't match.  If either chip can't be
+/// determined, the check is skipped.  By default, the check only reads the
+/// chip's ID registers, and therefore can't distinguish among parts that
+/// share a device ID; if `exact` is set, we will go to the (intrusive)
+/// effort of distinguishing them.
+///
+pub fn chip_check(
+    hubris: &HubrisArchive,
+    core: &mut dyn Core,
+    exact: bool,
+) -> Result<()> {
+    let expected = match chip_archive(hubris) {
+        Some(chip) => chip,
+        None => {
+            debug!("chip for archive unknown; not checking attached chip");
+            return Ok(());
+        }
+    };
+
+    let chip = match chip_identify(core, exact.then(|| expected)) {
+        Ok(Some(chip)) => chip,
+        Ok(None) => {
+            debug!("attached chip unknown; not checking against {}", expected);
+            return Ok(());
+        }
+        Err(err) if !exact => {
+            debug!("failed to identify attached chip: {}", err);
+            return Ok(());
+        }
+        Err(err) => return Err(err),
+    };
+
+    if !chip.matches(expected) {
+        bail!(
+            "archive is for {}, but attached chip is {}",
+            expected,
+            chip.name
+        );
+    }
+
+    debug!("attached chip ({}) matches archive ({})", chip.name, expected);
+
+    Ok(())
+}
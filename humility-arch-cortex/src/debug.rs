@@ -2,7 +2,7 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, bail, Result};
 
 use bitfield::bitfield;
 use humility::core::Core;
@@ -135,6 +135,22 @@ register!(CPUID, 0xe000_ed00,
     pub revision, _: 3, 0;
 );
 
+/*
+ * Application Interrupt and Reset Control Register
+ */
+register!(AIRCR, 0xe000_ed0c,
+    #[derive(Copy, Clone)]
+    pub struct AIRCR(u32);
+    impl Debug;
+    /// Must be written as 0x05fa for a write to take effect
+    pub vectkey, set_vectkey: 31, 16;
+    /// Request a system reset
+    pub sysresetreq, set_sysresetreq: 2;
+);
+
+/// The key that must be written to AIRCR
+pub const AIRCR_VECTKEY: u32 = 0x05fa;
+
 register!(CFSR, 0xe000_ed28,
     #[derive(Copy, Clone)]
     pub struct CFSR(u32);
@@ -169,6 +185,20 @@ register!(HFSR, 0xe000_ed2c,
     pub vector_fault, _: 1;
 );
 
+register!(MMFAR, 0xe000_ed34,
+    #[derive(Copy, Clone)]
+    pub struct MMFAR(u32);
+    impl Debug;
+    pub address, _: 31, 0;
+);
+
+register!(BFAR, 0xe000_ed38,
+    #[derive(Copy, Clone)]
+    pub struct BFAR(u32);
+    impl Debug;
+    pub address, _: 31, 0;
+);
+
 /*
  * Debug Fault Status Register
  */
@@ -273,7 +303,7 @@ register!(STM32F4_DBGMCU_CR, 0xe004_2004,
     pub trace_ioen, set_trace_ioen: 5;
     pub dbg_standby, _: 3;
     pub dbg_stop, _: 2;
-    pub dbg_sleep, _: 1;
+    pub dbg_sleep, set_dbg_sleep: 1;
 );
 
 register!(STM32H7_DBGMCU_IDC, 0x5c00_1000,
@@ -316,7 +346,7 @@ register!(STM32H7_DBGMCU_CR, 0x5c00_1004,
     pub dbgstop_cd, _: 1;
 
     /// CPU domain debug in Sleep mode
-    pub dbgsleep_cd, _: 0;
+    pub dbgsleep_cd, set_dbgsleep_cd: 0;
 );
 
 register!(LPC55_SYSCON_AHBCLKCTRL0, 0x5000_0200,
@@ -511,13 +541,52 @@ pub fn stm32_chipname(partno: u32) -> String {
     .to_string()
 }
 
-pub fn swoscaler(hubris: &HubrisArchive, core: &mut dyn Core) -> Result<u16> {
-    let debug_clock_mhz = 2_000_000;
+/// The SWO baud rate that we use absent any other specification
+pub const SWO_DEFAULT_BAUD: u32 = 2_000_000;
+
+//
+// The deviation from the desired baud rate (in parts per thousand) beyond
+// which we warn that the SWO output may not be reliably captured.
+//
+const SWO_BAUD_TOLERANCE: u64 = 30;
+
+///
+/// Determines the SWO prescaler for the specified trace clock (in kHz) and
+/// desired baud rate, returning the prescaler along with the baud rate
+/// that it will actually result in.
+///
+pub fn swoconfig(khz: u32, baud: u32) -> Result<(u16, u32)> {
+    let clock = khz as u64 * 1000;
+    let baud = baud as u64;
+
+    if baud == 0 || baud > clock {
+        bail!("cannot derive {} baud from a {} kHz clock", baud, khz);
+    }
+
+    let divisor = (clock + baud / 2) / baud;
+
+    if divisor > u16::MAX as u64 + 1 {
+        bail!("{} baud is too slow for a {} kHz clock", baud, khz);
+    }
+
+    let actual = clock / divisor;
+    let deviation = (actual as i64 - baud as i64).unsigned_abs();
 
+    if deviation * 1000 > baud * SWO_BAUD_TOLERANCE {
+        warn!(
+            "{} kHz clock results in {} baud rather than {}",
+            khz, actual, baud
+        );
+    }
+
+    Ok(((divisor - 1) as u16, actual as u32))
+}
+
+pub fn swoscaler(hubris: &HubrisArchive, core: &mut dyn Core) -> Result<u16> {
     match hubris.clock(core)? {
         None => Err(anyhow!(
             "clock couldn't be determined; set clock scaler explicitly"
         )),
-        Some(clock) => Ok(((clock * 1000) / debug_clock_mhz) as u16 - 1),
+        Some(clock) => Ok(swoconfig(clock, SWO_DEFAULT_BAUD)?.0),
     }
 }
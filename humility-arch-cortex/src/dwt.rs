@@ -2,10 +2,11 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
-use crate::debug::Register;
+use crate::debug::{Register, DEMCR};
 use crate::register;
+use anyhow::{bail, Result};
 use bitfield::bitfield;
-use humility::core::Core;
+use humility::core::{Core, CoreWatch};
 
 /*
  * DWT Control Register
@@ -26,12 +27,12 @@ register!(DWT_CTRL, 0xe000_1000,
     pub sleep_enabled, _: 19;
     pub exception_enabled, _: 18;
     pub cpi_enabled, _: 17;
-    pub exception_trace_enabled, _: 16;
-    pub pc_sampling_enabled, _: 12;
+    pub exception_trace_enabled, set_exception_trace_enabled: 16;
+    pub pc_sampling_enabled, set_pc_sampling_enabled: 12;
     pub _synctap, _set_synctap: 11, 10;
-    pub postcnt_tap, _: 9;
-    pub postcnt_init, _: 8, 5;
-    pub postcnt_reset, _: 4, 1;
+    pub postcnt_tap, set_postcnt_tap: 9;
+    pub postcnt_init, set_postcnt_init: 8, 5;
+    pub postcnt_reset, set_postcnt_reset: 4, 1;
     pub cyccnt_enabled, set_cyccnt_enabled: 0;
 );
 
@@ -54,3 +55,316 @@ impl DWT_CTRL {
         self._set_synctap(val);
     }
 }
+
+/*
+ * DWT Cycle Count Register
+ */
+register!(DWT_CYCCNT, 0xe000_1004,
+    #[derive(Copy, Clone)]
+    #[allow(non_camel_case_types)]
+    pub struct DWT_CYCCNT(u32);
+    impl Debug;
+    pub count, set_count: 31, 0;
+);
+
+/*
+ * DWT Program Counter Sample Register
+ */
+register!(DWT_PCSR, 0xe000_101c,
+    #[derive(Copy, Clone)]
+    #[allow(non_camel_case_types)]
+    pub struct DWT_PCSR(u32);
+    impl Debug;
+    pub pc, _: 31, 0;
+);
+
+/*
+ * DWT Device Architecture Register, which is only implemented by the
+ * ARMv8-M DWT (and reads as zero on ARMv7-M)
+ */
+register!(DWT_DEVARCH, 0xe000_1fbc,
+    #[derive(Copy, Clone)]
+    #[allow(non_camel_case_types)]
+    pub struct DWT_DEVARCH(u32);
+    impl Debug;
+    pub architect, _: 31, 21;
+    pub present, _: 20;
+    pub revision, _: 19, 16;
+    pub archid, _: 15, 0;
+);
+
+/// The architecture ID of the ARMv8-M DWT
+const DWT_DEVARCH_ARCHID_V8: u32 = 0x1a02;
+
+/// The address of the first comparator
+const DWT_COMP_BASE: u32 = 0xe000_1020;
+
+//
+// Each comparator consists of a comparator register, a mask register (on
+// ARMv7-M only) and a function register.
+//
+const DWT_COMP_STRIDE: u32 = 0x10;
+const DWT_MASK_OFFS: u32 = 0x4;
+const DWT_FUNCTION_OFFS: u32 = 0x8;
+
+/// The bits of a function register that are zero if a comparator is disabled
+const DWT_FUNCTION_MATCH: u32 = 0xf;
+
+/// The bit in a function register denoting that the comparator matched
+const DWT_FUNCTION_MATCHED: u32 = 1 << 24;
+
+#[derive(Copy, Clone, Debug)]
+pub struct DWTWatchpoints {
+    v8: bool,
+    ncomparators: u32,
+}
+
+impl DWTWatchpoints {
+    pub fn read(core: &mut dyn Core) -> Result<Self> {
+        let ctrl = DWT_CTRL::read(core)?;
+        let devarch = DWT_DEVARCH::read(core)?;
+
+        //
+        // ARMv8-M entirely redefined the function register; we determine
+        // which we have by the DWT's architecture, which only the ARMv8-M
+        // DWT identifies.
+        //
+        let v8 = devarch.present() && devarch.archid() == DWT_DEVARCH_ARCHID_V8;
+
+        Ok(Self { v8, ncomparators: ctrl.num_comparators() })
+    }
+
+    /// Returns the number of comparators.
+    pub fn ncomparators(&self) -> u32 {
+        self.ncomparators
+    }
+
+    fn base(&self, ndx: u32) -> Result<u32> {
+        if ndx >= self.ncomparators {
+            bail!(
+                "comparator {} exceeds {} comparators",
+                ndx,
+                self.ncomparators
+            );
+        }
+
+        Ok(DWT_COMP_BASE + ndx * DWT_COMP_STRIDE)
+    }
+
+    //
+    // Returns the value of a function register that halts the core on the
+    // specified kind of access to a word.
+    //
+    fn function(&self, watch: CoreWatch) -> u32 {
+        if self.v8 {
+            //
+            // On ARMv8-M, the function register has the match type in its
+            // low nibble, the action in bits 5:4 (0b01 denoting a debug
+            // event) and the size of the matched data in bits 11:10.
+            //
+            let matchtype = match watch {
+                CoreWatch::ReadWrite => 0b0100,
+                CoreWatch::Write => 0b0101,
+                CoreWatch::Read => 0b0110,
+            };
+
+            (0b10 << 10) | (0b01 << 4) | matchtype
+        } else {
+            match watch {
+                CoreWatch::Read => 0b0101,
+                CoreWatch::Write => 0b0110,
+                CoreWatch::ReadWrite => 0b0111,
+            }
+        }
+    }
+
+    /// Sets a watchpoint that halts the core on an access of the specified
+    /// kind to the word at the specified (word-aligned) address.  Note that
+    /// the DWT must be enabled via TRCENA in DEMCR.
+    pub fn set(
+        &self,
+        core: &mut dyn Core,
+        ndx: u32,
+        addr: u32,
+        watch: CoreWatch,
+    ) -> Result<()> {
+        let base = self.base(ndx)?;
+
+        if addr & 0b11 != 0 {
+            bail!("watchpoint address 0x{:x} is not word-aligned", addr);
+        }
+
+        //
+        // On ARMv7-M, we mask off the low two bits of the address to match
+        // any access to the word.
+        //
+        if !self.v8 {
+            core.write_word_32(base + DWT_MASK_OFFS, 2)?;
+        }
+
+        core.write_word_32(base, addr)?;
+        core.write_word_32(base + DWT_FUNCTION_OFFS, self.function(watch))
+    }
+
+    /// Returns true if the specified comparator has matched since it was
+    /// last checked.  (Reading the function register clears its matched
+    /// bit.)
+    pub fn matched(&self, core: &mut dyn Core, ndx: u32) -> Result<bool> {
+        let base = self.base(ndx)?;
+        let function = core.read_word_32(base + DWT_FUNCTION_OFFS)?;
+        Ok(function & DWT_FUNCTION_MATCHED != 0)
+    }
+
+    /// Clears the watchpoint (if any) on the specified comparator.
+    pub fn clear(&self, core: &mut dyn Core, ndx: u32) -> Result<()> {
+        let base = self.base(ndx)?;
+        core.write_word_32(base + DWT_FUNCTION_OFFS, 0)
+    }
+
+    /// Returns the address of the watchpoint set on the specified
+    /// comparator (if any), along with whether it has matched since it was
+    /// last checked.
+    pub fn watchpoint(
+        &self,
+        core: &mut dyn Core,
+        ndx: u32,
+    ) -> Result<Option<(u32, bool)>> {
+        let base = self.base(ndx)?;
+        let function = core.read_word_32(base + DWT_FUNCTION_OFFS)?;
+
+        if function & DWT_FUNCTION_MATCH == 0 {
+            return Ok(None);
+        }
+
+        let addr = core.read_word_32(base)?;
+        Ok(Some((addr, function & DWT_FUNCTION_MATCHED != 0)))
+    }
+
+    ///
+    /// Sets a watchpoint on the word containing the specified address via
+    /// the first comparator that isn't in use, enabling the DWT.  As with
+    /// [`FPB::set_breakpoint`], we don't track which comparators are in use,
+    /// instead taking any that is disabled.
+    ///
+    /// [`FPB::set_breakpoint`]: crate::fpb::FPB::set_breakpoint
+    ///
+    pub fn set_watchpoint(
+        &self,
+        core: &mut dyn Core,
+        addr: u32,
+        watch: CoreWatch,
+    ) -> Result<()> {
+        let addr = addr & !0b11;
+        let mut free = None;
+
+        for ndx in 0..self.ncomparators {
+            match self.watchpoint(core, ndx)? {
+                Some((wp, _)) if wp == addr => {
+                    bail!("watchpoint already set on 0x{:x}", addr);
+                }
+                Some(_) => {}
+                None => free = free.or(Some(ndx)),
+            }
+        }
+
+        let ndx = match free {
+            Some(ndx) => ndx,
+            None if self.ncomparators == 0 => bail!("DWT has no comparators"),
+            None => bail!("all {} watchpoints in use", self.ncomparators),
+        };
+
+        let mut demcr = DEMCR::read(core)?;
+        demcr.set_trcena(true);
+        demcr.write(core)?;
+
+        self.set(core, ndx, addr, watch)
+    }
+
+    /// Clears the watchpoint on the word containing the specified address.
+    pub fn clear_watchpoint(
+        &self,
+        core: &mut dyn Core,
+        addr: u32,
+    ) -> Result<()> {
+        let addr = addr & !0b11;
+
+        for ndx in 0..self.ncomparators {
+            if let Some((wp, _)) = self.watchpoint(core, ndx)? {
+                if wp == addr {
+                    return self.clear(core, ndx);
+                }
+            }
+        }
+
+        bail!("no watchpoint set on 0x{:x}", addr);
+    }
+
+    /// Returns the address of the watchpoint that has matched since it was
+    /// last checked, if any.
+    pub fn matched_watchpoint(
+        &self,
+        core: &mut dyn Core,
+    ) -> Result<Option<u32>> {
+        for ndx in 0..self.ncomparators {
+            if let Some((addr, true)) = self.watchpoint(core, ndx)? {
+                return Ok(Some(addr));
+            }
+        }
+
+        Ok(None)
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum DWTExceptionFunction {
+    Entered,
+    Exited,
+    Returned,
+}
+
+/*
+ * A packet generated by the DWT, as decoded from the discriminator and
+ * payload of an ITM hardware source packet.
+ */
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum DWTPacket {
+    EventCounter { counters: u8 },
+    Exception { number: u16, function: DWTExceptionFunction },
+    PCSample { pc: Option<u32> },
+    DataTrace { comparator: u8, kind: u8, value: u32 },
+}
+
+impl DWTPacket {
+    pub fn decode(source: u32, payload: &[u8]) -> Option<DWTPacket> {
+        let word = |payload: &[u8]| {
+            payload
+                .iter()
+                .enumerate()
+                .fold(0u32, |val, (i, b)| val | (*b as u32) << (i * 8))
+        };
+
+        match (source, payload.len()) {
+            (0, 1) => Some(DWTPacket::EventCounter { counters: payload[0] }),
+            (1, 2) => {
+                let number = payload[0] as u16 | (payload[1] as u16 & 1) << 8;
+
+                let function = match (payload[1] >> 4) & 0b11 {
+                    0b01 => DWTExceptionFunction::Entered,
+                    0b10 => DWTExceptionFunction::Exited,
+                    0b11 => DWTExceptionFunction::Returned,
+                    _ => return None,
+                };
+
+                Some(DWTPacket::Exception { number, function })
+            }
+            (2, 1) => Some(DWTPacket::PCSample { pc: None }),
+            (2, 4) => Some(DWTPacket::PCSample { pc: Some(word(payload)) }),
+            (8..=23, _) => Some(DWTPacket::DataTrace {
+                comparator: ((source >> 1) & 0b11) as u8,
+                kind: ((source & 0b1) | ((source >> 3) & 0b1) << 1) as u8,
+                value: word(payload),
+            }),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,485 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//!
+//! Export of ITM and DWT trace -- exception entry and exit, PC samples, and
+//! stimulus port data -- into formats that can be consumed by standard trace
+//! viewers.  Two formats are supported:
+//!
+//! - Perfetto, by way of the Chrome JSON trace event format (which Perfetto
+//!   natively ingests).  Exceptions are displayed as slices, PC samples and
+//!   stimulus port data as instant events.
+//!
+//! - Common Trace Format (CTF), as a trace directory that contains a TSDL
+//!   `metadata` file and a single binary `stream` file, as consumed by
+//!   Babeltrace and Trace Compass.
+//!
+//! In both cases, events are written as they arrive:  because ingesting from
+//! an attached device runs until interrupted, an export must remain valid
+//! even if it is never explicitly finished.  (The JSON trace event format
+//! explicitly allows a missing closing bracket, and a CTF stream without a
+//! packet context consists of a single packet that spans the file.)
+//!
+//! Timestamps are taken from the time at which each packet was received.
+//!
+
+use crate::dwt::{DWTExceptionFunction, DWTPacket};
+use crate::itm::{ITMHeader, ITMPacket, ITMPayload};
+use anyhow::{bail, Context, Result};
+use humility::hubris::HubrisArchive;
+use humility::json::Json;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::str::FromStr;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum TraceFormat {
+    Perfetto,
+    Ctf,
+}
+
+impl FromStr for TraceFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "perfetto" => Ok(TraceFormat::Perfetto),
+            "ctf" => Ok(TraceFormat::Ctf),
+            _ => bail!("unknown trace format \"{}\"", s),
+        }
+    }
+}
+
+//
+// Our CTF event identifiers, which must match our metadata.
+//
+const CTF_EXCEPTION_ENTRY: u32 = 0;
+const CTF_EXCEPTION_EXIT: u32 = 1;
+const CTF_PC_SAMPLE: u32 = 2;
+const CTF_STIMULUS: u32 = 3;
+const CTF_OVERFLOW: u32 = 4;
+
+const CTF_MAGIC: u32 = 0xc1fc_1fc1;
+
+const CTF_METADATA: &str = r#"/* CTF 1.8 */
+
+typealias integer { size = 8; align = 8; signed = false; } := uint8_t;
+typealias integer { size = 16; align = 8; signed = false; } := uint16_t;
+typealias integer { size = 32; align = 8; signed = false; } := uint32_t;
+typealias integer { size = 64; align = 8; signed = false; } := uint64_t;
+
+trace {
+    major = 1;
+    minor = 8;
+    byte_order = le;
+    packet.header := struct {
+        uint32_t magic;
+        uint32_t stream_id;
+    };
+};
+
+env {
+    domain = "humility";
+    tracer_name = "humility-itm";
+};
+
+clock {
+    name = host;
+    description = "time of receipt by humility";
+    freq = 1000000000;
+};
+
+typealias integer {
+    size = 64; align = 8; signed = false; map = clock.host.value;
+} := uint64_clock_t;
+
+stream {
+    id = 0;
+    event.header := struct {
+        uint32_t id;
+        uint64_clock_t timestamp;
+    };
+};
+
+event {
+    name = "exception_entry";
+    id = 0;
+    stream_id = 0;
+    fields := struct {
+        uint16_t number;
+        string name;
+    };
+};
+
+event {
+    name = "exception_exit";
+    id = 1;
+    stream_id = 0;
+    fields := struct {
+        uint16_t number;
+        string name;
+    };
+};
+
+event {
+    name = "pc_sample";
+    id = 2;
+    stream_id = 0;
+    fields := struct {
+        uint8_t sleeping;
+        uint32_t pc;
+        string symbol;
+    };
+};
+
+event {
+    name = "stimulus";
+    id = 3;
+    stream_id = 0;
+    fields := struct {
+        uint8_t port;
+        uint32_t len;
+        uint8_t data[len];
+    };
+};
+
+event {
+    name = "overflow";
+    id = 4;
+    stream_id = 0;
+    fields := struct {
+        uint32_t offset;
+    };
+};
+"#;
+
+//
+// Our Perfetto thread identifiers:  exceptions and PC samples each have
+// their own track, with a track per stimulus port after that.
+//
+const PERFETTO_PID: u32 = 1;
+const PERFETTO_EXCEPTIONS: u32 = 1;
+const PERFETTO_SAMPLES: u32 = 2;
+const PERFETTO_PORTS: u32 = 3;
+
+pub struct TraceExporter<'a> {
+    hubris: &'a HubrisArchive,
+    format: TraceFormat,
+    out: BufWriter<File>,
+    irqs: HashMap<u32, String>,
+    lines: HashMap<u32, Vec<u8>>,
+    ports: Vec<bool>,
+    events: u64,
+}
+
+impl<'a> TraceExporter<'a> {
+    ///
+    /// Creates an exporter of the specified format.  For Perfetto, `path`
+    /// is the JSON file to create; for CTF, it is a directory that will be
+    /// created (if need be) to contain the trace.
+    ///
+    pub fn new(
+        hubris: &'a HubrisArchive,
+        format: TraceFormat,
+        path: &str,
+    ) -> Result<Self> {
+        let out = match format {
+            TraceFormat::Perfetto => File::create(path)
+                .with_context(|| format!("failed to create {}", path))?,
+            TraceFormat::Ctf => {
+                let dir = Path::new(path);
+
+                fs::create_dir_all(dir)
+                    .with_context(|| format!("failed to create {}", path))?;
+                fs::write(dir.join("metadata"), CTF_METADATA)?;
+                File::create(dir.join("stream"))?
+            }
+        };
+
+        //
+        // Build a map of IRQ to owning task, so we can display interrupts
+        // in terms of the tasks that field them.
+        //
+        let mut irqs = HashMap::new();
+
+        for (task, owned) in &hubris.manifest.task_irqs {
+            for (_, irq) in owned {
+                irqs.insert(*irq, task.clone());
+            }
+        }
+
+        let mut exporter = Self {
+            hubris,
+            format,
+            out: BufWriter::new(out),
+            irqs,
+            lines: HashMap::new(),
+            ports: vec![false; 32],
+            events: 0,
+        };
+
+        match format {
+            TraceFormat::Perfetto => {
+                writeln!(exporter.out, "[")?;
+                exporter.thread(PERFETTO_EXCEPTIONS, "exceptions")?;
+                exporter.thread(PERFETTO_SAMPLES, "PC samples")?;
+            }
+            TraceFormat::Ctf => {
+                exporter.out.write_all(&CTF_MAGIC.to_le_bytes())?;
+                exporter.out.write_all(&0u32.to_le_bytes())?;
+            }
+        }
+
+        exporter.out.flush()?;
+
+        Ok(exporter)
+    }
+
+    fn exception_name(&self, number: u16) -> String {
+        match number {
+            0 => "Thread".to_string(),
+            1 => "Reset".to_string(),
+            2 => "NMI".to_string(),
+            3 => "HardFault".to_string(),
+            4 => "MemManage".to_string(),
+            5 => "BusFault".to_string(),
+            6 => "UsageFault".to_string(),
+            11 => "SVCall".to_string(),
+            12 => "DebugMonitor".to_string(),
+            14 => "PendSV".to_string(),
+            15 => "SysTick".to_string(),
+            n if n >= 16 => {
+                let irq = n as u32 - 16;
+
+                match self.irqs.get(&irq) {
+                    Some(task) => format!("IRQ {} ({})", irq, task),
+                    None => format!("IRQ {}", irq),
+                }
+            }
+            n => format!("exception {}", n),
+        }
+    }
+
+    fn symbol(&self, pc: u32) -> String {
+        let module = match self.hubris.instr_mod(pc) {
+            Some(module) if module != "kernel" => format!("{}:", module),
+            _ => "".to_string(),
+        };
+
+        match self.hubris.instr_sym(pc) {
+            Some((name, addr)) => {
+                format!("{}{}+0x{:x}", module, name, pc - addr)
+            }
+            None => format!("{}0x{:08x}", module, pc),
+        }
+    }
+
+    fn thread(&mut self, tid: u32, name: &str) -> Result<()> {
+        let event = Json::object(vec![
+            ("name", Json::from("thread_name")),
+            ("ph", Json::from("M")),
+            ("pid", Json::from(PERFETTO_PID)),
+            ("tid", Json::from(tid)),
+            ("args", Json::object(vec![("name", Json::from(name))])),
+        ]);
+
+        writeln!(self.out, "{},", event)?;
+
+        Ok(())
+    }
+
+    fn perfetto(
+        &mut self,
+        ph: &str,
+        tid: u32,
+        name: &str,
+        time: f64,
+        args: Option<Json>,
+    ) -> Result<()> {
+        //
+        // Timestamps are in microseconds; we round them to nanoseconds.
+        //
+        let mut members = vec![
+            ("name", Json::from(name)),
+            ("ph", Json::from(ph)),
+            ("ts", Json::from((time * 1_000_000_000.0).round() / 1000.0)),
+            ("pid", Json::from(PERFETTO_PID)),
+            ("tid", Json::from(tid)),
+        ];
+
+        if ph == "i" {
+            members.push(("s", Json::from("t")));
+        }
+
+        if let Some(args) = args {
+            members.push(("args", args));
+        }
+
+        writeln!(self.out, "{},", Json::object(members))?;
+        self.events += 1;
+
+        Ok(())
+    }
+
+    fn ctf(&mut self, id: u32, time: f64, fields: &[u8]) -> Result<()> {
+        let ns = (time * 1_000_000_000.0) as u64;
+
+        self.out.write_all(&id.to_le_bytes())?;
+        self.out.write_all(&ns.to_le_bytes())?;
+        self.out.write_all(fields)?;
+        self.events += 1;
+
+        Ok(())
+    }
+
+    fn exception(
+        &mut self,
+        number: u16,
+        function: DWTExceptionFunction,
+        time: f64,
+    ) -> Result<()> {
+        let name = self.exception_name(number);
+
+        match (self.format, function) {
+            //
+            // A return denotes the resumption of a preempted exception (or
+            // of thread mode), which is implied by the preceding exit.
+            //
+            (_, DWTExceptionFunction::Returned) => Ok(()),
+            (TraceFormat::Perfetto, f) => {
+                let ph =
+                    if f == DWTExceptionFunction::Entered { "B" } else { "E" };
+                self.perfetto(ph, PERFETTO_EXCEPTIONS, &name, time, None)
+            }
+            (TraceFormat::Ctf, f) => {
+                let id = if f == DWTExceptionFunction::Entered {
+                    CTF_EXCEPTION_ENTRY
+                } else {
+                    CTF_EXCEPTION_EXIT
+                };
+
+                let mut fields = number.to_le_bytes().to_vec();
+                fields.extend(name.as_bytes());
+                fields.push(0);
+                self.ctf(id, time, &fields)
+            }
+        }
+    }
+
+    fn sample(&mut self, pc: Option<u32>, time: f64) -> Result<()> {
+        let symbol = match pc {
+            Some(pc) => self.symbol(pc),
+            None => "<sleeping>".to_string(),
+        };
+
+        match self.format {
+            TraceFormat::Perfetto => {
+                let args = pc.map(|pc| {
+                    let pc = format!("0x{:08x}", pc);
+                    Json::object(vec![("pc", Json::from(pc))])
+                });
+                self.perfetto("i", PERFETTO_SAMPLES, &symbol, time, args)
+            }
+            TraceFormat::Ctf => {
+                let mut fields = vec![pc.is_none() as u8];
+                fields.extend(pc.unwrap_or(0).to_le_bytes());
+                fields.extend(symbol.as_bytes());
+                fields.push(0);
+                self.ctf(CTF_PC_SAMPLE, time, &fields)
+            }
+        }
+    }
+
+    fn stimulus(&mut self, port: u32, payload: &[u8], time: f64) -> Result<()> {
+        if self.format == TraceFormat::Ctf {
+            let mut fields = vec![port as u8];
+            fields.extend((payload.len() as u32).to_le_bytes());
+            fields.extend(payload);
+            return self.ctf(CTF_STIMULUS, time, &fields);
+        }
+
+        if !self.ports[port as usize] {
+            self.ports[port as usize] = true;
+            let name = format!("stimulus port {}", port);
+            self.thread(PERFETTO_PORTS + port, &name)?;
+        }
+
+        let tid = PERFETTO_PORTS + port;
+
+        //
+        // Ports 0 and 1 are used for text; we accumulate their output into
+        // lines, emitting an event for each.  Data on any other port is
+        // emitted an event per packet.
+        //
+        if port > 1 {
+            let name = format!("{:x?}", payload);
+            return self.perfetto("i", tid, &name, time, None);
+        }
+
+        let line = self.lines.entry(port).or_insert_with(Vec::new);
+        let mut text = vec![];
+
+        line.extend(payload);
+
+        while let Some(pos) = line.iter().position(|&c| c == b'\n') {
+            let l: String = line.drain(..=pos).map(|c| c as char).collect();
+            text.push(l.trim_end().to_string());
+        }
+
+        for l in text {
+            self.perfetto("i", tid, &l, time, None)?;
+        }
+
+        Ok(())
+    }
+
+    pub fn packet(&mut self, packet: &ITMPacket) -> Result<()> {
+        let time = packet.time;
+
+        match &packet.payload {
+            ITMPayload::Instrumentation { port, payload } => {
+                self.stimulus(*port, payload, time)?;
+            }
+            ITMPayload::Hardware { source, payload, len } => {
+                match DWTPacket::decode(*source, &payload[..*len]) {
+                    Some(DWTPacket::Exception { number, function }) => {
+                        self.exception(number, function, time)?;
+                    }
+                    Some(DWTPacket::PCSample { pc }) => {
+                        self.sample(pc, time)?;
+                    }
+                    _ => {}
+                }
+            }
+            ITMPayload::None if packet.header == ITMHeader::Overflow => {
+                match self.format {
+                    TraceFormat::Perfetto => {
+                        let tid = PERFETTO_EXCEPTIONS;
+                        self.perfetto("i", tid, "overflow", time, None)?;
+                    }
+                    TraceFormat::Ctf => {
+                        let offs = (packet.offset as u32).to_le_bytes();
+                        self.ctf(CTF_OVERFLOW, time, &offs)?;
+                    }
+                }
+            }
+            _ => {}
+        }
+
+        //
+        // We flush every event under the assumption that we will be
+        // interrupted rather than finished.
+        //
+        self.out.flush()?;
+
+        Ok(())
+    }
+
+    /// Returns the number of events that have been exported.
+    pub fn events(&self) -> u64 {
+        self.events
+    }
+}
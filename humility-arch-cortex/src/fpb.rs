@@ -0,0 +1,206 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//!
+//! The Flash Patch and Breakpoint (FPB) unit, which provides hardware
+//! breakpoints on instruction addresses.  There are two revisions of the
+//! FPB:  the original (found on ARMv7-M) can only break on addresses in
+//! the code region (that is, below 0x2000_0000), while the second (found on
+//! ARMv8-M) can break on any address.
+//!
+
+use crate::debug::Register;
+use crate::register;
+use anyhow::{bail, Result};
+use bitfield::bitfield;
+use humility::core::Core;
+
+/*
+ * Flash Patch Control Register
+ */
+register!(FP_CTRL, 0xe000_2000,
+    #[derive(Copy, Clone)]
+    #[allow(non_camel_case_types)]
+    pub struct FP_CTRL(u32);
+    impl Debug;
+    pub rev, _: 31, 28;
+    pub num_code_hi, _: 14, 12;
+    pub num_lit, _: 11, 8;
+    pub num_code_lo, _: 7, 4;
+    pub key, set_key: 1;
+    pub enable, set_enable: 0;
+);
+
+/// The address of the first comparator
+const FP_COMP_BASE: u32 = 0xe000_2008;
+
+/// The end of the code region, beyond which an FPBv1 cannot break
+const FP_V1_LIMIT: u32 = 0x2000_0000;
+
+#[derive(Copy, Clone, Debug)]
+pub struct FPB {
+    rev: u32,
+    ncomparators: u32,
+}
+
+impl FPB {
+    pub fn read(core: &mut dyn Core) -> Result<Self> {
+        let ctrl = FP_CTRL::read(core)?;
+
+        Ok(Self {
+            rev: ctrl.rev(),
+            ncomparators: (ctrl.num_code_hi() << 4) | ctrl.num_code_lo(),
+        })
+    }
+
+    /// Returns the number of instruction address comparators.
+    pub fn ncomparators(&self) -> u32 {
+        self.ncomparators
+    }
+
+    fn write_ctrl(core: &mut dyn Core, enable: bool) -> Result<()> {
+        let mut ctrl = FP_CTRL(0);
+
+        //
+        // The key must be set for a write to the register to take effect.
+        //
+        ctrl.set_key(true);
+        ctrl.set_enable(enable);
+        ctrl.write(core)
+    }
+
+    pub fn enable(&self, core: &mut dyn Core) -> Result<()> {
+        Self::write_ctrl(core, true)
+    }
+
+    pub fn disable(&self, core: &mut dyn Core) -> Result<()> {
+        Self::write_ctrl(core, false)
+    }
+
+    /// Sets a breakpoint on the specified address via the specified
+    /// comparator.
+    pub fn set(&self, core: &mut dyn Core, ndx: u32, addr: u32) -> Result<()> {
+        if ndx >= self.ncomparators {
+            bail!(
+                "comparator {} exceeds {} comparators",
+                ndx,
+                self.ncomparators
+            );
+        }
+
+        let val = match self.rev {
+            0 => {
+                if addr >= FP_V1_LIMIT {
+                    bail!("cannot set breakpoint on 0x{:x}", addr);
+                }
+
+                //
+                // The REPLACE field denotes which halfword of the word
+                // matched by the comparator is to have a breakpoint.
+                //
+                let replace = if addr & 0b10 == 0 { 0b01 } else { 0b10 };
+                (replace << 30) | (addr & 0x1fff_fffc) | 1
+            }
+            1 => (addr & !1) | 1,
+            rev => bail!("unsupported FPB revision {}", rev),
+        };
+
+        core.write_word_32(FP_COMP_BASE + ndx * 4, val)
+    }
+
+    /// Clears the breakpoint (if any) on the specified comparator.
+    pub fn clear(&self, core: &mut dyn Core, ndx: u32) -> Result<()> {
+        core.write_word_32(FP_COMP_BASE + ndx * 4, 0)
+    }
+
+    /// Returns the address of the breakpoint set on the specified
+    /// comparator, if any.
+    pub fn breakpoint(
+        &self,
+        core: &mut dyn Core,
+        ndx: u32,
+    ) -> Result<Option<u32>> {
+        let val = core.read_word_32(FP_COMP_BASE + ndx * 4)?;
+
+        if val & 1 == 0 {
+            return Ok(None);
+        }
+
+        Ok(Some(match self.rev {
+            0 => (val & 0x1fff_fffc) | if val >> 30 == 0b10 { 0b10 } else { 0 },
+            _ => val & !1,
+        }))
+    }
+
+    ///
+    /// Sets a breakpoint on the specified address via the first comparator
+    /// that isn't in use, enabling the FPB.  We don't track which
+    /// comparators are in use, instead taking any that is disabled -- so
+    /// breakpoints set by one client of the core can be cleared by another.
+    ///
+    pub fn set_breakpoint(&self, core: &mut dyn Core, addr: u32) -> Result<()> {
+        let addr = addr & !1;
+        let mut free = None;
+
+        for ndx in 0..self.ncomparators {
+            match self.breakpoint(core, ndx)? {
+                Some(bp) if bp == addr => {
+                    bail!("breakpoint already set on 0x{:x}", addr);
+                }
+                Some(_) => {}
+                None => free = free.or(Some(ndx)),
+            }
+        }
+
+        match free {
+            Some(ndx) => {
+                self.set(core, ndx, addr)?;
+                self.enable(core)
+            }
+            None => bail!("all {} breakpoints in use", self.ncomparators),
+        }
+    }
+
+    /// Clears the breakpoint on the specified address, disabling the FPB if
+    /// no other breakpoints remain.
+    pub fn clear_breakpoint(
+        &self,
+        core: &mut dyn Core,
+        addr: u32,
+    ) -> Result<()> {
+        let addr = addr & !1;
+        let mut found = false;
+        let mut remaining = false;
+
+        for ndx in 0..self.ncomparators {
+            match self.breakpoint(core, ndx)? {
+                Some(bp) if bp == addr => {
+                    self.clear(core, ndx)?;
+                    found = true;
+                }
+                Some(_) => remaining = true,
+                None => {}
+            }
+        }
+
+        if !found {
+            bail!("no breakpoint set on 0x{:x}", addr);
+        }
+
+        if !remaining {
+            self.disable(core)?;
+        }
+
+        Ok(())
+    }
+
+    /// Clears all breakpoints.
+    pub fn clear_all(&self, core: &mut dyn Core) -> Result<()> {
+        for ndx in 0..self.ncomparators {
+            self.clear(core, ndx)?;
+        }
+
+        Ok(())
+    }
+}
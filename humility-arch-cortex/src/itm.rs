@@ -8,10 +8,12 @@ use crate::register;
 use crate::scs::*;
 use crate::swo::*;
 use crate::tpiu::*;
-use anyhow::Result;
+use anyhow::{bail, Result};
 use bitfield::bitfield;
 use humility::core::Core;
 use humility::hubris::HubrisArchive;
+use std::thread;
+use std::time::{Duration, Instant};
 
 /*
  * ITM Trace Enable Register
@@ -114,7 +116,6 @@ pub enum ITMPayload {
         port: u32,
         payload: Vec<u8>,
     },
-    #[allow(dead_code)]
     Hardware {
         source: u32,
         payload: [u8; 4],
@@ -294,6 +295,17 @@ fn itm_payload_decode(hdr: ITMHeader, payload: &[u8]) -> ITMPayload {
             payload: payload.to_vec(),
         },
 
+        ITMHeader::Hardware { a, .. } => {
+            let mut buf = [0u8; 4];
+            buf[..payload.len()].copy_from_slice(payload);
+
+            ITMPayload::Hardware {
+                source: a as u32,
+                payload: buf,
+                len: payload.len(),
+            }
+        }
+
         ITMHeader::LocalTimestamp1 { tc } => {
             let mut delta: u32 = 0;
 
@@ -573,8 +585,156 @@ pub fn itm_enable_explicit(
 }
 
 ///
-/// Enables ITM by pulling clock scaler values from the specified Hubris
-/// archive.
+/// Enables the forwarding of DWT packets via the ITM:  exception trace and
+/// (if a rate is specified) periodic PC sampling.  The PC sampling rate is
+/// expressed as the number of CYCCNT ticks between samples, and must be a
+/// multiple of 64 (if less than or equal to 1024) or 1024 (if greater),
+/// and no more than 16K.  This must be called after the ITM is enabled.
+pub fn itm_enable_dwt(
+    core: &mut dyn Core,
+    exceptions: bool,
+    pcsample: Option<u32>,
+) -> Result<()> {
+    let mut dwt = DWT_CTRL::read(core)?;
+
+    if pcsample.is_some() && dwt.no_trace_sampling() {
+        bail!("PC sampling is not supported on this part");
+    }
+
+    dwt.set_exception_trace_enabled(exceptions);
+    dwt.set_pc_sampling_enabled(false);
+
+    if let Some(rate) = pcsample {
+        let (tap, shift) = if rate <= 1024 { (false, 6) } else { (true, 10) };
+
+        if rate == 0 || rate % (1 << shift) != 0 || rate > 16 * 1024 {
+            bail!("invalid PC sampling rate {}", rate);
+        }
+
+        dwt.set_postcnt_tap(tap);
+        dwt.set_postcnt_init((rate >> shift) - 1);
+        dwt.set_postcnt_reset((rate >> shift) - 1);
+        dwt.set_cyccnt_enabled(true);
+    }
+
+    dwt.write(core)?;
+
+    if let Some(_rate) = pcsample {
+        //
+        // The counter must be configured before PC sampling is enabled.
+        //
+        let mut dwt = DWT_CTRL::read(core)?;
+        dwt.set_pc_sampling_enabled(true);
+        dwt.write(core)?;
+    }
+
+    let mut tcr = ITM_TCR::read(core)?;
+    tcr.set_dwt_enable(exceptions || pcsample.is_some());
+    tcr.write(core)?;
+
+    Ok(())
+}
+
+//
+// The period over which we measure the trace clock, if we must.
+//
+const ITM_TRACECLOCK_PERIOD: Duration = Duration::from_millis(100);
+
+///
+/// Measures the core clock (in kHz) by comparing the progress of the DWT
+/// cycle counter to that of the host's clock.  To prevent the cycle counter
+/// from stopping while the core is sleeping, we (temporarily) keep the core
+/// clocked in sleep on those parts that allow for it.  If the core is
+/// halted, it is run for the duration of the measurement.
+///
+pub fn itm_traceclock_measure(
+    core: &mut dyn Core,
+    coreinfo: &CoreInfo,
+) -> Result<u32> {
+    let mut demcr = DEMCR::read(core)?;
+    demcr.set_trcena(true);
+    demcr.write(core)?;
+
+    let mut ctrl = DWT_CTRL::read(core)?;
+
+    if ctrl.no_cycle_counter() {
+        bail!("clock can't be measured; set clock scaler explicitly");
+    }
+
+    ctrl.set_cyccnt_enabled(true);
+    ctrl.write(core)?;
+
+    let restore = match (coreinfo.vendor, coreinfo.part) {
+        (Vendor::ST, ARMCore::CortexM4) => {
+            let orig = STM32F4_DBGMCU_CR::read(core)?;
+            let mut cr = orig;
+            cr.set_dbg_sleep(true);
+            cr.write(core)?;
+            Some(u32::from(orig))
+        }
+        (Vendor::ST, ARMCore::CortexM7) => {
+            let orig = STM32H7_DBGMCU_CR::read(core)?;
+            let mut cr = orig;
+            cr.set_dbgsleep_cd(true);
+            cr.write(core)?;
+            Some(u32::from(orig))
+        }
+        _ => None,
+    };
+
+    let halted = DHCSR::read(core)?.halted();
+
+    if halted {
+        core.run()?;
+    }
+
+    let start = (Instant::now(), DWT_CYCCNT::read(core)?.count());
+    thread::sleep(ITM_TRACECLOCK_PERIOD);
+    let end = (Instant::now(), DWT_CYCCNT::read(core)?.count());
+
+    if halted {
+        core.halt()?;
+    }
+
+    match (restore, coreinfo.part) {
+        (Some(cr), ARMCore::CortexM4) => STM32F4_DBGMCU_CR(cr).write(core)?,
+        (Some(cr), ARMCore::CortexM7) => STM32H7_DBGMCU_CR(cr).write(core)?,
+        _ => {}
+    }
+
+    let cycles = end.1.wrapping_sub(start.1) as u128;
+    let elapsed = (end.0 - start.0).as_micros();
+
+    if cycles == 0 || elapsed == 0 {
+        bail!("clock can't be measured; set clock scaler explicitly");
+    }
+
+    Ok(((cycles * 1000) / elapsed) as u32)
+}
+
+///
+/// Determines the trace clock (in kHz):  if the Hubris archive specifies
+/// the clock frequency, it is used; otherwise, the clock is measured.
+///
+pub fn itm_traceclock(
+    core: &mut dyn Core,
+    coreinfo: &CoreInfo,
+    hubris: &HubrisArchive,
+) -> Result<u32> {
+    if let Some(khz) = hubris.clock(core)? {
+        info!("trace clock is {} kHz", khz);
+        return Ok(khz);
+    }
+
+    let khz = itm_traceclock_measure(core, coreinfo)?;
+    info!("trace clock measured at {} kHz", khz);
+
+    Ok(khz)
+}
+
+///
+/// Enables ITM by deriving the clock scaler from the trace clock (either as
+/// specified in the Hubris archive or as measured).
 pub fn itm_enable_ingest(
     core: &mut dyn Core,
     hubris: &HubrisArchive,
@@ -583,13 +743,16 @@ pub fn itm_enable_ingest(
     let coreinfo = CoreInfo::read(core)?;
 
     let _info = core.halt();
-    core.init_swv()?;
 
     /*
-     * Pull our clock scaler from the Hubris archive -- and set our traceid
-     * to be a recognizable value.
+     * Derive our clock scaler from the trace clock, and capture at the
+     * baud rate that it yields -- and set our traceid to be a recognizable
+     * value.
      */
-    let clockscaler = swoscaler(hubris, core)?;
+    let khz = itm_traceclock(core, &coreinfo, hubris)?;
+    let (clockscaler, baud) = swoconfig(khz, SWO_DEFAULT_BAUD)?;
+    core.init_swv(khz * 1000, baud)?;
+
     let traceid = 0x3a;
 
     itm_enable_explicit(core, &coreinfo, clockscaler, traceid, stim)?;
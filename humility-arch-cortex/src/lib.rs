@@ -2,11 +2,16 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
+pub mod breakpoint;
+pub mod chip;
 pub mod debug;
 pub mod dwt;
 pub mod etm;
+pub mod export;
+pub mod fpb;
 pub mod itm;
 pub mod scs;
+pub mod semihosting;
 pub mod swo;
 pub mod tpiu;
 
@@ -408,11 +408,16 @@ pub struct CoreInfo {
 }
 
 impl CoreInfo {
-    pub fn read(core: &mut dyn humility::core::Core) -> Result<Self> {
+    ///
+    /// Identifies the core and its vendor (returning the ROM table that
+    /// identifies the latter) without walking the ROM tables.
+    ///
+    pub fn identify(
+        core: &mut dyn humility::core::Core,
+    ) -> Result<(ARMCore, Vendor, CoreSightPage)> {
         use num_traits::FromPrimitive;
 
         let cpuid = CPUID::read(core)?;
-        let mut components = MultiMap::new();
 
         let part = match ARMCore::from_u32(cpuid.partno()) {
             Some(part) => part,
@@ -435,6 +440,14 @@ impl CoreInfo {
             _ => Vendor::Other,
         };
 
+        Ok((part, vendor, id))
+    }
+
+    pub fn read(core: &mut dyn humility::core::Core) -> Result<Self> {
+        let (part, vendor, id) = Self::identify(core)?;
+        let rom = id.base;
+        let mut components = MultiMap::new();
+
         if vendor == Vendor::ST && part == ARMCore::CortexM7 {
             /*
              * Before we can walk the M7's ROM tables, we need to make sure
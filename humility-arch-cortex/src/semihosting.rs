@@ -0,0 +1,315 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//!
+//! Support for ARM semihosting, whereby code on the target makes requests
+//! of the debugger by executing `BKPT 0xab` with an operation in `r0` and
+//! its parameter (often a pointer to a parameter block) in `r1`.  When the
+//! core is halted on such a breakpoint, [`Semihost::handle`] performs the
+//! operation, places the result in `r0` and advances the PC beyond the
+//! breakpoint; the caller is responsible for resuming the core.
+//!
+//! We support the console operations (`SYS_WRITEC`, `SYS_WRITE0`,
+//! `SYS_WRITE` and `SYS_READ`/`SYS_READC`, along with opening the special
+//! `:tt` file), the clock operations, and `SYS_EXIT`; other operations
+//! (including opening any other file) fail.
+//!
+
+use crate::debug::{DFSR, DHCSR};
+use anyhow::{bail, Result};
+use humility::arch::ARMRegister;
+use humility::core::Core;
+use std::io::{Read, Write};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+/// The encoding of `BKPT 0xab`
+const SEMIHOSTING_BKPT: u16 = 0xbeab;
+
+const SYS_OPEN: u32 = 0x01;
+const SYS_CLOSE: u32 = 0x02;
+const SYS_WRITEC: u32 = 0x03;
+const SYS_WRITE0: u32 = 0x04;
+const SYS_WRITE: u32 = 0x05;
+const SYS_READ: u32 = 0x06;
+const SYS_READC: u32 = 0x07;
+const SYS_ISTTY: u32 = 0x09;
+const SYS_CLOCK: u32 = 0x10;
+const SYS_TIME: u32 = 0x11;
+const SYS_ERRNO: u32 = 0x13;
+const SYS_EXIT: u32 = 0x18;
+const SYS_EXIT_EXTENDED: u32 = 0x20;
+
+/// The reason passed to `SYS_EXIT` for a normal application exit
+const ADP_STOPPED_APPLICATIONEXIT: u32 = 0x20026;
+
+//
+// The handles that we return when opening `:tt`:  by convention, `:tt` is
+// opened for reading to get stdin, for writing to get stdout, and for
+// appending to get stderr.
+//
+const SEMIHOSTING_STDIN: u32 = 1;
+const SEMIHOSTING_STDOUT: u32 = 2;
+const SEMIHOSTING_STDERR: u32 = 3;
+
+/// The largest string that we will read for `SYS_WRITE0`
+const SEMIHOSTING_MAXSTR: u32 = 4096;
+
+///
+/// The largest buffer that we will read or write on behalf of the target:
+/// lengths come from target memory, and a corrupt one must not have us
+/// allocating (or reading) gigabytes.
+///
+const SEMIHOSTING_MAXLEN: u32 = 64 * 1024;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum SemihostExit {
+    /// The application exited with the specified code
+    Exited(u32),
+
+    /// The application stopped for the specified (abnormal) reason
+    Stopped(u32),
+}
+
+pub struct Semihost {
+    input: Option<Box<dyn Read>>,
+    started: Instant,
+}
+
+fn check_len(len: u32) -> Result<()> {
+    if len > SEMIHOSTING_MAXLEN {
+        bail!(
+            "semihosting length {} exceeds maximum of {}",
+            len,
+            SEMIHOSTING_MAXLEN
+        );
+    }
+
+    Ok(())
+}
+
+fn read_string(core: &mut dyn Core, addr: u32, len: u32) -> Result<Vec<u8>> {
+    check_len(len)?;
+
+    let mut buf = vec![0u8; len as usize];
+    core.read_8(addr, &mut buf)?;
+    Ok(buf)
+}
+
+fn read_params<const N: usize>(
+    core: &mut dyn Core,
+    addr: u32,
+) -> Result<[u32; N]> {
+    let mut params = [0u32; N];
+
+    for (i, param) in params.iter_mut().enumerate() {
+        *param = core.read_word_32(addr + (i as u32) * 4)?;
+    }
+
+    Ok(params)
+}
+
+///
+/// Determines if the core is halted on a semihosting breakpoint.
+///
+pub fn semihosting_pending(core: &mut dyn Core) -> Result<bool> {
+    if !DHCSR::read(core)?.halted() || !DFSR::read(core)?.breakpoint() {
+        return Ok(false);
+    }
+
+    let pc = core.read_reg(ARMRegister::PC)?;
+    let mut instr = [0u8; 2];
+    core.read_8(pc & !1, &mut instr)?;
+
+    Ok(u16::from_le_bytes(instr) == SEMIHOSTING_BKPT)
+}
+
+impl Semihost {
+    ///
+    /// Creates a semihosting handler; if `input` is specified, it will be
+    /// used to satisfy reads from the console.  (Otherwise, such reads will
+    /// return end-of-file.)
+    ///
+    pub fn new(input: Option<Box<dyn Read>>) -> Self {
+        Self { input, started: Instant::now() }
+    }
+
+    fn write(&mut self, handle: u32, buf: &[u8]) -> Result<()> {
+        if handle == SEMIHOSTING_STDERR {
+            let mut stderr = std::io::stderr();
+            stderr.write_all(buf)?;
+            stderr.flush()?;
+        } else {
+            let mut stdout = std::io::stdout();
+            stdout.write_all(buf)?;
+            stdout.flush()?;
+        }
+
+        Ok(())
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        match self.input {
+            Some(ref mut input) => Ok(input.read(buf)?),
+            None => Ok(0),
+        }
+    }
+
+    //
+    // Performs the specified operation, returning the value for r0 (and
+    // whether the application has exited).
+    //
+    fn operation(
+        &mut self,
+        core: &mut dyn Core,
+        op: u32,
+        param: u32,
+    ) -> Result<(u32, Option<SemihostExit>)> {
+        let rval = match op {
+            SYS_OPEN => {
+                let [name, mode, len] = read_params::<3>(core, param)?;
+                let name = read_string(core, name, len)?;
+
+                if name == b":tt" {
+                    match mode {
+                        0..=3 => SEMIHOSTING_STDIN,
+                        4..=7 => SEMIHOSTING_STDOUT,
+                        _ => SEMIHOSTING_STDERR,
+                    }
+                } else {
+                    warn!(
+                        "semihosting: failing open of \"{}\"",
+                        String::from_utf8_lossy(&name)
+                    );
+                    u32::MAX
+                }
+            }
+
+            SYS_CLOSE | SYS_ERRNO => 0,
+
+            SYS_ISTTY => 1,
+
+            SYS_WRITEC => {
+                let c = read_string(core, param, 1)?;
+                self.write(SEMIHOSTING_STDOUT, &c)?;
+                0
+            }
+
+            SYS_WRITE0 => {
+                let mut s = vec![];
+                let mut addr = param;
+
+                while s.len() < SEMIHOSTING_MAXSTR as usize {
+                    let chunk = read_string(core, addr, 64)?;
+
+                    match chunk.iter().position(|&c| c == 0) {
+                        Some(nul) => {
+                            s.extend_from_slice(&chunk[..nul]);
+                            break;
+                        }
+                        None => s.extend_from_slice(&chunk),
+                    }
+
+                    addr += 64;
+                }
+
+                self.write(SEMIHOSTING_STDOUT, &s)?;
+                0
+            }
+
+            SYS_WRITE => {
+                let [handle, buf, len] = read_params::<3>(core, param)?;
+                let buf = read_string(core, buf, len)?;
+                self.write(handle, &buf)?;
+
+                // SYS_WRITE returns the number of bytes *not* written
+                0
+            }
+
+            SYS_READ => {
+                let [_, buf, len] = read_params::<3>(core, param)?;
+                check_len(len)?;
+
+                let mut data = vec![0u8; len as usize];
+                let nread = self.read(&mut data)?;
+                core.write_8(buf, &data[..nread])?;
+
+                // SYS_READ returns the number of bytes *not* read
+                len - nread as u32
+            }
+
+            SYS_READC => {
+                let mut c = [0u8; 1];
+
+                match self.read(&mut c)? {
+                    0 => u32::MAX,
+                    _ => c[0] as u32,
+                }
+            }
+
+            SYS_CLOCK => (self.started.elapsed().as_millis() / 10) as u32,
+
+            SYS_TIME => SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs() as u32)
+                .unwrap_or(0),
+
+            SYS_EXIT | SYS_EXIT_EXTENDED => {
+                let (reason, code) = if op == SYS_EXIT {
+                    (param, 0)
+                } else {
+                    let [reason, code] = read_params::<2>(core, param)?;
+                    (reason, code)
+                };
+
+                let exit = if reason == ADP_STOPPED_APPLICATIONEXIT {
+                    SemihostExit::Exited(code)
+                } else {
+                    SemihostExit::Stopped(reason)
+                };
+
+                return Ok((0, Some(exit)));
+            }
+
+            _ => {
+                warn!("semihosting: unsupported operation 0x{:x}", op);
+                u32::MAX
+            }
+        };
+
+        Ok((rval, None))
+    }
+
+    ///
+    /// Handles a semihosting request on a core that is halted on a
+    /// semihosting breakpoint (as determined by [`semihosting_pending`]),
+    /// returning the exit status if the application has exited.  Unless the
+    /// application has exited, the PC is advanced beyond the breakpoint,
+    /// allowing the core to be resumed.
+    ///
+    pub fn handle(
+        &mut self,
+        core: &mut dyn Core,
+    ) -> Result<Option<SemihostExit>> {
+        let op = core.read_reg(ARMRegister::R0)?;
+        let param = core.read_reg(ARMRegister::R1)?;
+
+        trace!("semihosting: op 0x{:x}, param 0x{:x}", op, param);
+
+        let (rval, exit) = self.operation(core, op, param)?;
+
+        //
+        // Clear the breakpoint status (the DFSR is write-one-to-clear) so
+        // that we can distinguish our next breakpoint.
+        //
+        DFSR::from(0x1f).write(core)?;
+
+        if exit.is_none() {
+            let pc = core.read_reg(ARMRegister::PC)?;
+            core.write_reg(ARMRegister::R0, rval)?;
+            core.write_reg(ARMRegister::PC, pc + 2)?;
+        }
+
+        Ok(exit)
+    }
+}
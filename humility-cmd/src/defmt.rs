@@ -0,0 +1,681 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//!
+//! Decoding of defmt-encoded log frames.  Rather than formatting log
+//! messages on the target, defmt sends the index of an interned format
+//! string along with the binary encoding of its arguments; the format
+//! strings themselves are found in the names of symbols in the `.defmt`
+//! section of the object (see `HubrisArchive::lookup_defmt`).  A
+//! [`DefmtTable`] is constructed from these, and a [`DefmtDecoder`] then
+//! decodes frames from a stream of bytes (e.g., from ITM).
+//!
+//! This supports the defmt 0.3 wire format, in either its raw or rzCOBS
+//! encoding; types whose encoding isn't self-describing (bitfields, most
+//! notably) are not supported, and result in the frame being dropped.
+//!
+
+use anyhow::{anyhow, bail, Result};
+use humility::hubris::{HubrisArchive, HubrisDefmt};
+use std::collections::{BTreeMap, HashMap};
+use std::convert::TryInto;
+use std::fmt;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum DefmtEncoding {
+    Raw,
+    Rzcobs,
+}
+
+#[derive(Clone, Debug)]
+struct DefmtEntry {
+    tag: String,
+    format: String,
+}
+
+#[derive(Clone, Debug)]
+pub struct DefmtTable {
+    entries: HashMap<u16, DefmtEntry>,
+    timestamp: Option<String>,
+    encoding: DefmtEncoding,
+}
+
+///
+/// A decoded defmt frame.
+///
+#[derive(Clone, Debug)]
+pub struct DefmtFrame {
+    pub level: Option<&'static str>,
+    pub timestamp: Option<String>,
+    pub message: String,
+}
+
+impl fmt::Display for DefmtFrame {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(ref timestamp) = self.timestamp {
+            write!(f, "{} ", timestamp)?;
+        }
+
+        if let Some(level) = self.level {
+            write!(f, "{:<5} ", level)?;
+        }
+
+        write!(f, "{}", self.message)
+    }
+}
+
+//
+// The error returned when a frame is cut short; when decoding the raw
+// encoding, this merely indicates that we need more data.
+//
+#[derive(Debug)]
+struct Truncated;
+
+impl fmt::Display for Truncated {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "truncated defmt frame")
+    }
+}
+
+impl std::error::Error for Truncated {}
+
+//
+// The symbols in the defmt table are JSON objects with string values; we
+// parse just enough JSON to extract them.
+//
+fn parse_symbol(sym: &str) -> Option<HashMap<String, String>> {
+    let mut chars = sym.trim().chars().peekable();
+    let mut rval = HashMap::new();
+
+    fn string(
+        chars: &mut std::iter::Peekable<std::str::Chars>,
+    ) -> Option<String> {
+        let mut s = String::new();
+
+        if chars.next()? != '"' {
+            return None;
+        }
+
+        loop {
+            match chars.next()? {
+                '"' => return Some(s),
+                '\\' => match chars.next()? {
+                    'n' => s.push('\n'),
+                    't' => s.push('\t'),
+                    'r' => s.push('\r'),
+                    'u' => {
+                        let hex: String = chars.by_ref().take(4).collect();
+                        let c = u32::from_str_radix(&hex, 16).ok()?;
+                        s.push(char::from_u32(c)?);
+                    }
+                    c => s.push(c),
+                },
+                c => s.push(c),
+            }
+        }
+    }
+
+    if chars.next()? != '{' {
+        return None;
+    }
+
+    loop {
+        while chars.peek()?.is_whitespace() || *chars.peek()? == ',' {
+            chars.next();
+        }
+
+        if *chars.peek()? == '}' {
+            return Some(rval);
+        }
+
+        let key = string(&mut chars)?;
+
+        while chars.peek()?.is_whitespace() || *chars.peek()? == ':' {
+            chars.next();
+        }
+
+        let val = string(&mut chars)?;
+        rval.insert(key, val);
+    }
+}
+
+impl DefmtTable {
+    pub fn new(defmt: &HubrisDefmt) -> Result<Self> {
+        let encoding = match defmt.encoding.as_deref() {
+            Some("raw") => DefmtEncoding::Raw,
+            Some("rzcobs") | None => DefmtEncoding::Rzcobs,
+            Some(encoding) => bail!("unsupported defmt encoding {}", encoding),
+        };
+
+        let mut entries = HashMap::new();
+        let mut timestamp = None;
+
+        for (ndx, sym) in &defmt.table {
+            let mut fields = match parse_symbol(sym) {
+                Some(fields) => fields,
+                None => continue,
+            };
+
+            let tag = fields.remove("tag").unwrap_or_default();
+            let format = fields.remove("data").unwrap_or_default();
+
+            if tag == "defmt_timestamp" {
+                timestamp = Some(format);
+            } else {
+                entries.insert(*ndx, DefmtEntry { tag, format });
+            }
+        }
+
+        if entries.is_empty() {
+            bail!("defmt table is empty");
+        }
+
+        Ok(Self { entries, timestamp, encoding })
+    }
+
+    ///
+    /// Returns the table of the specified task (or, if no task is specified,
+    /// of the only task that uses defmt), along with the task's name.
+    ///
+    #[rustfmt::skip::macros(bail)]
+    pub fn lookup(
+        hubris: &HubrisArchive,
+        task: Option<&str>,
+    ) -> Result<(String, Self)> {
+        let task = match task {
+            Some(name) => match hubris.lookup_task(name) {
+                Some(task) => *task,
+                None => bail!("unknown task \"{}\"", name),
+            },
+            None => match hubris.defmt_tasks().as_slice() {
+                [task] => *task,
+                [] => bail!("no task in the archive uses defmt"),
+                _ => {
+                    bail!("multiple tasks use defmt; a task must be specified")
+                }
+            },
+        };
+
+        match hubris.lookup_defmt(task) {
+            Some(defmt) => {
+                let name = hubris.lookup_module(task)?.name.clone();
+                Ok((name, Self::new(defmt)?))
+            }
+            None => bail!("task {} does not use defmt", task),
+        }
+    }
+
+    pub fn encoding(&self) -> DefmtEncoding {
+        self.encoding
+    }
+
+    fn entry(&self, ndx: u16) -> Result<&DefmtEntry> {
+        self.entries
+            .get(&ndx)
+            .ok_or_else(|| anyhow!("unknown defmt index {}", ndx))
+    }
+
+    //
+    // Decodes a single frame from the front of the specified buffer,
+    // returning it and the number of bytes consumed.
+    //
+    fn decode(&self, buf: &[u8]) -> Result<(DefmtFrame, usize)> {
+        let mut r = Reader { buf, pos: 0 };
+        let entry = self.entry(r.u16()?)?;
+
+        let level = match entry.tag.as_str() {
+            "defmt_trace" => Some("TRACE"),
+            "defmt_debug" => Some("DEBUG"),
+            "defmt_info" => Some("INFO"),
+            "defmt_warn" => Some("WARN"),
+            "defmt_error" => Some("ERROR"),
+            _ => None,
+        };
+
+        let timestamp = match self.timestamp {
+            Some(ref format) => Some(self.format(&mut r, format)?),
+            None => None,
+        };
+
+        let message = self.format(&mut r, &entry.format)?;
+
+        Ok((DefmtFrame { level, timestamp, message }, r.pos))
+    }
+
+    //
+    // Decodes the arguments of the specified format string, returning the
+    // formatted result.
+    //
+    fn format(&self, r: &mut Reader, format: &str) -> Result<String> {
+        let pieces = parse_format(format)?;
+        let mut types = BTreeMap::new();
+
+        for piece in &pieces {
+            if let Piece::Param { index, ty, .. } = piece {
+                types.entry(*index).or_insert(ty.as_str());
+            }
+        }
+
+        //
+        // Arguments are encoded in the order of their indices, with each
+        // argument encoded once no matter how many times it is used.
+        //
+        let mut values = HashMap::new();
+
+        for (index, ty) in types {
+            values.insert(index, self.value(r, ty)?);
+        }
+
+        let mut rval = String::new();
+
+        for piece in &pieces {
+            match piece {
+                Piece::Literal(s) => rval.push_str(s),
+                Piece::Param { index, hint, .. } => {
+                    values[index].render(hint.as_deref(), &mut rval);
+                }
+            }
+        }
+
+        Ok(rval)
+    }
+
+    fn value(&self, r: &mut Reader, ty: &str) -> Result<Value> {
+        Ok(match ty {
+            "u8" => Value::Unsigned(r.u8()? as u128),
+            "u16" => Value::Unsigned(r.u16()? as u128),
+            "u32" => Value::Unsigned(r.u32()? as u128),
+            "u64" => Value::Unsigned(r.u64()? as u128),
+            "u128" => Value::Unsigned(r.u128()?),
+            "usize" => Value::Unsigned(r.leb()? as u128),
+            "i8" => Value::Signed(r.u8()? as i8 as i128),
+            "i16" => Value::Signed(r.u16()? as i16 as i128),
+            "i32" => Value::Signed(r.u32()? as i32 as i128),
+            "i64" => Value::Signed(r.u64()? as i64 as i128),
+            "i128" => Value::Signed(r.u128()? as i128),
+            "isize" => {
+                let v = r.leb()?;
+                Value::Signed(((v >> 1) as i64 ^ -((v & 1) as i64)) as i128)
+            }
+            "f32" => Value::Float(f32::from_bits(r.u32()?) as f64),
+            "f64" => Value::Float(f64::from_bits(r.u64()?)),
+            "bool" => Value::Bool(r.u8()? != 0),
+            "char" => {
+                let c = r.u32()?;
+                Value::Char(
+                    char::from_u32(c).unwrap_or(char::REPLACEMENT_CHARACTER),
+                )
+            }
+            "str" => {
+                let len = r.leb()? as usize;
+                Value::Str(String::from_utf8_lossy(r.bytes(len)?).to_string())
+            }
+            "istr" => {
+                let entry = self.entry(r.u16()?)?;
+                Value::Str(entry.format.clone())
+            }
+            "__internal_Display" | "__internal_Debug" => {
+                let mut s = vec![];
+
+                loop {
+                    match r.u8()? {
+                        0xff => break,
+                        b => s.push(b),
+                    }
+                }
+
+                Value::Str(String::from_utf8_lossy(&s).to_string())
+            }
+            "[u8]" => {
+                let len = r.leb()? as usize;
+                Value::Bytes(r.bytes(len)?.to_vec())
+            }
+            "?" => {
+                let entry = self.entry(r.u16()?)?;
+                Value::Formatted(self.format(r, &entry.format)?)
+            }
+            "[?]" => {
+                let len = r.leb()? as usize;
+                let mut list = vec![];
+
+                for _ in 0..len {
+                    let entry = self.entry(r.u16()?)?;
+                    list.push(self.format(r, &entry.format)?);
+                }
+
+                Value::List(list)
+            }
+            _ => {
+                if let Some(n) = ty
+                    .strip_prefix("[u8;")
+                    .and_then(|n| n.strip_suffix(']'))
+                    .and_then(|n| n.trim().parse::<usize>().ok())
+                {
+                    Value::Bytes(r.bytes(n)?.to_vec())
+                } else {
+                    bail!("unsupported defmt type \"{}\"", ty);
+                }
+            }
+        })
+    }
+}
+
+struct Reader<'b> {
+    buf: &'b [u8],
+    pos: usize,
+}
+
+impl<'b> Reader<'b> {
+    fn bytes(&mut self, n: usize) -> Result<&'b [u8]> {
+        if self.pos + n > self.buf.len() {
+            return Err(Truncated.into());
+        }
+
+        let rval = &self.buf[self.pos..self.pos + n];
+        self.pos += n;
+        Ok(rval)
+    }
+
+    fn u8(&mut self) -> Result<u8> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16> {
+        Ok(u16::from_le_bytes(self.bytes(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64> {
+        Ok(u64::from_le_bytes(self.bytes(8)?.try_into().unwrap()))
+    }
+
+    fn u128(&mut self) -> Result<u128> {
+        Ok(u128::from_le_bytes(self.bytes(16)?.try_into().unwrap()))
+    }
+
+    fn leb(&mut self) -> Result<u64> {
+        let mut rval = 0u64;
+
+        for shift in (0..64).step_by(7) {
+            let b = self.u8()?;
+            rval |= ((b & 0x7f) as u64) << shift;
+
+            if b & 0x80 == 0 {
+                return Ok(rval);
+            }
+        }
+
+        bail!("malformed LEB128 value");
+    }
+}
+
+#[derive(Clone, Debug)]
+enum Value {
+    Unsigned(u128),
+    Signed(i128),
+    Float(f64),
+    Bool(bool),
+    Char(char),
+    Str(String),
+    Bytes(Vec<u8>),
+    Formatted(String),
+    List(Vec<String>),
+}
+
+impl Value {
+    fn render(&self, hint: Option<&str>, out: &mut String) {
+        use std::fmt::Write;
+
+        let integer = |v: u128, out: &mut String| match hint {
+            Some("x") => write!(out, "{:x}", v),
+            Some("#x") => write!(out, "{:#x}", v),
+            Some("X") => write!(out, "{:X}", v),
+            Some("#X") => write!(out, "{:#X}", v),
+            Some("b") => write!(out, "{:b}", v),
+            Some("#b") => write!(out, "{:#b}", v),
+            Some("o") => write!(out, "{:o}", v),
+            Some("#o") => write!(out, "{:#o}", v),
+            Some("us") => write!(out, "{}.{:06}", v / 1_000_000, v % 1_000_000),
+            Some("ms") => write!(out, "{}.{:03}", v / 1_000, v % 1_000),
+            _ => write!(out, "{}", v),
+        };
+
+        let _ = match self {
+            Value::Unsigned(v) => integer(*v, out),
+            Value::Signed(v) if *v >= 0 => integer(*v as u128, out),
+            Value::Signed(v) => write!(out, "{}", v),
+            Value::Float(v) => write!(out, "{}", v),
+            Value::Bool(v) => write!(out, "{}", v),
+            Value::Char(c) if hint == Some("?") => write!(out, "{:?}", c),
+            Value::Char(c) => write!(out, "{}", c),
+            Value::Str(s) if hint == Some("?") => write!(out, "{:?}", s),
+            Value::Str(s) | Value::Formatted(s) => write!(out, "{}", s),
+            Value::Bytes(b) if hint == Some("a") => {
+                let s: String = b
+                    .iter()
+                    .flat_map(|c| std::ascii::escape_default(*c))
+                    .map(char::from)
+                    .collect();
+
+                write!(out, "b\"{}\"", s)
+            }
+            Value::Bytes(b) => {
+                out.push('[');
+
+                for (i, v) in b.iter().enumerate() {
+                    if i != 0 {
+                        out.push_str(", ");
+                    }
+
+                    integer(*v as u128, out).ok();
+                }
+
+                out.push(']');
+                Ok(())
+            }
+            Value::List(list) => write!(out, "[{}]", list.join(", ")),
+        };
+    }
+}
+
+#[derive(Clone, Debug)]
+enum Piece {
+    Literal(String),
+    Param { index: usize, ty: String, hint: Option<String> },
+}
+
+//
+// Parses a defmt format string into its literals and parameters.  A
+// parameter is of the form {[index][=type][:hint]}; a parameter without an
+// explicit type is formatted via its Format implementation.
+//
+fn parse_format(format: &str) -> Result<Vec<Piece>> {
+    let mut pieces = vec![];
+    let mut literal = String::new();
+    let mut chars = format.chars().peekable();
+    let mut implicit = 0;
+
+    while let Some(c) = chars.next() {
+        match c {
+            '{' if chars.peek() == Some(&'{') => {
+                chars.next();
+                literal.push('{');
+            }
+            '}' if chars.peek() == Some(&'}') => {
+                chars.next();
+                literal.push('}');
+            }
+            '{' => {
+                let mut param = String::new();
+
+                loop {
+                    match chars.next() {
+                        Some('}') => break,
+                        Some(c) => param.push(c),
+                        None => {
+                            bail!("unterminated parameter in \"{}\"", format)
+                        }
+                    }
+                }
+
+                if !literal.is_empty() {
+                    pieces.push(Piece::Literal(std::mem::take(&mut literal)));
+                }
+
+                //
+                // Our hint follows the last colon that isn't part of a
+                // type (e.g., "[u8; 4]" has no colon, but "::" could appear
+                // in a path).
+                //
+                let (spec, hint) = match param.rfind(':') {
+                    Some(ndx) if !param[..ndx].ends_with(':') => {
+                        (&param[..ndx], Some(param[ndx + 1..].to_string()))
+                    }
+                    _ => (param.as_str(), None),
+                };
+
+                let (index, ty) = match spec.split_once('=') {
+                    Some((index, ty)) => (index, ty.trim()),
+                    None => (spec, "?"),
+                };
+
+                let index = if index.is_empty() {
+                    implicit += 1;
+                    implicit - 1
+                } else {
+                    index.trim().parse::<usize>().map_err(|_| {
+                        anyhow!("bad parameter \"{}\" in \"{}\"", param, format)
+                    })?
+                };
+
+                let ty = ty.replace(' ', "");
+                pieces.push(Piece::Param { index, ty, hint });
+            }
+            c => literal.push(c),
+        }
+    }
+
+    if !literal.is_empty() {
+        pieces.push(Piece::Literal(literal));
+    }
+
+    Ok(pieces)
+}
+
+//
+// Decodes an rzCOBS-encoded frame (without its terminating zero).  rzCOBS
+// is decoded back to front:  each byte in 0x01..=0x7f is a bitmap denoting
+// which of the next seven bytes are zeroes; each byte in 0x80..=0xfe denotes
+// a zero preceded by a run of between 7 and 133 non-zero bytes; and 0xff
+// denotes a run of 134 non-zero bytes.  The decoded frame may have trailing
+// zeroes, which we can ignore.
+//
+fn rzcobs_decode(data: &[u8]) -> Result<Vec<u8>> {
+    let mut rval = vec![];
+    let mut data = data.iter().rev().copied();
+    let malformed = || anyhow!("malformed rzCOBS frame");
+
+    while let Some(x) = data.next() {
+        match x {
+            0 => return Err(malformed()),
+            0x01..=0x7f => {
+                for i in 0..7 {
+                    if x & (1 << (6 - i)) == 0 {
+                        rval.push(data.next().ok_or_else(malformed)?);
+                    } else {
+                        rval.push(0);
+                    }
+                }
+            }
+            0x80..=0xfe => {
+                rval.push(0);
+
+                for _ in 0..(x & 0x7f) + 7 {
+                    rval.push(data.next().ok_or_else(malformed)?);
+                }
+            }
+            0xff => {
+                for _ in 0..134 {
+                    rval.push(data.next().ok_or_else(malformed)?);
+                }
+            }
+        }
+    }
+
+    rval.reverse();
+    Ok(rval)
+}
+
+///
+/// Decodes defmt frames from a stream of bytes.
+///
+pub struct DefmtDecoder<'a> {
+    table: &'a DefmtTable,
+    buf: Vec<u8>,
+}
+
+impl<'a> DefmtDecoder<'a> {
+    pub fn new(table: &'a DefmtTable) -> Self {
+        Self { table, buf: vec![] }
+    }
+
+    /// Processes received bytes, returning any frames that they complete.
+    /// An error in decoding one frame does not preclude the decoding of
+    /// subsequent ones.
+    pub fn received(&mut self, bytes: &[u8]) -> Vec<Result<DefmtFrame>> {
+        let mut rval = vec![];
+
+        match self.table.encoding {
+            DefmtEncoding::Rzcobs => {
+                for &b in bytes {
+                    if b != 0 {
+                        self.buf.push(b);
+                        continue;
+                    }
+
+                    if self.buf.is_empty() {
+                        continue;
+                    }
+
+                    let frame = std::mem::take(&mut self.buf);
+
+                    rval.push(
+                        rzcobs_decode(&frame)
+                            .and_then(|f| self.table.decode(&f))
+                            .map(|(frame, _)| frame),
+                    );
+                }
+            }
+
+            DefmtEncoding::Raw => {
+                self.buf.extend_from_slice(bytes);
+
+                //
+                // With the raw encoding, frames aren't delimited:  we decode
+                // as many as we can, and wait for more data if the last is
+                // truncated.  If a frame is malformed, we can't know where
+                // the next one begins, so we discard everything we have.
+                //
+                while !self.buf.is_empty() {
+                    match self.table.decode(&self.buf) {
+                        Ok((frame, consumed)) => {
+                            self.buf.drain(..consumed);
+                            rval.push(Ok(frame));
+                        }
+                        Err(err) if err.is::<Truncated>() => break,
+                        Err(err) => {
+                            self.buf.clear();
+                            rval.push(Err(err));
+                        }
+                    }
+                }
+            }
+        }
+
+        rval
+    }
+}
@@ -0,0 +1,216 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//!
+//! Descriptions of external flash devices (e.g., QSPI NOR parts), allowing
+//! commands to determine the geometry and protection model of a part
+//! rather than assuming them.  A device is described by an implementation
+//! of [`FlashDevice`]; in addition to the devices that are built in,
+//! devices can be defined in a TOML file, e.g.:
+//!
+//! ```toml
+//! [[device]]
+//! name = "W25Q64JV"
+//! jedec = [0xef, 0x40, 0x17]
+//! capacity = 0x800000
+//! page-size = 256
+//! sector-size = 0x10000
+//!
+//! [device.protection]
+//! status-mask = 0x7c
+//! ```
+//!
+//! Devices defined in a file take precedence over built-in devices with
+//! the same name or JEDEC ID.  (The operations themselves are performed by
+//! the driver on the target, so a device's opcodes aren't described here.)
+//!
+
+use anyhow::{bail, Context, Result};
+use serde::Deserialize;
+use std::fs;
+
+/// The protection model of a device:  the bits of its status register
+/// that, if set, denote that some (or all) of the device is protected
+/// from being erased or written.
+#[derive(Copy, Clone, Debug, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct FlashProtection {
+    pub status_mask: u8,
+}
+
+pub trait FlashDevice {
+    fn name(&self) -> &str;
+
+    /// The JEDEC ID of the device (manufacturer, type and capacity).
+    fn jedec(&self) -> &[u8];
+
+    /// The capacity of the device, in bytes.
+    fn capacity(&self) -> u32;
+
+    /// The (maximum) size of a single page program, in bytes.
+    fn page_size(&self) -> u32;
+
+    /// The size of a single erasable sector, in bytes.
+    fn sector_size(&self) -> u32;
+
+    fn protection(&self) -> FlashProtection;
+
+    /// Determines if the device is protected, given its status register.
+    fn protected(&self, status: u8) -> bool {
+        status & self.protection().status_mask != 0
+    }
+
+    /// Checks that the specified range lies within the device.
+    fn check_range(&self, addr: u32, len: u32) -> Result<()> {
+        if addr as u64 + len as u64 > self.capacity() as u64 {
+            bail!(
+                "0x{:x} bytes at 0x{:x} exceeds {} capacity of 0x{:x} bytes",
+                len,
+                addr,
+                self.name(),
+                self.capacity()
+            );
+        }
+
+        Ok(())
+    }
+}
+
+/// A device described by data, either built in or from a TOML file.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct FlashDefinition {
+    pub name: String,
+    pub jedec: Vec<u8>,
+    pub capacity: u32,
+    pub page_size: u32,
+    pub sector_size: u32,
+    pub protection: FlashProtection,
+}
+
+impl FlashDevice for FlashDefinition {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn jedec(&self) -> &[u8] {
+        &self.jedec
+    }
+
+    fn capacity(&self) -> u32 {
+        self.capacity
+    }
+
+    fn page_size(&self) -> u32 {
+        self.page_size
+    }
+
+    fn sector_size(&self) -> u32 {
+        self.sector_size
+    }
+
+    fn protection(&self) -> FlashProtection {
+        self.protection
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct FlashDefinitions {
+    device: Vec<FlashDefinition>,
+}
+
+//
+// Our built-in devices, in the same format as a definitions file.
+//
+const FLASH_BUILTIN: &str = r#"
+[[device]]
+name = "MT25QU256ABA"
+jedec = [0x20, 0xbb, 0x19]
+capacity = 0x2000000
+page-size = 256
+sector-size = 0x10000
+
+[device.protection]
+status-mask = 0x5c
+
+[[device]]
+name = "MT25QL512ABB"
+jedec = [0x20, 0xba, 0x20]
+capacity = 0x4000000
+page-size = 256
+sector-size = 0x10000
+
+[device.protection]
+status-mask = 0x5c
+
+[[device]]
+name = "W25Q128JV"
+jedec = [0xef, 0x40, 0x18]
+capacity = 0x1000000
+page-size = 256
+sector-size = 0x10000
+
+[device.protection]
+status-mask = 0x7c
+
+[[device]]
+name = "MX25L25645G"
+jedec = [0xc2, 0x20, 0x19]
+capacity = 0x2000000
+page-size = 256
+sector-size = 0x10000
+
+[device.protection]
+status-mask = 0x3c
+"#;
+
+fn definitions(toml: &str) -> Result<Vec<FlashDefinition>> {
+    let definitions: FlashDefinitions = toml::from_str(toml)?;
+    Ok(definitions.device)
+}
+
+pub struct FlashDevices {
+    devices: Vec<Box<dyn FlashDevice>>,
+}
+
+impl FlashDevices {
+    /// Returns the built-in devices, preceded by those defined in the
+    /// specified file (if any).
+    pub fn new(filename: Option<&str>) -> Result<Self> {
+        let mut devices: Vec<Box<dyn FlashDevice>> = vec![];
+
+        if let Some(filename) = filename {
+            let contents = fs::read_to_string(filename)
+                .with_context(|| format!("failed to read {}", filename))?;
+
+            for d in definitions(&contents)
+                .with_context(|| format!("failed to parse {}", filename))?
+            {
+                devices.push(Box::new(d));
+            }
+        }
+
+        for d in definitions(FLASH_BUILTIN)? {
+            devices.push(Box::new(d));
+        }
+
+        Ok(Self { devices })
+    }
+
+    /// Looks up a device by name (ignoring case).
+    pub fn lookup(&self, name: &str) -> Option<&dyn FlashDevice> {
+        self.devices
+            .iter()
+            .find(|d| d.name().eq_ignore_ascii_case(name))
+            .map(|d| d.as_ref())
+    }
+
+    /// Identifies a device by the JEDEC ID that it returned.
+    pub fn identify(&self, id: &[u8]) -> Option<&dyn FlashDevice> {
+        self.devices
+            .iter()
+            .find(|d| !d.jedec().is_empty() && id.starts_with(d.jedec()))
+            .map(|d| d.as_ref())
+    }
+}
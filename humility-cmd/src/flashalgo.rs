@@ -0,0 +1,566 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//!
+//! Support for flash algorithms:  small, position-independent stubs that
+//! are loaded into target RAM and run to erase and program memories that
+//! Humility doesn't otherwise know how to operate on (e.g., unusual
+//! external flashes or FPGA configuration memories).  The stubs follow the
+//! CMSIS flash algorithm calling convention:  `Init(addr, clock, function)`,
+//! `UnInit(function)`, `EraseSector(addr)`, `EraseChip()` and
+//! `ProgramPage(addr, size, buffer)`, each of which returns 0 on success.
+//!
+//! An algorithm consists of its (raw binary) stub and a TOML descriptor,
+//! e.g.:
+//!
+//! ```toml
+//! name = "at25sf"
+//! description = "AT25SF081 via SPI2"
+//! blob = "at25sf.bin"
+//! load-address = 0x2000_0000
+//! base = 0x0
+//! size = 0x10_0000
+//! page-size = 256
+//! sector-size = 0x1000
+//!
+//! [entry]
+//! init = 0x1
+//! uninit = 0x4d
+//! erase-sector = 0x91
+//! program-page = 0xed
+//! ```
+//!
+//! Entry points are offsets within the stub (and should have their low bit
+//! set to denote Thumb code).  The blob is named relative to the
+//! descriptor; alternatively, the descriptor (as `algorithm.toml`) and the
+//! blob can be packaged together in a ZIP archive.
+//!
+//! The stub is loaded after a breakpoint to which each entry point returns;
+//! the stub is followed by a buffer for program data, which is in turn
+//! followed by the stack.  Before loading the stub, the contents of the RAM
+//! that it will occupy (along with the core's registers) are saved; they
+//! are restored when the stub is unloaded.
+//!
+
+use anyhow::{bail, Context, Result};
+use humility::arch::ARMRegister;
+use humility::core::Core;
+use serde::Deserialize;
+use std::fs;
+use std::io::Read;
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// The name of the descriptor within a packaged algorithm
+const FLASHALGO_DESCRIPTOR: &str = "algorithm.toml";
+
+//
+// The header that we place before the stub:  a pair of breakpoint
+// instructions, to which each entry point returns.
+//
+const FLASHALGO_HEADER: [u8; 4] = [0x00, 0xbe, 0x00, 0xbe];
+
+/// The interval at which we poll the core for the stub's completion
+const FLASHALGO_POLL: Duration = Duration::from_millis(1);
+
+//
+// The Debug Halting Control and Status Register, and its bit that denotes
+// that the core is halted.
+//
+const DHCSR: u32 = 0xe000_edf0;
+const DHCSR_S_HALT: u32 = 1 << 17;
+
+//
+// The registers that we save (and restore) around the stub's execution, in
+// the order that we restore them.
+//
+const FLASHALGO_REGISTERS: &[ARMRegister] = &[
+    ARMRegister::R0,
+    ARMRegister::R1,
+    ARMRegister::R2,
+    ARMRegister::R3,
+    ARMRegister::R4,
+    ARMRegister::R5,
+    ARMRegister::R6,
+    ARMRegister::R7,
+    ARMRegister::R8,
+    ARMRegister::R9,
+    ARMRegister::R10,
+    ARMRegister::R11,
+    ARMRegister::R12,
+    ARMRegister::SPR,
+    ARMRegister::MSP,
+    ARMRegister::PSP,
+    ARMRegister::SP,
+    ARMRegister::LR,
+    ARMRegister::xPSR,
+    ARMRegister::PC,
+];
+
+//
+// The Thumb bit in the xPSR, and PRIMASK in the special-purpose register
+// (which also includes BASEPRI, FAULTMASK and CONTROL).
+//
+const XPSR_THUMB: u32 = 1 << 24;
+const SPR_PRIMASK: u32 = 1;
+
+//
+// The functions passed to Init() and UnInit().
+//
+const FLASHALGO_ERASE: u32 = 1;
+const FLASHALGO_PROGRAM: u32 = 2;
+
+fn halted(core: &mut dyn Core) -> Result<bool> {
+    Ok(core.read_word_32(DHCSR)? & DHCSR_S_HALT != 0)
+}
+
+/// The entry points of an algorithm, as offsets within its stub.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct FlashAlgorithmEntry {
+    pub init: Option<u32>,
+    pub uninit: Option<u32>,
+    pub erase_sector: u32,
+    pub erase_all: Option<u32>,
+    pub program_page: u32,
+    /// offset of the stub's data (loaded into R9), if not at its start
+    pub static_base: Option<u32>,
+}
+
+fn default_stack_size() -> u32 {
+    0x800
+}
+
+fn default_erased() -> u8 {
+    0xff
+}
+
+fn default_timeout() -> u64 {
+    5000
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct FlashAlgorithmDescriptor {
+    pub name: String,
+    pub description: Option<String>,
+    pub blob: String,
+    pub load_address: u32,
+    #[serde(default = "default_stack_size")]
+    pub stack_size: u32,
+    /// address at which the memory appears to the algorithm
+    pub base: u32,
+    pub size: u32,
+    pub page_size: u32,
+    pub sector_size: u32,
+    #[serde(default = "default_erased")]
+    pub erased: u8,
+    /// clock value passed to Init()
+    #[serde(default)]
+    pub clock: u32,
+    /// timeout for any one operation, in milliseconds
+    #[serde(default = "default_timeout")]
+    pub timeout: u64,
+    pub entry: FlashAlgorithmEntry,
+}
+
+pub struct FlashAlgorithm {
+    pub descriptor: FlashAlgorithmDescriptor,
+    blob: Vec<u8>,
+}
+
+impl FlashAlgorithm {
+    fn new(
+        descriptor: FlashAlgorithmDescriptor,
+        blob: Vec<u8>,
+    ) -> Result<Self> {
+        let d = &descriptor;
+
+        if blob.is_empty() {
+            bail!("stub for {} is empty", d.name);
+        }
+
+        if d.page_size == 0 || d.sector_size == 0 {
+            bail!("{} has invalid page or sector size", d.name);
+        }
+
+        if d.sector_size % d.page_size != 0 {
+            bail!("{} sector size is not a multiple of page size", d.name);
+        }
+
+        if d.load_address & 0b11 != 0 {
+            bail!("{} load address is not word-aligned", d.name);
+        }
+
+        let len = blob.len() as u32;
+
+        for (what, offs) in [
+            ("init", d.entry.init),
+            ("uninit", d.entry.uninit),
+            ("erase-sector", Some(d.entry.erase_sector)),
+            ("erase-all", d.entry.erase_all),
+            ("program-page", Some(d.entry.program_page)),
+        ] {
+            if let Some(offs) = offs {
+                if offs >= len {
+                    bail!(
+                        "{} {} entry 0x{:x} is outside stub",
+                        d.name,
+                        what,
+                        offs
+                    );
+                }
+            }
+        }
+
+        Ok(Self { descriptor, blob })
+    }
+
+    ///
+    /// Loads an algorithm from the specified file, which is either a
+    /// descriptor (with the stub named relative to it) or a ZIP archive
+    /// containing both the descriptor and the stub.
+    ///
+    pub fn load(filename: &str) -> Result<Self> {
+        let path = Path::new(filename);
+
+        if path.extension().map_or(false, |ext| ext == "zip") {
+            let file = fs::File::open(path)
+                .with_context(|| format!("failed to open {}", filename))?;
+            let mut archive = zip::ZipArchive::new(file)?;
+
+            let mut descriptor = String::new();
+            archive
+                .by_name(FLASHALGO_DESCRIPTOR)
+                .with_context(|| {
+                    format!("{} is missing {}", filename, FLASHALGO_DESCRIPTOR)
+                })?
+                .read_to_string(&mut descriptor)?;
+
+            let d: FlashAlgorithmDescriptor = toml::from_str(&descriptor)
+                .with_context(|| format!("failed to parse {}", filename))?;
+
+            let mut blob = vec![];
+            archive
+                .by_name(&d.blob)
+                .with_context(|| format!("{} is missing {}", filename, d.blob))?
+                .read_to_end(&mut blob)?;
+
+            return Self::new(d, blob);
+        }
+
+        let descriptor = fs::read_to_string(path)
+            .with_context(|| format!("failed to read {}", filename))?;
+
+        let d: FlashAlgorithmDescriptor = toml::from_str(&descriptor)
+            .with_context(|| format!("failed to parse {}", filename))?;
+
+        let blobpath =
+            path.parent().unwrap_or_else(|| Path::new(".")).join(&d.blob);
+        let blob = fs::read(&blobpath).with_context(|| {
+            format!("failed to read stub {}", blobpath.display())
+        })?;
+
+        Self::new(d, blob)
+    }
+
+    fn stub(&self) -> u32 {
+        self.descriptor.load_address + FLASHALGO_HEADER.len() as u32
+    }
+
+    fn buffer(&self) -> u32 {
+        (self.stub() + self.blob.len() as u32 + 3) & !3
+    }
+
+    //
+    // The top of our stack, which must be 8-byte aligned.
+    //
+    fn stack(&self) -> u32 {
+        let d = &self.descriptor;
+        (self.buffer() + d.page_size + d.stack_size + 7) & !7
+    }
+
+    /// Returns the amount of target RAM that the algorithm occupies.
+    pub fn footprint(&self) -> u32 {
+        self.stack() - self.descriptor.load_address
+    }
+
+    /// Checks that the specified range lies within the memory.
+    pub fn check_range(&self, addr: u32, len: u32) -> Result<()> {
+        let d = &self.descriptor;
+
+        if addr < d.base
+            || addr as u64 + len as u64 > d.base as u64 + d.size as u64
+        {
+            bail!(
+                "0x{:x} bytes at 0x{:x} is outside of {} (0x{:x} bytes at \
+                0x{:x})",
+                len,
+                addr,
+                d.name,
+                d.size,
+                d.base
+            );
+        }
+
+        Ok(())
+    }
+}
+
+///
+/// An algorithm that has been loaded onto a target.  The core remains halted
+/// while the algorithm is loaded, except when running the stub.
+///
+pub struct FlashStub<'a> {
+    algorithm: &'a FlashAlgorithm,
+    registers: Vec<(ARMRegister, u32)>,
+    ram: Vec<u8>,
+    running: bool,
+}
+
+impl<'a> FlashStub<'a> {
+    ///
+    /// Loads the algorithm, saving the state of the core and the RAM that
+    /// the algorithm will occupy.
+    ///
+    pub fn load(
+        algorithm: &'a FlashAlgorithm,
+        core: &mut dyn Core,
+    ) -> Result<Self> {
+        let base = algorithm.descriptor.load_address;
+        let footprint = algorithm.footprint();
+
+        let running = !halted(core)?;
+
+        if running {
+            core.halt()?;
+        }
+
+        let mut registers = vec![];
+
+        for &reg in FLASHALGO_REGISTERS {
+            registers.push((reg, core.read_reg(reg)?));
+        }
+
+        let mut ram = vec![0u8; footprint as usize];
+        core.read_8(base, &mut ram)?;
+
+        core.write_8(base, &FLASHALGO_HEADER)?;
+        core.write_8(algorithm.stub(), &algorithm.blob)?;
+
+        info!(
+            "loaded {} (0x{:x} bytes at 0x{:x})",
+            algorithm.descriptor.name, footprint, base
+        );
+
+        Ok(Self { algorithm, registers, ram, running })
+    }
+
+    //
+    // Calls the specified entry point with the specified arguments,
+    // returning its result.
+    //
+    fn call(
+        &self,
+        core: &mut dyn Core,
+        what: &str,
+        entry: u32,
+        args: &[u32],
+    ) -> Result<u32> {
+        let algorithm = self.algorithm;
+        let regs = [
+            ARMRegister::R0,
+            ARMRegister::R1,
+            ARMRegister::R2,
+            ARMRegister::R3,
+        ];
+
+        for (reg, arg) in regs.iter().zip(args.iter()) {
+            core.write_reg(*reg, *arg)?;
+        }
+
+        let sb = algorithm.descriptor.entry.static_base.unwrap_or(0);
+        let breakpoint = algorithm.descriptor.load_address;
+
+        //
+        // We run with interrupts masked (and privileged, on the main
+        // stack) to prevent the target's own code from running.
+        //
+        core.write_reg(ARMRegister::SPR, SPR_PRIMASK)?;
+        core.write_reg(ARMRegister::R9, algorithm.stub() + sb)?;
+        core.write_reg(ARMRegister::SP, algorithm.stack())?;
+        core.write_reg(ARMRegister::LR, breakpoint | 1)?;
+        core.write_reg(ARMRegister::xPSR, XPSR_THUMB)?;
+        core.write_reg(ARMRegister::PC, (algorithm.stub() + entry) & !1)?;
+
+        core.run()?;
+
+        let timeout = Duration::from_millis(algorithm.descriptor.timeout);
+        let started = Instant::now();
+
+        while !halted(core)? {
+            if started.elapsed() > timeout {
+                core.halt()?;
+                bail!("{} timed out", what);
+            }
+
+            thread::sleep(FLASHALGO_POLL);
+        }
+
+        let pc = core.read_reg(ARMRegister::PC)?;
+
+        if pc != breakpoint {
+            bail!("{} halted unexpectedly at 0x{:x}", what, pc);
+        }
+
+        let rval = core.read_reg(ARMRegister::R0)?;
+        trace!("{}({:x?}) = 0x{:x}", what, args, rval);
+
+        Ok(rval)
+    }
+
+    fn check(
+        &self,
+        core: &mut dyn Core,
+        what: &str,
+        entry: u32,
+        args: &[u32],
+    ) -> Result<()> {
+        match self.call(core, what, entry, args)? {
+            0 => Ok(()),
+            rval => bail!("{} failed with 0x{:x}", what, rval),
+        }
+    }
+
+    fn init(&self, core: &mut dyn Core, function: u32) -> Result<()> {
+        let d = &self.algorithm.descriptor;
+
+        match d.entry.init {
+            Some(init) => {
+                self.check(core, "Init", init, &[d.base, d.clock, function])
+            }
+            None => Ok(()),
+        }
+    }
+
+    fn uninit(&self, core: &mut dyn Core, function: u32) -> Result<()> {
+        match self.algorithm.descriptor.entry.uninit {
+            Some(uninit) => self.check(core, "UnInit", uninit, &[function]),
+            None => Ok(()),
+        }
+    }
+
+    ///
+    /// Erases the sectors that contain the specified range, calling the
+    /// specified closure with the number of bytes erased after each sector.
+    ///
+    pub fn erase(
+        &self,
+        core: &mut dyn Core,
+        addr: u32,
+        len: u32,
+        mut progress: impl FnMut(u32),
+    ) -> Result<()> {
+        let d = &self.algorithm.descriptor;
+        self.algorithm.check_range(addr, len)?;
+
+        let start = addr - (addr - d.base) % d.sector_size;
+        let mut sector = start;
+
+        self.init(core, FLASHALGO_ERASE)?;
+
+        while (sector as u64) < addr as u64 + len as u64 {
+            self.check(core, "EraseSector", d.entry.erase_sector, &[sector])?;
+            sector += d.sector_size;
+            progress(sector - start);
+        }
+
+        self.uninit(core, FLASHALGO_ERASE)
+    }
+
+    /// Erases the entire memory.
+    pub fn erase_all(&self, core: &mut dyn Core) -> Result<()> {
+        let erase_all = match self.algorithm.descriptor.entry.erase_all {
+            Some(erase_all) => erase_all,
+            None => bail!(
+                "{} cannot erase the entire memory",
+                self.algorithm.descriptor.name
+            ),
+        };
+
+        self.init(core, FLASHALGO_ERASE)?;
+        self.check(core, "EraseChip", erase_all, &[])?;
+        self.uninit(core, FLASHALGO_ERASE)
+    }
+
+    ///
+    /// Programs the specified data at the specified address (which must
+    /// already be erased), calling the specified closure with the number of
+    /// bytes programmed after each page.
+    ///
+    pub fn program(
+        &self,
+        core: &mut dyn Core,
+        addr: u32,
+        data: &[u8],
+        mut progress: impl FnMut(u32),
+    ) -> Result<()> {
+        let d = &self.algorithm.descriptor;
+        self.algorithm.check_range(addr, data.len() as u32)?;
+
+        if (addr - d.base) % d.page_size != 0 {
+            bail!("0x{:x} is not aligned to a {}-byte page", addr, d.page_size);
+        }
+
+        let buffer = self.algorithm.buffer();
+        let mut offs = 0;
+
+        self.init(core, FLASHALGO_PROGRAM)?;
+
+        for chunk in data.chunks(d.page_size as usize) {
+            //
+            // A short final page is padded out with the erased value.
+            //
+            let mut page = chunk.to_vec();
+            page.resize(d.page_size as usize, d.erased);
+            core.write_8(buffer, &page)?;
+
+            self.check(
+                core,
+                "ProgramPage",
+                d.entry.program_page,
+                &[addr + offs, d.page_size, buffer],
+            )?;
+
+            offs += chunk.len() as u32;
+            progress(offs);
+        }
+
+        self.uninit(core, FLASHALGO_PROGRAM)
+    }
+
+    ///
+    /// Unloads the algorithm, restoring the RAM that it occupied and the
+    /// state of the core -- and resuming the core if it was running when
+    /// the algorithm was loaded.
+    ///
+    pub fn unload(self, core: &mut dyn Core) -> Result<()> {
+        if !halted(core)? {
+            core.halt()?;
+        }
+
+        core.write_8(self.algorithm.descriptor.load_address, &self.ram)?;
+
+        for &(reg, val) in &self.registers {
+            core.write_reg(reg, val)?;
+        }
+
+        if self.running {
+            core.run()?;
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,229 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//!
+//! Forwarding of device logs to the system log, allowing long-running
+//! captures to survive terminal disconnects (and to be searched).  The
+//! destination is specified via `--forward`:
+//!
+//! - `syslog` sends each line to the local syslog daemon (via `/dev/log`)
+//!   as an RFC 5424 message, with the task and board as structured data;
+//!
+//! - `journald` sends each line to the systemd journal via its native
+//!   protocol, with the task and board as `HUBRIS_TASK` and `HUBRIS_BOARD`
+//!   fields.
+//!
+//! In both cases, messages are sent with the `humility` identifier and the
+//! user facility.
+//!
+
+use crate::Args;
+use anyhow::{bail, Context, Result};
+use humility::hubris::HubrisArchive;
+use std::collections::HashMap;
+use std::os::unix::net::UnixDatagram;
+
+const FORWARD_SYSLOG_PATH: &str = "/dev/log";
+const FORWARD_JOURNALD_PATH: &str = "/run/systemd/journal/socket";
+
+//
+// Our syslog facility (user-level messages), and our RFC 5424 SD-ID.  (The
+// latter must include a private enterprise number; we use the number that
+// is reserved for documentation.)
+//
+const FORWARD_FACILITY: u8 = 1;
+const FORWARD_SDID: &str = "hubris@32473";
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum ForwardTarget {
+    Syslog,
+    Journald,
+}
+
+/// Syslog severities, in order of decreasing severity.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ForwardSeverity {
+    Error = 3,
+    Warning = 4,
+    Info = 6,
+    Debug = 7,
+}
+
+impl ForwardSeverity {
+    /// Returns the severity corresponding to a defmt log level.
+    pub fn from_level(level: Option<&str>) -> Self {
+        match level {
+            Some("ERROR") => ForwardSeverity::Error,
+            Some("WARN") => ForwardSeverity::Warning,
+            Some("DEBUG") | Some("TRACE") => ForwardSeverity::Debug,
+            _ => ForwardSeverity::Info,
+        }
+    }
+}
+
+pub struct LogForwarder {
+    target: ForwardTarget,
+    socket: UnixDatagram,
+    board: Option<String>,
+    partial: HashMap<u32, Vec<u8>>,
+}
+
+//
+// Escapes a value for inclusion in RFC 5424 structured data.
+//
+fn sdvalue(s: &str) -> String {
+    let mut rval = String::with_capacity(s.len());
+
+    for c in s.chars() {
+        if c == '"' || c == '\\' || c == ']' {
+            rval.push('\\');
+        }
+
+        rval.push(c);
+    }
+
+    rval
+}
+
+//
+// Appends a field in the journal's native format.  Values that contain a
+// newline must be sent as a little-endian length followed by the value.
+//
+fn journal_field(buf: &mut Vec<u8>, name: &str, value: &str) {
+    buf.extend_from_slice(name.as_bytes());
+
+    if value.contains('\n') {
+        buf.push(b'\n');
+        buf.extend_from_slice(&(value.len() as u64).to_le_bytes());
+    } else {
+        buf.push(b'=');
+    }
+
+    buf.extend_from_slice(value.as_bytes());
+    buf.push(b'\n');
+}
+
+impl LogForwarder {
+    ///
+    /// Returns a forwarder if log forwarding has been specified.
+    ///
+    pub fn new(args: &Args, hubris: &HubrisArchive) -> Result<Option<Self>> {
+        let (target, path) = match args.forward.as_deref() {
+            Some("syslog") => (ForwardTarget::Syslog, FORWARD_SYSLOG_PATH),
+            Some("journald") => {
+                (ForwardTarget::Journald, FORWARD_JOURNALD_PATH)
+            }
+            Some(other) => bail!("unknown log destination \"{}\"", other),
+            None => return Ok(None),
+        };
+
+        let socket = UnixDatagram::unbound()?;
+
+        socket
+            .connect(path)
+            .with_context(|| format!("failed to connect to {}", path))?;
+
+        Ok(Some(Self {
+            target,
+            socket,
+            board: hubris.board().map(|b| b.to_string()),
+            partial: HashMap::new(),
+        }))
+    }
+
+    /// Forwards a single line, optionally attributed to a task.
+    pub fn line(
+        &mut self,
+        severity: ForwardSeverity,
+        task: Option<&str>,
+        message: &str,
+    ) -> Result<()> {
+        let mut fields = vec![];
+
+        if let Some(task) = task {
+            fields.push(("task", task));
+        }
+
+        if let Some(ref board) = self.board {
+            fields.push(("board", board.as_str()));
+        }
+
+        let buf = match self.target {
+            ForwardTarget::Syslog => {
+                let pri = FORWARD_FACILITY * 8 + severity as u8;
+
+                let sd = if fields.is_empty() {
+                    "-".to_string()
+                } else {
+                    let params: Vec<String> = fields
+                        .iter()
+                        .map(|(k, v)| format!("{}=\"{}\"", k, sdvalue(v)))
+                        .collect();
+
+                    format!("[{} {}]", FORWARD_SDID, params.join(" "))
+                };
+
+                format!(
+                    "<{}>1 - - humility {} - {} {}",
+                    pri,
+                    std::process::id(),
+                    sd,
+                    message
+                )
+                .into_bytes()
+            }
+
+            ForwardTarget::Journald => {
+                let mut buf = vec![];
+                let priority = (severity as u8).to_string();
+
+                journal_field(&mut buf, "MESSAGE", message);
+                journal_field(&mut buf, "PRIORITY", &priority);
+                journal_field(&mut buf, "SYSLOG_IDENTIFIER", "humility");
+                journal_field(
+                    &mut buf,
+                    "SYSLOG_FACILITY",
+                    &FORWARD_FACILITY.to_string(),
+                );
+
+                for (k, v) in &fields {
+                    let name = format!("HUBRIS_{}", k.to_uppercase());
+                    journal_field(&mut buf, &name, v);
+                }
+
+                buf
+            }
+        };
+
+        self.socket.send(&buf).context("failed to forward log")?;
+
+        Ok(())
+    }
+
+    ///
+    /// Accumulates raw text received on the specified port, forwarding each
+    /// line as it is completed.
+    ///
+    pub fn received(&mut self, port: u32, bytes: &[u8]) -> Result<()> {
+        let mut lines = vec![];
+        let partial = self.partial.entry(port).or_default();
+
+        for b in bytes {
+            match *b {
+                b'\n' => {
+                    lines.push(String::from_utf8_lossy(partial).into_owned());
+                    partial.clear();
+                }
+                b'\r' => {}
+                b => partial.push(b),
+            }
+        }
+
+        for line in lines {
+            self.line(ForwardSeverity::Info, None, &line)?;
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,113 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use crate::doppel::{Task, TaskDesc};
+use crate::reflect::{self, Load};
+use anyhow::{bail, Result};
+use humility::arch::ARMRegister;
+use humility::core::Core;
+use humility::hubris::*;
+use num_traits::FromPrimitive;
+use std::collections::HashMap;
+
+/// The Vector Table Offset Register, from which we find the initial MSP
+const VTOR: u32 = 0xe000_ed08;
+
+///
+/// Determines the task that was running when the core halted, along with
+/// its name and stack limit.
+///
+pub fn current_task(
+    hubris: &HubrisArchive,
+    core: &mut dyn Core,
+) -> Result<(HubrisTask, String, u32)> {
+    let base = core.read_word_32(hubris.lookup_symword("TASK_TABLE_BASE")?)?;
+    let cur = core.read_word_32(hubris.lookup_symword("CURRENT_TASK_PTR")?)?;
+    let task_t = hubris.lookup_struct_byname("Task")?;
+
+    if cur < base || (cur - base) % task_t.size as u32 != 0 {
+        bail!("current task pointer 0x{:x} is invalid", cur);
+    }
+
+    let ndx = (cur - base) / task_t.size as u32;
+
+    let mut buf = vec![0; task_t.size];
+    core.read_8(cur, &mut buf)?;
+
+    let task_value: reflect::Value = reflect::load(hubris, &buf, task_t, 0)?;
+    let task: Task = Task::from_value(&task_value)?;
+    let desc: TaskDesc = task.descriptor.load_from(hubris, core)?;
+    let module = hubris.instr_mod(desc.entry_point).unwrap_or("<unknown>");
+
+    Ok((HubrisTask::Task(ndx), module.to_string(), desc.initial_stack))
+}
+
+///
+/// Determines the limit of the kernel's stack:  the initial MSP, as found
+/// in the vector table.
+///
+pub fn kernel_stack_limit(core: &mut dyn Core) -> Result<u32> {
+    let vtor = core.read_word_32(VTOR)?;
+    core.read_word_32(vtor)
+}
+
+/// Prints the specified registers, as at a breakpoint or a fault.
+pub fn print_regs(regs: &HashMap<ARMRegister, u32>) {
+    for r in 0..16 {
+        let reg = ARMRegister::from_usize(r).unwrap();
+
+        if r % 4 == 0 {
+            print!("   ");
+        }
+
+        print!("  {:>3} = 0x{:08x}", reg, regs.get(&reg).unwrap());
+
+        if r % 4 == 3 {
+            println!();
+        }
+    }
+
+    println!("  {:>6} = 0x{:08x}", "xPSR", regs[&ARMRegister::xPSR]);
+}
+
+/// Prints a stack backtrace (with line numbers, if specified).
+pub fn print_stack(
+    hubris: &HubrisArchive,
+    stack: &[HubrisStackFrame],
+    line: bool,
+) {
+    for frame in stack {
+        let pc = frame.registers.get(&ARMRegister::PC).unwrap();
+
+        if let Some(ref inlined) = frame.inlined {
+            for inline in inlined {
+                println!(
+                    "      0x{:08x} 0x{:08x} {}",
+                    frame.cfa, inline.addr, inline.name
+                );
+
+                if line {
+                    if let Some(src) = hubris.lookup_src(inline.origin) {
+                        println!("{:28}@ {}:{}", "", src.fullpath(), src.line);
+                    }
+                }
+            }
+        }
+
+        if let Some(sym) = frame.sym {
+            println!(
+                "      0x{:08x} 0x{:08x} {}",
+                frame.cfa, *pc, sym.demangled_name
+            );
+
+            if line {
+                if let Some(src) = hubris.lookup_src(sym.goff) {
+                    println!("{:28}@ {}:{}", "", src.fullpath(), src.line);
+                }
+            }
+        } else {
+            println!("      0x{:08x} 0x{:08x}", frame.cfa, *pc);
+        }
+    }
+}
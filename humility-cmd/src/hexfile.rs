@@ -0,0 +1,159 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//!
+//! Output of memory contents as Intel HEX or Motorola S-records, allowing
+//! captured memory to be consumed by programmers and other tools.
+//!
+//! For Intel HEX, data records carry only the low 16 bits of an address;
+//! an extended linear address record is emitted whenever the upper 16 bits
+//! change (and data records never span a 64 KiB boundary).  For
+//! S-records, the record type (S1, S2 or S3) is the smallest that can hold
+//! the highest address, and the count and termination records match it.
+//!
+
+use anyhow::{bail, Result};
+use std::io::Write;
+use std::str::FromStr;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum HexFormat {
+    Ihex,
+    Srec,
+}
+
+impl FromStr for HexFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "ihex" => Ok(HexFormat::Ihex),
+            "srec" => Ok(HexFormat::Srec),
+            _ => bail!("unknown output format \"{}\"", s),
+        }
+    }
+}
+
+/// The number of data bytes in each record.
+const HEXFILE_RECORD_SIZE: usize = 16;
+
+fn ihex_record(
+    out: &mut dyn Write,
+    rtype: u8,
+    addr: u16,
+    data: &[u8],
+) -> Result<()> {
+    let mut record = vec![data.len() as u8];
+    record.extend_from_slice(&addr.to_be_bytes());
+    record.push(rtype);
+    record.extend_from_slice(data);
+
+    let sum = record.iter().fold(0u8, |sum, b| sum.wrapping_add(*b));
+    record.push((!sum).wrapping_add(1));
+
+    write!(out, ":")?;
+
+    for b in record {
+        write!(out, "{:02X}", b)?;
+    }
+
+    writeln!(out)?;
+
+    Ok(())
+}
+
+fn srec_record(
+    out: &mut dyn Write,
+    rtype: u8,
+    addr: u32,
+    alen: usize,
+    data: &[u8],
+) -> Result<()> {
+    let mut record = vec![(alen + data.len() + 1) as u8];
+    record.extend_from_slice(&addr.to_be_bytes()[4 - alen..]);
+    record.extend_from_slice(data);
+
+    let sum = record.iter().fold(0u8, |sum, b| sum.wrapping_add(*b));
+    record.push(!sum);
+
+    write!(out, "S{}", rtype)?;
+
+    for b in record {
+        write!(out, "{:02X}", b)?;
+    }
+
+    writeln!(out)?;
+
+    Ok(())
+}
+
+fn write_ihex(out: &mut dyn Write, bytes: &[u8], addr: u32) -> Result<()> {
+    let mut upper = None;
+    let mut offs = 0;
+
+    while offs < bytes.len() {
+        let a = addr + offs as u32;
+
+        if upper != Some(a >> 16) {
+            let hi = (a >> 16) as u16;
+            ihex_record(out, 0x04, 0, &hi.to_be_bytes())?;
+            upper = Some(a >> 16);
+        }
+
+        //
+        // Don't let a record cross into the next 64 KiB segment.
+        //
+        let remaining = 0x1_0000 - (a & 0xffff) as usize;
+        let len = HEXFILE_RECORD_SIZE.min(remaining).min(bytes.len() - offs);
+
+        ihex_record(out, 0x00, a as u16, &bytes[offs..offs + len])?;
+        offs += len;
+    }
+
+    ihex_record(out, 0x01, 0, &[])
+}
+
+fn write_srec(out: &mut dyn Write, bytes: &[u8], addr: u32) -> Result<()> {
+    let last = addr as u64 + bytes.len().saturating_sub(1) as u64;
+
+    let (data, term, alen) = if last <= 0xffff {
+        (1, 9, 2)
+    } else if last <= 0xff_ffff {
+        (2, 8, 3)
+    } else {
+        (3, 7, 4)
+    };
+
+    srec_record(out, 0, 0, 2, b"humility")?;
+
+    let mut nrecords = 0;
+
+    for (i, chunk) in bytes.chunks(HEXFILE_RECORD_SIZE).enumerate() {
+        let a = addr + (i * HEXFILE_RECORD_SIZE) as u32;
+        srec_record(out, data, a, alen, chunk)?;
+        nrecords += 1;
+    }
+
+    if nrecords <= 0xffff {
+        srec_record(out, 5, nrecords, 2, &[])?;
+    } else {
+        srec_record(out, 6, nrecords, 3, &[])?;
+    }
+
+    srec_record(out, term, 0, alen, &[])
+}
+
+/// Writes the specified bytes, located at the specified address, in the
+/// specified format.
+pub fn writemem(
+    out: &mut dyn Write,
+    format: HexFormat,
+    bytes: &[u8],
+    addr: u32,
+) -> Result<()> {
+    match format {
+        HexFormat::Ihex => write_ihex(out, bytes, addr),
+        HexFormat::Srec => write_srec(out, bytes, addr),
+    }
+}
@@ -2,16 +2,31 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
+pub mod defmt;
 pub mod doppel;
-pub mod hiffy;
+pub mod flash;
+pub mod flashalgo;
+pub mod forward;
+pub mod halted;
+pub mod hexfile;
 pub mod i2c;
 pub mod jefe;
+pub mod otlp;
 pub mod reflect;
+pub mod regmap;
+pub mod rtt;
 pub mod test;
+pub mod trigger;
+pub mod xtask;
+
+pub use humility::hiffy;
+pub use humility::json;
 
 use anyhow::{bail, Result};
-use humility::core::Core;
+use humility::coalesce::CoalescingCore;
+use humility::core::{Core, CoreOps};
 use humility::hubris::*;
+use humility_cortex::breakpoint::BreakpointCore;
 use structopt::StructOpt;
 
 #[macro_use]
@@ -24,6 +39,10 @@ pub struct Args {
     #[structopt(long, short)]
     pub verbose: bool,
 
+    /// log transient errors on the debug transport and their retries
+    #[structopt(long)]
+    pub probe_log: bool,
+
     /// specific chip on attached device
     #[structopt(
         long,
@@ -46,14 +65,135 @@ pub struct Args {
     )]
     pub archive: Option<String>,
 
+    /// Hubris app (by name, board or app TOML) whose freshest archive
+    /// should be used, when run within a Hubris checkout
+    #[structopt(
+        long,
+        env = "HUMILITY_APP",
+        value_name = "app",
+        conflicts_with_all = &["archive", "dump"]
+    )]
+    pub app: Option<String>,
+
+    /// rebuild the app (via cargo xtask dist) before using its archive
+    #[structopt(long, requires = "app")]
+    pub rebuild: bool,
+
     /// Hubris dump
     #[structopt(long, short, env = "HUMILITY_DUMP")]
     pub dump: Option<String>,
 
+    /// skip validation if the target was last validated against this image
+    /// (or set HUMILITY_FAST)
+    #[structopt(long, conflicts_with = "dump")]
+    pub fast: bool,
+
+    /// before running a command, verify that the image on the target
+    /// matches the archive by comparing a sample of its text (or set
+    /// HUMILITY_VERIFY)
+    #[structopt(long, conflicts_with = "dump")]
+    pub verify: bool,
+
+    /// CMSIS-SVD file(s) describing additional peripherals
+    #[structopt(
+        long,
+        env = "HUMILITY_SVD",
+        value_name = "file",
+        use_delimiter = true,
+        number_of_values = 1
+    )]
+    pub svd: Vec<String>,
+
+    /// trigger(s) to fire before target operations (e.g., "gpio:addr=val",
+    /// "sigrok:args", or "exec:command")
+    #[structopt(long, value_name = "trigger", number_of_values = 1)]
+    pub trigger: Vec<String>,
+
+    /// OTLP/HTTP endpoint to which metrics and events should be exported
+    #[structopt(long, env = "HUMILITY_OTLP", value_name = "endpoint")]
+    pub otlp: Option<String>,
+
+    /// forward device logs to the system log
+    #[structopt(
+        long,
+        env = "HUMILITY_FORWARD",
+        value_name = "destination",
+        possible_values = &["syslog", "journald"]
+    )]
+    pub forward: Option<String>,
+
+    /// format of output, for commands that support machine-readable output
+    #[structopt(
+        long,
+        env = "HUMILITY_OUTPUT",
+        value_name = "format",
+        default_value = "text",
+        possible_values = &["text", "json"]
+    )]
+    pub output: OutputFormat,
+
     #[structopt(subcommand)]
     pub cmd: Subcommand,
 }
 
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum OutputFormat {
+    Text,
+    Json,
+}
+
+impl std::str::FromStr for OutputFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(format: &str) -> Result<Self> {
+        match format {
+            "text" => Ok(OutputFormat::Text),
+            "json" => Ok(OutputFormat::Json),
+            _ => bail!("unknown output format \"{}\"", format),
+        }
+    }
+}
+
+impl Args {
+    /// Returns true if machine-readable (JSON) output has been requested.
+    pub fn json(&self) -> bool {
+        self.output == OutputFormat::Json
+    }
+
+    /// Returns true if validation may be skipped, as specified via `--fast`
+    /// or by setting `HUMILITY_FAST`.  (We don't have clap read the latter,
+    /// as it would then expect `--fast` to take a value.)
+    pub fn fast(&self) -> bool {
+        self.fast || std::env::var_os("HUMILITY_FAST").is_some()
+    }
+
+    /// Returns true if the image on the target should be verified before
+    /// running a command, as specified via `--verify` or by setting
+    /// `HUMILITY_VERIFY`.
+    pub fn verify(&self) -> bool {
+        self.verify || std::env::var_os("HUMILITY_VERIFY").is_some()
+    }
+
+    /// Returns the triggers to fire, as specified via `--trigger` or (if
+    /// none are) by setting `HUMILITY_TRIGGER` to one trigger per line.
+    /// (We don't have clap read the latter, as it would take the entire
+    /// variable to be a single trigger.)
+    pub fn triggers(&self) -> Vec<String> {
+        if !self.trigger.is_empty() {
+            return self.trigger.clone();
+        }
+
+        match std::env::var("HUMILITY_TRIGGER") {
+            Ok(triggers) => triggers
+                .lines()
+                .filter(|t| !t.is_empty())
+                .map(|t| t.to_string())
+                .collect(),
+            Err(_) => vec![],
+        }
+    }
+}
+
 #[derive(StructOpt)]
 pub enum Subcommand {
     #[structopt(external_subcommand)]
@@ -74,12 +214,20 @@ pub enum Archive {
     Ignored,
 }
 
+///
+/// The kind of core to which a command attaches.  Rather than insisting on
+/// either a live system or a dump, a command can specify (via
+/// [`Attach::Requires`]) the core operations that it needs; it will then
+/// attach to a dump if one is specified and it needs only operations that a
+/// dump provides (e.g., reading memory and registers).
+///
 #[allow(dead_code)]
 #[derive(Copy, Clone, Debug)]
 pub enum Attach {
     LiveOnly,
     DumpOnly,
     Any,
+    Requires(CoreOps),
 }
 
 #[allow(dead_code)]
@@ -119,7 +267,12 @@ pub fn attach_live(args: &Args) -> Result<Box<dyn Core>> {
             None => "auto",
         };
 
-        humility::core::attach(probe, &args.chip)
+        //
+        // Cores that can't set breakpoints and watchpoints themselves have
+        // them set by programming the FPB and DWT directly.
+        //
+        let core = humility::core::attach(probe, &args.chip)?;
+        Ok(BreakpointCore::wrap(core))
     }
 }
 
@@ -134,6 +287,66 @@ pub fn attach_dump(
     }
 }
 
+///
+/// Attaches to a dump (if one has been specified) or to a live system, and
+/// checks that the resulting core supports the specified operations.
+///
+pub fn attach_requiring(
+    args: &Args,
+    hubris: &HubrisArchive,
+    ops: CoreOps,
+) -> Result<Box<dyn Core>> {
+    let core = if args.dump.is_some() {
+        attach_dump(args, hubris)?
+    } else {
+        attach_live(args)?
+    };
+
+    let missing = core.ops().missing(ops);
+
+    if missing != CoreOps::NONE {
+        bail!(
+            "{} does not support required operations ({}){}",
+            core.info().0,
+            missing,
+            if core.is_dump() {
+                "; must be run against a live system"
+            } else {
+                ""
+            }
+        );
+    }
+
+    Ok(core)
+}
+
+///
+/// Runs the specified closure with a core that coalesces small reads into
+/// larger ones (see [`CoalescingCore`]), as befits a command that decodes
+/// structures via DWARF or unwinds stacks.  Reads from writable memory are
+/// only coalesced while the core is halted, so a closure that inspects an
+/// already-halted core should call [`Core::halt`] first.  On a dump (or if
+/// the target's memory regions can't be determined), the closure is simply
+/// run with the specified core.
+///
+pub fn coalesced<T>(
+    hubris: &HubrisArchive,
+    core: &mut dyn Core,
+    f: impl FnOnce(&mut dyn Core) -> Result<T>,
+) -> Result<T> {
+    if core.is_dump() {
+        return f(core);
+    }
+
+    match hubris.regions(core) {
+        Ok(regions) => f(&mut CoalescingCore::new(core, &regions)),
+        Err(err) => {
+            debug!("not coalescing reads: {}", err);
+            f(core)
+        }
+    }
+}
+
 pub fn printmem(bytes: &[u8], addr: u32, size: usize, width: usize) {
     let mut addr = addr;
 
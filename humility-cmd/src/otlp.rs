@@ -0,0 +1,304 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//!
+//! Export of metrics and events via the OpenTelemetry protocol (OTLP),
+//! allowing long-running commands to feed an observability stack.  An
+//! endpoint is specified via `--otlp` (e.g., `http://localhost:4318`);
+//! metrics are sent as gauges to `/v1/metrics` and events are sent as log
+//! records to `/v1/logs`, both using the JSON encoding of OTLP/HTTP.
+//!
+//! Samples and events are buffered and sent periodically (and when the
+//! exporter is dropped); a failure to export is reported but does not
+//! interrupt the command.  Every metric and event carries the attributes
+//! of the resource:  the service name ("humility") and the board (if
+//! known).
+//!
+
+use crate::Args;
+use anyhow::{anyhow, bail, Context, Result};
+use humility::hubris::HubrisArchive;
+use humility::json::Json;
+use std::collections::BTreeMap;
+use std::io::{Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+//
+// The interval at which we send buffered samples and events.
+//
+const OTLP_FLUSH_INTERVAL: Duration = Duration::from_secs(5);
+
+//
+// The timeout for connecting to (and communicating with) the collector.
+//
+const OTLP_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum OtlpSeverity {
+    Info,
+    Warn,
+    Error,
+}
+
+impl OtlpSeverity {
+    //
+    // These are the OTLP severity numbers for the base of each range.
+    //
+    fn number(&self) -> u32 {
+        match self {
+            OtlpSeverity::Info => 9,
+            OtlpSeverity::Warn => 13,
+            OtlpSeverity::Error => 17,
+        }
+    }
+
+    fn text(&self) -> &'static str {
+        match self {
+            OtlpSeverity::Info => "INFO",
+            OtlpSeverity::Warn => "WARN",
+            OtlpSeverity::Error => "ERROR",
+        }
+    }
+}
+
+pub struct OtlpExporter {
+    host: String,
+    prefix: String,
+    resource: Json,
+    gauges: BTreeMap<String, Vec<Json>>,
+    logs: Vec<Json>,
+    flushed: Instant,
+}
+
+fn string(val: &str) -> Json {
+    Json::object(vec![("stringValue", Json::from(val))])
+}
+
+fn attributes(attrs: &[(&str, &str)]) -> Json {
+    Json::Array(
+        attrs
+            .iter()
+            .map(|(k, v)| {
+                Json::object(vec![
+                    ("key", Json::from(*k)),
+                    ("value", string(v)),
+                ])
+            })
+            .collect(),
+    )
+}
+
+//
+// Returns the current time in nanoseconds, which OTLP/JSON encodes as a
+// string (as it may not be representable as a JSON number).
+//
+fn now() -> Json {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos())
+        .unwrap_or(0);
+
+    Json::from(now.to_string())
+}
+
+//
+// Constructs the body of an export request:  the resource and its single
+// scope, containing the specified records.
+//
+fn request(
+    resource: &Json,
+    kind: &str,
+    records: &str,
+    vals: Vec<Json>,
+) -> Json {
+    let scope = Json::object(vec![
+        ("scope", Json::object(vec![("name", Json::from("humility"))])),
+        (records, Json::Array(vals)),
+    ]);
+
+    Json::object(vec![(
+        format!("resource{}", kind),
+        Json::Array(vec![Json::object(vec![
+            (
+                "resource".to_string(),
+                Json::object(vec![("attributes", resource.clone())]),
+            ),
+            (format!("scope{}", kind), Json::Array(vec![scope])),
+        ])]),
+    )])
+}
+
+impl OtlpExporter {
+    ///
+    /// Returns an exporter if an OTLP endpoint has been specified.
+    ///
+    pub fn new(args: &Args, hubris: &HubrisArchive) -> Result<Option<Self>> {
+        let endpoint = match &args.otlp {
+            Some(endpoint) => endpoint,
+            None => return Ok(None),
+        };
+
+        let rest = match endpoint.strip_prefix("http://") {
+            Some(rest) => rest,
+            None if endpoint.starts_with("https://") => {
+                bail!("OTLP export over HTTPS is not supported");
+            }
+            None => endpoint.as_str(),
+        };
+
+        let (host, prefix) = match rest.split_once('/') {
+            Some((host, prefix)) => (host, prefix.trim_end_matches('/')),
+            None => (rest, ""),
+        };
+
+        let host = if host.contains(':') {
+            host.to_string()
+        } else {
+            format!("{}:4318", host)
+        };
+
+        let mut resource = vec![("service.name", "humility")];
+
+        if let Some(board) = hubris.board() {
+            resource.push(("board", board));
+        }
+
+        Ok(Some(Self {
+            host,
+            prefix: if prefix.is_empty() {
+                "".to_string()
+            } else {
+                format!("/{}", prefix)
+            },
+            resource: attributes(&resource),
+            gauges: BTreeMap::new(),
+            logs: vec![],
+            flushed: Instant::now(),
+        }))
+    }
+
+    /// Records a sample of the specified gauge.
+    pub fn gauge(&mut self, name: &str, value: f64, attrs: &[(&str, &str)]) {
+        self.gauges.entry(name.to_string()).or_default().push(Json::object(
+            vec![
+                ("timeUnixNano", now()),
+                ("asDouble", Json::from(value)),
+                ("attributes", attributes(attrs)),
+            ],
+        ));
+    }
+
+    /// Records an event of the specified severity.
+    pub fn event(
+        &mut self,
+        severity: OtlpSeverity,
+        body: &str,
+        attrs: &[(&str, &str)],
+    ) {
+        self.logs.push(Json::object(vec![
+            ("timeUnixNano", now()),
+            ("severityNumber", Json::from(severity.number())),
+            ("severityText", Json::from(severity.text())),
+            ("body", string(body)),
+            ("attributes", attributes(attrs)),
+        ]));
+    }
+
+    fn post(&self, path: &str, body: &Json) -> Result<()> {
+        let body = body.to_string();
+
+        let addr = self
+            .host
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| anyhow!("could not resolve {}", self.host))?;
+
+        let mut stream = TcpStream::connect_timeout(&addr, OTLP_TIMEOUT)?;
+        stream.set_read_timeout(Some(OTLP_TIMEOUT))?;
+        stream.set_write_timeout(Some(OTLP_TIMEOUT))?;
+
+        write!(
+            stream,
+            "POST {}{} HTTP/1.1\r\nHost: {}\r\n\
+            Content-Type: application/json\r\nContent-Length: {}\r\n\
+            Connection: close\r\n\r\n{}",
+            self.prefix,
+            path,
+            self.host,
+            body.len(),
+            body
+        )?;
+
+        let mut response = String::new();
+        stream.read_to_string(&mut response)?;
+
+        let status = response.lines().next().unwrap_or("");
+
+        match status.split_whitespace().nth(1) {
+            Some(code) if code.starts_with('2') => Ok(()),
+            _ => bail!("{}{} failed: {}", self.prefix, path, status),
+        }
+    }
+
+    /// Sends any buffered samples and events.
+    pub fn flush(&mut self) -> Result<()> {
+        self.flushed = Instant::now();
+
+        if !self.gauges.is_empty() {
+            let gauges = std::mem::take(&mut self.gauges);
+
+            let metrics = gauges
+                .into_iter()
+                .map(|(name, points)| {
+                    Json::object(vec![
+                        ("name", Json::from(name)),
+                        (
+                            "gauge",
+                            Json::object(vec![(
+                                "dataPoints",
+                                Json::from(points),
+                            )]),
+                        ),
+                    ])
+                })
+                .collect();
+
+            let body = request(&self.resource, "Metrics", "metrics", metrics);
+
+            self.post("/v1/metrics", &body)
+                .context("failed to export metrics")?;
+        }
+
+        if !self.logs.is_empty() {
+            let logs = std::mem::take(&mut self.logs);
+            let body = request(&self.resource, "Logs", "logRecords", logs);
+
+            self.post("/v1/logs", &body).context("failed to export events")?;
+        }
+
+        Ok(())
+    }
+
+    ///
+    /// Sends buffered samples and events if the flush interval has elapsed.
+    /// Failures are reported but otherwise ignored, as we don't want to
+    /// interrupt a long-running command because a collector is unavailable.
+    ///
+    pub fn tick(&mut self) {
+        if self.flushed.elapsed() >= OTLP_FLUSH_INTERVAL {
+            if let Err(err) = self.flush() {
+                warn!("{:?}", err);
+            }
+        }
+    }
+}
+
+impl Drop for OtlpExporter {
+    fn drop(&mut self) {
+        if let Err(err) = self.flush() {
+            warn!("{:?}", err);
+        }
+    }
+}
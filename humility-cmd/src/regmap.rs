@@ -0,0 +1,393 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//!
+//! Register maps for I2C devices, allowing the values read from a device to
+//! be decoded into named registers, scaled values and fields rather than
+//! displayed as raw bytes.  In addition to the register maps that are built
+//! in, maps can be defined in a TOML file, e.g.:
+//!
+//! ```toml
+//! [[device]]
+//! name = "tmp117"
+//!
+//! [[device.register]]
+//! name = "temperature"
+//! address = 0x00
+//! size = 2
+//! signed = true
+//! scale = 0.0078125
+//! units = "C"
+//!
+//! [[device.register]]
+//! name = "configuration"
+//! address = 0x01
+//! size = 2
+//! fields = [
+//!     { name = "high-alert", msb = 15 },
+//!     { name = "conv", msb = 9, lsb = 7 },
+//! ]
+//! ```
+//!
+//! Registers are one byte by default, and multi-byte registers are
+//! big-endian by default (as is conventional for I2C devices).  If only
+//! some bits of a register constitute its value, they can be specified via
+//! `msb` and `lsb`; any scale (and offset) is applied to the value after it
+//! has been extracted (and, if `signed`, sign-extended).  Maps defined in a
+//! file take precedence over built-in maps with the same name.
+//!
+
+use anyhow::{bail, Context, Result};
+use serde::Deserialize;
+use std::fmt::Write;
+use std::fs;
+
+/// A field of a register, spanning bits `msb` through `lsb` (inclusive).
+#[derive(Clone, Debug, Deserialize)]
+pub struct I2cField {
+    pub name: String,
+    pub msb: u8,
+    pub lsb: Option<u8>,
+}
+
+impl I2cField {
+    /// Extracts the field from the raw value of its register.
+    pub fn extract(&self, raw: u64) -> u64 {
+        extract(raw, self.msb, self.lsb.unwrap_or(self.msb))
+    }
+}
+
+#[derive(Copy, Clone, Debug, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum I2cEndian {
+    Big,
+    Little,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct I2cRegister {
+    pub name: String,
+    pub address: u8,
+    pub size: Option<u8>,
+    pub endian: Option<I2cEndian>,
+    pub msb: Option<u8>,
+    pub lsb: Option<u8>,
+    #[serde(default)]
+    pub signed: bool,
+    pub scale: Option<f64>,
+    pub offset: Option<f64>,
+    pub units: Option<String>,
+    #[serde(default)]
+    pub fields: Vec<I2cField>,
+}
+
+fn extract(raw: u64, msb: u8, lsb: u8) -> u64 {
+    let width = (msb - lsb + 1) as u32;
+
+    if width >= 64 {
+        raw >> lsb
+    } else {
+        (raw >> lsb) & ((1u64 << width) - 1)
+    }
+}
+
+impl I2cRegister {
+    /// The size of the register, in bytes.
+    pub fn size(&self) -> u8 {
+        self.size.unwrap_or(1)
+    }
+
+    /// Assembles the raw value of the register from the bytes read.
+    pub fn raw(&self, bytes: &[u8]) -> Result<u64> {
+        if bytes.len() != self.size() as usize {
+            bail!(
+                "{}: expected {} bytes, found {}",
+                self.name,
+                self.size(),
+                bytes.len()
+            );
+        }
+
+        let fold = |val: u64, b: &u8| (val << 8) | *b as u64;
+
+        Ok(match self.endian.unwrap_or(I2cEndian::Big) {
+            I2cEndian::Big => bytes.iter().fold(0, fold),
+            I2cEndian::Little => bytes.iter().rev().fold(0, fold),
+        })
+    }
+
+    /// Determines the value of the register from its raw value:  the value
+    /// bits are extracted and sign-extended (as needed), and then scaled.
+    pub fn value(&self, raw: u64) -> f64 {
+        let msb = self.msb.unwrap_or(self.size() * 8 - 1);
+        let lsb = self.lsb.unwrap_or(0);
+        let val = extract(raw, msb, lsb);
+        let width = (msb - lsb + 1) as u32;
+
+        let val = if self.signed && width < 64 && val >> (width - 1) != 0 {
+            val as i64 - (1i64 << width)
+        } else {
+            val as i64
+        };
+
+        val as f64 * self.scale.unwrap_or(1.0) + self.offset.unwrap_or(0.0)
+    }
+
+    /// Returns true if the register has a value other than its raw value.
+    pub fn scaled(&self) -> bool {
+        self.signed
+            || self.msb.is_some()
+            || self.lsb.is_some()
+            || self.scale.is_some()
+            || self.offset.is_some()
+    }
+
+    /// Describes the register, given its raw value.
+    pub fn describe(&self, raw: u64) -> String {
+        let mut rval =
+            format!("0x{:0width$x}", raw, width = self.size() as usize * 2);
+
+        if self.scaled() {
+            write!(rval, " = {}", self.value(raw)).unwrap();
+
+            if let Some(ref units) = self.units {
+                write!(rval, " {}", units).unwrap();
+            }
+        }
+
+        for field in &self.fields {
+            write!(rval, " {}=0x{:x}", field.name, field.extract(raw)).unwrap();
+        }
+
+        rval
+    }
+
+    fn check(&self, device: &str) -> Result<()> {
+        let bits = self.size() as u32 * 8;
+
+        if self.size() == 0 || self.size() > 8 {
+            bail!("{}: {}: invalid size {}", device, self.name, self.size());
+        }
+
+        let check = |what: &str, msb: u8, lsb: u8| {
+            if msb < lsb || msb as u32 >= bits {
+                bail!("{}: {}: invalid bits for {}", device, self.name, what);
+            }
+
+            Ok(())
+        };
+
+        check(
+            "value",
+            self.msb.unwrap_or(bits as u8 - 1),
+            self.lsb.unwrap_or(0),
+        )?;
+
+        for field in &self.fields {
+            check(&field.name, field.msb, field.lsb.unwrap_or(field.msb))?;
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct I2cRegisterMap {
+    pub name: String,
+    pub register: Vec<I2cRegister>,
+}
+
+impl I2cRegisterMap {
+    /// Looks up a register by name (ignoring case) or by address.
+    pub fn lookup(&self, register: &str) -> Option<&I2cRegister> {
+        match parse_int::parse::<u8>(register) {
+            Ok(addr) => self.register.iter().find(|r| r.address == addr),
+            Err(_) => self
+                .register
+                .iter()
+                .find(|r| r.name.eq_ignore_ascii_case(register)),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct I2cRegisterMapDefinitions {
+    device: Vec<I2cRegisterMap>,
+}
+
+//
+// Our built-in register maps, in the same format as a definitions file.
+//
+const REGMAP_BUILTIN: &str = r#"
+[[device]]
+name = "tmp117"
+
+[[device.register]]
+name = "temperature"
+address = 0x00
+size = 2
+signed = true
+scale = 0.0078125
+units = "C"
+
+[[device.register]]
+name = "configuration"
+address = 0x01
+size = 2
+fields = [
+    { name = "high-alert", msb = 15 },
+    { name = "low-alert", msb = 14 },
+    { name = "data-ready", msb = 13 },
+    { name = "eeprom-busy", msb = 12 },
+    { name = "mod", msb = 11, lsb = 10 },
+    { name = "conv", msb = 9, lsb = 7 },
+    { name = "avg", msb = 6, lsb = 5 },
+]
+
+[[device.register]]
+name = "high-limit"
+address = 0x02
+size = 2
+signed = true
+scale = 0.0078125
+units = "C"
+
+[[device.register]]
+name = "low-limit"
+address = 0x03
+size = 2
+signed = true
+scale = 0.0078125
+units = "C"
+
+[[device.register]]
+name = "device-id"
+address = 0x0f
+size = 2
+fields = [
+    { name = "rev", msb = 15, lsb = 12 },
+    { name = "did", msb = 11, lsb = 0 },
+]
+
+[[device]]
+name = "adt7420"
+
+[[device.register]]
+name = "temperature"
+address = 0x00
+size = 2
+msb = 15
+lsb = 3
+signed = true
+scale = 0.0625
+units = "C"
+
+[[device.register]]
+name = "status"
+address = 0x02
+fields = [
+    { name = "rdy", msb = 7 },
+    { name = "t-crit", msb = 6 },
+    { name = "t-high", msb = 5 },
+    { name = "t-low", msb = 4 },
+]
+
+[[device.register]]
+name = "configuration"
+address = 0x03
+fields = [
+    { name = "resolution", msb = 7 },
+    { name = "mode", msb = 6, lsb = 5 },
+    { name = "fault-queue", msb = 1, lsb = 0 },
+]
+
+[[device.register]]
+name = "id"
+address = 0x0b
+
+[[device]]
+name = "tse2004av"
+
+[[device.register]]
+name = "capabilities"
+address = 0x00
+size = 2
+
+[[device.register]]
+name = "configuration"
+address = 0x01
+size = 2
+fields = [
+    { name = "hysteresis", msb = 10, lsb = 9 },
+    { name = "shutdown", msb = 8 },
+]
+
+[[device.register]]
+name = "temperature"
+address = 0x05
+size = 2
+msb = 12
+lsb = 0
+signed = true
+scale = 0.0625
+units = "C"
+fields = [
+    { name = "above-critical", msb = 15 },
+    { name = "above-high", msb = 14 },
+    { name = "below-low", msb = 13 },
+]
+
+[[device.register]]
+name = "manufacturer-id"
+address = 0x06
+size = 2
+
+[[device.register]]
+name = "device-id"
+address = 0x07
+size = 2
+"#;
+
+fn definitions(toml: &str) -> Result<Vec<I2cRegisterMap>> {
+    let definitions: I2cRegisterMapDefinitions = toml::from_str(toml)?;
+
+    for map in &definitions.device {
+        for register in &map.register {
+            register.check(&map.name)?;
+        }
+    }
+
+    Ok(definitions.device)
+}
+
+pub struct I2cRegisterMaps {
+    maps: Vec<I2cRegisterMap>,
+}
+
+impl I2cRegisterMaps {
+    /// Returns the built-in register maps, preceded by those defined in the
+    /// specified file (if any).
+    pub fn new(filename: Option<&str>) -> Result<Self> {
+        let mut maps = vec![];
+
+        if let Some(filename) = filename {
+            let contents = fs::read_to_string(filename)
+                .with_context(|| format!("failed to read {}", filename))?;
+
+            maps.extend(
+                definitions(&contents)
+                    .with_context(|| format!("failed to parse {}", filename))?,
+            );
+        }
+
+        maps.extend(definitions(REGMAP_BUILTIN)?);
+
+        Ok(Self { maps })
+    }
+
+    /// Looks up a register map by device name (ignoring case).
+    pub fn lookup(&self, name: &str) -> Option<&I2cRegisterMap> {
+        self.maps.iter().find(|m| m.name.eq_ignore_ascii_case(name))
+    }
+}
@@ -0,0 +1,331 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//!
+//! Support for SEGGER Real-Time Transfer (RTT), whereby the target writes
+//! to (and reads from) ring buffers in its RAM that the debugger accesses
+//! while the target runs.  The buffers are described by a control block
+//! that begins with the ID `SEGGER RTT`; the control block is either found
+//! via its symbol (`_SEGGER_RTT`) or by searching RAM for its ID.
+//!
+//! Each control block has some number of up channels (from the target to
+//! the host) and down channels (from the host to the target).  For each,
+//! the writer owns the write offset and the reader owns the read offset,
+//! allowing both to be accessed without any synchronization beyond that
+//! of the memory interface.
+//!
+
+use anyhow::{bail, Result};
+use humility::core::Core;
+use humility::hubris::HubrisArchive;
+
+/// The ID that denotes the control block
+pub const RTT_ID: &[u8] = b"SEGGER RTT";
+
+/// The size of the ID field of the control block
+const RTT_ID_SIZE: u32 = 16;
+
+/// The size of a buffer descriptor
+const RTT_DESC_SIZE: u32 = 24;
+
+//
+// The offsets of the fields within a buffer descriptor.
+//
+const RTT_DESC_NAME: u32 = 0;
+const RTT_DESC_BUFFER: u32 = 4;
+const RTT_DESC_SIZEOF: u32 = 8;
+const RTT_DESC_WROFF: u32 = 12;
+const RTT_DESC_RDOFF: u32 = 16;
+
+//
+// A sanity bound on the number of channels in a control block, to detect
+// a control block that hasn't been initialized (or isn't one at all).
+//
+const RTT_MAX_CHANNELS: u32 = 32;
+
+/// The size of the chunks in which we search memory
+const RTT_SEARCH_CHUNK: u32 = 1024;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RttDirection {
+    Up,
+    Down,
+}
+
+#[derive(Clone, Debug)]
+pub struct RttChannel {
+    pub direction: RttDirection,
+    pub index: u32,
+    pub name: Option<String>,
+    desc: u32,
+    buffer: u32,
+    pub size: u32,
+}
+
+#[derive(Clone, Debug)]
+pub struct RttControlBlock {
+    pub addr: u32,
+    pub up: Vec<RttChannel>,
+    pub down: Vec<RttChannel>,
+}
+
+//
+// Reads a NUL-terminated name, bounded to a reasonable length.
+//
+fn read_name(core: &mut dyn Core, addr: u32) -> Option<String> {
+    if addr == 0 {
+        return None;
+    }
+
+    let mut buf = [0u8; 32];
+    core.read_8(addr, &mut buf).ok()?;
+
+    let len = buf.iter().position(|&c| c == 0).unwrap_or(buf.len());
+    Some(String::from_utf8_lossy(&buf[..len]).to_string())
+}
+
+impl RttChannel {
+    fn read_desc(
+        core: &mut dyn Core,
+        direction: RttDirection,
+        index: u32,
+        desc: u32,
+    ) -> Result<Self> {
+        let name = core.read_word_32(desc + RTT_DESC_NAME)?;
+        let buffer = core.read_word_32(desc + RTT_DESC_BUFFER)?;
+        let size = core.read_word_32(desc + RTT_DESC_SIZEOF)?;
+
+        Ok(Self {
+            direction,
+            index,
+            name: read_name(core, name),
+            desc,
+            buffer,
+            size,
+        })
+    }
+
+    ///
+    /// Reads any data available on an up channel, advancing the read
+    /// offset accordingly.
+    ///
+    pub fn read(&self, core: &mut dyn Core) -> Result<Vec<u8>> {
+        if self.direction != RttDirection::Up {
+            bail!("channel {} is not an up channel", self.index);
+        }
+
+        let wroff = core.read_word_32(self.desc + RTT_DESC_WROFF)?;
+        let rdoff = core.read_word_32(self.desc + RTT_DESC_RDOFF)?;
+
+        if wroff >= self.size || rdoff >= self.size {
+            bail!(
+                "channel {} has bad offsets (write {}, read {}, size {})",
+                self.index,
+                wroff,
+                rdoff,
+                self.size
+            );
+        }
+
+        if wroff == rdoff {
+            return Ok(vec![]);
+        }
+
+        let mut rval = vec![];
+
+        let mut read = |from: u32, to: u32| -> Result<()> {
+            let mut buf = vec![0u8; (to - from) as usize];
+            core.read_8(self.buffer + from, &mut buf)?;
+            rval.extend_from_slice(&buf);
+            Ok(())
+        };
+
+        if wroff > rdoff {
+            read(rdoff, wroff)?;
+        } else {
+            read(rdoff, self.size)?;
+
+            if wroff > 0 {
+                read(0, wroff)?;
+            }
+        }
+
+        core.write_word_32(self.desc + RTT_DESC_RDOFF, wroff)?;
+
+        Ok(rval)
+    }
+
+    ///
+    /// Writes as much of the specified data as will fit to a down channel,
+    /// returning the number of bytes written.
+    ///
+    pub fn write(&self, core: &mut dyn Core, data: &[u8]) -> Result<usize> {
+        if self.direction != RttDirection::Down {
+            bail!("channel {} is not a down channel", self.index);
+        }
+
+        let mut wroff = core.read_word_32(self.desc + RTT_DESC_WROFF)?;
+        let rdoff = core.read_word_32(self.desc + RTT_DESC_RDOFF)?;
+
+        if wroff >= self.size || rdoff >= self.size {
+            bail!("channel {} has bad offsets", self.index);
+        }
+
+        //
+        // One byte is always left empty to distinguish full from empty.
+        //
+        let avail = if rdoff > wroff {
+            rdoff - wroff - 1
+        } else {
+            self.size - wroff + rdoff - 1
+        };
+
+        let mut written = 0;
+        let total = std::cmp::min(avail as usize, data.len());
+
+        while written < total {
+            let contiguous = (self.size - wroff) as usize;
+            let n = std::cmp::min(contiguous, total - written);
+
+            core.write_8(self.buffer + wroff, &data[written..written + n])?;
+
+            written += n;
+            wroff = (wroff + n as u32) % self.size;
+        }
+
+        if written > 0 {
+            core.write_word_32(self.desc + RTT_DESC_WROFF, wroff)?;
+        }
+
+        Ok(written)
+    }
+}
+
+impl RttControlBlock {
+    /// Reads the control block at the specified address.
+    pub fn read(core: &mut dyn Core, addr: u32) -> Result<Self> {
+        let mut id = [0u8; RTT_ID_SIZE as usize];
+        core.read_8(addr, &mut id)?;
+
+        if !id.starts_with(RTT_ID) {
+            bail!("no RTT control block at 0x{:x}", addr);
+        }
+
+        let nup = core.read_word_32(addr + RTT_ID_SIZE)?;
+        let ndown = core.read_word_32(addr + RTT_ID_SIZE + 4)?;
+
+        if nup > RTT_MAX_CHANNELS || ndown > RTT_MAX_CHANNELS {
+            bail!(
+                "RTT control block at 0x{:x} has implausible channel counts \
+                (up {}, down {})",
+                addr,
+                nup,
+                ndown
+            );
+        }
+
+        let base = addr + RTT_ID_SIZE + 8;
+        let mut up = vec![];
+        let mut down = vec![];
+
+        for i in 0..nup {
+            let desc = base + i * RTT_DESC_SIZE;
+            up.push(RttChannel::read_desc(core, RttDirection::Up, i, desc)?);
+        }
+
+        for i in 0..ndown {
+            let desc = base + (nup + i) * RTT_DESC_SIZE;
+            down.push(RttChannel::read_desc(
+                core,
+                RttDirection::Down,
+                i,
+                desc,
+            )?);
+        }
+
+        Ok(Self { addr, up, down })
+    }
+
+    ///
+    /// Searches the specified ranges (base/size tuples) for a control block,
+    /// returning its address if found.
+    ///
+    pub fn search(
+        core: &mut dyn Core,
+        ranges: &[(u32, u32)],
+    ) -> Result<Option<u32>> {
+        for &(base, size) in ranges {
+            let end = base as u64 + size as u64;
+            let mut addr = base;
+
+            while (addr as u64) < end {
+                //
+                // We read a little beyond our chunk to find an ID that
+                // straddles chunks.
+                //
+                let len = std::cmp::min(
+                    RTT_SEARCH_CHUNK as u64 + RTT_ID.len() as u64 - 1,
+                    end - addr as u64,
+                );
+
+                let mut buf = vec![0u8; len as usize];
+                core.read_8(addr, &mut buf)?;
+
+                if let Some(offs) =
+                    buf.windows(RTT_ID.len()).position(|w| w == RTT_ID)
+                {
+                    return Ok(Some(addr + offs as u32));
+                }
+
+                addr += RTT_SEARCH_CHUNK;
+            }
+        }
+
+        Ok(None)
+    }
+
+    ///
+    /// Locates the control block:  at the specified address if there is
+    /// one, via its symbol if the archive has it, or otherwise by searching
+    /// the specified ranges (or, failing those, the archive's writable
+    /// memory).
+    ///
+    pub fn locate(
+        hubris: &HubrisArchive,
+        core: &mut dyn Core,
+        addr: Option<u32>,
+        ranges: &[(u32, u32)],
+    ) -> Result<Self> {
+        if let Some(addr) = addr {
+            return Self::read(core, addr);
+        }
+
+        if let Ok(var) = hubris.lookup_variable("_SEGGER_RTT") {
+            return Self::read(core, var.addr);
+        }
+
+        let mut ranges = ranges.to_vec();
+
+        if ranges.is_empty() {
+            if !hubris.loaded() {
+                bail!("must specify an address or range to search");
+            }
+
+            ranges = hubris
+                .regions(core)?
+                .values()
+                .filter(|r| r.attr.write && !r.attr.device)
+                .map(|r| (r.base, r.size))
+                .collect();
+        }
+
+        match Self::search(core, &ranges)? {
+            Some(addr) => {
+                info!("found RTT control block at 0x{:x}", addr);
+                Self::read(core, addr)
+            }
+            None => bail!("no RTT control block found"),
+        }
+    }
+}
@@ -10,6 +10,7 @@ use std::fs;
 use std::fs::OpenOptions;
 use std::io::BufWriter;
 use std::io::Write;
+use std::time::{Duration, Instant};
 
 #[derive(Copy, Clone, Debug, PartialEq)]
 pub enum TestSource {
@@ -117,9 +118,31 @@ fn nargs(token: &TestToken) -> usize {
 struct TestCompletion {
     case: String,
     result: TestResult,
+    duration: Duration,
     log: Vec<(TestSource, String)>,
 }
 
+//
+// Escapes a string for inclusion in XML (as either text or an attribute).
+//
+fn xml_escape(s: &str) -> String {
+    let mut rval = String::with_capacity(s.len());
+
+    for c in s.chars() {
+        match c {
+            '&' => rval.push_str("&amp;"),
+            '<' => rval.push_str("&lt;"),
+            '>' => rval.push_str("&gt;"),
+            '"' => rval.push_str("&quot;"),
+            '\'' => rval.push_str("&apos;"),
+            c if c.is_control() && c != '\n' && c != '\t' => {}
+            c => rval.push(c),
+        }
+    }
+
+    rval
+}
+
 pub struct TestRun<'a> {
     hubris: &'a HubrisArchive,
     log: Vec<(char, TestSource)>,
@@ -131,6 +154,8 @@ pub struct TestRun<'a> {
     ncases: Option<usize>,
     result: Option<TestRunResult>,
     results: Vec<TestCompletion>,
+    began: Instant,
+    started: Option<Instant>,
 }
 
 #[rustfmt::skip::macros(bail)]
@@ -147,6 +172,8 @@ impl<'a> TestRun<'a> {
             ncases: None,
             result: None,
             results: Vec::new(),
+            began: Instant::now(),
+            started: None,
         }
     }
 
@@ -205,6 +232,7 @@ impl<'a> TestRun<'a> {
 
                 print!("humility: running {} ... ", self.cases[self.case]);
                 std::io::stdout().flush().unwrap();
+                self.started = Some(Instant::now());
 
                 TestToken::Finish
             }
@@ -245,6 +273,11 @@ impl<'a> TestRun<'a> {
                 let completion = TestCompletion {
                     case: self.cases[self.case].clone(),
                     result: TestResult::from(tokens[1]),
+                    duration: self
+                        .started
+                        .take()
+                        .map(|started| started.elapsed())
+                        .unwrap_or_default(),
                     log,
                 };
 
@@ -364,6 +397,132 @@ impl<'a> TestRun<'a> {
         Ok(())
     }
 
+    /// Prints a summary of the run:  the number of cases that passed and
+    /// failed (and the names of those that failed), and the time taken.
+    pub fn summary(&self) {
+        let failed: Vec<&str> = self
+            .results
+            .iter()
+            .filter(|c| c.result != TestResult::Ok)
+            .map(|c| c.case.as_str())
+            .collect();
+
+        let notrun = self.cases.len().saturating_sub(self.results.len());
+
+        info!(
+            "{} passed, {} failed{} in {:.2}s",
+            self.results.len() - failed.len(),
+            failed.len(),
+            if notrun > 0 {
+                format!(", {} not run", notrun)
+            } else {
+                "".into()
+            },
+            self.began.elapsed().as_secs_f64()
+        );
+
+        if !failed.is_empty() {
+            info!("failed: {}", failed.join(", "));
+        }
+    }
+
+    /// Writes the results of the run as a JUnit-style XML report, as
+    /// consumed by most CI systems.  Cases that were expected but never
+    /// completed (e.g., because the run was aborted) are reported as
+    /// errors.
+    pub fn junit(
+        &self,
+        filename: &str,
+        err: Option<&anyhow::Error>,
+    ) -> Result<()> {
+        let file = fs::File::create(filename)?;
+        let mut out = BufWriter::new(&file);
+
+        let failures =
+            self.results.iter().filter(|c| c.result != TestResult::Ok).count();
+        let errors = self.cases.len().saturating_sub(self.results.len());
+        let name = self.hubris.board().unwrap_or("hubris");
+
+        writeln!(out, r#"<?xml version="1.0" encoding="UTF-8"?>"#)?;
+        writeln!(out, "<testsuites>")?;
+        writeln!(
+            out,
+            concat!(
+                r#"  <testsuite name="{}" tests="{}" failures="{}" "#,
+                r#"errors="{}" time="{:.3}">"#
+            ),
+            xml_escape(name),
+            self.cases.len(),
+            failures,
+            errors,
+            self.began.elapsed().as_secs_f64()
+        )?;
+
+        for c in &self.results {
+            writeln!(
+                out,
+                r#"    <testcase name="{}" classname="{}" time="{:.3}">"#,
+                xml_escape(&c.case),
+                xml_escape(name),
+                c.duration.as_secs_f64()
+            )?;
+
+            if c.result != TestResult::Ok {
+                let message = match c.result {
+                    TestResult::Unknown(ref result) => {
+                        format!("unknown result \"{}\"", result)
+                    }
+                    _ => "test failed".to_string(),
+                };
+
+                writeln!(
+                    out,
+                    r#"      <failure message="{}"/>"#,
+                    xml_escape(&message)
+                )?;
+            }
+
+            if !c.log.is_empty() {
+                writeln!(out, "      <system-out>")?;
+
+                for (source, line) in &c.log {
+                    writeln!(out, "{:?}: {}", source, xml_escape(line))?;
+                }
+
+                writeln!(out, "      </system-out>")?;
+            }
+
+            writeln!(out, "    </testcase>")?;
+        }
+
+        let message = match err {
+            Some(err) => format!("not run: {}", err),
+            None => "not run".to_string(),
+        };
+
+        for case in self.cases.iter().skip(self.results.len()) {
+            writeln!(
+                out,
+                r#"    <testcase name="{}" classname="{}">"#,
+                xml_escape(case),
+                xml_escape(name)
+            )?;
+            writeln!(
+                out,
+                r#"      <error message="{}"/>"#,
+                xml_escape(&message)
+            )?;
+            writeln!(out, "    </testcase>")?;
+        }
+
+        writeln!(out, "  </testsuite>")?;
+        writeln!(out, "</testsuites>")?;
+
+        info!("JUnit report written to {}", filename);
+
+        Ok(())
+    }
+
     pub fn completed(&mut self) -> bool {
         self.result.is_some()
     }
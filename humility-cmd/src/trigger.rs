@@ -0,0 +1,156 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//!
+//! Triggers for external instruments, allowing logic analyzer captures to
+//! be lined up with stimulus driven by Humility.  Triggers are specified
+//! via `--trigger` (which may be given more than once), in one of the
+//! following forms:
+//!
+//! - `gpio:<address>=<value>` writes `value` to `address` through the
+//!   debug probe, e.g. to a GPIO bit set/reset register to assert a pin
+//!   that the analyzer triggers on;
+//!
+//! - `sigrok:<arguments>` runs `sigrok-cli` with the specified arguments
+//!   in the background, allowing it time to arm before the operation;
+//!
+//! - `exec:<command>` runs the specified command to completion via the
+//!   shell.
+//!
+//! Triggers are fired, in the order specified, immediately before the
+//! first target operation of a command that supports them.  Any capture
+//! is waited for before Humility exits.
+//!
+
+use anyhow::{anyhow, bail, Context, Result};
+use humility::core::Core;
+use humility::hiffy::HiffyTrigger;
+use std::process::{Child, Command};
+use std::thread;
+use std::time::Duration;
+
+//
+// The time we give sigrok-cli to open its device and arm its capture.
+//
+const TRIGGER_SIGROK_ARM_MS: u64 = 1000;
+
+#[derive(Clone, Debug)]
+pub enum Trigger {
+    Gpio { addr: u32, value: u32 },
+    Sigrok(Vec<String>),
+    Exec(String),
+}
+
+impl std::str::FromStr for Trigger {
+    type Err = anyhow::Error;
+
+    fn from_str(spec: &str) -> Result<Self> {
+        let (kind, rest) = spec.split_once(':').ok_or_else(|| {
+            anyhow!("trigger \"{}\" must be of the form kind:spec", spec)
+        })?;
+
+        match kind {
+            "gpio" => {
+                let (addr, value) = rest.split_once('=').ok_or_else(|| {
+                    anyhow!("gpio trigger must be of the form address=value")
+                })?;
+
+                Ok(Trigger::Gpio {
+                    addr: parse_int::parse(addr.trim())
+                        .with_context(|| format!("bad address {}", addr))?,
+                    value: parse_int::parse(value.trim())
+                        .with_context(|| format!("bad value {}", value))?,
+                })
+            }
+            "sigrok" => Ok(Trigger::Sigrok(
+                rest.split_whitespace().map(|s| s.to_string()).collect(),
+            )),
+            "exec" => Ok(Trigger::Exec(rest.to_string())),
+            _ => bail!("unknown trigger kind \"{}\"", kind),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Triggers {
+    triggers: Vec<Trigger>,
+    captures: Vec<Child>,
+    fired: bool,
+}
+
+impl Triggers {
+    /// Parses the specified triggers (as given to `--trigger`).
+    pub fn new(specs: &[String]) -> Result<Self> {
+        let mut triggers = vec![];
+
+        for spec in specs {
+            triggers.push(spec.parse()?);
+        }
+
+        Ok(Self { triggers, captures: vec![], fired: false })
+    }
+
+    /// Fires our triggers, if they have not already been fired.
+    pub fn fire(&mut self, core: &mut dyn Core) -> Result<()> {
+        if self.fired {
+            return Ok(());
+        }
+
+        self.fired = true;
+
+        for trigger in &self.triggers {
+            match trigger {
+                Trigger::Gpio { addr, value } => {
+                    core.write_word_32(*addr, *value)?;
+                }
+
+                Trigger::Sigrok(args) => {
+                    let child = Command::new("sigrok-cli")
+                        .args(args)
+                        .spawn()
+                        .context("failed to run sigrok-cli")?;
+
+                    self.captures.push(child);
+                    thread::sleep(Duration::from_millis(TRIGGER_SIGROK_ARM_MS));
+                }
+
+                Trigger::Exec(cmd) => {
+                    let status = Command::new("sh")
+                        .arg("-c")
+                        .arg(cmd)
+                        .status()
+                        .with_context(|| {
+                            format!("failed to run \"{}\"", cmd)
+                        })?;
+
+                    if !status.success() {
+                        bail!("trigger \"{}\" failed: {}", cmd, status);
+                    }
+                }
+            }
+
+            trace!("fired trigger {:?}", trigger);
+        }
+
+        Ok(())
+    }
+}
+
+impl HiffyTrigger for Triggers {
+    fn fire(&mut self, core: &mut dyn Core) -> Result<()> {
+        Triggers::fire(self, core)
+    }
+}
+
+impl Drop for Triggers {
+    fn drop(&mut self) {
+        for child in &mut self.captures {
+            info!("waiting for capture to complete");
+
+            if let Err(err) = child.wait() {
+                warn!("failed to wait for sigrok-cli: {}", err);
+            }
+        }
+    }
+}
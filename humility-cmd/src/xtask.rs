@@ -0,0 +1,182 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//!
+//! Integration with the Hubris build, allowing an archive to be located by
+//! its app rather than by its path.  When run within a Hubris checkout
+//! (that is, a directory with an `xtask` crate and an `app` directory, or
+//! any directory beneath it), `--app` specifies either the path of an app
+//! TOML file or the name (or board) of an app; the freshest archive that
+//! `cargo xtask dist` has built for that app is used.  If `--rebuild` is
+//! also specified, `cargo xtask dist` is run first.
+//!
+
+use crate::Args;
+use anyhow::{anyhow, bail, Context, Result};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::SystemTime;
+
+//
+// How deeply we descend into the app directory looking for app TOML files.
+//
+const XTASK_APP_DEPTH: usize = 3;
+
+#[derive(Clone, Debug)]
+struct XtaskApp {
+    toml: PathBuf,
+    name: String,
+    board: Option<String>,
+}
+
+fn checkout() -> Result<PathBuf> {
+    let cwd = std::env::current_dir()?;
+
+    for dir in cwd.ancestors() {
+        if dir.join("xtask").join("Cargo.toml").is_file()
+            && dir.join("app").is_dir()
+        {
+            return Ok(dir.to_path_buf());
+        }
+    }
+
+    bail!("--app must be used within a Hubris checkout");
+}
+
+fn app(path: &Path) -> Option<XtaskApp> {
+    let contents = fs::read_to_string(path).ok()?;
+    let toml: toml::Value = toml::from_str(&contents).ok()?;
+
+    Some(XtaskApp {
+        toml: path.to_path_buf(),
+        name: toml.get("name")?.as_str()?.to_string(),
+        board: toml.get("board").and_then(|b| b.as_str()).map(String::from),
+    })
+}
+
+fn apps(dir: &Path, depth: usize, rval: &mut Vec<XtaskApp>) {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+
+    for entry in entries.flatten() {
+        let path = entry.path();
+
+        if path.is_dir() && depth > 0 {
+            apps(&path, depth - 1, rval);
+        } else if path.extension().map_or(false, |e| e == "toml") {
+            if let Some(app) = app(&path) {
+                rval.push(app);
+            }
+        }
+    }
+}
+
+//
+// Returns the archives that may have been built for the specified app:
+// older builds place the archive directly in the dist directory, newer
+// ones in a subdirectory per image.
+//
+fn archives(root: &Path, name: &str) -> Vec<(PathBuf, SystemTime)> {
+    let dist = root.join("target").join(name).join("dist");
+    let file = format!("build-{}.zip", name);
+    let mut candidates = vec![dist.join(&file)];
+
+    if let Ok(entries) = fs::read_dir(&dist) {
+        for entry in entries.flatten() {
+            if entry.path().is_dir() {
+                candidates.push(entry.path().join(&file));
+            }
+        }
+    }
+
+    candidates
+        .into_iter()
+        .filter_map(|path| {
+            let modified = fs::metadata(&path).ok()?.modified().ok()?;
+            Some((path, modified))
+        })
+        .collect()
+}
+
+fn rebuild(root: &Path, app: &XtaskApp) -> Result<()> {
+    info!("building {} via cargo xtask dist", app.name);
+
+    let status = Command::new("cargo")
+        .current_dir(root)
+        .arg("xtask")
+        .arg("dist")
+        .arg(&app.toml)
+        .status()
+        .context("failed to run cargo xtask")?;
+
+    if !status.success() {
+        bail!("cargo xtask dist {} failed: {}", app.toml.display(), status);
+    }
+
+    Ok(())
+}
+
+///
+/// Returns the path of the freshest archive for the app specified via
+/// `--app`, if any.
+///
+pub fn archive(args: &Args) -> Result<Option<String>> {
+    let spec = match &args.app {
+        Some(spec) => spec,
+        None => return Ok(None),
+    };
+
+    let root = checkout()?;
+
+    let mut found = vec![];
+
+    if Path::new(spec).is_file() {
+        found.push(
+            app(Path::new(spec))
+                .ok_or_else(|| anyhow!("{} is not a valid app", spec))?,
+        );
+    } else {
+        let mut all = vec![];
+        apps(&root.join("app"), XTASK_APP_DEPTH, &mut all);
+
+        found = all.iter().filter(|a| &a.name == spec).cloned().collect();
+
+        if found.is_empty() {
+            found = all
+                .into_iter()
+                .filter(|a| a.board.as_deref() == Some(spec.as_str()))
+                .collect();
+        }
+    }
+
+    if found.is_empty() {
+        bail!("no app named \"{}\" found in {}", spec, root.display());
+    }
+
+    if args.rebuild {
+        if found.len() > 1 {
+            bail!("\"{}\" matches multiple apps; specify an app TOML", spec);
+        }
+
+        rebuild(&root, &found[0])?;
+    }
+
+    let freshest = found
+        .iter()
+        .flat_map(|a| archives(&root, &a.name))
+        .max_by_key(|(_, modified)| *modified);
+
+    match freshest {
+        Some((path, _)) => {
+            info!("using archive {}", path.display());
+            Ok(Some(path.to_string_lossy().to_string()))
+        }
+        None => {
+            bail!("no archive has been built for \"{}\" (try --rebuild)", spec)
+        }
+    }
+}
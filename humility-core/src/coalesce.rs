@@ -0,0 +1,362 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//!
+//! A [`Core`] that coalesces small reads into larger ones.  Decoding
+//! structures via DWARF (and unwinding stacks) results in many small reads
+//! -- often of a single word -- that are dominated by the latency of the
+//! debug probe.  A [`CoalescingCore`] instead reads memory in aligned
+//! blocks, satisfying subsequent reads from any block that it has already
+//! read.
+//!
+//! Blocks are only ever read from within the memory regions that the
+//! [`CoalescingCore`] has been given, and never from device memory.  Blocks
+//! from regions that are read-only are retained for the lifetime of the
+//! [`CoalescingCore`]; blocks from writable regions are only retained while
+//! the core is halted (that is, between a call to [`Core::halt`] and the
+//! subsequent call to [`Core::run`] or [`Core::step`]), and are discarded on
+//! any write.
+//!
+
+use anyhow::Result;
+use std::collections::{BTreeMap, HashMap};
+use std::ops::Range;
+use std::path::Path;
+
+use crate::arch::ARMRegister;
+use crate::core::{Core, CoreOps, CoreWatch};
+use crate::hubris::HubrisRegion;
+
+/// The size (and alignment) of the blocks that we read.
+pub const COALESCE_BLOCKSIZE: u32 = 1024;
+
+pub struct CoalescingCore<'a> {
+    core: &'a mut dyn Core,
+
+    // cacheable ranges: base to range/writable tuple (the range is 64-bit,
+    // as a region may extend to the top of the address space)
+    ranges: BTreeMap<u32, (Range<u64>, bool)>,
+
+    // cached blocks: aligned block address to block contents
+    blocks: HashMap<u32, (bool, Vec<u8>)>,
+
+    // whether we know the core to be halted
+    halted: bool,
+
+    // number of reads made of us, and of our underlying core
+    reads: usize,
+    transactions: usize,
+}
+
+impl<'a> CoalescingCore<'a> {
+    pub fn new(
+        core: &'a mut dyn Core,
+        regions: &BTreeMap<u32, HubrisRegion>,
+    ) -> Self {
+        let ranges = regions
+            .values()
+            .filter(|r| r.attr.read && !r.attr.device)
+            .map(|r| {
+                let range = r.base as u64..r.base as u64 + r.size as u64;
+                (r.base, (range, r.attr.write))
+            })
+            .collect();
+
+        Self {
+            core,
+            ranges,
+            blocks: HashMap::new(),
+            halted: false,
+            reads: 0,
+            transactions: 0,
+        }
+    }
+
+    //
+    // Returns the cacheable range that contains the specified range (if
+    // any), along with whether the range is writable.
+    //
+    fn range(&self, addr: u32, len: usize) -> Option<(Range<u64>, bool)> {
+        let end = addr as u64 + len as u64;
+        let (_, (range, writable)) = self.ranges.range(..=addr).next_back()?;
+
+        if addr as u64 >= range.start && end <= range.end {
+            if *writable && !self.halted {
+                None
+            } else {
+                Some((range.clone(), *writable))
+            }
+        } else {
+            None
+        }
+    }
+
+    fn block(
+        &mut self,
+        block: u32,
+        range: &Range<u64>,
+        writable: bool,
+    ) -> Result<&[u8]> {
+        if !self.blocks.contains_key(&block) {
+            //
+            // We clip our block to the region, so we never read memory that
+            // we weren't told was there.
+            //
+            let start = u64::max(block as u64, range.start);
+            let end =
+                u64::min(block as u64 + COALESCE_BLOCKSIZE as u64, range.end);
+
+            let mut buf = vec![0u8; (end - start) as usize];
+            self.core.read_8(start as u32, &mut buf)?;
+            self.transactions += 1;
+
+            self.blocks.insert(block, (writable, buf));
+        }
+
+        Ok(&self.blocks[&block].1)
+    }
+
+    fn invalidate(&mut self) {
+        self.blocks.retain(|_, (writable, _)| !*writable);
+    }
+}
+
+impl<'a> Drop for CoalescingCore<'a> {
+    fn drop(&mut self) {
+        trace!(
+            "coalesced {} reads into {} transactions",
+            self.reads,
+            self.transactions
+        );
+    }
+}
+
+impl<'a> Core for CoalescingCore<'a> {
+    fn info(&self) -> (String, Option<String>) {
+        self.core.info()
+    }
+
+    fn read_word_32(&mut self, addr: u32) -> Result<u32> {
+        let mut buf = [0u8; 4];
+        self.read_8(addr, &mut buf)?;
+        Ok(u32::from_le_bytes(buf))
+    }
+
+    fn read_8(&mut self, addr: u32, data: &mut [u8]) -> Result<()> {
+        self.reads += 1;
+
+        let (range, writable) = match self.range(addr, data.len()) {
+            Some(range) => range,
+            None => {
+                self.transactions += 1;
+                return self.core.read_8(addr, data);
+            }
+        };
+
+        let mut offs = 0;
+
+        while offs < data.len() {
+            let a = addr + offs as u32;
+            let block = a - (a % COALESCE_BLOCKSIZE);
+            let bytes = self.block(block, &range, writable)?;
+
+            //
+            // Our block may have been clipped at its start.
+            //
+            let bstart =
+                (a as u64 - u64::max(block as u64, range.start)) as usize;
+            let n = usize::min(bytes.len() - bstart, data.len() - offs);
+
+            data[offs..offs + n].copy_from_slice(&bytes[bstart..bstart + n]);
+            offs += n;
+        }
+
+        Ok(())
+    }
+
+    fn read_reg(&mut self, reg: ARMRegister) -> Result<u32> {
+        self.core.read_reg(reg)
+    }
+
+    fn write_reg(&mut self, reg: ARMRegister, value: u32) -> Result<()> {
+        self.core.write_reg(reg, value)
+    }
+
+    fn init_swv(&mut self, traceclk: u32, baud: u32) -> Result<()> {
+        self.core.init_swv(traceclk, baud)
+    }
+
+    fn read_swv(&mut self) -> Result<Vec<u8>> {
+        self.core.read_swv()
+    }
+
+    fn read_dp(&mut self, addr: u8) -> Result<u32> {
+        self.core.read_dp(addr)
+    }
+
+    fn write_dp(&mut self, addr: u8, value: u32) -> Result<()> {
+        self.invalidate();
+        self.core.write_dp(addr, value)
+    }
+
+    fn read_ap(&mut self, ap: u8, addr: u8) -> Result<u32> {
+        self.core.read_ap(ap, addr)
+    }
+
+    fn write_ap(&mut self, ap: u8, addr: u8, value: u32) -> Result<()> {
+        //
+        // A write to an access port can result in a write to memory (e.g.,
+        // via a MEM-AP's DRW), so we must invalidate our cache.
+        //
+        self.invalidate();
+        self.core.write_ap(ap, addr, value)
+    }
+
+    fn write_word_32(&mut self, addr: u32, data: u32) -> Result<()> {
+        self.invalidate();
+        self.core.write_word_32(addr, data)
+    }
+
+    fn write_8(&mut self, addr: u32, data: &[u8]) -> Result<()> {
+        self.invalidate();
+        self.core.write_8(addr, data)
+    }
+
+    fn halt(&mut self) -> Result<()> {
+        if !self.halted {
+            self.invalidate();
+        }
+
+        self.core.halt()?;
+        self.halted = true;
+        Ok(())
+    }
+
+    fn run(&mut self) -> Result<()> {
+        self.halted = false;
+        self.invalidate();
+        self.core.run()
+    }
+
+    fn step(&mut self) -> Result<()> {
+        self.invalidate();
+        self.core.step()
+    }
+
+    fn is_dump(&self) -> bool {
+        self.core.is_dump()
+    }
+
+    fn ops(&self) -> CoreOps {
+        self.core.ops()
+    }
+
+    fn load(&mut self, path: &Path) -> Result<()> {
+        self.blocks.clear();
+        self.core.load(path)
+    }
+
+    fn reset(&mut self) -> Result<()> {
+        self.halted = false;
+        self.blocks.clear();
+        self.core.reset()
+    }
+
+    fn set_breakpoint(&mut self, addr: u32) -> Result<()> {
+        self.core.set_breakpoint(addr)
+    }
+
+    fn clear_breakpoint(&mut self, addr: u32) -> Result<()> {
+        self.core.clear_breakpoint(addr)
+    }
+
+    fn set_watchpoint(&mut self, addr: u32, watch: CoreWatch) -> Result<()> {
+        self.core.set_watchpoint(addr, watch)
+    }
+
+    fn clear_watchpoint(&mut self, addr: u32) -> Result<()> {
+        self.core.clear_watchpoint(addr)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::hubris::{HubrisRegionAttr, HubrisTask};
+    use crate::mock::{MockAccess, MockCore};
+
+    fn regions(list: &[(u32, u32, bool)]) -> BTreeMap<u32, HubrisRegion> {
+        list.iter()
+            .map(|&(base, size, write)| {
+                let region = HubrisRegion {
+                    daddr: None,
+                    base,
+                    size,
+                    mapsize: size,
+                    attr: HubrisRegionAttr {
+                        read: true,
+                        write,
+                        execute: false,
+                        device: false,
+                        dma: false,
+                    },
+                    task: HubrisTask::Kernel,
+                };
+
+                (base, region)
+            })
+            .collect()
+    }
+
+    fn reads(core: &MockCore) -> usize {
+        core.log().iter().filter(|a| matches!(a, MockAccess::Read(..))).count()
+    }
+
+    #[test]
+    fn coalesce() {
+        let mut mock = MockCore::new();
+        mock.memory.add(0x0800_0000, vec![0xa5; 0x1000]).unwrap();
+        let regions = regions(&[(0x0800_0000, 0x1000, false)]);
+
+        {
+            let mut core = CoalescingCore::new(&mut mock, &regions);
+
+            for addr in (0x0800_0000..0x0800_0400).step_by(4) {
+                assert_eq!(core.read_word_32(addr).unwrap(), 0xa5a5_a5a5);
+            }
+        }
+
+        assert_eq!(reads(&mock), 1);
+    }
+
+    #[test]
+    fn invalidate() {
+        let mut mock = MockCore::new();
+        mock.memory.add(0x2000_0000, vec![0; 0x1000]).unwrap();
+        let regions = regions(&[(0x2000_0000, 0x1000, true)]);
+
+        let mut core = CoalescingCore::new(&mut mock, &regions);
+        core.halt().unwrap();
+
+        assert_eq!(core.read_word_32(0x2000_0010).unwrap(), 0);
+        core.write_word_32(0x2000_0010, 0x1de).unwrap();
+        assert_eq!(core.read_word_32(0x2000_0010).unwrap(), 0x1de);
+    }
+
+    #[test]
+    fn top() {
+        let mut mock = MockCore::new();
+        mock.memory.add(0xffff_f000, vec![0xa5; 0x1000]).unwrap();
+        let regions = regions(&[(0xffff_f000, 0x1000, false)]);
+
+        {
+            let mut core = CoalescingCore::new(&mut mock, &regions);
+
+            assert_eq!(core.read_word_32(0xffff_fffc).unwrap(), 0xa5a5_a5a5);
+            assert_eq!(core.read_word_32(0xffff_fc00).unwrap(), 0xa5a5_a5a5);
+        }
+
+        assert_eq!(reads(&mock), 1);
+    }
+}
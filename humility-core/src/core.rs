@@ -2,6 +2,7 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
+use probe_rs::architecture::arm::DapAccess;
 use probe_rs::MemoryInterface;
 use probe_rs::Probe;
 
@@ -9,6 +10,7 @@ use anyhow::{anyhow, bail, ensure, Result};
 
 use crate::arch::ARMRegister;
 use crate::hubris::*;
+use serde::{Deserialize, Serialize};
 use std::collections::BTreeMap;
 use std::collections::HashMap;
 use std::convert::TryInto;
@@ -16,20 +18,143 @@ use std::fmt;
 use std::fs;
 use std::io::Read;
 use std::io::Write;
-use std::net::TcpStream;
+use std::net::{TcpStream, ToSocketAddrs};
+use std::path::Path;
 use std::str;
 use std::time::Duration;
 use std::time::Instant;
 
 use goblin::elf::Elf;
 
+///
+/// A set of operations that a [`Core`] supports.  Not every core supports
+/// every operation (a dump, for example, can be read but not written or
+/// run), so commands declare the operations that they require and can then
+/// run against any core that provides them.
+///
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct CoreOps(u32);
+
+impl CoreOps {
+    /// No operations
+    pub const NONE: CoreOps = CoreOps(0);
+    /// Reading memory
+    pub const READ: CoreOps = CoreOps(1 << 0);
+    /// Reading registers
+    pub const REGISTERS: CoreOps = CoreOps(1 << 1);
+    /// Writing memory and registers
+    pub const WRITE: CoreOps = CoreOps(1 << 2);
+    /// Halting, running and stepping the core
+    pub const CONTROL: CoreOps = CoreOps(1 << 3);
+    /// Capturing SWV output
+    pub const SWV: CoreOps = CoreOps(1 << 4);
+    /// Raw access to the debug port and its access ports
+    pub const DEBUG_PORT: CoreOps = CoreOps(1 << 5);
+    /// Programming flash and resetting the target
+    pub const FLASH: CoreOps = CoreOps(1 << 6);
+    /// Setting hardware breakpoints and watchpoints
+    pub const BREAKPOINTS: CoreOps = CoreOps(1 << 7);
+    /// All operations
+    pub const ALL: CoreOps = CoreOps((1 << 8) - 1);
+
+    const NAMES: &'static [(CoreOps, &'static str)] = &[
+        (CoreOps::READ, "read"),
+        (CoreOps::REGISTERS, "registers"),
+        (CoreOps::WRITE, "write"),
+        (CoreOps::CONTROL, "control"),
+        (CoreOps::SWV, "SWV"),
+        (CoreOps::DEBUG_PORT, "debug port"),
+        (CoreOps::FLASH, "flash"),
+        (CoreOps::BREAKPOINTS, "breakpoints"),
+    ];
+
+    /// Returns the union of two sets of operations; this is `const` to
+    /// allow commands to declare their requirements statically.
+    pub const fn union(self, other: CoreOps) -> CoreOps {
+        CoreOps(self.0 | other.0)
+    }
+
+    /// Returns true if all of the specified operations are in this set.
+    pub fn contains(self, other: CoreOps) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// Returns those of the specified operations not in this set.
+    pub fn missing(self, other: CoreOps) -> CoreOps {
+        CoreOps(other.0 & !self.0)
+    }
+
+    /// Returns the set as raw bits (e.g., to send it over the network).
+    pub fn bits(self) -> u32 {
+        self.0
+    }
+
+    /// Returns the set denoted by the specified raw bits, ignoring any
+    /// operations that we don't know about.
+    pub fn from_bits(bits: u32) -> CoreOps {
+        CoreOps(bits & CoreOps::ALL.0)
+    }
+}
+
+impl std::ops::BitOr for CoreOps {
+    type Output = CoreOps;
+
+    fn bitor(self, other: CoreOps) -> CoreOps {
+        self.union(other)
+    }
+}
+
+impl fmt::Display for CoreOps {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let names: Vec<&str> = Self::NAMES
+            .iter()
+            .filter(|(ops, _)| self.contains(*ops))
+            .map(|(_, name)| *name)
+            .collect();
+
+        if names.is_empty() {
+            write!(f, "none")
+        } else {
+            write!(f, "{}", names.join(", "))
+        }
+    }
+}
+
+///
+/// The kind of access that a watchpoint halts the core upon.
+///
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum CoreWatch {
+    Read,
+    Write,
+    ReadWrite,
+}
+
+impl fmt::Display for CoreWatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                CoreWatch::Read => "read",
+                CoreWatch::Write => "write",
+                CoreWatch::ReadWrite => "access",
+            }
+        )
+    }
+}
+
 pub trait Core {
     fn info(&self) -> (String, Option<String>);
     fn read_word_32(&mut self, addr: u32) -> Result<u32>;
     fn read_8(&mut self, addr: u32, data: &mut [u8]) -> Result<()>;
     fn read_reg(&mut self, reg: ARMRegister) -> Result<u32>;
     fn write_reg(&mut self, reg: ARMRegister, value: u32) -> Result<()>;
-    fn init_swv(&mut self) -> Result<()>;
+
+    /// Initializes SWV to capture at the specified baud rate, given the
+    /// trace clock (in Hz) from which the baud rate has been derived.
+    fn init_swv(&mut self, traceclk: u32, baud: u32) -> Result<()>;
+
     fn read_swv(&mut self) -> Result<Vec<u8>>;
     fn write_word_32(&mut self, addr: u32, data: u32) -> Result<()>;
     fn write_8(&mut self, addr: u32, data: &[u8]) -> Result<()>;
@@ -40,11 +165,72 @@ pub trait Core {
         false
     }
 
+    /// Returns the operations that this core supports.
+    fn ops(&self) -> CoreOps {
+        CoreOps::ALL
+    }
+
     fn read_word_64(&mut self, addr: u32) -> Result<u64> {
         let mut buf = [0; 8];
         self.read_8(addr, &mut buf)?;
         Ok(u64::from_le_bytes(buf))
     }
+
+    /// Reads the specified debug port register.
+    fn read_dp(&mut self, _addr: u8) -> Result<u32> {
+        bail!("raw debug port access is not supported on this target");
+    }
+
+    /// Writes the specified debug port register.
+    fn write_dp(&mut self, _addr: u8, _value: u32) -> Result<()> {
+        bail!("raw debug port access is not supported on this target");
+    }
+
+    /// Reads the specified register of the specified access port.
+    fn read_ap(&mut self, _ap: u8, _addr: u8) -> Result<u32> {
+        bail!("raw access port access is not supported on this target");
+    }
+
+    /// Writes the specified register of the specified access port.
+    fn write_ap(&mut self, _ap: u8, _addr: u8, _value: u32) -> Result<()> {
+        bail!("raw access port access is not supported on this target");
+    }
+
+    /// Programs the loadable segments of the specified ELF file into the
+    /// target's flash.
+    fn load(&mut self, _path: &Path) -> Result<()> {
+        bail!("flash programming is not supported on this target");
+    }
+
+    /// Resets the target, leaving it running.
+    fn reset(&mut self) -> Result<()> {
+        bail!("reset is not supported on this target");
+    }
+
+    /// Sets a hardware breakpoint on the specified instruction address.
+    /// Cores that can program the FPB and DWT but don't implement this
+    /// (and the other breakpoint and watchpoint operations) themselves
+    /// have them provided by wrapping them in a `BreakpointCore`, as found
+    /// in humility-cortex.
+    fn set_breakpoint(&mut self, _addr: u32) -> Result<()> {
+        bail!("hardware breakpoints are not supported on this target");
+    }
+
+    /// Clears the hardware breakpoint on the specified address.
+    fn clear_breakpoint(&mut self, _addr: u32) -> Result<()> {
+        bail!("hardware breakpoints are not supported on this target");
+    }
+
+    /// Sets a watchpoint that halts the core on the specified kind of
+    /// access to the word containing the specified address.
+    fn set_watchpoint(&mut self, _addr: u32, _watch: CoreWatch) -> Result<()> {
+        bail!("watchpoints are not supported on this target");
+    }
+
+    /// Clears the watchpoint on the word containing the specified address.
+    fn clear_watchpoint(&mut self, _addr: u32) -> Result<()> {
+        bail!("watchpoints are not supported on this target");
+    }
 }
 
 pub struct ProbeCore {
@@ -53,10 +239,232 @@ pub struct ProbeCore {
     pub vendor_id: u16,
     pub product_id: u16,
     pub serial_number: Option<String>,
+    pub errors: ProbeErrors,
 }
 
 pub const CORE_MAX_READSIZE: usize = 65536; // 64K ought to be enough for anyone
 
+///
+/// The number of times that an operation that fails with a transient error
+/// on the debug transport is retried before the error is returned.
+///
+pub const PROBE_MAX_RETRIES: usize = 3;
+
+///
+/// The log target for transient errors on the debug transport (and their
+/// retries), which are logged at the debug level -- and therefore only
+/// seen with `--verbose` or `--probe-log`.
+///
+pub const PROBE_LOG_TARGET: &str = "humility::probe";
+
+///
+/// Counts of the transient errors seen on the debug transport.
+///
+#[derive(Debug, Default)]
+pub struct ProbeErrors {
+    /// WAIT responses (the target was busy)
+    pub wait: usize,
+
+    /// FAULT responses (a sticky error was set)
+    pub fault: usize,
+
+    /// parity errors on the wire
+    pub parity: usize,
+
+    /// operations that failed despite retries
+    pub failed: usize,
+}
+
+impl ProbeErrors {
+    pub fn total(&self) -> usize {
+        self.wait + self.fault + self.parity
+    }
+
+    ///
+    /// Performs an operation, retrying it up to the specified number of
+    /// times if it fails due to a transient error (as determined by
+    /// `transient`).  Each transient error is counted, and logged to
+    /// [`PROBE_LOG_TARGET`] along with the counts thus far.
+    ///
+    pub fn attempt<T, E>(
+        &mut self,
+        what: &str,
+        retries: usize,
+        transient: impl Fn(&E) -> Option<ProbeTransient>,
+        mut op: impl FnMut() -> Result<T, E>,
+    ) -> Result<T, E> {
+        let mut attempts = 0;
+
+        loop {
+            let err = match op() {
+                Ok(rval) => return Ok(rval),
+                Err(err) => err,
+            };
+
+            let kind = match transient(&err) {
+                Some(kind) => kind,
+                None => return Err(err),
+            };
+
+            match kind {
+                ProbeTransient::Wait => self.wait += 1,
+                ProbeTransient::Fault => self.fault += 1,
+                ProbeTransient::Parity => self.parity += 1,
+            }
+
+            if attempts == retries {
+                self.failed += 1;
+
+                debug!(
+                    target: PROBE_LOG_TARGET,
+                    "{} failed ({}){}; {}",
+                    what,
+                    kind,
+                    if retries == 0 { "" } else { " after retries" },
+                    self
+                );
+
+                return Err(err);
+            }
+
+            attempts += 1;
+
+            debug!(
+                target: PROBE_LOG_TARGET,
+                "{} failed ({}); retry {} of {}; {}",
+                what,
+                kind,
+                attempts,
+                retries,
+                self
+            );
+        }
+    }
+}
+
+impl fmt::Display for ProbeErrors {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} transient error(s) ({} wait, {} fault, {} parity); \
+            {} operation(s) failed",
+            self.total(),
+            self.wait,
+            self.fault,
+            self.parity,
+            self.failed
+        )
+    }
+}
+
+///
+/// A transient error on the debug transport.
+///
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ProbeTransient {
+    Wait,
+    Fault,
+    Parity,
+}
+
+impl fmt::Display for ProbeTransient {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                ProbeTransient::Wait => "wait",
+                ProbeTransient::Fault => "fault",
+                ProbeTransient::Parity => "parity",
+            }
+        )
+    }
+}
+
+//
+// Determines if an error from probe-rs is a transient error on the debug
+// transport (and therefore one worth retrying).
+//
+fn probe_transient(err: &probe_rs::Error) -> Option<ProbeTransient> {
+    use probe_rs::architecture::arm::DapError;
+
+    let mut source: Option<&(dyn std::error::Error + 'static)> = Some(err);
+
+    while let Some(err) = source {
+        let kind = match err.downcast_ref::<DapError>() {
+            Some(DapError::WaitResponse) => Some(ProbeTransient::Wait),
+            Some(DapError::FaultResponse) => Some(ProbeTransient::Fault),
+            Some(DapError::IncorrectParity) => Some(ProbeTransient::Parity),
+            _ => None,
+        };
+
+        if kind.is_some() {
+            return kind;
+        }
+
+        source = err.source();
+    }
+
+    None
+}
+
+impl ProbeCore {
+    //
+    // Performs an operation on our session, retrying it up to the specified
+    // number of times if it fails due to a transient error.  Operations on
+    // the core reacquire it on each attempt, which reprograms the access
+    // port (clearing any sticky error) before the operation is attempted
+    // again.
+    //
+    fn attempt<T>(
+        &mut self,
+        what: &str,
+        retries: usize,
+        mut op: impl FnMut(&mut probe_rs::Session) -> Result<T, probe_rs::Error>,
+    ) -> Result<T> {
+        let session = &mut self.session;
+        let errors = &mut self.errors;
+
+        Ok(errors.attempt(what, retries, probe_transient, || op(session))?)
+    }
+
+    //
+    // Performs an operation on our core that can safely be performed more
+    // than once (a read, or a write of memory or a register), retrying it if
+    // it fails due to a transient error.
+    //
+    fn retry<T>(
+        &mut self,
+        what: &str,
+        mut op: impl FnMut(&mut probe_rs::Core) -> Result<T, probe_rs::Error>,
+    ) -> Result<T> {
+        self.attempt(what, PROBE_MAX_RETRIES, |session| {
+            op(&mut session.core(0)?)
+        })
+    }
+
+    //
+    // Performs an operation on our core that must not be repeated (e.g.,
+    // running or stepping it):  a transient error is counted, but the
+    // operation is not retried.
+    //
+    fn once<T>(
+        &mut self,
+        what: &str,
+        mut op: impl FnMut(&mut probe_rs::Core) -> Result<T, probe_rs::Error>,
+    ) -> Result<T> {
+        self.attempt(what, 0, |session| op(&mut session.core(0)?))
+    }
+}
+
+impl Drop for ProbeCore {
+    fn drop(&mut self) {
+        if self.errors.total() != 0 {
+            info!("probe saw {}", self.errors);
+        }
+    }
+}
+
 #[rustfmt::skip::macros(anyhow, bail)]
 impl Core for ProbeCore {
     fn info(&self) -> (String, Option<String>) {
@@ -70,78 +478,68 @@ impl Core for ProbeCore {
 
     fn read_word_32(&mut self, addr: u32) -> Result<u32> {
         trace!("reading word at {:x}", addr);
-        let mut core = self.session.core(0)?;
-        Ok(core.read_word_32(addr)?)
+        self.retry("read", |core| core.read_word_32(addr))
     }
 
     fn read_8(&mut self, addr: u32, data: &mut [u8]) -> Result<()> {
-        let mut core = self.session.core(0)?;
-
         if data.len() > CORE_MAX_READSIZE {
             bail!("read of {} bytes at 0x{:x} exceeds max of {}",
                 data.len(), addr, CORE_MAX_READSIZE);
         }
 
-        Ok(core.read_8(addr, data)?)
+        self.retry("read", |core| core.read_8(addr, data))
     }
 
     fn read_reg(&mut self, reg: ARMRegister) -> Result<u32> {
-        let mut core = self.session.core(0)?;
         use num_traits::ToPrimitive;
 
-        Ok(core.read_core_reg(Into::<probe_rs::CoreRegisterAddress>::into(
+        let reg = Into::<probe_rs::CoreRegisterAddress>::into(
             ARMRegister::to_u16(&reg).unwrap(),
-        ))?)
+        );
+
+        self.retry("register read", |core| core.read_core_reg(reg))
     }
 
     fn write_reg(&mut self, reg: ARMRegister, value: u32) -> Result<()> {
-        let mut core = self.session.core(0)?;
         use num_traits::ToPrimitive;
 
-        core.write_core_reg(
-            Into::<probe_rs::CoreRegisterAddress>::into(
-                ARMRegister::to_u16(&reg).unwrap(),
-            ),
-            value,
-        )?;
+        let reg = Into::<probe_rs::CoreRegisterAddress>::into(
+            ARMRegister::to_u16(&reg).unwrap(),
+        );
 
-        Ok(())
+        self.retry("register write", |core| core.write_core_reg(reg, value))
     }
 
     fn write_word_32(&mut self, addr: u32, data: u32) -> Result<()> {
-        let mut core = self.session.core(0)?;
-        core.write_word_32(addr, data)?;
-        Ok(())
+        self.retry("write", |core| core.write_word_32(addr, data))
     }
 
     fn write_8(&mut self, addr: u32, data: &[u8]) -> Result<()> {
-        let mut core = self.session.core(0)?;
-        core.write_8(addr, data)?;
-        Ok(())
+        self.retry("write", |core| core.write_8(addr, data))
     }
 
     fn halt(&mut self) -> Result<()> {
-        let mut core = self.session.core(0)?;
-        core.halt(std::time::Duration::from_millis(1000))?;
-        Ok(())
+        self.once("halt", |core| {
+            core.halt(std::time::Duration::from_millis(1000))?;
+            Ok(())
+        })
     }
 
     fn run(&mut self) -> Result<()> {
-        let mut core = self.session.core(0)?;
-        core.run()?;
-        Ok(())
+        self.once("run", |core| core.run())
     }
 
     fn step(&mut self) -> Result<()> {
-        let mut core = self.session.core(0)?;
-        core.step()?;
-        Ok(())
+        self.once("step", |core| {
+            core.step()?;
+            Ok(())
+        })
     }
 
-    fn init_swv(&mut self) -> Result<()> {
+    fn init_swv(&mut self, _traceclk: u32, baud: u32) -> Result<()> {
         use probe_rs::architecture::arm::swo::SwoConfig;
 
-        let config = SwoConfig::new(0).set_baud(2_000_000);
+        let config = SwoConfig::new(0).set_baud(baud);
         self.session.setup_swv(&config)?;
 
         /*
@@ -156,12 +554,81 @@ impl Core for ProbeCore {
     fn read_swv(&mut self) -> Result<Vec<u8>> {
         Ok(self.session.read_swo()?)
     }
+
+    //
+    // Raw debug and access port accesses are never retried:  they can't
+    // safely be repeated, as an access may have side effects on subsequent
+    // ones (an access of DRW increments TAR; a read of RDBUFF returns the
+    // result of the previous AP access; a write of SELECT, CSW or TAR
+    // changes what subsequent accesses hit).  A transient error is
+    // counted, but returned to the caller.
+    //
+    fn read_dp(&mut self, addr: u8) -> Result<u32> {
+        self.attempt("DP read", 0, |session| {
+            let interface = session.get_arm_interface()?;
+            Ok(interface.read_raw_dp_register(addr)?)
+        })
+    }
+
+    fn write_dp(&mut self, addr: u8, value: u32) -> Result<()> {
+        self.attempt("DP write", 0, |session| {
+            let interface = session.get_arm_interface()?;
+            Ok(interface.write_raw_dp_register(addr, value)?)
+        })
+    }
+
+    fn read_ap(&mut self, ap: u8, addr: u8) -> Result<u32> {
+        self.attempt("AP read", 0, |session| {
+            let interface = session.get_arm_interface()?;
+            Ok(interface.read_raw_ap_register(ap, addr)?)
+        })
+    }
+
+    fn write_ap(&mut self, ap: u8, addr: u8, value: u32) -> Result<()> {
+        self.attempt("AP write", 0, |session| {
+            let interface = session.get_arm_interface()?;
+            Ok(interface.write_raw_ap_register(ap, addr, value)?)
+        })
+    }
+
+    fn load(&mut self, path: &Path) -> Result<()> {
+        use probe_rs::flashing::{download_file, Format};
+
+        download_file(&mut self.session, path, Format::Elf)?;
+        Ok(())
+    }
+
+    fn reset(&mut self) -> Result<()> {
+        self.once("reset", |core| core.reset())
+    }
+
+    fn ops(&self) -> CoreOps {
+        //
+        // We can do everything save set breakpoints and watchpoints:  those
+        // are set by programming the FPB and DWT directly (which a command
+        // attaching to us can do via humility_cortex's BreakpointCore).
+        //
+        CoreOps::READ
+            | CoreOps::REGISTERS
+            | CoreOps::WRITE
+            | CoreOps::CONTROL
+            | CoreOps::SWV
+            | CoreOps::DEBUG_PORT
+            | CoreOps::FLASH
+    }
 }
 
 const OPENOCD_COMMAND_DELIMITER: u8 = 0x1a;
 const OPENOCD_TRACE_DATA_BEGIN: &str = "type target_trace data ";
 const OPENOCD_TRACE_DATA_END: &str = "\r\n";
 
+//
+// The SWO baud rate and trace clock that we use if SWV is read without
+// being initialized.  (The trace clock is that of the STM32F4's HSI.)
+//
+const OPENOCD_SWV_BAUD: u32 = 2_000_000;
+const OPENOCD_SWV_TRACECLK: u32 = 16_000_000;
+
 pub struct OpenOCDCore {
     stream: TcpStream,
     swv: bool,
@@ -325,14 +792,13 @@ impl Core for OpenOCDCore {
         Err(anyhow!("\"{}\": malformed return value: {:?}", cmd, rval))
     }
 
-    fn init_swv(&mut self) -> Result<()> {
+    fn init_swv(&mut self, traceclk: u32, baud: u32) -> Result<()> {
         self.swv = true;
         self.sendcmd("tpiu config disable")?;
-
-        /*
-         * XXX: This assumes STM32F4's 16Mhz clock
-         */
-        self.sendcmd("tpiu config internal - uart on 16000000")?;
+        self.sendcmd(&format!(
+            "tpiu config internal - uart on {} {}",
+            traceclk, baud
+        ))?;
         self.sendcmd("tcl_trace on")?;
 
         Ok(())
@@ -340,7 +806,7 @@ impl Core for OpenOCDCore {
 
     fn read_swv(&mut self) -> Result<Vec<u8>> {
         if !self.swv {
-            self.init_swv()?
+            self.init_swv(OPENOCD_SWV_TRACECLK, OPENOCD_SWV_BAUD)?
         }
 
         let mut rbuf = vec![0; 8192];
@@ -402,6 +868,34 @@ impl Core for OpenOCDCore {
         Ok(swv)
     }
 
+    fn read_dp(&mut self, addr: u8) -> Result<u32> {
+        let cmd = format!("dap dpreg 0x{:x}", addr);
+        let rval = self.sendcmd(&cmd)?;
+
+        parse_int::parse::<u32>(rval.trim()).map_err(|_| {
+            anyhow!("\"{}\": malformed return value: {:?}", cmd, rval)
+        })
+    }
+
+    fn write_dp(&mut self, addr: u8, value: u32) -> Result<()> {
+        self.sendcmd(&format!("dap dpreg 0x{:x} 0x{:x}", addr, value))?;
+        Ok(())
+    }
+
+    fn read_ap(&mut self, ap: u8, addr: u8) -> Result<u32> {
+        let cmd = format!("dap apreg {} 0x{:x}", ap, addr);
+        let rval = self.sendcmd(&cmd)?;
+
+        parse_int::parse::<u32>(rval.trim()).map_err(|_| {
+            anyhow!("\"{}\": malformed return value: {:?}", cmd, rval)
+        })
+    }
+
+    fn write_ap(&mut self, ap: u8, addr: u8, value: u32) -> Result<()> {
+        self.sendcmd(&format!("dap apreg {} 0x{:x} 0x{:x}", ap, addr, value))?;
+        Ok(())
+    }
+
     fn write_word_32(&mut self, addr: u32, data: u32) -> Result<()> {
         self.sendcmd(&format!("mww 0x{:x} 0x{:x}", addr, data))?;
         Ok(())
@@ -411,6 +905,45 @@ impl Core for OpenOCDCore {
         bail!("OpenOCD target does not support modifying state");
     }
 
+    fn set_breakpoint(&mut self, addr: u32) -> Result<()> {
+        self.sendcmd(&format!("bp 0x{:x} 2 hw", addr))?;
+        Ok(())
+    }
+
+    fn clear_breakpoint(&mut self, addr: u32) -> Result<()> {
+        self.sendcmd(&format!("rbp 0x{:x}", addr))?;
+        Ok(())
+    }
+
+    fn set_watchpoint(&mut self, addr: u32, watch: CoreWatch) -> Result<()> {
+        let rw = match watch {
+            CoreWatch::Read => "r",
+            CoreWatch::Write => "w",
+            CoreWatch::ReadWrite => "a",
+        };
+
+        self.sendcmd(&format!("wp 0x{:x} 4 {}", addr & !0b11, rw))?;
+        Ok(())
+    }
+
+    fn clear_watchpoint(&mut self, addr: u32) -> Result<()> {
+        self.sendcmd(&format!("rwp 0x{:x}", addr & !0b11))?;
+        Ok(())
+    }
+
+    fn ops(&self) -> CoreOps {
+        //
+        // We can write words but not arbitrary memory (or registers), and
+        // we don't actually halt the target -- though OpenOCD will halt it
+        // upon a breakpoint or watchpoint.
+        //
+        CoreOps::READ
+            | CoreOps::REGISTERS
+            | CoreOps::SWV
+            | CoreOps::DEBUG_PORT
+            | CoreOps::BREAKPOINTS
+    }
+
     fn halt(&mut self) -> Result<()> {
         /*
          * On OpenOCD, we don't halt. If GDB is connected, it gets really,
@@ -436,6 +969,7 @@ impl Core for OpenOCDCore {
 enum GDBServer {
     OpenOCD,
     JLink,
+    Qemu,
 }
 
 impl fmt::Display for GDBServer {
@@ -446,6 +980,7 @@ impl fmt::Display for GDBServer {
             match self {
                 GDBServer::OpenOCD => "OpenOCD",
                 GDBServer::JLink => "JLink",
+                GDBServer::Qemu => "QEMU",
             }
         )
     }
@@ -587,6 +1122,16 @@ impl GDBCore {
         self.recv(true)
     }
 
+    fn sendok(&mut self, cmd: &str) -> Result<()> {
+        let rstr = self.sendcmd(cmd)?;
+
+        if rstr != "OK" {
+            bail!("cmd {} failed: {}", cmd, rstr);
+        }
+
+        Ok(())
+    }
+
     fn send_32(&mut self, cmd: &str) -> Result<u32> {
         let rstr = self.sendcmd(cmd)?;
         let mut buf: Vec<u8> = vec![];
@@ -624,23 +1169,30 @@ impl GDBCore {
         let port = match server {
             GDBServer::OpenOCD => 3333,
             GDBServer::JLink => 2331,
+            GDBServer::Qemu => 1234,
         };
 
-        let host = format!("127.0.0.1:{}", port);
-        let addr = host.parse()?;
+        Self::connect(server, &format!("127.0.0.1:{}", port))
+    }
+
+    fn connect(server: GDBServer, host: &str) -> Result<GDBCore> {
+        let addr = host
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| anyhow!("bad address for GDB server: {}", host))?;
         let timeout = Duration::from_millis(100);
 
         let stream =
             TcpStream::connect_timeout(&addr, timeout).map_err(|_| {
                 anyhow!(
                 "can't connect to {} GDB server on \
-                    port {}; is it running?",
-                server, port
+                    {}; is it running?",
+                server, host
             )
             })?;
 
         /*
-         * Both the OpenOCD and JLink GDB servers stop the target upon
+         * The OpenOCD, JLink and QEMU GDB servers all stop the target upon
          * connection.  This is helpful in that we know the state that
          * we're in -- but it's also not the state that we want to be
          * in.  We explicitly run the target before returning.
@@ -701,22 +1253,40 @@ impl Core for GDBCore {
         rval
     }
 
-    fn write_reg(&mut self, _reg: ARMRegister, _value: u32) -> Result<()> {
-        Err(anyhow!(
-            "{} GDB target does not support modifying state", self.server
-        ))
+    fn write_reg(&mut self, reg: ARMRegister, value: u32) -> Result<()> {
+        use num_traits::ToPrimitive;
+
+        if self.server != GDBServer::Qemu {
+            bail!("{} GDB target does not support modifying state",
+                self.server);
+        }
+
+        let cmd = format!(
+            "P{:x}={:08x}",
+            ARMRegister::to_u16(&reg).unwrap(),
+            value.swap_bytes()
+        );
+
+        self.sendok(&cmd)
     }
 
-    fn write_word_32(&mut self, _addr: u32, _data: u32) -> Result<()> {
-        Err(anyhow!(
-            "{} GDB target does not support modifying state", self.server
-        ))
+    fn write_word_32(&mut self, addr: u32, data: u32) -> Result<()> {
+        self.write_8(addr, &data.to_le_bytes())
     }
 
-    fn write_8(&mut self, _addr: u32, _data: &[u8]) -> Result<()> {
-        Err(anyhow!(
-            "{} GDB target does not support modifying state", self.server
-        ))
+    fn write_8(&mut self, addr: u32, data: &[u8]) -> Result<()> {
+        if self.server != GDBServer::Qemu {
+            bail!("{} GDB target does not support modifying state",
+                self.server);
+        }
+
+        let mut cmd = format!("M{:x},{:x}:", addr, data.len());
+
+        for b in data {
+            cmd.push_str(&format!("{:02x}", b));
+        }
+
+        self.sendok(&cmd)
     }
 
     fn halt(&mut self) -> Result<()> {
@@ -746,16 +1316,35 @@ impl Core for GDBCore {
     }
 
     fn step(&mut self) -> Result<()> {
+        /*
+         * QEMU will single-step a halted target, replying with a stop
+         * packet; for the other GDB servers, this remains a no-op.
+         */
+        if self.server == GDBServer::Qemu && self.halted {
+            let reply = self.sendcmd("s")?;
+            trace!("step reply: {}", reply);
+        }
+
         Ok(())
     }
 
-    fn init_swv(&mut self) -> Result<()> {
+    fn init_swv(&mut self, _traceclk: u32, _baud: u32) -> Result<()> {
         Ok(())
     }
 
     fn read_swv(&mut self) -> Result<Vec<u8>> {
         Err(anyhow!("GDB target does not support SWV"))
     }
+
+    fn ops(&self) -> CoreOps {
+        let ops = CoreOps::READ | CoreOps::REGISTERS | CoreOps::CONTROL;
+
+        if self.server == GDBServer::Qemu {
+            ops | CoreOps::WRITE
+        } else {
+            ops
+        }
+    }
 }
 
 pub struct DumpCore {
@@ -798,53 +1387,50 @@ impl Core for DumpCore {
     }
 
     fn read_word_32(&mut self, addr: u32) -> Result<u32> {
-        let rsize: usize = 4;
-
-        if let Some((&base, &(size, offset))) =
-            self.regions.range(..=addr).rev().next()
-        {
-            if base > addr {
-                // fall out to the bail below.
-            } else if (addr - base) + rsize as u32 > size {
-                bail!(
-                    "0x{:x} is valid, but size ({}) exceeds max ({})",
-                    addr, rsize, size - (addr - base)
-                );
-            } else {
-                let offs = offset + (addr - base) as usize;
-
-                return Ok(u32::from_le_bytes(
-                    self.contents[offs..offs + rsize].try_into().unwrap(),
-                ));
-            }
-        }
-        bail!("read from invalid address: 0x{:x}", addr);
+        let mut buf = [0u8; 4];
+        self.read_8(addr, &mut buf)?;
+        Ok(u32::from_le_bytes(buf))
     }
 
     fn read_8(&mut self, addr: u32, data: &mut [u8]) -> Result<()> {
         let rsize = data.len();
+        let mut nread = 0;
 
-        if let Some((&base, &(size, offset))) =
-            self.regions.range(..=addr).rev().next()
-        {
-            if base > addr {
-                // fall out to the bail below.
-            } else if (addr - base) + rsize as u32 > size {
-                bail!(
-                    "0x{:x} is valid, but size ({}) exceeds max ({})",
-                    addr, rsize, size - (addr - base)
-                );
-            } else {
-                let offs = offset + (addr - base) as usize;
+        //
+        // A read may span regions that are adjacent in the address space
+        // (e.g., a task's stack abutting its data), so we satisfy it from
+        // as many regions as needed.
+        //
+        while nread < rsize {
+            let a = addr + nread as u32;
+
+            let (base, size, offset) =
+                match self.regions.range(..=a).next_back() {
+                    Some((&base, &(size, offset))) if a - base < size => {
+                        (base, size, offset)
+                    }
+                    _ if nread == 0 => {
+                        bail!("read of {} bytes from invalid address: 0x{:x}",
+                        rsize, addr);
+                    }
+                    _ => {
+                        bail!(
+                        "0x{:x} is valid, but size ({}) exceeds max ({})",
+                        addr, rsize, nread
+                    );
+                    }
+                };
 
-                data[..rsize]
-                    .copy_from_slice(&self.contents[offs..rsize + offs]);
+            let n = std::cmp::min((size - (a - base)) as usize, rsize - nread);
+            let offs = offset + (a - base) as usize;
 
-                return Ok(());
-            }
+            data[nread..nread + n]
+                .copy_from_slice(&self.contents[offs..offs + n]);
+
+            nread += n;
         }
 
-        bail!("read of {} bytes from invalid address: 0x{:x}", rsize, addr);
+        Ok(())
     }
 
     fn read_reg(&mut self, reg: ARMRegister) -> Result<u32> {
@@ -879,7 +1465,7 @@ impl Core for DumpCore {
         bail!("can't step a dump");
     }
 
-    fn init_swv(&mut self) -> Result<()> {
+    fn init_swv(&mut self, _traceclk: u32, _baud: u32) -> Result<()> {
         bail!("cannot enable SWV on a dump");
     }
 
@@ -890,10 +1476,33 @@ impl Core for DumpCore {
     fn is_dump(&self) -> bool {
         true
     }
+
+    fn ops(&self) -> CoreOps {
+        //
+        // Halting and running a dump are no-ops, allowing commands that
+        // halt the core for consistency to run against a dump -- but a dump
+        // cannot be stepped, so we don't claim control.
+        //
+        CoreOps::READ | CoreOps::REGISTERS
+    }
 }
 
+pub fn attach(probe: &str, chip: &str) -> Result<Box<dyn Core>> {
+    attach_with_speed(probe, chip, None)
+}
+
+///
+/// Attaches to the specified probe, optionally setting the speed of the
+/// debug link (in kHz).  Setting the speed is only supported for probes
+/// that we attach to directly via USB.  A probe of `net:host[:port]`
+/// attaches via a `humility daemon` (see [`crate::net`]).
+///
 #[rustfmt::skip::macros(anyhow, bail)]
-pub fn attach(mut probe: &str, chip: &str) -> Result<Box<dyn Core>> {
+pub fn attach_with_speed(
+    mut probe: &str,
+    chip: &str,
+    speed: Option<u32>,
+) -> Result<Box<dyn Core>> {
     let mut index: Option<usize> = None;
 
     if probe.contains('-') {
@@ -948,8 +1557,14 @@ pub fn attach(mut probe: &str, chip: &str) -> Result<Box<dyn Core>> {
                 }
             }
 
-            let probe = res?;
+            let mut probe = res?;
             let name = probe.get_name();
+
+            if let Some(speed) = speed {
+                let actual = probe.set_speed(speed)?;
+                info!("debug link speed set to {} kHz", actual);
+            }
+
             let session = probe.attach(chip)?;
 
             info!("attached via {}", name);
@@ -960,9 +1575,14 @@ pub fn attach(mut probe: &str, chip: &str) -> Result<Box<dyn Core>> {
                 vendor_id: probes[selected].vendor_id,
                 product_id: probes[selected].product_id,
                 serial_number: probes[selected].serial_number.clone(),
+                errors: ProbeErrors::default(),
             }))
         }
 
+        _ if speed.is_some() && probe != "usb" && probe != "auto" => {
+            bail!("cannot set link speed when attaching via {}", probe);
+        }
+
         "ocd" => {
             let mut core = OpenOCDCore::new()?;
             let version = core.sendcmd("version")?;
@@ -977,15 +1597,17 @@ pub fn attach(mut probe: &str, chip: &str) -> Result<Box<dyn Core>> {
         }
 
         "auto" => {
-            if let Ok(probe) = attach("ocd", chip) {
-                return Ok(probe);
-            }
+            if speed.is_none() {
+                if let Ok(probe) = attach("ocd", chip) {
+                    return Ok(probe);
+                }
 
-            if let Ok(probe) = attach("jlink", chip) {
-                return Ok(probe);
+                if let Ok(probe) = attach("jlink", chip) {
+                    return Ok(probe);
+                }
             }
 
-            attach("usb", chip)
+            attach_with_speed("usb", chip, speed)
         }
 
         "ocdgdb" => {
@@ -1002,6 +1624,27 @@ pub fn attach(mut probe: &str, chip: &str) -> Result<Box<dyn Core>> {
             Ok(Box::new(core))
         }
 
+        "qemu" => {
+            let core = GDBCore::new(GDBServer::Qemu)?;
+            info!("attached via QEMU");
+
+            Ok(Box::new(core))
+        }
+
+        _ if probe.starts_with("qemu:") => {
+            let core = GDBCore::connect(GDBServer::Qemu, &probe[5..])?;
+            info!("attached via QEMU at {}", &probe[5..]);
+
+            Ok(Box::new(core))
+        }
+
+        _ if probe.starts_with("net:") => {
+            let core = crate::net::NetCore::connect(&probe[4..])?;
+            info!("attached via daemon at {}", core.host());
+
+            Ok(Box::new(core))
+        }
+
         _ => Err(anyhow!("unrecognized probe: {}", probe)),
     }
 }
@@ -1014,3 +1657,98 @@ pub fn attach_dump(
     info!("attached to dump");
     Ok(Box::new(core))
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::mock::{MockAccess, MockCore, MockFault, MockOp};
+    use std::cell::RefCell;
+
+    //
+    // A logger that captures the messages logged to PROBE_LOG_TARGET (that
+    // is, what `--probe-log` displays) on the current thread.
+    //
+    struct ProbeLog;
+
+    static PROBE_LOG: ProbeLog = ProbeLog;
+
+    thread_local! {
+        static PROBE_LOGGED: RefCell<Vec<String>> = RefCell::new(vec![]);
+    }
+
+    impl log::Log for ProbeLog {
+        fn enabled(&self, metadata: &log::Metadata) -> bool {
+            metadata.target() == PROBE_LOG_TARGET
+        }
+
+        fn log(&self, record: &log::Record) {
+            if self.enabled(record.metadata()) {
+                PROBE_LOGGED.with(|logged| {
+                    logged.borrow_mut().push(record.args().to_string())
+                });
+            }
+        }
+
+        fn flush(&self) {}
+    }
+
+    fn logged() -> Vec<String> {
+        let _ = log::set_logger(&PROBE_LOG);
+        log::set_max_level(log::LevelFilter::Debug);
+        PROBE_LOGGED.with(|logged| logged.borrow_mut().drain(..).collect())
+    }
+
+    fn fault(op: MockOp) -> MockFault {
+        MockFault { op, range: None, skip: 0, count: 1 }
+    }
+
+    fn transient(_err: &anyhow::Error) -> Option<ProbeTransient> {
+        Some(ProbeTransient::Fault)
+    }
+
+    #[test]
+    fn retry_memory() {
+        let mut core = MockCore::new();
+        core.memory.add(0x2000_0000, vec![0xa5; 16]).unwrap();
+        core.inject(fault(MockOp::Read));
+        logged();
+
+        let mut errors = ProbeErrors::default();
+
+        let val = errors
+            .attempt("read", PROBE_MAX_RETRIES, transient, || {
+                core.read_word_32(0x2000_0000)
+            })
+            .unwrap();
+
+        assert_eq!(val, 0xa5a5_a5a5);
+        assert_eq!((errors.fault, errors.failed), (1, 0));
+        assert_eq!(core.log().len(), 2);
+
+        let logged = logged();
+        assert_eq!(logged.len(), 1);
+        assert!(logged[0].starts_with("read failed (fault); retry 1 of 3"));
+    }
+
+    #[test]
+    fn raw_once() {
+        let mut core = MockCore::new();
+        core.inject(fault(MockOp::Port));
+        logged();
+
+        let mut errors = ProbeErrors::default();
+
+        let rval = errors.attempt("AP write", 0, transient, || {
+            core.write_ap(0, 0x0c, 0xdead_beef)
+        });
+
+        assert!(rval.is_err());
+        assert_eq!((errors.fault, errors.failed), (1, 1));
+        assert_eq!(core.log(), &[MockAccess::WriteAp(0, 0x0c, 0xdead_beef)]);
+
+        let logged = logged();
+        assert_eq!(logged.len(), 1);
+        assert!(logged[0].starts_with("AP write failed (fault); 1 transient"));
+        assert!(logged[0].contains("1 operation(s) failed"));
+    }
+}
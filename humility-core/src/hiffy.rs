@@ -0,0 +1,1895 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use crate::core::Core;
+use crate::hubris::*;
+use crate::mock::{MockCore, MockMemory};
+use anyhow::{anyhow, bail, Context, Result};
+use hif::*;
+use postcard::{take_from_bytes, to_slice};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::convert::{TryFrom, TryInto};
+use std::rc::Rc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+#[derive(Debug, PartialEq)]
+enum State {
+    Initialized,
+    Kicked,
+    ResultsReady,
+    ResultsConsumed,
+}
+
+///
+/// Something to be fired immediately before a HIF program is kicked (e.g.,
+/// a trigger for an external instrument, allowing its capture to be lined
+/// up with the program's execution).  It is fired before every program; an
+/// implementation that should fire only once must track that itself.
+///
+pub trait HiffyTrigger: std::fmt::Debug {
+    fn fire(&mut self, core: &mut dyn Core) -> Result<()>;
+}
+
+#[derive(Debug)]
+pub struct HiffyContext<'a> {
+    hubris: &'a HubrisArchive,
+    ready: &'a HubrisVariable,
+    kick: &'a HubrisVariable,
+    text: &'a HubrisVariable,
+    data: &'a HubrisVariable,
+    rstack: &'a HubrisVariable,
+    requests: &'a HubrisVariable,
+    errors: &'a HubrisVariable,
+    failure: &'a HubrisVariable,
+    functions: HubrisGoff,
+    cached: Option<(u32, u32)>,
+    kicked: Option<Instant>,
+    timeout: u32,
+    state: State,
+    rbuf: Vec<u8>,
+    integrity: Option<Integrity>,
+    triggers: Option<Box<dyn HiffyTrigger>>,
+}
+
+///
+/// The limits of the target's HIF execution facility, as returned by
+/// [`HiffyContext::limits`].  Commands can use these to size their
+/// transfers; [`HiffyContext::run_sequence`] and
+/// [`HiffyContext::run_chunked`] use them to split work that exceeds them
+/// across multiple programs.
+///
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct HiffyLimits {
+    /// size of the program text, in bytes
+    pub text: usize,
+    /// size of the data area, in bytes
+    pub data: usize,
+    /// size of the return stack, in bytes
+    pub rstack: usize,
+}
+
+//
+// Returns the number of bytes that postcard uses to encode a length.
+//
+fn varint_size(mut val: usize) -> usize {
+    let mut rval = 1;
+
+    while val >= 0x80 {
+        val >>= 7;
+        rval += 1;
+    }
+
+    rval
+}
+
+impl HiffyLimits {
+    /// Returns the number of bytes of the return stack consumed by a
+    /// successful result with a payload of the specified size.
+    pub fn result_size(payload: usize) -> usize {
+        1 + varint_size(payload) + payload
+    }
+
+    /// Returns the largest payload that each of the specified number of
+    /// results can have while still fitting in the return stack.
+    pub fn max_payload(&self, nresults: usize) -> usize {
+        //
+        // We leave a byte for the terminating result; any payload that fits
+        // in the remainder can have a length no greater than the remainder.
+        //
+        let each = self.rstack.saturating_sub(1) / usize::max(nresults, 1);
+        each.saturating_sub(1 + varint_size(each))
+    }
+
+    /// Returns the number of results with a payload of the specified size
+    /// that fit in the return stack.
+    pub fn max_results(&self, payload: usize) -> usize {
+        self.rstack.saturating_sub(1) / Self::result_size(payload)
+    }
+}
+
+///
+/// A call that is independent of every other, as run by
+/// [`HiffyContext::run_sequence`]:  its operations must leave the stack as
+/// they found it, and must not contain any labels (as they may be run in
+/// the same program as other calls).
+///
+#[derive(Clone, Debug)]
+pub struct HiffyCall {
+    /// operations constituting the call
+    pub ops: Vec<Op>,
+    /// upper bound on the size of the payload of each result of the call
+    pub rsize: usize,
+    /// number of results that the call returns
+    pub nresults: usize,
+}
+
+impl HiffyCall {
+    /// Creates a call that returns a single result.
+    pub fn new(ops: Vec<Op>, rsize: usize) -> Self {
+        Self { ops, rsize, nresults: 1 }
+    }
+}
+
+//
+// Returns the number of bytes of text consumed by the specified operations.
+//
+fn text_size(ops: &[Op]) -> Result<usize> {
+    let mut buf = [0u8; 32];
+    let mut rval = 0;
+
+    for op in ops {
+        rval += to_slice(op, &mut buf)?.len();
+    }
+
+    Ok(rval)
+}
+
+//
+// The areas that can be checksummed by the target's HiffyCrc32 function.
+//
+const HIFFY_CRC_DATA: u32 = 0;
+const HIFFY_CRC_RSTACK: u32 = 1;
+
+//
+// How the integrity of HIF transfers is checked:  if the image provides
+// HiffyCrc32, the target computes the CRC-32 of an area; otherwise, we read
+// the area back and compute its CRC-32 ourselves.
+//
+#[derive(Copy, Clone, Debug)]
+enum Integrity {
+    Target(TargetFunction),
+    Readback,
+}
+
+///
+/// The results of a HIF program.  These are borrowed from the buffer into
+/// which the [`HiffyContext`] read the return stack, and are decoded as they
+/// are iterated over; no copy of any payload is made unless it is asked for
+/// (e.g., via [`HiffyResults::to_vec`]).
+///
+#[derive(Copy, Clone, Debug)]
+pub struct HiffyResults<'b> {
+    rstack: &'b [u8],
+    len: usize,
+    nbytes: usize,
+}
+
+#[derive(Clone, Debug)]
+pub struct HiffyResultsIter<'b> {
+    rstack: &'b [u8],
+    remaining: usize,
+}
+
+impl<'b> Iterator for HiffyResultsIter<'b> {
+    type Item = Result<&'b [u8], u32>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.remaining == 0 {
+            return None;
+        }
+
+        //
+        // We have already decoded the return stack in its entirety (when
+        // the results were constructed), so we know this can't fail.
+        //
+        let (rval, next) =
+            take_from_bytes::<FunctionResult>(self.rstack).ok()?;
+
+        self.rstack = next;
+        self.remaining -= 1;
+
+        match rval {
+            FunctionResult::Success(payload) => Some(Ok(payload)),
+            FunctionResult::Failure(code) => Some(Err(code)),
+            FunctionResult::Done => None,
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
+
+impl<'b> ExactSizeIterator for HiffyResultsIter<'b> {}
+
+impl<'b> HiffyResults<'b> {
+    fn decode(rstack: &'b [u8]) -> Result<Self> {
+        let mut len = 0;
+        let mut result = rstack;
+
+        loop {
+            let (rval, next) = take_from_bytes::<FunctionResult>(result)?;
+
+            if let FunctionResult::Done = rval {
+                result = next;
+                break;
+            }
+
+            len += 1;
+            result = next;
+        }
+
+        let nbytes = rstack.len() - result.len();
+
+        Ok(Self { rstack, len, nbytes })
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn iter(&self) -> HiffyResultsIter<'b> {
+        HiffyResultsIter { rstack: self.rstack, remaining: self.len }
+    }
+
+    pub fn get(&self, ndx: usize) -> Option<Result<&'b [u8], u32>> {
+        self.iter().nth(ndx)
+    }
+
+    pub fn first(&self) -> Option<Result<&'b [u8], u32>> {
+        self.get(0)
+    }
+
+    /// Copies the results, allowing them to outlive the context.
+    pub fn to_vec(&self) -> Vec<Result<Vec<u8>, u32>> {
+        self.iter().map(|r| r.map(|payload| payload.to_vec())).collect()
+    }
+}
+
+impl<'b> IntoIterator for HiffyResults<'b> {
+    type Item = Result<&'b [u8], u32>;
+    type IntoIter = HiffyResultsIter<'b>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+impl<'b> IntoIterator for &HiffyResults<'b> {
+    type Item = Result<&'b [u8], u32>;
+    type IntoIter = HiffyResultsIter<'b>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+#[derive(Debug)]
+pub struct HiffyFunction {
+    pub id: TargetFunction,
+    pub name: String,
+    pub args: Vec<HubrisGoff>,
+    pub argnames: Vec<String>,
+    pub errmap: HashMap<u32, String>,
+}
+
+impl HiffyFunction {
+    pub fn strerror(&self, code: u32) -> String {
+        match self.errmap.get(&code) {
+            Some(name) => name.clone(),
+            None => format!("<Unknown {} error: {}>", self.name, code),
+        }
+    }
+
+    pub fn argument_variants(
+        &self,
+        hubris: &HubrisArchive,
+        ndx: usize,
+    ) -> Result<Vec<(String, u16)>> {
+        let arg = hubris
+            .lookup_enum(self.args[ndx])
+            .context(format!("expected enum for arg #{}", ndx))?;
+
+        let mut variants = vec![];
+
+        for v in &arg.variants {
+            let tag = v.tag.ok_or_else(|| {
+                anyhow!("{}: malformed variant in arg #{}", ndx, self.args[ndx])
+            })?;
+
+            variants.push((v.name.to_string(), u16::try_from(tag)?));
+        }
+
+        Ok(variants)
+    }
+
+    pub fn lookup_argument(
+        &self,
+        hubris: &HubrisArchive,
+        what: &str,
+        ndx: usize,
+        name: &str,
+    ) -> Result<u16> {
+        let arg = hubris
+            .lookup_enum(self.args[ndx])
+            .context(format!("expected enum for {}", what))?;
+
+        for v in &arg.variants {
+            let tag = v.tag.ok_or_else(|| {
+                anyhow!("{}: malformed variant in {}", what, self.args[ndx])
+            })?;
+
+            if v.name == name {
+                return Ok(u16::try_from(tag)?);
+            }
+        }
+
+        let mut vals: Vec<String> = vec![];
+
+        for variant in &arg.variants {
+            vals.push(variant.name.to_string());
+        }
+
+        let list = vals.join(", ");
+        bail!("invalid {} \"{}\" (must be one of: {})", what, name, list);
+    }
+}
+
+///
+/// The type of an argument to a HIF function, as derived from the archive.
+/// Arguments are passed on the HIF stack as 32-bit values; a type
+/// determines which of these values are valid for the argument.
+///
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum HiffyType {
+    Bool,
+    /// an unsigned integer of the specified size, in bytes
+    Unsigned(usize),
+    /// a signed integer of the specified size, in bytes
+    Signed(usize),
+    /// an enum, with the name and tag of each variant
+    Enum(Vec<(String, u16)>),
+    Option(Box<HiffyType>),
+}
+
+impl HiffyType {
+    fn load(hubris: &HubrisArchive, goff: HubrisGoff) -> Result<HiffyType> {
+        if let Ok(base) = hubris.lookup_basetype(goff) {
+            return Ok(match base.encoding {
+                HubrisEncoding::Bool => HiffyType::Bool,
+                HubrisEncoding::Unsigned => HiffyType::Unsigned(base.size),
+                HubrisEncoding::Signed => HiffyType::Signed(base.size),
+                _ => bail!("unsupported base type {}", goff),
+            });
+        }
+
+        if let Ok(e) = hubris.lookup_enum(goff) {
+            //
+            // An option is passed as its contents -- or as none.
+            //
+            let some = e.lookup_variant_byname("Some");
+
+            if e.variants.len() == 2
+                && e.lookup_variant_byname("None").is_ok()
+                && some.is_ok()
+            {
+                let some = some?
+                    .goff
+                    .ok_or_else(|| anyhow!("malformed option {}", goff))?;
+                let inner = hubris.lookup_struct(some)?.lookup_member("__0")?;
+
+                return Ok(HiffyType::Option(Box::new(HiffyType::load(
+                    hubris, inner.goff,
+                )?)));
+            }
+
+            let mut variants = vec![];
+
+            for v in &e.variants {
+                let tag = v.tag.ok_or_else(|| {
+                    anyhow!("{}: malformed variant in {}", v.name, goff)
+                })?;
+
+                variants.push((v.name.to_string(), u16::try_from(tag)?));
+            }
+
+            return Ok(HiffyType::Enum(variants));
+        }
+
+        //
+        // A newtype (e.g., a port index) is passed as the type it wraps.
+        //
+        match hubris.lookup_struct(goff) {
+            Ok(s) if s.members.len() == 1 => {
+                HiffyType::load(hubris, s.members[0].goff)
+            }
+            _ => bail!("unsupported argument type {}", goff),
+        }
+    }
+
+    fn parse(&self, val: &str) -> Result<HiffyValue> {
+        match self {
+            HiffyType::Option(_) if val == "none" => Ok(HiffyValue::None),
+            HiffyType::Option(inner) => inner.parse(val),
+            HiffyType::Bool if val == "true" || val == "false" => {
+                Ok(HiffyValue::Bool(val == "true"))
+            }
+            HiffyType::Enum(variants)
+                if variants.iter().any(|(name, _)| name == val) =>
+            {
+                Ok(HiffyValue::Name(val.to_string()))
+            }
+            _ => match parse_int::parse::<i64>(val) {
+                Ok(v) => Ok(HiffyValue::Int(v)),
+                Err(_) => match self {
+                    HiffyType::Enum(variants) => {
+                        let names: Vec<&str> =
+                            variants.iter().map(|(n, _)| n.as_str()).collect();
+                        bail!(
+                            "invalid value \"{}\" (must be one of: {})",
+                            val,
+                            names.join(", ")
+                        );
+                    }
+                    _ => bail!("invalid value \"{}\"", val),
+                },
+            },
+        }
+    }
+
+    //
+    // Returns the 32-bit value to be pushed for the specified value, or None
+    // if the value is to be pushed as none.
+    //
+    fn marshal(&self, val: &HiffyValue) -> Result<Option<u32>> {
+        let range = |size: usize, signed: bool| -> Result<(i64, i64)> {
+            if size == 0 || size > 4 {
+                bail!("{}-byte arguments are not supported", size);
+            }
+
+            let bits = size as u32 * 8;
+
+            Ok(if signed {
+                (-(1i64 << (bits - 1)), (1i64 << (bits - 1)) - 1)
+            } else {
+                (0, (1i64 << bits) - 1)
+            })
+        };
+
+        Ok(Some(match (self, val) {
+            (HiffyType::Option(_), HiffyValue::None) => return Ok(None),
+            (HiffyType::Option(inner), val) => return inner.marshal(val),
+            (HiffyType::Bool, HiffyValue::Bool(b)) => *b as u32,
+            (HiffyType::Bool, HiffyValue::Int(v)) if *v == 0 || *v == 1 => {
+                *v as u32
+            }
+            (HiffyType::Unsigned(size), HiffyValue::Int(v))
+            | (HiffyType::Signed(size), HiffyValue::Int(v)) => {
+                let signed = matches!(self, HiffyType::Signed(_));
+                let (min, max) = range(*size, signed)?;
+
+                if *v < min || *v > max {
+                    bail!("value {} out of range ({} to {})", v, min, max);
+                }
+
+                *v as u32 & (max - min) as u32
+            }
+            (HiffyType::Enum(variants), HiffyValue::Name(name)) => {
+                match variants.iter().find(|(n, _)| n == name) {
+                    Some((_, tag)) => *tag as u32,
+                    None => bail!("invalid variant \"{}\"", name),
+                }
+            }
+            (HiffyType::Enum(variants), HiffyValue::Int(v))
+                if variants.iter().any(|(_, tag)| *tag as i64 == *v) =>
+            {
+                *v as u32
+            }
+            (HiffyType::Enum(variants), _) => {
+                let names: Vec<&str> =
+                    variants.iter().map(|(n, _)| n.as_str()).collect();
+                bail!("expected one of: {}", names.join(", "));
+            }
+            (_, val) => bail!("invalid value {:?}", val),
+        }))
+    }
+}
+
+/// An argument to a HIF function, as returned by
+/// [`HiffyFunction::parameters`].
+#[derive(Clone, Debug)]
+pub struct HiffyParameter {
+    /// name of the argument (or, if unnamed, `arg`*N*)
+    pub name: String,
+    /// name of the argument's type, as it appears in the archive
+    pub typename: String,
+    pub ty: HiffyType,
+}
+
+impl HiffyParameter {
+    /// Parses a value for the argument:  an integer, an enum variant (by
+    /// name), `true` or `false` (for a boolean), or `none` (for an option).
+    pub fn parse(&self, val: &str) -> Result<HiffyValue> {
+        self.ty.parse(val).with_context(|| {
+            format!("bad value for {} (a {})", self.name, self.typename)
+        })
+    }
+}
+
+///
+/// A value for an argument to a HIF function.  Values are type-checked
+/// against the function's arguments when a call is marshalled (see
+/// [`HiffyFunction::call_ops`]).
+///
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum HiffyValue {
+    Bool(bool),
+    Int(i64),
+    /// an enum variant, by name
+    Name(String),
+    None,
+}
+
+impl From<bool> for HiffyValue {
+    fn from(val: bool) -> Self {
+        HiffyValue::Bool(val)
+    }
+}
+
+impl From<u8> for HiffyValue {
+    fn from(val: u8) -> Self {
+        HiffyValue::Int(val as i64)
+    }
+}
+
+impl From<u16> for HiffyValue {
+    fn from(val: u16) -> Self {
+        HiffyValue::Int(val as i64)
+    }
+}
+
+impl From<u32> for HiffyValue {
+    fn from(val: u32) -> Self {
+        HiffyValue::Int(val as i64)
+    }
+}
+
+impl From<&str> for HiffyValue {
+    fn from(val: &str) -> Self {
+        HiffyValue::Name(val.to_string())
+    }
+}
+
+impl<T: Into<HiffyValue>> From<Option<T>> for HiffyValue {
+    fn from(val: Option<T>) -> Self {
+        match val {
+            Some(val) => val.into(),
+            None => HiffyValue::None,
+        }
+    }
+}
+
+///
+/// A type into which the value returned by a HIF function can be decoded.
+/// The archive doesn't describe the values that functions return, so the
+/// caller of a function specifies the type that it expects; integers are
+/// little-endian, and must be of exactly the expected size.
+///
+pub trait HiffyDecode: Sized {
+    fn decode(buf: &[u8]) -> Result<Self>;
+}
+
+macro_rules! hiffy_decode_int {
+    ($($t:ty),*) => {
+        $(
+            impl HiffyDecode for $t {
+                fn decode(buf: &[u8]) -> Result<Self> {
+                    let buf = buf.try_into().map_err(|_| {
+                        anyhow!(
+                            "expected {}-byte value, found {} bytes",
+                            std::mem::size_of::<$t>(),
+                            buf.len()
+                        )
+                    })?;
+
+                    Ok(<$t>::from_le_bytes(buf))
+                }
+            }
+        )*
+    };
+}
+
+hiffy_decode_int!(u8, u16, u32, u64);
+
+impl HiffyDecode for bool {
+    fn decode(buf: &[u8]) -> Result<Self> {
+        Ok(u8::decode(buf)? != 0)
+    }
+}
+
+impl HiffyDecode for () {
+    fn decode(_buf: &[u8]) -> Result<Self> {
+        Ok(())
+    }
+}
+
+impl HiffyDecode for Vec<u8> {
+    fn decode(buf: &[u8]) -> Result<Self> {
+        Ok(buf.to_vec())
+    }
+}
+
+impl<const N: usize> HiffyDecode for [u8; N] {
+    fn decode(buf: &[u8]) -> Result<Self> {
+        buf.try_into().map_err(|_| {
+            anyhow!("expected {} bytes, found {} bytes", N, buf.len())
+        })
+    }
+}
+
+impl HiffyFunction {
+    /// Returns the arguments of the function, with their types.
+    pub fn parameters(
+        &self,
+        hubris: &HubrisArchive,
+    ) -> Result<Vec<HiffyParameter>> {
+        self.args
+            .iter()
+            .zip(self.argnames.iter())
+            .map(|(goff, name)| -> Result<HiffyParameter> {
+                Ok(HiffyParameter {
+                    name: name.clone(),
+                    typename: hubris.lookup_type(*goff)?.name(hubris)?.into(),
+                    ty: HiffyType::load(hubris, *goff).with_context(|| {
+                        format!("{}: argument {}", self.name, name)
+                    })?,
+                })
+            })
+            .collect()
+    }
+
+    /// Parses arguments for the function, each of which is either a value
+    /// or an argument name and a value (`name=value`).  Values without names
+    /// are taken to be for arguments in order; arguments that are options
+    /// may be omitted, and are then taken to be none.
+    pub fn parse_arguments(
+        &self,
+        hubris: &HubrisArchive,
+        args: &[String],
+    ) -> Result<Vec<HiffyValue>> {
+        let params = self.parameters(hubris)?;
+        let mut values: Vec<Option<HiffyValue>> = vec![None; params.len()];
+        let mut next = 0;
+
+        for arg in args {
+            let (ndx, val) = match arg.split_once('=') {
+                Some((name, val)) => {
+                    match params.iter().position(|p| p.name == name) {
+                        Some(ndx) => (ndx, val),
+                        None => bail!("{} has no argument {}", self.name, name),
+                    }
+                }
+                None if next < params.len() => (next, arg.as_str()),
+                None => bail!("too many arguments to {}", self.name),
+            };
+
+            if values[ndx].is_some() {
+                bail!("{} specified more than once", params[ndx].name);
+            }
+
+            values[ndx] = Some(params[ndx].parse(val)?);
+            next = ndx + 1;
+        }
+
+        params
+            .iter()
+            .zip(values.into_iter())
+            .map(|(p, val)| match (val, &p.ty) {
+                (Some(val), _) => Ok(val),
+                (None, HiffyType::Option(_)) => Ok(HiffyValue::None),
+                (None, _) => {
+                    bail!("{} requires argument {}", self.name, p.name)
+                }
+            })
+            .collect()
+    }
+
+    /// Returns the operations to call the function with the specified
+    /// arguments, each of which is checked against the type of the
+    /// corresponding argument.  The operations leave the stack as they
+    /// found it, and so are suitable for use in a [`HiffyCall`].
+    pub fn call_ops(
+        &self,
+        hubris: &HubrisArchive,
+        args: &[HiffyValue],
+    ) -> Result<Vec<Op>> {
+        let params = self.parameters(hubris)?;
+
+        if args.len() != params.len() {
+            bail!(
+                "{} takes {} arguments, found {}",
+                self.name,
+                params.len(),
+                args.len()
+            );
+        }
+
+        let mut ops = vec![];
+
+        for (p, val) in params.iter().zip(args.iter()) {
+            let val = p.ty.marshal(val).with_context(|| {
+                format!("bad value for {} (a {})", p.name, p.typename)
+            })?;
+
+            ops.push(match val {
+                None => Op::PushNone,
+                Some(v) if v <= u8::MAX as u32 => Op::Push(v as u8),
+                Some(v) if v <= u16::MAX as u32 => Op::Push16(v as u16),
+                Some(v) => Op::Push32(v),
+            });
+        }
+
+        ops.push(Op::Call(self.id));
+
+        if !args.is_empty() {
+            ops.push(Op::DropN(args.len() as u8));
+        }
+
+        Ok(ops)
+    }
+
+    /// Decodes the result of a call to the function, translating an error
+    /// into its name.
+    pub fn decode<T: HiffyDecode>(
+        &self,
+        result: &Result<Vec<u8>, u32>,
+    ) -> Result<T> {
+        match result {
+            Ok(val) => T::decode(val).with_context(|| {
+                format!("bad value returned from {}", self.name)
+            }),
+            Err(code) => {
+                bail!("{} failed: {}", self.name, self.strerror(*code))
+            }
+        }
+    }
+}
+
+/// Simple wrapper `struct` that exposes a checked `get(name, nargs)`
+#[derive(Debug)]
+pub struct HiffyFunctions(pub HashMap<String, HiffyFunction>);
+
+impl HiffyFunctions {
+    pub fn get(&self, name: &str, nargs: usize) -> Result<&HiffyFunction> {
+        let f = self
+            .0
+            .get(name)
+            .ok_or_else(|| anyhow!("did not find {} function", name))?;
+        if f.args.len() != nargs {
+            bail!("mismatched function signature on {}", name);
+        }
+        Ok(f)
+    }
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.0.len() == 0
+    }
+}
+
+thread_local! {
+    //
+    // The function table is derived entirely from the archive, so we cache
+    // it (keyed by image ID) for the lifetime of the process.
+    //
+    static FUNCTIONS: RefCell<HashMap<u64, Rc<HiffyFunctions>>> =
+        RefCell::new(HashMap::new());
+}
+
+impl<'a> HiffyContext<'a> {
+    fn variable(
+        hubris: &'a HubrisArchive,
+        name: &str,
+        wordsize: bool,
+    ) -> Result<&'a HubrisVariable> {
+        let v = hubris
+            .lookup_variable(name)
+            .context("expected hiffy interface not found")?;
+
+        if wordsize && v.size != 4 {
+            bail!("expected {} to be size 4, found {}", name, v.size);
+        }
+
+        Ok(v)
+    }
+
+    fn read_word(
+        hubris: &'a HubrisArchive,
+        core: &mut dyn Core,
+        name: &str,
+    ) -> Result<u32> {
+        let v = Self::variable(hubris, name, true)?;
+
+        let result = core
+            .read_word_32(v.addr)
+            .context(format!("couldn't read {}", name))?;
+
+        Ok(result)
+    }
+
+    fn definition(hubris: &'a HubrisArchive, name: &str) -> Result<HubrisGoff> {
+        let goff = hubris
+            .lookup_definition(name)
+            .context("expected hiffy definition not found")?;
+
+        Ok(*goff)
+    }
+
+    pub fn new(
+        hubris: &'a HubrisArchive,
+        core: &mut dyn Core,
+        timeout: u32,
+    ) -> Result<HiffyContext<'a>> {
+        core.halt()?;
+
+        let (major, minor) = (
+            Self::read_word(hubris, core, "HIFFY_VERSION_MAJOR"),
+            Self::read_word(hubris, core, "HIFFY_VERSION_MINOR"),
+        );
+
+        core.run()?;
+
+        let target = (major?, minor?);
+        let ours = (HIF_VERSION_MAJOR, HIF_VERSION_MINOR);
+
+        //
+        // For now, we insist on an exact version match between Humility
+        // and Hubris.
+        //
+        if ours != target {
+            #[rustfmt::skip]
+            bail!(
+                "HIF version mismatch: target has {}.{}; ours is {}.{}",
+                target.0, target.1, ours.0, ours.1
+            );
+        }
+
+        let rstack = Self::variable(hubris, "HIFFY_RSTACK", false)?;
+
+        Ok(Self {
+            hubris,
+            ready: Self::variable(hubris, "HIFFY_READY", true)?,
+            kick: Self::variable(hubris, "HIFFY_KICK", true)?,
+            text: Self::variable(hubris, "HIFFY_TEXT", false)?,
+            data: Self::variable(hubris, "HIFFY_DATA", false)?,
+            rstack,
+            requests: Self::variable(hubris, "HIFFY_REQUESTS", true)?,
+            errors: Self::variable(hubris, "HIFFY_ERRORS", true)?,
+            failure: Self::variable(hubris, "HIFFY_FAILURE", false)?,
+            functions: Self::definition(hubris, "HIFFY_FUNCTIONS")?,
+            cached: None,
+            kicked: None,
+            timeout,
+            state: State::Initialized,
+            rbuf: vec![0; rstack.size],
+            integrity: None,
+            triggers: None,
+        })
+    }
+
+    pub fn data_size(&self) -> usize {
+        self.data.size
+    }
+
+    pub fn rstack_size(&self) -> usize {
+        self.rstack.size
+    }
+
+    pub fn text_size(&self) -> usize {
+        self.text.size
+    }
+
+    /// Returns the limits of the target's HIF execution facility.
+    pub fn limits(&self) -> HiffyLimits {
+        HiffyLimits {
+            text: self.text.size,
+            data: self.data.size,
+            rstack: self.rstack.size,
+        }
+    }
+
+    /// Enables integrity checking of HIF transfers.  Before a program that
+    /// is accompanied by data is run, the data area is checksummed and
+    /// verified; after results are read, the return stack is checksummed
+    /// and verified.  This detects corruption over a marginal debug link
+    /// (rather than, say, writing corrupted data to flash).  If the image
+    /// provides the `HiffyCrc32` function, the target computes each
+    /// checksum, at the cost of an additional HIF program per transfer;
+    /// otherwise, each area is read back and checksummed on the host, at
+    /// the cost of an additional read per transfer.
+    pub fn set_integrity(&mut self, funcs: &HiffyFunctions) {
+        self.integrity = Some(match funcs.get("HiffyCrc32", 2) {
+            Ok(crc) => Integrity::Target(crc.id),
+            Err(_) => Integrity::Readback,
+        });
+    }
+
+    //
+    // Runs a program that has the target compute the CRC-32 of the first
+    // nbytes of the specified area.  The core must be running, and no
+    // program may be in flight.
+    //
+    fn target_crc(
+        &mut self,
+        core: &mut dyn Core,
+        func: TargetFunction,
+        area: u32,
+        nbytes: usize,
+    ) -> Result<u32> {
+        let ops = [
+            Op::Push32(area),
+            Op::Push32(nbytes as u32),
+            Op::Call(func),
+            Op::Done,
+        ];
+
+        core.halt()?;
+        let rval = self.kick(core, &ops, None);
+        core.run()?;
+        rval?;
+
+        while !self.done(core)? {
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        let mut buf = [0u8; 16];
+
+        core.halt()?;
+        let rval = core.read_8(self.rstack.addr, &mut buf);
+        core.run()?;
+        rval?;
+
+        self.state = State::ResultsConsumed;
+
+        match HiffyResults::decode(&buf)?.first() {
+            Some(Ok(payload)) if payload.len() == 4 => {
+                Ok(u32::from_le_bytes(payload.try_into().unwrap()))
+            }
+            Some(Err(code)) => {
+                bail!("integrity check failed with error {}", code);
+            }
+            _ => {
+                bail!("malformed integrity check result");
+            }
+        }
+    }
+
+    //
+    // Reads back the first nbytes at the specified address and computes
+    // their CRC-32 on the host.  The core must be running.
+    //
+    fn readback_crc(
+        &self,
+        core: &mut dyn Core,
+        addr: u32,
+        nbytes: usize,
+    ) -> Result<u32> {
+        let mut buf = vec![0u8; nbytes];
+
+        core.halt()?;
+        let rval = core.read_8(addr, &mut buf);
+        core.run()?;
+        rval?;
+
+        Ok(crc32fast::hash(&buf))
+    }
+
+    fn verify_data(&mut self, core: &mut dyn Core, data: &[u8]) -> Result<()> {
+        let crc = match self.integrity {
+            Some(Integrity::Target(func)) => {
+                self.target_crc(core, func, HIFFY_CRC_DATA, data.len())?
+            }
+            Some(Integrity::Readback) => {
+                self.readback_crc(core, self.data.addr, data.len())?
+            }
+            None => return Ok(()),
+        };
+
+        if crc != crc32fast::hash(data) {
+            bail!("HIF data area corrupted in transfer");
+        }
+
+        Ok(())
+    }
+
+    fn verify_results(&mut self, core: &mut dyn Core) -> Result<()> {
+        if self.integrity.is_none() {
+            return Ok(());
+        }
+
+        let nbytes = HiffyResults::decode(&self.rbuf)?.nbytes;
+
+        let crc = match self.integrity {
+            Some(Integrity::Target(func)) => {
+                self.target_crc(core, func, HIFFY_CRC_RSTACK, nbytes)?
+            }
+            _ => self.readback_crc(core, self.rstack.addr, nbytes)?,
+        };
+
+        if crc != crc32fast::hash(&self.rbuf[..nbytes]) {
+            bail!("HIF results corrupted in transfer");
+        }
+
+        Ok(())
+    }
+
+    /// Returns the functions available to HIF programs.  These are cached
+    /// per image, so constructing the table for a subsequent context on the
+    /// same image is free.
+    pub fn functions(&mut self) -> Result<Rc<HiffyFunctions>> {
+        let id = self.hubris.image_id();
+
+        if let Some(id) = id {
+            if let Some(f) = FUNCTIONS.with(|f| f.borrow().get(&id).cloned()) {
+                return Ok(f);
+            }
+        }
+
+        let functions = Rc::new(self.load_functions()?);
+
+        if let Some(id) = id {
+            FUNCTIONS.with(|f| f.borrow_mut().insert(id, functions.clone()));
+        }
+
+        Ok(functions)
+    }
+
+    fn load_functions(&self) -> Result<HiffyFunctions> {
+        let hubris = self.hubris;
+
+        let goff = hubris
+            .lookup_enum(self.functions)?
+            .lookup_variant_byname("Some")?
+            .goff
+            .ok_or_else(|| anyhow!("malconstructed functions"))?;
+
+        let ptr = hubris.lookup_struct(goff)?.lookup_member("__0")?.goff;
+        let goff = hubris.lookup_ptrtype(ptr)?;
+        let functions = hubris.lookup_enum(goff)?;
+        let mut rval = HashMap::new();
+
+        for f in &functions.variants {
+            //
+            // We expect every function to have a tag (unless there is only
+            // one function present in which case we know that the index is 0).
+            //
+            let tag = match f.tag {
+                Some(tag) => tag,
+                None if functions.variants.len() == 1 => 0,
+                _ => {
+                    bail!("function {} in {}: missing tag", f.name, goff);
+                }
+            };
+
+            let goff = f.goff.ok_or_else(|| {
+                anyhow!("function {} in {}: missing a type", f.name, goff)
+            })?;
+
+            let mut func = HiffyFunction {
+                id: TargetFunction(u8::try_from(tag)?),
+                name: f.name.to_string(),
+                args: Vec::new(),
+                argnames: Vec::new(),
+                errmap: HashMap::new(),
+            };
+
+            //
+            // We expect a 2-tuple that is our arguments and our error type
+            //
+            let sig = hubris.lookup_struct(goff)?;
+            let args = sig.lookup_member("__0")?.goff;
+
+            if let Ok(args) = hubris.lookup_struct(args) {
+                for (ndx, arg) in args.members.iter().enumerate() {
+                    func.args.push(arg.goff);
+
+                    //
+                    // The members of a tuple are named by their position.
+                    //
+                    func.argnames.push(if arg.name.starts_with("__") {
+                        format!("arg{}", ndx)
+                    } else {
+                        arg.name.clone()
+                    });
+                }
+            } else {
+                //
+                // This isn't a structure argument; if it's not an empty
+                // tuple (denoting no argument), push our single argument.
+                //
+                match hubris.lookup_basetype(args) {
+                    Ok(basetype) if basetype.size == 0 => {}
+                    _ => {
+                        func.args.push(args);
+                        func.argnames.push("arg0".to_string());
+                    }
+                }
+            }
+
+            let err = sig.lookup_member("__1")?.goff;
+
+            //
+            // We expect our error type to be 4-byte base type or an enum.
+            //
+            if let Ok(err) = hubris.lookup_enum(err) {
+                for e in &err.variants {
+                    let tag = e.tag.ok_or_else(|| {
+                        anyhow!(
+                            "function {}: malformed error type {}",
+                            f.name,
+                            err.goff,
+                        )
+                    })?;
+
+                    let val = u32::try_from(tag)?;
+                    func.errmap.insert(val, e.name.to_string());
+                }
+            }
+
+            rval.insert(func.name.clone(), func);
+        }
+
+        Ok(HiffyFunctions(rval))
+    }
+
+    /// Sets the triggers to be fired immediately before each program is
+    /// kicked; see [`HiffyTrigger`].
+    pub fn set_triggers(&mut self, triggers: impl HiffyTrigger + 'static) {
+        self.triggers = Some(Box::new(triggers));
+    }
+
+    /// Begins HIF execution.  This is non-blocking with respect to the HIF
+    /// program, so you will need to poll [Self::done] to check for completion.
+    pub fn start(
+        &mut self,
+        core: &mut dyn Core,
+        ops: &[Op],
+        data: Option<&[u8]>,
+    ) -> Result<()> {
+        match self.state {
+            State::Initialized | State::ResultsConsumed => {}
+            _ => {
+                bail!("invalid state for execution: {:?}", self.state);
+            }
+        }
+
+        //
+        // If we are checking integrity, we write and verify our data before
+        // kicking the program that uses it.
+        //
+        let data = match (self.integrity, data) {
+            (Some(_), Some(data)) => {
+                core.halt()?;
+                let rval = self.write_data(core, data);
+                core.run()?;
+                rval?;
+
+                self.verify_data(core, data)?;
+                None
+            }
+            _ => data,
+        };
+
+        self.fire_triggers(core)?;
+
+        core.halt()?;
+        let rval = self.kick(core, ops, data);
+        core.run()?;
+
+        rval
+    }
+
+    fn fire_triggers(&mut self, core: &mut dyn Core) -> Result<()> {
+        match self.triggers {
+            Some(ref mut triggers) => triggers.fire(core),
+            None => Ok(()),
+        }
+    }
+
+    fn write_data(&self, core: &mut dyn Core, data: &[u8]) -> Result<()> {
+        if data.len() > self.data.size {
+            bail!(
+                "data size ({}) exceeds maximum data size ({})",
+                data.len(),
+                self.data.size
+            );
+        }
+
+        core.write_8(self.data.addr, data)
+    }
+
+    //
+    // Loads and kicks a HIF program; the core must be halted.
+    //
+    fn kick(
+        &mut self,
+        core: &mut dyn Core,
+        ops: &[Op],
+        data: Option<&[u8]>,
+    ) -> Result<()> {
+        let size = text_size(ops)?;
+
+        if size > self.text.size {
+            bail!(
+                "program size ({}) exceeds maximum text size ({})",
+                size,
+                self.text.size
+            );
+        }
+
+        let mut text: Vec<u8> = vec![];
+        text.resize_with(self.text.size, Default::default);
+
+        if core.read_word_32(self.ready.addr)? != 1 {
+            bail!("HIF execution facility unavailable");
+        }
+
+        if let Some(data) = data {
+            self.write_data(core, data)?;
+        }
+
+        let buf = &mut text.as_mut_slice();
+        let mut current = 0;
+
+        for op in ops {
+            let serialized = to_slice(op, &mut buf[current..])?;
+            current += serialized.len();
+        }
+
+        core.write_8(self.text.addr, &buf[0..])?;
+
+        core.write_word_32(self.kick.addr, 1)?;
+
+        self.cached = Some((
+            core.read_word_32(self.requests.addr)?,
+            core.read_word_32(self.errors.addr)?,
+        ));
+
+        self.kicked = Some(Instant::now());
+
+        self.state = State::Kicked;
+
+        Ok(())
+    }
+
+    /// Blocking execution of a program, returning the results
+    pub fn run(
+        &mut self,
+        core: &mut dyn Core,
+        ops: &[Op],
+        data: Option<&[u8]>,
+    ) -> Result<Vec<Result<Vec<u8>, u32>>> {
+        self.start(core, ops, data)?;
+        while !self.done(core)? {
+            thread::sleep(Duration::from_millis(100));
+        }
+        Ok(self.results(core)?.to_vec())
+    }
+
+    /// Blocking call of the specified function with the specified
+    /// arguments, decoding its result as the specified type.  An error
+    /// returned by the function is returned as an error.
+    pub fn call<T: HiffyDecode>(
+        &mut self,
+        core: &mut dyn Core,
+        func: &HiffyFunction,
+        args: &[HiffyValue],
+    ) -> Result<T> {
+        let mut ops = func.call_ops(self.hubris, args)?;
+        ops.push(Op::Done);
+
+        match self.run(core, &ops, None)?.first() {
+            Some(result) => func.decode(result),
+            None => bail!("missing result from {}", func.name),
+        }
+    }
+
+    /// Blocking execution of a sequence of independent calls, each preceded
+    /// by the specified preamble (which is run once per program, and may
+    /// leave values on the stack for the calls to use).  As many calls are
+    /// packed into each program as fit in the program text and (given the
+    /// bound on the size of each result) the return stack; if the calls
+    /// don't all fit in a single program, they are run across as many
+    /// programs as needed (each with the specified data), and the results
+    /// are stitched together in the order of the calls.
+    pub fn run_sequence(
+        &mut self,
+        core: &mut dyn Core,
+        preamble: &[Op],
+        calls: &[HiffyCall],
+        data: Option<&[u8]>,
+    ) -> Result<Vec<Result<Vec<u8>, u32>>> {
+        let limits = self.limits();
+        let fixed = text_size(preamble)? + text_size(&[Op::Done])?;
+
+        let mut rval = vec![];
+        let mut ops = preamble.to_vec();
+        let mut text = fixed;
+        let mut rstack = 1;
+        let mut ncalls = 0;
+
+        for (ndx, call) in calls.iter().enumerate() {
+            let ctext = text_size(&call.ops)?;
+            let crstack = call.nresults * HiffyLimits::result_size(call.rsize);
+
+            if fixed + ctext > limits.text || 1 + crstack > limits.rstack {
+                bail!(
+                    "call {} ({} bytes of text, {} bytes of results) \
+                    cannot fit in a single program",
+                    ndx,
+                    ctext,
+                    crstack
+                );
+            }
+
+            if text + ctext > limits.text || rstack + crstack > limits.rstack {
+                ops.push(Op::Done);
+                rval.extend(self.run(core, &ops, data)?);
+
+                ops = preamble.to_vec();
+                text = fixed;
+                rstack = 1;
+                ncalls = 0;
+            }
+
+            ops.extend_from_slice(&call.ops);
+            text += ctext;
+            rstack += crstack;
+            ncalls += 1;
+        }
+
+        if ncalls > 0 {
+            ops.push(Op::Done);
+            rval.extend(self.run(core, &ops, data)?);
+        }
+
+        Ok(rval)
+    }
+
+    /// Blocking execution of an operation on data that may exceed the data
+    /// area.  The data is split into chunks of at most the specified size
+    /// (which must fit in the data area), and a program is run for each
+    /// chunk in turn; the program for a chunk is constructed by calling the
+    /// specified function with the offset of the chunk within the data and
+    /// its length.  The results of all programs are stitched together in
+    /// order.
+    pub fn run_chunked(
+        &mut self,
+        core: &mut dyn Core,
+        data: &[u8],
+        chunk: usize,
+        mut program: impl FnMut(usize, usize) -> Vec<Op>,
+    ) -> Result<Vec<Result<Vec<u8>, u32>>> {
+        if chunk == 0 || chunk > self.data.size {
+            bail!(
+                "chunk size ({}) must be non-zero and cannot exceed \
+                maximum data size ({})",
+                chunk,
+                self.data.size
+            );
+        }
+
+        let mut rval = vec![];
+
+        for (ndx, data) in data.chunks(chunk).enumerate() {
+            let offset = ndx * chunk;
+            let ops = program(offset, data.len());
+            rval.extend(self.run(core, &ops, Some(data))?);
+        }
+
+        Ok(rval)
+    }
+
+    pub fn done(&mut self, core: &mut dyn Core) -> Result<bool> {
+        if self.state != State::Kicked {
+            bail!("invalid state for waiting: {:?}", self.state);
+        }
+
+        core.halt()?;
+
+        let vars = (
+            core.read_word_32(self.requests.addr)?,
+            core.read_word_32(self.errors.addr)?,
+        );
+
+        core.run()?;
+
+        if let Some(kicked) = self.kicked {
+            if kicked.elapsed().as_millis() > self.timeout.into() {
+                bail!("operation timed out");
+            }
+        }
+
+        if let Some(cached) = self.cached {
+            if vars.0 != cached.0 {
+                self.state = State::ResultsReady;
+                Ok(true)
+            } else if vars.1 != cached.1 {
+                //
+                // If we have died because our HIF failed fatally (which we
+                // only expect due to programmer error), we want to print
+                // HIFFY_FAILURE to provide some additional context.
+                //
+                let mut buf: Vec<u8> = vec![];
+                buf.resize_with(self.failure.size, Default::default);
+
+                core.halt()?;
+                let r = core.read_8(self.failure.addr, buf.as_mut_slice());
+                core.run()?;
+
+                match r {
+                    Ok(_) => {
+                        let fmt = HubrisPrintFormat {
+                            hex: true,
+                            ..HubrisPrintFormat::default()
+                        };
+
+                        let hubris = self.hubris;
+                        let f =
+                            hubris.printfmt(&buf, self.failure.goff, &fmt)?;
+                        bail!("request failed: {}", f);
+                    }
+                    _ => {
+                        bail!("request failed");
+                    }
+                }
+            } else {
+                Ok(false)
+            }
+        } else {
+            Ok(false)
+        }
+    }
+
+    /// Consumes the results of the completed HIF program.  The results
+    /// borrow the context's buffer, and must be dropped before the context
+    /// is used again.
+    pub fn results(&mut self, core: &mut dyn Core) -> Result<HiffyResults<'_>> {
+        self.consume(core)?;
+        HiffyResults::decode(&self.rbuf)
+    }
+
+    /// Consumes the results of the completed HIF program and begins
+    /// execution of the next one while the target is still halted.  This
+    /// allows a loop of HIF programs to be pipelined:  the target executes
+    /// the next program while the results of the previous are being
+    /// decoded and processed on the host, and with one fewer halt/resume
+    /// cycle per program than calling [Self::results] and [Self::start] in
+    /// succession.  Note that this is not double buffering:  there is only
+    /// one return stack, so it is read (and the next program loaded) with
+    /// the target halted.  (If integrity checking is enabled, the results
+    /// must be verified before the next program is started, so no
+    /// pipelining is possible.)
+    pub fn results_and_start(
+        &mut self,
+        core: &mut dyn Core,
+        ops: &[Op],
+        data: Option<&[u8]>,
+    ) -> Result<HiffyResults<'_>> {
+        if self.integrity.is_some() {
+            self.consume(core)?;
+            self.start(core, ops, data)?;
+            return HiffyResults::decode(&self.rbuf);
+        }
+
+        if self.state != State::ResultsReady {
+            bail!("invalid state for consuming results: {:?}", self.state);
+        }
+
+        core.halt()?;
+
+        let rval = self.read_rstack(core).and_then(|_| {
+            self.state = State::ResultsConsumed;
+            self.fire_triggers(core)?;
+            self.kick(core, ops, data)
+        });
+
+        core.run()?;
+
+        rval?;
+        HiffyResults::decode(&self.rbuf)
+    }
+
+    //
+    // Reads the results of the completed HIF program into our buffer,
+    // verifying them if integrity checking is enabled.
+    //
+    fn consume(&mut self, core: &mut dyn Core) -> Result<()> {
+        if self.state != State::ResultsReady {
+            bail!("invalid state for consuming results: {:?}", self.state);
+        }
+
+        core.halt()?;
+        let rval = self.read_rstack(core);
+        core.run()?;
+
+        rval?;
+        self.state = State::ResultsConsumed;
+
+        self.verify_results(core)
+    }
+
+    //
+    // Reads the return stack into our buffer, which is reused across
+    // programs; the core must be halted.
+    //
+    fn read_rstack(&mut self, core: &mut dyn Core) -> Result<()> {
+        core.read_8(self.rstack.addr, self.rbuf.as_mut_slice())
+    }
+}
+
+///
+/// Emulates the HIF execution facility on a [`MockCore`], allowing HIF-based
+/// commands to be exercised without a target.  The HIF variables from the
+/// specified archive are backed with memory (if they are not already), and
+/// when the core is run after a kick, the HIF program is decoded and passed
+/// -- along with the contents of the data area -- to the specified handler.
+/// The results returned by the handler are then encoded into the return
+/// stack, just as the target would.
+///
+pub fn mock(
+    hubris: &HubrisArchive,
+    core: &mut MockCore,
+    mut handler: impl FnMut(&[Op], &[u8]) -> Vec<Result<Vec<u8>, u32>> + 'static,
+) -> Result<()> {
+    let mut var = |name| -> Result<(u32, usize)> {
+        let v = hubris.lookup_variable(name)?;
+        core.memory.ensure(v.addr, v.size)?;
+        Ok((v.addr, v.size))
+    };
+
+    let major = var("HIFFY_VERSION_MAJOR")?.0;
+    let minor = var("HIFFY_VERSION_MINOR")?.0;
+    let ready = var("HIFFY_READY")?.0;
+    let kick = var("HIFFY_KICK")?.0;
+    let text = var("HIFFY_TEXT")?;
+    let data = var("HIFFY_DATA")?;
+    let rstack = var("HIFFY_RSTACK")?;
+    let requests = var("HIFFY_REQUESTS")?.0;
+    var("HIFFY_ERRORS")?;
+    var("HIFFY_FAILURE")?;
+
+    core.memory.write_word_32(major, HIF_VERSION_MAJOR)?;
+    core.memory.write_word_32(minor, HIF_VERSION_MINOR)?;
+    core.memory.write_word_32(ready, 1)?;
+
+    core.on_write(
+        kick,
+        Box::new(move |memory: &mut MockMemory| {
+            if memory.read_word_32(kick)? == 0 {
+                return Ok(());
+            }
+
+            let mut buf = vec![0u8; text.1];
+            memory.read(text.0, &mut buf)?;
+
+            let mut ops = vec![];
+            let mut current = &buf[..];
+
+            loop {
+                let (op, next) = take_from_bytes::<Op>(current)?;
+                let done = matches!(op, Op::Done);
+                ops.push(op);
+
+                if done {
+                    break;
+                }
+
+                current = next;
+            }
+
+            let mut buf = vec![0u8; data.1];
+            memory.read(data.0, &mut buf)?;
+
+            let results = handler(&ops, &buf);
+
+            let mut buf = vec![0u8; rstack.1];
+            let mut offs = 0;
+
+            for result in &results {
+                let rval = match result {
+                    Ok(payload) => FunctionResult::Success(payload),
+                    Err(code) => FunctionResult::Failure(*code),
+                };
+
+                offs += to_slice(&rval, &mut buf[offs..])?.len();
+            }
+
+            to_slice(&FunctionResult::Done, &mut buf[offs..])?;
+            memory.write(rstack.0, &buf)?;
+
+            memory.write_word_32(kick, 0)?;
+
+            let n = memory.read_word_32(requests)?;
+            memory.write_word_32(requests, n.wrapping_add(1))
+        }),
+    );
+
+    Ok(())
+}
+
+//
+// The HIF variables of an archive assembled by [`mock_archive`], along with
+// their sizes; the sizes of the areas match Hubris's defaults.
+//
+const MOCK_VARIABLES: &[(&str, usize)] = &[
+    ("HIFFY_VERSION_MAJOR", 4),
+    ("HIFFY_VERSION_MINOR", 4),
+    ("HIFFY_READY", 4),
+    ("HIFFY_KICK", 4),
+    ("HIFFY_REQUESTS", 4),
+    ("HIFFY_ERRORS", 4),
+    ("HIFFY_FAILURE", 4),
+    ("HIFFY_TEXT", 2048),
+    ("HIFFY_DATA", 2048),
+    ("HIFFY_RSTACK", 2048),
+];
+
+///
+/// Assembles an archive that has the HIF interface (but nothing else), with
+/// the HIF variables laid out consecutively from the specified address.
+/// Along with [`mock`] and [`mock_functions`], this allows a
+/// [`HiffyContext`] to be used without either a target or an archive.
+///
+pub fn mock_archive(base: u32) -> Result<HubrisArchive> {
+    let mut hubris = HubrisArchive::new()?;
+    let mut addr = base;
+
+    for (name, size) in MOCK_VARIABLES {
+        hubris.mock_variable(name, addr, *size);
+        addr += *size as u32;
+    }
+
+    hubris.mock_definition("HIFFY_FUNCTIONS");
+
+    Ok(hubris)
+}
+
+///
+/// Assembles a function table, as would be described by an image's
+/// `HIFFY_FUNCTIONS`, consisting of the functions with the specified names
+/// and numbers of arguments (numbered in the order specified).  The
+/// functions' arguments and errors have no types; they can be called with
+/// operations that push their arguments, but not via
+/// [`HiffyFunction::call_ops`] with arguments.
+///
+pub fn mock_functions(functions: &[(&str, usize)]) -> HiffyFunctions {
+    let goff = HubrisGoff { object: 0, goff: 0 };
+
+    HiffyFunctions(
+        functions
+            .iter()
+            .enumerate()
+            .map(|(ndx, (name, nargs))| {
+                let func = HiffyFunction {
+                    id: TargetFunction(ndx as u8),
+                    name: name.to_string(),
+                    args: vec![goff; *nargs],
+                    argnames: (0..*nargs)
+                        .map(|i| format!("arg{}", i))
+                        .collect(),
+                    errmap: HashMap::new(),
+                };
+
+                (name.to_string(), func)
+            })
+            .collect(),
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::mock::MockAccess;
+
+    //
+    // Runs the operations of a program that calls functions with 32-bit
+    // arguments, passing each function's id and arguments to the specified
+    // closure.
+    //
+    fn interpret(
+        ops: &[Op],
+        mut call: impl FnMut(u8, &[u32]) -> Result<Vec<u8>, u32>,
+    ) -> Vec<Result<Vec<u8>, u32>> {
+        let mut stack = vec![];
+        let mut results = vec![];
+
+        for op in ops {
+            match op {
+                Op::Push(v) => stack.push(*v as u32),
+                Op::Push16(v) => stack.push(*v as u32),
+                Op::Push32(v) => stack.push(*v),
+                Op::DropN(n) => stack.truncate(stack.len() - *n as usize),
+                Op::Call(TargetFunction(id)) => results.push(call(*id, &stack)),
+                Op::Done => break,
+                _ => panic!("unexpected op"),
+            }
+        }
+
+        results
+    }
+
+    fn setup(
+        mut call: impl FnMut(u8, &[u32]) -> Result<Vec<u8>, u32> + 'static,
+    ) -> (HubrisArchive, MockCore) {
+        let hubris = mock_archive(0x2000_0000).unwrap();
+        let mut core = MockCore::new();
+
+        mock(&hubris, &mut core, move |ops, _| interpret(ops, &mut call))
+            .unwrap();
+
+        (hubris, core)
+    }
+
+    #[test]
+    fn run() {
+        let (hubris, mut core) = setup(|id, args| match id {
+            0 => Ok((args[0] + args[1]).to_le_bytes().to_vec()),
+            _ => Err(id as u32),
+        });
+
+        let mut context = HiffyContext::new(&hubris, &mut core, 1000).unwrap();
+
+        let ops = [
+            Op::Push32(1),
+            Op::Push32(2),
+            Op::Call(TargetFunction(0)),
+            Op::Call(TargetFunction(1)),
+            Op::Done,
+        ];
+
+        let results = context.run(&mut core, &ops, None).unwrap();
+        assert_eq!(results, vec![Ok(vec![3, 0, 0, 0]), Err(1)]);
+
+        //
+        // A subsequent program reuses the context.
+        //
+        let ops =
+            [Op::Push(7), Op::Push16(9), Op::Call(TargetFunction(0)), Op::Done];
+
+        let results = context.run(&mut core, &ops, None).unwrap();
+        assert_eq!(results, vec![Ok(vec![16, 0, 0, 0])]);
+    }
+
+    #[test]
+    fn call() {
+        let (hubris, mut core) = setup(|id, _| match id {
+            0 => Ok(0x1de_u32.to_le_bytes().to_vec()),
+            _ => Err(3),
+        });
+
+        let funcs = mock_functions(&[("Answer", 0), ("Fail", 0)]);
+        let mut context = HiffyContext::new(&hubris, &mut core, 1000).unwrap();
+
+        let answer = funcs.get("Answer", 0).unwrap();
+        let val: u32 = context.call(&mut core, answer, &[]).unwrap();
+        assert_eq!(val, 0x1de);
+
+        let fail = funcs.get("Fail", 0).unwrap();
+        assert!(context.call::<u32>(&mut core, fail, &[]).is_err());
+    }
+
+    #[test]
+    fn pipelined() {
+        let (hubris, mut core) =
+            setup(|_, args| Ok(vec![args[0] as u8; args[1] as usize]));
+
+        let mut context = HiffyContext::new(&hubris, &mut core, 1000).unwrap();
+        let ops = |n| {
+            vec![
+                Op::Push32(n),
+                Op::Push32(n),
+                Op::Call(TargetFunction(0)),
+                Op::Done,
+            ]
+        };
+
+        context.start(&mut core, &ops(1), None).unwrap();
+
+        for n in 2..=4 {
+            while !context.done(&mut core).unwrap() {}
+
+            let results =
+                context.results_and_start(&mut core, &ops(n), None).unwrap();
+            assert_eq!(
+                results.to_vec(),
+                vec![Ok(vec![n as u8 - 1; n as usize - 1])]
+            );
+        }
+
+        while !context.done(&mut core).unwrap() {}
+        let results = context.results(&mut core).unwrap();
+        assert_eq!(results.to_vec(), vec![Ok(vec![4; 4])]);
+    }
+
+    #[derive(Debug)]
+    struct Count(Rc<RefCell<usize>>);
+
+    impl HiffyTrigger for Count {
+        fn fire(&mut self, _core: &mut dyn Core) -> Result<()> {
+            *self.0.borrow_mut() += 1;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn triggers() {
+        let (hubris, mut core) = setup(|_, _| Ok(vec![]));
+        let mut context = HiffyContext::new(&hubris, &mut core, 1000).unwrap();
+        let fired = Rc::new(RefCell::new(0));
+        let ops = [Op::Call(TargetFunction(0)), Op::Done];
+
+        context.set_triggers(Count(fired.clone()));
+        context.run(&mut core, &ops, None).unwrap();
+        assert_eq!(*fired.borrow(), 1);
+
+        //
+        // Every pipelined program must fire the triggers, too.
+        //
+        context.start(&mut core, &ops, None).unwrap();
+
+        for _ in 0..3 {
+            while !context.done(&mut core).unwrap() {}
+            context.results_and_start(&mut core, &ops, None).unwrap();
+        }
+
+        while !context.done(&mut core).unwrap() {}
+        context.results(&mut core).unwrap();
+
+        assert_eq!(*fired.borrow(), 5);
+    }
+
+    #[test]
+    fn integrity() {
+        let (hubris, mut core) =
+            setup(|_, args| Ok(vec![0xa5; args[0] as usize]));
+        let funcs = mock_functions(&[("Fill", 1)]);
+
+        let mut context = HiffyContext::new(&hubris, &mut core, 1000).unwrap();
+        context.set_integrity(&funcs);
+
+        let ops = [Op::Push32(100), Op::Call(TargetFunction(0)), Op::Done];
+        let data = [0x1d; 16];
+
+        let results = context.run(&mut core, &ops, Some(&data)).unwrap();
+        assert_eq!(results, vec![Ok(vec![0xa5; 100])]);
+
+        //
+        // Our data must have been read back in its entirety, as must our
+        // results.
+        //
+        let data = hubris.lookup_variable("HIFFY_DATA").unwrap().addr;
+        let rstack = hubris.lookup_variable("HIFFY_RSTACK").unwrap().addr;
+        let log = core.log();
+        assert!(log.contains(&MockAccess::Read(data, 16)));
+        assert!(log.contains(&MockAccess::Read(rstack, 103)));
+    }
+}
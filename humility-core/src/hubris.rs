@@ -3,9 +3,14 @@
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
 use crate::arch::ARMRegister;
+use crate::interval::IntervalMap;
+use crate::json::Json;
+use crate::svd::{SvdDevice, SvdPeripheral, SvdRegister};
+use crate::timebase::{TimeSample, Timebase};
 use capstone::prelude::*;
 use indexmap::IndexMap;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use std::io::prelude::*;
 
 use std::borrow::Cow;
@@ -26,6 +31,7 @@ use gimli::UnwindSection;
 use goblin::elf::Elf;
 use multimap::MultiMap;
 use num_traits::FromPrimitive;
+use rayon::prelude::*;
 use rustc_demangle::demangle;
 use scroll::{IOwrite, Pwrite};
 
@@ -33,6 +39,7 @@ const OXIDE_NT_NAME: &str = "Oxide Computer Company";
 const OXIDE_NT_BASE: u32 = 0x1de << 20;
 const OXIDE_NT_HUBRIS_ARCHIVE: u32 = OXIDE_NT_BASE + 1;
 const OXIDE_NT_HUBRIS_REGISTERS: u32 = OXIDE_NT_BASE + 2;
+const OXIDE_NT_HUBRIS_TIMEBASE: u32 = OXIDE_NT_BASE + 3;
 
 #[derive(Default, Debug)]
 pub struct HubrisManifest {
@@ -43,9 +50,11 @@ pub struct HubrisManifest {
     target: Option<String>,
     task_features: HashMap<String, Vec<String>>,
     pub task_irqs: HashMap<String, Vec<(u32, u32)>>,
-    peripherals: BTreeMap<String, u32>,
+    pub task_slots: HashMap<String, Vec<HubrisTaskSlot>>,
+    pub peripherals: BTreeMap<String, HubrisPeripheral>,
     pub i2c_devices: Vec<HubrisI2cDevice>,
     pub i2c_buses: Vec<HubrisI2cBus>,
+    pub itm_ports: Vec<HubrisItmPort>,
 }
 
 //
@@ -74,10 +83,22 @@ struct HubrisConfigKernel {
 struct HubrisConfigTask {
     features: Option<Vec<String>>,
     interrupts: Option<IndexMap<String, u32>>,
+    #[serde(rename = "task-slots")]
+    task_slots: Option<Vec<HubrisConfigTaskSlot>>,
+}
+
+//
+// A task slot is either the name of a task (in which case the slot has the
+// same name), or a table mapping a slot name to the name of a task.
+//
+#[derive(Clone, Debug, Deserialize)]
+#[serde(untagged)]
+enum HubrisConfigTaskSlot {
+    Task(String),
+    Named(IndexMap<String, String>),
 }
 
 #[derive(Clone, Debug, Deserialize)]
-#[allow(dead_code)]
 struct HubrisConfigPeripheral {
     address: u32,
     size: u32,
@@ -120,9 +141,85 @@ struct HubrisConfigI2c {
     devices: Option<Vec<HubrisConfigI2cDevice>>,
 }
 
+#[derive(Clone, Debug, Deserialize)]
+struct HubrisConfigItmPort {
+    port: u32,
+    task: Option<String>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct HubrisConfigItm {
+    ports: Option<IndexMap<String, HubrisConfigItmPort>>,
+}
+
 #[derive(Clone, Debug, Deserialize)]
 struct HubrisConfigConfig {
     i2c: Option<HubrisConfigI2c>,
+    itm: Option<HubrisConfigItm>,
+}
+
+//
+// A task slot:  a named reference from one task to another, as specified in
+// the task-slots of the application configuration.
+//
+#[derive(Clone, Debug)]
+pub struct HubrisTaskSlot {
+    pub name: String,
+    pub task: String,
+}
+
+#[derive(Clone, Debug)]
+pub struct HubrisPeripheral {
+    pub address: u32,
+    pub size: Option<u32>,
+}
+
+//
+// A named ITM stimulus port, optionally attributed to the task that writes
+// to it.
+//
+#[derive(Clone, Debug)]
+pub struct HubrisItmPort {
+    pub name: String,
+    pub port: u32,
+    pub task: Option<String>,
+}
+
+impl HubrisItmPort {
+    fn from_config(config: &HubrisConfigItm) -> Result<Vec<Self>> {
+        let mut rval = vec![];
+
+        if let Some(ref ports) = config.ports {
+            for (name, p) in ports {
+                if p.port >= 32 {
+                    bail!("ITM port {} ({}) exceeds 31", name, p.port);
+                }
+
+                rval.push(HubrisItmPort {
+                    name: name.clone(),
+                    port: p.port,
+                    task: p.task.clone(),
+                });
+            }
+        }
+
+        Ok(rval)
+    }
+
+    ///
+    /// Parses ITM ports from a TOML file that is in the same form as the
+    /// `itm` section of the application config, e.g.:
+    ///
+    /// ```toml
+    /// [ports.console]
+    /// port = 0
+    /// task = "jefe"
+    /// ```
+    ///
+    pub fn from_toml(contents: &str) -> Result<Vec<Self>> {
+        let config: HubrisConfigItm = toml::from_str(contents)?;
+        Self::from_config(&config)
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -189,6 +286,9 @@ pub struct HubrisArchive {
     // Current registers (if a dump)
     registers: HashMap<ARMRegister, u32>,
 
+    // Correlation of target ticks with host time
+    timebase: Timebase,
+
     // Modules: text address to module
     modules: BTreeMap<u32, HubrisModule>,
 
@@ -198,9 +298,19 @@ pub struct HubrisArchive {
     // DWARF call frame debugging sections: task to raw bytes
     frames: HashMap<HubrisTask, Vec<u8>>,
 
+    // defmt tables: task to table
+    defmt: HashMap<HubrisTask, HubrisDefmt>,
+
     // DWARF source code: goff to file/line
     src: HashMap<HubrisGoff, HubrisSrc>,
 
+    // DWARF line tables: address to source file index/line tuple; None
+    // denotes the end of a sequence
+    lines: BTreeMap<u32, Option<(usize, u64)>>,
+
+    // DWARF line tables: source files, indexed by the above
+    srcfiles: Vec<HubrisSrc>,
+
     // DWARF symbols: address to HubrisSymbol
     dsyms: BTreeMap<u32, HubrisSymbol>,
 
@@ -251,6 +361,114 @@ pub struct HubrisArchive {
 
     // Definitions: name to goff
     definitions: MultiMap<String, HubrisGoff>,
+
+    // DWARF was loaded from the on-disk cache
+    dwarf_cached: bool,
+
+    // Index of DWARF symbols: address range to symbol address
+    dsyms_index: IntervalMap<u32>,
+
+    // Index of ELF symbols: address range to symbol address
+    esyms_index: IntervalMap<u32>,
+
+    // Peripherals loaded from SVD files
+    svd: Vec<SvdPeripheral>,
+
+    // Index of SVD registers: address range to peripheral/register indices
+    svd_index: IntervalMap<(usize, usize)>,
+}
+
+//
+// The on-disk cache of everything that we derive from DWARF.  This is keyed
+// by a hash of the archive and of our cache version; in the cache itself, we
+// also store our cache version and the length of the archive to guard
+// against the (remote) possibility of a hash collision.  The version must
+// be bumped whenever the cache -- or any type within it -- changes; because
+// postcard isn't self-describing, a stale cache might otherwise deserialize
+// successfully into the wrong values.
+//
+const HUBRIS_DWARF_CACHE_VERSION: u32 = 2;
+
+//
+// The number of cached archives we keep around before we start discarding
+// the oldest.
+//
+const HUBRIS_DWARF_CACHE_MAX: usize = 8;
+
+#[derive(Serialize, Deserialize)]
+struct HubrisDwarfCache {
+    version: u32,
+    length: usize,
+    src: HashMap<HubrisGoff, HubrisSrc>,
+    lines: BTreeMap<u32, Option<(usize, u64)>>,
+    srcfiles: Vec<HubrisSrc>,
+    dsyms: BTreeMap<u32, HubrisSymbol>,
+    inlined: BTreeMap<(u32, isize), (u32, HubrisGoff, HubrisGoff)>,
+    subprograms: HashMap<HubrisGoff, String>,
+    basetypes: HashMap<HubrisGoff, HubrisBasetype>,
+    ptrtypes: HashMap<HubrisGoff, (String, HubrisGoff)>,
+    structs: HashMap<HubrisGoff, HubrisStruct>,
+    structs_byname: Vec<(String, HubrisGoff)>,
+    enums: HashMap<HubrisGoff, HubrisEnum>,
+    enums_byname: Vec<(String, HubrisGoff)>,
+    arrays: HashMap<HubrisGoff, HubrisArray>,
+    variables: Vec<(String, HubrisVariable)>,
+    qualified_variables: Vec<(String, HubrisVariable)>,
+    unions: HashMap<HubrisGoff, HubrisUnion>,
+    definitions: Vec<(String, HubrisGoff)>,
+}
+
+//
+// Hashes the specified data.  Unlike the hashers in the standard library,
+// this is stable across Rust releases, so its results can be persisted.
+//
+fn stable_hash(data: &[&[u8]]) -> u64 {
+    let mut hasher = Sha256::new();
+
+    for d in data {
+        hasher.update(d);
+    }
+
+    u64::from_le_bytes(hasher.finalize()[..8].try_into().unwrap())
+}
+
+///
+/// Returns the directory in which Humility caches state across invocations,
+/// or `None` if caching has been disabled (or no such directory can be
+/// determined).
+///
+pub fn cache_dir() -> Option<std::path::PathBuf> {
+    if std::env::var_os("HUMILITY_NOCACHE").is_some() {
+        return None;
+    }
+
+    match std::env::var_os("HUMILITY_CACHE") {
+        Some(dir) => Some(std::path::PathBuf::from(dir)),
+        None => match std::env::var_os("XDG_CACHE_HOME") {
+            Some(dir) => Some(Path::new(&dir).join("humility")),
+            None => Some(
+                Path::new(&std::env::var_os("HOME")?)
+                    .join(".cache")
+                    .join("humility"),
+            ),
+        },
+    }
+}
+
+fn multimap_to_vec<V: Clone>(map: &MultiMap<String, V>) -> Vec<(String, V)> {
+    map.iter_all()
+        .flat_map(|(k, vals)| vals.iter().map(move |v| (k.clone(), v.clone())))
+        .collect()
+}
+
+fn vec_to_multimap<V>(vec: Vec<(String, V)>) -> MultiMap<String, V> {
+    let mut map = MultiMap::new();
+
+    for (k, v) in vec {
+        map.insert(k, v);
+    }
+
+    map
 }
 
 #[rustfmt::skip::macros(anyhow, bail)]
@@ -286,10 +504,14 @@ impl HubrisArchive {
             instrs: HashMap::new(),
             syscall_pushes: HashMap::new(),
             registers: HashMap::new(),
+            timebase: Timebase::new(),
             modules: BTreeMap::new(),
             tasks: HashMap::new(),
             frames: HashMap::new(),
+            defmt: HashMap::new(),
             src: HashMap::new(),
+            lines: BTreeMap::new(),
+            srcfiles: Vec::new(),
             dsyms: BTreeMap::new(),
             esyms: BTreeMap::new(),
             esyms_byname: MultiMap::new(),
@@ -306,6 +528,11 @@ impl HubrisArchive {
             qualified_variables: MultiMap::new(),
             unions: HashMap::new(),
             definitions: MultiMap::new(),
+            dwarf_cached: false,
+            dsyms_index: IntervalMap::default(),
+            esyms_index: IntervalMap::default(),
+            svd: Vec::new(),
+            svd_index: IntervalMap::default(),
         })
     }
 
@@ -336,64 +563,23 @@ impl HubrisArchive {
     }
 
     pub fn instr_sym(&self, addr: u32) -> Option<(&str, u32)> {
-        let sym: Option<(&str, u32)>;
-
         /*
          * First, check our DWARF symbols.
          */
-        sym = match self.dsyms.range(..=addr).next_back() {
-            Some((_, sym)) if addr < sym.addr + sym.size => {
-                Some((&sym.name, sym.addr))
-            }
-            _ => None,
-        };
+        if let Some((_, addr)) = self.dsyms_index.lookup(addr) {
+            return Some((&self.dsyms[addr].name, *addr));
+        }
 
         /*
          * Fallback to our ELF symbols.
          */
-        sym.or_else(|| match self.esyms.range(..=addr).next_back() {
-            Some((&sym_addr, (name, sym_len))) if addr < sym_addr + sym_len => {
-                Some((name, sym_addr))
-            }
-            _ => None,
-        })
+        self.esyms_index
+            .lookup(addr)
+            .map(|(_, addr)| (self.esyms[addr].0.as_str(), *addr))
     }
 
     pub fn instr_inlined(&self, pc: u32, base: u32) -> Vec<HubrisInlined> {
-        let mut inlined: Vec<HubrisInlined> = vec![];
-
-        /*
-         * We find our stack of inlined functions by searching backwards from
-         * our address (which we know must be greater than or equal to all
-         * inlined functions that it is in).  This yields a vector that
-         * starts from the greatest depth and ends with the least
-         * depth -- so we reverse it before we return it.  We know
-         * that our search is over when the address plus the length
-         * is less than our base.
-         */
-        for ((addr, _depth), (len, goff, origin)) in
-            self.inlined.range(..=(pc, std::isize::MAX)).rev()
-        {
-            if addr + len < base {
-                break;
-            }
-
-            if addr + len <= pc {
-                continue;
-            }
-
-            if let Some(func) = self.subprograms.get(origin) {
-                inlined.push(HubrisInlined {
-                    addr: *addr as u32,
-                    name: func,
-                    id: *goff,
-                    origin: *origin,
-                });
-            }
-        }
-
-        inlined.reverse();
-        inlined
+        self.unwinder().inlined(pc, base)
     }
 
     fn instr_branch_target(
@@ -599,30 +785,97 @@ impl HubrisArchive {
                 }
             };
 
-            let mut comp = None;
-            let directory;
-            if let Some(dir) = file.directory(header) {
-                let dir = dwarf.attr_string(unit, dir)?;
-                let dir = dir.to_string_lossy()?;
+            let (file, directory, comp) =
+                Self::dwarf_srcfile(dwarf, unit, header, file)?;
 
-                if !dir.starts_with('/') {
-                    if let Some(comp_dir) = &unit.comp_dir {
-                        comp = Some(comp_dir.to_string_lossy()?.into_owned());
-                    }
+            self.src.insert(
+                goff,
+                HubrisSrc { file, directory, comp_directory: comp, line },
+            );
+        }
+
+        Ok(())
+    }
+
+    #[allow(clippy::type_complexity)]
+    fn dwarf_srcfile<R: gimli::Reader<Offset = usize>>(
+        dwarf: &gimli::Dwarf<R>,
+        unit: &gimli::Unit<R>,
+        header: &gimli::LineProgramHeader<R>,
+        file: &gimli::FileEntry<R>,
+    ) -> Result<(String, Option<String>, Option<String>)> {
+        let mut comp = None;
+        let directory;
+        if let Some(dir) = file.directory(header) {
+            let dir = dwarf.attr_string(unit, dir)?;
+            let dir = dir.to_string_lossy()?;
+
+            if !dir.starts_with('/') {
+                if let Some(comp_dir) = &unit.comp_dir {
+                    comp = Some(comp_dir.to_string_lossy()?.into_owned());
                 }
+            }
 
-                directory = Some(dir.into_owned())
-            } else {
-                directory = None
+            directory = Some(dir.into_owned())
+        } else {
+            directory = None
+        }
+
+        let s = dwarf.attr_string(unit, file.path_name())?;
+        let file = s.to_string_lossy()?.into_owned();
+
+        Ok((file, directory, comp))
+    }
+
+    fn dwarf_lines<R: gimli::Reader<Offset = usize>>(
+        &mut self,
+        dwarf: &gimli::Dwarf<R>,
+        unit: &gimli::Unit<R>,
+        files: &mut HashMap<(String, Option<String>, Option<String>), usize>,
+    ) -> Result<()> {
+        let program = match &unit.line_program {
+            Some(program) => program.clone(),
+            None => return Ok(()),
+        };
+
+        let mut rows = program.rows();
+
+        while let Some((header, row)) = rows.next_row()? {
+            let addr = row.address() as u32;
+
+            if row.end_sequence() {
+                /*
+                 * Don't clobber the start of an adjacent sequence.
+                 */
+                self.lines.entry(addr).or_insert(None);
+                continue;
             }
 
-            let s = dwarf.attr_string(unit, file.path_name())?;
-            let file = s.to_string_lossy()?.into_owned();
+            let (file, line) = match (row.file(header), row.line()) {
+                (Some(file), Some(line)) => (file, u64::from(line)),
+                _ => continue,
+            };
 
-            self.src.insert(
-                goff,
-                HubrisSrc { file, directory, comp_directory: comp, line },
-            );
+            let key = Self::dwarf_srcfile(dwarf, unit, header, file)?;
+
+            let ndx = match files.get(&key) {
+                Some(ndx) => *ndx,
+                None => {
+                    let ndx = self.srcfiles.len();
+
+                    self.srcfiles.push(HubrisSrc {
+                        file: key.0.clone(),
+                        directory: key.1.clone(),
+                        comp_directory: key.2.clone(),
+                        line: 0,
+                    });
+
+                    files.insert(key, ndx);
+                    ndx
+                }
+            };
+
+            self.lines.insert(addr, Some((ndx, line)));
         }
 
         Ok(())
@@ -1417,8 +1670,12 @@ impl HubrisArchive {
 
         // Iterate over the compilation units.
         let mut iter = dwarf.units();
+        let mut files = HashMap::new();
+
         while let Some(header) = iter.next()? {
             let unit = dwarf.unit(header)?;
+            self.dwarf_lines(&dwarf, &unit, &mut files)?;
+
             let mut entries = unit.entries();
             let mut depth = 0;
             let mut stack: Vec<HubrisGoff> = vec![];
@@ -1555,6 +1812,48 @@ impl HubrisArchive {
         Ok(())
     }
 
+    //
+    // If an object uses defmt, its format strings (and other metadata) are
+    // encoded in the names of symbols in the .defmt section, with each
+    // symbol's address being its index; the encoding of the frames is
+    // denoted by the name of an absolute symbol.
+    //
+    fn load_object_defmt(
+        &mut self,
+        task: HubrisTask,
+        elf: &goblin::elf::Elf,
+    ) -> Result<()> {
+        let ndx = elf.section_headers.iter().position(|sh| {
+            matches!(elf.shdr_strtab.get(sh.sh_name), Some(Ok(".defmt")))
+        });
+
+        let ndx = match ndx {
+            Some(ndx) => ndx,
+            None => return Ok(()),
+        };
+
+        let mut defmt = HubrisDefmt::default();
+
+        for sym in elf.syms.iter() {
+            let name = match elf.strtab.get(sym.st_name) {
+                Some(Ok(name)) => name,
+                _ => continue,
+            };
+
+            if sym.st_shndx == ndx {
+                defmt.table.insert(sym.st_value as u16, name.to_string());
+            } else if let Some(encoding) = name.strip_prefix("_defmt_encoding_")
+            {
+                let encoding = encoding.trim_start_matches(&[' ', '='][..]);
+                defmt.encoding = Some(encoding.to_string());
+            }
+        }
+
+        self.defmt.insert(task, defmt);
+
+        Ok(())
+    }
+
     fn load_object_frames(
         &mut self,
         task: HubrisTask,
@@ -1725,12 +2024,19 @@ impl HubrisArchive {
 
         trace!("loading {} as object {}", object, self.current);
 
-        self.load_object_dwarf(buffer, &elf)
-            .context(format!("{}: failed to load DWARF", object))?;
+        if self.dwarf_cached {
+            self.current += 1;
+        } else {
+            self.load_object_dwarf(buffer, &elf)
+                .context(format!("{}: failed to load DWARF", object))?;
+        }
 
         self.load_object_frames(task, buffer, &elf)
             .context(format!("{}: failed to load debug frames", object))?;
 
+        self.load_object_defmt(task, &elf)
+            .context(format!("{}: failed to load defmt table", object))?;
+
         let t = buffer
             .get(offset..offset + size)
             .ok_or_else(|| anyhow!("bad offset/size for ELF text section"))?;
@@ -1909,6 +2215,31 @@ impl HubrisArchive {
                     .insert(name.clone(), features.clone());
             }
 
+            if let Some(ref slots) = task.task_slots {
+                let mut rval = vec![];
+
+                for slot in slots {
+                    match slot {
+                        HubrisConfigTaskSlot::Task(task) => {
+                            rval.push(HubrisTaskSlot {
+                                name: task.clone(),
+                                task: task.clone(),
+                            });
+                        }
+                        HubrisConfigTaskSlot::Named(slots) => {
+                            for (slot, task) in slots {
+                                rval.push(HubrisTaskSlot {
+                                    name: slot.clone(),
+                                    task: task.clone(),
+                                });
+                            }
+                        }
+                    }
+                }
+
+                self.manifest.task_slots.insert(name.clone(), rval);
+            }
+
             if let Some(ref irqs) = task.interrupts {
                 self.manifest.task_irqs.insert(
                     name.clone(),
@@ -1921,7 +2252,10 @@ impl HubrisArchive {
 
         if let Some(ref peripherals) = config.peripherals {
             for (name, p) in peripherals {
-                self.manifest.peripherals.insert(name.clone(), p.address);
+                self.manifest.peripherals.insert(
+                    name.clone(),
+                    HubrisPeripheral { address: p.address, size: Some(p.size) },
+                );
             }
         }
 
@@ -1929,12 +2263,143 @@ impl HubrisArchive {
             if let Some(ref i2c) = config.i2c {
                 self.load_i2c_config(i2c)?;
             }
+
+            if let Some(ref itm) = config.itm {
+                self.manifest.itm_ports = HubrisItmPort::from_config(itm)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    //
+    // Returns the path of the DWARF cache for the specified archive, if
+    // caching hasn't been disabled (and we can determine a cache directory).
+    //
+    fn dwarf_cache_path(archive: &[u8]) -> Option<std::path::PathBuf> {
+        let version = HUBRIS_DWARF_CACHE_VERSION.to_le_bytes();
+        let hash = stable_hash(&[&version, archive]);
+
+        Some(cache_dir()?.join(format!("dwarf-{:016x}.bin", hash)))
+    }
+
+    fn load_dwarf_cache(&mut self, archive: &[u8]) -> bool {
+        let path = match Self::dwarf_cache_path(archive) {
+            Some(path) => path,
+            None => return false,
+        };
+
+        let contents = match fs::read(&path) {
+            Ok(contents) => contents,
+            Err(_) => return false,
+        };
+
+        let cache: HubrisDwarfCache = match postcard::from_bytes(&contents) {
+            Ok(cache) => cache,
+            Err(err) => {
+                warn!("discarding corrupt cache {}: {}", path.display(), err);
+                let _ = fs::remove_file(&path);
+                return false;
+            }
+        };
+
+        if cache.version != HUBRIS_DWARF_CACHE_VERSION
+            || cache.length != archive.len()
+        {
+            let _ = fs::remove_file(&path);
+            return false;
+        }
+
+        trace!("loading DWARF from cache {}", path.display());
+
+        self.src = cache.src;
+        self.lines = cache.lines;
+        self.srcfiles = cache.srcfiles;
+        self.dsyms = cache.dsyms;
+        self.inlined = cache.inlined;
+        self.subprograms = cache.subprograms;
+        self.basetypes = cache.basetypes;
+        self.ptrtypes = cache.ptrtypes;
+        self.structs = cache.structs;
+        self.structs_byname = vec_to_multimap(cache.structs_byname);
+        self.enums = cache.enums;
+        self.enums_byname = vec_to_multimap(cache.enums_byname);
+        self.arrays = cache.arrays;
+        self.variables = vec_to_multimap(cache.variables);
+        self.qualified_variables = vec_to_multimap(cache.qualified_variables);
+        self.unions = cache.unions;
+        self.definitions = vec_to_multimap(cache.definitions);
+
+        true
+    }
+
+    fn save_dwarf_cache(&self, archive: &[u8]) -> Result<()> {
+        let path = match Self::dwarf_cache_path(archive) {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+
+        let cache = HubrisDwarfCache {
+            version: HUBRIS_DWARF_CACHE_VERSION,
+            length: archive.len(),
+            src: self.src.clone(),
+            lines: self.lines.clone(),
+            srcfiles: self.srcfiles.clone(),
+            dsyms: self.dsyms.clone(),
+            inlined: self.inlined.clone(),
+            subprograms: self.subprograms.clone(),
+            basetypes: self.basetypes.clone(),
+            ptrtypes: self.ptrtypes.clone(),
+            structs: self.structs.clone(),
+            structs_byname: multimap_to_vec(&self.structs_byname),
+            enums: self.enums.clone(),
+            enums_byname: multimap_to_vec(&self.enums_byname),
+            arrays: self.arrays.clone(),
+            variables: multimap_to_vec(&self.variables),
+            qualified_variables: multimap_to_vec(&self.qualified_variables),
+            unions: self.unions.clone(),
+            definitions: multimap_to_vec(&self.definitions),
+        };
+
+        let dir = path.parent().unwrap();
+        fs::create_dir_all(dir)?;
+
+        //
+        // Write to a temporary file and rename it, so a concurrent Humility
+        // never sees a partially written cache.
+        //
+        let tmp = path.with_extension(format!("tmp.{}", std::process::id()));
+        fs::write(&tmp, postcard::to_stdvec(&cache)?)?;
+        fs::rename(&tmp, &path)?;
+
+        //
+        // Finally, discard the oldest caches beyond our limit.
+        //
+        let mut caches = fs::read_dir(dir)?
+            .filter_map(|e| e.ok())
+            .filter(|e| {
+                let name = e.file_name();
+                let name = name.to_string_lossy();
+                name.starts_with("dwarf-") && name.ends_with(".bin")
+            })
+            .filter_map(|e| {
+                Some((e.metadata().ok()?.modified().ok()?, e.path()))
+            })
+            .collect::<Vec<_>>();
+
+        caches.sort();
+
+        while caches.len() > HUBRIS_DWARF_CACHE_MAX {
+            let _ = fs::remove_file(caches.remove(0).1);
         }
 
         Ok(())
     }
 
     fn load_archive(&mut self, archive: &[u8]) -> Result<()> {
+        self.dwarf_cached = self.load_dwarf_cache(archive);
+
+        let bytes = archive;
         let cursor = Cursor::new(archive);
         let mut archive = zip::ZipArchive::new(cursor)?;
         let mut manifest = &mut self.manifest;
@@ -2006,10 +2471,37 @@ impl HubrisArchive {
             id += 1;
         }
 
-        Ok(())
-    }
+        self.index_symbols();
 
-    pub fn load(&mut self, archive: &str) -> Result<()> {
+        if self.dwarf_cached {
+            return Ok(());
+        }
+
+        //
+        // A failure to write the cache is not fatal -- but we want to know.
+        //
+        if let Err(err) = self.save_dwarf_cache(bytes) {
+            warn!("failed to write DWARF cache: {}", err);
+        }
+
+        Ok(())
+    }
+
+    //
+    // Builds our indices of symbols, which allow an address to be resolved
+    // to the (innermost) symbol that contains it.
+    //
+    fn index_symbols(&mut self) {
+        self.dsyms_index = IntervalMap::new(
+            self.dsyms.values().map(|s| (s.addr..s.addr + s.size, s.addr)),
+        );
+
+        self.esyms_index = IntervalMap::new(
+            self.esyms.iter().map(|(&addr, (_, len))| (addr..addr + len, addr)),
+        );
+    }
+
+    pub fn load(&mut self, archive: &str) -> Result<()> {
         let metadata = fs::metadata(archive)?;
 
         if metadata.is_dir() {
@@ -2030,6 +2522,186 @@ impl HubrisArchive {
         Ok(())
     }
 
+    /// Returns the contents of the specified file within the archive.
+    pub fn extract_file(&self, name: &str) -> Result<Vec<u8>> {
+        if self.archive.is_empty() {
+            bail!("no archive loaded");
+        }
+
+        let cursor = Cursor::new(self.archive.as_slice());
+        let mut archive = zip::ZipArchive::new(cursor)?;
+
+        let mut file = archive
+            .by_name(name)
+            .map_err(|e| anyhow!("failed to find \"{}\": {}", name, e))?;
+
+        let mut buffer = vec![];
+        file.read_to_end(&mut buffer)?;
+
+        Ok(buffer)
+    }
+
+    ///
+    /// Returns the loadable contents of the final image in the archive, as
+    /// tuples of load address and contents (sorted by address).  This is
+    /// what is programmed into the target's flash.
+    ///
+    pub fn image_segments(&self) -> Result<Vec<(u32, Vec<u8>)>> {
+        let image = self.extract_file("img/final.elf")?;
+        let elf = Elf::parse(&image).map_err(|e| {
+            anyhow!("failed to parse img/final.elf as an ELF file: {}", e)
+        })?;
+
+        let mut rval = vec![];
+
+        for phdr in elf.program_headers.iter() {
+            if phdr.p_type != goblin::elf::program_header::PT_LOAD
+                || phdr.p_filesz == 0
+            {
+                continue;
+            }
+
+            let offs = phdr.p_offset as usize;
+            let len = phdr.p_filesz as usize;
+
+            let data = match image.get(offs..offs + len) {
+                Some(data) => data,
+                None => {
+                    bail!("segment at 0x{:x} exceeds image", phdr.p_paddr);
+                }
+            };
+
+            rval.push((phdr.p_paddr as u32, data.to_vec()));
+        }
+
+        rval.sort_by_key(|(addr, _)| *addr);
+
+        Ok(rval)
+    }
+
+    ///
+    /// Loads the peripherals described by the specified CMSIS-SVD file.
+    /// Peripherals are made available by their (lowercased) names, unless
+    /// the archive already has a peripheral of the same name; their
+    /// registers can then be looked up by address via [`lookup_register`].
+    ///
+    /// [`lookup_register`]: Self::lookup_register
+    ///
+    pub fn load_svd(&mut self, filename: &str) -> Result<()> {
+        let device = SvdDevice::load(filename)?;
+        let name = device.name.clone();
+        let added = self.add_svd(device);
+
+        info!("loaded {} peripherals from {} ({})", added, filename, name);
+
+        Ok(())
+    }
+
+    ///
+    /// Loads the peripherals described by any CMSIS-SVD files (that is,
+    /// files with a `.svd` extension) bundled in the archive, as per
+    /// [`load_svd`].  It is not an error for the archive to have none.  As
+    /// SVD files specified explicitly take the place of those bundled in
+    /// the archive, this does nothing if any SVD file has been loaded --
+    /// allowing commands that need SVD to call it unconditionally.
+    ///
+    /// [`load_svd`]: Self::load_svd
+    ///
+    pub fn load_archive_svd(&mut self) -> Result<()> {
+        if self.archive.is_empty() || self.svd_loaded() {
+            return Ok(());
+        }
+
+        let mut names: Vec<String> = {
+            let cursor = Cursor::new(self.archive.as_slice());
+            let archive = zip::ZipArchive::new(cursor)?;
+
+            archive
+                .file_names()
+                .filter(|n| n.ends_with(".svd"))
+                .map(|n| n.to_string())
+                .collect()
+        };
+
+        names.sort();
+
+        for name in names {
+            let contents = self.extract_file(&name)?;
+            let device = SvdDevice::parse(str::from_utf8(&contents)?)
+                .with_context(|| format!("failed to parse {}", name))?;
+            let added = self.add_svd(device);
+
+            debug!("loaded {} peripherals from archive's {}", added, name);
+        }
+
+        Ok(())
+    }
+
+    fn add_svd(&mut self, device: SvdDevice) -> usize {
+        let mut added = 0;
+
+        for p in device.peripherals {
+            let name = p.name.to_lowercase();
+
+            if let btree_map::Entry::Vacant(e) =
+                self.manifest.peripherals.entry(name)
+            {
+                e.insert(HubrisPeripheral { address: p.base, size: p.size });
+                added += 1;
+            }
+
+            self.svd.push(p);
+        }
+
+        self.svd_index =
+            IntervalMap::new(self.svd.iter().enumerate().flat_map(|(i, p)| {
+                p.registers.iter().enumerate().map(move |(j, r)| {
+                    let addr = p.base.wrapping_add(r.offset);
+                    (addr..addr.saturating_add(r.nbytes() as u32), (i, j))
+                })
+            }));
+
+        added
+    }
+
+    /// Returns the board for which the archive was built, if known.
+    pub fn board(&self) -> Option<&str> {
+        self.manifest.board.as_deref()
+    }
+
+    /// Returns the features with which the kernel was built.
+    pub fn features(&self) -> &[String] {
+        &self.manifest.features
+    }
+
+    pub fn svd_loaded(&self) -> bool {
+        !self.svd.is_empty()
+    }
+
+    /// Returns the peripherals described by any loaded SVD files.
+    pub fn svd_peripherals(&self) -> &[SvdPeripheral] {
+        &self.svd
+    }
+
+    /// Looks up an SVD-described peripheral by name (ignoring case).
+    pub fn lookup_svd_peripheral(&self, name: &str) -> Option<&SvdPeripheral> {
+        self.svd.iter().find(|p| p.name.eq_ignore_ascii_case(name))
+    }
+
+    /// Looks up the SVD-described register (if any) at the specified address.
+    pub fn lookup_register(
+        &self,
+        addr: u32,
+    ) -> Option<(&SvdPeripheral, &SvdRegister)> {
+        self.svd_index.lookup(addr).and_then(|(range, &(i, j))| {
+            if range.start == addr {
+                Some((&self.svd[i], &self.svd[i].registers[j]))
+            } else {
+                None
+            }
+        })
+    }
+
     fn load_registers(&mut self, r: &[u8]) -> Result<()> {
         if r.len() % 8 != 0 {
             bail!("bad length {} in registers note", r.len());
@@ -2058,6 +2730,22 @@ impl HubrisArchive {
         Ok(())
     }
 
+    fn load_timebase(&mut self, t: &[u8]) -> Result<()> {
+        if t.len() % 16 != 0 {
+            bail!("bad length {} in timebase note", t.len());
+        }
+
+        for chunk in t.chunks_exact(16) {
+            let (ticks, nanos) = chunk.split_at(8);
+            let ticks = u64::from_le_bytes(ticks.try_into().unwrap());
+            let nanos = u64::from_le_bytes(nanos.try_into().unwrap());
+
+            self.timebase.add(TimeSample::from_nanos(ticks, nanos));
+        }
+
+        Ok(())
+    }
+
     pub fn load_dump(&mut self, dumpfile: &str) -> Result<()> {
         /*
          * We expect the dump to be an ELF core dump.
@@ -2082,6 +2770,9 @@ impl HubrisArchive {
                             OXIDE_NT_HUBRIS_REGISTERS => {
                                 self.load_registers(note.desc)?;
                             }
+                            OXIDE_NT_HUBRIS_TIMEBASE => {
+                                self.load_timebase(note.desc)?;
+                            }
                             _ => {
                                 bail!("unrecognized note 0x{:x}", note.n_type);
                             }
@@ -2112,6 +2803,30 @@ impl HubrisArchive {
         Ok(())
     }
 
+    ///
+    /// Samples the target's tick counter against host time, returning the
+    /// current number of ticks.  (If operating on a dump, the ticks are
+    /// returned without being sampled, as the dump's host time is known
+    /// only from the samples recorded in it.)
+    ///
+    pub fn sample_ticks(
+        &mut self,
+        core: &mut dyn crate::core::Core,
+    ) -> Result<u64> {
+        let addr = self.lookup_variable("TICKS")?.addr;
+
+        if core.is_dump() {
+            core.read_word_64(addr)
+        } else {
+            self.timebase.sample(core, addr)
+        }
+    }
+
+    /// Returns the correlation of target ticks with host time.
+    pub fn timebase(&self) -> &Timebase {
+        &self.timebase
+    }
+
     pub fn loaded(&self) -> bool {
         !self.modules.is_empty()
     }
@@ -2195,6 +2910,27 @@ impl HubrisArchive {
         }
     }
 
+    /// Looks up a symbol by name (either mangled or demangled), returning
+    /// its address and size.
+    pub fn lookup_symbol(&self, name: &str) -> Result<(u32, u32)> {
+        if let Some(sym) = self.esyms_byname.get(name) {
+            return Ok(*sym);
+        }
+
+        let found: Vec<_> = self
+            .esyms
+            .iter()
+            .filter(|(_, (dem, _))| dem == name)
+            .map(|(addr, (_, size))| (*addr, *size))
+            .collect();
+
+        match found.as_slice() {
+            [sym] => Ok(*sym),
+            [] => Err(anyhow!("symbol {} not found", name)),
+            _ => Err(anyhow!("{} matches more than one symbol", name)),
+        }
+    }
+
     ///
     /// Looks up the specified symbol.  This is more of a convenience routine
     /// that turns an Option into a Result.
@@ -2232,6 +2968,27 @@ impl HubrisArchive {
         }
     }
 
+    ///
+    /// Adds a variable of the specified size at the specified address, as
+    /// if it had been loaded from an archive.  This allows an archive to be
+    /// assembled for use with a [`MockCore`](crate::mock::MockCore) without
+    /// a Hubris archive; the variable has no type.
+    ///
+    pub fn mock_variable(&mut self, name: &str, addr: u32, size: usize) {
+        let goff = HubrisGoff { object: 0, goff: 0 };
+        let var = HubrisVariable { goff, addr, size };
+        self.variables.insert(name.to_string(), var);
+    }
+
+    ///
+    /// Adds a (typeless) definition, as if it had been loaded from an
+    /// archive; see [`HubrisArchive::mock_variable`].
+    ///
+    pub fn mock_definition(&mut self, name: &str) {
+        let goff = HubrisGoff { object: 0, goff: 0 };
+        self.definitions.insert(name.to_string(), goff);
+    }
+
     pub fn qualified_variables(
         &self,
     ) -> impl Iterator<Item = (&str, &HubrisVariable)> {
@@ -2245,6 +3002,18 @@ impl HubrisArchive {
         }
     }
 
+    /// Returns the defmt table for the specified task, if it uses defmt.
+    pub fn lookup_defmt(&self, task: HubrisTask) -> Option<&HubrisDefmt> {
+        self.defmt.get(&task)
+    }
+
+    /// Returns the tasks that use defmt.
+    pub fn defmt_tasks(&self) -> Vec<HubrisTask> {
+        let mut tasks: Vec<_> = self.defmt.keys().copied().collect();
+        tasks.sort();
+        tasks
+    }
+
     pub fn lookup_task(&self, name: &str) -> Option<&HubrisTask> {
         self.tasks.get(name)
     }
@@ -2260,6 +3029,27 @@ impl HubrisArchive {
         self.src.get(&goff)
     }
 
+    fn line_src(&self, ndx: usize, line: u64) -> HubrisSrc {
+        HubrisSrc { line, ..self.srcfiles[ndx].clone() }
+    }
+
+    /// Looks up the source file and line corresponding to the instruction
+    /// at `addr`, as determined by the DWARF line tables.
+    pub fn instr_src(&self, addr: u32) -> Option<HubrisSrc> {
+        match self.lines.range(..=addr).next_back() {
+            Some((_, Some((ndx, line)))) => Some(self.line_src(*ndx, *line)),
+            _ => None,
+        }
+    }
+
+    /// Returns every address in the DWARF line tables along with its
+    /// corresponding source file and line.
+    pub fn instr_lines(&self) -> impl Iterator<Item = (u32, HubrisSrc)> + '_ {
+        self.lines.iter().filter_map(move |(addr, entry)| {
+            entry.map(|(ndx, line)| (*addr, self.line_src(ndx, line)))
+        })
+    }
+
     fn ntasks(&self) -> usize {
         if self.current >= 1 {
             self.current as usize - 1
@@ -2268,13 +3058,44 @@ impl HubrisArchive {
         }
     }
 
+    /// Returns an identifier for the image in the archive, derived from its
+    /// app table (which is what we validate against the target).  Returns
+    /// `None` if no archive is loaded.  The identifier is stable, and can
+    /// therefore be persisted.
+    pub fn image_id(&self) -> Option<u64> {
+        if self.current == 0 {
+            return None;
+        }
+
+        let (addr, apptable) = &self.apptable;
+        Some(stable_hash(&[&addr.to_le_bytes(), apptable]))
+    }
+
+    /// Returns the identifier (as per [`HubrisArchive::image_id`]) of the
+    /// image on the target, as derived from its app table.
+    pub fn read_image_id(
+        &self,
+        core: &mut dyn crate::core::Core,
+    ) -> Result<u64> {
+        if self.current == 0 {
+            bail!("no archive loaded");
+        }
+
+        let (addr, apptable) = &self.apptable;
+        let mut buf = vec![0; apptable.len()];
+
+        core.read_8(*addr, &mut buf).with_context(|| {
+            format!("failed to read .hubris_app_table at 0x{:x}", addr)
+        })?;
+
+        Ok(stable_hash(&[&addr.to_le_bytes(), &buf]))
+    }
+
     pub fn validate(
         &self,
         core: &mut dyn crate::core::Core,
         criteria: HubrisValidate,
     ) -> Result<()> {
-        let ntasks = self.ntasks();
-
         if self.current == 0 {
             /*
              * If we have no objects, we were never loaded -- and we consider
@@ -2308,6 +3129,16 @@ impl HubrisArchive {
             return Ok(());
         }
 
+        self.validate_booted(core)
+    }
+
+    /// Validates that the target (already known to be running the image in
+    /// the archive) has booted.
+    pub fn validate_booted(
+        &self,
+        core: &mut dyn crate::core::Core,
+    ) -> Result<()> {
+        let ntasks = self.ntasks();
         let n = core.read_word_32(self.lookup_symword("TASK_TABLE_SIZE")?)?;
 
         if n == ntasks as u32 {
@@ -2330,6 +3161,77 @@ impl HubrisArchive {
         bail!("target does not appear to be booted");
     }
 
+    ///
+    /// Compares the image on the target against the image in the archive:
+    /// the app table, and then the text of the kernel and each task.  If a
+    /// number of samples is specified, only that many evenly-spaced chunks
+    /// of each object's text are compared; otherwise, all of it is.
+    ///
+    pub fn verify(
+        &self,
+        core: &mut dyn crate::core::Core,
+        samples: Option<usize>,
+    ) -> Result<Vec<HubrisVerifyRegion>> {
+        if self.current == 0 {
+            bail!("no archive loaded");
+        }
+
+        if samples == Some(0) {
+            bail!("number of samples must be non-zero");
+        }
+
+        let segments = self.image_segments()?;
+        let mut regions = vec![];
+
+        //
+        // Finds the contents of the specified range within the image,
+        // clamping it to the segment that contains its base.
+        //
+        let contents = |base: u32, size: u32| {
+            segments.iter().find_map(|(addr, data)| {
+                let end = *addr as u64 + data.len() as u64;
+
+                if base >= *addr && (base as u64) < end {
+                    let offs = (base - addr) as usize;
+                    let len = std::cmp::min(size as u64, end - base as u64);
+                    Some(&data[offs..offs + len as usize])
+                } else {
+                    None
+                }
+            })
+        };
+
+        regions.push(verify_region(
+            core,
+            "app table",
+            self.apptable.0,
+            &self.apptable.1,
+            samples,
+        )?);
+
+        for module in self.modules.values() {
+            match contents(module.textbase, module.textsize) {
+                Some(expected) => {
+                    regions.push(verify_region(
+                        core,
+                        &module.name,
+                        module.textbase,
+                        expected,
+                        samples,
+                    )?);
+                }
+                None => {
+                    warn!(
+                        "text of {} (0x{:x}) is not in the image",
+                        module.name, module.textbase
+                    );
+                }
+            }
+        }
+
+        Ok(regions)
+    }
+
     pub fn member_offset(
         &self,
         structure: &HubrisStruct,
@@ -2397,6 +3299,45 @@ impl HubrisArchive {
         Ok(offset as u32)
     }
 
+    ///
+    /// Returns the regions of memory that are described by the archive
+    /// alone:  the loadable segments of the kernel and of each task, along
+    /// with the kernel heap+bss.  Unlike [`regions`], this does not require
+    /// a target -- but it also does not include any memory that a task has
+    /// been granted that isn't in one of its loadable segments.
+    ///
+    /// [`regions`]: Self::regions
+    ///
+    pub fn loaded_regions(&self) -> BTreeMap<u32, HubrisRegion> {
+        let mut regions = self.loaded.clone();
+
+        for module in
+            self.modules.values().filter(|m| m.task == HubrisTask::Kernel)
+        {
+            if let (Some(sheapbss), Some(eheapbss)) = module.heapbss {
+                regions.insert(
+                    sheapbss,
+                    HubrisRegion {
+                        daddr: None,
+                        base: sheapbss,
+                        size: eheapbss - sheapbss,
+                        mapsize: eheapbss - sheapbss,
+                        attr: HubrisRegionAttr {
+                            read: true,
+                            write: true,
+                            execute: false,
+                            device: false,
+                            dma: false,
+                        },
+                        task: HubrisTask::Kernel,
+                    },
+                );
+            }
+        }
+
+        regions
+    }
+
     pub fn regions(
         &self,
         core: &mut dyn crate::core::Core,
@@ -2425,40 +3366,12 @@ impl HubrisArchive {
         let mut regions: BTreeMap<u32, HubrisRegion> = BTreeMap::new();
 
         /*
-         * Add our loaded kernel regions, which don't otherwise have
-         * descriptors.
+         * Add our loaded kernel regions (including the kernel heap+bss),
+         * which don't otherwise have descriptors.
          */
-        for region in self.loaded.values() {
+        for (base, region) in self.loaded_regions() {
             if region.task == HubrisTask::Kernel {
-                regions.insert(region.base, *region);
-            }
-        }
-
-        /*
-         * Add a region for our kernel heap+bss, for which we don't have a
-         * descriptor.
-         */
-        for module in
-            self.modules.values().filter(|m| m.task == HubrisTask::Kernel)
-        {
-            if let (Some(sheapbss), Some(eheapbss)) = module.heapbss {
-                regions.insert(
-                    sheapbss,
-                    HubrisRegion {
-                        daddr: None,
-                        base: sheapbss,
-                        size: eheapbss - sheapbss,
-                        mapsize: eheapbss - sheapbss,
-                        attr: HubrisRegionAttr {
-                            read: true,
-                            write: true,
-                            execute: false,
-                            device: false,
-                            dma: false,
-                        },
-                        task: HubrisTask::Kernel,
-                    },
-                );
+                regions.insert(base, region);
             }
         }
 
@@ -2673,143 +3586,105 @@ impl HubrisArchive {
         regs: &HashMap<ARMRegister, u32>,
     ) -> Result<Vec<HubrisStackFrame>> {
         let regions = self.regions(core)?;
-        let sp = regs
-            .get(&ARMRegister::SP)
-            .ok_or_else(|| anyhow!("SP missing from regs map"))?;
-        let pc = regs
-            .get(&ARMRegister::PC)
-            .ok_or_else(|| anyhow!("PC missing from regs map"))?;
-
-        let mut rval: Vec<HubrisStackFrame> = Vec::new();
-        let mut frameregs = regs.clone();
+        let (base, buf) = Self::stack_region(core, &regions, regs)?;
 
-        //
-        // First, find the region that contains our stack pointer.  We want
-        // to read that entire region.
-        //
-        let (_, region) = regions.range(..=sp).last().ok_or_else(|| {
-            anyhow!("could not find memory region containing sp 0x{:x}", sp)
-        })?;
-
-        let mut buf: Vec<u8> = vec![];
-        buf.resize_with(region.size as usize, Default::default);
-        core.read_8(region.base, buf.as_mut_slice())?;
+        self.unwinder().unwind(task, limit, regs, base, &buf)
+    }
 
-        let readval = |addr| {
-            let o = (addr - region.base) as usize;
-            u32::from_le_bytes(buf[o..o + 4].try_into().unwrap())
-        };
+    ///
+    /// Unwinds the stacks of the specified tasks, each specified as a tuple
+    /// of the task, its stack limit and its registers.  The stacks are read
+    /// from the core first; because unwinding is then entirely CPU-bound
+    /// (and can be substantial for an image with many tasks), the stacks
+    /// are unwound in parallel.  The results are returned in the order of
+    /// the specified tasks.
+    ///
+    pub fn stacks(
+        &self,
+        core: &mut dyn crate::core::Core,
+        tasks: &[(HubrisTask, u32, HashMap<ARMRegister, u32>)],
+    ) -> Result<Vec<Result<Vec<HubrisStackFrame>>>> {
+        if tasks.is_empty() {
+            return Ok(vec![]);
+        }
 
-        //
-        // If our PC is in a system call (highly likely), we need to determine
-        // what has been pushed on our stack via asm!().
-        //
-        if let Some(Some(pushed)) = self.syscall_pushes.get(pc) {
-            for (i, &p) in pushed.iter().enumerate() {
-                let val = readval(sp + (i * 4) as u32);
-                frameregs.insert(p, val);
+        let regions = self.regions(core)?;
+        let mut memory: HashMap<u32, Vec<u8>> = HashMap::new();
+        let mut bases = vec![];
+
+        for (_, _, regs) in tasks {
+            let base = Self::stack_region_base(&regions, regs);
+
+            if let Ok(base) = base {
+                if !memory.contains_key(&base) {
+                    let region = &regions[&base];
+                    let mut buf = vec![0; region.size as usize];
+                    core.read_8(region.base, buf.as_mut_slice())?;
+                    memory.insert(base, buf);
+                }
             }
 
-            frameregs.insert(ARMRegister::SP, sp + (pushed.len() * 4) as u32);
+            bases.push(base);
         }
 
-        let frames = self
-            .frames
-            .get(&task)
-            .ok_or_else(|| anyhow!("task {:?} not present in image", task))?;
-        let frame = gimli::DebugFrame::new(frames, gimli::LittleEndian);
-
-        loop {
-            let bases = gimli::BaseAddresses::default();
-            let mut ctx = gimli::UninitializedUnwindContext::new();
-            let pc = *frameregs.get(&ARMRegister::PC).unwrap();
-
-            //
-            // Now we want to iterate up our frames
-            //
-            let unwind_info = frame.unwind_info_for_address(
-                &bases,
-                &mut ctx,
-                pc as u64,
-                gimli::DebugFrame::cie_from_offset,
-            )?;
-
-            //
-            // Determine the CFA (Canonical Frame Address)
-            //
-            let cfa = match unwind_info.cfa() {
-                gimli::CfaRule::RegisterAndOffset { register, offset } => {
-                    let reg = ARMRegister::from_u16(register.0).unwrap();
-                    *frameregs.get(&reg).unwrap() + *offset as u32
-                }
-                _ => {
-                    panic!("unimplemented CFA rule");
-                }
-            };
-
-            //
-            // Now iterate over all of our register rules to transform
-            // our registers.
-            //
-            for (register, rule) in unwind_info.registers() {
-                let val = match rule {
-                    gimli::RegisterRule::Offset(offset) => {
-                        readval((i64::from(cfa) + offset) as u32)
-                    }
-                    _ => {
-                        panic!("unimplemented register rule");
-                    }
-                };
+        let unwinder = self.unwinder();
 
-                let reg = ARMRegister::from_u16(register.0).unwrap();
-                frameregs.insert(reg, val);
-            }
+        Ok(tasks
+            .par_iter()
+            .zip(bases.into_par_iter())
+            .map(|((task, limit, regs), base)| {
+                let base = base?;
+                unwinder.unwind(*task, *limit, regs, base, &memory[&base])
+            })
+            .collect())
+    }
 
-            frameregs.insert(ARMRegister::SP, cfa);
+    //
+    // Returns the base of the memory region that contains the stack pointer
+    // in the specified registers.
+    //
+    fn stack_region_base(
+        regions: &BTreeMap<u32, HubrisRegion>,
+        regs: &HashMap<ARMRegister, u32>,
+    ) -> Result<u32> {
+        let sp = regs
+            .get(&ARMRegister::SP)
+            .ok_or_else(|| anyhow!("SP missing from regs map"))?;
 
-            //
-            // Lookup the DWARF symbol associated with our PC
-            //
-            let sym = match self.dsyms.range(..=pc).next_back() {
-                Some((addr, sym)) if pc < *addr + sym.size => Some(sym),
-                _ => None,
-            };
+        let (base, _) = regions.range(..=sp).last().ok_or_else(|| {
+            anyhow!("could not find memory region containing sp 0x{:x}", sp)
+        })?;
 
-            //
-            // Determine if there is, in fact, an inlined stack here.
-            //
-            let inlined = match sym {
-                Some(sym) => {
-                    let mut inlined = self.instr_inlined(pc, sym.addr);
-                    inlined.reverse();
-                    Some(inlined)
-                }
-                None => None,
-            };
+        Ok(*base)
+    }
 
-            //
-            // Our frame is complete -- push it and continue!
-            //
-            rval.push(HubrisStackFrame {
-                cfa,
-                sym,
-                inlined,
-                registers: frameregs.clone(),
-            });
+    //
+    // Reads the entirety of the memory region that contains the stack
+    // pointer, returning its base and its contents.
+    //
+    fn stack_region(
+        core: &mut dyn crate::core::Core,
+        regions: &BTreeMap<u32, HubrisRegion>,
+        regs: &HashMap<ARMRegister, u32>,
+    ) -> Result<(u32, Vec<u8>)> {
+        let region = &regions[&Self::stack_region_base(regions, regs)?];
 
-            //
-            // Get our LR, and make sure that the low (Thumb) bit is clear
-            //
-            let lr = *frameregs.get(&ARMRegister::LR).unwrap() & !1;
+        let mut buf: Vec<u8> = vec![];
+        buf.resize_with(region.size as usize, Default::default);
+        core.read_8(region.base, buf.as_mut_slice())?;
 
-            frameregs.insert(ARMRegister::PC, lr);
+        Ok((region.base, buf))
+    }
 
-            if cfa >= limit {
-                break;
-            }
+    fn unwinder(&self) -> HubrisUnwinder {
+        HubrisUnwinder {
+            frames: &self.frames,
+            syscall_pushes: &self.syscall_pushes,
+            dsyms: &self.dsyms,
+            dsyms_index: &self.dsyms_index,
+            inlined: &self.inlined,
+            subprograms: &self.subprograms,
         }
-
-        Ok(rval)
     }
 
     pub fn typesize(&self, goff: HubrisGoff) -> Result<usize> {
@@ -3161,6 +4036,24 @@ impl HubrisArchive {
             n_type: OXIDE_NT_HUBRIS_ARCHIVE,
         });
 
+        //
+        // We record our time samples -- along with a sample taken now -- to
+        // allow the host time of events in the dump to be determined.
+        //
+        let mut timebase = self.timebase.clone();
+
+        if let Ok(ticks) = self.lookup_variable("TICKS") {
+            timebase.sample(core, ticks.addr)?;
+        }
+
+        if !timebase.samples().is_empty() {
+            notes.push(goblin::elf::note::Nhdr32 {
+                n_namesz: (oxide.len() + 1) as u32,
+                n_descsz: timebase.samples().len() as u32 * 16,
+                n_type: OXIDE_NT_HUBRIS_TIMEBASE,
+            });
+        }
+
         let mut header = goblin::elf::header::Header::new(ctx);
         header.e_machine = goblin::elf::header::EM_ARM;
         header.e_type = goblin::elf::header::ET_CORE;
@@ -3278,6 +4171,15 @@ impl HubrisArchive {
                 OXIDE_NT_HUBRIS_ARCHIVE => {
                     file.write_all(&self.archive)?;
                 }
+                OXIDE_NT_HUBRIS_TIMEBASE => {
+                    let mut bytes = [0x0u8; 16];
+
+                    for sample in timebase.samples() {
+                        bytes.pwrite_with(sample.ticks, 0, ctx.le)?;
+                        bytes.pwrite_with(sample.nanos(), 8, ctx.le)?;
+                        file.write_all(&bytes)?;
+                    }
+                }
                 _ => {
                     panic!("unimplemented note");
                 }
@@ -3289,7 +4191,11 @@ impl HubrisArchive {
 
         /*
          * And now we write our segments.  This takes a little while, so
-         * we're going to indicate our progress as we go.
+         * we're going to indicate our progress as we go.  So that we are
+         * reading the next chunk from the target while the previous chunk
+         * is being written out, the writing is done by a separate thread;
+         * we bound the channel to it such that there is at most one chunk
+         * outstanding.
          */
         let mut written = 0;
 
@@ -3300,31 +4206,65 @@ impl HubrisArchive {
                 .template("humility: dumping [{bar:30}] {bytes}/{total_bytes}"),
         );
 
-        for (_, region) in regions.iter() {
+        let (tx, rx) = std::sync::mpsc::sync_channel::<Vec<u8>>(1);
+
+        let writer = std::thread::spawn(move || -> Result<()> {
+            for buf in rx {
+                file.write_all(&buf)?;
+            }
+
+            Ok(())
+        });
+
+        let mut rval = Ok(());
+
+        'regions: for (_, region) in regions.iter() {
             if region.attr.device {
                 continue;
             }
 
             let mut remain = region.size as usize;
-            let mut bytes = vec![0; 1024];
             let mut addr = region.base;
 
             while remain > 0 {
-                let nbytes =
-                    if remain > bytes.len() { bytes.len() } else { remain };
+                let nbytes = if remain > 1024 { 1024 } else { remain };
+                let mut bytes = vec![0; nbytes];
+
+                if let Err(err) = core.read_8(addr, &mut bytes) {
+                    rval = Err(err);
+                    break 'regions;
+                }
+
+                if tx.send(bytes).is_err() {
+                    /*
+                     * The writer has hung up on us, and will tell us why
+                     * when we join it.
+                     */
+                    break 'regions;
+                }
 
-                core.read_8(addr, &mut bytes[0..nbytes])?;
-                file.write_all(&bytes[0..nbytes])?;
                 remain -= nbytes;
                 written += nbytes;
                 addr += nbytes as u32;
                 bar.set_position(written as u64);
             }
 
-            let npad = pad!(region.size) as usize;
-            file.write_all(&pad[0..npad])?;
+            let npad = pad!(region.size) as usize;
+
+            if tx.send(pad[0..npad].to_vec()).is_err() {
+                break;
+            }
+        }
+
+        drop(tx);
+
+        match writer.join() {
+            Ok(r) => rval = rval.and(r),
+            Err(_) => bail!("dump writer thread panicked"),
         }
 
+        rval?;
+
         bar.finish_and_clear();
 
         info!(
@@ -3492,6 +4432,100 @@ impl HubrisArchive {
         Ok(())
     }
 
+    /// Returns the manifest (as displayed by [`Self::manifest`]) as JSON.
+    pub fn manifest_json(&self) -> Result<Json> {
+        ensure!(
+            !self.modules.is_empty(),
+            "must specify a valid Hubris archive"
+        );
+
+        let m = &self.manifest;
+
+        let tasks = self
+            .modules
+            .values()
+            .filter_map(|module| match module.task {
+                HubrisTask::Task(id) => Some((id, module)),
+                HubrisTask::Kernel => None,
+            })
+            .map(|(id, module)| {
+                Json::object(vec![
+                    ("id", Json::from(id)),
+                    ("name", Json::from(&module.name)),
+                    ("size", Json::from(module.memsize)),
+                    (
+                        "features",
+                        Json::from(
+                            m.task_features
+                                .get(&module.name)
+                                .cloned()
+                                .unwrap_or_default(),
+                        ),
+                    ),
+                ])
+            })
+            .collect::<Vec<_>>();
+
+        let port = |port: &HubrisI2cPort| Json::from(&port.name);
+
+        let buses = m
+            .i2c_buses
+            .iter()
+            .map(|bus| {
+                Json::object(vec![
+                    ("controller", Json::from(bus.controller)),
+                    ("port", port(&bus.port)),
+                    ("target", Json::from(bus.target)),
+                    ("name", Json::from(bus.name.as_ref())),
+                    ("description", Json::from(bus.description.as_ref())),
+                ])
+            })
+            .collect::<Vec<_>>();
+
+        let devices = m
+            .i2c_devices
+            .iter()
+            .map(|device| {
+                Json::object(vec![
+                    ("controller", Json::from(device.controller)),
+                    ("port", port(&device.port)),
+                    ("mux", Json::from(device.mux)),
+                    ("segment", Json::from(device.segment)),
+                    ("address", Json::from(device.address)),
+                    ("device", Json::from(&device.device)),
+                    ("description", Json::from(&device.description)),
+                ])
+            })
+            .collect::<Vec<_>>();
+
+        let kernel = self
+            .modules
+            .values()
+            .find(|module| module.task == HubrisTask::Kernel)
+            .map(|module| module.memsize);
+
+        Ok(Json::object(vec![
+            ("version", Json::from(m.version.as_ref())),
+            ("gitrev", Json::from(m.gitrev.as_ref())),
+            ("board", Json::from(m.board.as_ref())),
+            ("target", Json::from(m.target.as_ref())),
+            ("features", Json::from(m.features.clone())),
+            (
+                "size",
+                Json::from(
+                    self.modules
+                        .values()
+                        .map(|module| module.memsize)
+                        .sum::<u32>(),
+                ),
+            ),
+            ("kernel_size", Json::from(kernel)),
+            ("tasks", Json::Array(tasks)),
+            ("i2c_buses", Json::Array(buses)),
+            ("i2c_devices", Json::Array(devices)),
+        ]))
+    }
+
     #[allow(clippy::print_literal)]
     pub fn list_variables(&self) -> Result<()> {
         let mut variables = vec![];
@@ -3548,12 +4582,12 @@ impl HubrisArchive {
 
     pub fn lookup_peripheral(&self, name: &str) -> Result<u32> {
         ensure!(
-            !self.modules.is_empty(),
-            "Hubris archive required to specify a peripheral"
+            !self.modules.is_empty() || !self.svd.is_empty(),
+            "Hubris archive or SVD file required to specify a peripheral"
         );
 
-        if let Some(addr) = self.manifest.peripherals.get(name) {
-            Ok(*addr)
+        if let Some(p) = self.manifest.peripherals.get(name) {
+            Ok(p.address)
         } else {
             let peripherals: Vec<&str> =
                 self.manifest.peripherals.keys().map(String::as_str).collect();
@@ -3714,7 +4748,18 @@ impl From<HubrisGoff> for HubrisTask {
 ///
 /// An identifier that corresponds to a global offset within a particular DWARF
 /// object.
-#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Copy, Clone)]
+#[derive(
+    Debug,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Hash,
+    Copy,
+    Clone,
+    Serialize,
+    Deserialize,
+)]
 pub struct HubrisGoff {
     pub object: u32,
     pub goff: usize,
@@ -3730,7 +4775,7 @@ impl fmt::Display for HubrisGoff {
     }
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct HubrisSymbol {
     pub addr: u32,
     pub name: String,
@@ -3747,7 +4792,7 @@ pub struct HubrisInlined<'a> {
     pub origin: HubrisGoff,
 }
 
-#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub enum HubrisEncoding {
     Unknown,
     Signed,
@@ -3756,20 +4801,22 @@ pub enum HubrisEncoding {
     Bool,
 }
 
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
 pub struct HubrisBasetype {
     pub encoding: HubrisEncoding,
     pub size: usize,
 }
 
-#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[derive(
+    Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize,
+)]
 pub struct HubrisStructMember {
     pub offset: usize,
     pub name: String,
     pub goff: HubrisGoff,
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct HubrisStruct {
     pub name: String,
     pub goff: HubrisGoff,
@@ -3789,14 +4836,16 @@ impl HubrisStruct {
     }
 }
 
-#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[derive(
+    Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize,
+)]
 pub struct HubrisVariable {
     pub goff: HubrisGoff,
     pub addr: u32,
     pub size: usize,
 }
 
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
 pub struct HubrisArray {
     pub goff: HubrisGoff,
     pub count: usize,
@@ -3821,7 +4870,7 @@ pub struct HubrisRegion {
     pub task: HubrisTask,
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct HubrisEnumVariant {
     pub name: String,
     pub offset: usize,
@@ -3829,13 +4878,13 @@ pub struct HubrisEnumVariant {
     pub tag: Option<u64>,
 }
 
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
 pub enum HubrisDiscriminant {
     Expected(HubrisGoff),
     Value(HubrisGoff, usize),
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct HubrisEnum {
     pub name: String,
     pub goff: HubrisGoff,
@@ -3925,7 +4974,7 @@ impl HubrisEnum {
     }
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct HubrisUnion {
     pub name: String,
     pub goff: HubrisGoff,
@@ -4071,6 +5120,197 @@ pub enum HubrisTarget {
     Return,
 }
 
+//
+// The parts of an archive needed to unwind a stack.  The archive itself
+// can't be shared across threads (our disassembler isn't), so unwinding --
+// which we may want to do in parallel -- is done via this view of it.
+//
+#[derive(Copy, Clone)]
+struct HubrisUnwinder<'a> {
+    frames: &'a HashMap<HubrisTask, Vec<u8>>,
+    syscall_pushes: &'a HashMap<u32, Option<Vec<ARMRegister>>>,
+    dsyms: &'a BTreeMap<u32, HubrisSymbol>,
+    dsyms_index: &'a IntervalMap<u32>,
+    inlined: &'a BTreeMap<(u32, isize), (u32, HubrisGoff, HubrisGoff)>,
+    subprograms: &'a HashMap<HubrisGoff, String>,
+}
+
+impl<'a> HubrisUnwinder<'a> {
+    fn inlined(&self, pc: u32, base: u32) -> Vec<HubrisInlined<'a>> {
+        let mut inlined: Vec<HubrisInlined> = vec![];
+
+        /*
+         * We find our stack of inlined functions by searching backwards from
+         * our address (which we know must be greater than or equal to all
+         * inlined functions that it is in).  This yields a vector that
+         * starts from the greatest depth and ends with the least
+         * depth -- so we reverse it before we return it.  We know
+         * that our search is over when the address plus the length
+         * is less than our base.
+         */
+        for ((addr, _depth), (len, goff, origin)) in
+            self.inlined.range(..=(pc, std::isize::MAX)).rev()
+        {
+            if addr + len < base {
+                break;
+            }
+
+            if addr + len <= pc {
+                continue;
+            }
+
+            if let Some(func) = self.subprograms.get(origin) {
+                inlined.push(HubrisInlined {
+                    addr: *addr as u32,
+                    name: func,
+                    id: *goff,
+                    origin: *origin,
+                });
+            }
+        }
+
+        inlined.reverse();
+        inlined
+    }
+
+    //
+    // Unwinds a stack, given the contents of the memory region that
+    // contains it.
+    //
+    fn unwind(
+        &self,
+        task: HubrisTask,
+        limit: u32,
+        regs: &HashMap<ARMRegister, u32>,
+        base: u32,
+        buf: &[u8],
+    ) -> Result<Vec<HubrisStackFrame<'a>>> {
+        let sp = regs
+            .get(&ARMRegister::SP)
+            .ok_or_else(|| anyhow!("SP missing from regs map"))?;
+        let pc = regs
+            .get(&ARMRegister::PC)
+            .ok_or_else(|| anyhow!("PC missing from regs map"))?;
+
+        let mut rval: Vec<HubrisStackFrame> = Vec::new();
+        let mut frameregs = regs.clone();
+
+        let readval = |addr| {
+            let o = (addr - base) as usize;
+            u32::from_le_bytes(buf[o..o + 4].try_into().unwrap())
+        };
+
+        //
+        // If our PC is in a system call (highly likely), we need to determine
+        // what has been pushed on our stack via asm!().
+        //
+        if let Some(Some(pushed)) = self.syscall_pushes.get(pc) {
+            for (i, &p) in pushed.iter().enumerate() {
+                let val = readval(sp + (i * 4) as u32);
+                frameregs.insert(p, val);
+            }
+
+            frameregs.insert(ARMRegister::SP, sp + (pushed.len() * 4) as u32);
+        }
+
+        let frames = self
+            .frames
+            .get(&task)
+            .ok_or_else(|| anyhow!("task {:?} not present in image", task))?;
+        let frame = gimli::DebugFrame::new(frames, gimli::LittleEndian);
+
+        loop {
+            let bases = gimli::BaseAddresses::default();
+            let mut ctx = gimli::UninitializedUnwindContext::new();
+            let pc = *frameregs.get(&ARMRegister::PC).unwrap();
+
+            //
+            // Now we want to iterate up our frames
+            //
+            let unwind_info = frame.unwind_info_for_address(
+                &bases,
+                &mut ctx,
+                pc as u64,
+                gimli::DebugFrame::cie_from_offset,
+            )?;
+
+            //
+            // Determine the CFA (Canonical Frame Address)
+            //
+            let cfa = match unwind_info.cfa() {
+                gimli::CfaRule::RegisterAndOffset { register, offset } => {
+                    let reg = ARMRegister::from_u16(register.0).unwrap();
+                    *frameregs.get(&reg).unwrap() + *offset as u32
+                }
+                _ => {
+                    panic!("unimplemented CFA rule");
+                }
+            };
+
+            //
+            // Now iterate over all of our register rules to transform
+            // our registers.
+            //
+            for (register, rule) in unwind_info.registers() {
+                let val = match rule {
+                    gimli::RegisterRule::Offset(offset) => {
+                        readval((i64::from(cfa) + offset) as u32)
+                    }
+                    _ => {
+                        panic!("unimplemented register rule");
+                    }
+                };
+
+                let reg = ARMRegister::from_u16(register.0).unwrap();
+                frameregs.insert(reg, val);
+            }
+
+            frameregs.insert(ARMRegister::SP, cfa);
+
+            //
+            // Lookup the DWARF symbol associated with our PC
+            //
+            let sym =
+                self.dsyms_index.lookup(pc).map(|(_, addr)| &self.dsyms[addr]);
+
+            //
+            // Determine if there is, in fact, an inlined stack here.
+            //
+            let inlined = match sym {
+                Some(sym) => {
+                    let mut inlined = self.inlined(pc, sym.addr);
+                    inlined.reverse();
+                    Some(inlined)
+                }
+                None => None,
+            };
+
+            //
+            // Our frame is complete -- push it and continue!
+            //
+            rval.push(HubrisStackFrame {
+                cfa,
+                sym,
+                inlined,
+                registers: frameregs.clone(),
+            });
+
+            //
+            // Get our LR, and make sure that the low (Thumb) bit is clear
+            //
+            let lr = *frameregs.get(&ARMRegister::LR).unwrap() & !1;
+
+            frameregs.insert(ARMRegister::PC, lr);
+
+            if cfa >= limit {
+                break;
+            }
+        }
+
+        Ok(rval)
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct HubrisStackFrame<'a> {
     pub cfa: u32,
@@ -4079,7 +5319,7 @@ pub struct HubrisStackFrame<'a> {
     pub inlined: Option<Vec<HubrisInlined<'a>>>,
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct HubrisSrc {
     pub file: String,
     pub directory: Option<String>,
@@ -4106,6 +5346,16 @@ impl HubrisSrc {
     }
 }
 
+///
+/// The defmt table of an object:  the (JSON-encoded) symbol that describes
+/// each index, and the encoding of frames.
+///
+#[derive(Clone, Debug, Default)]
+pub struct HubrisDefmt {
+    pub table: BTreeMap<u16, String>,
+    pub encoding: Option<String>,
+}
+
 #[derive(Clone, Debug)]
 pub struct HubrisModule {
     pub name: String,
@@ -4135,6 +5385,88 @@ impl HubrisPrintFormat {
     }
 }
 
+/// The size of the chunks in which images are verified
+pub const HUBRIS_VERIFY_CHUNK: usize = 256;
+
+/// The number of chunks of each object sampled by a pre-flight verification
+pub const HUBRIS_VERIFY_SAMPLES: usize = 8;
+
+/// A region of the image on the target, as compared against the archive.
+#[derive(Clone, Debug)]
+pub struct HubrisVerifyRegion {
+    pub name: String,
+    pub base: u32,
+    pub size: u32,
+
+    /// the number of bytes compared (fewer than the size if sampled)
+    pub compared: u32,
+
+    /// the first address that differs, and the number of bytes that differ
+    pub differs: Option<(u32, u32)>,
+}
+
+///
+/// Compares the memory on the target at the specified base against the
+/// expected contents, in chunks of [`HUBRIS_VERIFY_CHUNK`] bytes.  If a
+/// number of samples is specified, only that many evenly-spaced chunks are
+/// compared; otherwise, all of them are.
+///
+pub fn verify_region(
+    core: &mut dyn crate::core::Core,
+    name: &str,
+    base: u32,
+    expected: &[u8],
+    samples: Option<usize>,
+) -> Result<HubrisVerifyRegion> {
+    let chunk = HUBRIS_VERIFY_CHUNK;
+    let nchunks = (expected.len() + chunk - 1) / chunk;
+
+    let chunks: Vec<usize> = match samples {
+        Some(0) => bail!("number of samples must be non-zero"),
+        Some(n) if n < nchunks => {
+            let gaps = std::cmp::max(n, 2) - 1;
+            (0..n).map(|i| i * (nchunks - 1) / gaps).collect()
+        }
+        _ => (0..nchunks).collect(),
+    };
+
+    let mut region = HubrisVerifyRegion {
+        name: name.to_string(),
+        base,
+        size: expected.len() as u32,
+        compared: 0,
+        differs: None,
+    };
+
+    let mut buf = vec![0u8; chunk];
+
+    for i in chunks {
+        let offs = i * chunk;
+        let expected =
+            &expected[offs..std::cmp::min(offs + chunk, expected.len())];
+        let buf = &mut buf[..expected.len()];
+
+        core.read_8(base + offs as u32, buf)?;
+        region.compared += expected.len() as u32;
+
+        let ndiffs =
+            buf.iter().zip(expected).filter(|(l, r)| l != r).count() as u32;
+
+        if ndiffs == 0 {
+            continue;
+        }
+
+        let first = buf.iter().zip(expected).position(|(l, r)| l != r).unwrap();
+
+        region.differs = match region.differs {
+            Some((addr, n)) => Some((addr, n + ndiffs)),
+            None => Some((base + (offs + first) as u32, ndiffs)),
+        };
+    }
+
+    Ok(region)
+}
+
 #[derive(Copy, Clone, Debug, PartialEq)]
 pub enum HubrisValidate {
     ArchiveMatch,
@@ -4161,3 +5493,68 @@ fn demangle_name(name: &str) -> String {
     // values on functions.
     format!("{:#}", rustc_demangle::demangle(name))
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::mock::MockCore;
+
+    fn core(contents: &[u8]) -> MockCore {
+        let mut core = MockCore::new();
+        core.memory.add(0x0800_0000, contents.to_vec()).unwrap();
+        core
+    }
+
+    #[test]
+    fn verify_region_matches() {
+        let image: Vec<u8> =
+            (0..4 * HUBRIS_VERIFY_CHUNK).map(|i| i as u8).collect();
+        let mut core = core(&image);
+
+        let region =
+            verify_region(&mut core, "flash", 0x0800_0000, &image, None)
+                .unwrap();
+
+        assert_eq!(region.compared, image.len() as u32);
+        assert_eq!(region.differs, None);
+    }
+
+    #[test]
+    fn verify_region_differs() {
+        let image = vec![0xa5u8; 4 * HUBRIS_VERIFY_CHUNK + 3];
+        let mut target = image.clone();
+        target[HUBRIS_VERIFY_CHUNK + 7] = 0;
+        target[image.len() - 1] = 0;
+
+        let mut core = core(&target);
+
+        let region =
+            verify_region(&mut core, "flash", 0x0800_0000, &image, None)
+                .unwrap();
+
+        let first = 0x0800_0000 + HUBRIS_VERIFY_CHUNK as u32 + 7;
+        assert_eq!(region.differs, Some((first, 2)));
+    }
+
+    #[test]
+    fn verify_region_sampled() {
+        let image = vec![0xa5u8; 8 * HUBRIS_VERIFY_CHUNK];
+        let mut core = core(&image);
+
+        let region =
+            verify_region(&mut core, "flash", 0x0800_0000, &image, Some(2))
+                .unwrap();
+
+        assert_eq!(region.compared, 2 * HUBRIS_VERIFY_CHUNK as u32);
+        assert_eq!(region.differs, None);
+
+        assert!(verify_region(
+            &mut core,
+            "flash",
+            0x0800_0000,
+            &image,
+            Some(0)
+        )
+        .is_err());
+    }
+}
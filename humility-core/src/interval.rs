@@ -0,0 +1,122 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//!
+//! A static map from address ranges to values, built once and then queried
+//! (many times) for the ranges that contain a given address.  This is used
+//! for address-to-symbol lookup, where ranges may nest (or, in degenerate
+//! cases, partially overlap):  a lookup that only considers the range that
+//! starts nearest to (but at or below) an address will miss an enclosing
+//! range whenever that nearest range ends before the address.
+//!
+//! Ranges are kept sorted by their start and then, for ranges that start at
+//! the same address, outermost (that is, longest) first, along with the
+//! running maximum of their ends.  A lookup binary searches for the last
+//! range that starts at or below the address, and then walks backwards only
+//! as long as some earlier range could still contain it -- so the ranges
+//! that contain an address are found innermost first, even when they share
+//! a start.
+//!
+
+use std::cmp::Reverse;
+use std::ops::Range;
+
+#[derive(Clone, Debug)]
+pub struct IntervalMap<T> {
+    entries: Vec<(Range<u32>, T)>,
+    maxend: Vec<u64>,
+}
+
+impl<T> Default for IntervalMap<T> {
+    fn default() -> Self {
+        Self { entries: vec![], maxend: vec![] }
+    }
+}
+
+impl<T> IntervalMap<T> {
+    /// Builds a map from the specified ranges; empty ranges are dropped.
+    pub fn new(ranges: impl IntoIterator<Item = (Range<u32>, T)>) -> Self {
+        let mut entries: Vec<(Range<u32>, T)> = ranges
+            .into_iter()
+            .filter(|(range, _)| range.start < range.end)
+            .collect();
+
+        //
+        // For ranges with a common start, the outermost must sort first so
+        // that walking backwards finds the innermost first.
+        //
+        entries.sort_by_key(|(range, _)| (range.start, Reverse(range.end)));
+
+        let mut max = 0;
+
+        let maxend = entries
+            .iter()
+            .map(|(range, _)| {
+                max = u64::max(max, range.end as u64);
+                max
+            })
+            .collect();
+
+        Self { entries, maxend }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Returns all ranges that contain the specified address, innermost
+    /// (that is, latest starting and, for a common start, shortest) first.
+    pub fn containing(
+        &self,
+        addr: u32,
+    ) -> impl Iterator<Item = (&Range<u32>, &T)> + '_ {
+        let n = self.entries.partition_point(|(r, _)| r.start <= addr);
+
+        (0..n)
+            .rev()
+            .take_while(move |&i| self.maxend[i] > addr as u64)
+            .map(move |i| (&self.entries[i].0, &self.entries[i].1))
+            .filter(move |(range, _)| range.contains(&addr))
+    }
+
+    /// Returns the innermost range that contains the specified address.
+    pub fn lookup(&self, addr: u32) -> Option<(&Range<u32>, &T)> {
+        self.containing(addr).next()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn lookup_nested() {
+        let map = IntervalMap::new(vec![(0..100, 'a'), (10..20, 'b')]);
+
+        assert_eq!(map.lookup(5).map(|(_, v)| *v), Some('a'));
+        assert_eq!(map.lookup(15).map(|(_, v)| *v), Some('b'));
+        assert_eq!(map.lookup(50).map(|(_, v)| *v), Some('a'));
+        assert_eq!(map.lookup(100), None);
+    }
+
+    #[test]
+    fn lookup_common_start() {
+        let map = IntervalMap::new(vec![
+            (10..20, 'b'),
+            (10..100, 'a'),
+            (10..15, 'c'),
+        ]);
+
+        assert_eq!(map.lookup(12).map(|(_, v)| *v), Some('c'));
+        assert_eq!(map.lookup(17).map(|(_, v)| *v), Some('b'));
+        assert_eq!(map.lookup(50).map(|(_, v)| *v), Some('a'));
+
+        let all: Vec<char> = map.containing(12).map(|(_, v)| *v).collect();
+        assert_eq!(all, vec!['c', 'b', 'a']);
+    }
+}
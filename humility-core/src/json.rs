@@ -0,0 +1,217 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//!
+//! Machine-readable output.  Commands that support `--output json` build a
+//! [`Json`] value describing their results and print it, rather than
+//! printing their usual human-readable output.  Objects retain the order in
+//! which their members were added, which keeps the output stable (and
+//! diffable) from run to run.  Values are serialized (and strings escaped)
+//! by `serde_json`.
+//!
+//! The results of HIF function calls can be converted via [`hiffy_result`]
+//! and [`hiffy_results`], which denote errors by name rather than by code
+//! and decode small payloads as integers.
+//!
+
+use crate::hiffy::HiffyFunction;
+use serde::ser::{Serialize, SerializeMap, SerializeSeq, Serializer};
+use std::fmt;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Json {
+    Null,
+    Bool(bool),
+    Int(i64),
+    Uint(u64),
+    Float(f64),
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    /// Creates an object from the specified members.
+    pub fn object<K: Into<String>>(members: Vec<(K, Json)>) -> Self {
+        Json::Object(members.into_iter().map(|(k, v)| (k.into(), v)).collect())
+    }
+
+    /// Prints the value, followed by a newline.
+    pub fn print(&self) {
+        println!("{}", self);
+    }
+}
+
+impl Serialize for Json {
+    fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        match self {
+            Json::Null => s.serialize_unit(),
+            Json::Bool(val) => s.serialize_bool(*val),
+            Json::Int(val) => s.serialize_i64(*val),
+            Json::Uint(val) => s.serialize_u64(*val),
+            Json::Float(val) if val.is_finite() => s.serialize_f64(*val),
+            Json::Float(_) => s.serialize_unit(),
+            Json::String(val) => s.serialize_str(val),
+            Json::Array(vals) => {
+                let mut seq = s.serialize_seq(Some(vals.len()))?;
+
+                for val in vals {
+                    seq.serialize_element(val)?;
+                }
+
+                seq.end()
+            }
+            Json::Object(members) => {
+                let mut map = s.serialize_map(Some(members.len()))?;
+
+                for (key, val) in members {
+                    map.serialize_entry(key, val)?;
+                }
+
+                map.end()
+            }
+        }
+    }
+}
+
+impl fmt::Display for Json {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let json = serde_json::to_string(self).map_err(|_| fmt::Error)?;
+        write!(f, "{}", json)
+    }
+}
+
+macro_rules! json_from {
+    ($variant:ident, $into:ty, $($t:ty),*) => (
+        $(
+            impl From<$t> for Json {
+                #[allow(clippy::unnecessary_cast)]
+                fn from(val: $t) -> Self {
+                    Json::$variant(val as $into)
+                }
+            }
+        )*
+    )
+}
+
+json_from!(Uint, u64, u8, u16, u32, u64, usize);
+json_from!(Int, i64, i8, i16, i32, i64, isize);
+json_from!(Float, f64, f32, f64);
+
+impl From<bool> for Json {
+    fn from(val: bool) -> Self {
+        Json::Bool(val)
+    }
+}
+
+impl From<&str> for Json {
+    fn from(val: &str) -> Self {
+        Json::String(val.to_string())
+    }
+}
+
+impl From<String> for Json {
+    fn from(val: String) -> Self {
+        Json::String(val)
+    }
+}
+
+impl From<&String> for Json {
+    fn from(val: &String) -> Self {
+        Json::String(val.clone())
+    }
+}
+
+impl<T: Into<Json>> From<Option<T>> for Json {
+    fn from(val: Option<T>) -> Self {
+        match val {
+            Some(val) => val.into(),
+            None => Json::Null,
+        }
+    }
+}
+
+impl<T: Into<Json>> From<Vec<T>> for Json {
+    fn from(vals: Vec<T>) -> Self {
+        Json::Array(vals.into_iter().map(|v| v.into()).collect())
+    }
+}
+
+impl From<&[u8]> for Json {
+    fn from(vals: &[u8]) -> Self {
+        Json::Array(vals.iter().map(|v| Json::from(*v)).collect())
+    }
+}
+
+///
+/// Converts the result of a call to the specified HIF function.  A
+/// successful result has its payload as an array of bytes -- and, if the
+/// payload is the size of an integer, also as a little-endian integer.  A
+/// failed result has its error code along with the name of the error.
+///
+pub fn hiffy_result(
+    func: &HiffyFunction,
+    result: &Result<Vec<u8>, u32>,
+) -> Json {
+    match result {
+        Ok(payload) => {
+            let mut members = vec![
+                ("ok", Json::Bool(true)),
+                ("data", Json::from(payload.as_slice())),
+            ];
+
+            if let 1 | 2 | 4 | 8 = payload.len() {
+                let value = payload
+                    .iter()
+                    .rev()
+                    .fold(0u64, |val, b| (val << 8) | *b as u64);
+
+                members.push(("value", Json::from(value)));
+            }
+
+            Json::object(members)
+        }
+        Err(code) => Json::object(vec![
+            ("ok", Json::Bool(false)),
+            ("code", Json::from(*code)),
+            ("error", Json::from(func.strerror(*code))),
+        ]),
+    }
+}
+
+/// Converts the results of calls to the specified HIF function.
+pub fn hiffy_results(
+    func: &HiffyFunction,
+    results: &[Result<Vec<u8>, u32>],
+) -> Json {
+    Json::Array(results.iter().map(|r| hiffy_result(func, r)).collect())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn order() {
+        let json = Json::object(vec![
+            ("z", Json::from(1u32)),
+            ("a", Json::from(-1i32)),
+            ("m", Json::Null),
+        ]);
+
+        assert_eq!(json.to_string(), r#"{"z":1,"a":-1,"m":null}"#);
+    }
+
+    #[test]
+    fn escape() {
+        let json = Json::from("a \"quoted\"\tline\u{1}\n");
+        assert_eq!(json.to_string(), r#""a \"quoted\"\tline\u0001\n""#);
+    }
+
+    #[test]
+    fn float() {
+        let json = Json::Array(vec![Json::from(1.5f64), Json::from(f64::NAN)]);
+        assert_eq!(json.to_string(), "[1.5,null]");
+    }
+}
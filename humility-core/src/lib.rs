@@ -2,9 +2,43 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
+//!
+//! The machinery of Humility, as a library for other tools (e.g.,
+//! manufacturing fixtures or fleet tooling) that want to operate on Hubris
+//! targets without shelling out to the `humility` command.  The principal
+//! abstractions are:
+//!
+//! - [`core::Core`], a connection to a target (whether via a probe, a GDB
+//!   server, a `humility daemon` or a dump), as returned by
+//!   [`core::attach`] or [`core::attach_dump`] -- and which can itself be
+//!   served to other machines via [`net::serve`];
+//!
+//! - [`hubris::HubrisArchive`], a loaded Hubris archive (or dump), which
+//!   provides validation against a target, symbol and type lookup, stack
+//!   unwinding, and the creation of dumps via [`hubris::HubrisArchive::dump`];
+//!
+//! - [`timebase::Timebase`], which correlates the target's tick counter
+//!   with host time;
+//!
+//! - [`hiffy::HiffyContext`], which runs HIF programs on a target that
+//!   includes the `hiffy` task, and can be used to call the HIF functions
+//!   that the target provides.
+//!
+//! The Humility commands are consumers of this interface, which is not
+//! yet stable.
+//!
+
 pub mod arch;
+pub mod coalesce;
 pub mod core;
+pub mod hiffy;
 pub mod hubris;
+pub mod interval;
+pub mod json;
+pub mod mock;
+pub mod net;
+pub mod svd;
+pub mod timebase;
 
 #[macro_use]
 extern crate num_derive;
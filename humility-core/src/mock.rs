@@ -0,0 +1,528 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//!
+//! A scriptable implementation of [`Core`] that doesn't require hardware.
+//! A [`MockCore`] is backed by canned memory images (either specified
+//! explicitly or loaded from a Hubris dump), can have hooks that emulate
+//! target behavior in response to writes, can have faults injected into
+//! its operations, and keeps a log of every access made to it.  This allows
+//! commands to be exercised by tests as they would be against a live
+//! target.
+//!
+
+use anyhow::{anyhow, bail, Result};
+use goblin::elf::Elf;
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
+use std::fs;
+use std::ops::Range;
+
+use crate::arch::ARMRegister;
+use crate::core::Core;
+use crate::hubris::HubrisArchive;
+
+///
+/// The memory of a [`MockCore`], consisting of disjoint regions.
+///
+#[derive(Debug, Default)]
+pub struct MockMemory {
+    regions: BTreeMap<u32, Vec<u8>>,
+}
+
+#[rustfmt::skip::macros(bail)]
+impl MockMemory {
+    fn locate(&self, addr: u32, len: usize) -> Option<(u32, usize)> {
+        let (&base, contents) = self.regions.range(..=addr).next_back()?;
+        let offs = (addr - base) as usize;
+
+        if offs + len <= contents.len() {
+            Some((base, offs))
+        } else {
+            None
+        }
+    }
+
+    /// Adds a region at the specified base address with the specified
+    /// contents; it is an error for regions to overlap.
+    pub fn add(&mut self, base: u32, contents: Vec<u8>) -> Result<()> {
+        let end = base as u64 + contents.len() as u64;
+
+        for (&b, c) in self.regions.iter() {
+            if (b as u64) < end && (base as u64) < b as u64 + c.len() as u64 {
+                bail!("region at 0x{:x} overlaps region at 0x{:x}", base, b);
+            }
+        }
+
+        self.regions.insert(base, contents);
+        Ok(())
+    }
+
+    /// Assures that the specified range is backed by memory, adding a
+    /// zero-filled region if it is not.
+    pub fn ensure(&mut self, base: u32, size: usize) -> Result<()> {
+        if self.locate(base, size).is_none() {
+            self.add(base, vec![0; size])?;
+        }
+
+        Ok(())
+    }
+
+    pub fn read(&self, addr: u32, data: &mut [u8]) -> Result<()> {
+        match self.locate(addr, data.len()) {
+            Some((base, offs)) => {
+                let contents = &self.regions[&base];
+                data.copy_from_slice(&contents[offs..offs + data.len()]);
+                Ok(())
+            }
+            None => {
+                bail!("read of {} bytes from invalid address: 0x{:x}",
+                    data.len(), addr);
+            }
+        }
+    }
+
+    pub fn write(&mut self, addr: u32, data: &[u8]) -> Result<()> {
+        match self.locate(addr, data.len()) {
+            Some((base, offs)) => {
+                let contents = self.regions.get_mut(&base).unwrap();
+                contents[offs..offs + data.len()].copy_from_slice(data);
+                Ok(())
+            }
+            None => {
+                bail!("write of {} bytes to invalid address: 0x{:x}",
+                    data.len(), addr);
+            }
+        }
+    }
+
+    pub fn read_word_32(&self, addr: u32) -> Result<u32> {
+        let mut buf = [0u8; 4];
+        self.read(addr, &mut buf)?;
+        Ok(u32::from_le_bytes(buf))
+    }
+
+    pub fn write_word_32(&mut self, addr: u32, val: u32) -> Result<()> {
+        self.write(addr, &val.to_le_bytes())
+    }
+}
+
+///
+/// The class of operation to which a [`MockFault`] applies.
+///
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum MockOp {
+    Read,
+    Write,
+    Register,
+    Halt,
+    Run,
+    Port,
+}
+
+///
+/// A fault to be injected into a [`MockCore`].  The fault applies to
+/// operations of the specified class (and, for memory operations, that
+/// touch the specified range, if any); the first `skip` such operations
+/// succeed, after which the next `count` operations fail.
+///
+#[derive(Clone, Debug)]
+pub struct MockFault {
+    pub op: MockOp,
+    pub range: Option<Range<u32>>,
+    pub skip: usize,
+    pub count: usize,
+}
+
+///
+/// A record of an access to a [`MockCore`].
+///
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum MockAccess {
+    Read(u32, usize),
+    Write(u32, Vec<u8>),
+    ReadReg(ARMRegister),
+    WriteReg(ARMRegister, u32),
+    Halt,
+    Run,
+    Step,
+    ReadDp(u8),
+    WriteDp(u8, u32),
+    ReadAp(u8, u8),
+    WriteAp(u8, u8, u32),
+}
+
+///
+/// A hook, called with the core's memory when the core is run after a
+/// write to the hooked address.
+///
+pub type MockHook = Box<dyn FnMut(&mut MockMemory) -> Result<()>>;
+
+pub struct MockCore {
+    pub memory: MockMemory,
+    registers: HashMap<ARMRegister, u32>,
+    ports: HashMap<(Option<u8>, u8), u32>,
+    halted: bool,
+    hooks: Vec<(u32, MockHook)>,
+    pending: BTreeSet<u32>,
+    faults: Vec<MockFault>,
+    swv: VecDeque<Vec<u8>>,
+    log: Vec<MockAccess>,
+}
+
+impl Default for MockCore {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[rustfmt::skip::macros(bail)]
+impl MockCore {
+    pub fn new() -> Self {
+        Self {
+            memory: MockMemory::default(),
+            registers: HashMap::new(),
+            ports: HashMap::new(),
+            halted: false,
+            hooks: vec![],
+            pending: BTreeSet::new(),
+            faults: vec![],
+            swv: VecDeque::new(),
+            log: vec![],
+        }
+    }
+
+    ///
+    /// Loads the memory and registers of the specified Hubris dump, which
+    /// must have been loaded into the specified archive.
+    ///
+    pub fn load_dump(
+        &mut self,
+        dump: &str,
+        hubris: &HubrisArchive,
+    ) -> Result<()> {
+        let contents = fs::read(dump)?;
+
+        let elf = Elf::parse(&contents).map_err(|e| {
+            anyhow!("failed to parse {} as an ELF file: {}", dump, e)
+        })?;
+
+        for phdr in elf.program_headers.iter() {
+            if phdr.p_type != goblin::elf::program_header::PT_LOAD {
+                continue;
+            }
+
+            let offs = phdr.p_offset as usize;
+            let mut region =
+                contents[offs..offs + phdr.p_filesz as usize].to_vec();
+            region.resize(phdr.p_memsz as usize, 0);
+
+            self.memory.add(phdr.p_vaddr as u32, region)?;
+        }
+
+        self.registers.extend(hubris.dump_registers());
+
+        Ok(())
+    }
+
+    pub fn set_register(&mut self, reg: ARMRegister, val: u32) {
+        self.registers.insert(reg, val);
+    }
+
+    /// Installs a hook to be called when the core is run after a write to
+    /// the specified address.  (If the core is already running, the hook is
+    /// called immediately upon the write.)
+    pub fn on_write(&mut self, addr: u32, hook: MockHook) {
+        self.hooks.push((addr, hook));
+    }
+
+    pub fn inject(&mut self, fault: MockFault) {
+        self.faults.push(fault);
+    }
+
+    /// Queues data to be returned by [`Core::read_swv`].
+    pub fn push_swv(&mut self, data: Vec<u8>) {
+        self.swv.push_back(data);
+    }
+
+    /// Returns the log of every access made to the core.
+    pub fn log(&self) -> &[MockAccess] {
+        &self.log
+    }
+
+    pub fn clear_log(&mut self) {
+        self.log.clear();
+    }
+
+    fn check(&mut self, op: MockOp, range: Option<Range<u64>>) -> Result<()> {
+        for fault in self.faults.iter_mut() {
+            if fault.op != op {
+                continue;
+            }
+
+            if let (Some(f), Some(r)) = (&fault.range, &range) {
+                if f.start as u64 >= r.end || r.start >= f.end as u64 {
+                    continue;
+                }
+            }
+
+            if fault.skip > 0 {
+                fault.skip -= 1;
+                continue;
+            }
+
+            if fault.count > 0 {
+                fault.count -= 1;
+
+                match range {
+                    Some(r) => {
+                        bail!("injected {:?} fault at 0x{:x}", op, r.start);
+                    }
+                    None => bail!("injected {:?} fault", op),
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    //
+    // Returns the range of addresses touched by an access, failing if it
+    // would extend beyond the end of the address space.  (The range is
+    // 64-bit so that an access may end at the very top of memory.)
+    //
+    fn range(addr: u32, len: usize) -> Result<Range<u64>> {
+        let end = addr as u64 + len as u64;
+
+        if end > u32::MAX as u64 + 1 {
+            bail!("access of {} bytes at 0x{:x} overflows", len, addr);
+        }
+
+        Ok(addr as u64..end)
+    }
+
+    fn fire(&mut self) -> Result<()> {
+        let pending = std::mem::take(&mut self.pending);
+
+        for (addr, hook) in self.hooks.iter_mut() {
+            if pending.contains(addr) {
+                hook(&mut self.memory)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[rustfmt::skip::macros(bail)]
+impl Core for MockCore {
+    fn info(&self) -> (String, Option<String>) {
+        ("mock".to_string(), None)
+    }
+
+    fn read_word_32(&mut self, addr: u32) -> Result<u32> {
+        let mut buf = [0u8; 4];
+        self.read_8(addr, &mut buf)?;
+        Ok(u32::from_le_bytes(buf))
+    }
+
+    fn read_8(&mut self, addr: u32, data: &mut [u8]) -> Result<()> {
+        self.log.push(MockAccess::Read(addr, data.len()));
+        let range = Self::range(addr, data.len())?;
+        self.check(MockOp::Read, Some(range))?;
+        self.memory.read(addr, data)
+    }
+
+    fn read_reg(&mut self, reg: ARMRegister) -> Result<u32> {
+        self.log.push(MockAccess::ReadReg(reg));
+        self.check(MockOp::Register, None)?;
+
+        match self.registers.get(&reg) {
+            Some(val) => Ok(*val),
+            None => bail!("register {} not set on mock core", reg),
+        }
+    }
+
+    fn write_reg(&mut self, reg: ARMRegister, value: u32) -> Result<()> {
+        self.log.push(MockAccess::WriteReg(reg, value));
+        self.check(MockOp::Register, None)?;
+        self.registers.insert(reg, value);
+        Ok(())
+    }
+
+    fn write_word_32(&mut self, addr: u32, data: u32) -> Result<()> {
+        self.write_8(addr, &data.to_le_bytes())
+    }
+
+    fn write_8(&mut self, addr: u32, data: &[u8]) -> Result<()> {
+        self.log.push(MockAccess::Write(addr, data.to_vec()));
+        let range = Self::range(addr, data.len())?;
+
+        self.check(MockOp::Write, Some(range.clone()))?;
+        self.memory.write(addr, data)?;
+
+        for (hooked, _) in &self.hooks {
+            if range.contains(&(*hooked as u64)) {
+                self.pending.insert(*hooked);
+            }
+        }
+
+        if !self.halted {
+            self.fire()?;
+        }
+
+        Ok(())
+    }
+
+    fn halt(&mut self) -> Result<()> {
+        self.log.push(MockAccess::Halt);
+        self.check(MockOp::Halt, None)?;
+        self.halted = true;
+        Ok(())
+    }
+
+    fn run(&mut self) -> Result<()> {
+        self.log.push(MockAccess::Run);
+        self.check(MockOp::Run, None)?;
+        self.halted = false;
+        self.fire()
+    }
+
+    fn step(&mut self) -> Result<()> {
+        self.log.push(MockAccess::Step);
+        Ok(())
+    }
+
+    fn init_swv(&mut self, _traceclk: u32, _baud: u32) -> Result<()> {
+        Ok(())
+    }
+
+    fn read_swv(&mut self) -> Result<Vec<u8>> {
+        Ok(self.swv.pop_front().unwrap_or_default())
+    }
+
+    //
+    // Debug and access port registers are simply stored; a register that
+    // has never been written reads as zero.
+    //
+    fn read_dp(&mut self, addr: u8) -> Result<u32> {
+        self.log.push(MockAccess::ReadDp(addr));
+        self.check(MockOp::Port, None)?;
+        Ok(self.ports.get(&(None, addr)).copied().unwrap_or(0))
+    }
+
+    fn write_dp(&mut self, addr: u8, value: u32) -> Result<()> {
+        self.log.push(MockAccess::WriteDp(addr, value));
+        self.check(MockOp::Port, None)?;
+        self.ports.insert((None, addr), value);
+        Ok(())
+    }
+
+    fn read_ap(&mut self, ap: u8, addr: u8) -> Result<u32> {
+        self.log.push(MockAccess::ReadAp(ap, addr));
+        self.check(MockOp::Port, None)?;
+        Ok(self.ports.get(&(Some(ap), addr)).copied().unwrap_or(0))
+    }
+
+    fn write_ap(&mut self, ap: u8, addr: u8, value: u32) -> Result<()> {
+        self.log.push(MockAccess::WriteAp(ap, addr, value));
+        self.check(MockOp::Port, None)?;
+        self.ports.insert((Some(ap), addr), value);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    fn core() -> MockCore {
+        let mut core = MockCore::new();
+        core.memory.add(0x2000_0000, (0..=255).collect()).unwrap();
+        core
+    }
+
+    #[test]
+    fn read_write() {
+        let mut core = core();
+
+        assert_eq!(core.read_word_32(0x2000_0004).unwrap(), 0x0706_0504);
+        core.write_word_32(0x2000_0004, 0xdead_beef).unwrap();
+        assert_eq!(core.read_word_32(0x2000_0004).unwrap(), 0xdead_beef);
+
+        assert_eq!(
+            core.log(),
+            &[
+                MockAccess::Read(0x2000_0004, 4),
+                MockAccess::Write(0x2000_0004, vec![0xef, 0xbe, 0xad, 0xde]),
+                MockAccess::Read(0x2000_0004, 4),
+            ]
+        );
+    }
+
+    #[test]
+    fn invalid() {
+        let mut core = core();
+        let mut buf = [0u8; 8];
+
+        assert!(core.read_8(0x2000_00fc, &mut buf).is_err());
+        assert!(core.read_8(0x1fff_fffc, &mut buf).is_err());
+        assert!(core.read_8(0xffff_fffc, &mut buf).is_err());
+        assert!(core.write_8(0xffff_fffc, &buf).is_err());
+    }
+
+    #[test]
+    fn top() {
+        let mut core = MockCore::new();
+        core.memory.add(0xffff_fff0, vec![0xa5; 16]).unwrap();
+
+        let mut buf = [0u8; 16];
+        core.read_8(0xffff_fff0, &mut buf).unwrap();
+        assert_eq!(buf, [0xa5; 16]);
+
+        core.write_word_32(0xffff_fffc, 0).unwrap();
+        assert_eq!(core.read_word_32(0xffff_fffc).unwrap(), 0);
+    }
+
+    #[test]
+    fn faults() {
+        let mut core = core();
+
+        core.inject(MockFault {
+            op: MockOp::Read,
+            range: Some(0x2000_0010..0x2000_0020),
+            skip: 1,
+            count: 1,
+        });
+
+        assert!(core.read_word_32(0x2000_0000).is_ok());
+        assert!(core.read_word_32(0x2000_0010).is_ok());
+        assert!(core.read_word_32(0x2000_0010).is_err());
+        assert!(core.read_word_32(0x2000_0010).is_ok());
+    }
+
+    #[test]
+    fn hooks() {
+        let mut core = core();
+        let fired = Rc::new(Cell::new(0));
+        let f = fired.clone();
+
+        core.on_write(
+            0x2000_0008,
+            Box::new(move |memory| {
+                f.set(f.get() + 1);
+                memory.write_word_32(0x2000_000c, 1)
+            }),
+        );
+
+        core.halt().unwrap();
+        core.write_word_32(0x2000_0008, 0).unwrap();
+        assert_eq!(fired.get(), 0);
+
+        core.run().unwrap();
+        assert_eq!(fired.get(), 1);
+        assert_eq!(core.read_word_32(0x2000_000c).unwrap(), 1);
+    }
+}
@@ -0,0 +1,687 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//!
+//! Remote attachment to a core over the network.  A daemon (`humility
+//! daemon`) owns a core and serves the operations of the [`Core`] trait via
+//! [`serve`]; clients attach to it with a [`NetCore`] (as selected with
+//! `-p net:host:port`).  The protocol is a simple one:  each message is a
+//! little-endian 32-bit length followed by a postcard-encoded
+//! [`NetRequest`] (from the client) or [`NetResponse`] (from the daemon),
+//! and every request receives exactly one response.  A connection must
+//! begin with a [`NetRequest::Hello`].
+//!
+//! The daemon serves one client at a time:  a client has exclusive use of
+//! the core for the duration of its connection (as a command may need a
+//! sequence of operations -- halting the target, reading memory and
+//! running it -- not to be interleaved with those of another).  Other
+//! clients wait for the connection to be closed.  If a client disconnects
+//! having left breakpoints or watchpoints set, the daemon clears them; if
+//! it disconnects with the target halted, the daemon runs it.  So that a
+//! peer that goes silent can't keep the core from others, a client that
+//! doesn't say hello within a few seconds of connecting (or that is
+//! subsequently idle for ten minutes) is disconnected.
+//!
+
+use crate::arch::ARMRegister;
+use crate::core::{Core, CoreOps, CoreWatch};
+use anyhow::{anyhow, bail, Context, Result};
+use num_traits::{FromPrimitive, ToPrimitive};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::convert::TryInto;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::path::Path;
+use std::time::Duration;
+
+/// The version of the protocol, which must match between client and daemon
+pub const NET_VERSION: u32 = 3;
+
+/// The port on which the daemon listens by default
+pub const NET_DEFAULT_PORT: u16 = 9437;
+
+/// The largest message that we will accept (large enough for an image)
+const NET_MAX_MESSAGE: usize = 16 * 1024 * 1024;
+
+/// How long the daemon waits for a connecting client's hello
+const NET_HELLO_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long the daemon waits on an idle client before disconnecting it
+const NET_IDLE_TIMEOUT: Duration = Duration::from_secs(600);
+
+/// How often [`serve_until`] checks for a client (or to be done)
+const NET_ACCEPT_POLL: Duration = Duration::from_millis(50);
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum NetRequest {
+    Hello(u32),
+    ReadWord32(u32),
+    ReadWord64(u32),
+    Read8(u32, u32),
+    ReadReg(u16),
+    WriteReg(u16, u32),
+    WriteWord32(u32, u32),
+    Write8(u32, Vec<u8>),
+    InitSwv(u32, u32),
+    ReadSwv,
+    Halt,
+    Run,
+    Step,
+    ReadDp(u8),
+    WriteDp(u8, u32),
+    ReadAp(u8, u8),
+    WriteAp(u8, u8, u32),
+    Load(Vec<u8>),
+    Reset,
+    SetBreakpoint(u32),
+    ClearBreakpoint(u32),
+    SetWatchpoint(u32, CoreWatch),
+    ClearWatchpoint(u32),
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum NetResponse {
+    Hello { version: u32, name: String, serial: Option<String>, ops: u32 },
+    Ok,
+    Word32(u32),
+    Word64(u64),
+    Bytes(Vec<u8>),
+    Err(String),
+}
+
+fn write_message<T: Serialize>(stream: &mut TcpStream, msg: &T) -> Result<()> {
+    let buf = postcard::to_stdvec(msg)?;
+
+    stream.write_all(&(buf.len() as u32).to_le_bytes())?;
+    stream.write_all(&buf)?;
+
+    Ok(())
+}
+
+//
+// Reads a message, returning None if the other end has closed the
+// connection before sending one.
+//
+fn read_message<T: DeserializeOwned>(
+    stream: &mut TcpStream,
+) -> Result<Option<T>> {
+    let mut len = [0u8; 4];
+
+    match stream.read_exact(&mut len) {
+        Ok(_) => {}
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+
+    let len = u32::from_le_bytes(len) as usize;
+
+    if len > NET_MAX_MESSAGE {
+        bail!(
+            "message of {} bytes exceeds maximum of {}",
+            len,
+            NET_MAX_MESSAGE
+        );
+    }
+
+    let mut buf = vec![0u8; len];
+    stream.read_exact(&mut buf)?;
+
+    Ok(Some(postcard::from_bytes(&buf)?))
+}
+
+///
+/// A core attached via a `humility daemon` on another machine.
+///
+pub struct NetCore {
+    stream: TcpStream,
+    host: String,
+    name: String,
+    serial: Option<String>,
+    ops: CoreOps,
+}
+
+impl NetCore {
+    pub fn connect(host: &str) -> Result<NetCore> {
+        let host = if host.contains(':') {
+            host.to_string()
+        } else {
+            format!("{}:{}", host, NET_DEFAULT_PORT)
+        };
+
+        let addr = host
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| anyhow!("bad address for daemon: {}", host))?;
+
+        let stream = TcpStream::connect_timeout(&addr, Duration::from_secs(5))
+            .map_err(|e| {
+                anyhow!("can't connect to daemon on {}: {}", host, e)
+            })?;
+
+        stream.set_nodelay(true)?;
+
+        let mut core = Self {
+            stream,
+            host,
+            name: String::new(),
+            serial: None,
+            ops: CoreOps::NONE,
+        };
+
+        //
+        // If the daemon is serving another client, our hello will not be
+        // answered until that client is done.
+        //
+        debug!("connected to {}; waiting for daemon", core.host);
+
+        match core.request(NetRequest::Hello(NET_VERSION))? {
+            NetResponse::Hello { version, name, serial, ops } => {
+                if version != NET_VERSION {
+                    bail!(
+                        "daemon on {} speaks version {}, expected {}",
+                        core.host,
+                        version,
+                        NET_VERSION
+                    );
+                }
+
+                core.name = name;
+                core.serial = serial;
+                core.ops = CoreOps::from_bits(ops);
+            }
+            r => bail!("unexpected response to hello: {:?}", r),
+        }
+
+        Ok(core)
+    }
+
+    pub fn host(&self) -> &str {
+        &self.host
+    }
+
+    fn request(&mut self, request: NetRequest) -> Result<NetResponse> {
+        write_message(&mut self.stream, &request)?;
+
+        match read_message(&mut self.stream)? {
+            Some(NetResponse::Err(err)) => Err(anyhow!("{}", err)),
+            Some(response) => Ok(response),
+            None => bail!("daemon on {} closed connection", self.host),
+        }
+    }
+
+    fn request_ok(&mut self, request: NetRequest) -> Result<()> {
+        match self.request(request)? {
+            NetResponse::Ok => Ok(()),
+            r => bail!("unexpected response: {:?}", r),
+        }
+    }
+
+    fn request_word(&mut self, request: NetRequest) -> Result<u32> {
+        match self.request(request)? {
+            NetResponse::Word32(val) => Ok(val),
+            r => bail!("unexpected response: {:?}", r),
+        }
+    }
+
+    fn request_bytes(&mut self, request: NetRequest) -> Result<Vec<u8>> {
+        match self.request(request)? {
+            NetResponse::Bytes(bytes) => Ok(bytes),
+            r => bail!("unexpected response: {:?}", r),
+        }
+    }
+}
+
+impl Core for NetCore {
+    fn info(&self) -> (String, Option<String>) {
+        (format!("{} via {}", self.name, self.host), self.serial.clone())
+    }
+
+    fn ops(&self) -> CoreOps {
+        self.ops
+    }
+
+    fn read_word_32(&mut self, addr: u32) -> Result<u32> {
+        self.request_word(NetRequest::ReadWord32(addr))
+    }
+
+    fn read_word_64(&mut self, addr: u32) -> Result<u64> {
+        match self.request(NetRequest::ReadWord64(addr))? {
+            NetResponse::Word64(val) => Ok(val),
+            r => bail!("unexpected response: {:?}", r),
+        }
+    }
+
+    fn read_8(&mut self, addr: u32, data: &mut [u8]) -> Result<()> {
+        let bytes =
+            self.request_bytes(NetRequest::Read8(addr, data.len() as u32))?;
+
+        if bytes.len() != data.len() {
+            bail!("short read: expected {}, found {}", data.len(), bytes.len());
+        }
+
+        data.copy_from_slice(&bytes);
+        Ok(())
+    }
+
+    fn read_reg(&mut self, reg: ARMRegister) -> Result<u32> {
+        let reg = ARMRegister::to_u16(&reg).unwrap();
+        self.request_word(NetRequest::ReadReg(reg))
+    }
+
+    fn write_reg(&mut self, reg: ARMRegister, value: u32) -> Result<()> {
+        let reg = ARMRegister::to_u16(&reg).unwrap();
+        self.request_ok(NetRequest::WriteReg(reg, value))
+    }
+
+    fn init_swv(&mut self, traceclk: u32, baud: u32) -> Result<()> {
+        self.request_ok(NetRequest::InitSwv(traceclk, baud))
+    }
+
+    fn read_swv(&mut self) -> Result<Vec<u8>> {
+        self.request_bytes(NetRequest::ReadSwv)
+    }
+
+    fn write_word_32(&mut self, addr: u32, data: u32) -> Result<()> {
+        self.request_ok(NetRequest::WriteWord32(addr, data))
+    }
+
+    fn write_8(&mut self, addr: u32, data: &[u8]) -> Result<()> {
+        self.request_ok(NetRequest::Write8(addr, data.to_vec()))
+    }
+
+    fn halt(&mut self) -> Result<()> {
+        self.request_ok(NetRequest::Halt)
+    }
+
+    fn run(&mut self) -> Result<()> {
+        self.request_ok(NetRequest::Run)
+    }
+
+    fn step(&mut self) -> Result<()> {
+        self.request_ok(NetRequest::Step)
+    }
+
+    fn read_dp(&mut self, addr: u8) -> Result<u32> {
+        self.request_word(NetRequest::ReadDp(addr))
+    }
+
+    fn write_dp(&mut self, addr: u8, value: u32) -> Result<()> {
+        self.request_ok(NetRequest::WriteDp(addr, value))
+    }
+
+    fn read_ap(&mut self, ap: u8, addr: u8) -> Result<u32> {
+        self.request_word(NetRequest::ReadAp(ap, addr))
+    }
+
+    fn write_ap(&mut self, ap: u8, addr: u8, value: u32) -> Result<()> {
+        self.request_ok(NetRequest::WriteAp(ap, addr, value))
+    }
+
+    fn load(&mut self, path: &Path) -> Result<()> {
+        let contents = fs::read(path)
+            .with_context(|| format!("failed to read {}", path.display()))?;
+        self.request_ok(NetRequest::Load(contents))
+    }
+
+    fn reset(&mut self) -> Result<()> {
+        self.request_ok(NetRequest::Reset)
+    }
+
+    fn set_breakpoint(&mut self, addr: u32) -> Result<()> {
+        self.request_ok(NetRequest::SetBreakpoint(addr))
+    }
+
+    fn clear_breakpoint(&mut self, addr: u32) -> Result<()> {
+        self.request_ok(NetRequest::ClearBreakpoint(addr))
+    }
+
+    fn set_watchpoint(&mut self, addr: u32, watch: CoreWatch) -> Result<()> {
+        self.request_ok(NetRequest::SetWatchpoint(addr, watch))
+    }
+
+    fn clear_watchpoint(&mut self, addr: u32) -> Result<()> {
+        self.request_ok(NetRequest::ClearWatchpoint(addr))
+    }
+}
+
+fn register(reg: u16) -> Result<ARMRegister> {
+    ARMRegister::from_u16(reg).ok_or_else(|| anyhow!("bad register {}", reg))
+}
+
+//
+// Performs a single request on behalf of a client.
+//
+fn serve_request(
+    core: &mut dyn Core,
+    request: NetRequest,
+) -> Result<NetResponse> {
+    Ok(match request {
+        NetRequest::Hello(_) => bail!("unexpected hello"),
+        NetRequest::ReadWord32(addr) => {
+            NetResponse::Word32(core.read_word_32(addr)?)
+        }
+        NetRequest::ReadWord64(addr) => {
+            NetResponse::Word64(core.read_word_64(addr)?)
+        }
+        NetRequest::Read8(addr, len) => {
+            let len: usize = len.try_into()?;
+
+            if len > NET_MAX_MESSAGE {
+                bail!(
+                    "read of {} bytes exceeds maximum of {}",
+                    len,
+                    NET_MAX_MESSAGE
+                );
+            }
+
+            let mut buf = vec![0u8; len];
+            core.read_8(addr, &mut buf)?;
+            NetResponse::Bytes(buf)
+        }
+        NetRequest::ReadReg(reg) => {
+            NetResponse::Word32(core.read_reg(register(reg)?)?)
+        }
+        NetRequest::WriteReg(reg, value) => {
+            core.write_reg(register(reg)?, value)?;
+            NetResponse::Ok
+        }
+        NetRequest::WriteWord32(addr, data) => {
+            core.write_word_32(addr, data)?;
+            NetResponse::Ok
+        }
+        NetRequest::Write8(addr, data) => {
+            core.write_8(addr, &data)?;
+            NetResponse::Ok
+        }
+        NetRequest::InitSwv(traceclk, baud) => {
+            core.init_swv(traceclk, baud)?;
+            NetResponse::Ok
+        }
+        NetRequest::ReadSwv => NetResponse::Bytes(core.read_swv()?),
+        NetRequest::Halt => {
+            core.halt()?;
+            NetResponse::Ok
+        }
+        NetRequest::Run => {
+            core.run()?;
+            NetResponse::Ok
+        }
+        NetRequest::Step => {
+            core.step()?;
+            NetResponse::Ok
+        }
+        NetRequest::ReadDp(addr) => NetResponse::Word32(core.read_dp(addr)?),
+        NetRequest::WriteDp(addr, value) => {
+            core.write_dp(addr, value)?;
+            NetResponse::Ok
+        }
+        NetRequest::ReadAp(ap, addr) => {
+            NetResponse::Word32(core.read_ap(ap, addr)?)
+        }
+        NetRequest::WriteAp(ap, addr, value) => {
+            core.write_ap(ap, addr, value)?;
+            NetResponse::Ok
+        }
+        NetRequest::Load(contents) => {
+            //
+            // Our core loads from a file, so we write the image out to one.
+            //
+            let path = std::env::temp_dir()
+                .join(format!("humility-daemon-{}.elf", std::process::id()));
+
+            fs::write(&path, contents)?;
+            let rval = core.load(&path);
+            fs::remove_file(&path)?;
+            rval?;
+
+            NetResponse::Ok
+        }
+        NetRequest::Reset => {
+            core.reset()?;
+            NetResponse::Ok
+        }
+        NetRequest::SetBreakpoint(addr) => {
+            core.set_breakpoint(addr)?;
+            NetResponse::Ok
+        }
+        NetRequest::ClearBreakpoint(addr) => {
+            core.clear_breakpoint(addr)?;
+            NetResponse::Ok
+        }
+        NetRequest::SetWatchpoint(addr, watch) => {
+            core.set_watchpoint(addr, watch)?;
+            NetResponse::Ok
+        }
+        NetRequest::ClearWatchpoint(addr) => {
+            core.clear_watchpoint(addr)?;
+            NetResponse::Ok
+        }
+    })
+}
+
+//
+// Serves a single client until it disconnects.
+//
+fn serve_client(core: &mut dyn Core, stream: &mut TcpStream) -> Result<()> {
+    //
+    // A peer that connects but never says hello (or never reads our
+    // responses) must not keep the core from other clients.
+    //
+    stream.set_read_timeout(Some(NET_HELLO_TIMEOUT))?;
+    stream.set_write_timeout(Some(NET_HELLO_TIMEOUT))?;
+
+    match read_message(stream).context("failed to read hello")? {
+        Some(NetRequest::Hello(version)) => {
+            let (name, serial) = core.info();
+
+            write_message(
+                stream,
+                &NetResponse::Hello {
+                    version: NET_VERSION,
+                    name,
+                    serial,
+                    ops: core.ops().bits(),
+                },
+            )?;
+
+            if version != NET_VERSION {
+                bail!("client speaks version {}", version);
+            }
+        }
+        Some(request) => bail!("expected hello, found {:?}", request),
+        None => return Ok(()),
+    }
+
+    stream.set_read_timeout(Some(NET_IDLE_TIMEOUT))?;
+    stream.set_write_timeout(Some(NET_IDLE_TIMEOUT))?;
+
+    let mut session = NetSession::default();
+
+    let rval = loop {
+        let request = match read_message(stream) {
+            Ok(Some(request)) => request,
+            Ok(None) => break Ok(()),
+            Err(err) => break Err(err),
+        };
+
+        let update = session.update(&request);
+
+        let response = match serve_request(core, request) {
+            Ok(response) => {
+                session.apply(update);
+                response
+            }
+            Err(err) => NetResponse::Err(format!("{:?}", err)),
+        };
+
+        if let Err(err) = write_message(stream, &response) {
+            break Err(err);
+        }
+    };
+
+    session.cleanup(core)?;
+
+    rval
+}
+
+//
+// The state that a client has left on the target:  whether it has halted
+// it, and the breakpoints and watchpoints that it has set.
+//
+#[derive(Default)]
+struct NetSession {
+    halted: bool,
+    stopped: bool,
+    breakpoints: Vec<u32>,
+    watchpoints: Vec<u32>,
+}
+
+//
+// How a request changes the state of a session, should it succeed.
+//
+enum NetSessionUpdate {
+    None,
+    Halted(bool),
+    SetBreakpoint(u32),
+    ClearBreakpoint(u32),
+    SetWatchpoint(u32),
+    ClearWatchpoint(u32),
+}
+
+impl NetSession {
+    fn update(&self, request: &NetRequest) -> NetSessionUpdate {
+        match request {
+            NetRequest::Halt => NetSessionUpdate::Halted(true),
+            NetRequest::Run | NetRequest::Reset => {
+                NetSessionUpdate::Halted(false)
+            }
+            NetRequest::SetBreakpoint(addr) => {
+                NetSessionUpdate::SetBreakpoint(*addr)
+            }
+            NetRequest::ClearBreakpoint(addr) => {
+                NetSessionUpdate::ClearBreakpoint(*addr)
+            }
+            NetRequest::SetWatchpoint(addr, _) => {
+                NetSessionUpdate::SetWatchpoint(*addr)
+            }
+            NetRequest::ClearWatchpoint(addr) => {
+                NetSessionUpdate::ClearWatchpoint(*addr)
+            }
+            _ => NetSessionUpdate::None,
+        }
+    }
+
+    fn apply(&mut self, update: NetSessionUpdate) {
+        match update {
+            NetSessionUpdate::None => {}
+            NetSessionUpdate::Halted(halted) => self.halted = halted,
+            NetSessionUpdate::SetBreakpoint(addr) => {
+                self.breakpoints.push(addr);
+                self.stopped = true;
+            }
+            NetSessionUpdate::ClearBreakpoint(addr) => {
+                self.breakpoints.retain(|&bp| bp != addr);
+            }
+            NetSessionUpdate::SetWatchpoint(addr) => {
+                self.watchpoints.push(addr);
+                self.stopped = true;
+            }
+            NetSessionUpdate::ClearWatchpoint(addr) => {
+                self.watchpoints.retain(|&wp| wp != addr);
+            }
+        }
+    }
+
+    //
+    // Called when the client disconnects to clear any breakpoints and
+    // watchpoints that it left set, and to run the target if it left it
+    // halted.  A client that has set a breakpoint or watchpoint may have
+    // left the target halted on one without ever asking that it halt, so
+    // we run the target in that case too.
+    //
+    fn cleanup(&mut self, core: &mut dyn Core) -> Result<()> {
+        for addr in self.breakpoints.drain(..) {
+            info!("clearing breakpoint left on 0x{:x}", addr);
+
+            if let Err(err) = core.clear_breakpoint(addr) {
+                warn!("failed to clear breakpoint on 0x{:x}: {}", addr, err);
+            }
+        }
+
+        for addr in self.watchpoints.drain(..) {
+            info!("clearing watchpoint left on 0x{:x}", addr);
+
+            if let Err(err) = core.clear_watchpoint(addr) {
+                warn!("failed to clear watchpoint on 0x{:x}: {}", addr, err);
+            }
+        }
+
+        if self.halted || self.stopped {
+            info!("client may have left target halted; running it");
+            core.run()?;
+        }
+
+        Ok(())
+    }
+}
+
+///
+/// Serves the specified core to clients connecting to the specified
+/// listener, one client at a time.  This does not return unless accepting
+/// a connection fails; errors with any one client are logged, and do not
+/// prevent others from being served.
+///
+pub fn serve(core: &mut dyn Core, listener: &TcpListener) -> Result<()> {
+    loop {
+        let (stream, peer) = listener.accept()?;
+        serve_peer(core, stream, peer)?;
+    }
+}
+
+///
+/// Like [`serve`], but returns once the specified function returns true
+/// (which is checked between clients, and periodically while waiting for
+/// one).
+///
+pub fn serve_until(
+    core: &mut dyn Core,
+    listener: &TcpListener,
+    mut done: impl FnMut() -> Result<bool>,
+) -> Result<()> {
+    listener.set_nonblocking(true)?;
+
+    while !done()? {
+        match listener.accept() {
+            Ok((stream, peer)) => {
+                stream.set_nonblocking(false)?;
+                serve_peer(core, stream, peer)?;
+            }
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+                std::thread::sleep(NET_ACCEPT_POLL);
+            }
+            Err(err) => return Err(err.into()),
+        }
+    }
+
+    Ok(())
+}
+
+fn serve_peer(
+    core: &mut dyn Core,
+    mut stream: TcpStream,
+    peer: std::net::SocketAddr,
+) -> Result<()> {
+    stream.set_nodelay(true)?;
+
+    info!("serving {}", peer);
+
+    match serve_client(core, &mut stream) {
+        Ok(()) => info!("{} disconnected", peer),
+        Err(err) => warn!("{}: {:?}", peer, err),
+    }
+
+    Ok(())
+}
@@ -0,0 +1,507 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//!
+//! Support for CMSIS-SVD files, which describe the peripherals of a part
+//! (and the registers and fields within them).  We only pull out what we
+//! need for symbolic display (and for safely writing fields):  peripheral
+//! base addresses, register offsets and sizes, field positions, enumerated
+//! values and access.  Register arrays (`dim`) are expanded, clusters are
+//! flattened (with their names prefixed onto the names of their registers),
+//! and derived peripherals inherit the registers of the peripheral that
+//! they derive from.
+//!
+
+use anyhow::{anyhow, bail, Context, Result};
+use roxmltree::Node;
+use std::collections::HashMap;
+use std::fs;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum SvdAccess {
+    ReadOnly,
+    WriteOnly,
+    ReadWrite,
+    WriteOnce,
+    ReadWriteOnce,
+}
+
+impl SvdAccess {
+    fn parse(s: &str) -> Result<Self> {
+        Ok(match s {
+            "read-only" => SvdAccess::ReadOnly,
+            "write-only" => SvdAccess::WriteOnly,
+            "read-write" => SvdAccess::ReadWrite,
+            "writeOnce" => SvdAccess::WriteOnce,
+            "read-writeOnce" => SvdAccess::ReadWriteOnce,
+            _ => bail!("invalid access \"{}\"", s),
+        })
+    }
+
+    pub fn readable(&self) -> bool {
+        !matches!(self, SvdAccess::WriteOnly | SvdAccess::WriteOnce)
+    }
+
+    pub fn writable(&self) -> bool {
+        !matches!(self, SvdAccess::ReadOnly)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct SvdEnumeratedValue {
+    pub name: String,
+    pub description: Option<String>,
+    pub value: u64,
+}
+
+#[derive(Clone, Debug)]
+pub struct SvdField {
+    pub name: String,
+    pub description: Option<String>,
+    pub offset: u32,
+    pub width: u32,
+    pub access: Option<SvdAccess>,
+    pub values: Vec<SvdEnumeratedValue>,
+}
+
+#[derive(Clone, Debug)]
+pub struct SvdRegister {
+    pub name: String,
+    pub description: Option<String>,
+    pub offset: u32,
+    pub size: u32,
+    pub access: Option<SvdAccess>,
+    /// true if reading the register has side-effects (`readAction`)
+    pub read_action: bool,
+    pub fields: Vec<SvdField>,
+}
+
+#[derive(Clone, Debug)]
+pub struct SvdPeripheral {
+    pub name: String,
+    pub description: Option<String>,
+    pub base: u32,
+    pub size: Option<u32>,
+    pub registers: Vec<SvdRegister>,
+}
+
+#[derive(Clone, Debug)]
+pub struct SvdDevice {
+    pub name: String,
+    pub peripherals: Vec<SvdPeripheral>,
+}
+
+impl SvdField {
+    /// Extracts the value of this field from the specified register value.
+    pub fn extract(&self, val: u64) -> u64 {
+        let mask = if self.width >= 64 { !0 } else { (1u64 << self.width) - 1 };
+
+        (val >> self.offset) & mask
+    }
+
+    /// Returns the specified register value with this field replaced by
+    /// the specified field value, failing if it doesn't fit in the field.
+    pub fn insert(&self, val: u64, field: u64) -> Result<u64> {
+        let mask = if self.width >= 64 { !0 } else { (1u64 << self.width) - 1 };
+
+        if field & !mask != 0 {
+            bail!(
+                "value 0x{:x} does not fit in {} ({} bits)",
+                field,
+                self.name,
+                self.width
+            );
+        }
+
+        Ok((val & !(mask << self.offset)) | (field << self.offset))
+    }
+
+    /// Looks up the enumerated value (if any) for the specified field value.
+    pub fn lookup_value(&self, field: u64) -> Option<&SvdEnumeratedValue> {
+        self.values.iter().find(|v| v.value == field)
+    }
+
+    /// Looks up an enumerated value by name (ignoring case).
+    pub fn lookup_value_byname(
+        &self,
+        name: &str,
+    ) -> Option<&SvdEnumeratedValue> {
+        self.values.iter().find(|v| v.name.eq_ignore_ascii_case(name))
+    }
+}
+
+impl SvdRegister {
+    /// Returns the size of the register in bytes.
+    pub fn nbytes(&self) -> usize {
+        ((self.size + 7) / 8) as usize
+    }
+
+    /// Looks up a field by name (ignoring case).
+    pub fn lookup_field(&self, name: &str) -> Option<&SvdField> {
+        self.fields.iter().find(|f| f.name.eq_ignore_ascii_case(name))
+    }
+}
+
+impl SvdPeripheral {
+    /// Looks up a register by name (ignoring case).
+    pub fn lookup_register(&self, name: &str) -> Option<&SvdRegister> {
+        self.registers.iter().find(|r| r.name.eq_ignore_ascii_case(name))
+    }
+}
+
+//
+// SVD integers may be in decimal, hex (0x), or binary (either 0b or, per
+// the spec, a leading '#').  Binary values may contain 'x' to denote a
+// don't-care bit; we treat these as zeroes.
+//
+fn svd_int(s: &str) -> Result<u64> {
+    let s = s.trim();
+    let lower = s.to_lowercase();
+
+    let (digits, radix) = if let Some(hex) = lower.strip_prefix("0x") {
+        (hex.to_string(), 16)
+    } else if let Some(bin) = lower.strip_prefix('#') {
+        (bin.replace('x', "0"), 2)
+    } else if let Some(bin) = lower.strip_prefix("0b") {
+        (bin.replace('x', "0"), 2)
+    } else {
+        (lower.clone(), 10)
+    };
+
+    u64::from_str_radix(&digits, radix)
+        .map_err(|_| anyhow!("invalid SVD integer \"{}\"", s))
+}
+
+fn child<'a, 'input>(
+    node: Node<'a, 'input>,
+    name: &str,
+) -> Option<Node<'a, 'input>> {
+    node.children().find(|n| n.is_element() && n.tag_name().name() == name)
+}
+
+fn children<'a, 'input: 'a>(
+    node: Node<'a, 'input>,
+    name: &'a str,
+) -> impl Iterator<Item = Node<'a, 'input>> + 'a {
+    node.children()
+        .filter(move |n| n.is_element() && n.tag_name().name() == name)
+}
+
+fn text(node: Node, name: &str) -> Option<String> {
+    child(node, name)
+        .and_then(|n| n.text())
+        .map(|t| t.split_whitespace().collect::<Vec<_>>().join(" "))
+}
+
+fn int(node: Node, name: &str) -> Result<Option<u64>> {
+    match child(node, name).and_then(|n| n.text()) {
+        Some(t) => {
+            Ok(Some(svd_int(t).with_context(|| format!("bad <{}>", name))?))
+        }
+        None => Ok(None),
+    }
+}
+
+fn access(node: Node) -> Result<Option<SvdAccess>> {
+    match text(node, "access") {
+        Some(access) => Ok(Some(SvdAccess::parse(&access)?)),
+        None => Ok(None),
+    }
+}
+
+fn required(node: Node, name: &str) -> Result<String> {
+    text(node, name).ok_or_else(|| {
+        anyhow!("<{}> is missing <{}>", node.tag_name().name(), name)
+    })
+}
+
+//
+// Expands an element that may be an array (that is, that has a `dim`) into
+// its constituent names and offsets.  Names containing "[%s]" are arrays
+// proper and are expanded with an index; names containing "%s" are lists,
+// and may have their substitutions specified via `dimIndex`.
+//
+fn dim(node: Node, name: &str, offset: u32) -> Result<Vec<(String, u32)>> {
+    let count = match int(node, "dim")? {
+        Some(count) => count as u32,
+        None => return Ok(vec![(name.to_string(), offset)]),
+    };
+
+    let increment = int(node, "dimIncrement")?.unwrap_or(0) as u32;
+
+    let indices: Vec<String> = match text(node, "dimIndex") {
+        Some(index) if index.contains('-') => {
+            let (lo, hi) = index.split_once('-').unwrap();
+
+            match (lo.trim().parse::<u32>(), hi.trim().parse::<u32>()) {
+                (Ok(lo), Ok(hi)) => (lo..=hi).map(|i| i.to_string()).collect(),
+                _ => {
+                    //
+                    // This can also be a range of letters (e.g., "A-D").
+                    //
+                    let (lo, hi) = (lo.trim().chars(), hi.trim().chars());
+
+                    match (lo.clone().count(), hi.clone().count()) {
+                        (1, 1) => {
+                            let lo = lo.last().unwrap();
+                            let hi = hi.last().unwrap();
+                            (lo..=hi).map(|c| c.to_string()).collect()
+                        }
+                        _ => bail!("invalid dimIndex \"{}\"", index),
+                    }
+                }
+            }
+        }
+        Some(index) => index.split(',').map(|i| i.trim().to_string()).collect(),
+        None => (0..count).map(|i| i.to_string()).collect(),
+    };
+
+    if indices.len() != count as usize {
+        bail!("{}: dimIndex does not match dim of {}", name, count);
+    }
+
+    Ok(indices
+        .iter()
+        .enumerate()
+        .map(|(i, index)| {
+            let n = if name.contains("[%s]") {
+                name.replace("[%s]", index)
+            } else {
+                name.replace("%s", index)
+            };
+
+            (n, offset + i as u32 * increment)
+        })
+        .collect())
+}
+
+//
+// Parses the enumerated values of a field.  Values that are marked as the
+// default (and therefore have no value of their own) and sets of values
+// that are derived from other sets are skipped.
+//
+fn parse_values(node: Node) -> Result<Vec<SvdEnumeratedValue>> {
+    let mut rval = vec![];
+
+    for values in children(node, "enumeratedValues") {
+        for v in children(values, "enumeratedValue") {
+            let value = match int(v, "value")? {
+                Some(value) => value,
+                None => continue,
+            };
+
+            rval.push(SvdEnumeratedValue {
+                name: required(v, "name")?,
+                description: text(v, "description"),
+                value,
+            });
+        }
+    }
+
+    Ok(rval)
+}
+
+fn parse_field(
+    node: Node,
+    inherited: Option<SvdAccess>,
+) -> Result<Vec<SvdField>> {
+    let name = required(node, "name")?;
+    let description = text(node, "description");
+    let access = access(node)?.or(inherited);
+    let values =
+        parse_values(node).with_context(|| format!("field {}", name))?;
+
+    let (offset, width) = if let Some(offset) = int(node, "bitOffset")? {
+        (offset, int(node, "bitWidth")?.unwrap_or(1))
+    } else if let Some(lsb) = int(node, "lsb")? {
+        let msb = int(node, "msb")?
+            .ok_or_else(|| anyhow!("{}: <lsb> without <msb>", name))?;
+        (lsb, (msb + 1).saturating_sub(lsb))
+    } else if let Some(range) = text(node, "bitRange") {
+        let range = range.trim_start_matches('[').trim_end_matches(']');
+
+        match range.split_once(':') {
+            Some((msb, lsb)) => {
+                let (msb, lsb) = (svd_int(msb)?, svd_int(lsb)?);
+                (lsb, (msb + 1).saturating_sub(lsb))
+            }
+            None => bail!("{}: invalid bitRange \"{}\"", name, range),
+        }
+    } else {
+        bail!("field {} has no bit range", name);
+    };
+
+    Ok(dim(node, &name, offset as u32)?
+        .into_iter()
+        .map(|(name, offset)| SvdField {
+            name,
+            description: description.clone(),
+            offset,
+            width: width as u32,
+            access,
+            values: values.clone(),
+        })
+        .collect())
+}
+
+//
+// Parses the registers (and clusters of registers) within the specified
+// node, which may be a `registers` node or a `cluster` node.
+//
+fn parse_registers(
+    node: Node,
+    prefix: &str,
+    base: u32,
+    size: u32,
+    inherited: Option<SvdAccess>,
+    rval: &mut Vec<SvdRegister>,
+) -> Result<()> {
+    for n in node.children().filter(|n| n.is_element()) {
+        match n.tag_name().name() {
+            "register" => {
+                let name = required(n, "name")?;
+                let offset = int(n, "addressOffset")?.unwrap_or(0) as u32;
+                let description = text(n, "description");
+                let size = int(n, "size")?.map_or(size, |s| s as u32);
+                let access = access(n)?.or(inherited);
+
+                let mut fields = vec![];
+
+                if let Some(f) = child(n, "fields") {
+                    for field in children(f, "field") {
+                        fields.extend(parse_field(field, access)?);
+                    }
+                }
+
+                fields.sort_by_key(|f| std::cmp::Reverse(f.offset));
+
+                for (name, offset) in dim(n, &name, base + offset)? {
+                    rval.push(SvdRegister {
+                        name: format!("{}{}", prefix, name),
+                        description: description.clone(),
+                        offset,
+                        size,
+                        access,
+                        read_action: child(n, "readAction").is_some(),
+                        fields: fields.clone(),
+                    });
+                }
+            }
+            "cluster" => {
+                let name = required(n, "name")?;
+                let offset = int(n, "addressOffset")?.unwrap_or(0) as u32;
+                let size = int(n, "size")?.map_or(size, |s| s as u32);
+                let access = access(n)?.or(inherited);
+
+                for (name, offset) in dim(n, &name, base + offset)? {
+                    let prefix = format!("{}{}_", prefix, name);
+                    parse_registers(n, &prefix, offset, size, access, rval)?;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    Ok(())
+}
+
+impl SvdDevice {
+    pub fn parse(xml: &str) -> Result<Self> {
+        let doc = roxmltree::Document::parse(xml)?;
+        let device = doc.root_element();
+
+        if device.tag_name().name() != "device" {
+            bail!("expected <device>, found <{}>", device.tag_name().name());
+        }
+
+        let name = text(device, "name").unwrap_or_else(|| "<unknown>".into());
+        let size = int(device, "size")?.unwrap_or(32) as u32;
+        let daccess = access(device)?;
+
+        let peripherals = match child(device, "peripherals") {
+            Some(peripherals) => peripherals,
+            None => bail!("device {} has no peripherals", name),
+        };
+
+        let mut rval: Vec<SvdPeripheral> = vec![];
+        let mut derived = vec![];
+        let mut byname = HashMap::new();
+
+        for p in children(peripherals, "peripheral") {
+            let pname = required(p, "name")?;
+            let base = int(p, "baseAddress")?
+                .ok_or_else(|| anyhow!("{}: missing baseAddress", pname))?;
+            let size = int(p, "size")?.map_or(size, |s| s as u32);
+            let paccess = access(p)?.or(daccess);
+
+            let mut registers = vec![];
+
+            if let Some(r) = child(p, "registers") {
+                parse_registers(r, "", 0, size, paccess, &mut registers)
+                    .with_context(|| format!("failed to parse {}", pname))?;
+            }
+
+            //
+            // The extent of the peripheral is the extent of its address
+            // blocks.
+            //
+            let mut extent = None;
+
+            for block in children(p, "addressBlock") {
+                let offset = int(block, "offset")?.unwrap_or(0);
+                let size = int(block, "size")?.unwrap_or(0);
+                extent = Some(u64::max(extent.unwrap_or(0), offset + size));
+            }
+
+            if let Some(from) = p.attribute("derivedFrom") {
+                derived.push((rval.len(), from.to_string()));
+            }
+
+            byname.insert(pname.clone(), rval.len());
+
+            rval.push(SvdPeripheral {
+                name: pname,
+                description: text(p, "description"),
+                base: base as u32,
+                size: extent.map(|e| e as u32),
+                registers,
+            });
+        }
+
+        //
+        // Now that we have all of our peripherals, fill in the registers
+        // (and, if need be, descriptions) of any derived peripherals.
+        //
+        for (ndx, from) in derived {
+            let src = match byname.get(&from) {
+                Some(src) => *src,
+                None => {
+                    bail!("{} derived from unknown {}", rval[ndx].name, from)
+                }
+            };
+
+            if rval[ndx].registers.is_empty() {
+                rval[ndx].registers = rval[src].registers.clone();
+            }
+
+            if rval[ndx].size.is_none() {
+                rval[ndx].size = rval[src].size;
+            }
+
+            if rval[ndx].description.is_none() {
+                rval[ndx].description = rval[src].description.clone();
+            }
+        }
+
+        Ok(Self { name, peripherals: rval })
+    }
+
+    pub fn load(filename: &str) -> Result<Self> {
+        let xml = fs::read_to_string(filename)
+            .with_context(|| format!("failed to read {}", filename))?;
+
+        Self::parse(&xml)
+            .with_context(|| format!("failed to parse {}", filename))
+    }
+}
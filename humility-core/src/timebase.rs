@@ -0,0 +1,181 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//!
+//! Correlation of target time (that is, the kernel's tick counter) with
+//! host time.  The tick counter is sampled only by those commands that need
+//! to correlate ticks with host time (as they need it, and periodically if
+//! they run for a while) and when a dump is taken, with each sample taken
+//! at the midpoint of the read of `TICKS`.  From these samples, a tick
+//! value can be converted to the host (wall-clock) time at which it
+//! occurred.  With a single sample, we assume the nominal tick rate of one
+//! tick per millisecond; with more than one, the rate is derived from the
+//! first and last samples.
+//!
+//! The samples are recorded in dumps, allowing the host time of events in
+//! a dump to be determined long after the fact.
+//!
+
+use crate::core::Core;
+use anyhow::Result;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+//
+// The nominal duration of a tick, in nanoseconds.
+//
+const TIMEBASE_NOMINAL_NS: f64 = 1_000_000.0;
+
+//
+// The number of samples that we retain; beyond this, we keep the first
+// sample (to have as long a baseline as possible) and the most recent ones.
+//
+const TIMEBASE_MAX_SAMPLES: usize = 64;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct TimeSample {
+    pub ticks: u64,
+    pub time: SystemTime,
+}
+
+impl TimeSample {
+    /// Returns the host time of the sample in nanoseconds since the epoch.
+    pub fn nanos(&self) -> u64 {
+        self.time
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos() as u64)
+            .unwrap_or(0)
+    }
+
+    pub fn from_nanos(ticks: u64, nanos: u64) -> Self {
+        Self { ticks, time: UNIX_EPOCH + Duration::from_nanos(nanos) }
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Timebase {
+    samples: Vec<TimeSample>,
+}
+
+impl Timebase {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    ///
+    /// Adds a sample.  If the tick counter has gone backwards, the target
+    /// has been reset, and any prior samples are discarded.
+    ///
+    pub fn add(&mut self, sample: TimeSample) {
+        if let Some(last) = self.samples.last() {
+            if sample.ticks < last.ticks {
+                self.samples.clear();
+            }
+        }
+
+        if self.samples.len() >= TIMEBASE_MAX_SAMPLES {
+            self.samples.remove(1);
+        }
+
+        self.samples.push(sample);
+    }
+
+    ///
+    /// Samples the tick counter (which resides at the specified address)
+    /// against host time, returning the number of ticks.
+    ///
+    pub fn sample(&mut self, core: &mut dyn Core, addr: u32) -> Result<u64> {
+        let before = SystemTime::now();
+        let ticks = core.read_word_64(addr)?;
+        let after = SystemTime::now();
+
+        let time = match after.duration_since(before) {
+            Ok(elapsed) => before + elapsed / 2,
+            Err(_) => after,
+        };
+
+        self.add(TimeSample { ticks, time });
+
+        Ok(ticks)
+    }
+
+    pub fn samples(&self) -> &[TimeSample] {
+        &self.samples
+    }
+
+    //
+    // Returns our tick duration in nanoseconds.
+    //
+    fn rate(&self) -> f64 {
+        match (self.samples.first(), self.samples.last()) {
+            (Some(first), Some(last)) if last.ticks > first.ticks => {
+                last.nanos().saturating_sub(first.nanos()) as f64
+                    / (last.ticks - first.ticks) as f64
+            }
+            _ => TIMEBASE_NOMINAL_NS,
+        }
+    }
+
+    /// Converts a tick value to host time, if we have any samples.
+    pub fn to_host(&self, ticks: u64) -> Option<SystemTime> {
+        let nearest = self
+            .samples
+            .iter()
+            .min_by_key(|s| (s.ticks as i128 - ticks as i128).abs())?;
+
+        let offset = (ticks as f64 - nearest.ticks as f64) * self.rate();
+        let nanos = nearest.nanos() as f64 + offset;
+
+        if nanos < 0.0 {
+            None
+        } else {
+            Some(UNIX_EPOCH + Duration::from_nanos(nanos as u64))
+        }
+    }
+
+    ///
+    /// Formats a tick value along with its host time (if known), e.g.
+    /// `4147292 (2022-01-27 18:27:14.107 UTC)`.
+    ///
+    pub fn display(&self, ticks: u64) -> String {
+        match self.to_host(ticks) {
+            Some(time) => format!("{} ({})", ticks, format_time(time)),
+            None => format!("{}", ticks),
+        }
+    }
+}
+
+///
+/// Formats a host time as UTC, to millisecond precision.
+///
+pub fn format_time(time: SystemTime) -> String {
+    let d = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = d.as_secs();
+    let days = (secs / 86400) as i64;
+    let rem = secs % 86400;
+
+    //
+    // Convert days since the epoch to a civil date (per Howard Hinnant's
+    // days_from_civil algorithm, in reverse).
+    //
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02}.{:03} UTC",
+        year,
+        month,
+        day,
+        rem / 3600,
+        (rem / 60) % 60,
+        rem % 60,
+        d.subsec_millis()
+    )
+}
@@ -3,13 +3,22 @@
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
 use anyhow::{bail, Context, Result};
+use humility::core::Core;
 use humility::hubris::*;
 use humility_cmd::Args;
-use humility_cmd::{attach_dump, attach_live};
+use humility_cmd::{attach_dump, attach_live, attach_requiring};
 use humility_cmd::{Archive, Attach, Command, Validate};
 use std::collections::HashMap;
+use std::fs;
+use std::net::TcpListener;
+use std::path::{Path, PathBuf};
 use structopt::clap::App;
 
+//
+// External commands are executables on the PATH named with this prefix.
+//
+const PLUGIN_PREFIX: &str = "humility-";
+
 pub fn init<'a, 'b>(
     app: App<'a, 'b>,
 ) -> (HashMap<&'static str, Command>, App<'a, 'b>) {
@@ -18,25 +27,49 @@ pub fn init<'a, 'b>(
 
     let dcmds = [
         cmd_apptable::init,
+        cmd_bench::init,
+        cmd_break::init,
+        cmd_calibration::init,
+        cmd_chip::init,
+        cmd_clocks::init,
+        cmd_compare::init,
+        cmd_coverage::init,
+        cmd_cycles::init,
+        cmd_daemon::init,
+        cmd_dap::init,
         cmd_etm::init,
         cmd_diagnose::init,
         cmd_dump::init,
         cmd_etm::init,
+        cmd_faultmon::init,
+        cmd_flash::init,
+        cmd_flashalgo::init,
+        cmd_gdb::init,
+        cmd_gdbmi::init,
         cmd_gpio::init,
+        cmd_graph::init,
         cmd_hiffy::init,
         cmd_i2c::init,
         cmd_itm::init,
         cmd_jefe::init,
         cmd_manifest::init,
         cmd_map::init,
+        cmd_memmap::init,
+        cmd_orchestrate::init,
+        cmd_otp::init,
         cmd_pmbus::init,
         cmd_probe::init,
         cmd_qspi::init,
         cmd_readmem::init,
         cmd_readvar::init,
+        cmd_registers::init,
         cmd_renbb::init,
         cmd_rencm::init,
         cmd_ringbuf::init,
+        cmd_rtt::init,
+        cmd_selftest::init,
+        cmd_semihost::init,
+        cmd_snapshot::init,
         cmd_spd::init,
         cmd_spi::init,
         cmd_stackmargin::init,
@@ -44,7 +77,10 @@ pub fn init<'a, 'b>(
         cmd_test::init,
         cmd_trace::init,
         cmd_stmsecure::init,
+        cmd_validate::init,
+        cmd_verify::init,
         cmd_vsc7448::init,
+        cmd_watch::init,
     ];
 
     for dcmd in &dcmds {
@@ -62,6 +98,262 @@ pub fn init<'a, 'b>(
     (cmds, rval)
 }
 
+#[cfg(unix)]
+fn executable(path: &Path) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+
+    match fs::metadata(path) {
+        Ok(md) => md.is_file() && md.permissions().mode() & 0o111 != 0,
+        Err(_) => false,
+    }
+}
+
+#[cfg(not(unix))]
+fn executable(path: &Path) -> bool {
+    fs::metadata(path).map(|md| md.is_file()).unwrap_or(false)
+}
+
+//
+// Returns the path of the specified external command, if it can be found on
+// the PATH.  (Where executables are denoted by their extension, each of the
+// extensions in PATHEXT is tried in turn.)
+//
+fn plugin_path(name: &str) -> Option<PathBuf> {
+    let filename = format!("{}{}", PLUGIN_PREFIX, name);
+
+    let exts: Vec<String> = if cfg!(unix) {
+        vec!["".to_string()]
+    } else {
+        std::env::var("PATHEXT")
+            .unwrap_or_else(|_| ".EXE;.BAT;.CMD".to_string())
+            .split(';')
+            .map(|ext| ext.to_string())
+            .collect()
+    };
+
+    for dir in std::env::split_paths(&std::env::var_os("PATH")?) {
+        for ext in &exts {
+            let path = dir.join(format!("{}{}", filename, ext));
+
+            if executable(&path) {
+                return Some(path);
+            }
+        }
+    }
+
+    None
+}
+
+//
+// Runs an external command.  The command is given its arguments, and our
+// global options are passed via their environment variables, allowing the
+// command to load the same archive as we would.  As we (and not the
+// command) hold the probe, a command run against a live target is given a
+// session on it by our serving the attached core on a loopback port for
+// the duration of the command:  the command finds this as a `net:` probe
+// in `HUMILITY_PROBE`.
+//
+fn plugin(args: &Args, path: &Path, subargs: &[String]) -> Result<()> {
+    let mut cmd = std::process::Command::new(path);
+    cmd.args(&subargs[1..]);
+    cmd.env("HUMILITY_CHIP", &args.chip);
+
+    let mut core = None;
+
+    match &args.probe {
+        Some(probe) if probe.starts_with("net:") => {
+            cmd.env("HUMILITY_PROBE", probe);
+        }
+        _ if args.dump.is_some() => {}
+        probe => match attach_live(args) {
+            Ok(c) => core = Some(c),
+            Err(err) => {
+                //
+                // The command may not need a target at all, so we don't
+                // fail here; if it does need one, it will fail to attach.
+                //
+                log::info!("not serving target to {}: {}", subargs[0], err);
+
+                if let Some(probe) = probe {
+                    cmd.env("HUMILITY_PROBE", probe);
+                }
+            }
+        },
+    }
+
+    let archive = match &args.archive {
+        Some(archive) => Some(archive.clone()),
+        None => humility_cmd::xtask::archive(args)?,
+    };
+
+    if let Some(archive) = archive {
+        cmd.env("HUMILITY_ARCHIVE", archive);
+        cmd.env_remove("HUMILITY_APP");
+    }
+
+    if let Some(dump) = &args.dump {
+        cmd.env("HUMILITY_DUMP", dump);
+    }
+
+    if !args.svd.is_empty() {
+        cmd.env("HUMILITY_SVD", args.svd.join(","));
+    }
+
+    if args.json() {
+        cmd.env("HUMILITY_OUTPUT", "json");
+    }
+
+    let triggers = args.triggers();
+
+    if !triggers.is_empty() {
+        cmd.env("HUMILITY_TRIGGER", triggers.join("\n"));
+    }
+
+    let failed = || format!("failed to run {}", path.display());
+
+    let status = match core {
+        Some(mut core) => {
+            let listener = TcpListener::bind("127.0.0.1:0")
+                .context("failed to listen for external command")?;
+            let addr = listener.local_addr()?;
+
+            cmd.env("HUMILITY_PROBE", format!("net:{}", addr));
+
+            let mut child = cmd.spawn().with_context(failed)?;
+            let mut status = None;
+
+            let served =
+                humility::net::serve_until(core.as_mut(), &listener, || {
+                    status = child.try_wait()?;
+                    Ok(status.is_some())
+                });
+
+            if let Err(err) = served {
+                let _ = child.kill();
+                return Err(err);
+            }
+
+            status.unwrap()
+        }
+        None => cmd.status().with_context(failed)?,
+    };
+
+    if !status.success() {
+        bail!("{} {}", path.display(), status);
+    }
+
+    Ok(())
+}
+
+//
+// To allow --fast to skip validation, we record (in our cache directory) the
+// image that was last successfully validated against each target (as
+// denoted by its chip and probe).  We don't record whether the target was
+// found to be booted, as that can change at any time.
+//
+fn validated_path() -> Option<PathBuf> {
+    Some(humility::hubris::cache_dir()?.join("validated"))
+}
+
+fn validated_key(args: &Args, core: &dyn Core) -> String {
+    let (name, serial) = core.info();
+    format!("{}/{}", args.chip, serial.unwrap_or(name)).replace(' ', "_")
+}
+
+fn validated_entries() -> Vec<(String, u64)> {
+    let contents = match validated_path().map(fs::read_to_string) {
+        Some(Ok(contents)) => contents,
+        _ => return vec![],
+    };
+
+    contents
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            let key = fields.next()?.to_string();
+            let id = u64::from_str_radix(fields.next()?, 16).ok()?;
+            Some((key, id))
+        })
+        .collect()
+}
+
+//
+// Determines if the target was last validated against the image in the
+// archive -- and, because it may have since been reflashed, that it is
+// still running that image.
+//
+fn validated(
+    hubris: &HubrisArchive,
+    core: &mut dyn Core,
+    key: &str,
+) -> Result<bool> {
+    let id = match hubris.image_id() {
+        Some(id) => id,
+        None => return Ok(false),
+    };
+
+    if !validated_entries().iter().any(|(k, i)| k == key && *i == id) {
+        return Ok(false);
+    }
+
+    Ok(hubris.read_image_id(core)? == id)
+}
+
+fn set_validated(hubris: &HubrisArchive, key: &str) -> Result<()> {
+    let (path, id) = match (validated_path(), hubris.image_id()) {
+        (Some(path), Some(id)) => (path, id),
+        _ => return Ok(()),
+    };
+
+    let mut entries = validated_entries();
+
+    if entries.iter().any(|(k, i)| k == key && *i == id) {
+        return Ok(());
+    }
+
+    entries.retain(|(k, _)| k != key);
+    entries.push((key.to_string(), id));
+
+    let contents: String = entries
+        .iter()
+        .map(|(key, id)| format!("{} {:016x}\n", key, id))
+        .collect();
+
+    fs::create_dir_all(path.parent().unwrap())?;
+    fs::write(&path, contents)?;
+
+    Ok(())
+}
+
+//
+// Verifies (by sampling) that the image on the target matches the archive,
+// failing if any region of it differs.
+//
+fn verify(hubris: &HubrisArchive, core: &mut dyn Core) -> Result<()> {
+    let regions = hubris.verify(core, Some(HUBRIS_VERIFY_SAMPLES))?;
+    let differs: Vec<_> =
+        regions.iter().filter(|r| r.differs.is_some()).collect();
+
+    if let Some(region) = differs.first() {
+        let (addr, _) = region.differs.unwrap();
+
+        bail!(
+            "image on target does not match archive: {} differs at 0x{:x}{}",
+            region.name,
+            addr,
+            if differs.len() > 1 {
+                format!(" (and {} other regions differ)", differs.len() - 1)
+            } else {
+                "".to_string()
+            }
+        );
+    }
+
+    log::info!("verified image against archive");
+
+    Ok(())
+}
+
 pub fn subcommand(
     commands: &HashMap<&'static str, Command>,
     args: &Args,
@@ -81,6 +373,17 @@ pub fn subcommand(
                 hubris.load(archive).context("failed to load archive")?;
             } else if let Some(dump) = &args.dump {
                 hubris.load_dump(dump).context("failed to load dump")?;
+            } else if let Some(archive) = humility_cmd::xtask::archive(args)? {
+                hubris.load(&archive).context("failed to load archive")?;
+            }
+
+            //
+            // SVD files specified explicitly take the place of any that are
+            // bundled in the archive; the latter are loaded only by those
+            // commands that need them.
+            //
+            for svd in &args.svd {
+                hubris.load_svd(svd).context("failed to load SVD file")?;
             }
         }
 
@@ -89,7 +392,7 @@ pub fn subcommand(
         }
 
         match command {
-            Command::Attached { run, attach, validate, .. } => {
+            Command::Attached { name, run, attach, validate, .. } => {
                 let mut c = match attach {
                     Attach::LiveOnly => attach_live(args),
                     Attach::DumpOnly => attach_dump(args, &hubris),
@@ -100,18 +403,65 @@ pub fn subcommand(
                             attach_live(args)
                         }
                     }
+                    Attach::Requires(ops) => {
+                        attach_requiring(args, &hubris, *ops)
+                    }
                 }?;
 
                 let core = c.as_mut();
 
-                match validate {
-                    Validate::Booted => {
-                        hubris.validate(core, HubrisValidate::Booted)?;
+                //
+                // Before we go any further, make sure that we haven't been
+                // pointed at a chip other than the one that the archive is
+                // for.  This must not modify the target, so we check only
+                // what can be read from the chip's ID registers.
+                //
+                if hubris.loaded() && !core.is_dump() {
+                    humility_cortex::chip::chip_check(&hubris, core, false)?;
+                }
+
+                let criteria = match validate {
+                    Validate::Booted => Some(HubrisValidate::Booted),
+                    Validate::Match => Some(HubrisValidate::ArchiveMatch),
+                    Validate::None => None,
+                };
+
+                if let Some(criteria) = criteria {
+                    let key = validated_key(args, core);
+
+                    if args.fast() && validated(&hubris, core, &key)? {
+                        log::info!("image ID matches; skipping validation");
+
+                        if criteria == HubrisValidate::Booted {
+                            hubris.validate_booted(core)?;
+                        }
+                    } else {
+                        hubris.validate(core, criteria)?;
+
+                        if !core.is_dump() {
+                            if let Err(err) = set_validated(&hubris, &key) {
+                                log::warn!(
+                                    "failed to record validation: {}",
+                                    err
+                                );
+                            }
+                        }
                     }
-                    Validate::Match => {
-                        hubris.validate(core, HubrisValidate::ArchiveMatch)?;
+                }
+
+                //
+                // Verification is applied regardless of the command's
+                // validation criteria -- but it needs an archive to verify
+                // against.  The exception is flash, which replaces the image
+                // (and verifies what it writes):  verifying beforehand would
+                // only fail.
+                //
+                if args.verify() && !core.is_dump() && *name != "flash" {
+                    if !hubris.loaded() {
+                        bail!("--verify requires an archive");
                     }
-                    Validate::None => {}
+
+                    verify(&hubris, core)?;
                 }
 
                 (run)(&mut hubris, core, args, subargs)
@@ -120,6 +470,8 @@ pub fn subcommand(
                 (run)(&mut hubris, args, subargs)
             }
         }
+    } else if let Some(path) = plugin_path(&subargs[0]) {
+        plugin(args, &path, subargs)
     } else {
         bail!("command {} not found", subargs[0]);
     }
@@ -22,6 +22,9 @@ macro_rules! fatal {
 #[derive(Debug, Clone, Copy)]
 pub struct HumilityLog {
     level: log::LevelFilter,
+
+    // also log transient errors on the debug transport
+    probe: bool,
 }
 
 fn is_humility(metadata: &log::Metadata) -> bool {
@@ -35,6 +38,9 @@ fn is_humility(metadata: &log::Metadata) -> bool {
 impl log::Log for HumilityLog {
     fn enabled(&self, metadata: &log::Metadata) -> bool {
         metadata.level() <= self.level
+            || (self.probe
+                && metadata.level() <= log::Level::Debug
+                && metadata.target() == humility::core::PROBE_LOG_TARGET)
     }
 
     fn log(&self, record: &log::Record) {
@@ -64,7 +70,11 @@ impl HumilityLog {
                 fatal!("unable to enable logging: {}", e);
             }
             Ok(_l) => {
-                log::set_max_level(self.level);
+                log::set_max_level(if self.probe {
+                    std::cmp::max(self.level, log::LevelFilter::Debug)
+                } else {
+                    self.level
+                });
             }
         };
     }
@@ -89,10 +99,12 @@ fn main() {
      */
     let args = Args::from_args();
 
+    let probe = args.probe_log;
+
     if args.verbose {
-        HumilityLog { level: log::LevelFilter::Trace }.enable();
+        HumilityLog { level: log::LevelFilter::Trace, probe }.enable();
     } else {
-        HumilityLog { level: log::LevelFilter::Info }.enable();
+        HumilityLog { level: log::LevelFilter::Info, probe }.enable();
     }
 
     match &args.cmd {